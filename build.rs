@@ -0,0 +1,79 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Build the startup image: a heap image of a fresh heap with the primitives
+//! defined and the prelude evaluated. The `oxischeme` binary embeds it, and
+//! loads it at startup rather than evaluating the prelude from source.
+//!
+//! A build script cannot link against the crate it is building, so this
+//! compiles the crate's modules into itself instead.
+//!
+//! Cargo does not pass the crate's features on to its build script, so the
+//! heap built here has stubs for the primitives of optional features, such as
+//! `trace`. That's fine: primitives are saved in an image by name, and loading
+//! the image finds the real primitives by the same names.
+
+#![feature(alloc)]
+#![feature(collections)]
+#![feature(core)]
+#![feature(env)]
+#![feature(old_io)]
+#![feature(old_path)]
+#![feature(test)]
+#![feature(unicode)]
+#![feature(unsafe_destructor)]
+
+#![allow(dead_code)]
+
+extern crate time;
+
+use std::env;
+use std::old_io::{File};
+
+#[path = "src/channel.rs"]     mod channel;
+#[path = "src/environment.rs"] mod environment;
+#[path = "src/errors.rs"]      mod errors;
+#[path = "src/eval.rs"]        mod eval;
+#[path = "src/generator.rs"]   mod generator;
+#[path = "src/heap.rs"]        mod heap;
+#[path = "src/image.rs"]       mod image;
+#[path = "src/primitives.rs"]  mod primitives;
+#[path = "src/random.rs"]      mod random;
+#[path = "src/read.rs"]        mod read;
+#[path = "src/repl.rs"]        mod repl;
+#[path = "src/tools.rs"]       mod tools;
+#[path = "src/transcript.rs"]  mod transcript;
+#[path = "src/value.rs"]       mod value;
+#[path = "src/vm.rs"]          mod vm;
+
+/// The name of the startup image in cargo's output directory. The binary
+/// includes it from there.
+static STARTUP_IMAGE: &'static str = "startup.image";
+
+pub fn main() {
+    let out_dir = env::var("OUT_DIR").ok().expect("Cargo should set OUT_DIR");
+    let path = Path::new(out_dir).join(STARTUP_IMAGE);
+
+    let heap = &mut heap::Heap::new();
+    if let Err(msg) = eval::ensure_prelude(heap) {
+        panic!("Could not build the startup image: {}", msg);
+    }
+
+    let mut file = File::create(&path)
+        .ok()
+        .expect("Should be able to create the startup image");
+    if let Err(msg) = heap.save_image(&mut file) {
+        panic!("Could not build the startup image: {}", msg);
+    }
+}
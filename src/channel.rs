@@ -0,0 +1,223 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Channels for passing messages between heaps running on different threads,
+//! and between Scheme code and the host.
+//!
+//! Each heap belongs to a single thread, and its values cannot be shared with
+//! other heaps. Instead, a value sent on a channel is copied into a `Message`,
+//! which is copied back into a value in the receiving heap. Only plain data can
+//! be sent: the empty list, integers, booleans, characters, strings, symbols,
+//! bytevectors, and pairs and vectors of these. Shared structure is not
+//! preserved, and cyclic data cannot be sent.
+//!
+//! The host makes a `Channel`, hands a clone of it to each heap with
+//! `Channel::to_value`, and keeps one for itself to send and receive messages
+//! directly. Scheme code can also make channels of its own:
+//!
+//!     (define ch (make-channel))
+//!     (channel-send ch '(hello 1 2))
+//!     (channel-receive ch)  ;; => (hello 1 2)
+
+use std::collections::{HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use heap::{Heap, Rooted};
+use value::{RootedValue, Value};
+
+/// The tag of the native objects wrapping channels.
+pub static CHANNEL_TAG : &'static str = "channel";
+
+/// A copy of a Scheme value that can be sent between threads.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    EmptyList,
+    Integer(i64),
+    Flonum(f64),
+    Boolean(bool),
+    Character(char),
+    String(String),
+    Symbol(String),
+    Bytevector(Vec<u8>),
+    Pair(Box<Message>, Box<Message>),
+    Vector(Vec<Message>),
+}
+
+impl Message {
+    /// Copy the given value into a message. It is an error if the value is not
+    /// plain data, or is cyclic.
+    pub fn from_value(val: &Value) -> Result<Message, String> {
+        Message::copy(*val, &mut HashSet::new())
+    }
+
+    /// Copy the given value, where `path` holds the pairs and vectors being
+    /// copied that contain it.
+    fn copy(val: Value, path: &mut HashSet<Value>) -> Result<Message, String> {
+        let message = match val {
+            Value::EmptyList      => return Ok(Message::EmptyList),
+            Value::Integer(i)     => return Ok(Message::Integer(i)),
+            Value::Flonum(f)      => return Ok(Message::Flonum(*f)),
+            Value::Boolean(b)     => return Ok(Message::Boolean(b)),
+            Value::Character(c)   => return Ok(Message::Character(c)),
+            Value::String(str)    => return Ok(Message::String((*str).clone())),
+            Value::Symbol(sym)    => return Ok(Message::Symbol((*sym).clone())),
+            Value::Bytevector(bv) => return Ok(Message::Bytevector((*bv).clone())),
+            Value::Pair(_) | Value::Vector(_) => {
+                if !path.insert(val) {
+                    return Err("Error: cannot send cyclic data on a channel".to_string());
+                }
+
+                if let Value::Pair(cons) = val {
+                    let car = try!(Message::copy(cons.unrooted_car(), path));
+                    let cdr = try!(Message::copy(cons.unrooted_cdr(), path));
+                    Message::Pair(Box::new(car), Box::new(cdr))
+                } else if let Value::Vector(vector) = val {
+                    let mut elements = vec!();
+                    for i in range(0, vector.len()) {
+                        elements.push(try!(Message::copy(vector.unrooted_get(i).unwrap(),
+                                                         path)));
+                    }
+                    Message::Vector(elements)
+                } else {
+                    unreachable!()
+                }
+            },
+            _ => return Err(format!("Error: cannot send {} on a channel", val)),
+        };
+        path.remove(&val);
+        Ok(message)
+    }
+
+    /// Copy this message into a value in the given heap.
+    pub fn to_value(&self, heap: &mut Heap) -> RootedValue {
+        match *self {
+            Message::EmptyList          => Rooted::new(heap, Value::EmptyList),
+            Message::Integer(i)         => Rooted::new(heap, Value::new_integer(i)),
+            Message::Flonum(f)          => Value::new_flonum(heap, f),
+            Message::Boolean(b)         => Rooted::new(heap, Value::new_boolean(b)),
+            Message::Character(c)       => Rooted::new(heap, Value::new_character(c)),
+            Message::String(ref str)    => Value::new_string(heap, str.clone()),
+            Message::Symbol(ref sym)    => heap.get_or_create_symbol(sym.clone()),
+            Message::Bytevector(ref bv) => Value::new_bytevector(heap, bv.clone()),
+            Message::Pair(ref car, ref cdr) => {
+                let car = car.to_value(heap);
+                let cdr = cdr.to_value(heap);
+                Value::new_pair(heap, &car, &cdr)
+            },
+            Message::Vector(ref elements) => {
+                let elements : Vec<RootedValue> = elements.iter()
+                    .map(|e| e.to_value(heap))
+                    .collect();
+                Value::new_vector(heap, elements.as_slice())
+            },
+        }
+    }
+}
+
+/// A multi-producer, multi-consumer queue of messages. Clones of a channel
+/// share the same queue, and may be used from any thread.
+#[derive(Clone)]
+pub struct Channel {
+    sender: Sender<Message>,
+    receiver: Arc<Mutex<Receiver<Message>>>,
+}
+
+impl Channel {
+    /// Create a new, empty channel.
+    pub fn new() -> Channel {
+        let (sender, receiver) = mpsc::channel();
+        Channel {
+            sender: sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+
+    /// Add the given message to the end of the queue.
+    pub fn send(&self, message: Message) {
+        // This channel holds the receiver, so sending cannot fail.
+        self.sender.send(message).ok().expect("A channel's receiver outlives it");
+    }
+
+    /// Take the message at the front of the queue, blocking until there is one.
+    pub fn receive(&self) -> Message {
+        let receiver = self.receiver.lock().unwrap();
+        receiver.recv().ok().expect("A channel's sender outlives it")
+    }
+
+    /// Take the message at the front of the queue, or return `None` if the
+    /// queue is empty.
+    pub fn try_receive(&self) -> Option<Message> {
+        let receiver = self.receiver.lock().unwrap();
+        receiver.try_recv().ok()
+    }
+
+    /// Wrap a clone of this channel in a native object, so that Scheme code in
+    /// the given heap can use it.
+    pub fn to_value(&self, heap: &mut Heap) -> RootedValue {
+        heap.new_native(CHANNEL_TAG, Box::new(self.clone()))
+    }
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heap::{Heap};
+
+    #[test]
+    fn test_channel_round_trip() {
+        let heap = &mut Heap::new();
+        let val = heap.eval_str("'(a \"b\" #\\c #(1 2) . #u8(3))")
+            .ok()
+            .expect("Should be able to make a message.");
+        let message = Message::from_value(&*val)
+            .ok()
+            .expect("Should be able to copy plain data.");
+
+        let other = &mut Heap::new();
+        let copy = message.to_value(other);
+        assert_eq!(format!("{}", *copy), format!("{}", *val));
+
+        let procedure = heap.eval_str("car").ok().expect("car");
+        assert!(Message::from_value(&*procedure).is_err());
+    }
+
+    #[test]
+    fn test_channel_between_threads() {
+        use std::thread::{Thread};
+
+        let requests = Channel::new();
+        let responses = Channel::new();
+
+        let (req, resp) = (requests.clone(), responses.clone());
+        let worker = Thread::scoped(move || {
+            let heap = &mut Heap::new();
+            let double = heap.eval_str("(lambda (requests responses)                 \
+                                          (channel-send responses                    \
+                                                        (* 2 (channel-receive requests))))")
+                .ok()
+                .expect("Should be able to define the worker.");
+            let args = [req.to_value(heap), resp.to_value(heap)];
+            heap.call(&double, &args)
+                .ok()
+                .expect("Should be able to use channels from Scheme.");
+        });
+
+        requests.send(Message::Integer(21));
+        assert_eq!(responses.receive(), Message::Integer(42));
+        assert!(worker.join().is_ok());
+    }
+}
@@ -26,13 +26,14 @@
 //! deal with activations, and we no longer need the symbols nor the
 //! `Environment`.
 
-use std::collections::{HashMap};
+use std::collections::{HashMap, HashSet};
 use std::default::{Default};
 use std::fmt;
 use std::hash;
 
-use heap::{ArenaPtr, GcThing, Heap, IterGcThing, Rooted, ToGcThing, Trace};
-use value::{Value, RootedValue};
+use errors::{E0312, SchemeError};
+use heap::{ArenaPtr, Finalizer, GcThing, Heap, IterGcThing, Rooted, ToGcThing, Trace};
+use value::{Value, RootedValue, Word};
 
 /// An `Activation` represents a runtime instance of a lexical block (either a
 /// lambda or the global top-level).
@@ -40,10 +41,14 @@ pub struct Activation {
     /// The parent scope, or `None` if this is the global activation.
     parent: Option<ActivationPtr>,
     /// For a lambda with N arguments, the first N slots are those arguments
-    /// respectively. The rest are local definitions. If a slot is `None`, then
-    /// it's variable hasn't been defined yet (but is referenced by something
-    /// and potentially will be defined in the future).
-    vals: Vec<Option<Value>>,
+    /// respectively. The rest are local definitions. If a slot is undefined,
+    /// then it's variable hasn't been defined yet (but is referenced by
+    /// something and potentially will be defined in the future).
+    vals: Vec<Word>,
+    /// True if a procedure or another activation may refer to this one, in
+    /// which case it must not be reused by a tail call. See
+    /// `Activation::reuse_for_tail_call`.
+    captured: bool,
 }
 
 impl Activation {
@@ -54,10 +59,57 @@ impl Activation {
                   values: Vec<RootedValue>) -> RootedActivationPtr {
         let mut act = heap.allocate_activation();
         act.parent = Some(**parent);
-        act.vals = values.into_iter().map(|v| Some(*v)).collect();
+        act.captured = false;
+        let mut parent_act = **parent;
+        parent_act.capture();
+        act.vals = values.into_iter().map(|v| Word::new(*v)).collect();
         return act;
     }
 
+    /// Extend the given `Activation` with the arguments above the given height
+    /// on the heap's argument stack, popping them. The new activation reuses
+    /// the storage of whichever activation previously occupied its arena slot,
+    /// so in steady state, calls do not allocate any memory.
+    pub fn extend_from_arguments(heap: &mut Heap,
+                                 parent: &RootedActivationPtr,
+                                 base: usize) -> RootedActivationPtr {
+        let mut act = heap.allocate_activation();
+        act.parent = Some(**parent);
+        act.captured = false;
+        let mut parent_act = **parent;
+        parent_act.capture();
+        act.vals.clear();
+        act.vals.extend(heap.arguments(base).iter().map(|v| Word::new(*v)));
+        heap.truncate_arguments(base);
+        return act;
+    }
+
+    /// Reuse the given activation for a tail call to a procedure closed over
+    /// `parent`, with the arguments above the given height on the heap's
+    /// argument stack, popping them. This is only possible when `act` is itself
+    /// an extension of `parent` that nothing else refers to; otherwise, return
+    /// false without doing anything.
+    pub fn reuse_for_tail_call(heap: &mut Heap,
+                               act: &mut RootedActivationPtr,
+                               parent: &RootedActivationPtr,
+                               base: usize) -> bool {
+        if act.captured || act.parent != Some(**parent) {
+            return false;
+        }
+
+        act.vals.clear();
+        act.vals.extend(heap.arguments(base).iter().map(|v| Word::new(*v)));
+        heap.truncate_arguments(base);
+        true
+    }
+
+    /// Note that something other than the evaluator, such as a procedure or a
+    /// child activation, refers to this activation.
+    #[inline]
+    pub fn capture(&mut self) {
+        self.captured = true;
+    }
+
     /// Fetch the j'th variable from the i'th lexical activation.
     ///
     /// Returns an error when trying to fetch the value of a variable that has
@@ -72,7 +124,7 @@ impl Activation {
                 return Err(());
             }
 
-            if let Some(val) = self.vals[jj] {
+            if let Some(val) = self.vals[jj].get() {
                 return Ok(Rooted::new(heap, val));
             }
 
@@ -94,11 +146,11 @@ impl Activation {
                   val: &RootedValue) -> Result<(), ()> {
         if i == 0 {
             let jj = j as usize;
-            if jj >= self.vals.len() || self.vals[jj].is_none() {
+            if jj >= self.vals.len() || self.vals[jj] == Word::undefined() {
                 return Err(());
             }
 
-            self.vals[jj] = Some(**val);
+            self.vals[jj] = Word::new(**val);
             return Ok(());
         }
 
@@ -108,14 +160,50 @@ impl Activation {
 
     fn fill_to(&mut self, n: u32) {
         while self.len() < n + 1 {
-            self.vals.push(None);
+            self.vals.push(Word::undefined());
         }
     }
 
     /// Define the j'th variable of this activation to be the given value.
     pub fn define(&mut self, j: u32, val: Value) {
         self.fill_to(j);
-        self.vals[j as usize] = Some(val);
+        self.vals[j as usize] = Word::new(val);
+    }
+
+    /// Return true if the j'th variable of this activation has been defined,
+    /// false otherwise.
+    pub fn is_defined(&self, j: u32) -> bool {
+        self.vals.get(j as usize).map_or(false, |v| *v != Word::undefined())
+    }
+
+    /// Get the parent activation, or `None` if this is the global activation.
+    pub fn parent(&self) -> Option<ActivationPtr> {
+        self.parent
+    }
+
+    /// Get the values of this activation's variables, with `None` for each
+    /// variable that has not been defined yet.
+    pub fn values(&self) -> Vec<Option<Value>> {
+        self.vals.iter().map(|v| v.get()).collect()
+    }
+
+    /// Return true if this activation has been captured, false otherwise. See
+    /// `Activation::capture`.
+    pub fn is_captured(&self) -> bool {
+        self.captured
+    }
+
+    /// Replace this activation's parent and variables wholesale, as when
+    /// loading a heap image.
+    pub fn restore(&mut self,
+                   parent: Option<ActivationPtr>,
+                   values: Vec<Option<Value>>,
+                   captured: bool) {
+        self.parent = parent;
+        self.vals = values.into_iter()
+            .map(|v| v.map_or(Word::undefined(), Word::new))
+            .collect();
+        self.captured = captured;
     }
 
     #[inline]
@@ -138,6 +226,7 @@ impl Default for Activation {
         Activation {
             parent: None,
             vals: vec!(),
+            captured: false,
         }
     }
 }
@@ -145,13 +234,7 @@ impl Default for Activation {
 impl Trace for Activation {
     fn trace(&self) -> IterGcThing {
         let mut results: Vec<GcThing> = self.vals.iter()
-            .filter_map(|v| {
-                if let Some(val) = *v {
-                    val.to_gc_thing()
-                } else {
-                    None
-                }
-            })
+            .filter_map(|v| v.get().and_then(|val| val.to_gc_thing()))
             .collect();
 
         if let Some(parent) = self.parent {
@@ -167,7 +250,7 @@ impl fmt::Debug for Activation {
         try!(write!(f, "(activation :length {}\n", self.vals.len()));
         try!(write!(f, "            :parent "));
         if let Some(ref p) = self.parent {
-            write!(f, "Some({:?}))", **p)
+            write!(f, "Some({:?}))", *p)
         } else {
             write!(f, "None)")
         }
@@ -186,26 +269,118 @@ impl ToGcThing for ActivationPtr {
 /// A rooted pointer to an `Activation` on the heap.
 pub type RootedActivationPtr = Rooted<ActivationPtr>;
 
+/// A compact handle for an interned variable name: an index into the side table
+/// of names kept by the heap's `Environment`, so that the `Environment` and the
+/// meanings produced by syntactic analysis compare, hash, and copy names as
+/// integers rather than as strings.
+///
+/// Ids are only interned for the names of variables, not for every symbol that
+/// is read, so the table stays small even when a program creates many symbols
+/// with `string->symbol`. Ids are never reused: a meaning or a reified
+/// environment's `Scope` may hold an id long after the binding it came from is
+/// gone, and evaluating a form in that environment must find the same id for
+/// the same name. The table is dropped along with its heap.
+///
+/// An id only means something to the heap that interned it. Use
+/// `Environment::name` to get the name back.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SymbolId(u32);
+
+/// `Display` can not find the name without the environment, so it prints the
+/// id's index. Use `Environment::name` for messages meant for people.
+impl fmt::Display for SymbolId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#<variable {}>", self.0)
+    }
+}
+
+/// The side table mapping `SymbolId`s to names and back.
+#[derive(Clone)]
+pub struct SymbolNames {
+    ids: HashMap<String, SymbolId>,
+    names: Vec<String>,
+}
+
+impl SymbolNames {
+    fn new() -> SymbolNames {
+        SymbolNames {
+            ids: HashMap::new(),
+            names: vec!(),
+        }
+    }
+
+    fn intern(&mut self, name: &str) -> SymbolId {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+
+        let id = SymbolId(self.names.len() as u32);
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    /// Get the name the given id was interned from.
+    pub fn name(&self, id: SymbolId) -> &str {
+        self.names[id.0 as usize].as_slice()
+    }
+}
+
+/// The names and binding indices of the variables of each lexical block between
+/// a point in a program and the global top level, oldest first, as recorded by
+/// syntactic analysis. Each block's variables are in order of their indices.
+pub type Scope = Vec<Vec<(SymbolId, u32)>>;
+
 /// The `Environment` represents what we know about bindings statically, during
 /// syntactic analysis.
 pub struct Environment {
     /// A hash map for each lexical block we are currently in, which maps from a
     /// variable name to its position in any activations that get created for
     /// this block.
-    bindings: Vec<HashMap<String, u32>>,
+    bindings: Vec<HashMap<SymbolId, u32>>,
+    /// The interned variable names. See `SymbolId`.
+    names: SymbolNames,
 }
 
 impl Environment {
     /// Create a new `Environemnt`.
     pub fn new() -> Environment {
         Environment {
-            bindings: vec!(HashMap::new())
+            bindings: vec!(HashMap::new()),
+            names: SymbolNames::new(),
         }
     }
 
+    /// Get the id for the given variable name, interning it if this is the
+    /// first time it has been seen.
+    pub fn intern(&mut self, name: &str) -> SymbolId {
+        self.names.intern(name)
+    }
+
+    /// Get the id for the given variable name if it is interned, without
+    /// interning it.
+    pub fn interned(&self, name: &str) -> Option<SymbolId> {
+        self.names.ids.get(name).map(|id| *id)
+    }
+
+    /// Get the name the given id was interned from.
+    pub fn name(&self, id: SymbolId) -> &str {
+        self.names.name(id)
+    }
+
+    /// Get the table of interned names.
+    pub fn names(&self) -> &SymbolNames {
+        &self.names
+    }
+
+    /// Get the number of names in the table of interned names.
+    pub fn interned_count(&self) -> usize {
+        self.names.names.len()
+    }
+
     /// Extend the environment with a new lexical block with the given set of
     /// variables.
-    pub fn extend(&mut self, names: Vec<String>) {
+    pub fn extend(&mut self, names: Vec<SymbolId>) {
         self.bindings.push(HashMap::new());
         for n in names.into_iter() {
             self.define(n);
@@ -221,7 +396,7 @@ impl Environment {
 
     /// Define a variable in the youngest block and return the coordinates to
     /// get its value from an activation at runtime.
-    pub fn define(&mut self, name: String) -> (u32, u32) {
+    pub fn define(&mut self, name: SymbolId) -> (u32, u32) {
         if let Some(n) = self.youngest().get(&name) {
             return (0, *n);
         }
@@ -232,24 +407,326 @@ impl Environment {
     }
 
     /// Define a global variable and return its activation coordinates.
-    pub fn define_global(&mut self, name: String) -> (u32, u32) {
+    pub fn define_global(&mut self, name: SymbolId) -> (u32, u32) {
         let n = self.bindings[0].len() as u32;
         self.bindings[0].insert(name, n);
         return ((self.bindings.len() - 1) as u32, n);
     }
 
     /// Get the activation coordinates associated with the given variable name.
-    pub fn lookup(&self, name: &String) -> Option<(u32, u32)> {
+    pub fn lookup(&self, name: SymbolId) -> Option<(u32, u32)> {
         for (i, bindings) in self.bindings.iter().rev().enumerate() {
-            if let Some(j) = bindings.get(name) {
+            if let Some(j) = bindings.get(&name) {
                 return Some((i as u32, *j));
             }
         }
         return None;
     }
 
-    fn youngest<'a>(&'a mut self) -> &'a mut HashMap<String, u32> {
+    /// Get the activation index of the global variable with the given name.
+    pub fn lookup_global(&self, name: SymbolId) -> Option<u32> {
+        self.bindings[0].get(&name).map(|j| *j)
+    }
+
+    /// Get the activation index of the global variable with the given name,
+    /// without interning the name.
+    pub fn lookup_global_name(&self, name: &str) -> Option<u32> {
+        self.interned(name).and_then(|id| self.lookup_global(id))
+    }
+
+    /// Get every global variable's name and activation index.
+    pub fn globals(&self) -> Vec<(SymbolId, u32)> {
+        self.bindings[0].iter().map(|(name, j)| (*name, *j)).collect()
+    }
+
+    /// Replace every global variable with the given names and activation
+    /// indices, as when loading a heap image.
+    pub fn restore_globals(&mut self, globals: Vec<(SymbolId, u32)>) {
+        self.bindings[0] = globals.into_iter().collect();
+    }
+
+    /// Return true if the youngest lexical block is the global top level,
+    /// false otherwise.
+    pub fn is_global_scope(&self) -> bool {
+        self.bindings.len() == 1
+    }
+
+    /// Get the number of lexical blocks between the youngest block and the
+    /// global top level.
+    pub fn depth(&self) -> u32 {
+        (self.bindings.len() - 1) as u32
+    }
+
+    /// Get the names of the variables that start with the given prefix, sorted
+    /// and without duplicates, as for completing a name being typed. Globals
+    /// are only included once they are defined in the given global activation,
+    /// rather than as soon as something refers to them.
+    pub fn names_with_prefix(&self, prefix: &str, global: &Activation) -> Vec<String> {
+        let mut names : Vec<String> = self.bindings[0].iter()
+            .filter(|&(_, j)| global.is_defined(*j))
+            .map(|(name, _)| self.name(*name).to_string())
+            .chain(self.bindings.iter().skip(1)
+                   .flat_map(|bindings| bindings.keys())
+                   .map(|name| self.name(*name).to_string()))
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Get the names and indices of the variables of every lexical block but
+    /// the global top level.
+    pub fn local_scope(&self) -> Scope {
+        self.bindings.iter().skip(1).map(|bindings| {
+            let mut block : Vec<(SymbolId, u32)> = bindings.iter()
+                .map(|(name, j)| (*name, *j))
+                .collect();
+            block.sort_by(|a, b| a.1.cmp(&b.1));
+            block
+        }).collect()
+    }
+
+    /// Replace every lexical block but the global top level with the given
+    /// ones, as when analyzing a form to evaluate in a first-class environment,
+    /// and return the blocks that were replaced.
+    pub fn replace_local_scope(&mut self, scope: Scope) -> Scope {
+        let replaced = self.local_scope();
+        self.bindings.truncate(1);
+        for block in scope.into_iter() {
+            self.bindings.push(block.into_iter().collect());
+        }
+        replaced
+    }
+
+    fn youngest<'a>(&'a mut self) -> &'a mut HashMap<SymbolId, u32> {
         let last_idx = self.bindings.len() - 1;
         &mut self.bindings[last_idx]
     }
 }
+
+/// ## First-Class Environments
+///
+/// `(the-environment)` reifies the environment it is evaluated in as a value:
+/// the current activation, along with the `Scope` naming its variables and
+/// those of its parents. Environments can be inspected with
+/// `environment-bindings` and `environment-ref`, and forms can be evaluated in
+/// them with `eval`. The global variables seen through an environment are
+/// always those of the heap's global activation, and are looked up by name
+/// when they are inspected, so that globals defined later are seen too.
+///
+/// Activations cannot be sent to other threads like native objects can, so
+/// like generators, an environment value is a native object holding the key of
+/// its entry in the heap's `ReifiedEnvironments` table.
+
+/// The tag of the native objects wrapping environments.
+pub static ENVIRONMENT_TAG : &'static str = "environment";
+
+/// The native object wrapped by an environment value.
+pub struct EnvironmentHandle(usize);
+
+/// An entry in the table of reified environments.
+struct Reified {
+    act: RootedActivationPtr,
+    scope: Scope,
+}
+
+/// A heap's table of reified environments. An entry is removed by a finalizer
+/// when its environment value is collected.
+pub struct ReifiedEnvironments {
+    entries: HashMap<usize, Reified>,
+    next_key: usize,
+}
+
+impl ReifiedEnvironments {
+    /// Create a new, empty table.
+    pub fn new() -> ReifiedEnvironments {
+        ReifiedEnvironments {
+            entries: HashMap::new(),
+            next_key: 0,
+        }
+    }
+
+    /// Get the number of environments in the table.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Remove the environment with the given key.
+    pub fn remove(&mut self, key: usize) {
+        self.entries.remove(&key);
+    }
+}
+
+/// Reify the given activation, whose variables and whose parents' variables
+/// are named by the given scope, as an environment value.
+pub fn reify(heap: &mut Heap,
+             act: &RootedActivationPtr,
+             scope: &Scope) -> Result<RootedValue, SchemeError> {
+    // The environment may outlive the call that created the activation, so a
+    // tail call must not reuse it.
+    let mut act_ptr = **act;
+    act_ptr.capture();
+
+    let key = {
+        let environments = heap.reified_environments();
+        let key = environments.next_key;
+        environments.next_key += 1;
+        environments.entries.insert(key, Reified {
+            act: act.clone(),
+            scope: scope.clone(),
+        });
+        key
+    };
+
+    let env = heap.new_native(ENVIRONMENT_TAG, Box::new(EnvironmentHandle(key)));
+    try!(heap.register_finalizer(&env, Finalizer::Native(Box::new(
+        move |heap: &mut Heap| heap.reified_environments().remove(key)))));
+    Ok(env)
+}
+
+/// Get an environment value for the global top level.
+pub fn global_environment(heap: &mut Heap) -> Result<RootedValue, SchemeError> {
+    let act = heap.global_activation();
+    reify(heap, &act, &vec!())
+}
+
+/// Get the key, activation, and scope of the given environment value. The
+/// name of the primitive asking is used in error messages.
+pub fn reified(heap: &mut Heap,
+               who: &str,
+               env: &RootedValue) -> Result<(usize, RootedActivationPtr, Scope), SchemeError> {
+    let native = try!(env.to_native(heap).ok_or(
+        E0312.error(format!("Error: `{}` expects an environment, found {}", who, **env))));
+    let key = try!(native.downcast_ref::<EnvironmentHandle>()).0;
+    match heap.reified_environments().entries.get(&key) {
+        Some(entry) => Ok((key, entry.act.clone(), entry.scope.clone())),
+        None        => Err(E0312.error(format!("Error: `{}` expects an environment, found {}",
+                                               who, **env))),
+    }
+}
+
+/// Replace the scope of the environment with the given key, after definitions
+/// evaluated in it added variables to its innermost block.
+pub fn set_scope(heap: &mut Heap, key: usize, scope: Scope) {
+    if let Some(entry) = heap.reified_environments().entries.get_mut(&key) {
+        entry.scope = scope;
+    }
+}
+
+/// Get the names and values of the variables visible in the given environment,
+/// innermost first. Shadowed variables and variables that have not been
+/// defined yet are left out.
+pub fn bindings(heap: &mut Heap,
+                env: &RootedValue) -> Result<Vec<(SymbolId, RootedValue)>, SchemeError> {
+    let (_, act, scope) = try!(reified(heap, "environment-bindings", env));
+    let mut seen = HashSet::new();
+    let mut bindings = vec!();
+
+    for (i, block) in scope.iter().rev().enumerate() {
+        for &(name, j) in block.iter() {
+            if seen.insert(name) {
+                if let Ok(val) = act.fetch(heap, i as u32, j) {
+                    bindings.push((name, val));
+                }
+            }
+        }
+    }
+
+    let global = heap.global_activation();
+    let mut globals = heap.environment.globals();
+    globals.sort_by(|a, b| a.1.cmp(&b.1));
+    for (name, j) in globals.into_iter() {
+        if seen.insert(name) {
+            if let Ok(val) = global.fetch(heap, 0, j) {
+                bindings.push((name, val));
+            }
+        }
+    }
+
+    Ok(bindings)
+}
+
+/// Get the value of the variable with the given name in the given
+/// environment, or `None` if it is not bound or has not been defined yet.
+pub fn lookup(heap: &mut Heap,
+              env: &RootedValue,
+              name: &str) -> Result<Option<RootedValue>, SchemeError> {
+    let (_, act, scope) = try!(reified(heap, "environment-ref", env));
+    // A name that was never interned can not be bound anywhere.
+    let id = match heap.environment.interned(name) {
+        Some(id) => id,
+        None     => return Ok(None),
+    };
+
+    for (i, block) in scope.iter().rev().enumerate() {
+        if let Some(&(_, j)) = block.iter().find(|&&(n, _)| n == id) {
+            return Ok(act.fetch(heap, i as u32, j).ok());
+        }
+    }
+
+    let global = heap.global_activation();
+    Ok(match heap.environment.lookup_global(id) {
+        Some(j) => global.fetch(heap, 0, j).ok(),
+        None    => None,
+    })
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environment_intern_same_name() {
+        let mut env = Environment::new();
+        let a = env.intern("foo");
+        let b = env.intern("foo");
+        let c = env.intern("bar");
+        assert_eq!(a, b);
+        assert!(a != c);
+        assert_eq!(env.name(a), "foo");
+        assert_eq!(env.name(c), "bar");
+        assert_eq!(env.interned_count(), 2);
+    }
+
+    #[test]
+    fn test_environment_interned_does_not_intern() {
+        let mut env = Environment::new();
+        assert!(env.interned("foo").is_none());
+        assert_eq!(env.interned_count(), 0);
+        let id = env.intern("foo");
+        assert_eq!(env.interned("foo"), Some(id));
+    }
+
+    #[test]
+    fn test_environment_ids_are_per_environment() {
+        let mut first = Environment::new();
+        let mut second = Environment::new();
+        first.intern("foo");
+        let bar = first.intern("bar");
+        let other_bar = second.intern("bar");
+        assert_eq!(second.interned_count(), 1);
+        assert!(second.interned("foo").is_none());
+        assert_eq!(first.name(bar), second.name(other_bar));
+    }
+
+    #[test]
+    fn test_environment_scope_keeps_ids() {
+        let mut env = Environment::new();
+        let x = env.intern("x");
+        env.extend(vec!(x));
+        let scope = env.local_scope();
+        env.pop();
+        let (_, j) = env.define_global(x);
+        assert_eq!(env.lookup_global_name("x"), Some(j));
+
+        // Analyzing a form in a reified environment must find the same id
+        // for the same name, even after the binding it came from is gone.
+        env.replace_local_scope(scope);
+        let x = env.intern("x");
+        assert_eq!(env.lookup(x), Some((0, 0)));
+        assert_eq!(env.interned_count(), 1);
+    }
+}
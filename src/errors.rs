@@ -0,0 +1,580 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The catalog of errors produced while reading, analyzing, and evaluating
+//! Scheme code.
+//!
+//! Every kind of error has a stable code, such as `E0201`, which is printed
+//! along with its message:
+//!
+//!     [E0201] Error: too many arguments passed: ...
+//!
+//! Each code is an `ErrorCode` static of the same name, and errors are tagged
+//! with their code where they are raised, with `ErrorCode::error`. The code is
+//! kept in its own field of the resulting `SchemeError`, apart from the
+//! message, and is only written in front of the message for display.
+//!
+//! Error messages may be reworded, but codes never change meaning, so they are
+//! what users should search for and what embedders should branch on. Codes are
+//! grouped by the phase that produces them:
+//!
+//! * `E00xx`: read errors
+//! * `E01xx`: static errors found during syntactic analysis
+//! * `E02xx`: errors invoking procedures and evaluating forms
+//! * `E03xx`: errors signalled by primitives
+//! * `E04xx`: errors signalled by Scheme code
+
+use std::error::{FromError};
+use std::fmt;
+
+/// An entry in the error catalog.
+#[derive(Debug, PartialEq)]
+pub struct ErrorCode {
+    /// The stable code identifying this kind of error.
+    pub code: &'static str,
+    /// A longer explanation of what causes this kind of error.
+    pub explanation: &'static str,
+}
+
+impl ErrorCode {
+    /// Create an error of this kind with the given message. Errors are tagged
+    /// where they are raised, which is the only place that knows their kind
+    /// for sure.
+    pub fn error(&'static self, message: String) -> SchemeError {
+        SchemeError {
+            code: Some(self),
+            message: message,
+        }
+    }
+}
+
+/// An error raised while reading, analyzing, or evaluating Scheme code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchemeError {
+    /// The catalog entry for this kind of error, if it is a known kind of
+    /// error.
+    pub code: Option<&'static ErrorCode>,
+    /// The error message, without its code.
+    pub message: String,
+}
+
+impl SchemeError {
+    /// Create an error of no particular kind with the given message.
+    pub fn new(message: String) -> SchemeError {
+        SchemeError {
+            code: None,
+            message: message,
+        }
+    }
+
+    /// Put the given context, such as the location of the form that failed,
+    /// in front of this error's message. The code stays the same.
+    pub fn context(self, context: &str) -> SchemeError {
+        SchemeError {
+            code: self.code,
+            message: format!("{}: {}", context, self.message),
+        }
+    }
+}
+
+impl FromError<String> for SchemeError {
+    fn from_error(message: String) -> SchemeError {
+        SchemeError::new(message)
+    }
+}
+
+impl FromError<SchemeError> for String {
+    /// Interfaces that report errors to people rather than to code, such as
+    /// `eval::evaluate`, report them as strings, with their codes.
+    fn from_error(error: SchemeError) -> String {
+        error.to_string()
+    }
+}
+
+impl fmt::Display for SchemeError {
+    /// Print this error's message, after its code if it has one:
+    ///
+    ///     [E0303] Error: divide by zero
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(code) = self.code {
+            try!(write!(f, "[{}] ", code.code));
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+pub static E0001: ErrorCode = ErrorCode {
+    code: "E0001",
+    explanation: "The reader found a character that cannot begin or \
+                 continue any datum.",
+};
+
+pub static E0002: ErrorCode = ErrorCode {
+    code: "E0002",
+    explanation: "The reader expected a specific character, such as the \
+                 closing parenthesis of a list, but found another \
+                 character or the end of the input.",
+};
+
+pub static E0003: ErrorCode = ErrorCode {
+    code: "E0003",
+    explanation: "The input ended in the middle of a datum.",
+};
+
+pub static E0004: ErrorCode = ErrorCode {
+    code: "E0004",
+    explanation: "A character literal, such as `#\\a`, names no \
+                 character.",
+};
+
+pub static E0005: ErrorCode = ErrorCode {
+    code: "E0005",
+    explanation: "The input ended before the closing `\"` of a string.",
+};
+
+pub static E0006: ErrorCode = ErrorCode {
+    code: "E0006",
+    explanation: "An integer literal is too large or too small to fit in \
+                 a fixnum.",
+};
+
+pub static E0007: ErrorCode = ErrorCode {
+    code: "E0007",
+    explanation: "The input ended before the closing `|#` of a block \
+                 comment. Note that block comments nest, so each `#|` \
+                 needs its own `|#`.",
+};
+
+pub static E0008: ErrorCode = ErrorCode {
+    code: "E0008",
+    explanation: "A decimal literal is malformed, or the `#e` prefix asks \
+                 for an exact number that is not an integer. Only \
+                 integers are exact: remove the prefix to read an \
+                 inexact flonum instead.",
+};
+
+pub static E0009: ErrorCode = ErrorCode {
+    code: "E0009",
+    explanation: "Every element of a `#u8(...)` literal must be an exact \
+                 integer that fits in a byte, such as `#u8(0 127 255)`.",
+};
+
+pub static E0010: ErrorCode = ErrorCode {
+    code: "E0010",
+    explanation: "A datum label reference `#n#` must come after the \
+                 `#n=` that defines it, within the same outermost \
+                 datum. A label may not refer to nothing but itself, as \
+                 in `#0=#0#`.",
+};
+
+pub static E0011: ErrorCode = ErrorCode {
+    code: "E0011",
+    explanation: "Each datum label `#n=` may only be defined once within \
+                 an outermost datum.",
+};
+
+pub static E0012: ErrorCode = ErrorCode {
+    code: "E0012",
+    explanation: "The only directives are `#!fold-case` and \
+                 `#!no-fold-case`, which turn case folding of symbols on \
+                 and off for the rest of the input.",
+};
+
+pub static E0013: ErrorCode = ErrorCode {
+    code: "E0013",
+    explanation: "The input ended before the closing `|` of a symbol \
+                 written between pipes. Write `\\|` for a pipe inside \
+                 the symbol's name.",
+};
+
+pub static E0014: ErrorCode = ErrorCode {
+    code: "E0014",
+    explanation: "A `\\x...;` escape must name a Unicode scalar value: a \
+                 code point up to `10FFFF` that is not a surrogate.",
+};
+
+pub static E0101: ErrorCode = ErrorCode {
+    code: "E0101",
+    explanation: "The form is not a self-evaluating value, a variable \
+                 reference, or a list, and so has no meaning.",
+};
+
+pub static E0102: ErrorCode = ErrorCode {
+    code: "E0102",
+    explanation: "`quote` takes exactly one datum: `(quote datum)`.",
+};
+
+pub static E0103: ErrorCode = ErrorCode {
+    code: "E0103",
+    explanation: "The name being defined by `define` is not a symbol.",
+};
+
+pub static E0104: ErrorCode = ErrorCode {
+    code: "E0104",
+    explanation: "`define` takes a name and a value, `(define name \
+                 value)`, or a procedure signature and a body, `(define \
+                 (name arg ...) body ...)`.",
+};
+
+pub static E0105: ErrorCode = ErrorCode {
+    code: "E0105",
+    explanation: "The variable being assigned by `set!` is not a symbol.",
+};
+
+pub static E0106: ErrorCode = ErrorCode {
+    code: "E0106",
+    explanation: "`set!` takes a variable and a value: `(set! name \
+                 value)`.",
+};
+
+pub static E0107: ErrorCode = ErrorCode {
+    code: "E0107",
+    explanation: "`lambda` takes a list of parameter names and a body: \
+                 `(lambda (arg ...) body ...)`.",
+};
+
+pub static E0108: ErrorCode = ErrorCode {
+    code: "E0108",
+    explanation: "`if` takes a condition, a consequent, and an optional \
+                 alternative.",
+};
+
+pub static E0109: ErrorCode = ErrorCode {
+    code: "E0109",
+    explanation: "A `begin` form or procedure body is not a proper list \
+                 of forms.",
+};
+
+pub static E0110: ErrorCode = ErrorCode {
+    code: "E0110",
+    explanation: "The arguments of a procedure call are not a proper \
+                 list.",
+};
+
+pub static E0111: ErrorCode = ErrorCode {
+    code: "E0111",
+    explanation: "The transformer of a `define-macro` form must evaluate \
+                 to a procedure.",
+};
+
+pub static E0112: ErrorCode = ErrorCode {
+    code: "E0112",
+    explanation: "The name of the macro being defined by `define-macro` \
+                 is not a symbol.",
+};
+
+pub static E0113: ErrorCode = ErrorCode {
+    code: "E0113",
+    explanation: "`define-macro` takes a name and a transformer, or a \
+                 signature and a body.",
+};
+
+pub static E0114: ErrorCode = ErrorCode {
+    code: "E0114",
+    explanation: "The parameters of a `lambda` form are not a proper \
+                 list.",
+};
+
+pub static E0115: ErrorCode = ErrorCode {
+    code: "E0115",
+    explanation: "A parameter of a `lambda` form is not a symbol.",
+};
+
+pub static E0116: ErrorCode = ErrorCode {
+    code: "E0116",
+    explanation: "A call passes more arguments than the lambda literal \
+                 it invokes has parameters. Calls to globals with the wrong \
+                 number of arguments are warned about instead, because the \
+                 global may be redefined before the call is evaluated.",
+};
+
+pub static E0117: ErrorCode = ErrorCode {
+    code: "E0117",
+    explanation: "A call passes fewer arguments than the lambda literal \
+                 it invokes has parameters. Calls to globals with the wrong \
+                 number of arguments are warned about instead, because the \
+                 global may be redefined before the call is evaluated.",
+};
+
+pub static E0118: ErrorCode = ErrorCode {
+    code: "E0118",
+    explanation: "`the-environment` takes no operands: \
+                 `(the-environment)`.",
+};
+
+pub static E0119: ErrorCode = ErrorCode {
+    code: "E0119",
+    explanation: "Forms are nested within each other too deeply to be \
+                 analyzed without overflowing the stack. Long bodies \
+                 and argument lists are fine: only nesting is limited.",
+};
+
+pub static E0201: ErrorCode = ErrorCode {
+    code: "E0201",
+    explanation: "A procedure was called with more arguments than it has \
+                 parameters.",
+};
+
+pub static E0202: ErrorCode = ErrorCode {
+    code: "E0202",
+    explanation: "A procedure was called with fewer arguments than it has \
+                 parameters.",
+};
+
+pub static E0203: ErrorCode = ErrorCode {
+    code: "E0203",
+    explanation: "The operator of a call evaluated to a value which is not \
+                 a procedure.",
+};
+
+pub static E0204: ErrorCode = ErrorCode {
+    code: "E0204",
+    explanation: "A variable was referenced before any value was defined \
+                 for it.",
+};
+
+pub static E0205: ErrorCode = ErrorCode {
+    code: "E0205",
+    explanation: "The heap reached its limit on arenas or bytes, and \
+                 garbage collection could not free enough space.",
+};
+
+pub static E0206: ErrorCode = ErrorCode {
+    code: "E0206",
+    explanation: "The file to evaluate does not exist or could not be \
+                 opened.",
+};
+
+pub static E0207: ErrorCode = ErrorCode {
+    code: "E0207",
+    explanation: "The primitive requires a cargo feature which this build \
+                 of oxischeme was compiled without.",
+};
+
+pub static E0208: ErrorCode = ErrorCode {
+    code: "E0208",
+    explanation: "An improper or cyclic list was given where a proper list \
+                 was required.",
+};
+
+pub static E0209: ErrorCode = ErrorCode {
+    code: "E0209",
+    explanation: "`set!` was used on a global variable before any value \
+                 was defined for it.",
+};
+
+pub static E0210: ErrorCode = ErrorCode {
+    code: "E0210",
+    explanation: "Evaluation with limited fuel ran out of fuel inside a \
+                 call that the interpreter made, such as a call through \
+                 `apply`, which cannot be suspended.",
+};
+
+pub static E0211: ErrorCode = ErrorCode {
+    code: "E0211",
+    explanation: "Evaluation was aborted through the heap's \
+                 `InterruptHandle`, for example by a Ctrl-C handler or a \
+                 watchdog thread.",
+};
+
+pub static E0212: ErrorCode = ErrorCode {
+    code: "E0212",
+    explanation: "A primitive panicked while the heap was catching panics, \
+                 so the panic was converted into an error. This is a bug \
+                 in the primitive, usually one registered by the host.",
+};
+
+pub static E0213: ErrorCode = ErrorCode {
+    code: "E0213",
+    explanation: "A heap image could not be saved or loaded. Foreign \
+                 objects, native objects, and closure primitives belong \
+                 to the host and cannot be saved, and neither can \
+                 procedures with optional or keyword parameters or code \
+                 using `the-environment`. An image can only be loaded \
+                 by a build with the primitives it refers to.",
+};
+
+pub static E0214: ErrorCode = ErrorCode {
+    code: "E0214",
+    explanation: "A generator's `yield` procedure was called after its body \
+                 stopped running, or from within a call made by a \
+                 primitive, which cannot be suspended. Or a generator's \
+                 body asked the generator itself for its next value.",
+};
+
+pub static E0215: ErrorCode = ErrorCode {
+    code: "E0215",
+    explanation: "A procedure with keyword parameters was passed a keyword \
+                 it does not have a parameter for, or a keyword as its \
+                 last argument, with no value after it.",
+};
+
+pub static E0216: ErrorCode = ErrorCode {
+    code: "E0216",
+    explanation: "Non-tail recursion nested deeper than the heap allows, \
+                 which would otherwise overflow the host stack. Make the \
+                 recursion a tail call, use the bytecode execution mode, \
+                 or see `HeapConfig::max_recursion_depth`.",
+};
+
+pub static E0301: ErrorCode = ErrorCode {
+    code: "E0301",
+    explanation: "A primitive was called with the wrong number of \
+                 arguments.",
+};
+
+pub static E0302: ErrorCode = ErrorCode {
+    code: "E0302",
+    explanation: "The result of integer arithmetic does not fit in a \
+                 fixnum. See `arithmetic-mode`.",
+};
+
+pub static E0303: ErrorCode = ErrorCode {
+    code: "E0303",
+    explanation: "A number was divided by zero.",
+};
+
+pub static E0304: ErrorCode = ErrorCode {
+    code: "E0304",
+    explanation: "A numeric primitive was given an argument which is not a \
+                 number.",
+};
+
+pub static E0305: ErrorCode = ErrorCode {
+    code: "E0305",
+    explanation: "A pair accessor or mutator was given an argument which \
+                 is not a pair.",
+};
+
+pub static E0306: ErrorCode = ErrorCode {
+    code: "E0306",
+    explanation: "A foreign object was used after it was released.",
+};
+
+pub static E0307: ErrorCode = ErrorCode {
+    code: "E0307",
+    explanation: "A foreign or native object holds a different type of \
+                 Rust value than the one it was accessed as.",
+};
+
+pub static E0308: ErrorCode = ErrorCode {
+    code: "E0308",
+    explanation: "A foreign object was released more than once.",
+};
+
+pub static E0309: ErrorCode = ErrorCode {
+    code: "E0309",
+    explanation: "Finalizers can only be registered on heap allocated \
+                 objects.",
+};
+
+pub static E0310: ErrorCode = ErrorCode {
+    code: "E0310",
+    explanation: "`profile-report` was called while profiling is \
+                 disabled.",
+};
+
+pub static E0311: ErrorCode = ErrorCode {
+    code: "E0311",
+    explanation: "`arithmetic-mode` only accepts the modes it documents.",
+};
+
+pub static E0312: ErrorCode = ErrorCode {
+    code: "E0312",
+    explanation: "A primitive was given an argument of the wrong type.",
+};
+
+pub static E0313: ErrorCode = ErrorCode {
+    code: "E0313",
+    explanation: "Scheme code called `exit` or `emergency-exit`. This is \
+                 only reported when the host does not end the program \
+                 itself.",
+};
+
+pub static E0314: ErrorCode = ErrorCode {
+    code: "E0314",
+    explanation: "An integer converted from outside Scheme, such as a \
+                 flonum passed to `exact` or a number in serialized data, \
+                 does not fit in a fixnum: fixnums are 61 bits, between \
+                 -2^60 and 2^60 - 1.",
+};
+
+pub static E0401: ErrorCode = ErrorCode {
+    code: "E0401",
+    explanation: "Scheme code signalled an error with `error`.",
+};
+
+pub static E0402: ErrorCode = ErrorCode {
+    code: "E0402",
+    explanation: "An `assert`ed expression evaluated to `#f`.",
+};
+
+/// Every kind of error, in order of code.
+pub static CATALOG: &'static [&'static ErrorCode] = &[
+    &E0001, &E0002, &E0003, &E0004, &E0005, &E0006, &E0007, &E0008, &E0009, &E0010, &E0011, &E0012,
+    &E0013, &E0014, &E0101, &E0102, &E0103, &E0104, &E0105, &E0106, &E0107, &E0108, &E0109, &E0110,
+    &E0111, &E0112, &E0113, &E0114, &E0115, &E0116, &E0117, &E0118, &E0119, &E0201, &E0202, &E0203,
+    &E0204, &E0205, &E0206, &E0207, &E0208, &E0209, &E0210, &E0211, &E0212, &E0213, &E0214, &E0215,
+    &E0216, &E0301, &E0302, &E0303, &E0304, &E0305, &E0306, &E0307, &E0308, &E0309, &E0310, &E0311,
+    &E0312, &E0313, &E0314, &E0401, &E0402,
+];
+
+/// Find the catalog entry with the given code.
+pub fn explain(code: &str) -> Option<&'static ErrorCode> {
+    CATALOG.iter().map(|entry| *entry).find(|entry| entry.code == code)
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_codes_are_unique() {
+        for (i, entry) in CATALOG.iter().enumerate() {
+            assert!(CATALOG[i + 1..].iter().all(|other| other.code != entry.code),
+                    "Duplicate error code: {}", entry.code);
+        }
+    }
+
+    #[test]
+    fn test_errors_codes_are_fields() {
+        let error = E0301.error("Error: bad arguments to `car`".to_string());
+        assert_eq!(error.code.map(|entry| entry.code), Some("E0301"));
+        assert_eq!(error.message, "Error: bad arguments to `car`");
+
+        // Messages are never guessed at, however familiar they look.
+        let error = SchemeError::new("Error: divide by zero".to_string());
+        assert!(error.code.is_none());
+        let error = SchemeError::new("[E0303] Error: divide by zero".to_string());
+        assert!(error.code.is_none());
+        let error = E0401.error("ERROR! [E0303] Error: divide by zero".to_string());
+        assert_eq!(error.code.map(|entry| entry.code), Some("E0401"));
+
+        let error = E0001.error("Unexpected character: )".to_string()).context("f.scm:1:2");
+        assert_eq!(error.code.map(|entry| entry.code), Some("E0001"));
+        assert_eq!(error.message, "f.scm:1:2: Unexpected character: )");
+    }
+
+    #[test]
+    fn test_errors_display() {
+        let error = E0303.error("Error: divide by zero".to_string());
+        assert_eq!(format!("{}", error), "[E0303] Error: divide by zero");
+        let error = SchemeError::new("Error: divide by zero".to_string());
+        assert_eq!(format!("{}", error), "Error: divide by zero");
+        assert_eq!(explain("E0303").map(|entry| entry.explanation),
+                   Some("A number was divided by zero."));
+    }
+}
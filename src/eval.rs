@@ -64,31 +64,145 @@
 
 extern crate test;
 
+use std::cell::{Cell};
 use std::cmp::{Ordering};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::hash;
-
-use environment::{Activation, RootedActivationPtr};
+use std::hash::{self, Hash, Hasher, SipHasher};
+use std::mem;
+use std::rc::{Rc};
+use std::thread::{Thread};
+use time;
+
+use environment::{self, Activation, Environment, RootedActivationPtr, Scope, SymbolId};
+use errors::{E0101, E0102, E0103, E0104, E0105, E0106, E0107, E0108, E0109, E0110, E0111, E0112,
+             E0113, E0114, E0115, E0116, E0117, E0118, E0119, E0201, E0202, E0203, E0204, E0205,
+             E0206, E0209, E0210, E0211, E0212, E0215, ErrorCode, SchemeError};
 use heap::{Heap, Rooted};
+use primitives::{self, ArithmeticMode, FastPath};
 use read::{Location};
-use value::{RootedValue, SchemeResult, Value};
+use value::{self, Primitive, Procedure, ProcedurePtr, RootedValue, SchemeResult, Value};
+use vm::{self, ExecutionMode};
 
 /// Evaluate the given form in the global environment.
-pub fn evaluate(heap: &mut Heap, form: &RootedValue, location: Location) -> SchemeResult {
-    let meaning = try!(analyze(heap, form, location));
+pub fn evaluate(heap: &mut Heap,
+                form: &RootedValue,
+                location: Location) -> Result<RootedValue, String> {
+    evaluate_with_backtrace(heap, form, location).map_err(|e| e.render(heap))
+}
+
+/// Evaluate the given form in the global environment. On failure, return a
+/// structured `EvalError` describing the state of the call stack at the time of
+/// the error.
+pub fn evaluate_with_backtrace(heap: &mut Heap,
+                               form: &RootedValue,
+                               location: Location) -> Result<RootedValue, EvalError> {
+    if let Err(error) = ensure_prelude(heap) {
+        return Err(EvalError::new(error));
+    }
+
+    let meaning = match analyze_cached(heap, form, location) {
+        Ok(m) => m,
+        Err(error) => return Err(EvalError::new(error)),
+    };
+    evaluate_meaning(heap, &meaning)
+}
+
+/// Evaluate the given meaning of a top-level form in the global environment,
+/// such as one from a compiled script. On failure, return a structured
+/// `EvalError` like `evaluate_with_backtrace` does.
+pub fn evaluate_meaning(heap: &mut Heap, meaning: &Meaning) -> Result<RootedValue, EvalError> {
+    let meaning = if heap.is_flat_closures() {
+        flatten_closures(meaning)
+    } else {
+        meaning.clone()
+    };
+
     let mut act = heap.global_activation();
-    meaning.evaluate(heap, &mut act)
+    let depth = heap.stack_depth();
+    heap.take_error_location();
+    heap.take_error_irritants();
+
+    // The debugger hooks into the meaning interpreter, so we only use the VM
+    // when not debugging.
+    let result = match heap.execution_mode() {
+        ExecutionMode::Bytecode if !heap.is_debugging() => {
+            vm::evaluate(heap, &meaning, &act)
+        },
+        _ => meaning.evaluate(heap, &mut act),
+    };
+
+    match result {
+        Ok(val) => Ok(val),
+        Err(error) => {
+            let backtrace = heap.backtrace_since(depth);
+            heap.unwind_to(depth);
+            Err(EvalError {
+                code: error.code,
+                message: error.message,
+                location: heap.take_error_location(),
+                irritants: heap.take_error_irritants(),
+                backtrace: backtrace,
+            })
+        },
+    }
+}
+
+/// Evaluate the given form in the global environment on the VM, until it
+/// finishes or the heap runs out of fuel. See `Heap::evaluate_with_fuel`.
+pub fn evaluate_with_fuel(heap: &mut Heap,
+                          form: &RootedValue,
+                          location: Location) -> Result<vm::Evaluation, SchemeError> {
+    let meaning = try!(analyze(heap, form, location));
+    let meaning = if heap.is_flat_closures() {
+        flatten_closures(&meaning)
+    } else {
+        meaning
+    };
+
+    let act = heap.global_activation();
+    heap.take_error_location();
+    vm::evaluate_with_fuel(heap, &meaning, &act)
+}
+
+/// Evaluate the given form in the given first-class environment, as `eval`
+/// does. Definitions add variables to the environment's innermost block, or
+/// define globals in the global environment.
+pub fn evaluate_in_environment(heap: &mut Heap,
+                               form: &RootedValue,
+                               env: &RootedValue) -> TrampolineResult {
+    try!(ensure_prelude(heap));
+
+    let (key, act, scope) = try!(environment::reified(heap, "eval", env));
+    let is_global = scope.is_empty();
+    let location = match form.to_pair(heap) {
+        Some(pair) => heap.locate(&pair),
+        None       => Location::unknown(),
+    };
+
+    let outer = heap.environment.replace_local_scope(scope);
+    let result = analyze(heap, form, location);
+    let scope = heap.environment.replace_local_scope(outer);
+    environment::set_scope(heap, key, scope);
+
+    let meaning = try!(result);
+    let meaning = if is_global && heap.is_flat_closures() {
+        flatten_closures(&meaning)
+    } else {
+        meaning
+    };
+    Ok(Trampoline::Thunk(act, meaning))
 }
 
 /// Evaluate the file at the given path and return the value of the last form.
-pub fn evaluate_file(heap: &mut Heap, file_path: &str) -> SchemeResult {
+pub fn evaluate_file(heap: &mut Heap, file_path: &str) -> Result<RootedValue, String> {
     use read::read_from_file;
     let reader = match read_from_file(file_path, heap) {
         Ok(r) => r,
         Err(e) => {
-            return Err(format!("Error: evaluate_file could not read {}: {}",
-                               file_path,
-                               e));
+            return Err(E0206.error(format!("Error: evaluate_file could not read {}: {}",
+                                           file_path,
+                                           e)).to_string());
         },
     };
 
@@ -101,6 +215,155 @@ pub fn evaluate_file(heap: &mut Heap, file_path: &str) -> SchemeResult {
     return Ok(result);
 }
 
+/// A frame in the Scheme call stack: the name of the procedure being invoked
+/// and the location of the call site.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// The name of the invoked procedure.
+    pub name: String,
+    /// The location of the invocation.
+    pub location: Location,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.name, self.location)
+    }
+}
+
+/// An error that occurred during evaluation, along with a backtrace of the
+/// Scheme call stack at the time the error occurred.
+#[derive(Clone, Debug)]
+pub struct EvalError {
+    /// The catalog entry for this kind of error from the `errors` catalog, if
+    /// it is a known kind of error.
+    pub code: Option<&'static ErrorCode>,
+    /// The error message, without its code.
+    pub message: String,
+    /// The location of the innermost form whose evaluation failed, if known.
+    pub location: Option<Location>,
+    /// The values passed to `error` along with the message, if the error was
+    /// signalled by Scheme code.
+    pub irritants: Vec<RootedValue>,
+    /// The call stack at the time of the error, youngest frame first. Frames
+    /// for tail calls are not retained.
+    pub backtrace: Vec<Frame>,
+}
+
+impl EvalError {
+    /// Create a new `EvalError` from the given error, with no location or
+    /// backtrace.
+    pub fn new(error: SchemeError) -> EvalError {
+        EvalError {
+            code: error.code,
+            message: error.message,
+            location: None,
+            irritants: vec!(),
+            backtrace: vec!(),
+        }
+    }
+
+    /// Get a `SchemeError` with this error's code, and its message after its
+    /// location, if known. The backtrace is left out.
+    pub fn to_scheme_error(&self) -> SchemeError {
+        let error = SchemeError {
+            code: self.code,
+            message: self.message.clone(),
+        };
+        match self.location {
+            Some(ref location) => error.context(format!("{}", location).as_slice()),
+            None               => error,
+        }
+    }
+
+    /// Describe this error as its `Display` implementation does, and show the
+    /// line of source it occurred on below its location, if that source is
+    /// available.
+    pub fn render(&self, heap: &Heap) -> String {
+        let snippet = self.location.as_ref().and_then(|location| render_snippet(heap, location));
+        self.describe(snippet)
+    }
+
+    /// Describe this error, with the given snippet of source after its
+    /// location.
+    fn describe(&self, snippet: Option<String>) -> String {
+        let mut out = String::new();
+        if let Some(ref location) = self.location {
+            out.push_str(format!("{}:\n", location).as_slice());
+            if let Some(snippet) = snippet {
+                out.push_str(snippet.as_slice());
+            }
+        }
+        if let Some(entry) = self.code {
+            out.push_str(format!("[{}] ", entry.code).as_slice());
+        }
+        out.push_str(self.message.as_slice());
+
+        if !self.backtrace.is_empty() {
+            out.push_str("\nBacktrace:");
+            for (i, frame) in self.backtrace.iter().enumerate() {
+                out.push_str(format!("\n    {}: {}", i, frame).as_slice());
+            }
+        }
+
+        out
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.describe(None))
+    }
+}
+
+/// Render the line of source the given location is on, with a caret under the
+/// location's column, if the source is available:
+///
+///     3 | (car '())
+///       | ^
+pub fn render_snippet(heap: &Heap, location: &Location) -> Option<String> {
+    heap.source_line(location).map(|line| {
+        let number = location.line.to_string();
+        let gutter : String = number.chars().map(|_| ' ').collect();
+        // Keep tabs, so that the caret lines up however wide they are shown.
+        let indent : String = line.chars()
+            .take((location.column - 1) as usize)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!("    {} | {}\n    {} | {}^\n", number, line, gutter, indent)
+    })
+}
+
+/// A warning about suspicious code found during syntactic analysis, such as a
+/// parameter that is never used. Unlike errors, warnings do not stop the code
+/// from being evaluated. See `Heap::take_warnings`.
+#[derive(Clone, Debug)]
+pub struct Warning {
+    /// The warning message.
+    pub message: String,
+    /// The location of the suspicious code.
+    pub location: Location,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: Warning: {}", self.location, self.message)
+    }
+}
+
+/// What evaluation should do after the debugger has been invoked.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DebugAction {
+    /// Run until the next breakpoint.
+    Continue,
+    /// Stop again before evaluating the next form.
+    Step,
+}
+
+/// A debugger callback, invoked with the current activation and the meaning
+/// about to be evaluated whenever a breakpoint is hit or while stepping.
+pub type Debugger = Box<FnMut(&mut Heap, &RootedActivationPtr, &Meaning) -> DebugAction + Send>;
+
 /// To optimize tail calls and eliminate the stack frames that would otherwise
 /// be used by them, we trampoline thunks in a loop and encode that process in
 /// this type.
@@ -111,8 +374,21 @@ pub enum Trampoline {
 }
 
 impl Trampoline {
-    /// Keep evaluating thunks until it yields a value.
+    /// Keep evaluating thunks until it yields a value. Primitives that call
+    /// back into Scheme run their thunks with this, so it counts towards the
+    /// maximum recursion depth.
     pub fn run(self, heap: &mut Heap) -> SchemeResult {
+        try!(heap.enter_recursion());
+        let depth = heap.stack_depth();
+        let result = self.run_from(heap, depth);
+        heap.exit_recursion();
+        result
+    }
+
+    /// Keep evaluating thunks until it yields a value. Call stack frames pushed
+    /// above the given depth by tail calls are replaced by the frame for the
+    /// next tail call, so that they do not accumulate.
+    fn run_from(self, heap: &mut Heap, depth: usize) -> SchemeResult {
         match self {
             Trampoline::Value(v) => {
                 return Ok(v);
@@ -121,6 +397,15 @@ impl Trampoline {
                 let mut a = act;
                 let mut m = meaning;
                 loop {
+                    // The interpreter keeps its state on the Rust stack, so it
+                    // can't be suspended like the VM can.
+                    if !heap.consume_fuel() {
+                        return Err(E0210.error("Error: out of fuel".to_string()));
+                    }
+                    if heap.take_interrupt() {
+                        return Err(E0211.error("Error: interrupted".to_string()));
+                    }
+                    heap.elide_tail_frames(depth);
                     match try!(m.evaluate_to_thunk(heap, &mut a)) {
                         Trampoline::Value(v) => {
                             return Ok(v);
@@ -136,17 +421,118 @@ impl Trampoline {
     }
 }
 
-/// Either a `Trampoline`, or a `String` describing the error.
-pub type TrampolineResult = Result<Trampoline, String>;
+/// Either a `Trampoline`, or a `SchemeError` describing the error.
+pub type TrampolineResult = Result<Trampoline, SchemeError>;
+
+/// The optional and keyword parameters of a lambda, after Guile's `lambda*`:
+///
+///     (lambda (a #:optional b (c 2) #:key (d 3)) ...)
+///
+/// Every parameter has a binding in the procedure's activation: first the
+/// required parameters, then the optional ones, and then the keyword ones. The
+/// bindings of arguments that were not passed hold the default object, until
+/// the start of the procedure body gives them their default values.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Signature {
+    /// The number of required parameters.
+    pub required: u32,
+    /// The number of optional parameters.
+    pub optional: u32,
+    /// The keywords of the keyword parameters, including their `#:` prefix.
+    pub keywords: Vec<String>,
+}
+
+impl Signature {
+    /// Get the total number of parameters.
+    pub fn arity(&self) -> u32 {
+        self.required + self.optional + self.keywords.len() as u32
+    }
+
+    /// Arrange the given arguments into one value per parameter, in the order
+    /// of the parameters' bindings. Optional arguments are passed positionally
+    /// after the required ones, and keyword arguments as a keyword followed by
+    /// a value, after the optional ones.
+    fn bind(&self,
+            heap: &mut Heap,
+            procedure: &Procedure,
+            args: Vec<RootedValue>) -> Result<Vec<RootedValue>, SchemeError> {
+        let num_args = args.len();
+        if num_args < self.required as usize {
+            return Err(E0202.error(format!("Error: too few arguments passed: {}",
+                                           arity_mismatch(procedure, num_args))));
+        }
+
+        let default = heap.default_object();
+        let mut bound = Vec::with_capacity(self.arity() as usize);
+        let mut args = args.into_iter().peekable();
+        for _ in 0..self.required {
+            bound.push(args.next().unwrap());
+        }
+        for _ in 0..self.optional {
+            let is_keyword = match args.peek() {
+                Some(arg) => !self.keywords.is_empty() && is_keyword(&**arg),
+                None      => true,
+            };
+            bound.push(if is_keyword { default.clone() } else { args.next().unwrap() });
+        }
+
+        let start = bound.len();
+        for _ in 0..self.keywords.len() {
+            bound.push(default.clone());
+        }
+        while let Some(arg) = args.next() {
+            if self.keywords.is_empty() {
+                return Err(E0201.error(format!("Error: too many arguments passed: {}",
+                                               arity_mismatch(procedure, num_args))));
+            }
+            let index = try!(self.keywords.iter().position(|k| is_keyword_named(&*arg, k))
+                .ok_or_else(|| E0215.error(format!(
+                    "Error: unexpected keyword argument {} passed to {}",
+                    *arg,
+                    describe_procedure(&procedure.name, &procedure.location)))));
+            let val = try!(args.next().ok_or_else(|| {
+                E0215.error(format!("Error: keyword argument {} passed to {} without a value",
+                                    *arg,
+                                    describe_procedure(&procedure.name, &procedure.location)))
+            }));
+            bound[start + index] = val;
+        }
+
+        Ok(bound)
+    }
+}
+
+/// Return true if the given value is a keyword, such as `#:key`, false
+/// otherwise.
+pub fn is_keyword(val: &Value) -> bool {
+    match *val {
+        Value::Symbol(sym) => sym.starts_with("#:"),
+        _                  => false,
+    }
+}
+
+/// Return true if the given value is the keyword with the given name, including
+/// its `#:` prefix, false otherwise.
+fn is_keyword_named(val: &Value, keyword: &str) -> bool {
+    match *val {
+        Value::Symbol(sym) => *sym == keyword,
+        _                  => false,
+    }
+}
 
 /// The set of data generated by our syntactic analysis pretreatment.
-#[derive(Clone, Hash, Debug)]
-enum MeaningData {
+#[derive(Clone, Hash)]
+pub enum MeaningData {
     /// The quoted value.
     Quotation(RootedValue),
 
     /// A reference to (i'th activation, j'th binding, original name).
-    Reference(u32, u32, String),
+    Reference(u32, u32, SymbolId),
+
+    /// A reference to the j'th binding of the global activation, which is held
+    /// directly rather than found by walking up the activation chain: (the
+    /// global activation, j'th binding, original name).
+    GlobalReference(RootedActivationPtr, u32, SymbolId),
 
     /// Push a new binding to the current activation with the value of the given
     /// meaning.
@@ -163,11 +549,249 @@ enum MeaningData {
     /// evaluating and returning the second meaning.
     Sequence(Meaning, Meaning),
 
-    /// Arity and body.
-    Lambda(u32, Meaning),
+    /// Arity, body, name (if it was defined with one), the location it was
+    /// defined at, and its optional and keyword parameters, if it has any.
+    Lambda(u32, Meaning, Option<String>, Location, Option<Rc<Signature>>),
+
+    /// A lambda whose closure only holds the free variables its body uses,
+    /// rather than the whole enclosing activation chain. Arity, body, name,
+    /// location, the `(i, j)` coordinates of the captured variables where the
+    /// closure is created, and its optional and keyword parameters. The body
+    /// sees the captured variables at `(c, k)` for the k'th capture, where `c`
+    /// is its own depth, and the global activation just beyond them.
+    FlatLambda(u32, Meaning, Option<String>, Location, Vec<(u32, u32, SymbolId)>,
+               Option<Rc<Signature>>),
+
+    /// The current activation, reified as a first-class environment by
+    /// `(the-environment)`, and the names of the variables of the lexical
+    /// blocks enclosing it.
+    TheEnvironment(Rc<Scope>),
+
+    /// Procedure, parameters, and the location of the call site.
+    Invocation(Meaning, Vec<Meaning>, Location),
+
+    /// An invocation of a lambda literal, such as `let` expands to, which binds
+    /// the parameters in a new activation extending the current one and
+    /// evaluates the lambda's body there, without creating a closure:
+    /// parameters, the lambda's body, and the location of the call site.
+    Let(Vec<Meaning>, Meaning, Location),
+
+    /// Invocations with zero to three parameters, which are the vast majority
+    /// of calls, store their parameters inline rather than in a `Vec`.
+    Invocation0(Meaning, Location),
+    Invocation1(Meaning, Meaning, Location),
+    Invocation2(Meaning, Meaning, Meaning, Location),
+    Invocation3(Meaning, Meaning, Meaning, Meaning, Location),
+
+    /// An invocation of a small global procedure whose body was substituted
+    /// in at the call site: the reference to the procedure, parameters, the
+    /// procedure value the body was taken from, the inlined body, and the
+    /// location of the call site.
+    InlinedInvocation(Meaning, Vec<Meaning>, RootedValue, Meaning, Location),
+
+    /// An invocation of a global that held a primitive with a fast path when
+    /// analyzed: the reference to the primitive, the primitive value, its fast
+    /// path, parameters, and the location of the call site.
+    PrimitiveInvocation(Meaning, RootedValue, FastPath, Vec<Meaning>, Location),
+}
+
+impl MeaningData {
+    /// Get the name of this kind of meaning.
+    fn kind(&self) -> &'static str {
+        match *self {
+            MeaningData::Quotation(_)     => "quotation",
+            MeaningData::Reference(..)    => "reference",
+            MeaningData::GlobalReference(..) => "global-reference",
+            MeaningData::TheEnvironment(_) => "the-environment",
+            MeaningData::Definition(..)   => "definition",
+            MeaningData::SetVariable(..)  => "set-variable",
+            MeaningData::Conditional(..)  => "conditional",
+            MeaningData::Sequence(..)     => "sequence",
+            MeaningData::Lambda(..)       => "lambda",
+            MeaningData::FlatLambda(..)   => "flat-lambda",
+            MeaningData::Invocation(..)   |
+            MeaningData::Invocation0(..)  |
+            MeaningData::Invocation1(..)  |
+            MeaningData::Invocation2(..)  |
+            MeaningData::Invocation3(..)  => "invocation",
+            MeaningData::Let(..)          => "let",
+            MeaningData::InlinedInvocation(..) => "inlined-invocation",
+            MeaningData::PrimitiveInvocation(..) => "primitive-invocation",
+        }
+    }
+
+    /// Get the sub-meanings this meaning directly contains, in evaluation
+    /// order.
+    fn children(&self) -> Vec<&Meaning> {
+        match *self {
+            MeaningData::Quotation(_)       |
+            MeaningData::Reference(..)      |
+            MeaningData::GlobalReference(..)      |
+            MeaningData::TheEnvironment(_)                        => vec!(),
+            MeaningData::Definition(_, _, ref val) |
+            MeaningData::SetVariable(_, _, ref val)                => vec!(val),
+            MeaningData::Lambda(_, ref body, _, _, _)              |
+            MeaningData::FlatLambda(_, ref body, _, _, _, _)       => vec!(body),
+            MeaningData::Sequence(ref first, ref second)           => vec!(first, second),
+            MeaningData::Conditional(ref condition,
+                                     ref consequent,
+                                     ref alternative)              => {
+                vec!(condition, consequent, alternative)
+            },
+            MeaningData::Invocation0(ref procedure, _)             => vec!(procedure),
+            MeaningData::Invocation1(ref procedure, ref a, _)      => vec!(procedure, a),
+            MeaningData::Invocation2(ref procedure, ref a, ref b, _) => vec!(procedure, a, b),
+            MeaningData::Invocation3(ref procedure, ref a, ref b, ref c, _) => {
+                vec!(procedure, a, b, c)
+            },
+            MeaningData::Invocation(ref procedure, ref params, _) |
+            MeaningData::PrimitiveInvocation(ref procedure, _, _, ref params, _) => {
+                let mut children = vec!(procedure);
+                children.extend(params.iter());
+                children
+            },
+            MeaningData::Let(ref params, ref body, _) => {
+                let mut children : Vec<&Meaning> = params.iter().collect();
+                children.push(body);
+                children
+            },
+            MeaningData::InlinedInvocation(ref procedure, ref params, _, ref body, _) => {
+                let mut children = vec!(procedure);
+                children.extend(params.iter());
+                children.push(body);
+                children
+            },
+        }
+    }
+
+    /// Get the number of sub-meanings this meaning directly contains.
+    fn child_count(&self) -> usize {
+        self.children().len()
+    }
+
+    /// Describe this meaning, not including its sub-meanings, as a single line
+    /// of disassembly. Variables' names are found in the given environment.
+    fn label(&self, env: &Environment) -> String {
+        match *self {
+            MeaningData::Quotation(ref val) => format!("quotation {}", **val),
+            MeaningData::Reference(i, j, name) => {
+                format!("reference {} ({}, {})", env.name(name), i, j)
+            },
+            MeaningData::GlobalReference(_, j, name) => {
+                format!("global-reference {} {}", env.name(name), j)
+            },
+            MeaningData::TheEnvironment(_) => "the-environment".to_string(),
+            MeaningData::Definition(i, j, _) => format!("definition ({}, {})", i, j),
+            MeaningData::SetVariable(i, j, _) => format!("set-variable ({}, {})", i, j),
+            MeaningData::Lambda(arity, _, ref name, _, _) => {
+                format!("lambda {} {}",
+                        name.as_ref().map(|n| n.as_slice()).unwrap_or("<anonymous>"),
+                        arity)
+            },
+            MeaningData::FlatLambda(arity, _, ref name, _, ref captures, _) => {
+                let captured: Vec<String> = captures.iter()
+                    .map(|&(_, _, name)| env.name(name).to_string())
+                    .collect();
+                format!("flat-lambda {} {} [{}]",
+                        name.as_ref().map(|n| n.as_slice()).unwrap_or("<anonymous>"),
+                        arity,
+                        captured.connect(" "))
+            },
+            _ => self.kind().to_string(),
+        }
+    }
+
+    /// Return true if this meaning and the other are the same kind of meaning
+    /// with the same immediate data, not considering sub-meanings or source
+    /// locations.
+    fn shallow_eq(&self, other: &MeaningData) -> bool {
+        match (self, other) {
+            (&MeaningData::Quotation(ref a), &MeaningData::Quotation(ref b)) => {
+                values_structurally_eq(&**a, &**b)
+            },
+            (&MeaningData::Reference(i, j, ref a), &MeaningData::Reference(k, l, ref b)) => {
+                i == k && j == l && a == b
+            },
+            (&MeaningData::GlobalReference(_, j, ref a),
+             &MeaningData::GlobalReference(_, l, ref b)) => {
+                j == l && a == b
+            },
+            (&MeaningData::TheEnvironment(ref a), &MeaningData::TheEnvironment(ref b)) => {
+                a == b
+            },
+            (&MeaningData::Definition(i, j, _), &MeaningData::Definition(k, l, _)) |
+            (&MeaningData::SetVariable(i, j, _), &MeaningData::SetVariable(k, l, _)) => {
+                i == k && j == l
+            },
+            (&MeaningData::Lambda(a, _, ref m, _, ref s),
+             &MeaningData::Lambda(b, _, ref n, _, ref t)) => {
+                a == b && m == n && s == t
+            },
+            (&MeaningData::FlatLambda(a, _, ref m, _, ref x, ref s),
+             &MeaningData::FlatLambda(b, _, ref n, _, ref y, ref t)) => {
+                a == b && m == n && x == y && s == t
+            },
+            (&MeaningData::Conditional(..), &MeaningData::Conditional(..)) |
+            (&MeaningData::Sequence(..), &MeaningData::Sequence(..))       => true,
+            (&MeaningData::Invocation(_, ref a, _),
+             &MeaningData::Invocation(_, ref b, _))                          => {
+                a.len() == b.len()
+            },
+            (&MeaningData::Invocation0(..), &MeaningData::Invocation0(..)) |
+            (&MeaningData::Invocation1(..), &MeaningData::Invocation1(..)) |
+            (&MeaningData::Invocation2(..), &MeaningData::Invocation2(..)) |
+            (&MeaningData::Invocation3(..), &MeaningData::Invocation3(..)) => true,
+            (&MeaningData::Let(ref a, _, _), &MeaningData::Let(ref b, _, _)) => {
+                a.len() == b.len()
+            },
+            (&MeaningData::InlinedInvocation(_, ref a, ref x, _, _),
+             &MeaningData::InlinedInvocation(_, ref b, ref y, _, _))         => {
+                a.len() == b.len() && **x == **y
+            },
+            (&MeaningData::PrimitiveInvocation(_, _, f, ref a, _),
+             &MeaningData::PrimitiveInvocation(_, _, g, ref b, _))         => {
+                f == g && a.len() == b.len()
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Compare two quoted values structurally: pairs are equal if their cars and
+/// cdrs are, and strings are equal if their contents are. Long lists are
+/// compared iteratively.
+fn values_structurally_eq(a: &Value, b: &Value) -> bool {
+    let mut pending = vec!((*a, *b));
+    while let Some(values) = pending.pop() {
+        match values {
+            (Value::Pair(x), Value::Pair(y)) => {
+                if x != y {
+                    pending.push((x.unrooted_cdr(), y.unrooted_cdr()));
+                    pending.push((x.unrooted_car(), y.unrooted_car()));
+                }
+            },
+            (Value::String(x), Value::String(y)) => {
+                if *x != *y {
+                    return false;
+                }
+            },
+            (x, y) => {
+                if x != y {
+                    return false;
+                }
+            },
+        }
+    }
+    true
+}
 
-    /// Procedure and parameters.
-    Invocation(Meaning, Vec<Meaning>),
+/// `Debug` only summarizes a `MeaningData`, rather than printing the whole tree
+/// beneath it, so that it is usable in logs for large programs. Use
+/// `disassemble` to get a full dump.
+impl fmt::Debug for MeaningData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MeaningData({}, {} children)", self.kind(), self.child_count())
+    }
 }
 
 impl fmt::Display for MeaningData {
@@ -176,9 +800,15 @@ impl fmt::Display for MeaningData {
             MeaningData::Quotation(ref val) => {
                 write!(f, "(quotation {})", **val)
             },
-            MeaningData::Reference(i, j, ref name) => {
+            MeaningData::Reference(i, j, name) => {
                 write!(f, "(reference {} {} {})", i, j, name)
             },
+            MeaningData::GlobalReference(_, j, name) => {
+                write!(f, "(global-reference {} {})", j, name)
+            },
+            MeaningData::TheEnvironment(_) => {
+                write!(f, "(the-environment)")
+            },
             MeaningData::Definition(i, j, ref val) => {
                 write!(f, "(definition {} {} {})", i, j, val)
             },
@@ -196,11 +826,52 @@ impl fmt::Display for MeaningData {
             MeaningData::Sequence(ref first, ref second) => {
                 write!(f, "(sequence {} {})", first, second)
             },
-            MeaningData::Lambda(arity, ref body) => {
+            MeaningData::Lambda(arity, ref body, _, _, _) => {
                 write!(f, "(lambda {} {})", arity, body)
             },
-            MeaningData::Invocation(ref procedure, ref arguments) => {
-                try!(write!(f, "(invocation {} [", procedure));
+            MeaningData::FlatLambda(arity, ref body, _, _, ref captures, _) => {
+                try!(write!(f, "(flat-lambda {} [", arity));
+                let mut is_first = true;
+                for &(i, j, name) in captures.iter() {
+                    try!(write!(f, "{}({} {} {})", if is_first { "" } else { " " }, i, j, name));
+                    is_first = false;
+                }
+                write!(f, "] {})", body)
+            },
+            MeaningData::Invocation(..)  |
+            MeaningData::Invocation0(..) |
+            MeaningData::Invocation1(..) |
+            MeaningData::Invocation2(..) |
+            MeaningData::Invocation3(..) => {
+                let children = self.children();
+                try!(write!(f, "(invocation {} [", children[0]));
+                let mut is_first = true;
+                for arg in children[1..].iter() {
+                    try!(write!(f, "{}{}", if is_first { "" } else { " " }, arg));
+                    is_first = false;
+                }
+                write!(f, "])")
+            },
+            MeaningData::Let(ref arguments, ref body, _) => {
+                try!(write!(f, "(let ["));
+                let mut is_first = true;
+                for arg in arguments.iter() {
+                    try!(write!(f, "{}{}", if is_first { "" } else { " " }, arg));
+                    is_first = false;
+                }
+                write!(f, "] {})", body)
+            },
+            MeaningData::InlinedInvocation(ref procedure, ref arguments, _, ref body, _) => {
+                try!(write!(f, "(inlined-invocation {} [", procedure));
+                let mut is_first = true;
+                for arg in arguments.iter() {
+                    try!(write!(f, "{}{}", if is_first { "" } else { " " }, arg));
+                    is_first = false;
+                }
+                write!(f, "] {})", body)
+            },
+            MeaningData::PrimitiveInvocation(ref procedure, _, _, ref arguments, _) => {
+                try!(write!(f, "(primitive-invocation {} [", procedure));
                 let mut is_first = true;
                 for arg in arguments.iter() {
                     try!(write!(f, "{}{}", if is_first { "" } else { " " }, arg));
@@ -235,12 +906,37 @@ fn evaluate_quotation(heap: &mut Heap,
     panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
 }
 
+fn evaluate_the_environment(heap: &mut Heap,
+                            data: &MeaningData,
+                            act: &mut RootedActivationPtr) -> TrampolineResult {
+    if let MeaningData::TheEnvironment(ref scope) = *data {
+        return Ok(Trampoline::Value(try!(environment::reify(heap, act, &**scope))));
+    }
+
+    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+}
+
 fn evaluate_reference(heap: &mut Heap,
                       data: &MeaningData,
                       act: &mut RootedActivationPtr) -> TrampolineResult {
-    if let MeaningData::Reference(i, j, ref name) = *data {
+    if let MeaningData::Reference(i, j, name) = *data {
         let val = try!(act.fetch(heap, i, j).ok().ok_or(
-            format!("Reference to variable that hasn't been defined: {}", name)));
+            E0204.error(format!("Reference to variable that hasn't been defined: {}",
+                                heap.environment.name(name)))));
+        return Ok(Trampoline::Value(val));
+    }
+
+    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+}
+
+#[allow(unused_variables)]
+fn evaluate_global_reference(heap: &mut Heap,
+                             data: &MeaningData,
+                             act: &mut RootedActivationPtr) -> TrampolineResult {
+    if let MeaningData::GlobalReference(ref global_act, j, name) = *data {
+        let val = try!(global_act.fetch(heap, 0, j).ok().ok_or(
+            E0204.error(format!("Reference to variable that hasn't been defined: {}",
+                                heap.environment.name(name)))));
         return Ok(Trampoline::Value(val));
     }
 
@@ -268,7 +964,7 @@ fn evaluate_set_variable(heap: &mut Heap,
     if let MeaningData::SetVariable(i, j, ref definition_value_meaning) = *data {
         let val = try!(definition_value_meaning.evaluate(heap, act));
         try!(act.update(i, j, &val).ok().ok_or(
-            "Cannot set variable before it has been defined".to_string()));
+            E0209.error("Cannot set variable before it has been defined".to_string())));
         return Ok(Trampoline::Value(heap.unspecified_symbol()));
     }
 
@@ -307,310 +1003,1638 @@ fn evaluate_sequence(heap: &mut Heap,
 fn evaluate_lambda(heap: &mut Heap,
                    data: &MeaningData,
                    act: &mut RootedActivationPtr) -> TrampolineResult {
-    if let MeaningData::Lambda(arity, ref body) = *data {
-        return Ok(Trampoline::Value(
-            Value::new_procedure(heap, arity, act, (*body).clone())));
+    match *data {
+        MeaningData::Lambda(..) | MeaningData::FlatLambda(..) => {
+            return make_closure(heap, data, act).map(Trampoline::Value);
+        },
+        _ => { },
     }
 
     panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
 }
 
+/// Create the procedure for a `Lambda` or `FlatLambda` meaning evaluated in the
+/// given activation. Flat closures copy their captured variables out of the
+/// activation into a new one, whose parent is the global activation.
+pub fn make_closure(heap: &mut Heap,
+                    data: &MeaningData,
+                    act: &RootedActivationPtr) -> SchemeResult {
+    match *data {
+        MeaningData::Lambda(arity, ref body, ref name, ref location, ref signature) => {
+            Ok(Value::new_procedure(heap,
+                                    arity,
+                                    act,
+                                    (*body).clone(),
+                                    name.clone(),
+                                    location.clone(),
+                                    signature.clone()))
+        },
+        MeaningData::FlatLambda(arity,
+                                ref body,
+                                ref name,
+                                ref location,
+                                ref captures,
+                                ref signature) => {
+            let global_act = heap.global_activation();
+            let closure_act = if captures.is_empty() {
+                global_act
+            } else {
+                let mut vals = Vec::with_capacity(captures.len());
+                for &(i, j, var) in captures.iter() {
+                    vals.push(try!(act.fetch(heap, i, j).ok().ok_or(
+                        E0204.error(format!("Reference to variable that hasn't been defined: {}",
+                                            heap.environment.name(var))))));
+                }
+                Activation::extend(heap, &global_act, vals)
+            };
+            Ok(Value::new_procedure(heap,
+                                    arity,
+                                    &closure_act,
+                                    (*body).clone(),
+                                    name.clone(),
+                                    location.clone(),
+                                    signature.clone()))
+        },
+        _ => panic!("Should only make closures of lambdas"),
+    }
+}
+
+/// Describe an arity mismatch when calling the given procedure with the given
+/// number of arguments.
+fn arity_mismatch(procedure: &Procedure, num_args: usize) -> String {
+    let expected = match procedure.signature {
+        Some(ref s) if s.keywords.is_empty() => format!("{} to {}", s.required, s.arity()),
+        Some(ref s)                          => format!("at least {}", s.required),
+        None                                 => format!("{}", procedure.arity),
+    };
+    format!("expected {} args for {}, found {}",
+            expected,
+            describe_procedure(&procedure.name, &procedure.location),
+            num_args)
+}
+
+/// Describe the procedure with the given name, defined at the given location,
+/// for use in error messages.
+fn describe_procedure(name: &Option<String>, location: &Location) -> String {
+    match *name {
+        Some(ref name) => format!("`{}` defined at {}", name, location),
+        None           => format!("anonymous procedure defined at {}", location),
+    }
+}
+
+/// Apply the given procedure to the given arguments.
 pub fn apply_invocation(heap: &mut Heap,
                         proc_val: &RootedValue,
                         args: Vec<RootedValue>) -> TrampolineResult {
+    if heap.is_profiling() {
+        return apply_profiled_invocation(heap, proc_val, args);
+    }
+    apply_unprofiled_invocation(heap, proc_val, args)
+}
+
+/// Apply a procedure while profiling, recording the call and the time it took
+/// to evaluate. Like traced calls, this evaluates the call to completion, and so
+/// calls are never tail calls while profiling.
+fn apply_profiled_invocation(heap: &mut Heap,
+                             proc_val: &RootedValue,
+                             args: Vec<RootedValue>) -> TrampolineResult {
+    let (name, location) = match **proc_val {
+        Value::Primitive(ref primitive) => (primitive.name().to_string(), None),
+        Value::Procedure(ref procedure) => {
+            (procedure.name.clone()
+                 .unwrap_or_else(|| "<anonymous procedure>".to_string()),
+             Some(procedure.location.clone()))
+        },
+        _                               => (format!("{}", **proc_val), None),
+    };
+
+    let depth = heap.stack_depth();
+    let start = time::precise_time_ns();
+    let result = apply_unprofiled_invocation(heap, proc_val, args)
+        .and_then(|trampoline| trampoline.run(heap));
+    heap.record_profile_sample(name, location, time::precise_time_ns() - start);
+    if result.is_err() {
+        heap.unwind_to(depth);
+    }
+
+    Ok(Trampoline::Value(try!(result)))
+}
+
+fn apply_unprofiled_invocation(heap: &mut Heap,
+                               proc_val: &RootedValue,
+                               args: Vec<RootedValue>) -> TrampolineResult {
+    if heap.is_traced(&**proc_val) {
+        return apply_traced_invocation(heap, proc_val, args);
+    }
+    apply_untraced_invocation(heap, proc_val, args)
+}
+
+/// Apply a traced procedure, printing the call and its eventual return value
+/// indented to the current tracing depth. Note that this evaluates the call to
+/// completion, and so calls to traced procedures are never tail calls.
+fn apply_traced_invocation(heap: &mut Heap,
+                           proc_val: &RootedValue,
+                           args: Vec<RootedValue>) -> TrampolineResult {
+    let indent = heap.trace_depth();
+    let name = match **proc_val {
+        Value::Primitive(ref primitive) => primitive.name().to_string(),
+        Value::Procedure(ref procedure) => {
+            procedure.name.clone()
+                .unwrap_or_else(|| "<anonymous procedure>".to_string())
+        },
+        _                               => format!("{}", **proc_val),
+    };
+
+    let mut call = String::new();
+    for _ in 0..indent {
+        call.push_str("| ");
+    }
+    call.push_str(format!("> ({}", name).as_slice());
+    for arg in args.iter() {
+        call.push_str(format!(" {}", **arg).as_slice());
+    }
+    println!("{})", call);
+
+    let depth = heap.stack_depth();
+    heap.enter_trace();
+    let result = apply_untraced_invocation(heap, proc_val, args)
+        .and_then(|trampoline| trampoline.run(heap));
+    heap.exit_trace();
+    if result.is_err() {
+        heap.unwind_to(depth);
+    }
+
+    let val = try!(result);
+    let mut ret = String::new();
+    for _ in 0..indent {
+        ret.push_str("| ");
+    }
+    println!("{}< {}", ret, *val);
+    Ok(Trampoline::Value(val))
+}
+
+/// A value lent to the thread that calls a primitive while catching panics,
+/// and returned from it. See `call_primitive_catching_panics`.
+struct Lent<T>(T);
+
+/// Neither the heap nor the values rooted in it are `Send`, but the thread that
+/// lends them out blocks until the primitive's thread is done with them, so
+/// only one thread ever uses them at a time.
+unsafe impl<T> Send for Lent<T> { }
+
+/// Call the given primitive, converting a panic within it into an error that
+/// carries the panic's message, so that a buggy native extension cannot tear
+/// down the host process. See `HeapConfig::catch_panics`.
+///
+/// A panic can only be caught at a thread's boundary, so the primitive is
+/// called on a scoped thread, which is joined before returning.
+fn call_primitive_catching_panics(heap: &mut Heap,
+                                  primitive: Primitive,
+                                  args: Vec<RootedValue>) -> TrampolineResult {
+    let depth = heap.stack_depth();
+    let recursion_depth = heap.recursion_depth();
+    let result = {
+        let lent = Lent((&mut *heap, primitive, args));
+        Thread::scoped(move || {
+            let Lent((heap, primitive, args)) = lent;
+            Lent(primitive.call(heap, args))
+        }).join()
+    };
+    match result {
+        Ok(Lent(result)) => result,
+        Err(payload)     => {
+            heap.unwind_to(depth);
+            heap.unwind_recursion_to(recursion_depth);
+            let message = match payload.downcast_ref::<&'static str>() {
+                Some(message) => message.to_string(),
+                None          => match payload.downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None          => "<unknown panic>".to_string(),
+                },
+            };
+            Err(E0212.error(format!("Error: primitive `{}` panicked: {}",
+                                    primitive.name(), message)))
+        },
+    }
+}
+
+fn apply_untraced_invocation(heap: &mut Heap,
+                             proc_val: &RootedValue,
+                             args: Vec<RootedValue>) -> TrampolineResult {
     match **proc_val {
         Value::Primitive(primitive) => {
+            if heap.is_catching_panics() {
+                return call_primitive_catching_panics(heap, primitive, args);
+            }
             return primitive.call(heap, args);
         },
 
         Value::Procedure(proc_ptr) => {
+            if let Some(signature) = proc_ptr.signature.clone() {
+                let args = try!(signature.bind(heap, &*proc_ptr, args));
+                let (proc_act, body) = procedure_closure(heap, &proc_ptr);
+                let new_act = Activation::extend(heap, &proc_act, args);
+                return Ok(Trampoline::Thunk(new_act, body));
+            }
+
             match proc_ptr.arity.cmp(&(args.len() as u32)) {
                 Ordering::Less => {
-                    return Err("Error: too many arguments passed".to_string());
+                    return Err(E0201.error(format!("Error: too many arguments passed: {}",
+                                                   arity_mismatch(&*proc_ptr, args.len()))));
                 },
                 Ordering::Greater => {
-                    return Err("Error: too few arguments passed".to_string());
+                    return Err(E0202.error(format!("Error: too few arguments passed: {}",
+                                                   arity_mismatch(&*proc_ptr, args.len()))));
                 },
                 _ => {
-                    let proc_act = proc_ptr.act.as_ref()
-                        .expect("Should never see an uninitialized procedure!");
-                    let rooted_proc_act = Rooted::new(heap, *proc_act);
-                    let body = proc_ptr.body.as_ref()
-                        .expect("Should never see an uninitialized procedure!");
-
-                    let new_act = Activation::extend(heap,
-                                                     &rooted_proc_act,
-                                                     args);
-                    return Ok(Trampoline::Thunk(new_act, (**body).clone()));
+                    let (proc_act, body) = procedure_closure(heap, &proc_ptr);
+                    let new_act = Activation::extend(heap, &proc_act, args);
+                    return Ok(Trampoline::Thunk(new_act, body));
                 },
             }
         },
 
         _ => {
-            return Err(format!("Error: expected a procedure to call, found {}",
-                               **proc_val));
+            return Err(E0203.error(format!("Error: expected a procedure to call, found {}",
+                                           **proc_val)));
         }
     }
 }
 
-fn evaluate_invocation(heap: &mut Heap,
-                       data: &MeaningData,
-                       act: &mut RootedActivationPtr) -> TrampolineResult {
-    if let MeaningData::Invocation(ref procedure, ref params) = *data {
-        let proc_val = try!(procedure.evaluate(heap, act));
-        let args = try!(params.iter().map(|p| p.evaluate(heap, act)).collect());
-        return apply_invocation(heap, &proc_val, args);
+/// Apply the given procedure to the arguments above `base` on the argument
+/// stack, popping them. Calls to procedures that are neither profiled nor
+/// traced, that have no optional or keyword parameters, and that are passed the
+/// right number of arguments, extend their activation directly from the
+/// argument stack. Everything else, including
+/// calls to primitives, which take their arguments by value, goes through
+/// `apply_invocation`.
+///
+/// Tail calls pass the activation of the procedure body they are made from as
+/// `tail_act`. If the callee is closed over the same activation as the caller
+/// (as a procedure calling itself is), and nothing has captured the caller's
+/// activation, then the caller's activation is reused for the callee, so that
+/// simple loops do not allocate an activation per iteration.
+fn apply_arguments(heap: &mut Heap,
+                   proc_val: &RootedValue,
+                   base: usize,
+                   tail_act: Option<&mut RootedActivationPtr>) -> TrampolineResult {
+    if let Value::Procedure(proc_ptr) = **proc_val {
+        let num_args = heap.arguments_height() - base;
+        if proc_ptr.arity as usize == num_args
+            && proc_ptr.signature.is_none()
+            && !heap.is_profiling()
+            && !heap.is_traced(&**proc_val) {
+            let (proc_act, body) = procedure_closure(heap, &proc_ptr);
+
+            // The debugger may hold on to the activations it is shown.
+            if let Some(act) = tail_act {
+                if !heap.is_debugging()
+                    && Activation::reuse_for_tail_call(heap, act, &proc_act, base) {
+                    return Ok(Trampoline::Thunk(Rooted::new(heap, **act), body));
+                }
+            }
+
+            let new_act = Activation::extend_from_arguments(heap, &proc_act, base);
+            return Ok(Trampoline::Thunk(new_act, body));
+        }
     }
 
-    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+    let args = heap.pop_arguments(base);
+    apply_invocation(heap, proc_val, args)
 }
 
-/// The `Meaning` type is our intermediate language produced by syntactic
-/// analysis. It is a triple containing a `MeaningData` variant, its
-/// corresponding `MeaningEvaluatorFn`, and the source location this `Meaning`
-/// originates from.
-#[derive(Debug)]
-pub struct Meaning {
-    data: Box<MeaningData>,
-    evaluator: MeaningEvaluatorFn,
-    location: Location,
+/// Get the activation the given procedure closes over, and its body.
+fn procedure_closure(heap: &mut Heap,
+                     procedure: &ProcedurePtr) -> (RootedActivationPtr, Meaning) {
+    let proc_act = procedure.act.as_ref()
+        .expect("Should never see an uninitialized procedure!");
+    let body = procedure.body.as_ref()
+        .expect("Should never see an uninitialized procedure!");
+    (Rooted::new(heap, *proc_act), (**body).clone())
 }
 
-/// ## `Meaning` Constructors
-impl Meaning {
-    fn new_quotation(form: &RootedValue, location: Location) -> Meaning {
-        Meaning {
-            data: Box::new(MeaningData::Quotation((*form).clone())),
-            evaluator: evaluate_quotation,
-            location: location
-        }
+/// Get a name for the procedure being invoked, for use in call stack frames.
+fn procedure_name(heap: &Heap, procedure: &Meaning, proc_val: &RootedValue) -> String {
+    match *procedure.data() {
+        MeaningData::Reference(_, _, name) |
+        MeaningData::GlobalReference(_, _, name) => {
+            return heap.environment.name(name).to_string();
+        },
+        _                                        => { },
     }
 
-    fn new_reference(i: u32, j: u32, name: String, location: Location) -> Meaning {
-        Meaning {
-            data: Box::new(MeaningData::Reference(i, j, name)),
-            evaluator: evaluate_reference,
-            location: location
-        }
+    procedure_value_name(proc_val)
+}
+
+/// Get a name for the given procedure value, for use in call stack frames when
+/// the name it was invoked by is not known.
+pub fn procedure_value_name(proc_val: &RootedValue) -> String {
+    match **proc_val {
+        Value::Primitive(ref primitive) => primitive.name().to_string(),
+        Value::Procedure(ref procedure) => {
+            procedure.name.clone()
+                .unwrap_or_else(|| "<anonymous procedure>".to_string())
+        },
+        _                               => "<anonymous procedure>".to_string(),
     }
+}
 
-    fn new_set_variable(i: u32, j: u32, val: Meaning, location: Location) -> Meaning {
-        Meaning {
-            data: Box::new(MeaningData::SetVariable(i, j, val)),
-            evaluator: evaluate_set_variable,
-            location: location,
-        }
+fn evaluate_invocation(heap: &mut Heap,
+                       data: &MeaningData,
+                       act: &mut RootedActivationPtr) -> TrampolineResult {
+    if let MeaningData::Invocation(ref procedure, ref params, ref location) = *data {
+        let proc_val = try!(procedure.evaluate(heap, act));
+        return invoke(heap, procedure, &proc_val, params, location, act);
     }
 
-    fn new_conditional(condition: Meaning,
-                       consquent: Meaning,
-                       alternative: Meaning,
-                       location: Location) -> Meaning {
-        Meaning {
-            data: Box::new(MeaningData::Conditional(condition,
-                                                    consquent,
-                                                    alternative)),
-            evaluator: evaluate_conditional,
-            location: location,
+    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+}
+
+// The fixed arity invocations know exactly how many arguments they will pass,
+// so they push them onto the argument stack without iterating over a `Vec` of
+// parameters.
+
+fn evaluate_invocation0(heap: &mut Heap,
+                        data: &MeaningData,
+                        act: &mut RootedActivationPtr) -> TrampolineResult {
+    if let MeaningData::Invocation0(ref procedure, ref location) = *data {
+        let proc_val = try!(procedure.evaluate(heap, act));
+        let base = heap.arguments_height();
+        return apply_at(heap, procedure, &proc_val, base, location, None);
+    }
+
+    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+}
+
+fn evaluate_invocation1(heap: &mut Heap,
+                        data: &MeaningData,
+                        act: &mut RootedActivationPtr) -> TrampolineResult {
+    if let MeaningData::Invocation1(ref procedure, ref a, ref location) = *data {
+        let proc_val = try!(procedure.evaluate(heap, act));
+        let base = try!(push_arguments(heap, [a].iter().map(|m| *m), act));
+        return apply_at(heap, procedure, &proc_val, base, location, None);
+    }
+
+    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+}
+
+fn evaluate_invocation2(heap: &mut Heap,
+                        data: &MeaningData,
+                        act: &mut RootedActivationPtr) -> TrampolineResult {
+    if let MeaningData::Invocation2(ref procedure, ref a, ref b, ref location) = *data {
+        let proc_val = try!(procedure.evaluate(heap, act));
+        let base = try!(push_arguments(heap, [a, b].iter().map(|m| *m), act));
+        return apply_at(heap, procedure, &proc_val, base, location, None);
+    }
+
+    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+}
+
+fn evaluate_invocation3(heap: &mut Heap,
+                        data: &MeaningData,
+                        act: &mut RootedActivationPtr) -> TrampolineResult {
+    if let MeaningData::Invocation3(ref procedure, ref a, ref b, ref c, ref location) = *data {
+        let proc_val = try!(procedure.evaluate(heap, act));
+        let base = try!(push_arguments(heap, [a, b, c].iter().map(|m| *m), act));
+        return apply_at(heap, procedure, &proc_val, base, location, None);
+    }
+
+    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+}
+
+/// The evaluator for invocations in tail position of a procedure body, which
+/// may reuse the body's activation for the callee. See `mark_tail_calls`.
+fn evaluate_tail_invocation(heap: &mut Heap,
+                            data: &MeaningData,
+                            act: &mut RootedActivationPtr) -> TrampolineResult {
+    let (procedure, location) = match *data {
+        MeaningData::Invocation(ref procedure, _, ref location)       |
+        MeaningData::Invocation0(ref procedure, ref location)         |
+        MeaningData::Invocation1(ref procedure, _, ref location)      |
+        MeaningData::Invocation2(ref procedure, _, _, ref location)   |
+        MeaningData::Invocation3(ref procedure, _, _, _, ref location) => (procedure, location),
+        _ => panic!("unsynchronized MeaningData and MeaningEvaluatorFn"),
+    };
+
+    let proc_val = try!(procedure.evaluate(heap, act));
+    let base = try!(match *data {
+        MeaningData::Invocation(_, ref params, _) => {
+            push_arguments(heap, params.iter(), act)
+        },
+        MeaningData::Invocation1(_, ref a, _) => {
+            push_arguments(heap, [a].iter().map(|m| *m), act)
+        },
+        MeaningData::Invocation2(_, ref a, ref b, _) => {
+            push_arguments(heap, [a, b].iter().map(|m| *m), act)
+        },
+        MeaningData::Invocation3(_, ref a, ref b, ref c, _) => {
+            push_arguments(heap, [a, b, c].iter().map(|m| *m), act)
+        },
+        _ => Ok(heap.arguments_height()),
+    });
+    apply_at(heap, procedure, &proc_val, base, location, Some(act))
+}
+
+/// Evaluate the parameters of an invocation and apply the already evaluated
+/// procedure to them.
+fn invoke(heap: &mut Heap,
+          procedure: &Meaning,
+          proc_val: &RootedValue,
+          params: &Vec<Meaning>,
+          location: &Location,
+          act: &mut RootedActivationPtr) -> TrampolineResult {
+    let base = try!(push_arguments(heap, params.iter(), act));
+    apply_at(heap, procedure, proc_val, base, location, None)
+}
+
+/// Evaluate the given parameters, pushing each of them onto the argument stack,
+/// and return the height of the stack beneath them. If evaluating a parameter
+/// fails, the arguments pushed so far are discarded.
+fn push_arguments<'a, I>(heap: &mut Heap,
+                         params: I,
+                         act: &mut RootedActivationPtr) -> Result<usize, SchemeError>
+    where I: Iterator<Item=&'a Meaning>
+{
+    let base = heap.arguments_height();
+    for param in params {
+        match param.evaluate(heap, act) {
+            Ok(val) => heap.push_argument(&val),
+            Err(e)  => {
+                heap.truncate_arguments(base);
+                return Err(e);
+            },
         }
     }
+    Ok(base)
+}
 
-    fn new_sequence(first: Meaning, second: Meaning, location: Location) -> Meaning {
-        Meaning {
-            data: Box::new(MeaningData::Sequence(first, second)),
-            evaluator: evaluate_sequence,
-            location: location,
+/// Apply the already evaluated procedure to the already evaluated arguments
+/// above `base` on the argument stack, with a call stack frame for the call
+/// site at the given location. See `apply_arguments` for `tail_act`.
+fn apply_at(heap: &mut Heap,
+            procedure: &Meaning,
+            proc_val: &RootedValue,
+            base: usize,
+            location: &Location,
+            tail_act: Option<&mut RootedActivationPtr>) -> TrampolineResult {
+    let name = procedure_name(heap, procedure, proc_val);
+    heap.push_frame(Frame {
+        name: name,
+        location: location.clone(),
+    });
+
+    let result = try!(apply_arguments(heap, proc_val, base, tail_act));
+    if let Trampoline::Value(_) = result {
+        heap.pop_frame();
+    }
+    Ok(result)
+}
+
+fn evaluate_let(heap: &mut Heap,
+                data: &MeaningData,
+                act: &mut RootedActivationPtr) -> TrampolineResult {
+    if let MeaningData::Let(ref params, ref body, _) = *data {
+        let base = try!(push_arguments(heap, params.iter(), act));
+        let new_act = Activation::extend_from_arguments(heap, act, base);
+        return Ok(Trampoline::Thunk(new_act, body.clone()));
+    }
+
+    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+}
+
+fn evaluate_inlined_invocation(heap: &mut Heap,
+                               data: &MeaningData,
+                               act: &mut RootedActivationPtr) -> TrampolineResult {
+    if let MeaningData::InlinedInvocation(ref procedure,
+                                          ref params,
+                                          ref inlined,
+                                          ref body,
+                                          ref location) = *data {
+        let proc_val = try!(procedure.evaluate(heap, act));
+
+        // If the global was `set!` or redefined since we inlined its body, or
+        // the call needs to be observed by the profiler or tracing, fall back
+        // to a normal invocation.
+        if *proc_val != **inlined || heap.is_profiling() || heap.is_traced(&*proc_val) {
+            return invoke(heap, procedure, &proc_val, params, location, act);
         }
+
+        let base = try!(push_arguments(heap, params.iter(), act));
+
+        let name = procedure_name(heap, procedure, &proc_val);
+        heap.push_frame(Frame {
+            name: name,
+            location: location.clone(),
+        });
+
+        // The inlined body's references to globals were adjusted for the call
+        // site, so it is evaluated in an extension of the caller's activation.
+        let new_act = Activation::extend_from_arguments(heap, act, base);
+        return Ok(Trampoline::Thunk(new_act, body.clone()));
     }
 
-    fn new_definition(i: u32, j: u32, defined: Meaning, location: Location) -> Meaning {
-        Meaning {
-            data: Box::new(MeaningData::Definition(i, j, defined)),
-            evaluator: evaluate_definition,
-            location: location,
+    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+}
+
+fn evaluate_primitive_invocation(heap: &mut Heap,
+                                 data: &MeaningData,
+                                 act: &mut RootedActivationPtr) -> TrampolineResult {
+    if let MeaningData::PrimitiveInvocation(ref procedure,
+                                            ref primitive,
+                                            fast_path,
+                                            ref params,
+                                            ref location) = *data {
+        let proc_val = try!(procedure.evaluate(heap, act));
+
+        // If the global no longer holds the primitive, or the call needs to be
+        // observed by the profiler or tracing, fall back to a normal
+        // invocation.
+        if *proc_val != **primitive || heap.is_profiling() || heap.is_traced(&*proc_val) {
+            return invoke(heap, procedure, &proc_val, params, location, act);
         }
+
+        let result = match params.as_slice() {
+            [ref a] => {
+                let a = try!(a.evaluate(heap, act));
+                fast_path.call1(heap, &a)
+            },
+            [ref a, ref b] => {
+                let a = try!(a.evaluate(heap, act));
+                let b = try!(b.evaluate(heap, act));
+                fast_path.call2(heap, &a, &b)
+            },
+            _ => panic!("primitive invocation with the wrong number of parameters"),
+        };
+
+        // Only failed calls need a frame, so that they show up in backtraces.
+        return match result {
+            Ok(val) => Ok(Trampoline::Value(val)),
+            Err(e)  => {
+                let name = procedure_name(heap, procedure, &proc_val);
+                heap.push_frame(Frame {
+                    name: name,
+                    location: location.clone(),
+                });
+                Err(e)
+            },
+        };
     }
 
-    fn new_lambda(arity: u32, body: Meaning, location: Location) -> Meaning {
-        Meaning {
-            data: Box::new(MeaningData::Lambda(arity, body)),
-            evaluator: evaluate_lambda,
-            location: location,
+    panic!("unsynchronized MeaningData and MeaningEvaluatorFn");
+}
+
+/// A node of a meaning tree: a `MeaningData` variant, its corresponding
+/// `MeaningEvaluatorFn`, and the source location it originates from.
+struct MeaningNode {
+    data: MeaningData,
+    evaluator: MeaningEvaluatorFn,
+    location: Location,
+    /// The unit this node was allocated in.
+    unit: MeaningUnit,
+}
+
+/// The meaning nodes produced by analyzing a single form. Every form passed to
+/// `analyze` gets its own unit, which counts the nodes allocated in it, so that
+/// the size of each form's meaning tree can be measured. The heap holds the
+/// unit for the form currently being analyzed; see `Heap::meaning_unit`.
+#[derive(Clone)]
+pub struct MeaningUnit {
+    nodes: Rc<Cell<usize>>,
+}
+
+impl MeaningUnit {
+    /// Create a new, empty unit.
+    pub fn new() -> MeaningUnit {
+        MeaningUnit {
+            nodes: Rc::new(Cell::new(0)),
         }
     }
 
-    fn new_invocation(procedure: Meaning, params: Vec<Meaning>, location: Location) -> Meaning {
+    /// Get the number of meaning nodes allocated in this unit.
+    pub fn len(&self) -> usize {
+        self.nodes.get()
+    }
+
+    /// Allocate a new meaning node in this unit.
+    fn alloc(&self,
+             data: MeaningData,
+             evaluator: MeaningEvaluatorFn,
+             location: Location) -> Meaning {
+        self.nodes.set(self.nodes.get() + 1);
         Meaning {
-            data: Box::new(MeaningData::Invocation(procedure, params)),
-            evaluator: evaluate_invocation,
-            location: location
+            node: Rc::new(MeaningNode {
+                data: data,
+                evaluator: evaluator,
+                location: location,
+                unit: self.clone(),
+            }),
         }
     }
 }
 
+/// The `Meaning` type is our intermediate language produced by syntactic
+/// analysis. It is a reference counted handle to a `MeaningNode`.
+///
+/// Nodes are immutable, so cloning a `Meaning` (as we do for every thunk when
+/// trampolining) is cheap, no matter how large the tree beneath it is.
+#[derive(Clone)]
+pub struct Meaning {
+    node: Rc<MeaningNode>,
+}
+
+/// ## `Meaning` Constructors
+impl Meaning {
+    fn new_quotation(unit: &MeaningUnit, form: &RootedValue, location: Location) -> Meaning {
+        unit.alloc(MeaningData::Quotation((*form).clone()), evaluate_quotation, location)
+    }
+
+    fn new_reference(unit: &MeaningUnit,
+                     i: u32,
+                     j: u32,
+                     name: SymbolId,
+                     location: Location) -> Meaning {
+        unit.alloc(MeaningData::Reference(i, j, name), evaluate_reference, location)
+    }
+
+    fn new_global_reference(unit: &MeaningUnit,
+                            global_act: RootedActivationPtr,
+                            j: u32,
+                            name: SymbolId,
+                            location: Location) -> Meaning {
+        unit.alloc(MeaningData::GlobalReference(global_act, j, name),
+                   evaluate_global_reference,
+                   location)
+    }
+
+    fn new_set_variable(unit: &MeaningUnit,
+                        i: u32,
+                        j: u32,
+                        val: Meaning,
+                        location: Location) -> Meaning {
+        unit.alloc(MeaningData::SetVariable(i, j, val), evaluate_set_variable, location)
+    }
+
+    fn new_conditional(unit: &MeaningUnit,
+                       condition: Meaning,
+                       consquent: Meaning,
+                       alternative: Meaning,
+                       location: Location) -> Meaning {
+        unit.alloc(MeaningData::Conditional(condition, consquent, alternative),
+                   evaluate_conditional,
+                   location)
+    }
+
+    fn new_sequence(unit: &MeaningUnit,
+                    first: Meaning,
+                    second: Meaning,
+                    location: Location) -> Meaning {
+        unit.alloc(MeaningData::Sequence(first, second), evaluate_sequence, location)
+    }
+
+    fn new_definition(unit: &MeaningUnit,
+                      i: u32,
+                      j: u32,
+                      defined: Meaning,
+                      location: Location) -> Meaning {
+        unit.alloc(MeaningData::Definition(i, j, defined), evaluate_definition, location)
+    }
+
+    fn new_lambda(unit: &MeaningUnit,
+                  arity: u32,
+                  body: Meaning,
+                  location: Location,
+                  signature: Option<Rc<Signature>>) -> Meaning {
+        unit.alloc(MeaningData::Lambda(arity, body, None, location.clone(), signature),
+                   evaluate_lambda,
+                   location)
+    }
+
+    fn new_invocation(unit: &MeaningUnit,
+                      procedure: Meaning,
+                      params: Vec<Meaning>,
+                      location: Location) -> Meaning {
+        let call_site = location.clone();
+        let (data, evaluator) : (MeaningData, MeaningEvaluatorFn) = match params.len() {
+            0 => (MeaningData::Invocation0(procedure, call_site), evaluate_invocation0),
+            1 => {
+                let mut params = params.into_iter();
+                let a = params.next().unwrap();
+                (MeaningData::Invocation1(procedure, a, call_site), evaluate_invocation1)
+            },
+            2 => {
+                let mut params = params.into_iter();
+                let a = params.next().unwrap();
+                let b = params.next().unwrap();
+                (MeaningData::Invocation2(procedure, a, b, call_site), evaluate_invocation2)
+            },
+            3 => {
+                let mut params = params.into_iter();
+                let a = params.next().unwrap();
+                let b = params.next().unwrap();
+                let c = params.next().unwrap();
+                (MeaningData::Invocation3(procedure, a, b, c, call_site), evaluate_invocation3)
+            },
+            _ => (MeaningData::Invocation(procedure, params, call_site), evaluate_invocation),
+        };
+
+        unit.alloc(data, evaluator, location)
+    }
+
+    fn new_let(unit: &MeaningUnit,
+               params: Vec<Meaning>,
+               body: Meaning,
+               location: Location) -> Meaning {
+        unit.alloc(MeaningData::Let(params, body, location.clone()), evaluate_let, location)
+    }
+
+    fn new_inlined_invocation(unit: &MeaningUnit,
+                              procedure: Meaning,
+                              params: Vec<Meaning>,
+                              inlined: RootedValue,
+                              body: Meaning,
+                              location: Location) -> Meaning {
+        unit.alloc(MeaningData::InlinedInvocation(procedure,
+                                                  params,
+                                                  inlined,
+                                                  body,
+                                                  location.clone()),
+                   evaluate_inlined_invocation,
+                   location)
+    }
+
+    fn new_primitive_invocation(unit: &MeaningUnit,
+                                procedure: Meaning,
+                                primitive: RootedValue,
+                                fast_path: FastPath,
+                                params: Vec<Meaning>,
+                                location: Location) -> Meaning {
+        unit.alloc(MeaningData::PrimitiveInvocation(procedure,
+                                                    primitive,
+                                                    fast_path,
+                                                    params,
+                                                    location.clone()),
+                   evaluate_primitive_invocation,
+                   location)
+    }
+
+    /// Allocate a meaning with the given data in the given unit, with the
+    /// evaluator that syntactic analysis would have chosen for it. Invocations
+    /// in tail position must say so, because their data does not record it.
+    /// This is how heap images rebuild the bodies of procedures.
+    pub fn from_data(unit: &MeaningUnit,
+                     data: MeaningData,
+                     location: Location,
+                     tail: bool) -> Meaning {
+        let evaluator : MeaningEvaluatorFn = match data {
+            MeaningData::Quotation(..)          => evaluate_quotation,
+            MeaningData::Reference(..)          => evaluate_reference,
+            MeaningData::GlobalReference(..)    => evaluate_global_reference,
+            MeaningData::TheEnvironment(..)     => evaluate_the_environment,
+            MeaningData::Definition(..)         => evaluate_definition,
+            MeaningData::SetVariable(..)        => evaluate_set_variable,
+            MeaningData::Conditional(..)        => evaluate_conditional,
+            MeaningData::Sequence(..)           => evaluate_sequence,
+            MeaningData::Lambda(..)             |
+            MeaningData::FlatLambda(..)         => evaluate_lambda,
+            MeaningData::Invocation(..)         |
+            MeaningData::Invocation0(..)        |
+            MeaningData::Invocation1(..)        |
+            MeaningData::Invocation2(..)        |
+            MeaningData::Invocation3(..) if tail => evaluate_tail_invocation,
+            MeaningData::Invocation(..)         => evaluate_invocation,
+            MeaningData::Invocation0(..)        => evaluate_invocation0,
+            MeaningData::Invocation1(..)        => evaluate_invocation1,
+            MeaningData::Invocation2(..)        => evaluate_invocation2,
+            MeaningData::Invocation3(..)        => evaluate_invocation3,
+            MeaningData::Let(..)                => evaluate_let,
+            MeaningData::InlinedInvocation(..)  => evaluate_inlined_invocation,
+            MeaningData::PrimitiveInvocation(..) => evaluate_primitive_invocation,
+        };
+        unit.alloc(data, evaluator, location)
+    }
+}
+
 /// ## `Meaning` Methods
 impl Meaning {
+    /// Return true if this meaning is an invocation in tail position, false
+    /// otherwise.
+    pub fn is_tail_invocation(&self) -> bool {
+        self.evaluator() as usize == evaluate_tail_invocation as usize
+    }
+
+    /// If this meaning is a lambda, give it the name and location of the
+    /// definition it is being bound by, and return true. Otherwise, return
+    /// false.
+    fn name_lambda(&mut self, name: &String, location: &Location) -> bool {
+        let named = match *self.data() {
+            MeaningData::Lambda(arity, ref body, _, _, ref signature) => {
+                MeaningData::Lambda(arity,
+                                    body.clone(),
+                                    Some(name.clone()),
+                                    location.clone(),
+                                    signature.clone())
+            },
+            MeaningData::FlatLambda(arity, ref body, _, _, ref captures, ref signature) => {
+                MeaningData::FlatLambda(arity,
+                                        body.clone(),
+                                        Some(name.clone()),
+                                        location.clone(),
+                                        captures.clone(),
+                                        signature.clone())
+            },
+            _ => return false,
+        };
+        *self = self.unit().alloc(named, self.evaluator(), self.location().clone());
+        true
+    }
+
+    /// Return true if this meaning has the same structure as the other, ie they
+    /// are the same kinds of meanings with the same data and sub-meanings. Source
+    /// locations are not compared. Use `diff_meanings` to find out where two
+    /// meanings differ.
+    pub fn structural_eq(&self, other: &Meaning) -> bool {
+        self.data().shallow_eq(other.data()) && {
+            let mine = self.data().children();
+            let theirs = other.data().children();
+            mine.len() == theirs.len()
+                && mine.iter().zip(theirs.iter()).all(|(a, b)| a.structural_eq(b))
+        }
+    }
+
+    /// Get the source location this meaning originates from.
+    pub fn location(&self) -> &Location {
+        &self.node().location
+    }
+
+    /// Get the data for this meaning.
+    pub fn data(&self) -> &MeaningData {
+        &self.node().data
+    }
+
+    /// Get a number identifying this meaning's data, which is shared by all of
+    /// its clones. The identity is only unique while the data is alive.
+    pub fn identity(&self) -> usize {
+        &*self.node as *const MeaningNode as usize
+    }
+
+    /// Get a handle to the unit this meaning was allocated in.
+    pub fn unit(&self) -> MeaningUnit {
+        self.node.unit.clone()
+    }
+
+    #[inline]
+    fn node(&self) -> &MeaningNode {
+        &*self.node
+    }
+
+    #[inline]
+    fn evaluator(&self) -> MeaningEvaluatorFn {
+        self.node().evaluator
+    }
+
     /// Evaluate this form no further than until the next thunk.
     #[inline]
     fn evaluate_to_thunk(&self,
                          heap: &mut Heap,
                          act: &mut RootedActivationPtr) -> TrampolineResult {
-        match (self.evaluator)(heap, &*self.data, act) {
-            // Add this location to the error message. These stack up and give a
-            // backtrace.
-            Err(e) => Err(format!("{}:\n{}", self.location, e)),
-            ok => ok
+        if heap.is_debugging() {
+            heap.debug_hook(act, self);
         }
+
+        let node = self.node();
+        let mut result = (node.evaluator)(heap, &node.data, act);
+        if heap.take_out_of_memory() {
+            result = Err(E0205.error("Error: out of memory".to_string()));
+        }
+        if result.is_err() {
+            // The innermost form to fail is the first to report its location.
+            heap.note_error_location(&node.location);
+        }
+        result
     }
 
     /// Evaluate this form completely, trampolining all thunks until a value is
-    /// produced.
+    /// produced. This recurses on the host stack for every non-tail call, so
+    /// it fails once the heap's maximum recursion depth is reached.
     fn evaluate(&self,
                 heap: &mut Heap,
                 act: &mut RootedActivationPtr) -> SchemeResult {
-        let thunk = try!(self.evaluate_to_thunk(heap, act));
-        thunk.run(heap)
+        if let Err(e) = heap.enter_recursion() {
+            heap.note_error_location(self.location());
+            return Err(e);
+        }
+        let depth = heap.stack_depth();
+        let result = self.evaluate_to_thunk(heap, act)
+            .and_then(|thunk| thunk.run_from(heap, depth));
+        heap.exit_recursion();
+        let val = try!(result);
+        heap.unwind_to(depth);
+        Ok(val)
     }
 }
 
-impl Clone for Meaning {
-    fn clone(&self) -> Self {
-        Meaning {
-            data: self.data.clone(),
-            evaluator: self.evaluator,
-            location: self.location.clone(),
-        }
+/// Like `MeaningData`'s, `Meaning`'s `Debug` implementation is a bounded summary.
+/// Use `disassemble` to get a full dump.
+impl fmt::Debug for Meaning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Meaning {{ kind: {}, location: {}, children: {} }}",
+               self.data().kind(),
+               self.location(),
+               self.data().child_count())
     }
 }
 
 impl fmt::Display for Meaning {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", *self.data)
+        write!(f, "{}", *self.data())
     }
 }
 
 impl hash::Hash for Meaning {
     fn hash<H: hash::Hasher>(&self, state: &mut H) {
-        let u = self.evaluator as usize;
+        let u = self.evaluator() as usize;
         u.hash(state);
-        self.data.hash(state);
+        self.data().hash(state);
     }
 }
 
-/// Either a `Meaning`, or a `String` explaining the error.
-pub type MeaningResult = Result<Meaning, String>;
+/// Render the given meaning as an indented tree, one node per line, annotated
+/// with source locations. This shows exactly what the syntactic analysis
+/// produced, which is useful when debugging performance.
+///
+/// For example, the meaning of `(if (< n 2) n (fib (- n 1)))` disassembles to:
+///
+///     conditional @ fib.scm:2:3
+///       primitive-invocation @ fib.scm:2:7
+///         global-reference < 12 @ fib.scm:2:7
+///         reference n (0, 0) @ fib.scm:2:7
+///         quotation 2 @ fib.scm:2:7
+///       ...
+///
+/// Variables' names are found in the given environment, which must be that of
+/// the heap the meaning was analyzed in.
+pub fn disassemble(env: &Environment, meaning: &Meaning) -> String {
+    let mut out = String::new();
+    disassemble_into(env, meaning, 0, &mut out);
+    out
+}
 
-/// The main entry point for syntactic analysis.
-pub fn analyze(heap: &mut Heap,
-               form: &RootedValue,
-               location: Location) -> MeaningResult {
-    if form.is_atom() {
-        return analyze_atom(heap, form, location);
+fn disassemble_into(env: &Environment, meaning: &Meaning, depth: usize, out: &mut String) {
+    for _ in 0..depth {
+        out.push_str("  ");
     }
 
-    let pair = form.to_pair(heap).expect(
-        "If a value is not an atom, then it must be a pair.");
-
-    let quote = heap.quote_symbol();
-    let if_symbol = heap.if_symbol();
-    let begin = heap.begin_symbol();
-    let define = heap.define_symbol();
-    let set_bang = heap.set_bang_symbol();
-    let lambda = heap.lambda_symbol();
+    out.push_str(format!("{} @ {}\n", meaning.data().label(env), meaning.location()).as_slice());
 
-    match *pair.car(heap) {
-        v if v == *quote     => analyze_quoted(heap, form),
-        v if v == *define    => analyze_definition(heap, form),
-        v if v == *set_bang  => analyze_set(heap, form),
-        v if v == *lambda    => analyze_lambda(heap, form),
-        v if v == *if_symbol => analyze_conditional(heap, form),
-        v if v == *begin     => analyze_sequence(heap, form),
-        _                    => analyze_invocation(heap, form),
+    for child in meaning.data().children().into_iter() {
+        disassemble_into(env, child, depth + 1, out);
     }
 }
 
-/// Return true if the form doesn't need to be evaluated because it is
-/// "autoquoting" or "self evaluating", false otherwise.
-fn is_auto_quoting(form: &RootedValue) -> bool {
-    match **form {
-        Value::EmptyList    => false,
-        Value::Pair(_)      => false,
-        Value::Symbol(_)    => false,
-        _                   => true,
+/// Compare the expected meaning with the actual one, and describe where they
+/// differ. Return `None` if they are structurally equal.
+///
+/// Each difference is reported with the path to it, as the index of each
+/// sub-meaning from the root, and the disassembly of the differing subtrees.
+/// For example:
+///
+///     at /2:
+///       expected:
+///         quotation 3 @ test.scm:1:1
+///       actual:
+///         quotation 4 @ test.scm:1:1
+pub fn diff_meanings(env: &Environment, expected: &Meaning, actual: &Meaning) -> Option<String> {
+    let mut out = String::new();
+    diff_into(env, expected, actual, &mut String::new(), &mut out);
+    if out.is_empty() {
+        None
+    } else {
+        Some(out)
     }
 }
 
-fn analyze_atom(heap: &mut Heap,
-                form: &RootedValue,
-                location: Location) -> MeaningResult {
-    if is_auto_quoting(form) {
-        return Ok(Meaning::new_quotation(form, location));
-    }
-
-    if let Some(sym) = form.to_symbol(heap) {
-        if let Some((i, j)) = heap.environment.lookup(&**sym) {
-            return Ok(Meaning::new_reference(i, j, (**sym).clone(), location));
+fn diff_into(env: &Environment,
+             expected: &Meaning,
+             actual: &Meaning,
+             path: &mut String,
+             out: &mut String) {
+    if expected.data().shallow_eq(actual.data()) {
+        let expected_children = expected.data().children();
+        let actual_children = actual.data().children();
+        if expected_children.len() == actual_children.len() {
+            for (i, (e, a)) in expected_children.into_iter()
+                .zip(actual_children.into_iter())
+                .enumerate() {
+                let len = path.len();
+                path.push_str(format!("/{}", i).as_slice());
+                diff_into(env, e, a, path, out);
+                path.truncate(len);
+            }
+            return;
         }
-
-        // This is a reference to a global variable that hasn't been defined
-        // yet.
-        let (i, j) = heap.environment.define_global((**sym).clone());
-        return Ok(Meaning::new_reference(i, j, (**sym).clone(), location));
     }
 
-    return Err(format!("Static error: Cannot evaluate: {}", **form));
+    let indent = |text: String| {
+        text.lines()
+            .map(|line| format!("    {}\n", line))
+            .collect::<Vec<String>>()
+            .concat()
+    };
+    out.push_str(format!("at {}:\n  expected:\n{}  actual:\n{}",
+                         if path.is_empty() { "/" } else { path.as_slice() },
+                         indent(disassemble(env, expected)),
+                         indent(disassemble(env, actual))).as_slice());
+}
+/// Either a `Meaning`, or a `SchemeError` explaining the error.
+pub type MeaningResult = Result<Meaning, SchemeError>;
+
+/// The deepest that forms may be nested within each other. Syntactic analysis
+/// recurses on the host stack for each level of nesting, so deeper forms are a
+/// static error rather than a stack overflow. Long lists of forms, such as the
+/// body of a `begin` or the operands of a call, are analyzed iteratively and
+/// may be any length.
+pub static MAX_ANALYSIS_DEPTH : usize = 512;
+
+/// The main entry point for syntactic analysis. The meaning of each form is
+/// allocated in a `MeaningUnit` of its own.
+pub fn analyze(heap: &mut Heap,
+               form: &RootedValue,
+               location: Location) -> MeaningResult {
+    let outer = heap.replace_meaning_unit(MeaningUnit::new());
+    let result = analyze_form(heap, form, location);
+    heap.replace_meaning_unit(outer);
+    result
 }
 
-fn analyze_quoted(heap: &mut Heap, form: &RootedValue) -> MeaningResult {
-    if let Ok(2) = form.len() {
-        let pair = form.to_pair(heap).unwrap();
-        return Ok(Meaning::new_quotation(
-            &form.cdr(heap).unwrap().car(heap).unwrap(),
-            heap.locate(&pair)));
+/// The heap state that syntactic analysis consults beyond the form itself and
+/// the global environment.
+#[derive(Copy, Clone, PartialEq)]
+struct AnalysisSettings {
+    constant_folding: bool,
+    arithmetic_mode: ArithmeticMode,
+    inline_budget: usize,
+}
+
+impl AnalysisSettings {
+    fn current(heap: &Heap) -> AnalysisSettings {
+        AnalysisSettings {
+            constant_folding: heap.is_constant_folding(),
+            arithmetic_mode: heap.arithmetic_mode(),
+            inline_budget: heap.inline_budget(),
+        }
     }
+}
 
-    let msg = "Static error: Wrong number of parts in quoted form";
-    Err(if let Some(pair) = form.to_pair(heap) {
-        format!("{}: {}", heap.locate(&pair), msg)
-    } else {
-        msg.to_string()
-    })
+/// The meaning of a top-level form, and what it was analyzed under.
+struct CachedAnalysis {
+    form: RootedValue,
+    location: Location,
+    settings: AnalysisSettings,
+    /// The index and value of each global whose primitive was invoked by a
+    /// folded invocation. Unlike inlining, folding is not checked at runtime,
+    /// so the meaning is stale once one of these globals is redefined.
+    folded: Vec<(u32, RootedValue)>,
+    meaning: Meaning,
+    /// When this meaning was last analyzed or reused.
+    used: u64,
 }
 
-fn analyze_definition(heap: &mut Heap,
-                      form: &RootedValue) -> MeaningResult {
-    if let Ok(3) = form.len() {
-        let pair = form.to_pair(heap).expect(
-            "If len = 3, then form must be a pair");
-        let sym = try!(pair.cadr(heap));
+/// A cache of the meanings of top-level forms evaluated with `evaluate`, so
+/// that evaluating a form that is structurally equal to one evaluated before,
+/// at the same location, skips syntactic analysis. This pays off when Rust code
+/// builds and evaluates the same form over and over.
+///
+/// A cached meaning is reused only if analysis would produce the same meaning
+/// again: the analysis settings are unchanged, no folded global has been
+/// redefined, and no macro has been defined since. Macro transformers are
+/// assumed to depend only on the use being expanded, so a reused meaning keeps
+/// the expansion it was analyzed with even if a global the transformer reads
+/// has since been changed. Warnings are reported only when a form is first
+/// analyzed. Once the cache is full, the least recently used meaning is evicted
+/// to make room.
+///
+/// See `HeapConfig::analysis_cache_capacity`.
+pub struct AnalysisCache {
+    capacity: usize,
+    entries: HashMap<u64, CachedAnalysis>,
+    /// The folded globals of the form currently being analyzed, if it is to be
+    /// cached.
+    folded: Option<Vec<(u32, RootedValue)>>,
+    /// Incremented whenever every entry is invalidated, so that a form whose
+    /// analysis invalidated the cache is not cached itself.
+    generation: u64,
+    clock: u64,
+    hits: usize,
+}
 
-        let location = heap.locate(&pair);
+impl AnalysisCache {
+    /// Create a new, empty cache that holds at most `capacity` meanings. A
+    /// capacity of `0` disables caching.
+    pub fn new(capacity: usize) -> AnalysisCache {
+        AnalysisCache {
+            capacity: capacity,
+            entries: HashMap::new(),
+            folded: None,
+            generation: 0,
+            clock: 0,
+            hits: 0,
+        }
+    }
 
-        if let Some(str) = sym.to_symbol(heap) {
-            let def_value_form = try!(pair.caddr(heap));
-            let def_value_meaning = try!(analyze(heap,
-                                                 &def_value_form,
-                                                 location.clone()));
+    /// Get the maximum number of meanings this cache holds.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
 
-            let (i, j) = heap.environment.define((**str).clone());
-            return Ok(Meaning::new_definition(i, j, def_value_meaning, location));
-        }
+    /// Set the maximum number of meanings this cache holds, and forget every
+    /// cached meaning.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.invalidate();
+    }
 
-        return Err(format!("{}: Static error: can only define symbols, found: {}",
-                           location,
-                           *sym));
+    /// Get the number of meanings currently cached.
+    pub fn len(&self) -> usize {
+        self.entries.len()
     }
 
-    let msg = "Static error: improperly formed definition";
-    Err(if let Some(pair) = form.to_pair(heap) {
-        format!("{}: {}: {}", heap.locate(&pair), msg, **form)
-    } else {
-        format!("{}: {}", msg, **form)
-    })
+    /// Get the number of times a cached meaning was reused.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Forget every cached meaning, such as when a macro is defined.
+    pub fn invalidate(&mut self) {
+        self.entries.clear();
+        self.generation += 1;
+    }
+
+    /// Note that analysis folded an invocation of the primitive that is the
+    /// value of the j'th global.
+    pub fn note_folded(&mut self, j: u32, val: RootedValue) {
+        if let Some(ref mut folded) = self.folded {
+            folded.push((j, val));
+        }
+    }
 }
 
-fn analyze_set(heap: &mut Heap,
-               form: &RootedValue) -> MeaningResult {
+/// Hash the given form by its structure, consistently with
+/// `values_structurally_eq`.
+fn hash_form<H: Hasher>(form: &Value, state: &mut H) {
+    let mut pending = vec!(*form);
+    while let Some(val) = pending.pop() {
+        match val {
+            Value::Pair(cons) => {
+                0u8.hash(state);
+                pending.push(cons.unrooted_cdr());
+                pending.push(cons.unrooted_car());
+            },
+            Value::String(str) => {
+                1u8.hash(state);
+                (*str).hash(state);
+            },
+            _ => val.hash(state),
+        }
+    }
+}
+
+/// Analyze the given top-level form as `analyze` does, but reuse its meaning
+/// from the analysis cache when the cache is enabled and the same form was
+/// analyzed at the same location before. See `AnalysisCache`.
+pub fn analyze_cached(heap: &mut Heap,
+                      form: &RootedValue,
+                      location: Location) -> MeaningResult {
+    if heap.analysis_cache().capacity() == 0 {
+        return analyze(heap, form, location);
+    }
+
+    let key = {
+        let mut state = SipHasher::new();
+        hash_form(&**form, &mut state);
+        location.file.hash(&mut state);
+        location.line.hash(&mut state);
+        location.column.hash(&mut state);
+        state.finish()
+    };
+    let settings = AnalysisSettings::current(heap);
+
+    if let Some(meaning) = cached_meaning(heap, key, form, &location, settings) {
+        let cache = heap.analysis_cache();
+        cache.hits += 1;
+        cache.clock += 1;
+        let clock = cache.clock;
+        if let Some(entry) = cache.entries.get_mut(&key) {
+            entry.used = clock;
+        }
+        return Ok(meaning);
+    }
+
+    let generation = heap.analysis_cache().generation;
+    let outer = mem::replace(&mut heap.analysis_cache().folded, Some(vec!()));
+    let result = analyze(heap, form, location.clone());
+    let folded = mem::replace(&mut heap.analysis_cache().folded, outer)
+        .expect("The folded globals set above should still be set");
+    let meaning = try!(result);
+
+    let is_current = heap.analysis_cache().generation == generation
+        && AnalysisSettings::current(heap) == settings;
+    if is_current {
+        let cache = heap.analysis_cache();
+        if cache.entries.len() >= cache.capacity && !cache.entries.contains_key(&key) {
+            let oldest = cache.entries.iter()
+                .min_by(|&(_, entry)| entry.used)
+                .map(|(&key, _)| key);
+            if let Some(oldest) = oldest {
+                cache.entries.remove(&oldest);
+            }
+        }
+        cache.clock += 1;
+        cache.entries.insert(key, CachedAnalysis {
+            form: form.clone(),
+            location: location,
+            settings: settings,
+            folded: folded,
+            meaning: meaning.clone(),
+            used: cache.clock,
+        });
+    }
+    Ok(meaning)
+}
+
+/// Get the cached meaning of the given form, if there is one and it is not
+/// stale.
+fn cached_meaning(heap: &mut Heap,
+                  key: u64,
+                  form: &RootedValue,
+                  location: &Location,
+                  settings: AnalysisSettings) -> Option<Meaning> {
+    let (meaning, folded) = match heap.analysis_cache().entries.get(&key) {
+        Some(entry) => {
+            if entry.settings != settings
+                || entry.location.file != location.file
+                || entry.location.line != location.line
+                || entry.location.column != location.column
+                || !values_structurally_eq(&*entry.form, &**form) {
+                return None;
+            }
+            (entry.meaning.clone(), entry.folded.clone())
+        },
+        None => return None,
+    };
+
+    let global_act = heap.global_activation();
+    for &(j, ref val) in folded.iter() {
+        match global_act.fetch(heap, 0, j) {
+            Ok(ref current) if **current == **val => { },
+            _                                     => return None,
+        }
+    }
+    Some(meaning)
+}
+
+/// Analyze the given form, allocating its meaning in the current unit.
+fn analyze_form(heap: &mut Heap,
+                form: &RootedValue,
+                location: Location) -> MeaningResult {
+    if heap.analysis_depth() >= MAX_ANALYSIS_DEPTH {
+        return Err(E0119.error(format!("{}: Static error: forms are nested more than {} deep",
+                                       location,
+                                       MAX_ANALYSIS_DEPTH)));
+    }
+
+    heap.enter_analysis();
+    let result = analyze_nested_form(heap, form, location);
+    heap.exit_analysis();
+    result
+}
+
+fn analyze_nested_form(heap: &mut Heap,
+                       form: &RootedValue,
+                       location: Location) -> MeaningResult {
+    if form.is_atom() {
+        return analyze_atom(heap, form, location);
+    }
+
+    let pair = form.to_pair(heap).expect(
+        "If a value is not an atom, then it must be a pair.");
+
+    if let Some(sym) = pair.car(heap).to_symbol(heap) {
+        let transformer = if is_lexically_bound(heap, sym.as_slice()) {
+            None
+        } else {
+            heap.get_macro(&**sym)
+        };
+        if let Some(transformer) = transformer {
+            let expansion = try!(expand_macro(heap, &transformer, form, &location));
+            return analyze_form(heap, &expansion, location);
+        }
+    }
+
+    // Only the names of variables are interned, so that the operator of every
+    // invocation does not have to be.
+    let special_form = match pair.car(heap).to_symbol(heap) {
+        Some(sym) => Some((*sym).clone()),
+        None      => None,
+    };
+
+    match special_form.as_ref().map(|name| name.as_slice()) {
+        Some("quote")           => analyze_quoted(heap, form),
+        Some("define")          => analyze_definition(heap, form),
+        Some("define-macro")    => analyze_macro_definition(heap, form),
+        Some("set!")            => analyze_set(heap, form),
+        Some("lambda")          => analyze_lambda(heap, form),
+        Some("if")              => analyze_conditional(heap, form),
+        Some("begin")           => analyze_sequence(heap, form),
+        Some("the-environment") => analyze_the_environment(heap, form),
+        _                       => analyze_invocation(heap, form),
+    }
+}
+
+/// Return true if the given name is bound by an enclosing lambda or internal
+/// definition where it is used. Such a local variable shadows any macro with
+/// the same name.
+fn is_lexically_bound(heap: &Heap, name: &str) -> bool {
+    let id = match heap.environment.interned(name) {
+        Some(id) => id,
+        None     => return false,
+    };
+    match heap.environment.lookup(id) {
+        Some((i, _)) => i < heap.environment.depth(),
+        None         => false,
+    }
+}
+
+/// Expand a use of a macro by applying its transformer procedure to the whole
+/// form. Any pairs in the expansion that did not come from the reader are
+/// registered as originating from the macro use's location, so that errors in
+/// the expansion still point somewhere useful.
+fn expand_macro(heap: &mut Heap,
+                transformer: &RootedValue,
+                form: &RootedValue,
+                location: &Location) -> SchemeResult {
+    let depth = heap.stack_depth();
+    let result = apply_invocation(heap, transformer, vec!(form.clone()))
+        .and_then(|thunk| thunk.run(heap));
+    let expansion = try!(result.map_err(|e| unwind_failed_analysis(heap, depth, e)));
+    enlocate_expansion(heap, &expansion, location);
+    Ok(expansion)
+}
+
+/// Clean up after an evaluation during syntactic analysis, such as a call to a
+/// macro transformer, failed with the given error, and return the error. The
+/// failure is reported as an analysis error rather than by `evaluate_meaning`,
+/// so the call stack is unwound to the given depth here, and the location and
+/// irritants recorded for the error are forgotten.
+fn unwind_failed_analysis(heap: &mut Heap, depth: usize, error: SchemeError) -> SchemeError {
+    heap.unwind_to(depth);
+    heap.take_error_location();
+    heap.take_error_irritants();
+    error
+}
+
+fn enlocate_expansion(heap: &mut Heap, form: &RootedValue, location: &Location) {
+    if let Some(pair) = form.to_pair(heap) {
+        if heap.is_located(&pair) {
+            return;
+        }
+        heap.enlocate(location.clone(), pair.clone());
+
+        let car = pair.car(heap);
+        enlocate_expansion(heap, &car, location);
+        let cdr = pair.cdr(heap);
+        enlocate_expansion(heap, &cdr, location);
+    }
+}
+
+/// Analyze a `(define-macro name transformer)` form. The transformer expression
+/// is evaluated immediately in the global environment, and must produce a
+/// procedure that takes a use of the macro and returns its expansion. Macros
+/// are not hygienic, but a local variable with a macro's name shadows the
+/// macro, and a global definition with its name replaces it.
+fn analyze_macro_definition(heap: &mut Heap,
+                            form: &RootedValue) -> MeaningResult {
+    if let Ok(3) = form.len() {
+        let pair = form.to_pair(heap).expect(
+            "If len = 3, then form must be a pair");
+        let sym = try!(pair.cadr(heap));
+        let location = heap.locate(&pair);
+
+        if let Some(str) = sym.to_symbol(heap) {
+            let transformer_form = try!(pair.caddr(heap));
+            let transformer_meaning = try!(analyze_form(heap,
+                                                        &transformer_form,
+                                                        location.clone()));
+            let mut act = heap.global_activation();
+            let depth = heap.stack_depth();
+            let transformer = try!(transformer_meaning.evaluate(heap, &mut act)
+                .map_err(|e| unwind_failed_analysis(heap, depth, e)));
+            match *transformer {
+                Value::Procedure(_) | Value::Primitive(_) => { },
+                _ => {
+                    return Err(E0111.error(format!("{}: Static error: macro transformer must be \
+                                                    a procedure, found: {}",
+                                                   location,
+                                                   *transformer)));
+                },
+            }
+
+            heap.define_macro((**str).clone(), transformer);
+            let unspecified = heap.unspecified_symbol();
+            return Ok(Meaning::new_quotation(heap.meaning_unit(), &unspecified, location));
+        }
+
+        return Err(E0112.error(format!("{}: Static error: can only define-macro symbols, found: {}",
+                                       location,
+                                       *sym)));
+    }
+
+    let msg = "Static error: improperly formed define-macro";
+    Err(E0113.error(if let Some(pair) = form.to_pair(heap) {
+        format!("{}: {}: {}", heap.locate(&pair), msg, **form)
+    } else {
+        format!("{}: {}", msg, **form)
+    }))
+}
+
+/// Evaluate the Scheme prelude, which defines the parts of the standard library
+/// that are implemented in Scheme rather than as primitives, unless it has
+/// already been evaluated in this heap.
+///
+/// The prelude is loaded upon the first evaluation in a heap rather than when
+/// the heap is created, because `Rooted` values hold a pointer to their heap,
+/// and the heap is moved when it is returned from its constructor.
+///
+/// Reading and analyzing the prelude dominates startup time for short scripts.
+/// A heap loaded from an image that includes the prelude does not evaluate it
+/// again, so the `oxischeme` binary starts from an image built by `build.rs`.
+/// Embedders that start many heaps can do the same: save one after the prelude
+/// is loaded, with `Heap::save_image`, and load that image into each new heap.
+///
+/// The prelude only refers to primitives from within procedures, so a heap
+/// whose `PrimitiveSet` withholds some primitives can still load it, and only
+/// the procedures that call those primitives fail. If a form of the prelude
+/// does fail to evaluate, the rest of the prelude is still evaluated, and the
+/// first error is returned. The prelude is not evaluated again either way.
+pub fn ensure_prelude(heap: &mut Heap) -> Result<(), SchemeError> {
+    use read::read_from_str;
+
+    heap.ensure_created();
+    if !heap.mark_prelude_loaded() {
+        return Ok(());
+    }
+
+    // Warnings about the prelude are not the user's concern.
+    let warnings_enabled = heap.is_warnings_enabled();
+    heap.set_warnings_enabled(false);
+
+    let mut result = Ok(());
+    let reader = read_from_str(PRELUDE, heap, "prelude.scm");
+    for (location, read_result) in reader {
+        let form = read_result.ok().expect("The prelude should always be readable");
+        if let Err(error) = evaluate_with_backtrace(heap, &form, location) {
+            if result.is_ok() {
+                result = Err(error.to_scheme_error().context("Error evaluating the prelude"));
+            }
+        }
+    }
+
+    heap.set_warnings_enabled(warnings_enabled);
+    result
+}
+
+/// The source of the Scheme prelude.
+static PRELUDE: &'static str = include_str!("prelude.scm");
+
+/// Return true if the form doesn't need to be evaluated because it is
+/// "autoquoting" or "self evaluating", false otherwise.
+fn is_auto_quoting(form: &RootedValue) -> bool {
+    match **form {
+        Value::EmptyList    => false,
+        Value::Pair(_)      => false,
+        // Keywords, such as `#:key`, evaluate to themselves.
+        Value::Symbol(_)    => is_keyword(&**form),
+        // Vector literals evaluate to themselves, whether or not they are
+        // quoted.
+        Value::Vector(_)    => true,
+        Value::Bytevector(_) => true,
+        _                   => true,
+    }
+}
+
+fn analyze_atom(heap: &mut Heap,
+                form: &RootedValue,
+                location: Location) -> MeaningResult {
+    if is_auto_quoting(form) {
+        return Ok(Meaning::new_quotation(heap.meaning_unit(), form, location));
+    }
+
+    if let Some(sym) = form.to_symbol(heap) {
+        let id = heap.environment.intern(sym.as_slice());
+        let depth = heap.environment.depth();
+        let j = match heap.environment.lookup(id) {
+            Some((i, j)) if i < depth => {
+                heap.note_reference(id, i, j, location.clone(), false);
+                return Ok(Meaning::new_reference(heap.meaning_unit(), i, j, id, location));
+            },
+            Some((_, j)) => j,
+            // This is a reference to a global variable that hasn't been defined
+            // yet.
+            None         => heap.environment.define_global(id).1,
+        };
+        heap.note_reference(id, depth, j, location.clone(), false);
+
+        let global_act = heap.global_activation();
+        if !global_act.is_defined(j) {
+            heap.note_undefined_reference(j, id, location.clone());
+        }
+        return Ok(Meaning::new_global_reference(heap.meaning_unit(), global_act, j, id, location));
+    }
+
+    return Err(E0101.error(format!("Static error: Cannot evaluate: {}", **form)));
+}
+
+fn analyze_quoted(heap: &mut Heap, form: &RootedValue) -> MeaningResult {
+    if let Ok(2) = form.len() {
+        let pair = form.to_pair(heap).unwrap();
+        let quoted = form.cdr(heap).unwrap().car(heap).unwrap();
+        let location = heap.locate(&pair);
+        return Ok(Meaning::new_quotation(heap.meaning_unit(), &quoted, location));
+    }
+
+    let msg = "Static error: Wrong number of parts in quoted form";
+    Err(E0102.error(if let Some(pair) = form.to_pair(heap) {
+        format!("{}: {}", heap.locate(&pair), msg)
+    } else {
+        msg.to_string()
+    }))
+}
+
+fn analyze_the_environment(heap: &mut Heap, form: &RootedValue) -> MeaningResult {
+    let pair = form.to_pair(heap).unwrap();
+    let location = heap.locate(&pair);
+    if let Ok(1) = form.len() {
+        let scope = Rc::new(heap.environment.local_scope());
+        return Ok(heap.meaning_unit().alloc(MeaningData::TheEnvironment(scope),
+                                            evaluate_the_environment,
+                                            location));
+    }
+
+    Err(E0118.error(format!("{}: Static error: improperly formed the-environment: {}",
+                            location, **form)))
+}
+
+fn analyze_definition(heap: &mut Heap,
+                      form: &RootedValue) -> MeaningResult {
+    if let Ok(3) = form.len() {
+        let pair = form.to_pair(heap).expect(
+            "If len = 3, then form must be a pair");
+        let sym = try!(pair.cadr(heap));
+
+        let location = heap.locate(&pair);
+
+        if let Some(str) = sym.to_symbol(heap) {
+            let def_value_form = try!(pair.caddr(heap));
+            let mut def_value_meaning = try!(analyze_form(heap,
+                                                          &def_value_form,
+                                                          location.clone()));
+            // The lambda's documentation moves along with its location.
+            let doc = heap.documentation(def_value_meaning.location());
+            if def_value_meaning.name_lambda(&**str, &location) {
+                heap.set_documentation(&location, doc);
+            }
+
+            if heap.environment.is_global_scope() {
+                heap.record_definition((**str).clone(), location.clone());
+                // A global definition replaces any macro with the same name,
+                // just as a later `define-macro` would replace the global.
+                heap.remove_macro(&**str);
+            }
+
+            let id = heap.environment.intern(str.as_slice());
+            let (i, j) = heap.environment.define(id);
+            heap.note_binding(id, i, j, location.clone());
+            return Ok(Meaning::new_definition(heap.meaning_unit(),
+                                              i,
+                                              j,
+                                              def_value_meaning,
+                                              location));
+        }
+
+        return Err(E0103.error(format!("{}: Static error: can only define symbols, found: {}",
+                                       location,
+                                       *sym)));
+    }
+
+    let msg = "Static error: improperly formed definition";
+    Err(E0104.error(if let Some(pair) = form.to_pair(heap) {
+        format!("{}: {}: {}", heap.locate(&pair), msg, **form)
+    } else {
+        format!("{}: {}", msg, **form)
+    }))
+}
+
+fn analyze_set(heap: &mut Heap,
+               form: &RootedValue) -> MeaningResult {
     if let Ok(3) = form.len() {
         let pair = form.to_pair(heap).expect(
             "If len = 3, then form must be a pair");
@@ -620,11 +2644,14 @@ fn analyze_set(heap: &mut Heap,
 
         if let Some(str) = sym.to_symbol(heap) {
             let set_value_form = try!(pair.caddr(heap));
-            let set_value_meaning = try!(analyze(heap,
-                                                 &set_value_form,
-                                                 location.clone()));
-            if let Some((i, j)) = heap.environment.lookup(&**str) {
-                return Ok(Meaning::new_set_variable(i,
+            let set_value_meaning = try!(analyze_form(heap,
+                                                      &set_value_form,
+                                                      location.clone()));
+            let id = heap.environment.intern(str.as_slice());
+            if let Some((i, j)) = heap.environment.lookup(id) {
+                heap.note_reference(id, i, j, location.clone(), true);
+                return Ok(Meaning::new_set_variable(heap.meaning_unit(),
+                                                    i,
                                                     j,
                                                     set_value_meaning,
                                                     location));
@@ -632,81 +2659,103 @@ fn analyze_set(heap: &mut Heap,
 
             // This is setting a global variable that isn't defined yet, but
             // could be defined later. The check will happen at evaluation time.
-            let (i, j) = heap.environment.define_global((**str).clone());
-            return Ok(Meaning::new_set_variable(i,
+            let (i, j) = heap.environment.define_global(id);
+            heap.note_reference(id, i, j, location.clone(), true);
+            return Ok(Meaning::new_set_variable(heap.meaning_unit(),
+                                                i,
                                                 j,
                                                 set_value_meaning,
                                                 location));
         }
 
-        return Err(format!("{}: Static error: can only set! symbols, found: {}",
-                           location,
-                           *sym));
+        return Err(E0105.error(format!("{}: Static error: can only set! symbols, found: {}",
+                                       location,
+                                       *sym)));
     }
 
     let msg = "Static error: improperly formed set!";
-    Err(if let Some(pair) = form.to_pair(heap) {
+    Err(E0106.error(if let Some(pair) = form.to_pair(heap) {
         format!("{}: {}: {}", heap.locate(&pair), msg, **form)
     } else {
         format!("{}: {}", msg, **form)
-    })
+    }))
 }
 
 fn analyze_lambda(heap: &mut Heap,
                   form: &RootedValue) -> MeaningResult {
     let length = try!(form.len().ok().ok_or_else(|| {
         let msg = "Static error: improperly formed lambda";
-        if let Some(pair) = form.to_pair(heap) {
+        E0107.error(if let Some(pair) = form.to_pair(heap) {
             format!("{}: {}: {}", heap.locate(&pair), msg, **form)
         } else {
             format!("{}: {}", msg, **form)
-        }
+        })
     }));
 
     if length < 3 {
         let msg = "Static error: improperly formed lambda";
-        return Err(if let Some(pair) = form.to_pair(heap) {
+        return Err(E0107.error(if let Some(pair) = form.to_pair(heap) {
             format!("{}: {}: {}", heap.locate(&pair), msg, **form)
         } else {
             format!("{}: {}", msg, **form)
-        })
+        }))
     }
 
     let pair = form.to_pair(heap).unwrap();
     let location = heap.locate(&pair);
 
-    let body = pair.cddr(heap)
+    let mut body = pair.cddr(heap)
         .ok().expect("Must be here since length >= 3");
 
+    // A string literal followed by more body forms documents the procedure,
+    // rather than being evaluated.
+    let mut doc = None;
+    if let Some(body_pair) = body.to_pair(heap) {
+        let rest = body_pair.cdr(heap);
+        if let (Value::String(str), Value::Pair(_)) = (*body_pair.car(heap), *rest) {
+            doc = Some((*str).clone());
+            body = rest;
+        }
+    }
+    heap.set_documentation(&location, doc);
+
     let mut params = vec!();
-    let mut arity = 0;
     let params_form = pair.cadr(heap).ok().expect(
         "Must be here since length >= 3");
     for p in params_form.iter() {
-        arity += 1;
-        params.push(try!(p.ok().ok_or(format!("{}: Bad lambda parameters: {}",
-                                              location,
-                                              *params_form))));
+        params.push(try!(p.ok().ok_or(E0114.error(format!("{}: Bad lambda parameters: {}",
+                                                          location,
+                                                          *params_form)))));
+    }
+
+    let (params, signature, defaults) = try!(analyze_signature(heap,
+                                                               params,
+                                                               &params_form,
+                                                               &location));
+    let arity = params.len();
+    for default in defaults.into_iter().rev() {
+        body = Value::new_pair(heap, &default, &body);
     }
 
-    let mut param_names : Vec<String> = try!(params.into_iter().map(|p| {
+    let mut param_names : Vec<SymbolId> = try!(params.into_iter().map(|p| {
         let sym = try!(p.to_symbol(heap)
-                       .ok_or(format!("{}: Can only define symbol parameters, found {}",
-                                      location,
-                                      p)));
-        Ok((**sym).clone())
+                       .ok_or(E0115.error(format!("{}: Can only define symbol parameters, found {}",
+                                                  location,
+                                                  p))));
+        Ok(heap.environment.intern(sym.as_slice()))
     }).collect());
 
     // Find any definitions in the body, so we can add them to the extended
     // environment.
     let define = heap.define_symbol();
-    let mut local_definitions : Vec<String> = body.iter()
+    let mut local_definitions : Vec<SymbolId> = body.iter()
         .filter_map(|form_result| {
             if let Ok(form) = form_result {
                 if let Some(pair) = form.to_pair(heap) {
                     if pair.car(heap) == define {
                         if let Ok(name) = pair.cadr(heap) {
-                            return name.to_symbol(heap).map(|s| (**s).clone())
+                            return name.to_symbol(heap)
+                                .map(|s| heap.environment.intern(s.as_slice()))
                         }
                     }
                 }
@@ -716,15 +2765,159 @@ fn analyze_lambda(heap: &mut Heap,
         })
         .collect();
 
+    let depth = heap.environment.depth();
+    for &name in param_names.iter().chain(local_definitions.iter()) {
+        match heap.environment.lookup(name) {
+            Some((i, _)) if i < depth => {
+                let message = format!("`{}` shadows a binding in an enclosing scope",
+                                      heap.environment.name(name));
+                heap.warn(location.clone(), message);
+            },
+            _ => { },
+        }
+    }
+    let unused_candidates = param_names.clone();
+
     let mut new_bindings = Vec::with_capacity(param_names.len() + local_definitions.len());
     new_bindings.append(&mut param_names);
     new_bindings.append(&mut local_definitions);
 
     let body_meaning = try!(heap.with_extended_env(new_bindings, &|heap| {
+        for (j, &name) in unused_candidates.iter().enumerate() {
+            heap.note_binding(name, 0, j as u32, location.clone());
+        }
         make_meaning_sequence(heap, &body)
     }));
 
-    return Ok(Meaning::new_lambda(arity as u32, body_meaning, location));
+    // Parameters whose names start with an underscore are meant to be unused.
+    for (j, &name) in unused_candidates.iter().enumerate() {
+        let name = heap.environment.name(name).to_string();
+        if !name.starts_with("_") && !refers_to_local(&body_meaning, j as u32, 0) {
+            heap.warn(location.clone(), format!("unused parameter `{}`", name));
+        }
+    }
+
+    let body_meaning = mark_tail_calls(heap.meaning_unit(), &body_meaning);
+    return Ok(Meaning::new_lambda(heap.meaning_unit(),
+                                  arity as u32,
+                                  body_meaning,
+                                  location,
+                                  signature));
+}
+
+/// The sections of a lambda's parameter list.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Section {
+    Required,
+    Optional,
+    Keyword,
+}
+
+/// Split the optional and keyword parameters out of a lambda's parameter list,
+/// where they follow `#:optional` and `#:key` respectively, and may be written
+/// `(name default)` to give a default value other than `#f`. Return the names
+/// of all the parameters in the order of their bindings, the lambda's
+/// signature if it has any optional or keyword parameters, and the forms that
+/// give missing arguments their default values at the start of its body.
+fn analyze_signature(heap: &mut Heap,
+                     params: Vec<RootedValue>,
+                     params_form: &RootedValue,
+                     location: &Location)
+                     -> Result<(Vec<RootedValue>, Option<Rc<Signature>>, Vec<RootedValue>),
+                               SchemeError> {
+    let mut section = Section::Required;
+    let mut signature = Signature { required: 0, optional: 0, keywords: vec!() };
+    let mut names = vec!();
+    let mut defaults = vec!();
+    for p in params.into_iter() {
+        if is_keyword(&*p) {
+            section = match (section, p.to_symbol(heap).unwrap().as_slice()) {
+                (Section::Required, "#:optional") => Section::Optional,
+                (Section::Required, "#:key")      |
+                (Section::Optional, "#:key")      => Section::Keyword,
+                _                                 => {
+                    return Err(E0114.error(format!("{}: Bad lambda parameters: {}",
+                                                   location,
+                                                   **params_form)));
+                },
+            };
+            continue;
+        }
+
+        if section == Section::Required {
+            signature.required += 1;
+            names.push(p);
+            continue;
+        }
+
+        let (name, default) = match p.len() {
+            Ok(2) => {
+                let rest = p.cdr(heap).unwrap();
+                (p.car(heap).unwrap(), rest.car(heap).unwrap())
+            },
+            _     => (p, Rooted::new(heap, Value::new_boolean(false))),
+        };
+        let name_str = try!(name.to_symbol(heap).ok_or(
+            E0115.error(format!("{}: Can only define symbol parameters, found {}",
+                                location, *name))));
+        if section == Section::Optional {
+            signature.optional += 1;
+        } else {
+            signature.keywords.push(format!("#:{}", *name_str));
+        }
+
+        // (if (%default-object? name) (set! name default) #f)
+        let if_symbol = heap.if_symbol();
+        let set_bang = heap.set_bang_symbol();
+        let is_default = heap.get_or_create_symbol("%default-object?".to_string());
+        let test = value::list(heap, &[is_default, name.clone()]);
+        let set = value::list(heap, &[set_bang, name.clone(), default]);
+        let otherwise = Rooted::new(heap, Value::new_boolean(false));
+        defaults.push(value::list(heap, &[if_symbol, test, set, otherwise]));
+        names.push(name);
+    }
+
+    if section == Section::Required {
+        return Ok((names, None, defaults));
+    }
+    Ok((names, Some(Rc::new(signature)), defaults))
+}
+
+/// Return true if the given meaning, nested `level` blocks within a procedure
+/// body, refers to the j'th variable of the body's activation.
+fn refers_to_local(meaning: &Meaning, j: u32, level: u32) -> bool {
+    match *meaning.data() {
+        MeaningData::Reference(i, jj, _) if i == level && jj == j => true,
+        _ => children_with_levels(meaning, level).into_iter()
+            .any(|(child, child_level)| refers_to_local(child, j, child_level)),
+    }
+}
+
+/// Rebuild the given procedure body so that the invocations in tail position
+/// are evaluated by `evaluate_tail_invocation`. Nothing else in the body is
+/// changed.
+fn mark_tail_calls(unit: &MeaningUnit, meaning: &Meaning) -> Meaning {
+    let data = match *meaning.data() {
+        MeaningData::Conditional(ref condition, ref consequent, ref alternative) => {
+            MeaningData::Conditional(condition.clone(),
+                                     mark_tail_calls(unit, consequent),
+                                     mark_tail_calls(unit, alternative))
+        },
+        MeaningData::Sequence(ref first, ref second) => {
+            MeaningData::Sequence(first.clone(), mark_tail_calls(unit, second))
+        },
+        MeaningData::Invocation(..)  |
+        MeaningData::Invocation0(..) |
+        MeaningData::Invocation1(..) |
+        MeaningData::Invocation2(..) |
+        MeaningData::Invocation3(..) => {
+            return unit.alloc(meaning.data().clone(),
+                              evaluate_tail_invocation,
+                              meaning.location().clone());
+        },
+        _ => return meaning.clone(),
+    };
+    unit.alloc(data, meaning.evaluator(), meaning.location().clone())
 }
 
 fn analyze_conditional(heap: &mut Heap,
@@ -735,254 +2928,1736 @@ fn analyze_conditional(heap: &mut Heap,
         let location = heap.locate(&pair);
 
         let condition_form = try!(pair.cadr(heap));
-        let condition_meaning = try!(analyze(heap,
-                                             &condition_form,
-                                             location.clone()));
+        let condition_meaning = try!(analyze_form(heap,
+                                                  &condition_form,
+                                                  location.clone()));
 
         let consequent_form = try!(pair.caddr(heap));
-        let consequent_meaning = try!(analyze(heap,
-                                              &consequent_form,
-                                              location.clone()));
+        let consequent_meaning = try!(analyze_form(heap,
+                                                   &consequent_form,
+                                                   location.clone()));
 
         let alternative_form = try!(pair.cadddr(heap));
-        let alternative_meaning = try!(analyze(heap,
-                                               &alternative_form,
-                                               location.clone()));
+        let alternative_meaning = try!(analyze_form(heap,
+                                                    &alternative_form,
+                                                    location.clone()));
+
+        if heap.is_constant_folding() {
+            if let MeaningData::Quotation(ref val) = *condition_meaning.data() {
+                return Ok(if **val == Value::new_boolean(false) {
+                    alternative_meaning
+                } else {
+                    consequent_meaning
+                });
+            }
+        }
 
-        return Ok(Meaning::new_conditional(condition_meaning,
+        return Ok(Meaning::new_conditional(heap.meaning_unit(),
+                                           condition_meaning,
                                            consequent_meaning,
                                            alternative_meaning,
                                            location));
     }
 
     let msg = "Static error: improperly if expression";
-    Err(if let Some(pair) = form.to_pair(heap) {
+    Err(E0108.error(if let Some(pair) = form.to_pair(heap) {
         format!("{}: {}: {}", heap.locate(&pair), msg, **form)
     } else {
         format!("{}: {}", msg, **form)
-    })
+    }))
+}
+
+fn make_meaning_sequence(heap: &mut Heap,
+                         forms: &RootedValue) -> MeaningResult {
+    // Analyze the forms in order, and then chain their meanings together from
+    // the last one back, so that long sequences don't recurse on the stack.
+    let mut meanings = vec!();
+    let mut rest = forms.clone();
+    loop {
+        let cons = match rest.to_pair(heap) {
+            Some(cons) => cons,
+            None       => {
+                return Err(E0109.error(format!("Static error: improperly formed sequence: {}",
+                                               **forms)));
+            },
+        };
+        let form = cons.car(heap);
+        let location = heap.locate(&cons);
+        meanings.push((try!(analyze_form(heap, &form, location.clone())), location));
+
+        rest = cons.cdr(heap);
+        if *rest == Value::EmptyList {
+            break;
+        }
+    }
+
+    let (mut sequence, _) = meanings.pop().expect("Should have analyzed at least one form");
+    while let Some((first, location)) = meanings.pop() {
+        // The value of every form but the last is discarded, so forms that do
+        // nothing else need not be evaluated at all.
+        if !is_side_effect_free(&first) {
+            sequence = Meaning::new_sequence(heap.meaning_unit(), first, sequence, location);
+        }
+    }
+    Ok(sequence)
+}
+
+/// Return true if evaluating the given meaning does nothing but produce its
+/// value, false otherwise. References to local variables are not side-effect
+/// free, because referring to an internal definition before it is evaluated
+/// is an error, and neither are references to globals that are not defined
+/// yet.
+fn is_side_effect_free(meaning: &Meaning) -> bool {
+    match *meaning.data() {
+        MeaningData::Quotation(_) |
+        MeaningData::Lambda(..)   => true,
+        MeaningData::GlobalReference(ref global_act, j, _) => global_act.is_defined(j),
+        _ => false,
+    }
+}
+
+fn analyze_sequence(heap: &mut Heap,
+                    form: &RootedValue) -> MeaningResult {
+    let forms = try!(form.cdr(heap).ok_or(
+        E0109.error(format!("Static error: improperly formed sequence: {}", **form))));
+    make_meaning_sequence(heap, &forms)
+}
+
+fn make_meaning_vector(heap: &mut Heap,
+                       forms: &RootedValue,
+                       mut meanings: Vec<Meaning>) -> Result<Vec<Meaning>, SchemeError> {
+    let mut rest = forms.clone();
+    loop {
+        let val = *rest;
+        match val {
+            Value::EmptyList => return Ok(meanings),
+            Value::Pair(_)   => {
+                let pair = rest.to_pair(heap).unwrap();
+                let car = pair.car(heap);
+                let location = heap.locate(&pair);
+                meanings.push(try!(analyze_form(heap,
+                                                &car,
+                                                location)));
+                rest = pair.cdr(heap);
+            },
+            _ => {
+                panic!("Passed improper list to `make_meaning_vector`!");
+            }
+        }
+    }
 }
 
-fn make_meaning_sequence(heap: &mut Heap,
-                         forms: &RootedValue) -> MeaningResult {
-    if let Some(ref cons) = forms.to_pair(heap) {
-        let first_form = cons.car(heap);
-        let location = heap.locate(cons);
-        let first = try!(analyze(heap, &first_form, location.clone()));
+fn analyze_invocation(heap: &mut Heap,
+                      form: &RootedValue) -> MeaningResult {
+    if let Some(ref cons) = form.to_pair(heap) {
+        let location = heap.locate(cons);
+        let proc_form = cons.car(heap);
+        let proc_meaning = try!(analyze_form(heap, &proc_form, location.clone()));
+
+        let params_form = cons.cdr(heap);
+        let arity = try!(params_form.len().ok().ok_or(
+            E0110.error("Static error: improperly formed invocation".to_string())));
+        let params_meaning = try!(make_meaning_vector(
+            heap, &params_form, Vec::with_capacity(arity as usize)));
+        try!(check_static_arity(heap, &proc_meaning, params_meaning.len(), &location));
+
+        // A lambda literal that is invoked right away binds the parameters
+        // directly, rather than creating a closure only to apply it.
+        if let MeaningData::Lambda(_, ref body, _, _, None) = *proc_meaning.data() {
+            return Ok(Meaning::new_let(heap.meaning_unit(),
+                                       params_meaning,
+                                       body.clone(),
+                                       location));
+        }
+
+        if let Some(val) = fold_invocation(heap, &proc_meaning, &params_meaning) {
+            return Ok(Meaning::new_quotation(heap.meaning_unit(), &val, location));
+        }
+
+        if let Some((primitive, fast_path)) = fast_path_candidate(heap,
+                                                                  &proc_meaning,
+                                                                  params_meaning.len()) {
+            return Ok(Meaning::new_primitive_invocation(heap.meaning_unit(),
+                                                        proc_meaning,
+                                                        primitive,
+                                                        fast_path,
+                                                        params_meaning,
+                                                        location));
+        }
+
+        if let Some((inlined, body)) = inline_candidate(heap,
+                                                        &proc_meaning,
+                                                        params_meaning.len()) {
+            return Ok(Meaning::new_inlined_invocation(heap.meaning_unit(),
+                                                      proc_meaning,
+                                                      params_meaning,
+                                                      inlined,
+                                                      body,
+                                                      location));
+        }
+
+        return Ok(Meaning::new_invocation(heap.meaning_unit(),
+                                          proc_meaning,
+                                          params_meaning,
+                                          location));
+    }
+
+    return Err(E0110.error(format!("Static error: improperly formed invocation: {}", **form)));
+}
+
+/// Check the number of arguments passed by an invocation against the arity of
+/// the invoked procedure, when it is known statically: when the procedure is a
+/// lambda literal, or a reference to a global that currently holds a procedure
+/// or a primitive taking a fixed number of arguments.
+///
+/// Calling a lambda literal with the wrong number of arguments can never
+/// succeed, so it is an error. A global may be redefined before the call is
+/// evaluated, so a mismatch with its current value is only a warning.
+fn check_static_arity(heap: &mut Heap,
+                      procedure: &Meaning,
+                      num_args: usize,
+                      location: &Location) -> Result<(), SchemeError> {
+    let (arity, description, is_literal) = match *procedure.data() {
+        // Procedures with optional or keyword parameters take a range of
+        // numbers of arguments, which are checked when they are called.
+        MeaningData::Lambda(_, _, _, _, Some(_))        |
+        MeaningData::FlatLambda(_, _, _, _, _, Some(_)) => return Ok(()),
+        MeaningData::Lambda(arity, _, ref name, ref defined_at, _)         |
+        MeaningData::FlatLambda(arity, _, ref name, ref defined_at, _, _) => {
+            (arity as usize, describe_procedure(name, defined_at), true)
+        },
+        _ => {
+            let proc_val = match global_value(heap, procedure) {
+                Some((_, val)) => val,
+                None           => return Ok(()),
+            };
+            match *proc_val {
+                Value::Procedure(proc_ptr) if proc_ptr.signature.is_none() => {
+                    (proc_ptr.arity as usize,
+                     describe_procedure(&proc_ptr.name, &proc_ptr.location),
+                     false)
+                },
+                Value::Primitive(primitive) if primitive.is_builtin() => {
+                    match primitive.signature().and_then(|signature| signature.arity()) {
+                        Some(arity) => (arity, format!("`{}`", primitive.name()), false),
+                        None        => return Ok(()),
+                    }
+                },
+                _ => return Ok(()),
+            }
+        },
+    };
+
+    let (code, problem) = match arity.cmp(&num_args) {
+        Ordering::Less    => (&E0116, "too many"),
+        Ordering::Greater => (&E0117, "too few"),
+        Ordering::Equal   => return Ok(()),
+    };
+    let message = format!("{} arguments passed: expected {} args for {}, found {}",
+                          problem,
+                          arity,
+                          description,
+                          num_args);
+    if !is_literal {
+        heap.warn(location.clone(), message);
+        return Ok(());
+    }
+    Err(code.error(format!("{}: Static error: {}", location, message)))
+}
+
+/// If the given meaning is a reference to a global that is currently defined,
+/// return the global's index in the global activation and its current value.
+fn global_value(heap: &mut Heap, meaning: &Meaning) -> Option<(u32, RootedValue)> {
+    match *meaning.data() {
+        MeaningData::GlobalReference(ref global_act, j, _) => {
+            global_act.fetch(heap, 0, j).ok().map(|val| (j, val))
+        },
+        _ => None,
+    }
+}
+
+/// If the invoked procedure is a reference to a global that currently holds a
+/// primitive with a fast path taking the given number of arguments, return the
+/// primitive and its fast path.
+///
+/// Like inlining, this is a guess about the global's value at runtime, which is
+/// checked before each call.
+fn fast_path_candidate(heap: &mut Heap,
+                       procedure: &Meaning,
+                       num_args: usize) -> Option<(RootedValue, FastPath)> {
+    let proc_val = match global_value(heap, procedure) {
+        Some((_, val)) => val,
+        None           => return None,
+    };
+
+    let fast_path = match *proc_val {
+        Value::Primitive(p) if p.is_builtin() => match FastPath::from_name(p.name()) {
+            Some(fast_path) if fast_path.arity() == num_args => fast_path,
+            _                                                => return None,
+        },
+        _ => return None,
+    };
+
+    Some((proc_val, fast_path))
+}
+
+/// If constant folding is enabled, and this is an invocation of a global that
+/// currently holds a pure primitive, and every parameter is a quotation, then
+/// evaluate the invocation now and return its value. Invocations that fail are
+/// left alone, so that the error is reported if and when they are evaluated.
+fn fold_invocation(heap: &mut Heap,
+                   procedure: &Meaning,
+                   params: &Vec<Meaning>) -> Option<RootedValue> {
+    if !heap.is_constant_folding() {
+        return None;
+    }
+
+    // In the other modes, the result of an overflowing operation depends on the
+    // mode at the time of the call, which might not be the current mode.
+    if heap.arithmetic_mode() != ArithmeticMode::Error {
+        return None;
+    }
+
+    let (j, proc_val) = match global_value(heap, procedure) {
+        Some(global) => global,
+        None         => return None,
+    };
+
+    let primitive = match *proc_val {
+        Value::Primitive(p) if p.is_builtin() && primitives::is_pure_primitive(p.name()) => p,
+        _                                                                      => return None,
+    };
+
+    let mut args = Vec::with_capacity(params.len());
+    for param in params.iter() {
+        match *param.data() {
+            MeaningData::Quotation(ref val) => args.push(val.clone()),
+            _                               => return None,
+        }
+    }
+
+    match primitive.call(heap, args) {
+        Ok(Trampoline::Value(val)) => {
+            heap.analysis_cache().note_folded(j, proc_val.clone());
+            Some(val)
+        },
+        _ => None,
+    }
+}
+
+/// If the invoked procedure is a reference to a global that currently holds a
+/// small, non-recursive procedure defined at the top level and taking the
+/// given number of arguments, return that procedure and a copy of its body
+/// that can be evaluated in place of the invocation.
+///
+/// Inlining is a guess about the global's value at runtime: the inlined
+/// invocation checks that the global still holds the same procedure before
+/// using the inlined body.
+fn inline_candidate(heap: &mut Heap,
+                    procedure: &Meaning,
+                    num_args: usize) -> Option<(RootedValue, Meaning)> {
+    let budget = heap.inline_budget();
+    if budget == 0 {
+        return None;
+    }
+
+    let depth = heap.environment.depth();
+    let (j, proc_val) = match global_value(heap, procedure) {
+        Some(global) => global,
+        None         => return None,
+    };
+
+    let global_act = heap.global_activation();
+    let body = match *proc_val {
+        Value::Procedure(proc_ptr) => {
+            if proc_ptr.arity as usize != num_args
+                || proc_ptr.signature.is_some()
+                || proc_ptr.act != Some(*global_act) {
+                return None;
+            }
+            match proc_ptr.body {
+                Some(ref body) => (**body).clone(),
+                None           => return None,
+            }
+        },
+        _ => return None,
+    };
+
+    if meaning_cost(&body) > budget || refers_to_global(&body, j, 0) {
+        return None;
+    }
+
+    Some((proc_val, shift_global_references(heap.meaning_unit(), &body, 0, depth)))
+}
+
+/// Get the size of the given meaning, counted in meanings.
+fn meaning_cost(meaning: &Meaning) -> usize {
+    meaning.data().children().into_iter().fold(1, |cost, child| cost + meaning_cost(child))
+}
+
+/// Return true if the given procedure body, nested `level` activations deep,
+/// refers to the j'th global binding.
+fn refers_to_global(meaning: &Meaning, j: u32, level: u32) -> bool {
+    match *meaning.data() {
+        MeaningData::Reference(i, jj, _) |
+        MeaningData::Definition(i, jj, _) |
+        MeaningData::SetVariable(i, jj, _) if i == level + 1 && jj == j => true,
+        MeaningData::GlobalReference(_, jj, _) => jj == j,
+        MeaningData::Lambda(_, ref body, _, _, _) => refers_to_global(body, j, level + 1),
+        // A flat closure's body sees the global activation just beyond its
+        // captured variables, regardless of where the closure was created.
+        MeaningData::FlatLambda(_, ref body, _, _, ref captures, _) => {
+            refers_to_global(body, j, if captures.is_empty() { 0 } else { 1 })
+        },
+        MeaningData::Let(ref params, ref body, _) => {
+            params.iter().any(|p| refers_to_global(p, j, level))
+                || refers_to_global(body, j, level + 1)
+        },
+        MeaningData::InlinedInvocation(ref procedure, ref params, _, ref body, _) => {
+            refers_to_global(procedure, j, level)
+                || params.iter().any(|p| refers_to_global(p, j, level))
+                || refers_to_global(body, j, level + 1)
+        },
+        _ => meaning.data().children().into_iter().any(|c| refers_to_global(c, j, level)),
+    }
+}
+
+/// Adjust the global references in a procedure body, nested `level` activations
+/// deep, so that the body can be evaluated in an activation extending one that
+/// is `shift` activations away from the global activation, rather than
+/// extending the global activation itself. Global references hold the global
+/// activation directly, so only definitions and assignments need adjusting.
+fn shift_global_references(unit: &MeaningUnit,
+                           meaning: &Meaning,
+                           level: u32,
+                           shift: u32) -> Meaning {
+    let shifted = |i: u32| if i > level { i + shift } else { i };
+    let shift_all = |meanings: &Vec<Meaning>| -> Vec<Meaning> {
+        meanings.iter().map(|m| shift_global_references(unit, m, level, shift)).collect()
+    };
+
+    let data = match *meaning.data() {
+        MeaningData::Quotation(_)        |
+        MeaningData::GlobalReference(..) |
+        MeaningData::TheEnvironment(_)   => {
+            return meaning.clone();
+        },
+        MeaningData::Reference(i, j, name) => {
+            MeaningData::Reference(shifted(i), j, name)
+        },
+        MeaningData::Definition(i, j, ref val) => {
+            MeaningData::Definition(shifted(i), j, shift_global_references(unit, val, level, shift))
+        },
+        MeaningData::SetVariable(i, j, ref val) => {
+            MeaningData::SetVariable(shifted(i),
+                                     j,
+                                     shift_global_references(unit, val, level, shift))
+        },
+        MeaningData::Conditional(ref condition, ref consequent, ref alternative) => {
+            MeaningData::Conditional(shift_global_references(unit, condition, level, shift),
+                                     shift_global_references(unit, consequent, level, shift),
+                                     shift_global_references(unit, alternative, level, shift))
+        },
+        MeaningData::Sequence(ref first, ref second) => {
+            MeaningData::Sequence(shift_global_references(unit, first, level, shift),
+                                  shift_global_references(unit, second, level, shift))
+        },
+        MeaningData::Lambda(arity, ref body, ref name, ref location, ref signature) => {
+            MeaningData::Lambda(arity,
+                                shift_global_references(unit, body, level + 1, shift),
+                                name.clone(),
+                                location.clone(),
+                                signature.clone())
+        },
+        // A flat closure's body does not see the activations it was created
+        // in, so only its captures need adjusting.
+        MeaningData::FlatLambda(arity,
+                                ref body,
+                                ref name,
+                                ref location,
+                                ref captures,
+                                ref signature) => {
+            MeaningData::FlatLambda(arity,
+                                    body.clone(),
+                                    name.clone(),
+                                    location.clone(),
+                                    captures.iter()
+                                        .map(|&(i, j, var)| (shifted(i), j, var))
+                                        .collect(),
+                                    signature.clone())
+        },
+        MeaningData::Invocation(ref procedure, ref params, ref location) => {
+            MeaningData::Invocation(shift_global_references(unit, procedure, level, shift),
+                                    shift_all(params),
+                                    location.clone())
+        },
+        MeaningData::Invocation0(ref procedure, ref location) => {
+            MeaningData::Invocation0(shift_global_references(unit, procedure, level, shift),
+                                     location.clone())
+        },
+        MeaningData::Invocation1(ref procedure, ref a, ref location) => {
+            MeaningData::Invocation1(shift_global_references(unit, procedure, level, shift),
+                                     shift_global_references(unit, a, level, shift),
+                                     location.clone())
+        },
+        MeaningData::Invocation2(ref procedure, ref a, ref b, ref location) => {
+            MeaningData::Invocation2(shift_global_references(unit, procedure, level, shift),
+                                     shift_global_references(unit, a, level, shift),
+                                     shift_global_references(unit, b, level, shift),
+                                     location.clone())
+        },
+        MeaningData::Invocation3(ref procedure, ref a, ref b, ref c, ref location) => {
+            MeaningData::Invocation3(shift_global_references(unit, procedure, level, shift),
+                                     shift_global_references(unit, a, level, shift),
+                                     shift_global_references(unit, b, level, shift),
+                                     shift_global_references(unit, c, level, shift),
+                                     location.clone())
+        },
+        MeaningData::Let(ref params, ref body, ref location) => {
+            MeaningData::Let(shift_all(params),
+                             shift_global_references(unit, body, level + 1, shift),
+                             location.clone())
+        },
+        MeaningData::InlinedInvocation(ref procedure,
+                                       ref params,
+                                       ref inlined,
+                                       ref body,
+                                       ref location) => {
+            MeaningData::InlinedInvocation(shift_global_references(unit, procedure, level, shift),
+                                           shift_all(params),
+                                           inlined.clone(),
+                                           shift_global_references(unit, body, level + 1, shift),
+                                           location.clone())
+        },
+        MeaningData::PrimitiveInvocation(ref procedure,
+                                         ref primitive,
+                                         fast_path,
+                                         ref params,
+                                         ref location) => {
+            MeaningData::PrimitiveInvocation(shift_global_references(unit, procedure, level, shift),
+                                             primitive.clone(),
+                                             fast_path,
+                                             shift_all(params),
+                                             location.clone())
+        },
+    };
+
+    unit.alloc(data, meaning.evaluator(), meaning.location().clone())
+}
+
+/// ## Flat Closures
+///
+/// Lambdas normally capture the entire activation chain they are created in,
+/// which keeps every enclosing activation alive for as long as the closure is,
+/// and makes references to variables in distant activations walk the whole
+/// chain. When `Heap::is_flat_closures` is enabled, lambdas nested inside other
+/// procedures whose free variables are never assigned or defined after the
+/// fact are converted to `FlatLambda`s, which copy just those variables into
+/// a single activation when the closure is created.
+///
+/// The pass numbers lexical blocks absolutely: the global activation is block
+/// `0`, and a meaning nested `m` blocks deep sees a reference `(i, j)` as the
+/// j'th variable of block `m - i`.
+
+/// Convert every eligible lambda in the given top level meaning into a flat
+/// closure.
+fn flatten_closures(meaning: &Meaning) -> Meaning {
+    let mut unsafe_vars = HashSet::new();
+    collect_unsafe_variables(meaning, 0, &mut unsafe_vars);
+    flatten_in(&meaning.unit(), meaning, 0, &unsafe_vars)
+}
+
+/// Get the sub-meanings of a meaning nested `level` blocks deep, along with the
+/// level each of them is nested at. The bodies of flat closures are not
+/// included, because they do not see the enclosing blocks.
+fn children_with_levels<'a>(meaning: &'a Meaning, level: u32) -> Vec<(&'a Meaning, u32)> {
+    match *meaning.data() {
+        MeaningData::Lambda(_, ref body, _, _, _) => vec!((body, level + 1)),
+        MeaningData::FlatLambda(..) => vec!(),
+        MeaningData::Let(ref params, ref body, _) => {
+            let mut children : Vec<(&Meaning, u32)> = params.iter().map(|p| (p, level)).collect();
+            children.push((body, level + 1));
+            children
+        },
+        MeaningData::InlinedInvocation(ref procedure, ref params, _, ref body, _) => {
+            let mut children = vec!((procedure, level));
+            children.extend(params.iter().map(|p| (p, level)));
+            children.push((body, level + 1));
+            children
+        },
+        _ => meaning.data().children().into_iter().map(|c| (c, level)).collect(),
+    }
+}
+
+/// Collect the absolute coordinates of every non-global variable that is
+/// assigned with `set!` or defined after its block is created. Copying these
+/// into a flat closure could observe a stale or missing value.
+fn collect_unsafe_variables(meaning: &Meaning, level: u32, unsafe_vars: &mut HashSet<(u32, u32)>) {
+    match *meaning.data() {
+        MeaningData::Definition(i, j, _) |
+        MeaningData::SetVariable(i, j, _) if i < level => {
+            unsafe_vars.insert((level - i, j));
+        },
+        _ => { },
+    }
+
+    for (child, child_level) in children_with_levels(meaning, level).into_iter() {
+        collect_unsafe_variables(child, child_level, unsafe_vars);
+    }
+}
+
+/// Collect the absolute coordinates and names of the variables from blocks
+/// `1..outer` that the given meaning, nested `level` blocks deep, refers to.
+fn collect_free_variables(meaning: &Meaning,
+                          level: u32,
+                          outer: u32,
+                          free: &mut Vec<(u32, u32, SymbolId)>) {
+    {
+        let mut add = |block: u32, j: u32, name: SymbolId| {
+            if block >= 1 && block <= outer
+                && !free.iter().any(|&(b, jj, _)| b == block && jj == j) {
+                free.push((block, j, name));
+            }
+        };
+
+        match *meaning.data() {
+            MeaningData::Reference(i, j, name) => add(level - i, j, name),
+            MeaningData::FlatLambda(_, _, _, _, ref captures, _) => {
+                for &(i, j, name) in captures.iter() {
+                    add(level - i, j, name);
+                }
+            },
+            _ => { },
+        }
+    }
+
+    for (child, child_level) in children_with_levels(meaning, level).into_iter() {
+        collect_free_variables(child, child_level, outer, free);
+    }
+}
+
+/// Rebuild the given meaning, nested `level` blocks deep, with each of its
+/// sub-meanings replaced by the result of `f` on it and its level.
+fn map_children<F>(unit: &MeaningUnit, meaning: &Meaning, level: u32, f: &mut F) -> Meaning
+    where F: FnMut(&Meaning, u32) -> Meaning
+{
+    fn map_all<F>(meanings: &Vec<Meaning>, level: u32, f: &mut F) -> Vec<Meaning>
+        where F: FnMut(&Meaning, u32) -> Meaning
+    {
+        let mut mapped = Vec::with_capacity(meanings.len());
+        for m in meanings.iter() {
+            mapped.push((*f)(m, level));
+        }
+        mapped
+    }
+
+    let data = match *meaning.data() {
+        MeaningData::Quotation(_)        |
+        MeaningData::Reference(..)       |
+        MeaningData::GlobalReference(..) |
+        MeaningData::TheEnvironment(_)   |
+        MeaningData::FlatLambda(..)      => return meaning.clone(),
+        MeaningData::Definition(i, j, ref val) => {
+            MeaningData::Definition(i, j, (*f)(val, level))
+        },
+        MeaningData::SetVariable(i, j, ref val) => {
+            MeaningData::SetVariable(i, j, (*f)(val, level))
+        },
+        MeaningData::Conditional(ref condition, ref consequent, ref alternative) => {
+            MeaningData::Conditional((*f)(condition, level),
+                                     (*f)(consequent, level),
+                                     (*f)(alternative, level))
+        },
+        MeaningData::Sequence(ref first, ref second) => {
+            MeaningData::Sequence((*f)(first, level), (*f)(second, level))
+        },
+        MeaningData::Lambda(arity, ref body, ref name, ref location, ref signature) => {
+            MeaningData::Lambda(arity,
+                                (*f)(body, level + 1),
+                                name.clone(),
+                                location.clone(),
+                                signature.clone())
+        },
+        MeaningData::Invocation(ref procedure, ref params, ref location) => {
+            MeaningData::Invocation((*f)(procedure, level),
+                                    map_all(params, level, f),
+                                    location.clone())
+        },
+        MeaningData::Invocation0(ref procedure, ref location) => {
+            MeaningData::Invocation0((*f)(procedure, level), location.clone())
+        },
+        MeaningData::Invocation1(ref procedure, ref a, ref location) => {
+            MeaningData::Invocation1((*f)(procedure, level),
+                                     (*f)(a, level),
+                                     location.clone())
+        },
+        MeaningData::Invocation2(ref procedure, ref a, ref b, ref location) => {
+            MeaningData::Invocation2((*f)(procedure, level),
+                                     (*f)(a, level),
+                                     (*f)(b, level),
+                                     location.clone())
+        },
+        MeaningData::Invocation3(ref procedure, ref a, ref b, ref c, ref location) => {
+            MeaningData::Invocation3((*f)(procedure, level),
+                                     (*f)(a, level),
+                                     (*f)(b, level),
+                                     (*f)(c, level),
+                                     location.clone())
+        },
+        MeaningData::Let(ref params, ref body, ref location) => {
+            MeaningData::Let(map_all(params, level, f), (*f)(body, level + 1), location.clone())
+        },
+        MeaningData::InlinedInvocation(ref procedure,
+                                       ref params,
+                                       ref inlined,
+                                       ref body,
+                                       ref location) => {
+            MeaningData::InlinedInvocation((*f)(procedure, level),
+                                           map_all(params, level, f),
+                                           inlined.clone(),
+                                           (*f)(body, level + 1),
+                                           location.clone())
+        },
+        MeaningData::PrimitiveInvocation(ref procedure,
+                                         ref primitive,
+                                         fast_path,
+                                         ref params,
+                                         ref location) => {
+            MeaningData::PrimitiveInvocation((*f)(procedure, level),
+                                             primitive.clone(),
+                                             fast_path,
+                                             map_all(params, level, f),
+                                             location.clone())
+        },
+    };
+
+    unit.alloc(data, meaning.evaluator(), meaning.location().clone())
+}
+
+/// Flatten the lambdas within the given meaning, nested `level` blocks deep,
+/// innermost first.
+fn flatten_in(unit: &MeaningUnit,
+              meaning: &Meaning,
+              level: u32,
+              unsafe_vars: &HashSet<(u32, u32)>) -> Meaning {
+    let meaning = map_children(unit, meaning, level, &mut |child: &Meaning, child_level: u32| {
+        flatten_in(unit, child, child_level, unsafe_vars)
+    });
+
+    // Lambdas at the top level only capture the global activation already.
+    if level == 0 {
+        return meaning;
+    }
+
+    let flattened = match *meaning.data() {
+        MeaningData::Lambda(arity, ref body, ref name, ref location, ref signature) => {
+            let mut free = vec!();
+            collect_free_variables(body, level + 1, level, &mut free);
+            if captures_environment(body)
+                || free.iter().any(|&(block, j, _)| unsafe_vars.contains(&(block, j))) {
+                None
+            } else {
+                let c = if free.is_empty() { 0 } else { 1 };
+                let captures = free.iter()
+                    .map(|&(block, j, name)| (level - block, j, name))
+                    .collect();
+                Some(MeaningData::FlatLambda(arity,
+                                             remap(unit, body, level + 1, level, &free, c),
+                                             name.clone(),
+                                             location.clone(),
+                                             captures,
+                                             signature.clone()))
+            }
+        },
+        _ => None,
+    };
+
+    match flattened {
+        Some(data) => unit.alloc(data, evaluate_lambda, meaning.location().clone()),
+        None => meaning,
+    }
+}
+
+/// Return true if the given meaning reifies the environment it is evaluated in
+/// with `(the-environment)`, which must see the whole activation chain and so
+/// cannot be within a flat closure.
+fn captures_environment(meaning: &Meaning) -> bool {
+    match *meaning.data() {
+        MeaningData::TheEnvironment(_) => true,
+        ref data => data.children().into_iter().any(captures_environment),
+    }
+}
+
+/// Rewrite the coordinates within the body of a lambda created `outer` blocks
+/// deep, for when it becomes a flat closure with `c` activations (either zero
+/// or one) of captured `free` variables between it and the global activation.
+/// The given meaning is nested `level` blocks deep in the original body.
+fn remap(unit: &MeaningUnit,
+         meaning: &Meaning,
+         level: u32,
+         outer: u32,
+         free: &Vec<(u32, u32, SymbolId)>,
+         c: u32) -> Meaning {
+    let new_level = level - outer + c;
+    let coordinates = |i: u32, j: u32| -> (u32, u32) {
+        let block = level - i;
+        if block == 0 {
+            (new_level, j)
+        } else if block <= outer {
+            let k = free.iter()
+                .position(|&(b, jj, _)| b == block && jj == j)
+                .expect("free variables should have been collected");
+            (new_level - 1, k as u32)
+        } else {
+            (i, j)
+        }
+    };
+
+    let data = match *meaning.data() {
+        MeaningData::Reference(i, j, name) => {
+            let (i, j) = coordinates(i, j);
+            MeaningData::Reference(i, j, name)
+        },
+        MeaningData::Definition(i, j, ref val) => {
+            let (i, j) = coordinates(i, j);
+            MeaningData::Definition(i, j, remap(unit, val, level, outer, free, c))
+        },
+        MeaningData::SetVariable(i, j, ref val) => {
+            let (i, j) = coordinates(i, j);
+            MeaningData::SetVariable(i, j, remap(unit, val, level, outer, free, c))
+        },
+        MeaningData::FlatLambda(arity,
+                                ref body,
+                                ref name,
+                                ref location,
+                                ref captures,
+                                ref signature) => {
+            MeaningData::FlatLambda(arity,
+                                    body.clone(),
+                                    name.clone(),
+                                    location.clone(),
+                                    captures.iter().map(|&(i, j, var)| {
+                                        let (i, j) = coordinates(i, j);
+                                        (i, j, var)
+                                    }).collect(),
+                                    signature.clone())
+        },
+        _ => {
+            return map_children(unit, meaning, level, &mut |child: &Meaning, child_level: u32| {
+                remap(unit, child, child_level, outer, free, c)
+            });
+        },
+    };
+
+    unit.alloc(data, meaning.evaluator(), meaning.location().clone())
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use environment::{RootedActivationPtr};
+    use heap::{Heap, Rooted};
+    use read::{Location};
+    use value::{list, Value};
+
+    #[test]
+    fn test_eval_integer() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_integer.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(42));
+    }
+
+    #[test]
+    fn test_eval_shebang() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_shebang.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(42));
+    }
+
+    #[test]
+    fn test_eval_boolean() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_boolean.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_boolean(true));
+    }
+
+    #[test]
+    fn test_eval_quoted() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_quoted.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::EmptyList);
+    }
+
+    #[test]
+    fn test_eval_vector_literals() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_vector_literals.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        heap.collect_garbage();
+        assert_eq!(format!("{}", *result),
+                   "(#(1 \"two\" (3 4) #(5)) #(six #u8(7 8)) #u8() #t)");
+    }
+
+    #[test]
+    fn test_eval_if_consequent() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_if_consequent.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(1));
+    }
+
+    #[test]
+    fn test_eval_if_alternative() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_if_alternative.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(2));
+    }
+
+    #[test]
+    fn test_eval_begin() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_begin.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(2));
+    }
+
+    #[test]
+    fn test_eval_variables() {
+        let heap = &mut Heap::new();
+
+        let define_symbol = heap.define_symbol();
+        let set_bang_symbol = heap.set_bang_symbol();
+        let foo_symbol = heap.get_or_create_symbol("foo".to_string());
+
+        let mut def_items = [
+            define_symbol,
+            foo_symbol,
+            Rooted::new(heap, Value::new_integer(2))
+        ];
+        let def_form = list(heap, &mut def_items);
+        evaluate(heap, &def_form, Location::unknown()).ok()
+            .expect("Should be able to define");
+
+        let foo_symbol_ = heap.get_or_create_symbol("foo".to_string());
+
+        let def_val = evaluate(heap, &foo_symbol_, Location::unknown()).ok()
+            .expect("Should be able to get a defined symbol's value");
+        assert_eq!(*def_val, Value::new_integer(2));
+
+        let mut set_items = [
+            set_bang_symbol,
+            foo_symbol_,
+            Rooted::new(heap, Value::new_integer(1))
+        ];
+        let set_form = list(heap, &mut set_items);
+        evaluate(heap, &set_form, Location::unknown()).ok()
+            .expect("Should be able to define");
+
+        let foo_symbol__ = heap.get_or_create_symbol("foo".to_string());
+
+        let set_val = evaluate(heap, &foo_symbol__, Location::unknown()).ok()
+            .expect("Should be able to get a defined symbol's value");
+        assert_eq!(*set_val, Value::new_integer(1));
+    }
+
+    #[test]
+    fn test_eval_and_call_lambda() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_and_call_lambda.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(5));
+    }
+
+    #[test]
+    fn test_eval_closures() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_closures.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(1));
+    }
+
+    #[test]
+    fn test_ref_defined_later() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_ref_defined_later.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(1));
+    }
+
+    #[test]
+    fn test_set_defined_later() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_set_defined_later.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(5));
+    }
+
+    #[test]
+    fn test_rooting_bug() {
+        let mut heap = Heap::new();
+        evaluate_file(&mut heap, "./tests/rooting-bug.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert!(true, "Should be able to evaluate that file without panicking.");
+    }
+
+    #[test]
+    fn test_rooting_bug_gc_stress() {
+        let mut heap = Heap::new();
+        heap.set_gc_stress(true);
+        let result = evaluate_file(&mut heap, "./tests/rooting-bug.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "(1)");
+    }
+
+    #[test]
+    fn test_eval_local_definitions() {
+        let mut heap = Heap::new();
+        match evaluate_file(&mut heap, "./tests/test_eval_local_definitions.scm") {
+            Err(msg) => panic!(msg),
+            Ok(result) => assert_eq!(*result, Value::new_integer(120)),
+        }
+    }
+
+    #[test]
+    fn test_eval_define_macro() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_eval_define_macro.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(3));
+    }
+
+    #[test]
+    fn test_eval_macro_shadowing() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_eval_macro_shadowing.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "(3 40 42)");
+    }
+
+    #[test]
+    fn test_prelude_for() {
+        let mut heap = Heap::new();
+        let result = evaluate_file(&mut heap, "./tests/test_prelude_for.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(43));
+    }
+
+    #[test]
+    fn test_prelude_match() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_prelude_match.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result),
+                   "(zero nil (diagonal 3) 3 (starts-with 4) foo other (pair-vector 1 2) \
+                    (nested-vector b 3) other other)");
+    }
+
+    #[test]
+    fn test_prelude_quasiquote() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_prelude_quasiquote.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result),
+                   "((a 2 3 4 e) (1 . 2) (x (quasiquote (y (unquote (z 2))))) 2 (3 4) ())");
+    }
+
+    #[test]
+    fn test_prelude_streams() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_prelude_streams.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        // Taking the first three evens forces only the squares up to 16, and
+        // forcing the same elements again must not re-evaluate them.
+        assert_eq!(format!("{}", *result), "((0 4 16) (0 4 16) 5 (1 2) (0 1 2))");
+    }
+
+    #[test]
+    fn test_prelude_streams_retention() {
+        let live_cons_cells_after = |file: &str| {
+            let heap = &mut Heap::new();
+            let result = evaluate_file(heap, file)
+                .ok()
+                .expect("Should be able to eval a file.");
+            assert_eq!(*result, Value::new_integer(3000));
+            drop(result);
+            heap.collect_garbage();
+            heap.statistics().live_cons_cells
+        };
+
+        // Holding on to the head of a long, partially forced stream retains
+        // every forced element, but walking a stream without holding its head
+        // lets the forced prefix be collected.
+        let retained = live_cons_cells_after("./tests/test_prelude_streams_retained.scm");
+        let unretained = live_cons_cells_after("./tests/test_prelude_streams_unretained.scm");
+        assert!(retained > unretained + 3000);
+    }
+
+    #[test]
+    fn test_prelude_list_tabulate() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_prelude_list_tabulate.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        let pair = result.to_pair(heap)
+            .expect("Result should be a pair");
+        assert_eq!(*pair.car(heap), Value::new_integer(0));
+        assert_eq!(*pair.cadr(heap).ok().expect("pair.cadr"),
+                   Value::new_integer(1));
+        assert_eq!(*pair.caddr(heap).ok().expect("pair.caddr"),
+                   Value::new_integer(4));
+        assert_eq!(*pair.cdddr(heap).ok().expect("pair.cdddr"),
+                   Value::EmptyList);
+    }
+
+    #[test]
+    fn test_eval_breakpoint() {
+        use std::sync::{Arc, Mutex};
+
+        let heap = &mut Heap::new();
+        let hits = Arc::new(Mutex::new(0));
+        let h = hits.clone();
+        heap.set_debugger(Some(Box::new(move |_: &mut Heap, _: &RootedActivationPtr, m: &Meaning| {
+            assert_eq!(m.location().line, 2);
+            *h.lock().unwrap() += 1;
+            DebugAction::Continue
+        })));
+        heap.set_breakpoint("./tests/test_eval_breakpoint.scm", 2);
+
+        let result = evaluate_file(heap, "./tests/test_eval_breakpoint.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(2));
+        assert_eq!(*hits.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_eval_single_step() {
+        use std::sync::{Arc, Mutex};
+
+        let heap = &mut Heap::new();
+        let steps = Arc::new(Mutex::new(0));
+        let s = steps.clone();
+        heap.set_debugger(Some(Box::new(move |_: &mut Heap, _: &RootedActivationPtr, _: &Meaning| {
+            *s.lock().unwrap() += 1;
+            DebugAction::Step
+        })));
+        heap.set_breakpoint("./tests/test_eval_breakpoint.scm", 1);
+
+        evaluate_file(heap, "./tests/test_eval_breakpoint.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert!(*steps.lock().unwrap() > 3);
+    }
+
+    #[test]
+    fn test_eval_meaning_debug() {
+        use read::{read_from_str};
 
-        if *cons.cdr(heap) == Value::EmptyList {
-            return Ok(first);
-        } else {
-            let rest_forms = cons.cdr(heap);
-            let rest = try!(make_meaning_sequence(heap, &rest_forms));
-            return Ok(Meaning::new_sequence(first, rest, location));
-        }
+        let heap = &mut Heap::new();
+        let mut reader = read_from_str("(f (g 1 2) (h 3) 4)", heap, "debug.scm");
+        let (location, form) = reader.next().expect("Should have a form.");
+        let form = form.ok().expect("Should be able to read the form.");
+        let meaning = analyze(heap, &form, location)
+            .ok()
+            .expect("Should be able to analyze the form.");
+        assert_eq!(format!("{:?}", meaning),
+                   "Meaning { kind: invocation, location: debug.scm:1:1, children: 4 }");
     }
 
-    Err(format!("Static error: improperly formed sequence: {}", **forms))
-}
+    #[test]
+    fn test_eval_meaning_unit() {
+        use read::{read_from_str};
 
-fn analyze_sequence(heap: &mut Heap,
-                    form: &RootedValue) -> MeaningResult {
-    let forms = try!(form.cdr(heap).ok_or(
-        format!("Static error: improperly formed sequence: {}", **form)));
-    make_meaning_sequence(heap, &forms)
-}
+        let heap = &mut Heap::new();
+        let mut reader = read_from_str("(f (g 1 2) (h 3) 4) (f)", heap, "unit.scm");
+        let (location, form) = reader.next().expect("Should have a form.");
+        let form = form.ok().expect("Should be able to read the form.");
+        let first = analyze(heap, &form, location)
+            .ok()
+            .expect("Should be able to analyze the form.");
+        let (location, form) = reader.next().expect("Should have a second form.");
+        let form = form.ok().expect("Should be able to read the form.");
+        let second = analyze(heap, &form, location)
+            .ok()
+            .expect("Should be able to analyze the form.");
 
-fn make_meaning_vector(heap: &mut Heap,
-                       forms: &RootedValue,
-                       mut meanings: Vec<Meaning>) -> Result<Vec<Meaning>, String> {
-    match **forms {
-        Value::EmptyList => Ok(meanings),
-        Value::Pair(ref cons) => {
-            let car = cons.car(heap);
-            let rest = cons.cdr(heap);
-            let pair = forms.to_pair(heap).unwrap();
-            let location = heap.locate(&pair);
-            meanings.push(try!(analyze(heap,
-                                       &car,
-                                       location)));
-            make_meaning_vector(heap, &rest, meanings)
-        },
-        _ => {
-            panic!("Passed improper list to `make_meaning_vector`!");
-        }
+        assert_eq!(first.unit().len(), 10);
+        assert_eq!(second.unit().len(), 2);
+        assert_eq!(heap.meaning_unit().len(), 0);
+
+        let copy = first.clone();
+        drop(first);
+        assert_eq!(copy.identity(), copy.clone().identity());
+        assert_eq!(format!("{:?}", copy),
+                   "Meaning { kind: invocation, location: unit.scm:1:1, children: 4 }");
     }
-}
 
-fn analyze_invocation(heap: &mut Heap,
-                      form: &RootedValue) -> MeaningResult {
-    if let Some(ref cons) = form.to_pair(heap) {
-        let location = heap.locate(cons);
-        let proc_form = cons.car(heap);
-        let proc_meaning = try!(analyze(heap, &proc_form, location.clone()));
+    #[test]
+    fn test_eval_meaning_outlives_parent() {
+        use read::{read_from_str};
 
-        let params_form = cons.cdr(heap);
-        let arity = try!(params_form.len().ok().ok_or(
-            "Static error: improperly formed invocation".to_string()));
-        let params_meaning = try!(make_meaning_vector(
-            heap, &params_form, Vec::with_capacity(arity as usize)));
+        let heap = &mut Heap::new();
+        let mut reader = read_from_str("(f (g 1 2) 4)", heap, "child.scm");
+        let (location, form) = reader.next().expect("Should have a form.");
+        let form = form.ok().expect("Should be able to read the form.");
+        let parent = analyze(heap, &form, location)
+            .ok()
+            .expect("Should be able to analyze the form.");
 
-        return Ok(Meaning::new_invocation(proc_meaning, params_meaning, location));
-    }
+        let child = parent.data().children()[1].clone();
+        let identity = child.identity();
+        drop(parent);
 
-    return Err(format!("Static error: improperly formed invocation: {}", **form));
-}
+        assert_eq!(child.identity(), identity);
+        assert_eq!(child.data().kind(), "invocation");
+        assert_eq!(child.data().child_count(), 3);
+        assert_eq!(child.unit().len(), 7);
+        assert!(disassemble(&heap.environment, &child).contains("quotation 2"));
+    }
 
-// TESTS -----------------------------------------------------------------------
+    #[test]
+    fn test_eval_inline() {
+        let heap = &mut Heap::new();
+        heap.set_inline_budget(32);
+        let result = evaluate_file(heap, "./tests/test_eval_inline.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        let printed = format!("{}", *result);
+        assert!(printed.starts_with("(10 4 "),
+                "Should use the inlined body until `square` is set: {}", printed);
+        assert!(printed.contains("inlined-invocation"),
+                "Should inline `square` into `add1-square`: {}", printed);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use heap::{Heap, Rooted};
-    use read::{Location};
-    use value::{list, Value};
+    #[test]
+    fn test_eval_let() {
+        use vm::{ExecutionMode};
+
+        for &(mode, flat_closures) in [(ExecutionMode::Interpreter, false),
+                                       (ExecutionMode::Interpreter, true),
+                                       (ExecutionMode::Bytecode, false)].iter() {
+            let heap = &mut Heap::new();
+            heap.set_execution_mode(mode);
+            heap.set_flat_closures(flat_closures);
+            let result = evaluate_file(heap, "./tests/test_eval_let.scm")
+                .ok()
+                .expect("Should be able to eval a file.");
+            assert_eq!(format!("{}", *result), "(5 49 50005000)");
+
+            let disassembly = heap.eval_str("(%disassemble make-counter)")
+                .ok()
+                .expect("Should be able to disassemble `make-counter`.");
+            assert!(format!("{}", *disassembly).contains("let"),
+                    "Should bind the lambda literal's parameters directly: {}", *disassembly);
+        }
+    }
 
     #[test]
-    fn test_eval_integer() {
-        let mut heap = Heap::new();
-        let result = evaluate_file(&mut heap, "./tests/test_eval_integer.scm")
+    fn test_eval_dead_code() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_eval_dead_code.scm")
             .ok()
             .expect("Should be able to eval a file.");
-        assert_eq!(*result, Value::new_integer(42));
+        assert_eq!(format!("{}", *result), "(6 5 3)");
+
+        let disassembly = heap.eval_str("(%disassemble f)")
+            .ok()
+            .expect("Should be able to disassemble `f`.");
+        let printed = format!("{}", *disassembly);
+        assert_eq!(printed.lines().filter(|line| line.contains("sequence")).count(), 1,
+                   "Should only keep the `set!` and the last form: {}", printed);
+        assert!(!printed.contains("stray"), "Should drop the literal: {}", printed);
+
+        assert!(heap.eval_str("(begin not-defined-yet 1)").is_err(),
+                "Should keep references to undefined globals");
     }
 
     #[test]
-    fn test_eval_boolean() {
-        let mut heap = Heap::new();
-        let result = evaluate_file(&mut heap, "./tests/test_eval_boolean.scm")
+    fn test_eval_analysis_depth() {
+        let heap = &mut Heap::new();
+        heap.eval_str("(define n 0)").ok().expect("Should define n.");
+
+        // Long sequences and argument lists are analyzed without recursing.
+        let increment = heap.eval_str("'(set! n (+ n 1))").ok().expect("Should quote.");
+        let mut forms = vec!(heap.get_or_create_symbol("begin".to_string()));
+        let mut operands = vec!(heap.get_or_create_symbol("list".to_string()));
+        for i in range(0, 100000) {
+            forms.push(increment.clone());
+            operands.push(Rooted::new(heap, Value::new_integer(i)));
+        }
+        let begin = list(heap, forms.as_slice());
+        let call = list(heap, operands.as_slice());
+        forms.clear();
+        operands.clear();
+
+        evaluate(heap, &begin, Location::unknown()).ok().expect("Should evaluate a long begin.");
+        let n = heap.eval_str("n").ok().expect("Should get n.");
+        assert_eq!(*n, Value::new_integer(100000));
+        let result = evaluate(heap, &call, Location::unknown())
             .ok()
-            .expect("Should be able to eval a file.");
-        assert_eq!(*result, Value::new_boolean(true));
+            .expect("Should evaluate a call with many operands.");
+        assert_eq!(result.iter().count(), 100000);
+
+        // Deeply nested forms are a static error rather than a stack overflow.
+        let car = heap.get_or_create_symbol("car".to_string());
+        let mut nested = Rooted::new(heap, Value::EmptyList);
+        for _ in range(0, 100000) {
+            nested = list(heap, &[car.clone(), nested]);
+        }
+        let error = evaluate(heap, &nested, Location::unknown())
+            .err()
+            .expect("Should refuse to analyze deeply nested forms.");
+        assert!(error.contains(format!("forms are nested more than {} deep",
+                                       MAX_ANALYSIS_DEPTH).as_slice()),
+                "{}", error);
+        assert_eq!(heap.analysis_depth(), 0);
     }
 
     #[test]
-    fn test_eval_quoted() {
-        let mut heap = Heap::new();
-        let result = evaluate_file(&mut heap, "./tests/test_eval_quoted.scm")
+    fn test_eval_max_recursion_depth() {
+        use vm::{ExecutionMode};
+
+        let heap = &mut Heap::new();
+        heap.set_max_recursion_depth(200);
+        heap.eval_str("(define count (lambda (n) (if (= n 0) 0 (+ 1 (count (- n 1))))))")
             .ok()
-            .expect("Should be able to eval a file.");
-        assert_eq!(*result, Value::EmptyList);
+            .expect("Should define count.");
+
+        let result = heap.eval_str("(count 50)").ok().expect("Should recurse a little.");
+        assert_eq!(*result, Value::new_integer(50));
+
+        let error = heap.eval_str("(count 100000)").err().expect("Should recurse too deep.");
+        assert!(error.contains("[E0216] Error: maximum recursion depth exceeded"), "{}", error);
+        assert_eq!(heap.recursion_depth(), 0);
+
+        let result = heap.eval_str("(count 50)").ok().expect("Should recover.");
+        assert_eq!(*result, Value::new_integer(50));
+
+        // The VM keeps Scheme calls off of the host stack, but primitives that
+        // call back into Scheme still recurse on it.
+        heap.set_execution_mode(ExecutionMode::Bytecode);
+        let result = heap.eval_str("(count 100000)").ok().expect("Should not use the stack.");
+        assert_eq!(*result, Value::new_integer(100000));
+        heap.eval_str("(define nest \
+                         (lambda (n) \
+                           (if (= n 0) \
+                               0 \
+                               (car (list-sort (lambda (a b) (nest (- n 1)) #t) '(1 2))))))")
+            .ok()
+            .expect("Should define nest.");
+        let error = heap.eval_str("(nest 100000)").err()
+            .expect("Should recurse too deep through `list-sort`.");
+        assert!(error.contains("maximum recursion depth exceeded"), "{}", error);
+        assert_eq!(heap.recursion_depth(), 0);
     }
 
     #[test]
-    fn test_eval_if_consequent() {
-        let mut heap = Heap::new();
-        let result = evaluate_file(&mut heap, "./tests/test_eval_if_consequent.scm")
+    fn test_eval_global_reference() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_eval_global_reference.scm")
             .ok()
             .expect("Should be able to eval a file.");
-        assert_eq!(*result, Value::new_integer(1));
+        let printed = format!("{}", *result);
+        assert!(printed.starts_with("(5 "),
+                "Should see globals defined after analysis: {}", printed);
+        assert!(printed.contains("global-reference later "),
+                "Should refer to the global directly: {}", printed);
     }
 
     #[test]
-    fn test_eval_if_alternative() {
-        let mut heap = Heap::new();
-        let result = evaluate_file(&mut heap, "./tests/test_eval_if_alternative.scm")
+    fn test_eval_invocation_arities() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_eval_invocation_arities.scm")
             .ok()
             .expect("Should be able to eval a file.");
-        assert_eq!(*result, Value::new_integer(2));
+        assert_eq!(format!("{}", *result), "(0 1 (1 . 2) (1 2 3) (1 2 3 4))");
     }
 
     #[test]
-    fn test_eval_begin() {
-        let mut heap = Heap::new();
-        let result = evaluate_file(&mut heap, "./tests/test_eval_begin.scm")
+    fn test_eval_optional_arguments() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_eval_optional_arguments.scm")
             .ok()
             .expect("Should be able to eval a file.");
-        assert_eq!(*result, Value::new_integer(2));
+        assert_eq!(format!("{}", *result),
+                   "((1 #f 11) (1 2 12) (1 2 3) (1 2 20) (1 2 3) (1 5 50) (1 4 3) \
+                    (1 b #f) (1 b 3) (1 2 3) (1 2))");
+
+        let error = heap.eval_str("(key 1 #:d 4)").err().expect("Should fail.");
+        assert!(error.contains("Error: unexpected keyword argument #:d passed to `key`"),
+                "{}", error);
+        let error = heap.eval_str("(key 1 #:b)").err().expect("Should fail.");
+        assert!(error.contains("Error: keyword argument #:b passed to `key` \
+                                defined at ./tests/test_eval_optional_arguments.scm:2:"),
+                "{}", error);
+        let error = heap.eval_str("(opt 1 2 3 4)").err().expect("Should fail.");
+        assert!(error.contains("Error: too many arguments passed: expected 1 to 3 args"),
+                "{}", error);
+        let error = heap.eval_str("(key)").err().expect("Should fail.");
+        assert!(error.contains("Error: too few arguments passed: expected at least 1 args"),
+                "{}", error);
+        let error = heap.eval_str("(lambda (a #:key b #:optional c) a)").err()
+            .expect("Should fail.");
+        assert!(error.contains("Bad lambda parameters"), "{}", error);
     }
 
     #[test]
-    fn test_eval_variables() {
+    fn test_eval_argument_stack() {
+        use read::{read_from_str};
+
         let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_eval_argument_stack.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "(1000 47)");
+        assert_eq!(heap.arguments_height(), 0);
+
+        let mut reader = read_from_str("(list 1 2 (car '()) 4)", heap, "args.scm");
+        let (location, form) = reader.next().expect("Should have a form.");
+        let form = form.ok().expect("Should be able to read the form.");
+        assert!(evaluate(heap, &form, location).is_err());
+        assert_eq!(heap.arguments_height(), 0,
+                   "Should discard the arguments of a call whose parameters fail");
+    }
 
-        let define_symbol = heap.define_symbol();
-        let set_bang_symbol = heap.set_bang_symbol();
-        let foo_symbol = heap.get_or_create_symbol("foo".to_string());
+    #[test]
+    fn test_eval_static_arity_errors() {
+        use read::{read_from_str};
 
-        let mut def_items = [
-            define_symbol,
-            foo_symbol,
-            Rooted::new(heap, Value::new_integer(2))
-        ];
-        let def_form = list(heap, &mut def_items);
-        evaluate(heap, &def_form, Location::unknown()).ok()
-            .expect("Should be able to define");
+        let heap = &mut Heap::new();
+        let source = "(define f (lambda (x y) x)) \
+                      ((lambda (x) x) 1 2) \
+                      (f 1) \
+                      (car 1 2) \
+                      (define g (lambda () (f 1 2 3)))";
+        let reader = read_from_str(source, heap, "arity.scm");
+        let mut errors = vec!();
+        for (location, form) in reader {
+            let form = form.ok().expect("Should be able to read the form.");
+            errors.push(evaluate(heap, &form, location).err().unwrap_or(String::new()));
+        }
 
-        let foo_symbol_ = heap.get_or_create_symbol("foo".to_string());
+        assert_eq!(errors[0], "");
+        assert!(errors[1].contains("[E0116] arity.scm:1:"), "{}", errors[1]);
+        assert!(errors[1].contains("Static error: too many arguments passed: expected 1 \
+                                    args for anonymous procedure defined at arity.scm:1:"));
+
+        // Calls to globals are only checked when they are evaluated, since
+        // the global could be redefined first, but they are warned about.
+        assert!(errors[2].contains("[E0202] Error: too few arguments passed"), "{}", errors[2]);
+        assert!(errors[3].contains("[E0301] Error: `car` expects 1 argument"), "{}", errors[3]);
+        assert_eq!(errors[4], "");
+        let warnings : Vec<String> = heap.take_warnings().iter()
+            .map(|w| w.message.clone())
+            .filter(|m| m.contains("arguments passed"))
+            .collect();
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings[0].starts_with("too few arguments passed: expected 2 args for `f` \
+                                         defined at arity.scm:1:"));
+        assert_eq!(warnings[1], "too many arguments passed: expected 1 args for `car`, found 2");
+        assert!(warnings[2].ends_with("found 3"));
+    }
 
-        let def_val = evaluate(heap, &foo_symbol_, Location::unknown()).ok()
-            .expect("Should be able to get a defined symbol's value");
-        assert_eq!(*def_val, Value::new_integer(2));
+    #[test]
+    fn test_eval_warnings() {
+        let heap = &mut Heap::new();
+        evaluate_file(heap, "./tests/test_eval_warnings.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        let warnings : Vec<String> = heap.take_warnings().iter()
+            .map(|w| format!("{}", w))
+            .collect();
+        let file = "./tests/test_eval_warnings.scm";
+        assert_eq!(warnings,
+                   vec!(format!("{}:1:15: Warning: unused parameter `y`", file),
+                        format!("{}:3:16: Warning: `x` shadows a binding in an enclosing scope",
+                                file),
+                        format!("{}:5:26: Warning: reference to undefined global `undefined-thing`",
+                                file)));
+        assert!(heap.take_warnings().is_empty());
+    }
 
-        let mut set_items = [
-            set_bang_symbol,
-            foo_symbol_,
-            Rooted::new(heap, Value::new_integer(1))
-        ];
-        let set_form = list(heap, &mut set_items);
-        evaluate(heap, &set_form, Location::unknown()).ok()
-            .expect("Should be able to define");
+    #[test]
+    fn test_eval_self_tail_calls() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_eval_self_tail_calls.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "(done 1 2 3)",
+                   "Should not reuse activations captured by closures");
 
-        let foo_symbol__ = heap.get_or_create_symbol("foo".to_string());
+        let count_down = heap.get_or_create_symbol("count-down".to_string());
+        let count_down = evaluate(heap, &count_down, Location::unknown())
+            .ok()
+            .expect("Should be able to evaluate `count-down`");
+        let n = Rooted::new(heap, Value::new_integer(1000));
+        let before = heap.statistics().allocations;
+        let result = apply_invocation(heap, &count_down, vec!(n))
+            .and_then(|trampoline| trampoline.run(heap))
+            .ok()
+            .expect("Should be able to call `count-down`");
+        assert_eq!(format!("{}", *result), "done");
+        assert!(heap.statistics().allocations - before < 10,
+                "Should reuse the activation for each iteration");
+    }
 
-        let set_val = evaluate(heap, &foo_symbol__, Location::unknown()).ok()
-            .expect("Should be able to get a defined symbol's value");
-        assert_eq!(*set_val, Value::new_integer(1));
+    #[test]
+    fn test_eval_primitive_panic_is_error() {
+        use errors::{E0212};
+        use value::{SchemeResult};
+
+        let heap = &mut Heap::new();
+        heap.set_catch_panics(true);
+        let buggy = heap.register_primitive("buggy", |_: &mut Heap,
+                                                      args: &[RootedValue]| -> SchemeResult {
+            panic!("cannot handle {} arguments", args.len())
+        });
+        let arg = Rooted::new(heap, Value::new_integer(1));
+        let error = apply_invocation(heap, &buggy, vec!(arg))
+            .and_then(|trampoline| trampoline.run(heap))
+            .err()
+            .expect("Should turn the panic into an error.");
+        assert_eq!(error.code, Some(&E0212));
+        assert_eq!(error.message,
+                   "Error: primitive `buggy` panicked: cannot handle 1 arguments");
+        assert_eq!(heap.stack_depth(), 0);
     }
 
     #[test]
-    fn test_eval_and_call_lambda() {
-        let mut heap = Heap::new();
-        let result = evaluate_file(&mut heap, "./tests/test_eval_and_call_lambda.scm")
+    fn test_eval_primitive_fast_path() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_eval_primitive_fast_path.scm")
             .ok()
             .expect("Should be able to eval a file.");
-        assert_eq!(*result, Value::new_integer(5));
+        let printed = format!("{}", *result);
+        assert!(printed.starts_with("(1 (2) "),
+                "Should notice when `car` is set: {}", printed);
+        assert!(printed.contains("primitive-invocation"),
+                "Should use the fast path for `car`: {}", printed);
     }
 
     #[test]
-    fn test_eval_closures() {
-        let mut heap = Heap::new();
-        let result = evaluate_file(&mut heap, "./tests/test_eval_closures.scm")
+    fn test_eval_flat_closures() {
+        let heap = &mut Heap::new();
+        heap.set_flat_closures(true);
+        let result = evaluate_file(heap, "./tests/test_eval_flat_closures.scm")
             .ok()
             .expect("Should be able to eval a file.");
-        assert_eq!(*result, Value::new_integer(1));
+        let printed = format!("{}", *result);
+        assert!(printed.starts_with("(3 2 #t 6 "), "Should evaluate correctly: {}", printed);
+        assert!(printed.contains("flat-lambda <anonymous> 1 [n]"),
+                "Should flatten the adder's closure: {}", printed);
+        assert!(printed.contains("lambda <anonymous> 0 @")
+                && !printed.contains("flat-lambda <anonymous> 0"),
+                "Should not flatten a closure over an assigned variable: {}", printed);
     }
 
     #[test]
-    fn test_ref_defined_later() {
-        let mut heap = Heap::new();
-        let result = evaluate_file(&mut heap, "./tests/test_ref_defined_later.scm")
+    fn test_eval_constant_folding() {
+        let heap = &mut Heap::new();
+        heap.set_constant_folding(true);
+        let result = evaluate_file(heap, "./tests/test_eval_constant_folding.scm")
             .ok()
             .expect("Should be able to eval a file.");
-        assert_eq!(*result, Value::new_integer(1));
+        let printed = format!("{}", *result);
+        assert!(printed.starts_with("(7 "), "Should evaluate correctly: {}", printed);
+        assert!(printed.contains("quotation 7 @"),
+                "Should fold `f` down to a quotation: {}", printed);
+        assert!(!printed.contains("conditional"),
+                "Should fold the constant conditional: {}", printed);
+        assert!(printed.contains("reference /"),
+                "Should not fold the division by zero: {}", printed);
     }
 
     #[test]
-    fn test_set_defined_later() {
-        let mut heap = Heap::new();
-        let result = evaluate_file(&mut heap, "./tests/test_set_defined_later.scm")
-            .ok()
-            .expect("Should be able to eval a file.");
-        assert_eq!(*result, Value::new_integer(5));
+    fn test_eval_analysis_cache() {
+        use read::{read_from_str};
+
+        let heap = &mut Heap::new();
+        heap.set_constant_folding(true);
+        ensure_prelude(heap).ok().expect("Should be able to load the prelude.");
+        heap.set_analysis_cache_capacity(16);
+
+        let eval_str = |heap: &mut Heap, source: &str, file: &str| {
+            let mut reader = read_from_str(source, heap, file);
+            let (location, form) = reader.next().expect("Should have a form.");
+            let form = form.ok().expect("Should be able to read the form.");
+            let result = evaluate(heap, &form, location)
+                .ok()
+                .expect("Should be able to evaluate the form.");
+            *result
+        };
+
+        assert_eq!(eval_str(heap, "(+ 1 2)", "a.scm"), Value::new_integer(3));
+        assert_eq!(eval_str(heap, "(+ 1 2)", "a.scm"), Value::new_integer(3));
+        assert_eq!(heap.analysis_cache().hits(), 1);
+        assert_eq!(heap.analysis_cache().len(), 1);
+
+        assert_eq!(eval_str(heap, "(+ 1 2)", "b.scm"), Value::new_integer(3));
+        assert_eq!(heap.analysis_cache().hits(), 1,
+                   "Should not reuse a meaning analyzed at another location");
+
+        eval_str(heap, "(set! + -)", "c.scm");
+        assert_eq!(eval_str(heap, "(+ 1 2)", "a.scm"), Value::new_integer(-1),
+                   "Should reanalyze a folded invocation of a redefined global");
+        assert_eq!(heap.analysis_cache().hits(), 1);
+
+        eval_str(heap, "(define-macro m (lambda (form) 1))", "d.scm");
+        assert_eq!(eval_str(heap, "(m)", "e.scm"), Value::new_integer(1));
+        assert_eq!(eval_str(heap, "(m)", "e.scm"), Value::new_integer(1));
+        assert_eq!(heap.analysis_cache().hits(), 2);
+        eval_str(heap, "(define-macro m (lambda (form) 2))", "d.scm");
+        assert_eq!(eval_str(heap, "(m)", "e.scm"), Value::new_integer(2),
+                   "Should reanalyze macro uses after a macro is defined");
     }
 
     #[test]
-    fn test_rooting_bug() {
-        let mut heap = Heap::new();
-        evaluate_file(&mut heap, "./tests/rooting-bug.scm")
+    fn test_eval_analysis_cache_eviction() {
+        use read::{read_from_str};
+
+        let heap = &mut Heap::new();
+        ensure_prelude(heap).ok().expect("Should be able to load the prelude.");
+        heap.set_analysis_cache_capacity(2);
+
+        let eval_str = |heap: &mut Heap, source: &str| {
+            let mut reader = read_from_str(source, heap, "a.scm");
+            let (location, form) = reader.next().expect("Should have a form.");
+            let form = form.ok().expect("Should be able to read the form.");
+            evaluate(heap, &form, location).ok().expect("Should be able to evaluate the form.");
+        };
+
+        eval_str(heap, "(+ 1 2)");
+        eval_str(heap, "(+ 3 4)");
+        eval_str(heap, "(+ 1 2)");
+        assert_eq!(heap.analysis_cache().hits(), 1);
+
+        eval_str(heap, "(+ 5 6)");
+        assert_eq!(heap.analysis_cache().len(), 2);
+        eval_str(heap, "(+ 1 2)");
+        assert_eq!(heap.analysis_cache().hits(), 2,
+                   "Should keep the most recently used meaning when full");
+        eval_str(heap, "(+ 3 4)");
+        assert_eq!(heap.analysis_cache().hits(), 2,
+                   "Should evict only the least recently used meaning");
+    }
+
+    #[test]
+    fn test_eval_structural_eq_and_diff() {
+        use read::{read_from_str};
+
+        let heap = &mut Heap::new();
+        let analyze_str = |heap: &mut Heap, source: &str, file: &str| {
+            let mut reader = read_from_str(source, heap, file);
+            let (location, form) = reader.next().expect("Should have a form.");
+            let form = form.ok().expect("Should be able to read the form.");
+            analyze(heap, &form, location)
+                .ok()
+                .expect("Should be able to analyze the form.")
+        };
+
+        let a = analyze_str(heap, "(if x (+ 1 '(2 \"three\")) 4)", "a.scm");
+        let b = analyze_str(heap, "  (if x (+ 1 '(2 \"three\")) 4)", "b.scm");
+        let c = analyze_str(heap, "(if x (+ 1 '(2 \"three\")) 5)", "c.scm");
+
+        assert!(a.structural_eq(&b));
+        assert_eq!(diff_meanings(&heap.environment, &a, &b), None);
+
+        assert!(!a.structural_eq(&c));
+        assert_eq!(diff_meanings(&heap.environment, &a, &c),
+                   Some("at /2:\n  expected:\n    quotation 4 @ a.scm:1:1\n  \
+                         actual:\n    quotation 5 @ c.scm:1:1\n".to_string()));
+    }
+
+    #[test]
+    fn test_eval_failing_macro_transformer() {
+        let heap = &mut Heap::new();
+        heap.eval_str("(define-macro broken (lambda (form) (car (cdr '()))))")
             .ok()
-            .expect("Should be able to eval a file.");
-        assert!(true, "Should be able to evaluate that file without panicking.");
+            .expect("Should be able to define the macro.");
+
+        assert!(heap.eval_str("(broken)").is_err());
+        assert_eq!(heap.stack_depth(), 0, "Should unwind the failed transformer's frames");
+        assert!(heap.take_error_location().is_none(),
+                "Should not leave the failed transformer's error location behind");
     }
 
     #[test]
-    fn test_eval_local_definitions() {
-        let mut heap = Heap::new();
-        match evaluate_file(&mut heap, "./tests/test_eval_local_definitions.scm") {
-            Err(msg) => panic!(msg),
-            Ok(result) => assert_eq!(*result, Value::new_integer(120)),
+    fn test_eval_backtrace() {
+        use read::{read_from_file};
+
+        let heap = &mut Heap::new();
+        let reader = read_from_file("./tests/test_eval_backtrace.scm", heap)
+            .ok()
+            .expect("Should be able to read a file.");
+
+        let mut error = None;
+        for (location, read_result) in reader {
+            let form = read_result.ok().expect("Should be able to read a form.");
+            if let Err(e) = evaluate_with_backtrace(heap, &form, location) {
+                error = Some(e);
+            }
         }
+
+        let error = error.expect("Should get an error evaluating this file.");
+        let names : Vec<String> = error.backtrace.iter()
+            .map(|frame| frame.name.clone())
+            .collect();
+        assert_eq!(names, vec!("car".to_string(),
+                               "inner".to_string(),
+                               "outer".to_string()));
+        assert_eq!(error.backtrace[2].location.line, 3);
+        assert_eq!(error.code.map(|entry| entry.code), Some("E0305"));
+        assert!(format!("{}", error).contains("[E0305] Error: `car` expects a pair"));
+        assert_eq!(heap.stack_depth(), 0);
+
+        let rendered = error.render(heap);
+        assert!(rendered.contains("1 | (define inner (lambda (x) (car x)))\n"),
+                "Should show the line from the file's recorded text: {}", rendered);
+        assert_eq!(heap.source_line(&error.backtrace[2].location),
+                   Some("(outer 5)".to_string()));
+    }
+
+    #[test]
+    fn test_eval_error_code_from_raise_site() {
+        use read::{read_from_str};
+
+        let heap = &mut Heap::new();
+        let mut reader = read_from_str("(car \"heap image\")", heap, "<string>");
+        let (location, form) = reader.next().expect("Should read a form.");
+        let form = form.ok().expect("Should read a form.");
+        let error = evaluate_with_backtrace(heap, &form, location)
+            .err()
+            .expect("Should fail to take the car of a string.");
+        assert_eq!(error.code.map(|entry| entry.code), Some("E0305"));
+        assert_eq!(error.message,
+                   "Error: `car` expects a pair as argument 1, found \"heap image\"");
     }
 }
 
@@ -990,9 +4665,10 @@ mod tests {
 mod bench {
     use super::*;
     use super::test::{Bencher};
-    use heap::{Heap, Rooted};
+    use heap::{Heap, HeapConfig, Rooted};
     use read::{Location};
-    use value::{list, Value};
+    use value::{Value};
+    use vm::{ExecutionMode};
 
     #[bench]
     fn bench_iterate_empty_loops(b: &mut Bencher) {
@@ -1002,12 +4678,8 @@ mod bench {
             .expect("Should be able to eval a file.");
 
         b.iter(|| {
-            let mut call_items = [
-                iter_fn.clone(),
-                Rooted::new(&mut heap, Value::new_integer(10000))
-            ];
-            let call = list(&mut heap, &mut call_items);
-            evaluate(&mut heap, &call, Location::unknown()).ok()
+            let n = Rooted::new(&mut heap, Value::new_integer(10000));
+            heap.call(&iter_fn, &[n]).ok()
                 .expect("Should be able to call our function");
         });
     }
@@ -1020,17 +4692,11 @@ mod bench {
             Err(msg) => panic!(msg)
         };
 
-        let quote = heap.quote_symbol();
         let empty_list = Rooted::new(&mut heap, Value::EmptyList);
 
         b.iter(|| {
-            let mut call_items = [
-                alloc_fn.clone(),
-                Rooted::new(&mut heap, Value::new_integer(10000)),
-                list(&mut heap, &mut [quote.clone(), empty_list.clone()])
-            ];
-            let call = list(&mut heap, &mut call_items);
-            match evaluate(&mut heap, &call, Location::unknown()) {
+            let n = Rooted::new(&mut heap, Value::new_integer(10000));
+            match heap.call(&alloc_fn, &[n, empty_list.clone()]) {
                 Err(msg) => panic!(msg),
                 _ => { }
             };
@@ -1052,4 +4718,61 @@ mod bench {
             };
         });
     }
+
+    #[bench]
+    fn bench_eval_metacircular_bytecode(b: &mut Bencher) {
+        let heap = &mut Heap::with_config(HeapConfig {
+            execution_mode: ExecutionMode::Bytecode,
+            .. Default::default()
+        });
+        let eval_fib_call = match evaluate_file(heap, "./tests/bench_eval_metacircular.scm") {
+            Ok(v) => v,
+            Err(msg) => panic!(msg)
+        };
+
+        b.iter(|| {
+            match evaluate(heap, &eval_fib_call.clone(), Location::unknown()) {
+                Err(msg) => panic!(msg),
+                _ => { },
+            };
+        });
+    }
+
+    #[bench]
+    fn bench_eval_metacircular_inlined(b: &mut Bencher) {
+        let heap = &mut Heap::with_config(HeapConfig {
+            inline_budget: 32,
+            .. Default::default()
+        });
+        let eval_fib_call = match evaluate_file(heap, "./tests/bench_eval_metacircular.scm") {
+            Ok(v) => v,
+            Err(msg) => panic!(msg)
+        };
+
+        b.iter(|| {
+            match evaluate(heap, &eval_fib_call.clone(), Location::unknown()) {
+                Err(msg) => panic!(msg),
+                _ => { },
+            };
+        });
+    }
+
+    #[bench]
+    fn bench_eval_metacircular_analysis_cached(b: &mut Bencher) {
+        let heap = &mut Heap::with_config(HeapConfig {
+            analysis_cache_capacity: 16,
+            .. Default::default()
+        });
+        let eval_fib_call = match evaluate_file(heap, "./tests/bench_eval_metacircular.scm") {
+            Ok(v) => v,
+            Err(msg) => panic!(msg)
+        };
+
+        b.iter(|| {
+            match evaluate(heap, &eval_fib_call.clone(), Location::unknown()) {
+                Err(msg) => panic!(msg),
+                _ => { },
+            };
+        });
+    }
 }
@@ -0,0 +1,267 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generators, which produce a sequence of values lazily, one value at a time.
+//!
+//! A generator is made from a procedure of one argument, its body. The body is
+//! called with a `yield` procedure, and each call to `yield` hands a value to
+//! `generator-next` and suspends the body until the next value is asked for.
+//! Once the body returns, the generator is finished, and `generator-next`
+//! returns its default argument, or the EOF object if it has none:
+//!
+//!     (define g (make-generator (lambda (yield) (yield 1) (yield 2))))
+//!     (generator-next g)        ;; => 1
+//!     (generator-next g)        ;; => 2
+//!     (generator-next g 'done)  ;; => done
+//!
+//! There is no `call/cc`. Instead, a generator's body runs on the bytecode VM,
+//! whatever the execution mode, and `yield` suspends the VM the same way that
+//! running out of fuel does. See `Heap::evaluate_with_fuel`. Each suspension is
+//! resumed once, by the next `generator-next`, like a one-shot continuation.
+//!
+//! The VM can only suspend its own frames, so `yield` must be called by code
+//! the VM runs for the body, and not from within a call made by a primitive,
+//! such as a procedure passed to `apply` or `vector-sort!`. When generators are
+//! nested, `yield` suspends the innermost running generator.
+
+use std::collections::{HashMap};
+use std::mem;
+
+use errors::{E0210, E0214, E0312, SchemeError};
+use eval::{Trampoline, TrampolineResult};
+use heap::{Finalizer, Heap, Rooted};
+use primitives::{ArgumentType, PrimitiveSignature};
+use value::{RootedValue, Value};
+use vm::{self, Evaluation, Suspended};
+
+/// The tag of the native objects wrapping generators.
+pub static GENERATOR_TAG : &'static str = "generator";
+
+/// How far along a generator is in producing its values.
+enum State {
+    /// The generator has not been started, and holds its body.
+    Fresh(RootedValue),
+    /// The generator's body is suspended after yielding a value, or after
+    /// running out of fuel.
+    Suspended(Suspended),
+    /// The generator's body is running.
+    Running,
+    /// The generator's body has returned or failed.
+    Finished,
+}
+
+/// The native object wrapped by a generator value. A generator's state holds
+/// rooted values, which cannot be sent to other threads like native objects
+/// can, so the state is kept in the heap's `Generators` table and the native
+/// object only holds its key.
+pub struct Generator(usize);
+
+/// A heap's table of the states of its generators. A generator's state is
+/// removed by a finalizer when the generator is collected.
+pub struct Generators {
+    states: HashMap<usize, State>,
+    next_key: usize,
+}
+
+impl Generators {
+    /// Create a new, empty table.
+    pub fn new() -> Generators {
+        Generators {
+            states: HashMap::new(),
+            next_key: 0,
+        }
+    }
+
+    /// Get the number of generators in the table.
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Remove the state of the generator with the given key.
+    pub fn remove(&mut self, key: usize) {
+        self.states.remove(&key);
+    }
+}
+
+/// Make a new generator with the given body, which must be a procedure of one
+/// argument.
+pub fn make_generator(heap: &mut Heap, body: &RootedValue) -> Result<RootedValue, SchemeError> {
+    match **body {
+        Value::Procedure(procedure) if procedure.arity == 1
+                                       && procedure.signature.is_none() => { },
+        _ => {
+            return Err(E0312.error(format!("Error: `make-generator` expects a procedure of one \
+                                            argument, found {}", **body)));
+        },
+    }
+
+    let key = {
+        let generators = heap.generators();
+        let key = generators.next_key;
+        generators.next_key += 1;
+        generators.states.insert(key, State::Fresh(body.clone()));
+        key
+    };
+
+    let generator = heap.new_native(GENERATOR_TAG, Box::new(Generator(key)));
+    try!(heap.register_finalizer(&generator, Finalizer::Native(Box::new(
+        move |heap: &mut Heap| heap.generators().remove(key)))));
+    Ok(generator)
+}
+
+/// Run the given generator until its body yields its next value, and return
+/// that value, or `None` if the generator is finished.
+pub fn next(heap: &mut Heap, generator: &RootedValue) -> Result<Option<RootedValue>, SchemeError> {
+    let native = try!(generator.to_native(heap).ok_or(
+        E0312.error(format!("Error: `generator-next` expects a generator, found {}",
+                            **generator))));
+    let key = try!(native.downcast_ref::<Generator>()).0;
+
+    let state = match heap.generators().states.get_mut(&key) {
+        Some(state) => mem::replace(state, State::Running),
+        None        => State::Finished,
+    };
+
+    let evaluation = match state {
+        State::Fresh(body) => {
+            let signature = PrimitiveSignature::fixed(&YIELD_ARGUMENTS);
+            let yield_procedure = Value::new_builtin_primitive(&mut heap.primitive_table,
+                                                               "yield",
+                                                               yield_value,
+                                                               Some(signature));
+            let yield_procedure = Rooted::new(heap, yield_procedure);
+            run(heap, |heap| vm::call_with_fuel(heap, &body, vec!(yield_procedure)))
+        },
+        State::Suspended(suspended) => run(heap, |heap| vm::resume(heap, suspended)),
+        State::Running => {
+            return Err(E0214.error("Error: generator is already running".to_string()));
+        },
+        State::Finished => {
+            set_state(heap, key, State::Finished);
+            return Ok(None);
+        },
+    };
+    let yielded = heap.take_yielded();
+
+    match (evaluation, yielded) {
+        (Ok(Evaluation::Suspended(suspended)), Some(val)) => {
+            set_state(heap, key, State::Suspended(suspended));
+            Ok(Some(val))
+        },
+        // Only the outermost evaluation can be suspended for lack of fuel, but
+        // the generator can still be resumed by a later `generator-next`.
+        (Ok(Evaluation::Suspended(suspended)), None) => {
+            set_state(heap, key, State::Suspended(suspended));
+            Err(E0210.error("Error: out of fuel".to_string()))
+        },
+        // A `yield` in tail position finishes the body too.
+        (Ok(Evaluation::Done(_)), yielded) => {
+            set_state(heap, key, State::Finished);
+            Ok(yielded)
+        },
+        (Err(_), Some(_)) => {
+            set_state(heap, key, State::Finished);
+            Err(E0214.error(
+                "Error: `yield` called from within a call made by a primitive".to_string()))
+        },
+        (Err(e), None) => {
+            set_state(heap, key, State::Finished);
+            Err(e)
+        },
+    }
+}
+
+/// Run a generator's body with the given function, counting it as running so
+/// that its `yield` procedure may be called.
+fn run<F>(heap: &mut Heap, f: F) -> Result<Evaluation, SchemeError>
+    where F: FnOnce(&mut Heap) -> Result<Evaluation, SchemeError>
+{
+    heap.enter_generator();
+    let result = f(heap);
+    heap.leave_generator();
+    result
+}
+
+/// Put the state of the generator with the given key back into the table,
+/// unless the generator was collected while it was running.
+fn set_state(heap: &mut Heap, key: usize, state: State) {
+    if let Some(entry) = heap.generators().states.get_mut(&key) {
+        *entry = state;
+    }
+}
+
+/// The arguments the `yield` procedure takes: the value to yield.
+static YIELD_ARGUMENTS: [ArgumentType; 1] = [ArgumentType::Any];
+
+/// The `yield` procedure passed to every generator's body.
+fn yield_value(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    try!(heap.yield_value(&args[0]));
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heap::{Heap};
+    use value::{RootedValue};
+
+    fn next_value(heap: &mut Heap, generator: &RootedValue) -> Option<String> {
+        next(heap, generator)
+            .ok()
+            .expect("Should be able to get the next value.")
+            .map(|val| format!("{}", *val))
+    }
+
+    #[test]
+    fn test_generator_next() {
+        let heap = &mut Heap::new();
+        let body = heap.eval_str("(lambda (yield)
+                                    (define (loop i)
+                                      (if (< i 3)
+                                          (begin (yield i) (loop (+ i 1)))
+                                          'done))
+                                    (loop 0))")
+            .ok()
+            .expect("Should be able to make the body.");
+        let generator = make_generator(heap, &body)
+            .ok()
+            .expect("Should be able to make a generator.");
+
+        assert_eq!(next_value(heap, &generator), Some("0".to_string()));
+        assert_eq!(next_value(heap, &generator), Some("1".to_string()));
+        assert_eq!(next_value(heap, &generator), Some("2".to_string()));
+        assert_eq!(next_value(heap, &generator), None);
+        assert_eq!(next_value(heap, &generator), None);
+        assert_eq!(heap.stack_depth(), 0);
+    }
+
+    #[test]
+    fn test_generator_collected() {
+        let heap = &mut Heap::new();
+        {
+            let body = heap.eval_str("(lambda (yield) (yield 1) (yield 2))")
+                .ok()
+                .expect("Should be able to make the body.");
+            let generator = make_generator(heap, &body)
+                .ok()
+                .expect("Should be able to make a generator.");
+            assert_eq!(next_value(heap, &generator), Some("1".to_string()));
+            assert_eq!(heap.generators().len(), 1);
+        }
+        heap.collect_garbage();
+        assert_eq!(heap.generators().len(), 0);
+    }
+}
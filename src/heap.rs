@@ -17,7 +17,7 @@
 //! ## Allocation
 //!
 //! Scheme has a variety of types that must be allocated on the heap: cons cells,
-//! strings, procedures, and vectors (currently unimplemented).
+//! strings, procedures, vectors, bytevectors, flonums, and native objects.
 //!
 //! Oxischeme does not allocate each individual object directly from the OS,
 //! which would have unnecessary bookkeeping overhead. Instead, we allocate
@@ -128,35 +128,79 @@
 //!   collection!
 //!
 //! * When in doubt, Just Root It!
+//!
+//! ### Handle Scopes
+//!
+//! Each `Rooted` registers and unregisters its own root, which is expensive
+//! when many temporaries are created in a loop. A `HandleScope` is a cheaper
+//! alternative for temporaries that don't outlive a block: every `Handle`
+//! created in the scope is pushed onto a stack of roots, and all of them are
+//! released at once when the scope is dropped. The borrow checker ensures that
+//! no `Handle` outlives its scope; use `HandleScope::escape` to return a value
+//! out of the scope as a `Rooted`.
+//!
+//!     let scope = HandleScope::new(heap);
+//!     let mut result = scope.handle(Value::EmptyList);
+//!     for ... {
+//!         result = scope.handle(some_gc_thing_made_from(*result));
+//!     }
+//!     scope.escape(result)
 
+use std::any::{Any};
 use std::cmp;
-use std::collections::{BitVec, HashMap};
+use std::collections::{HashMap, HashSet};
 use std::default::{Default};
 use std::fmt;
+use std::hash;
+use std::marker::{PhantomData};
+use std::mem;
+use std::old_io::{IoResult, Reader, Writer};
 use std::ops::{Deref, DerefMut};
+use std::rc::{Rc};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::vec::{IntoIter};
 
-use environment::{Activation, ActivationPtr, RootedActivationPtr, Environment};
-use primitives::{define_primitives};
-use read::{Location};
-use value::{Cons, ConsPtr, Procedure, ProcedurePtr, RootedConsPtr,
-            RootedProcedurePtr, RootedValue, Value};
+use environment::{Activation, ActivationPtr, RootedActivationPtr, Environment,
+                  ReifiedEnvironments, SymbolId};
+use errors::{E0214, E0216, E0306, E0307, E0308, E0309, E0313, SchemeError};
+use eval::{apply_invocation, ensure_prelude, evaluate, evaluate_with_fuel, AnalysisCache,
+           DebugAction, Debugger, Frame, Meaning, MeaningUnit, Trampoline, TrampolineResult,
+           Warning};
+use generator::{Generators};
+use image::{self, CompiledScript};
+use primitives::{ArithmeticMode, Capability, PrimitiveInfo, PrimitiveSet, EMBEDDER_MODULE};
+use random::{RandomSource};
+use read::{read_from_str, Location};
+use time;
+use tools::{ToolRecorder};
+use vm::{self, Code, CodeCache, Evaluation, ExecutionMode, Suspended};
+use value::{Cons, ConsPtr, PrimitiveTable, Procedure, ProcedurePtr, RootedConsPtr,
+            RootedProcedurePtr, RootedValue, RootedVectorPtr, SchemeResult, Value, Vector,
+            VectorPtr};
 
 /// We use a vector for our implementation of a free list. `Vector::push` to add
 /// new entries, `Vector::pop` to remove the next entry when we allocate.
 type FreeList = Vec<usize>;
 
+/// An object in an arena's pool, along with its mark bit. Keeping the mark bit
+/// next to the object means that an `ArenaPtr` only needs to point at the slot,
+/// rather than at the arena and an index into it.
+struct ArenaSlot<T> {
+    /// During a GC, whether this object has been marked as reachable.
+    marked: bool,
+    value: T,
+}
+
 /// An arena from which to allocate `T` objects from.
 pub struct Arena<T> {
-    pool: Vec<T>,
+    /// The pool of objects. It is never resized, so pointers to its slots stay
+    /// valid for the lifetime of the arena.
+    pool: Vec<ArenaSlot<T>>,
 
     /// The set of free indices into `pool` that are available for allocating an
     /// object from.
     free: FreeList,
-
-    /// During a GC, if the nth bit of `marked` is set, that means that the nth
-    /// object in `pool` has been marked as reachable.
-    marked: BitVec,
 }
 
 impl<T: Default> Arena<T> {
@@ -165,9 +209,13 @@ impl<T: Default> Arena<T> {
     pub fn new(capacity: usize) -> Box<Arena<T>> {
         assert!(capacity > 0);
         Box::new(Arena {
-            pool: range(0, capacity).map(|_| Default::default()).collect(),
+            pool: range(0, capacity).map(|_| {
+                ArenaSlot {
+                    marked: false,
+                    value: Default::default(),
+                }
+            }).collect(),
             free: range(0, capacity).collect(),
-            marked: BitVec::from_elem(capacity, false),
         })
     }
 
@@ -181,6 +229,12 @@ impl<T: Default> Arena<T> {
         self.free.is_empty()
     }
 
+    /// Get the number of objects allocated in this arena, which are either
+    /// reachable or have not been swept yet.
+    pub fn allocated_count(&self) -> usize {
+        self.capacity() - self.free.len()
+    }
+
     /// Return true if this arena does not contain any reachable objects (ie,
     /// the free list is full), and false otherwise.
     pub fn is_empty(&self) -> bool {
@@ -195,8 +249,8 @@ impl<T: Default> Arena<T> {
     pub fn allocate(&mut self) -> ArenaPtr<T> {
         match self.free.pop() {
             Some(idx) => {
-                let self_ptr : *mut Arena<T> = self;
-                ArenaPtr::new(self_ptr, idx)
+                let slot : *mut ArenaSlot<T> = &mut self.pool[idx];
+                ArenaPtr::new(slot)
             },
             None => panic!("Arena is at capacity!"),
         }
@@ -205,15 +259,25 @@ impl<T: Default> Arena<T> {
     /// Sweep the arena and add any reclaimed objects back to the free list.
     pub fn sweep(&mut self) {
         self.free = range(0, self.capacity())
-            .filter(|&n| {
-                !self.marked.get(n)
-                    .expect("`marked` should always have length == self.capacity()")
-            })
+            .filter(|&n| !self.pool[n].marked)
             .collect();
 
-        // Reset `marked` to all zero.
-        self.marked.set_all();
-        self.marked.negate();
+        // Reset every mark bit to unmarked.
+        for slot in self.pool.iter_mut() {
+            slot.marked = false;
+        }
+    }
+
+    /// Like `sweep`, but also reset reclaimed objects to their default value,
+    /// so that any resources they own are released now, rather than whenever
+    /// their slot happens to be reused.
+    pub fn sweep_resetting(&mut self) {
+        for slot in self.pool.iter_mut() {
+            if !slot.marked {
+                slot.value = Default::default();
+            }
+        }
+        self.sweep();
     }
 }
 
@@ -234,6 +298,34 @@ impl<T: Default> ArenaSet<T> {
         }
     }
 
+    /// Get the number of arenas in this set.
+    pub fn arena_count(&self) -> usize {
+        self.arenas.len()
+    }
+
+    /// Return true if allocating from this set requires allocating a new arena,
+    /// false otherwise.
+    pub fn is_full(&self) -> bool {
+        self.arenas.iter().all(|a| a.is_full())
+    }
+
+    /// Get the number of bytes in the object pool of each arena in this set.
+    pub fn arena_bytes(&self) -> usize {
+        self.capacity * mem::size_of::<ArenaSlot<T>>()
+    }
+
+    /// Get the number of bytes in the object pools of all of the arenas in
+    /// this set.
+    pub fn bytes(&self) -> usize {
+        self.arena_count() * self.arena_bytes()
+    }
+
+    /// Get the number of objects allocated across all of the arenas in this
+    /// set.
+    pub fn allocated_count(&self) -> usize {
+        self.arenas.iter().fold(0, |n, a| n + a.allocated_count())
+    }
+
     /// Sweep all of the arenas in this set.
     pub fn sweep(&mut self) {
         for arena in self.arenas.iter_mut() {
@@ -244,6 +336,15 @@ impl<T: Default> ArenaSet<T> {
         self.arenas.retain(|a| !a.is_empty());
     }
 
+    /// Sweep all of the arenas in this set, resetting reclaimed objects. See
+    /// `Arena::sweep_resetting`.
+    pub fn sweep_resetting(&mut self) {
+        for arena in self.arenas.iter_mut() {
+            arena.sweep_resetting();
+        }
+        self.arenas.retain(|a| !a.is_empty());
+    }
+
     /// Allocate a `T` object from one of the arenas in this set and return a
     /// pointer to it.
     pub fn allocate(&mut self) -> ArenaPtr<T> {
@@ -263,52 +364,60 @@ impl<T: Default> ArenaSet<T> {
     }
 }
 
-/// A pointer to a `T` instance in an arena.
-#[allow(raw_pointer_derive)]
-#[derive(Hash)]
+/// A pointer to a `T` instance in an arena. It is a single machine word, and
+/// always aligned to at least eight bytes, so that it can be packed into a
+/// tagged `Word`.
 pub struct ArenaPtr<T> {
-    arena: *mut Arena<T>,
-    index: usize,
+    slot: *mut ArenaSlot<T>,
 }
 
 // XXX: We have to manually declare that ArenaPtr<T> is copy-able because if we
 // use `#[derive(Copy)]` it wants T to be copy-able as well, despite the fact
-// that we only need to copy our pointer to the Arena<T>, not any T or the Arena
-// itself.
+// that we only need to copy our pointer to the slot, not the T inside it.
 impl<T> ::std::marker::Copy for ArenaPtr<T> { }
 
 impl<T: Default> ArenaPtr<T> {
-    /// Create a new `ArenaPtr` to the `T` instance at the given index in the
-    /// provided arena. **Not** publicly exposed, and should only be called by
+    /// Create a new `ArenaPtr` to the `T` instance in the given slot of an
+    /// arena. **Not** publicly exposed, and should only be called by
     /// `Arena::allocate`.
-    fn new(arena: *mut Arena<T>, index: usize) -> ArenaPtr<T> {
-        unsafe {
-            let arena_ref = arena.as_ref()
-                .expect("ArenaPtr<T>::new should be passed a valid Arena.");
-            assert!(index < arena_ref.capacity());
-        }
+    fn new(slot: *mut ArenaSlot<T>) -> ArenaPtr<T> {
+        assert!(!slot.is_null(), "ArenaPtr<T>::new should be passed a valid slot.");
         ArenaPtr {
-            arena: arena,
-            index: index,
+            slot: slot,
         }
     }
 
     /// During a GC, mark this `ArenaPtr` as reachable.
     fn mark(&self) {
         unsafe {
-            let arena = self.arena.as_mut()
-                .expect("An ArenaPtr<T> should always have a valid Arena.");
-            arena.marked.set(self.index, true);
+            (*self.slot).marked = true;
         }
     }
 
     /// During a GC, determine if this `ArenaPtr` has been marked as reachable.
     fn is_marked(&self) -> bool {
         unsafe {
-            let arena = self.arena.as_mut()
-                .expect("An ArenaPtr<T> should always have a valid Arena.");
-            return arena.marked.get(self.index)
-                .expect("self.index should always be within the marked bitv's length.");
+            (*self.slot).marked
+        }
+    }
+}
+
+impl<T> ArenaPtr<T> {
+    /// Get the address of this pointer's slot, for packing into a `Word`.
+    #[inline]
+    pub fn to_raw(&self) -> usize {
+        self.slot as usize
+    }
+
+    /// Recreate an `ArenaPtr` from an address previously returned by
+    /// `ArenaPtr::to_raw`.
+    ///
+    /// This is unsafe because nothing checks that the address came from an
+    /// `ArenaPtr<T>` for the same `T`, or that its arena is still alive.
+    #[inline]
+    pub unsafe fn from_raw(raw: usize) -> ArenaPtr<T> {
+        ArenaPtr {
+            slot: raw as *mut ArenaSlot<T>,
         }
     }
 }
@@ -317,9 +426,7 @@ impl<T> Deref for ArenaPtr<T> {
     type Target = T;
     fn deref<'a>(&'a self) -> &'a T {
         unsafe {
-            let arena = self.arena.as_ref()
-                .expect("ArenaPtr::deref should always have an Arena.");
-            &arena.pool[self.index]
+            &(*self.slot).value
         }
     }
 }
@@ -327,16 +434,14 @@ impl<T> Deref for ArenaPtr<T> {
 impl<T> DerefMut for ArenaPtr<T> {
     fn deref_mut<'a>(&'a mut self) -> &'a mut T {
         unsafe {
-            let arena = self.arena.as_mut()
-                .expect("ArenaPtr::deref_mut should always have an Arena.");
-            &mut arena.pool[self.index]
+            &mut (*self.slot).value
         }
     }
 }
 
 impl<T> fmt::Debug for ArenaPtr<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ArenaPtr({:p}, {})", &self.arena, &self.index)
+        write!(f, "ArenaPtr({:p})", self.slot)
     }
 }
 
@@ -345,13 +450,20 @@ impl<T> cmp::PartialEq for ArenaPtr<T> {
     /// comparison. In other words, it is equivalent to the scheme function
     /// `eq?`, not the scheme function `equal?`.
     fn eq(&self, other: &ArenaPtr<T>) -> bool {
-        self.index == other.index
-            && (self.arena as usize) == (other.arena as usize)
+        self.slot == other.slot
     }
 }
 
 impl<T> cmp::Eq for ArenaPtr<T> { }
 
+impl<T> hash::Hash for ArenaPtr<T> {
+    /// Like `PartialEq`, hashing is by object identity, so `T` need not be
+    /// hashable itself.
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        (self.slot as usize).hash(state);
+    }
+}
+
 /// A trait for types that can be coerced to a `GcThing`.
 pub trait ToGcThing: fmt::Debug {
     /// Coerce this value to a `GcThing`.
@@ -452,293 +564,3024 @@ impl<T: PartialEq> PartialEq for Rooted<T> {
 }
 impl<T: PartialEq + Eq> Eq for Rooted<T> { }
 
-/// A pointer to a string on the heap.
-pub type StringPtr = ArenaPtr<String>;
-
-impl ToGcThing for StringPtr {
-    fn to_gc_thing(&self) -> Option<GcThing> {
-        Some(GcThing::from_string_ptr(*self))
-    }
+/// A scope within which `Handle`s may be created. Every `Handle` created within
+/// the scope is rooted until the scope is dropped. See the module level
+/// documentation on handle scopes.
+pub struct HandleScope {
+    heap: *mut Heap,
+    base: usize,
 }
 
-/// A rooted pointer to a string on the heap.
-pub type RootedStringPtr = Rooted<StringPtr>;
+impl HandleScope {
+    /// Open a new `HandleScope`. Scopes must be dropped in the reverse of the
+    /// order they were created in, which is always the case for scopes bound to
+    /// local variables.
+    pub fn new(heap: &mut Heap) -> HandleScope {
+        let base = heap.handles.len();
+        HandleScope {
+            heap: heap,
+            base: base,
+        }
+    }
 
-/// The scheme heap and GC runtime, containing all allocated cons cells,
-/// activations, procedures, and strings (including strings for symbols).
-pub struct Heap {
-    /// The static environment.
-    pub environment: Environment,
+    /// Root the given GC thing pointer until this scope is dropped.
+    pub fn handle<'a, T: ToGcThing + Copy>(&'a self, ptr: T) -> Handle<'a, T> {
+        if let Some(thing) = ptr.to_gc_thing() {
+            unsafe {
+                self.heap.as_mut()
+                    .expect("HandleScope should always have a Heap")
+                    .add_handle(thing);
+            }
+        }
+        Handle {
+            ptr: ptr,
+            scope: PhantomData,
+        }
+    }
 
-    cons_cells: ArenaSet<Cons>,
-    strings: ArenaSet<String>,
-    activations: ArenaSet<Activation>,
-    procedures: ArenaSet<Procedure>,
+    /// Root the given handle's referent individually, so that it may outlive
+    /// this scope.
+    pub fn escape<T: ToGcThing + Copy>(&self, handle: Handle<T>) -> Rooted<T> {
+        unsafe {
+            let heap = self.heap.as_mut()
+                .expect("HandleScope should always have a Heap");
+            Rooted::new(heap, handle.ptr)
+        }
+    }
+}
 
-    roots: Vec<(GcThing, usize)>,
-    symbol_table: HashMap<String, StringPtr>,
-    global_activation: ActivationPtr,
-    allocations: usize,
-    allocations_threshold: usize,
+impl Drop for HandleScope {
+    fn drop(&mut self) {
+        unsafe {
+            let heap = self.heap.as_mut()
+                .expect("HandleScope should always have a Heap");
+            assert!(heap.handles.len() >= self.base,
+                    "HandleScopes should be dropped in the reverse order they were created");
+            heap.handles.truncate(self.base);
+        }
+    }
+}
 
-    locations: HashMap<ConsPtr, Location>,
+/// A pointer to a GC thing that is rooted for as long as the `HandleScope` it
+/// was created in. Unlike `Rooted`, creating a `Handle` only pushes onto the
+/// heap's stack of handles, and dropping one does nothing at all.
+pub struct Handle<'a, T> {
+    ptr: T,
+    scope: PhantomData<&'a HandleScope>,
 }
 
-/// The default capacity of cons cells per arena.
-pub static DEFAULT_CONS_CAPACITY : usize = 1 << 10;
+impl<'a, T: Copy> ::std::marker::Copy for Handle<'a, T> { }
 
-/// The default capacity of strings per arena.
-pub static DEFAULT_STRINGS_CAPACITY : usize = 1 << 10;
+impl<'a, T: Copy> Clone for Handle<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
 
-/// The default capacity of activations per arena.
-pub static DEFAULT_ACTIVATIONS_CAPACITY : usize = 1 << 10;
+impl<'a, T> Deref for Handle<'a, T> {
+    type Target = T;
+    fn deref<'b>(&'b self) -> &'b T {
+        &self.ptr
+    }
+}
 
-/// The default capacity of procedures per arena.
-pub static DEFAULT_PROCEDURES_CAPACITY : usize = 1 << 10;
+impl<'a, T: fmt::Debug> fmt::Debug for Handle<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handle({:?})", self.ptr)
+    }
+}
 
-/// ## `Heap` Constructors
-impl Heap {
-    /// Create a new `Heap` with the default capacity.
-    pub fn new() -> Heap {
-        Heap::with_arenas(ArenaSet::new(DEFAULT_CONS_CAPACITY),
-                          ArenaSet::new(DEFAULT_STRINGS_CAPACITY),
-                          ArenaSet::new(DEFAULT_ACTIVATIONS_CAPACITY),
-                          ArenaSet::new(DEFAULT_PROCEDURES_CAPACITY))
+impl<'a, T: ToGcThing> ToGcThing for Handle<'a, T> {
+    fn to_gc_thing(&self) -> Option<GcThing> {
+        self.ptr.to_gc_thing()
     }
+}
 
-    /// Create a new `Heap` using the given arenas for allocating cons cells and
-    /// strings within.
-    pub fn with_arenas(cons_cells: ArenaSet<Cons>,
-                       strings: ArenaSet<String>,
-                       mut acts: ArenaSet<Activation>,
-                       procs: ArenaSet<Procedure>) -> Heap {
-        let mut global_act = acts.allocate();
-        let mut env = Environment::new();
-        define_primitives(&mut env, &mut global_act);
+/// A pointer to a string on the heap.
+pub type StringPtr = ArenaPtr<String>;
 
-        let mut h = Heap {
-            environment: env,
+impl ToGcThing for StringPtr {
+    fn to_gc_thing(&self) -> Option<GcThing> {
+        Some(GcThing::from_string_ptr(*self))
+    }
+}
 
-            cons_cells: cons_cells,
-            strings: strings,
-            activations: acts,
-            procedures: procs,
+/// A rooted pointer to a string on the heap.
+pub type RootedStringPtr = Rooted<StringPtr>;
 
-            global_activation: global_act,
-            roots: vec!(),
-            symbol_table: HashMap::new(),
-            allocations: 0,
-            allocations_threshold: 0,
+/// A pointer to the bytes of a bytevector on the heap.
+pub type BytevectorPtr = ArenaPtr<Vec<u8>>;
 
-            locations: HashMap::new()
-        };
+impl ToGcThing for BytevectorPtr {
+    fn to_gc_thing(&self) -> Option<GcThing> {
+        Some(GcThing::from_bytevector_ptr(*self))
+    }
+}
 
-        h.reset_gc_pressure();
+/// A rooted pointer to the bytes of a bytevector on the heap.
+pub type RootedBytevectorPtr = Rooted<BytevectorPtr>;
 
-        h
+/// A pointer to a flonum on the heap. Flonums need all 64 bits of an `f64`,
+/// which leaves no room for a tag, so unlike fixnums they are boxed.
+pub type FlonumPtr = ArenaPtr<f64>;
+
+impl ToGcThing for FlonumPtr {
+    fn to_gc_thing(&self) -> Option<GcThing> {
+        Some(GcThing::from_flonum_ptr(*self))
     }
 }
 
-/// ## `Heap` Allocation Methods
-impl Heap {
-    /// Allocate a new cons cell and return a pointer to it.
-    ///
-    /// ## Panics
-    ///
-    /// Panics if the `Arena` for cons cells has already reached capacity.
-    pub fn allocate_cons(&mut self) -> RootedConsPtr {
-        self.on_allocation();
-        let c = self.cons_cells.allocate();
-        Rooted::new(self, c)
+/// A rooted pointer to a flonum on the heap.
+pub type RootedFlonumPtr = Rooted<FlonumPtr>;
+
+/// An opaque native object, such as a socket or a database connection, that is
+/// owned by the heap and garbage collected like any other Scheme value. Created
+/// with `Heap::new_native`.
+///
+/// Unlike foreign objects, natives need not be released explicitly: the native
+/// object is dropped when the collector reclaims it, and any finalizers
+/// registered on it with `Heap::register_finalizer` are run.
+pub struct Native {
+    /// A tag naming the type of the native object, for printing and error
+    /// messages.
+    tag: &'static str,
+    /// The native object, or `None` while this slot is free.
+    object: Option<Box<Any + Send>>,
+}
+
+impl Default for Native {
+    fn default() -> Native {
+        Native {
+            tag: "",
+            object: None,
+        }
     }
+}
 
-    /// Allocate a new string and return a pointer to it.
-    ///
-    /// ## Panics
-    ///
-    /// Panics if the `Arena` for strings has already reached capacity.
-    pub fn allocate_string(&mut self) -> RootedStringPtr {
-        self.on_allocation();
-        let s = self.strings.allocate();
-        Rooted::new(self, s)
+impl Native {
+    /// Get the tag naming the type of this native object.
+    pub fn tag(&self) -> &'static str {
+        self.tag
     }
 
-    /// Allocate a new `Activation` and return a pointer to it.
-    ///
-    /// ## Panics
-    ///
-    /// Panics if the `Arena` for activations has already reached capacity.
-    pub fn allocate_activation(&mut self) -> RootedActivationPtr {
-        self.on_allocation();
-        let a = self.activations.allocate();
-        Rooted::new(self, a)
+    /// Get a reference to the native object. It is an error if it is not a
+    /// `T`.
+    pub fn downcast_ref<T: Any>(&self) -> Result<&T, SchemeError> {
+        self.object.as_ref()
+            .and_then(|object| object.downcast_ref::<T>())
+            .ok_or_else(|| E0307.error(format!(
+                "Error: native object #<native {}> has the wrong type", self.tag)))
     }
 
-    /// Allocate a new `Procedure` and return a pointer to it.
-    ///
-    /// ## Panics
-    ///
-    /// Panics if the `Arena` for procedures has already reached capacity.
-    pub fn allocate_procedure(&mut self) -> RootedProcedurePtr {
-        self.on_allocation();
-        let p = self.procedures.allocate();
-        Rooted::new(self, p)
+    /// Get a mutable reference to the native object. It is an error if it is
+    /// not a `T`.
+    pub fn downcast_mut<T: Any>(&mut self) -> Result<&mut T, SchemeError> {
+        let tag = self.tag;
+        self.object.as_mut()
+            .and_then(|object| object.downcast_mut::<T>())
+            .ok_or_else(|| E0307.error(format!(
+                "Error: native object #<native {}> has the wrong type", tag)))
     }
 }
 
-/// ## `Heap` Methods for Garbage Collection
-impl Heap {
-    /// Perform a garbage collection on the heap.
-    pub fn collect_garbage(&mut self) {
-        self.reset_gc_pressure();
+/// A pointer to a native object on the heap.
+pub type NativePtr = ArenaPtr<Native>;
 
-        // First, trace the heap graph and mark everything that is reachable.
+impl ToGcThing for NativePtr {
+    fn to_gc_thing(&self) -> Option<GcThing> {
+        Some(GcThing::from_native_ptr(*self))
+    }
+}
 
-        let mut pending_trace = self.get_roots();
+/// A rooted pointer to a native object on the heap.
+pub type RootedNativePtr = Rooted<NativePtr>;
 
-        while !pending_trace.is_empty() {
-            let mut newly_pending_trace = vec!();
+/// Events in the lifecycle of a `Heap` that embedders can hook into.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LifecycleEvent {
+    /// The heap has been created and is ready for use.
+    Create,
+    /// A garbage collection is about to begin.
+    BeforeGc,
+    /// A garbage collection has finished.
+    AfterGc,
+    /// The heap is about to be destroyed.
+    Destroy,
+}
 
-            for thing in pending_trace.drain() {
-                if !thing.is_marked() {
-                    thing.mark();
+/// A host callback invoked on a `LifecycleEvent`.
+pub type LifecycleHook = Box<FnMut(&mut Heap) + Send>;
 
-                    for referent in thing.trace() {
-                        newly_pending_trace.push(referent);
-                    }
-                }
-            }
+/// Which end of a garbage collection a `GcObserver` is being notified of.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GcPhase {
+    /// A garbage collection is about to begin.
+    Start,
+    /// A garbage collection has finished.
+    End,
+}
 
-            pending_trace.append(&mut newly_pending_trace);
-        }
+/// A host callback notified at the start and end of every garbage collection
+/// with the heap's statistics at that time. See `Heap::on_gc`.
+pub type GcObserver = Box<FnMut(GcPhase, &HeapStatistics) + Send>;
 
-        // Second, sweep each `ArenaSet`.
+/// A host closure implementing a primitive procedure. Unlike a
+/// `PrimitiveFunction`, it may capture state. See `Heap::register_primitive`.
+pub type ClosurePrimitive = Box<Fn(&mut Heap, &[RootedValue]) -> SchemeResult + Send>;
 
-        self.strings.sweep();
-        self.activations.sweep();
-        self.cons_cells.sweep();
-        self.procedures.sweep();
+/// The registered `LifecycleHook`s for each `LifecycleEvent`.
+struct LifecycleHooks {
+    create: Vec<LifecycleHook>,
+    before_gc: Vec<LifecycleHook>,
+    after_gc: Vec<LifecycleHook>,
+    destroy: Vec<LifecycleHook>,
+}
+
+impl LifecycleHooks {
+    fn new() -> LifecycleHooks {
+        LifecycleHooks {
+            create: vec!(),
+            before_gc: vec!(),
+            after_gc: vec!(),
+            destroy: vec!(),
+        }
     }
 
-    /// Explicitly add the given GC thing as a root.
-    pub fn add_root(&mut self, root: GcThing) {
-        for pair in self.roots.iter_mut() {
-            let (ref r, ref mut count) = *pair;
-            if *r == root {
-                *count += 1;
-                return;
-            }
+    fn get_mut(&mut self, event: LifecycleEvent) -> &mut Vec<LifecycleHook> {
+        match event {
+            LifecycleEvent::Create   => &mut self.create,
+            LifecycleEvent::BeforeGc => &mut self.before_gc,
+            LifecycleEvent::AfterGc  => &mut self.after_gc,
+            LifecycleEvent::Destroy  => &mut self.destroy,
         }
-        self.roots.push((root, 1));
     }
+}
 
-    /// Unroot a GC thing that was explicitly rooted with `add_root`.
-    pub fn drop_root<T: ToGcThing>(&mut self, root: &Rooted<T>) {
-        if let Some(r) = root.to_gc_thing() {
-            self.roots = self.roots.iter().fold(vec!(), |mut v, pair| {
-                let (ref rr, ref count) = *pair;
-                if r == *rr {
-                    if *count == 1 {
-                        return v;
-                    } else {
-                        v.push((*rr, *count - 1));
-                    }
-                } else {
-                    v.push((*rr, *count));
-                }
+/// Where a global variable was defined.
+#[derive(Clone, Debug)]
+pub enum DefinitionOrigin {
+    /// The variable is bound to the primitive with the given name, which is
+    /// implemented in Rust.
+    Primitive(&'static str),
+    /// The variable was defined by Scheme source at the given location.
+    Source(Location),
+}
 
-                return v;
-            });
+/// Source text kept so that errors can show the line they occurred on. Text
+/// that keeps being appended to, such as REPL input, only keeps its most recent
+/// lines. See `Heap::record_source`.
+struct RecordedSource {
+    /// The line number of the first line of `text`.
+    first_line: u64,
+    /// The number of lines in `text`.
+    lines: usize,
+    text: String,
+}
+
+impl RecordedSource {
+    fn new(text: &str) -> RecordedSource {
+        RecordedSource {
+            first_line: 1,
+            lines: 0,
+            text: String::new(),
+        }.appended(text)
+    }
+
+    /// Append the given text, and forget the oldest lines if there are more
+    /// than twice `MAX_RECORDED_SOURCE_LINES` of them.
+    fn appended(mut self, text: &str) -> RecordedSource {
+        self.text.push_str(text);
+        self.lines += text.chars().filter(|&c| c == '\n').count();
+        if self.lines > 2 * MAX_RECORDED_SOURCE_LINES {
+            let excess = self.lines - MAX_RECORDED_SOURCE_LINES;
+            let start = self.text.char_indices()
+                .filter(|&(_, c)| c == '\n')
+                .nth(excess - 1)
+                .map(|(i, _)| i + 1)
+                .expect("There should be more than `excess` lines");
+            self.text = self.text[start..].to_string();
+            self.first_line += excess as u64;
+            self.lines -= excess;
         }
+        self
     }
 
-    /// Apply pressure to the GC, and if enough pressure has built up, then
-    /// perform a garbage collection.
-    pub fn increase_gc_pressure(&mut self) {
-        self.allocations += 1;
-        if self.is_too_much_pressure() {
-            self.collect_garbage();
+    /// Get the given line of the text, if it is still recorded.
+    fn line(&self, line: u64) -> Option<String> {
+        if line < self.first_line {
+            return None;
         }
+        self.text.lines().nth((line - self.first_line) as usize).map(|l| l.to_string())
     }
+}
 
-    /// Get a vector of all of the GC roots.
-    fn get_roots(&self) -> Vec<GcThing> {
-        let mut roots: Vec<GcThing> = self.symbol_table
-            .values()
-            .map(|s| GcThing::from_string_ptr(*s))
+/// A finalizer, run by the garbage collector after the object it was
+/// registered on with `Heap::register_finalizer` becomes unreachable.
+pub enum Finalizer {
+    /// A Rust closure.
+    Native(Box<FnMut(&mut Heap) + Send>),
+    /// A Scheme procedure, called with no arguments.
+    Scheme(RootedValue),
+}
+
+/// A handle to a foreign object in the heap's foreign object table, created
+/// with `Heap::new_foreign`.
+///
+/// Foreign objects are not garbage collected. Instead, their lifetimes are
+/// managed explicitly: once released with `Heap::release_foreign` (or
+/// `foreign-release!` from Scheme), the native object is handed back to Rust
+/// and any further use of the handle is an error rather than a use-after-free.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ForeignHandle(usize);
+
+impl ForeignHandle {
+    /// Recreate the handle with the given index in the foreign object table,
+    /// for unpacking a `Word`.
+    pub fn from_index(index: usize) -> ForeignHandle {
+        ForeignHandle(index)
+    }
+
+    /// Get this handle's index in the foreign object table.
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// An entry in the foreign object table.
+struct ForeignObject {
+    /// A tag naming the type of the native object, for error messages.
+    tag: &'static str,
+    /// The native object, or `None` once it has been released.
+    object: Option<Box<Any + Send>>,
+}
+
+/// A handle for interrupting a heap's evaluation from another thread, such as
+/// a Ctrl-C handler or a watchdog, created with `Heap::interrupt_handle`.
+///
+/// An interrupted evaluation fails with an "Error: interrupted" error the next
+/// time it makes a call or a bytecode step, and unwinds as usual, leaving the
+/// heap usable.
+#[derive(Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Interrupt the current evaluation, or the next one if the heap is not
+    /// evaluating anything right now.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A request from Scheme code to end the program, made by calling `exit` or
+/// `emergency-exit`. Ending the process is up to the host, which should check
+/// `Heap::take_exit_request` whenever evaluation fails.
+///
+/// The request fails the current evaluation with an "Exit:" error, which
+/// unwinds the Scheme stack as usual, leaving the heap usable.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ExitRequest {
+    /// The status the program should exit with.
+    pub status: i32,
+    /// True for `emergency-exit`, which asks the host to exit without any of
+    /// its usual cleanup, such as running finalizers.
+    pub emergency: bool,
+}
+
+/// The profile of a single procedure, accumulated while profiling is enabled.
+#[derive(Clone, Debug)]
+pub struct ProfileEntry {
+    /// The procedure's name.
+    pub name: String,
+    /// Where the procedure was defined, or `None` for primitives.
+    pub location: Option<Location>,
+    /// The number of times the procedure was invoked.
+    pub calls: u64,
+    /// The total time spent in the procedure, including time spent in the
+    /// procedures it called, in nanoseconds.
+    pub nanoseconds: u64,
+}
+
+/// A snapshot of the heap's memory behavior, as returned by
+/// `Heap::statistics`.
+#[derive(Clone, Debug)]
+pub struct HeapStatistics {
+    /// The total number of objects allocated over the heap's lifetime.
+    pub allocations: u64,
+    /// The number of cons cells currently allocated.
+    pub live_cons_cells: usize,
+    /// The number of strings currently allocated.
+    pub live_strings: usize,
+    /// The number of activations currently allocated.
+    pub live_activations: usize,
+    /// The number of procedures currently allocated.
+    pub live_procedures: usize,
+    /// The number of vectors currently allocated.
+    pub live_vectors: usize,
+    /// The number of bytevectors currently allocated.
+    pub live_bytevectors: usize,
+    /// The number of flonums currently allocated.
+    pub live_flonums: usize,
+    /// The number of native objects currently allocated.
+    pub live_natives: usize,
+    /// The number of garbage collections performed.
+    pub collections: u64,
+    /// The total time spent collecting garbage, in nanoseconds.
+    pub total_pause_ns: u64,
+    /// The longest single garbage collection, in nanoseconds.
+    pub max_pause_ns: u64,
+    /// The most recent garbage collection, in nanoseconds.
+    pub last_pause_ns: u64,
+}
+
+/// How the REPL renders its prompt.
+pub enum ReplPrompt {
+    /// A format string, in which `{count}` is replaced with the number of
+    /// results printed so far and `{module}` with the name of the current
+    /// module. There is no module system yet, so the current module is always
+    /// `user`.
+    Format(String),
+    /// A Scheme procedure that is called with the result count and the name of
+    /// the current module, and returns the prompt.
+    Procedure(RootedValue),
+}
+
+/// The scheme heap and GC runtime, containing all allocated cons cells,
+/// activations, procedures, and strings (including strings for symbols).
+pub struct Heap {
+    /// The static environment.
+    pub environment: Environment,
+    /// The primitives that values in this heap may refer to.
+    pub primitive_table: PrimitiveTable,
+
+    cons_cells: ArenaSet<Cons>,
+    strings: ArenaSet<String>,
+    activations: ArenaSet<Activation>,
+    procedures: ArenaSet<Procedure>,
+    vectors: ArenaSet<Vector>,
+    bytevectors: ArenaSet<Vec<u8>>,
+    flonums: ArenaSet<f64>,
+    natives: ArenaSet<Native>,
+
+    roots: Vec<(GcThing, usize)>,
+    handles: Vec<GcThing>,
+    /// The intern table, which holds its symbols weakly.
+    symbol_table: HashMap<String, StringPtr>,
+    /// The EOF object, an uninterned symbol. See `Heap::eof_symbol`.
+    eof_object: Option<RootedValue>,
+    /// The default object, an uninterned symbol. See `Heap::default_object`.
+    default_object: Option<RootedValue>,
+    global_activation: ActivationPtr,
+    allocations: usize,
+    allocations_threshold: usize,
+    collection_trigger: f64,
+    growth_factor: f64,
+    min_collection_threshold: usize,
+    incremental_mark_budget: Option<usize>,
+    marking: bool,
+    pending_trace: Vec<GcThing>,
+    max_arenas: Option<usize>,
+    max_bytes: Option<usize>,
+    out_of_memory: bool,
+    gc_stress: bool,
+
+    catch_panics: bool,
+
+    inline_budget: usize,
+    constant_folding: bool,
+    flat_closures: bool,
+    meaning_unit: MeaningUnit,
+    analysis_depth: usize,
+    analysis_cache: AnalysisCache,
+
+    warnings_enabled: bool,
+    warnings: Vec<Warning>,
+    /// References to globals which were not defined when they were analyzed:
+    /// (index in the global activation, name, location of the reference).
+    undefined_references: Vec<(u32, SymbolId, Location)>,
+
+    execution_mode: ExecutionMode,
+    compiled: CodeCache,
+
+    total_allocations: u64,
+    collections: u64,
+    total_pause_ns: u64,
+    max_pause_ns: u64,
+    last_pause_ns: u64,
+
+    locations: HashMap<ConsPtr, Location>,
+
+    command_line: Vec<String>,
+
+    frames: Vec<Frame>,
+    /// The number of nested evaluations currently recursing on the host stack.
+    recursion_depth: usize,
+    max_recursion_depth: usize,
+    /// The argument stack, which invocations evaluate their arguments onto.
+    /// Every value on it is a root.
+    arguments: Vec<Value>,
+    error_location: Option<Location>,
+    error_irritants: Vec<RootedValue>,
+    /// The keys and values of the continuation marks in effect, oldest first.
+    continuation_marks: Vec<(RootedValue, RootedValue)>,
+    /// The `after` thunks of the `dynamic-wind` extents in effect, oldest
+    /// first.
+    winds: Vec<RootedValue>,
+
+    random_seed: u64,
+    random: RandomSource,
+    clock_stub: Option<i64>,
+
+    fuel: Option<u64>,
+    reified_environments: ReifiedEnvironments,
+    generators: Generators,
+    running_generators: usize,
+    /// The value passed to `yield` by the running generator's body, and the
+    /// fuel the heap had before `yield` took it away to suspend the body.
+    yielded: Option<(RootedValue, Option<u64>)>,
+    interrupted: Arc<AtomicBool>,
+    exit_request: Option<ExitRequest>,
+
+    hooks: LifecycleHooks,
+
+    arithmetic_mode: ArithmeticMode,
+
+    macros: HashMap<String, RootedValue>,
+    prelude_loaded: bool,
+    created: bool,
+
+    repl_prompt: ReplPrompt,
+    repl_banner: String,
+
+    traced: Vec<RootedValue>,
+    trace_depth: usize,
+
+    definitions: HashMap<String, Location>,
+    documentation: HashMap<(String, u64, u64), String>,
+    sources: HashMap<String, RecordedSource>,
+    tool_recorder: Option<ToolRecorder>,
+
+    debugger: Option<Debugger>,
+    breakpoints: HashSet<(String, u64)>,
+    stepping: bool,
+    last_stop: Option<(String, u64)>,
+
+    profile: Option<HashMap<String, ProfileEntry>>,
+
+    foreign_objects: Vec<ForeignObject>,
+    primitive_set: PrimitiveSet,
+    primitive_registry: Vec<PrimitiveInfo>,
+    closure_primitives: Vec<Rc<ClosurePrimitive>>,
+
+    finalizers: Vec<(GcThing, Finalizer)>,
+    pending_finalizers: Vec<Finalizer>,
+    running_finalizers: bool,
+}
+
+/// A heap that nothing outside of it refers to, so that an embedder can hand a
+/// whole interpreter to a worker thread. See `OwnedHeap::into_send`.
+///
+/// An `OwnedHeap` creates its heap itself, and only lends it out through
+/// `OwnedHeap::with`, whose closure can neither capture nor return anything
+/// that is not `Send`. That is not enough to make it `Send`, though: the types
+/// can not stop a handle into the heap from being stashed somewhere that
+/// outlives the closure, such as a thread-local.
+pub struct OwnedHeap {
+    heap: Box<Heap>,
+}
+
+impl OwnedHeap {
+    /// Create a new `OwnedHeap` with the default capacity.
+    pub fn new() -> OwnedHeap {
+        OwnedHeap::with_config(Default::default())
+    }
+
+    /// Create a new `OwnedHeap` with the given configuration. See
+    /// `Heap::with_config`.
+    pub fn with_config(config: HeapConfig) -> OwnedHeap {
+        OwnedHeap {
+            heap: Box::new(Heap::with_config(config)),
+        }
+    }
+
+    /// Perform some work with the heap, and return its result.
+    pub fn with<F, T>(&mut self, f: F) -> T
+        where F: Send + FnOnce(&mut Heap) -> T,
+              T: Send
+    {
+        f(&mut *self.heap)
+    }
+
+    /// Vouch that this heap may be moved to another thread, and get a
+    /// `SendHeap` that can be.
+    ///
+    /// Everything the heap owns is either `Send`, or only ever touched by the
+    /// thread that is using the heap, as long as no handle to it is left
+    /// behind. Host callbacks and objects stored in the heap (closure
+    /// primitives, finalizers, foreign and native objects, lifecycle hooks, GC
+    /// observers, and the debugger) are required to be `Send` by their types.
+    /// What is not `Send` is:
+    ///
+    /// * `Rooted` values, `ArenaPtr`s, and `Primitive`s, which point into the
+    ///   heap and its primitive table with raw pointers, and register roots
+    ///   with the heap when created and dropped.
+    ///
+    /// * `Meaning`s, `MeaningUnit`s, compiled `Code`, the `Scope`s of reified
+    ///   environments, procedures' `Signature`s, and closure primitives, which
+    ///   are shared with non-atomic reference counts.
+    ///
+    /// This is unsafe because nothing checks that no value of any of the types
+    /// above that was obtained from this heap is alive outside of it, anywhere:
+    /// not in a thread-local or a static written to during `with`, not in a
+    /// host callback stored in another heap, and not on the stack of the thread
+    /// giving the heap away. The caller must ensure that.
+    pub unsafe fn into_send(self) -> SendHeap {
+        SendHeap {
+            heap: self,
+        }
+    }
+}
+
+/// An `OwnedHeap` that may be moved to another thread. It can only be made by
+/// `OwnedHeap::into_send`, whose caller vouches that it is safe to.
+pub struct SendHeap {
+    heap: OwnedHeap,
+}
+
+unsafe impl Send for SendHeap { }
+
+impl SendHeap {
+    /// Get the heap back, once it has been moved to the thread that will use
+    /// it.
+    pub fn into_owned(self) -> OwnedHeap {
+        self.heap
+    }
+}
+
+/// The default capacity of cons cells per arena.
+pub static DEFAULT_CONS_CAPACITY : usize = 1 << 10;
+
+/// The default capacity of strings per arena.
+pub static DEFAULT_STRINGS_CAPACITY : usize = 1 << 10;
+
+/// The default capacity of activations per arena.
+pub static DEFAULT_ACTIVATIONS_CAPACITY : usize = 1 << 10;
+
+/// The default capacity of procedures per arena.
+pub static DEFAULT_PROCEDURES_CAPACITY : usize = 1 << 10;
+
+/// The default capacity of vectors per arena.
+pub static DEFAULT_VECTORS_CAPACITY : usize = 1 << 8;
+
+/// The default capacity of bytevectors per arena.
+pub static DEFAULT_BYTEVECTORS_CAPACITY : usize = 1 << 8;
+
+/// The default capacity of flonums per arena.
+pub static DEFAULT_FLONUMS_CAPACITY : usize = 1 << 10;
+
+/// The default capacity of native objects per arena.
+pub static DEFAULT_NATIVES_CAPACITY : usize = 1 << 6;
+
+/// The default fraction of the heap's total arena capacity that may be
+/// allocated between garbage collections.
+pub static DEFAULT_COLLECTION_TRIGGER : f64 = 0.5;
+
+/// The default heap growth factor.
+pub static DEFAULT_GROWTH_FACTOR : f64 = 1.0;
+
+/// The default maximum depth of non-tail recursion during evaluation.
+pub static DEFAULT_MAX_RECURSION_DEPTH : usize = 10_000;
+
+/// The default maximum number of procedure bodies whose bytecode is cached.
+pub static DEFAULT_COMPILED_CODE_CAPACITY : usize = 4096;
+
+/// The number of most recent lines of appended source text, such as REPL
+/// input, that are kept for showing the lines errors occurred on. See
+/// `Heap::record_source`.
+pub static MAX_RECORDED_SOURCE_LINES : usize = 1000;
+
+/// The most warnings, and the most references to undefined globals, that are
+/// kept until they are taken with `Heap::take_warnings`. Any more are dropped,
+/// so that the warnings of a heap whose embedder never takes them do not grow
+/// without bound.
+pub static MAX_PENDING_WARNINGS : usize = 1000;
+
+/// The number of jiffies in a second, as returned by `jiffies-per-second`. A
+/// jiffy is a microsecond.
+pub static JIFFIES_PER_SECOND : i64 = 1_000_000;
+
+/// Tuning parameters for the heap and its garbage collector, passed to
+/// `Heap::with_config`.
+#[derive(Clone, Debug)]
+pub struct HeapConfig {
+    /// The capacity of cons cells per arena.
+    pub cons_capacity: usize,
+    /// The capacity of strings per arena.
+    pub strings_capacity: usize,
+    /// The capacity of activations per arena.
+    pub activations_capacity: usize,
+    /// The capacity of procedures per arena.
+    pub procedures_capacity: usize,
+    /// The capacity of vectors per arena.
+    pub vectors_capacity: usize,
+    /// The capacity of bytevectors per arena.
+    pub bytevectors_capacity: usize,
+    /// The capacity of flonums per arena.
+    pub flonums_capacity: usize,
+    /// The capacity of native objects per arena.
+    pub natives_capacity: usize,
+    /// A garbage collection is triggered once this fraction of the heap's total
+    /// arena capacity has been allocated since the last collection.
+    pub collection_trigger: f64,
+    /// After a collection, the heap may grow to this multiple of its live size
+    /// before the next collection, if that allows more allocations than
+    /// `collection_trigger` does. A factor of `1.0` disables growth-based
+    /// triggering.
+    pub growth_factor: f64,
+    /// The minimum number of allocations between garbage collections.
+    pub min_collection_threshold: usize,
+    /// If set, collect garbage incrementally, marking at most this many objects
+    /// per slice so that no single pause is too long. Otherwise, collect
+    /// garbage all at once.
+    pub incremental_mark_budget: Option<usize>,
+    /// If set, the maximum number of arenas the heap may use across all object
+    /// types. Evaluation that needs more than this fails with an out of memory
+    /// error. See `Heap::take_out_of_memory`.
+    pub max_arenas: Option<usize>,
+    /// If set, the maximum number of bytes the heap's arenas may use across
+    /// all object types. Like `max_arenas`, evaluation that needs more than
+    /// this fails with an out of memory error. See `Heap::set_max_bytes`.
+    pub max_bytes: Option<usize>,
+    /// The deepest that the meaning interpreter, or a primitive calling back
+    /// into Scheme, may recurse on the host stack before evaluation fails with
+    /// a "maximum recursion depth exceeded" error, rather than overflowing the
+    /// stack and aborting the process. Threads with small stacks need a smaller
+    /// limit. See `Heap::set_max_recursion_depth`.
+    pub max_recursion_depth: usize,
+    /// The maximum size, in meanings, of a global procedure's body for it to
+    /// be inlined at its call sites during syntactic analysis. A budget of `0`
+    /// disables inlining.
+    pub inline_budget: usize,
+    /// If true, invocations of pure primitives on constant arguments, and
+    /// conditionals with constant tests, are evaluated during syntactic
+    /// analysis. Folded code does not notice if the primitive's global is later
+    /// redefined, so this is off by default.
+    pub constant_folding: bool,
+    /// The maximum number of top-level forms whose meanings are cached, so
+    /// that evaluating the same form again skips syntactic analysis. A
+    /// capacity of `0` disables the cache. See `eval::AnalysisCache`.
+    ///
+    /// Cached forms are not expanded again, so a macro whose transformer reads
+    /// a global variable keeps expanding the way it did when the form was
+    /// first evaluated, even after the global is changed with `set!` or
+    /// `define`. Defining a macro, or calling `invalidate` on
+    /// `Heap::analysis_cache`, forgets every cached meaning.
+    pub analysis_cache_capacity: usize,
+    /// If true, lambdas nested in other procedures copy just the free
+    /// variables they use into a flat closure, rather than retaining every
+    /// enclosing activation. See `Heap::set_flat_closures`.
+    pub flat_closures: bool,
+    /// If true, syntactic analysis reports suspicious code, such as unused
+    /// parameters, as warnings. See `Heap::take_warnings`.
+    pub warnings: bool,
+    /// Whether to interpret meanings directly or to compile them to bytecode
+    /// first. See `Heap::set_execution_mode`.
+    pub execution_mode: ExecutionMode,
+    /// The maximum number of procedure bodies whose bytecode is cached, in
+    /// bytecode mode. A capacity of `0` disables the cache. See
+    /// `vm::CodeCache`.
+    pub compiled_code_capacity: usize,
+    /// If true, perform a full garbage collection before every allocation.
+    /// This is very slow, but catches rooting bugs deterministically. See
+    /// `Heap::set_gc_stress`.
+    pub gc_stress: bool,
+    /// If true, a panic within a primitive is caught and converted into an
+    /// error, rather than unwinding through the host. This keeps a buggy native
+    /// extension from tearing down the host process, but makes every call to a
+    /// primitive much slower, because each one is made on a thread of its own.
+    /// See `Heap::set_catch_panics`.
+    pub catch_panics: bool,
+    /// The primitives defined in the global environment. Embedders running
+    /// untrusted scripts can withhold primitives that reach outside of the
+    /// heap. See `PrimitiveSet`.
+    pub primitives: PrimitiveSet,
+}
+
+impl Default for HeapConfig {
+    fn default() -> HeapConfig {
+        HeapConfig {
+            cons_capacity: DEFAULT_CONS_CAPACITY,
+            strings_capacity: DEFAULT_STRINGS_CAPACITY,
+            activations_capacity: DEFAULT_ACTIVATIONS_CAPACITY,
+            procedures_capacity: DEFAULT_PROCEDURES_CAPACITY,
+            vectors_capacity: DEFAULT_VECTORS_CAPACITY,
+            bytevectors_capacity: DEFAULT_BYTEVECTORS_CAPACITY,
+            flonums_capacity: DEFAULT_FLONUMS_CAPACITY,
+            natives_capacity: DEFAULT_NATIVES_CAPACITY,
+            collection_trigger: DEFAULT_COLLECTION_TRIGGER,
+            growth_factor: DEFAULT_GROWTH_FACTOR,
+            min_collection_threshold: 0,
+            incremental_mark_budget: None,
+            max_arenas: None,
+            max_bytes: None,
+            max_recursion_depth: DEFAULT_MAX_RECURSION_DEPTH,
+            inline_budget: 0,
+            constant_folding: false,
+            analysis_cache_capacity: 0,
+            flat_closures: false,
+            warnings: true,
+            execution_mode: ExecutionMode::Interpreter,
+            compiled_code_capacity: DEFAULT_COMPILED_CODE_CAPACITY,
+            gc_stress: false,
+            catch_panics: false,
+            primitives: PrimitiveSet::all(),
+        }
+    }
+}
+
+impl HeapConfig {
+    /// Check that this configuration can be used to create a heap, returning a
+    /// message describing the first problem found otherwise. Every arena must
+    /// have room for at least one object.
+    pub fn validate(&self) -> Result<(), String> {
+        let capacities = [("cons_capacity", self.cons_capacity),
+                          ("strings_capacity", self.strings_capacity),
+                          ("activations_capacity", self.activations_capacity),
+                          ("procedures_capacity", self.procedures_capacity),
+                          ("vectors_capacity", self.vectors_capacity),
+                          ("bytevectors_capacity", self.bytevectors_capacity),
+                          ("flonums_capacity", self.flonums_capacity),
+                          ("natives_capacity", self.natives_capacity)];
+        for &(name, capacity) in capacities.iter() {
+            if capacity == 0 {
+                return Err(format!("HeapConfig: `{}` must be greater than 0", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ## `Heap` Constructors
+impl Heap {
+    /// Create a new `Heap` with the default capacity.
+    pub fn new() -> Heap {
+        Heap::with_config(Default::default())
+    }
+
+    /// Create a new `Heap` with the given arena sizes and garbage collection
+    /// tuning parameters.
+    ///
+    /// Panics if the configuration is invalid; see `HeapConfig::validate`, or
+    /// use `Heap::try_with_config` to handle that case.
+    pub fn with_config(config: HeapConfig) -> Heap {
+        match Heap::try_with_config(config) {
+            Ok(heap) => heap,
+            Err(msg) => panic!("{}", msg),
+        }
+    }
+
+    /// Create a new `Heap` with the given arena sizes and garbage collection
+    /// tuning parameters, or return an error if the configuration is invalid.
+    pub fn try_with_config(config: HeapConfig) -> Result<Heap, String> {
+        try!(config.validate());
+
+        let mut h = Heap::with_arenas(ArenaSet::new(config.cons_capacity),
+                                      ArenaSet::new(config.strings_capacity),
+                                      ArenaSet::new(config.activations_capacity),
+                                      ArenaSet::new(config.procedures_capacity),
+                                      ArenaSet::new(config.vectors_capacity),
+                                      ArenaSet::new(config.bytevectors_capacity),
+                                      ArenaSet::new(config.flonums_capacity),
+                                      ArenaSet::new(config.natives_capacity),
+                                      &config.primitives);
+        h.collection_trigger = config.collection_trigger;
+        h.growth_factor = config.growth_factor;
+        h.min_collection_threshold = config.min_collection_threshold;
+        h.incremental_mark_budget = config.incremental_mark_budget;
+        h.max_arenas = config.max_arenas;
+        h.max_bytes = config.max_bytes;
+        h.max_recursion_depth = config.max_recursion_depth;
+        h.inline_budget = config.inline_budget;
+        h.constant_folding = config.constant_folding;
+        h.analysis_cache = AnalysisCache::new(config.analysis_cache_capacity);
+        h.flat_closures = config.flat_closures;
+        h.warnings_enabled = config.warnings;
+        h.execution_mode = config.execution_mode;
+        h.compiled = CodeCache::new(config.compiled_code_capacity);
+        h.gc_stress = config.gc_stress;
+        h.catch_panics = config.catch_panics;
+        h.reset_gc_pressure();
+        Ok(h)
+    }
+
+    /// Create a new `Heap` with the default capacity and register the given
+    /// lifecycle hooks. Any `LifecycleEvent::Create` hooks run when the heap
+    /// is first used; see `Heap::ensure_created`.
+    pub fn with_lifecycle_hooks(hooks: Vec<(LifecycleEvent, LifecycleHook)>) -> Heap {
+        let mut h = Heap::new();
+        for (event, hook) in hooks.into_iter() {
+            h.add_lifecycle_hook(event, hook);
+        }
+        h
+    }
+
+    /// Create a new `Heap` using the given arenas for allocating each type of
+    /// GC thing within, and with the given primitives defined.
+    pub fn with_arenas(cons_cells: ArenaSet<Cons>,
+                       strings: ArenaSet<String>,
+                       mut acts: ArenaSet<Activation>,
+                       procs: ArenaSet<Procedure>,
+                       vectors: ArenaSet<Vector>,
+                       bytevectors: ArenaSet<Vec<u8>>,
+                       flonums: ArenaSet<f64>,
+                       natives: ArenaSet<Native>,
+                       primitives: &PrimitiveSet) -> Heap {
+        let mut global_act = acts.allocate();
+        let mut env = Environment::new();
+        let mut primitive_table = PrimitiveTable::new();
+        let primitive_registry = primitives.install(&mut primitive_table,
+                                                    &mut env,
+                                                    &mut global_act);
+        let random_seed = time::get_time().sec as u64;
+
+        let mut h = Heap {
+            environment: env,
+            primitive_table: primitive_table,
+
+            cons_cells: cons_cells,
+            strings: strings,
+            activations: acts,
+            procedures: procs,
+            vectors: vectors,
+            bytevectors: bytevectors,
+            flonums: flonums,
+            natives: natives,
+
+            global_activation: global_act,
+            roots: vec!(),
+            handles: vec!(),
+            symbol_table: HashMap::new(),
+            eof_object: None,
+            default_object: None,
+            allocations: 0,
+            allocations_threshold: 0,
+            collection_trigger: DEFAULT_COLLECTION_TRIGGER,
+            growth_factor: DEFAULT_GROWTH_FACTOR,
+            min_collection_threshold: 0,
+            incremental_mark_budget: None,
+            marking: false,
+            pending_trace: vec!(),
+            max_arenas: None,
+            max_bytes: None,
+            out_of_memory: false,
+            gc_stress: false,
+
+            catch_panics: false,
+
+            inline_budget: 0,
+            constant_folding: false,
+            flat_closures: false,
+            meaning_unit: MeaningUnit::new(),
+            analysis_depth: 0,
+            analysis_cache: AnalysisCache::new(0),
+
+            warnings_enabled: true,
+            warnings: vec!(),
+            undefined_references: vec!(),
+
+            execution_mode: ExecutionMode::Interpreter,
+            compiled: CodeCache::new(DEFAULT_COMPILED_CODE_CAPACITY),
+
+            total_allocations: 0,
+            collections: 0,
+            total_pause_ns: 0,
+            max_pause_ns: 0,
+            last_pause_ns: 0,
+
+            locations: HashMap::new(),
+
+            command_line: vec!(),
+
+            frames: vec!(),
+            recursion_depth: 0,
+            max_recursion_depth: DEFAULT_MAX_RECURSION_DEPTH,
+            arguments: vec!(),
+            error_location: None,
+            error_irritants: vec!(),
+            continuation_marks: vec!(),
+            winds: vec!(),
+
+            random_seed: random_seed,
+            random: RandomSource::new(random_seed),
+            clock_stub: None,
+
+            fuel: None,
+            reified_environments: ReifiedEnvironments::new(),
+            generators: Generators::new(),
+            running_generators: 0,
+            yielded: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            exit_request: None,
+
+            hooks: LifecycleHooks::new(),
+
+            arithmetic_mode: ArithmeticMode::Error,
+
+            macros: HashMap::new(),
+            prelude_loaded: false,
+            created: false,
+
+            repl_prompt: ReplPrompt::Format("oxischeme> ".to_string()),
+            repl_banner: "Welcome to oxischeme!\nC-c to exit.\n".to_string(),
+
+            traced: vec!(),
+            trace_depth: 0,
+
+            definitions: HashMap::new(),
+            documentation: HashMap::new(),
+            sources: HashMap::new(),
+            tool_recorder: None,
+
+            debugger: None,
+            breakpoints: HashSet::new(),
+            stepping: false,
+            last_stop: None,
+
+            profile: None,
+
+            foreign_objects: vec!(),
+            primitive_set: primitives.clone(),
+            primitive_registry: primitive_registry,
+            closure_primitives: vec!(),
+
+            finalizers: vec!(),
+            pending_finalizers: vec!(),
+            running_finalizers: false,
+        };
+
+        h.reset_gc_pressure();
+
+        h
+    }
+}
+
+/// ## `Heap` Allocation Methods
+impl Heap {
+    /// Allocate a new cons cell and return a pointer to it.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the `Arena` for cons cells has already reached capacity.
+    pub fn allocate_cons(&mut self) -> RootedConsPtr {
+        self.on_allocation();
+        if self.cons_cells.is_full() {
+            let bytes = self.cons_cells.arena_bytes();
+            self.check_arena_limit(|h| h.cons_cells.is_full(), bytes);
+        }
+        let c = self.cons_cells.allocate();
+        if self.marking {
+            // Allocate black during incremental marking.
+            c.mark();
+        }
+        Rooted::new(self, c)
+    }
+
+    /// Allocate a new string and return a pointer to it.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the `Arena` for strings has already reached capacity.
+    pub fn allocate_string(&mut self) -> RootedStringPtr {
+        self.on_allocation();
+        if self.strings.is_full() {
+            let bytes = self.strings.arena_bytes();
+            self.check_arena_limit(|h| h.strings.is_full(), bytes);
+        }
+        let s = self.strings.allocate();
+        if self.marking {
+            // Allocate black during incremental marking.
+            s.mark();
+        }
+        Rooted::new(self, s)
+    }
+
+    /// Allocate a new `Activation` and return a pointer to it.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the `Arena` for activations has already reached capacity.
+    pub fn allocate_activation(&mut self) -> RootedActivationPtr {
+        self.on_allocation();
+        if self.activations.is_full() {
+            let bytes = self.activations.arena_bytes();
+            self.check_arena_limit(|h| h.activations.is_full(), bytes);
+        }
+        let a = self.activations.allocate();
+        if self.marking {
+            // Allocate black during incremental marking.
+            a.mark();
+        }
+        Rooted::new(self, a)
+    }
+
+    /// Allocate a new `Procedure` and return a pointer to it.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the `Arena` for procedures has already reached capacity.
+    pub fn allocate_procedure(&mut self) -> RootedProcedurePtr {
+        self.on_allocation();
+        if self.procedures.is_full() {
+            let bytes = self.procedures.arena_bytes();
+            self.check_arena_limit(|h| h.procedures.is_full(), bytes);
+        }
+        let p = self.procedures.allocate();
+        if self.marking {
+            // Allocate black during incremental marking.
+            p.mark();
+        }
+        Rooted::new(self, p)
+    }
+
+    /// Allocate a new `Vector` and return a pointer to it.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the `Arena` for vectors has already reached capacity.
+    pub fn allocate_vector(&mut self) -> RootedVectorPtr {
+        self.on_allocation();
+        if self.vectors.is_full() {
+            let bytes = self.vectors.arena_bytes();
+            self.check_arena_limit(|h| h.vectors.is_full(), bytes);
+        }
+        let v = self.vectors.allocate();
+        if self.marking {
+            // Allocate black during incremental marking.
+            v.mark();
+        }
+        Rooted::new(self, v)
+    }
+
+    /// Allocate a new bytevector and return a pointer to it.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the `Arena` for bytevectors has already reached capacity.
+    pub fn allocate_bytevector(&mut self) -> RootedBytevectorPtr {
+        self.on_allocation();
+        if self.bytevectors.is_full() {
+            let bytes = self.bytevectors.arena_bytes();
+            self.check_arena_limit(|h| h.bytevectors.is_full(), bytes);
+        }
+        let b = self.bytevectors.allocate();
+        if self.marking {
+            // Allocate black during incremental marking.
+            b.mark();
+        }
+        Rooted::new(self, b)
+    }
+
+    /// Allocate a new flonum and return a pointer to it.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the `Arena` for flonums has already reached capacity.
+    pub fn allocate_flonum(&mut self) -> RootedFlonumPtr {
+        self.on_allocation();
+        if self.flonums.is_full() {
+            let bytes = self.flonums.arena_bytes();
+            self.check_arena_limit(|h| h.flonums.is_full(), bytes);
+        }
+        let f = self.flonums.allocate();
+        if self.marking {
+            // Allocate black during incremental marking.
+            f.mark();
+        }
+        Rooted::new(self, f)
+    }
+
+    /// Allocate a new `Native` and return a pointer to it.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the `Arena` for native objects has already reached capacity.
+    pub fn allocate_native(&mut self) -> RootedNativePtr {
+        self.on_allocation();
+        if self.natives.is_full() {
+            let bytes = self.natives.arena_bytes();
+            self.check_arena_limit(|h| h.natives.is_full(), bytes);
+        }
+        let n = self.natives.allocate();
+        if self.marking {
+            // Allocate black during incremental marking.
+            n.mark();
+        }
+        Rooted::new(self, n)
+    }
+}
+
+/// ## `Heap` Methods for Running Out of Memory
+impl Heap {
+    /// Called before allocating a new arena of the given size in bytes. If that
+    /// would exceed the maximum number of arenas or bytes, try collecting
+    /// garbage to make room first, and if that doesn't help, note that we are
+    /// out of memory.
+    ///
+    /// The allocation still goes ahead, using the heap's headroom beyond the
+    /// limit, so that allocation never fails and the heap is always left in a
+    /// consistent state. Instead, the evaluator checks for running out of
+    /// memory after every step and raises an error, unwinding the offending
+    /// computation so that its garbage can be reclaimed.
+    fn check_arena_limit<F: Fn(&Heap) -> bool>(&mut self, is_full: F, arena_bytes: usize) {
+        if self.would_exceed_limits(arena_bytes) {
+            self.collect_garbage();
+            if is_full(self) && self.would_exceed_limits(arena_bytes) {
+                self.out_of_memory = true;
+            }
+        }
+    }
+
+    /// Return true if allocating a new arena of the given size in bytes would
+    /// exceed the maximum number of arenas or bytes, false otherwise.
+    fn would_exceed_limits(&self, arena_bytes: usize) -> bool {
+        let too_many_arenas = match self.max_arenas {
+            Some(max) => self.arena_count() >= max,
+            None      => false,
+        };
+        let too_many_bytes = match self.max_bytes {
+            Some(max) => self.bytes() + arena_bytes > max,
+            None      => false,
+        };
+        too_many_arenas || too_many_bytes
+    }
+
+    /// Get the total number of bytes in the object pools of the heap's arenas.
+    /// This does not include memory owned by the objects themselves, such as
+    /// the characters of strings.
+    pub fn bytes(&self) -> usize {
+        self.cons_cells.bytes()
+            + self.strings.bytes()
+            + self.activations.bytes()
+            + self.procedures.bytes()
+            + self.vectors.bytes()
+            + self.bytevectors.bytes()
+            + self.flonums.bytes()
+            + self.natives.bytes()
+    }
+
+    /// Get the maximum number of bytes the heap's arenas may use, if any. See
+    /// `HeapConfig::max_bytes`.
+    pub fn max_bytes(&self) -> Option<usize> {
+        self.max_bytes
+    }
+
+    /// Set the maximum number of bytes the heap's arenas may use, or remove
+    /// the limit with `None`. This lets embedders bound the memory of each
+    /// script they run. A heap that is already over the new limit is not
+    /// shrunk, but it will run out of memory the next time it needs to grow.
+    pub fn set_max_bytes(&mut self, max_bytes: Option<usize>) {
+        self.max_bytes = max_bytes;
+    }
+
+    /// Get the total number of arenas the heap is using.
+    pub fn arena_count(&self) -> usize {
+        self.cons_cells.arena_count()
+            + self.strings.arena_count()
+            + self.activations.arena_count()
+            + self.procedures.arena_count()
+            + self.vectors.arena_count()
+            + self.bytevectors.arena_count()
+            + self.flonums.arena_count()
+            + self.natives.arena_count()
+    }
+
+    /// Return true if the heap has exceeded its maximum number of arenas or
+    /// bytes since the last time this method was called, false otherwise.
+    pub fn take_out_of_memory(&mut self) -> bool {
+        mem::replace(&mut self.out_of_memory, false)
+    }
+}
+
+/// ## `Heap` Methods for Garbage Collection
+impl Heap {
+    /// Perform a garbage collection on the heap. If an incremental collection
+    /// is in progress, it is finished.
+    pub fn collect_garbage(&mut self) {
+        let start = time::precise_time_ns();
+
+        if !self.marking {
+            self.start_marking();
+        }
+        self.mark(None);
+        self.sweep();
+
+        self.record_pause(time::precise_time_ns() - start);
+        self.trigger_lifecycle_event(LifecycleEvent::AfterGc);
+        self.run_finalizers();
+    }
+
+    /// Perform one slice of an incremental garbage collection, starting a new
+    /// collection if none is in progress. At most the incremental mark budget's
+    /// worth of objects are marked (or all of them, if no budget is set), and
+    /// if that finishes marking, the heap is swept. Return true if this slice
+    /// finished the collection, false otherwise.
+    pub fn collect_garbage_slice(&mut self) -> bool {
+        let start = time::precise_time_ns();
+
+        if !self.marking {
+            self.start_marking();
+        }
+        let budget = self.incremental_mark_budget;
+        let finished = self.mark(budget);
+        if finished {
+            self.sweep();
+        }
+
+        self.record_pause(time::precise_time_ns() - start);
+        if finished {
+            self.trigger_lifecycle_event(LifecycleEvent::AfterGc);
+            self.run_finalizers();
+        }
+        finished
+    }
+
+    /// Enable or disable GC stress mode, in which a full garbage collection is
+    /// performed before every allocation. Any GC thing that is not properly
+    /// rooted across a call that allocates is then reclaimed as soon as
+    /// possible, rather than whenever enough pressure happens to build up, so
+    /// that rooting bugs in new primitives show up deterministically.
+    pub fn set_gc_stress(&mut self, gc_stress: bool) {
+        self.gc_stress = gc_stress;
+    }
+
+    /// Return true if GC stress mode is enabled, false otherwise.
+    pub fn is_gc_stress(&self) -> bool {
+        self.gc_stress
+    }
+
+    /// Set the maximum number of objects marked in each slice of an incremental
+    /// collection, or `None` to collect garbage all at once.
+    pub fn set_incremental_mark_budget(&mut self, budget: Option<usize>) {
+        self.incremental_mark_budget = budget;
+    }
+
+    /// Return true if an incremental collection is in progress, false
+    /// otherwise.
+    pub fn is_marking(&self) -> bool {
+        self.marking
+    }
+
+    /// Begin a collection by snapshotting the roots.
+    ///
+    /// While marking is in progress, the mutator may run between slices. This
+    /// is safe because everything reachable from the snapshot will be marked,
+    /// objects allocated during marking are allocated already marked, and any
+    /// object that is rooted during marking is added to the pending trace. The
+    /// mutator only ever stores rooted values into heap objects, so every
+    /// object that becomes reachable while marking gets marked.
+    fn start_marking(&mut self) {
+        self.trigger_lifecycle_event(LifecycleEvent::BeforeGc);
+        self.reset_gc_pressure();
+        self.pending_trace = self.get_roots();
+        self.marking = true;
+    }
+
+    /// Trace the heap graph and mark everything that is reachable, marking at
+    /// most `budget` objects if one is given. Return true if marking is
+    /// finished, false otherwise.
+    fn mark(&mut self, budget: Option<usize>) -> bool {
+        let mut marked = 0;
+
+        while let Some(thing) = self.pending_trace.pop() {
+            if thing.is_marked() {
+                continue;
+            }
+
+            thing.mark();
+            for referent in thing.trace() {
+                self.pending_trace.push(referent);
+            }
+
+            marked += 1;
+            if budget.map_or(false, |b| marked >= b) {
+                break;
+            }
+        }
+
+        self.pending_trace.is_empty()
+    }
+
+    /// Sweep each `ArenaSet`, finishing the current collection.
+    fn sweep(&mut self) {
+        // Queue the finalizers of any objects that are about to be swept.
+        let (dead, live) : (Vec<(GcThing, Finalizer)>, Vec<(GcThing, Finalizer)>) =
+            mem::replace(&mut self.finalizers, vec!())
+                .into_iter()
+                .partition(|&(ref thing, _)| !thing.is_marked());
+        self.finalizers = live;
+        self.pending_finalizers.extend(dead.into_iter().map(|(_, f)| f));
+
+        self.sweep_symbol_table();
+
+        self.strings.sweep();
+        self.activations.sweep();
+        self.cons_cells.sweep();
+        self.procedures.sweep();
+        self.vectors.sweep();
+        self.bytevectors.sweep();
+        self.flonums.sweep();
+        // Drop collected native objects right away, so that the resources they
+        // hold are released promptly: this is what closes the descriptors of
+        // unreachable ports and TCP listeners.
+        self.natives.sweep_resetting();
+
+        self.marking = false;
+        self.collections += 1;
+    }
+
+    /// Register a finalizer on the given object, to be run after the object
+    /// becomes unreachable and is collected. The finalizer does not keep the
+    /// object alive, but note that a Scheme procedure finalizer which refers to
+    /// its object does, and so will never run. It is an error to register a
+    /// finalizer on a value that is not garbage collected, such as an integer.
+    ///
+    /// Finalizers run at the end of the garbage collection that collects their
+    /// object, which may be during any allocation. Errors from Scheme
+    /// finalizers have nowhere to go, and are discarded. Finalizers that are
+    /// still registered when the heap is dropped are dropped without being
+    /// run.
+    pub fn register_finalizer(&mut self,
+                              val: &RootedValue,
+                              finalizer: Finalizer) -> Result<(), SchemeError> {
+        match val.to_gc_thing() {
+            Some(thing) => {
+                self.finalizers.push((thing, finalizer));
+                Ok(())
+            },
+            None => Err(E0309.error(format!("Error: cannot register a finalizer on {}, which \
+                                             is not garbage collected", **val))),
+        }
+    }
+
+    /// Run the finalizers of objects collected by the last garbage collection.
+    fn run_finalizers(&mut self) {
+        // A finalizer may allocate and trigger another collection, whose
+        // finalizers are picked up by the loop below.
+        if self.running_finalizers {
+            return;
+        }
+        self.running_finalizers = true;
+
+        while let Some(finalizer) = self.pending_finalizers.pop() {
+            match finalizer {
+                Finalizer::Native(mut f) => (*f)(self),
+                Finalizer::Scheme(procedure) => {
+                    let _ = self.call_nested(&procedure, vec!());
+                },
+            }
+        }
+
+        self.running_finalizers = false;
+    }
+
+    /// Remove the symbols that are about to be swept from the symbol table. If
+    /// a symbol with the same name is needed again, it is simply re-interned;
+    /// since nothing references the old symbol anymore, nothing can tell that
+    /// the new one is not `eq?` to it.
+    fn sweep_symbol_table(&mut self) {
+        let dead : Vec<String> = self.symbol_table.iter()
+            .filter(|&(_, sym)| !sym.is_marked())
+            .map(|(name, _)| name.clone())
             .collect();
+        for name in dead.iter() {
+            self.symbol_table.remove(name);
+        }
+    }
+
+    /// Record a garbage collection pause of the given length.
+    fn record_pause(&mut self, pause: u64) {
+        self.total_pause_ns += pause;
+        self.max_pause_ns = cmp::max(self.max_pause_ns, pause);
+        self.last_pause_ns = pause;
+    }
+
+    /// Explicitly add the given GC thing as a root.
+    pub fn add_root(&mut self, root: GcThing) {
+        for pair in self.roots.iter_mut() {
+            let (ref r, ref mut count) = *pair;
+            if *r == root {
+                *count += 1;
+                return;
+            }
+        }
+        self.roots.push((root, 1));
+
+        if self.marking {
+            self.pending_trace.push(root);
+        }
+    }
+
+    /// Root a GC thing until the innermost `HandleScope` is dropped.
+    fn add_handle(&mut self, root: GcThing) {
+        self.handles.push(root);
+
+        if self.marking {
+            self.pending_trace.push(root);
+        }
+    }
+
+    /// Unroot a GC thing that was explicitly rooted with `add_root`.
+    pub fn drop_root<T: ToGcThing>(&mut self, root: &Rooted<T>) {
+        if let Some(r) = root.to_gc_thing() {
+            self.roots = self.roots.iter().fold(vec!(), |mut v, pair| {
+                let (ref rr, ref count) = *pair;
+                if r == *rr {
+                    if *count == 1 {
+                        return v;
+                    } else {
+                        v.push((*rr, *count - 1));
+                    }
+                } else {
+                    v.push((*rr, *count));
+                }
+
+                return v;
+            });
+        }
+    }
+
+    /// Apply pressure to the GC, and if enough pressure has built up, then
+    /// perform a garbage collection.
+    pub fn increase_gc_pressure(&mut self) {
+        self.allocations += 1;
+
+        if self.gc_stress {
+            // Don't start a nested collection if something allocates while we
+            // are already collecting.
+            if !self.marking {
+                self.collect_garbage();
+            }
+            return;
+        }
+
+        if self.incremental_mark_budget.is_some() {
+            // Keep marking ahead of allocation by doing a slice of work on
+            // every allocation while a collection is in progress.
+            if self.marking || self.is_too_much_pressure() {
+                self.collect_garbage_slice();
+            }
+        } else if self.is_too_much_pressure() {
+            self.collect_garbage();
+        }
+    }
+
+    /// Get a vector of all of the GC roots.
+    fn get_roots(&self) -> Vec<GcThing> {
+        self.get_labeled_roots().into_iter().map(|(root, _)| root).collect()
+    }
+
+    /// Get every root, along with a description of why it is a root.
+    fn get_labeled_roots(&self) -> Vec<(GcThing, &'static str)> {
+        // Note that the symbol table is not a root: it holds its symbols weakly,
+        // so that symbols which are no longer referenced can be reclaimed. See
+        // `Heap::sweep_symbol_table`.
+        let mut roots = vec!((GcThing::from_activation_ptr(self.global_activation),
+                              "global-activation"));
+
+        for pair in self.roots.iter() {
+            let (ref root, _) = *pair;
+            roots.push((*root, "rooted"));
+        }
+
+        for handle in self.handles.iter() {
+            roots.push((*handle, "handle-scope"));
+        }
+
+        for cons in self.locations.keys() {
+            roots.push((GcThing::from_cons_ptr(*cons), "source-location"));
+        }
+
+        for val in self.arguments.iter() {
+            if let Some(thing) = val.to_gc_thing() {
+                roots.push((thing, "argument-stack"));
+            }
+        }
+
+        roots
+    }
+
+    /// A method that should be called on every allocation.
+    fn on_allocation(&mut self)  {
+        self.total_allocations += 1;
+        self.increase_gc_pressure();
+    }
+
+    /// Returns true when we have built up too much GC pressure, and it is time
+    /// to collect garbage. False otherwise.
+    fn is_too_much_pressure(&mut self) -> bool {
+        self.allocations > self.allocations_threshold
+    }
+
+    /// Resets the GC pressure, so that it must build all the way back up to the
+    /// max again before a GC is triggered.
+    #[inline]
+    fn reset_gc_pressure(&mut self) {
+        self.allocations = 0;
+
+        let capacity =
+            (self.cons_cells.capacity * self.cons_cells.arenas.len())
+            + (self.strings.capacity * self.strings.arenas.len())
+            + (self.activations.capacity * self.activations.arenas.len())
+            + (self.procedures.capacity * self.procedures.arenas.len());
+        let by_trigger = (capacity as f64 * self.collection_trigger) as usize;
+
+        let live = self.cons_cells.allocated_count()
+            + self.strings.allocated_count()
+            + self.activations.allocated_count()
+            + self.procedures.allocated_count();
+        let by_growth = (live as f64 * (self.growth_factor - 1.0)) as usize;
+
+        self.allocations_threshold = cmp::max(self.min_collection_threshold,
+                                              cmp::max(by_trigger, by_growth));
+    }
+}
+
+/// ## `Heap` Methods for Statistics
+impl Heap {
+    /// Get a snapshot of allocation counts, live object counts, and garbage
+    /// collection counts and pause times, for monitoring the heap's memory
+    /// behavior.
+    pub fn statistics(&self) -> HeapStatistics {
+        HeapStatistics {
+            allocations: self.total_allocations,
+            live_cons_cells: self.cons_cells.allocated_count(),
+            live_strings: self.strings.allocated_count(),
+            live_activations: self.activations.allocated_count(),
+            live_procedures: self.procedures.allocated_count(),
+            live_vectors: self.vectors.allocated_count(),
+            live_bytevectors: self.bytevectors.allocated_count(),
+            live_flonums: self.flonums.allocated_count(),
+            live_natives: self.natives.allocated_count(),
+            collections: self.collections,
+            total_pause_ns: self.total_pause_ns,
+            max_pause_ns: self.max_pause_ns,
+            last_pause_ns: self.last_pause_ns,
+        }
+    }
+}
+
+/// ## `Heap` Methods for Heap Snapshots
+impl Heap {
+    /// Write a JSON dump of every live object to the given writer. Each object
+    /// is listed with a numeric id, its type, its shallow size in bytes, and the
+    /// ids of the objects it references. Each root is listed with the id of the
+    /// object it roots and why it is a root, so that leaks caused by stray roots
+    /// can be tracked down. For example:
+    ///
+    ///     {"roots": [{"id": 0, "kind": "global-activation"}, ...],
+    ///      "objects": [{"id": 0, "type": "activation", "size": 32,
+    ///                   "references": [1, 2]}, ...]}
+    ///
+    /// Strings and named procedures additionally have a `"label"`.
+    pub fn dump_snapshot<W: Writer>(&self, writer: &mut W) -> IoResult<()> {
+        let mut ids : HashMap<GcThing, usize> = HashMap::new();
+        let mut order = vec!();
+        let mut get_id = |thing: GcThing, order: &mut Vec<GcThing>| {
+            let next = ids.len();
+            *ids.entry(thing).get().unwrap_or_else(|v| {
+                order.push(thing);
+                v.insert(next)
+            })
+        };
+
+        try!(writer.write_str("{\"roots\": ["));
+        for (i, (root, kind)) in self.get_labeled_roots().into_iter().enumerate() {
+            let id = get_id(root, &mut order);
+            try!(writer.write_str(format!("{}{{\"id\": {}, \"kind\": \"{}\"}}",
+                                          if i == 0 { "" } else { ", " },
+                                          id,
+                                          kind).as_slice()));
+        }
+
+        try!(writer.write_str("],\n \"objects\": ["));
+        let mut i = 0;
+        while i < order.len() {
+            let thing = order[i];
+            let references : Vec<String> = thing.trace()
+                .map(|referent| get_id(referent, &mut order).to_string())
+                .collect();
+
+            let (kind, size, label) = match thing {
+                GcThing::Cons(_)         => ("cons", mem::size_of::<Cons>(), None),
+                GcThing::String(ref str) => {
+                    ("string", mem::size_of::<String>() + str.len(), Some((**str).clone()))
+                },
+                GcThing::Activation(_)   => ("activation", mem::size_of::<Activation>(), None),
+                GcThing::Procedure(ref p) => {
+                    ("procedure", mem::size_of::<Procedure>(), p.name.clone())
+                },
+                GcThing::Vector(ref v)   => {
+                    ("vector", mem::size_of::<Vector>() + v.len() * mem::size_of::<u64>(), None)
+                },
+                GcThing::Bytevector(ref b) => {
+                    ("bytevector", mem::size_of::<Vec<u8>>() + b.len(), None)
+                },
+                GcThing::Flonum(ref f)   => {
+                    ("flonum", mem::size_of::<f64>(), Some((**f).to_string()))
+                },
+                GcThing::Native(ref n)   => {
+                    ("native", mem::size_of::<Native>(), Some(n.tag().to_string()))
+                },
+            };
+
+            try!(writer.write_str(format!("{}{{\"id\": {}, \"type\": \"{}\", \
+                                           \"size\": {}, \"references\": [{}]",
+                                          if i == 0 { "" } else { ",\n  " },
+                                          i,
+                                          kind,
+                                          size,
+                                          references.connect(", ")).as_slice()));
+            if let Some(label) = label {
+                try!(writer.write_str(format!(", \"label\": {}",
+                                              json_string(label.as_slice())).as_slice()));
+            }
+            try!(writer.write_str("}"));
+            i += 1;
+        }
+
+        writer.write_str("]}\n")
+    }
+}
+
+/// Quote and escape the given string as a JSON string literal.
+fn json_string(str: &str) -> String {
+    let mut out = String::from_str("\"");
+    for c in str.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(format!("\\u{:04x}", c as u32).as_slice()),
+            c    => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// ## `Heap` Environment Methods
+impl Heap {
+    /// Get the global activation.
+    pub fn global_activation(&mut self) -> RootedActivationPtr {
+        let act = self.global_activation;
+        Rooted::new(self, act)
+    }
+
+    /// Extend the environment with a new lexical block containing the given
+    /// variables and then perform some work before popping the new block.
+    pub fn with_extended_env<T>(&mut self,
+                                names: Vec<SymbolId>,
+                                block: &Fn(&mut Heap) -> T) -> T {
+        self.environment.extend(names);
+        if let Some(ref mut recorder) = self.tool_recorder {
+            recorder.enter_block();
+        }
+        let result = block(self);
+        if let Some(ref mut recorder) = self.tool_recorder {
+            recorder.exit_block();
+        }
+        self.environment.pop();
+        result
+    }
+
+    /// Get the maximum size of global procedures that are inlined at their
+    /// call sites. See `HeapConfig::inline_budget`.
+    #[inline]
+    pub fn inline_budget(&self) -> usize {
+        self.inline_budget
+    }
+
+    /// Set the maximum size of global procedures that are inlined at call
+    /// sites analyzed from now on, or disable inlining with a budget of `0`.
+    pub fn set_inline_budget(&mut self, budget: usize) {
+        self.inline_budget = budget;
+    }
+
+    /// Return true if constant folding is enabled, false otherwise. See
+    /// `HeapConfig::constant_folding`.
+    #[inline]
+    pub fn is_constant_folding(&self) -> bool {
+        self.constant_folding
+    }
+
+    /// Enable or disable constant folding for code analyzed from now on.
+    pub fn set_constant_folding(&mut self, constant_folding: bool) {
+        self.constant_folding = constant_folding;
+    }
+
+    /// Get the maximum number of top-level forms whose meanings are cached.
+    /// See `HeapConfig::analysis_cache_capacity`.
+    pub fn analysis_cache_capacity(&self) -> usize {
+        self.analysis_cache.capacity()
+    }
+
+    /// Set the maximum number of top-level forms whose meanings are cached, or
+    /// disable the cache with a capacity of `0`. This empties the cache.
+    pub fn set_analysis_cache_capacity(&mut self, capacity: usize) {
+        self.analysis_cache.set_capacity(capacity);
+    }
+
+    /// Get the cache of the meanings of top-level forms.
+    #[inline]
+    pub fn analysis_cache(&mut self) -> &mut AnalysisCache {
+        &mut self.analysis_cache
+    }
+
+    /// Return true if closures are flattened, false otherwise. See
+    /// `HeapConfig::flat_closures`.
+    #[inline]
+    pub fn is_flat_closures(&self) -> bool {
+        self.flat_closures
+    }
+
+    /// Enable or disable flat closures for code evaluated from now on. Closures
+    /// that were already created keep their environments.
+    pub fn set_flat_closures(&mut self, flat_closures: bool) {
+        self.flat_closures = flat_closures;
+    }
+}
+
+/// ## `Heap` Methods for Meaning Units
+impl Heap {
+    /// Get the unit that meanings produced by syntactic analysis are currently
+    /// allocated in.
+    #[inline]
+    pub fn meaning_unit(&self) -> &MeaningUnit {
+        &self.meaning_unit
+    }
+
+    /// Make `unit` the unit that meanings are allocated in, and return the
+    /// previous one.
+    pub fn replace_meaning_unit(&mut self, unit: MeaningUnit) -> MeaningUnit {
+        mem::replace(&mut self.meaning_unit, unit)
+    }
+
+    /// Get the number of nested forms that are currently being analyzed. See
+    /// `eval::MAX_ANALYSIS_DEPTH`.
+    pub fn analysis_depth(&self) -> usize {
+        self.analysis_depth
+    }
+
+    /// Note that analysis of a nested form began.
+    pub fn enter_analysis(&mut self) {
+        self.analysis_depth += 1;
+    }
+
+    /// Note that analysis of a nested form finished.
+    pub fn exit_analysis(&mut self) {
+        self.analysis_depth -= 1;
+    }
+}
+
+/// ## `Heap` Methods for Warnings
+impl Heap {
+    /// Return true if syntactic analysis reports warnings, false otherwise.
+    pub fn is_warnings_enabled(&self) -> bool {
+        self.warnings_enabled
+    }
+
+    /// Enable or disable warnings. See `HeapConfig::warnings`.
+    pub fn set_warnings_enabled(&mut self, warnings_enabled: bool) {
+        self.warnings_enabled = warnings_enabled;
+    }
+
+    /// Report a warning about the code at the given location. The warning is
+    /// dropped if `MAX_PENDING_WARNINGS` have not been taken yet.
+    pub fn warn(&mut self, location: Location, message: String) {
+        if self.warnings_enabled && self.warnings.len() < MAX_PENDING_WARNINGS {
+            self.warnings.push(Warning {
+                message: message,
+                location: location,
+            });
+        }
+    }
+
+    /// Note a reference to the j'th global, which was not defined when the
+    /// reference was analyzed. If it is still not defined when the warnings
+    /// are taken, the reference is reported.
+    pub fn note_undefined_reference(&mut self, j: u32, name: SymbolId, location: Location) {
+        if self.warnings_enabled && self.undefined_references.len() < MAX_PENDING_WARNINGS {
+            self.undefined_references.push((j, name, location));
+        }
+    }
+
+    /// Take every warning reported since the last time they were taken, oldest
+    /// first, up to `MAX_PENDING_WARNINGS` of them.
+    ///
+    /// A global is often referenced before it is defined, for example by
+    /// mutually recursive procedures, so references to undefined globals are
+    /// only reported here, if the global has still not been defined.
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        let references = mem::replace(&mut self.undefined_references, vec!());
+        for (j, name, location) in references.into_iter() {
+            if !self.global_activation.is_defined(j) {
+                let message = format!("reference to undefined global `{}`",
+                                      self.environment.name(name));
+                self.warn(location, message);
+            }
+        }
+
+        mem::replace(&mut self.warnings, vec!())
+    }
+}
+
+/// ## `Heap` Methods for the Bytecode VM
+impl Heap {
+    /// Get the current execution mode.
+    #[inline]
+    pub fn execution_mode(&self) -> ExecutionMode {
+        self.execution_mode
+    }
+
+    /// Set the execution mode used for forms evaluated from now on. Meanings
+    /// are still produced by syntactic analysis either way, so this may be
+    /// changed at any time.
+    pub fn set_execution_mode(&mut self, mode: ExecutionMode) {
+        self.execution_mode = mode;
+    }
+
+    /// Get the bytecode for the given procedure body, compiling it the first
+    /// time it is called. Every procedure created from the same `lambda` form
+    /// shares its body, and so shares its bytecode as well.
+    ///
+    /// The code for at most `HeapConfig::compiled_code_capacity` bodies is
+    /// kept, along with the quoted values it refers to; the least recently
+    /// called bodies are recompiled if they are called again.
+    pub fn compiled_code(&mut self, body: &Meaning) -> Rc<Code> {
+        self.compiled.get(body)
+    }
+
+    /// Get the number of procedure bodies whose bytecode is currently cached.
+    pub fn compiled_code_len(&self) -> usize {
+        self.compiled.len()
+    }
+}
+
+/// ## `Heap` Methods for Source Locations
+impl Heap {
+    /// Record that the global variable with the given name was defined at the
+    /// given location.
+    pub fn record_definition(&mut self, name: String, loc: Location) {
+        self.definitions.insert(name, loc);
+    }
+
+    /// Get the name and location of every recorded global definition.
+    pub fn definitions(&self) -> Vec<(String, Location)> {
+        self.definitions.iter().map(|(name, loc)| (name.clone(), loc.clone())).collect()
+    }
+
+    /// Record the documentation string of the lambda defined at the given
+    /// location, or forget it with `None` when the lambda has none.
+    pub fn set_documentation(&mut self, loc: &Location, doc: Option<String>) {
+        let key = (loc.file.clone(), loc.line, loc.column);
+        match doc {
+            Some(doc) => { self.documentation.insert(key, doc); },
+            None      => { self.documentation.remove(&key); },
+        }
+    }
+
+    /// Get the documentation string of the lambda defined at the given
+    /// location, if it has one.
+    pub fn documentation(&self, loc: &Location) -> Option<String> {
+        self.documentation.get(&(loc.file.clone(), loc.line, loc.column)).cloned()
+    }
+
+    /// Record source text that is read incrementally, such as lines typed at
+    /// the REPL, so that errors in it can show the line they occurred on. Text
+    /// recorded for the same source name is appended to what was recorded
+    /// before, and only the last `MAX_RECORDED_SOURCE_LINES` or so lines are
+    /// kept.
+    pub fn record_source(&mut self, file: &str, text: &str) {
+        let source = match self.sources.remove(file) {
+            Some(source) => source.appended(text),
+            None         => RecordedSource::new(text),
+        };
+        self.sources.insert(file.to_string(), source);
+    }
+
+    /// Record the whole text of a source file, replacing any text recorded for
+    /// it before. `read::read_from_file` records every file it reads.
+    pub fn set_source(&mut self, file: &str, text: &str) {
+        self.sources.insert(file.to_string(), RecordedSource {
+            first_line: 1,
+            lines: 0,
+            text: text.to_string(),
+        });
+    }
+
+    /// Get the line of recorded source text the given location is on, if it
+    /// is still recorded.
+    pub fn source_line(&self, loc: &Location) -> Option<String> {
+        self.sources.get(&loc.file).and_then(|source| source.line(loc.line))
+    }
+
+    /// Find where the global variable with the given name was defined, if it
+    /// is defined.
+    pub fn where_defined(&mut self, name: &String) -> Option<DefinitionOrigin> {
+        if let Some(loc) = self.definitions.get(name) {
+            return Some(DefinitionOrigin::Source(loc.clone()));
+        }
+
+        // Primitives are defined before the heap is created, so we don't have
+        // records for them. Recognize them by their values instead.
+        let j = match self.environment.lookup_global_name(name.as_slice()) {
+            Some(j) => j,
+            None    => return None,
+        };
+        let act = self.global_activation;
+        match act.fetch(self, 0, j) {
+            Ok(val) => match *val {
+                Value::Primitive(ref p) => Some(DefinitionOrigin::Primitive(p.name())),
+                _                       => None,
+            },
+            Err(_) => None,
+        }
+    }
+
+    /// Set the recorder that analysis reports bindings and references to, or
+    /// stop reporting them with `None`, and return the previous recorder.
+    pub fn replace_tool_recorder(&mut self,
+                                 recorder: Option<ToolRecorder>) -> Option<ToolRecorder> {
+        mem::replace(&mut self.tool_recorder, recorder)
+    }
+
+    /// Report to the tool recorder, if there is one, that the variable at the
+    /// given coordinates is bound at the given location.
+    pub fn note_binding(&mut self, name: SymbolId, i: u32, j: u32, location: Location) {
+        if let Some(ref mut recorder) = self.tool_recorder {
+            recorder.bind(self.environment.name(name), i, j, location);
+        }
+    }
+
+    /// Report to the tool recorder, if there is one, a reference to the
+    /// variable at the given coordinates.
+    pub fn note_reference(&mut self,
+                          name: SymbolId,
+                          i: u32,
+                          j: u32,
+                          location: Location,
+                          is_assignment: bool) {
+        if let Some(ref mut recorder) = self.tool_recorder {
+            recorder.refer(self.environment.name(name), i, j, location, is_assignment);
+        }
+    }
+
+    /// Register the given pair as having originated from the given location.
+    pub fn enlocate(&mut self, loc: Location, cons: RootedConsPtr) {
+        self.locations.insert(*cons, loc);
+    }
+
+    /// Return true if the given pair has a registered source location, false
+    /// otherwise.
+    pub fn is_located(&self, cons: &RootedConsPtr) -> bool {
+        self.locations.contains_key(&**cons)
+    }
+
+    /// Get the registered source location of the given pair. If the pair was
+    /// not created by the reader, then None is returned.
+    pub fn locate(&self, cons: &RootedConsPtr) -> Location {
+        self.locations.get(&**cons)
+            .map(|loc| loc.clone())
+            .unwrap_or_else(Location::unknown)
+    }
+}
+
+/// ## `Heap` Methods for Lifecycle Hooks
+impl Heap {
+    /// Register a host callback to be invoked on the given lifecycle event.
+    /// This allows embedders to keep external caches synchronized with the
+    /// heap, for example by clearing Rust-side maps after a collection.
+    ///
+    /// Note that `LifecycleEvent::Create` hooks only run if they are registered
+    /// before the heap is first used.
+    pub fn add_lifecycle_hook(&mut self, event: LifecycleEvent, hook: LifecycleHook) {
+        self.hooks.get_mut(event).push(hook);
+    }
+
+    /// Register a host callback to be notified at the start and end of every
+    /// garbage collection, along with the heap's statistics. This is useful for
+    /// logging and metrics, or for coordinating with a host allocator.
+    ///
+    /// For incremental collections, the start is when marking begins and the
+    /// end is when the heap has been swept.
+    pub fn on_gc(&mut self, observer: GcObserver) {
+        let observer = Arc::new(Mutex::new(observer));
+
+        let on_start = observer.clone();
+        self.add_lifecycle_hook(LifecycleEvent::BeforeGc, Box::new(move |heap: &mut Heap| {
+            let stats = heap.statistics();
+            let mut observer = on_start.lock().unwrap();
+            (**observer)(GcPhase::Start, &stats);
+        }));
+
+        let on_end = observer;
+        self.add_lifecycle_hook(LifecycleEvent::AfterGc, Box::new(move |heap: &mut Heap| {
+            let stats = heap.statistics();
+            let mut observer = on_end.lock().unwrap();
+            (**observer)(GcPhase::End, &stats);
+        }));
+    }
+
+    /// Run the `LifecycleEvent::Create` hooks, unless they have already run.
+    ///
+    /// A `Heap` is returned by value from its constructors, so running these
+    /// hooks any earlier would hand them a heap that is about to move. Instead,
+    /// they run the first time the heap evaluates something or triggers any
+    /// other lifecycle event, by which point it is at its final address.
+    pub fn ensure_created(&mut self) {
+        if self.created {
+            return;
+        }
+        self.created = true;
+        self.trigger_lifecycle_event(LifecycleEvent::Create);
+    }
+
+    /// Run every hook registered for the given event. The hooks are taken out
+    /// of the heap while they run, so that they may freely use the heap. An
+    /// event triggered from within a hook does not re-run the hooks for that
+    /// same event.
+    fn trigger_lifecycle_event(&mut self, event: LifecycleEvent) {
+        if event != LifecycleEvent::Create {
+            self.ensure_created();
+        }
+
+        let mut hooks = mem::replace(self.hooks.get_mut(event), vec!());
+        if hooks.is_empty() {
+            return;
+        }
+
+        for hook in hooks.iter_mut() {
+            (**hook)(self);
+        }
+
+        // Keep any hooks that were registered while we were running these.
+        hooks.append(self.hooks.get_mut(event));
+        *self.hooks.get_mut(event) = hooks;
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        self.trigger_lifecycle_event(LifecycleEvent::Destroy);
+    }
+}
+
+/// ## `Heap` Methods for the Argument Stack
+///
+/// Rather than collecting each invocation's arguments into a freshly allocated
+/// `Vec`, the evaluator pushes them onto a stack that is reused across calls.
+/// An invocation notes the height of the stack before evaluating its arguments,
+/// and then pops everything above that height when it applies the procedure.
+impl Heap {
+    /// Get the current height of the argument stack.
+    #[inline]
+    pub fn arguments_height(&self) -> usize {
+        self.arguments.len()
+    }
+
+    /// Push an argument onto the argument stack.
+    #[inline]
+    pub fn push_argument(&mut self, val: &RootedValue) {
+        self.arguments.push(**val);
+    }
+
+    /// Get the arguments above the given height.
+    pub fn arguments(&self, base: usize) -> &[Value] {
+        &self.arguments[base..]
+    }
+
+    /// Pop the arguments above the given height, and return them rooted.
+    pub fn pop_arguments(&mut self, base: usize) -> Vec<RootedValue> {
+        let mut args = Vec::with_capacity(self.arguments.len() - base);
+        for i in base..self.arguments.len() {
+            let val = self.arguments[i];
+            args.push(Rooted::new(self, val));
+        }
+        self.arguments.truncate(base);
+        args
+    }
+
+    /// Discard the arguments above the given height.
+    #[inline]
+    pub fn truncate_arguments(&mut self, base: usize) {
+        self.arguments.truncate(base);
+    }
+}
+
+/// ## `Heap` Methods for the Call Stack
+impl Heap {
+    /// Push a new frame onto the call stack.
+    pub fn push_frame(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    /// Pop the youngest frame off of the call stack.
+    pub fn pop_frame(&mut self) -> Option<Frame> {
+        self.frames.pop()
+    }
+
+    /// Get the number of frames on the call stack.
+    pub fn stack_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Pop frames off of the call stack until it has the given depth.
+    pub fn unwind_to(&mut self, depth: usize) {
+        self.frames.truncate(depth);
+    }
+
+    /// Remove all but the youngest of the frames above the given depth. These
+    /// frames belong to procedures that have since made a tail call, and their
+    /// place on the stack is taken by the callee.
+    pub fn elide_tail_frames(&mut self, depth: usize) {
+        let len = self.frames.len();
+        if len > depth + 1 {
+            let youngest = self.frames.pop().unwrap();
+            self.frames.truncate(depth);
+            self.frames.push(youngest);
+        }
+    }
+
+    /// Remove the frames above the given depth from the call stack, and return
+    /// them oldest first.
+    pub fn split_off_frames(&mut self, depth: usize) -> Vec<Frame> {
+        let frames = self.frames[depth..].to_vec();
+        self.frames.truncate(depth);
+        frames
+    }
+
+    /// Note that evaluation is about to recurse on the host stack, or return an
+    /// error if it has already recursed as deep as it may. Every successful call
+    /// must be paired with a call to `exit_recursion`.
+    pub fn enter_recursion(&mut self) -> Result<(), SchemeError> {
+        if self.recursion_depth >= self.max_recursion_depth {
+            return Err(E0216.error("Error: maximum recursion depth exceeded".to_string()));
+        }
+        self.recursion_depth += 1;
+        Ok(())
+    }
 
-        roots.push(GcThing::from_activation_ptr(self.global_activation));
+    /// Note that a recursive evaluation finished.
+    pub fn exit_recursion(&mut self) {
+        self.recursion_depth -= 1;
+    }
 
-        for pair in self.roots.iter() {
-            let (ref root, _) = *pair;
-            roots.push(*root);
+    /// Get the number of nested evaluations currently recursing on the host
+    /// stack.
+    pub fn recursion_depth(&self) -> usize {
+        self.recursion_depth
+    }
+
+    /// Forget the evaluations nested deeper than the given depth, after
+    /// unwinding past them with a panic.
+    pub fn unwind_recursion_to(&mut self, depth: usize) {
+        self.recursion_depth = depth;
+    }
+
+    /// Get the maximum depth of recursive evaluation. See
+    /// `HeapConfig::max_recursion_depth`.
+    pub fn max_recursion_depth(&self) -> usize {
+        self.max_recursion_depth
+    }
+
+    /// Set the maximum depth of recursive evaluation.
+    pub fn set_max_recursion_depth(&mut self, depth: usize) {
+        self.max_recursion_depth = depth;
+    }
+
+    /// Get a copy of the frames above the given depth, youngest first.
+    pub fn backtrace_since(&self, depth: usize) -> Vec<Frame> {
+        self.frames[depth..].iter().rev().map(|f| f.clone()).collect()
+    }
+
+    /// Record the location of a form that failed to evaluate, unless a location
+    /// has already been recorded for the current error.
+    pub fn note_error_location(&mut self, location: &Location) {
+        if self.error_location.is_none() {
+            self.error_location = Some(location.clone());
         }
+    }
 
-        for cons in self.locations.keys() {
-            roots.push(GcThing::from_cons_ptr(*cons));
+    /// Take the recorded location of the innermost form that failed to
+    /// evaluate, if any.
+    pub fn take_error_location(&mut self) -> Option<Location> {
+        self.error_location.take()
+    }
+
+    /// Record the irritants passed to `error` for the current error, so that
+    /// they can be reported along with it.
+    pub fn note_error_irritants(&mut self, irritants: Vec<RootedValue>) {
+        self.error_irritants = irritants;
+    }
+
+    /// Take the recorded irritants of the current error, if any.
+    pub fn take_error_irritants(&mut self) -> Vec<RootedValue> {
+        mem::replace(&mut self.error_irritants, vec!())
+    }
+}
+
+/// ## `Heap` Methods for Continuation Marks
+impl Heap {
+    /// Attach a mark with the given key and value to the current continuation,
+    /// until it is removed with `Heap::pop_continuation_mark`.
+    pub fn push_continuation_mark(&mut self, key: RootedValue, val: RootedValue) {
+        self.continuation_marks.push((key, val));
+    }
+
+    /// Remove the youngest continuation mark.
+    pub fn pop_continuation_mark(&mut self) {
+        self.continuation_marks.pop();
+    }
+
+    /// Get the keys and values of the continuation marks in effect, youngest
+    /// first. Exception handlers and debuggers can use these to inspect the
+    /// dynamic context of the current evaluation.
+    pub fn continuation_marks(&self) -> Vec<(RootedValue, RootedValue)> {
+        self.continuation_marks.iter().rev().map(|mark| mark.clone()).collect()
+    }
+}
+
+/// ## `Heap` Methods for `dynamic-wind`
+impl Heap {
+    /// Enter a `dynamic-wind` extent, whose given `after` thunk must be called
+    /// when it is left, and return the number of extents in effect before it.
+    pub fn push_wind(&mut self, after: RootedValue) -> usize {
+        self.winds.push(after);
+        self.winds.len() - 1
+    }
+
+    /// Leave every `dynamic-wind` extent entered since the given number of
+    /// extents were in effect, and return their `after` thunks, youngest first,
+    /// which is the order they must be called in.
+    pub fn pop_winds(&mut self, height: usize) -> Vec<RootedValue> {
+        let mut afters = vec!();
+        while self.winds.len() > height {
+            afters.push(self.winds.pop().unwrap());
         }
+        afters
+    }
+}
 
-        roots
+/// ## `Heap` Methods for Debugging
+impl Heap {
+    /// Set the debugger callback that is invoked when a breakpoint is hit, or
+    /// pass `None` to remove it.
+    pub fn set_debugger(&mut self, debugger: Option<Debugger>) {
+        self.debugger = debugger;
     }
 
-    /// A method that should be called on every allocation.
-    fn on_allocation(&mut self)  {
-        self.increase_gc_pressure();
+    /// Break before evaluating any form on the given line of the given file.
+    pub fn set_breakpoint(&mut self, file: &str, line: u64) {
+        self.breakpoints.insert((file.to_string(), line));
     }
 
-    /// Returns true when we have built up too much GC pressure, and it is time
-    /// to collect garbage. False otherwise.
-    fn is_too_much_pressure(&mut self) -> bool {
-        self.allocations > self.allocations_threshold
+    /// Remove the breakpoint on the given line of the given file.
+    pub fn clear_breakpoint(&mut self, file: &str, line: u64) {
+        self.breakpoints.remove(&(file.to_string(), line));
     }
 
-    /// Resets the GC pressure, so that it must build all the way back up to the
-    /// max again before a GC is triggered.
+    /// Return true if the debugger might need to be invoked during evaluation,
+    /// false otherwise.
     #[inline]
-    fn reset_gc_pressure(&mut self) {
-        self.allocations = 0;
-        self.allocations_threshold =
-            ((self.cons_cells.capacity / 2) * self.cons_cells.arenas.len())
-            + ((self.strings.capacity / 2) * self.strings.arenas.len())
-            + ((self.activations.capacity / 2) * self.activations.arenas.len())
-            + ((self.procedures.capacity / 2) * self.procedures.arenas.len());
+    pub fn is_debugging(&self) -> bool {
+        self.debugger.is_some() && (self.stepping || !self.breakpoints.is_empty())
+    }
+
+    /// Invoke the debugger callback if we are stepping or the given meaning is
+    /// at a breakpoint.
+    ///
+    /// Many forms on the same line are evaluated in a row, so after stopping at
+    /// a breakpoint, we do not stop at it again until a form on some other line
+    /// has been evaluated.
+    pub fn debug_hook(&mut self, act: &RootedActivationPtr, meaning: &Meaning) {
+        let here = (meaning.location().file.clone(), meaning.location().line);
+
+        if self.last_stop.as_ref().map_or(false, |stop| *stop != here) {
+            self.last_stop = None;
+        }
+
+        let at_breakpoint = self.last_stop.is_none() && self.breakpoints.contains(&here);
+        if !self.stepping && !at_breakpoint {
+            return;
+        }
+
+        let mut debugger = match self.debugger.take() {
+            Some(d) => d,
+            // We are already inside the debugger callback.
+            None    => return,
+        };
+        let action = (*debugger)(self, act, meaning);
+        if self.debugger.is_none() {
+            self.debugger = Some(debugger);
+        }
+
+        self.stepping = action == DebugAction::Step;
+        self.last_stop = Some(here);
     }
 }
 
-/// ## `Heap` Environment Methods
+/// ## `Heap` Methods for Tracing
 impl Heap {
-    /// Get the global activation.
-    pub fn global_activation(&mut self) -> RootedActivationPtr {
-        let act = self.global_activation;
-        Rooted::new(self, act)
+    /// Start tracing calls to the given procedure.
+    pub fn trace(&mut self, procedure: &RootedValue) {
+        if !self.is_traced(&**procedure) {
+            self.traced.push(procedure.clone());
+        }
     }
 
-    /// Extend the environment with a new lexical block containing the given
-    /// variables and then perform some work before popping the new block.
-    pub fn with_extended_env<T>(&mut self,
-                                names: Vec<String>,
-                                block: &Fn(&mut Heap) -> T) -> T {
-        self.environment.extend(names);
-        let result = block(self);
-        self.environment.pop();
+    /// Stop tracing calls to the given procedure.
+    pub fn untrace(&mut self, procedure: &RootedValue) {
+        self.traced.retain(|p| **p != **procedure);
+    }
+
+    /// Return true if calls to the given procedure are being traced, false
+    /// otherwise.
+    #[inline]
+    pub fn is_traced(&self, procedure: &Value) -> bool {
+        !self.traced.is_empty() && self.traced.iter().any(|p| **p == *procedure)
+    }
+
+    /// Get the number of traced calls that are currently being evaluated.
+    pub fn trace_depth(&self) -> usize {
+        self.trace_depth
+    }
+
+    /// Note that a traced call is being evaluated.
+    pub fn enter_trace(&mut self) {
+        self.trace_depth += 1;
+    }
+
+    /// Note that a traced call has finished being evaluated.
+    pub fn exit_trace(&mut self) {
+        self.trace_depth -= 1;
+    }
+}
+
+/// ## `Heap` Methods for Profiling
+impl Heap {
+    /// Start counting invocations of and time spent in each procedure. Any
+    /// previously collected profile is discarded.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(HashMap::new());
+    }
+
+    /// Stop profiling and discard the collected profile.
+    pub fn disable_profiling(&mut self) {
+        self.profile = None;
+    }
+
+    /// Return true if profiling is enabled, false otherwise.
+    #[inline]
+    pub fn is_profiling(&self) -> bool {
+        self.profile.is_some()
+    }
+
+    /// Record one invocation of the procedure with the given name and
+    /// definition location, which took the given number of nanoseconds.
+    pub fn record_profile_sample(&mut self,
+                                 name: String,
+                                 location: Option<Location>,
+                                 nanoseconds: u64) {
+        if let Some(ref mut profile) = self.profile {
+            // Procedures are keyed by where they were defined, since many
+            // procedures may share a name (or have none at all).
+            let key = match location {
+                Some(ref loc) => format!("{}", loc),
+                None          => name.clone(),
+            };
+            let entry = profile.entry(key).get().unwrap_or_else(|vacant| {
+                vacant.insert(ProfileEntry {
+                    name: name,
+                    location: location,
+                    calls: 0,
+                    nanoseconds: 0,
+                })
+            });
+            entry.calls += 1;
+            entry.nanoseconds += nanoseconds;
+        }
+    }
+
+    /// Get the collected profile, sorted by time spent in each procedure, most
+    /// time first.
+    pub fn profile_report(&self) -> Vec<ProfileEntry> {
+        let mut entries : Vec<ProfileEntry> = match self.profile {
+            Some(ref profile) => profile.values().cloned().collect(),
+            None              => vec!(),
+        };
+        entries.sort_by(|a, b| b.nanoseconds.cmp(&a.nanoseconds));
+        entries
+    }
+}
+
+/// ## `Heap` Methods for Foreign Objects
+impl Heap {
+    /// Hand ownership of the given native object to the heap, and return a
+    /// Scheme value that is a handle to it. The tag names the object's type in
+    /// error messages.
+    pub fn new_foreign(&mut self, tag: &'static str, object: Box<Any + Send>) -> RootedValue {
+        let handle = ForeignHandle(self.foreign_objects.len());
+        self.foreign_objects.push(ForeignObject {
+            tag: tag,
+            object: Some(object),
+        });
+        Rooted::new(self, Value::Foreign(handle))
+    }
+
+    /// Get the tag of the foreign object with the given handle.
+    pub fn foreign_tag(&self, handle: ForeignHandle) -> &'static str {
+        self.foreign_objects[handle.0].tag
+    }
+
+    /// Return true if the foreign object with the given handle has been
+    /// released, false otherwise.
+    pub fn is_foreign_released(&self, handle: ForeignHandle) -> bool {
+        self.foreign_objects[handle.0].object.is_none()
+    }
+
+    /// Get a reference to the foreign object with the given handle. It is an
+    /// error if the object has been released or is not a `T`.
+    pub fn foreign_ref<T: Any>(&self, handle: ForeignHandle) -> Result<&T, SchemeError> {
+        let entry = &self.foreign_objects[handle.0];
+        match entry.object {
+            Some(ref object) => object.downcast_ref::<T>().ok_or_else(|| {
+                E0307.error(format!("Error: foreign object #<foreign {}> has the wrong type, \
+                                     found {}", handle.0, entry.tag))
+            }),
+            None => Err(E0306.error(format!("Error: use of released foreign object \
+                                             #<foreign {}> ({})", handle.0, entry.tag))),
+        }
+    }
+
+    /// Get a mutable reference to the foreign object with the given handle. It
+    /// is an error if the object has been released or is not a `T`.
+    pub fn foreign_mut<T: Any>(&mut self,
+                               handle: ForeignHandle) -> Result<&mut T, SchemeError> {
+        let entry = &mut self.foreign_objects[handle.0];
+        let tag = entry.tag;
+        match entry.object {
+            Some(ref mut object) => object.downcast_mut::<T>().ok_or_else(|| {
+                E0307.error(format!("Error: foreign object #<foreign {}> has the wrong type, \
+                                     found {}", handle.0, tag))
+            }),
+            None => Err(E0306.error(format!("Error: use of released foreign object \
+                                             #<foreign {}> ({})", handle.0, tag))),
+        }
+    }
+
+    /// Release the foreign object with the given handle, returning ownership
+    /// of the native object to the caller. It is an error to release an object
+    /// twice.
+    pub fn release_foreign(&mut self,
+                           handle: ForeignHandle) -> Result<Box<Any + Send>, SchemeError> {
+        let entry = &mut self.foreign_objects[handle.0];
+        entry.object.take().ok_or_else(|| {
+            E0308.error(format!("Error: foreign object #<foreign {}> ({}) was already released",
+                                handle.0, entry.tag))
+        })
+    }
+}
+
+/// ## `Heap` Methods for Native Objects
+impl Heap {
+    /// Hand ownership of the given native object to the heap, and return a
+    /// garbage collected Scheme value wrapping it. The tag names the object's
+    /// type when it is printed and in error messages.
+    pub fn new_native(&mut self, tag: &'static str, object: Box<Any + Send>) -> RootedValue {
+        let mut native = self.allocate_native();
+        native.tag = tag;
+        native.object = Some(object);
+        Rooted::new(self, Value::Native(*native))
+    }
+}
+
+/// ## `Heap` Methods for Closure Primitives
+impl Heap {
+    /// Return true if this heap's builtin primitives were granted the given
+    /// capability, false otherwise. Primitives that can do more with a
+    /// capability, such as `format` writing to standard output, check this.
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.primitive_set.has_capability(capability)
+    }
+
+    /// Define a global primitive procedure with the given name that is
+    /// implemented by the given closure, and return the primitive. The heap
+    /// owns the closure, which may capture state such as a database handle or
+    /// a channel.
+    pub fn register_primitive<F>(&mut self, name: &'static str, function: F) -> RootedValue
+        where F: Fn(&mut Heap, &[RootedValue]) -> SchemeResult + Send + 'static
+    {
+        let index = self.closure_primitives.len();
+        self.closure_primitives.push(Rc::new(Box::new(function) as ClosurePrimitive));
+        let primitive = Value::new_closure_primitive(&mut self.primitive_table, name, index);
+
+        let id = self.environment.intern(name);
+        let j = match self.environment.lookup_global(id) {
+            Some(j) => j,
+            None    => self.environment.define_global(id).1,
+        };
+        let mut act = self.global_activation;
+        act.define(j, primitive);
+        self.primitive_registry.push(PrimitiveInfo {
+            name: name,
+            module: EMBEDDER_MODULE,
+            signature: None,
+            documentation: None,
+        });
+        Rooted::new(self, primitive)
+    }
+
+    /// Get every primitive installed in the global environment, in the order
+    /// they were installed. This includes primitives that were installed and
+    /// later shadowed by a global definition.
+    pub fn primitive_registry(&self) -> &[PrimitiveInfo] {
+        self.primitive_registry.as_slice()
+    }
+
+    /// Get what is known about the installed primitive with the given name. If
+    /// several were installed under that name, this is the latest of them.
+    pub fn primitive_info(&self, name: &str) -> Option<&PrimitiveInfo> {
+        self.primitive_registry.iter().rev().find(|info| info.name == name)
+    }
+
+    /// Call the closure primitive with the given index in this heap's table of
+    /// closure primitives.
+    pub fn call_closure_primitive(&mut self, index: usize,
+                                  args: Vec<RootedValue>) -> TrampolineResult {
+        let function = match self.closure_primitives.get(index) {
+            Some(function) => function.clone(),
+            None           => return Err(SchemeError::new(format!(
+                "Error: closure primitive {} was not registered with this heap", index))),
+        };
+        let result = try!((**function)(self, args.as_slice()));
+        Ok(Trampoline::Value(result))
+    }
+
+    /// Return true if panics within primitives are caught and converted into
+    /// errors, false otherwise. See `HeapConfig::catch_panics`.
+    #[inline]
+    pub fn is_catching_panics(&self) -> bool {
+        self.catch_panics
+    }
+
+    /// Enable or disable catching panics within primitives.
+    pub fn set_catch_panics(&mut self, catch_panics: bool) {
+        self.catch_panics = catch_panics;
+    }
+}
+
+/// ## `Heap` Methods for Calling Procedures
+impl Heap {
+    /// Call the given procedure or primitive with the given arguments, and
+    /// return its result. This lets the host invoke callbacks defined in Scheme
+    /// without building call forms by hand.
+    pub fn call(&mut self, procedure: &RootedValue, args: &[RootedValue]) -> SchemeResult {
+        let depth = self.stack_depth();
+        self.take_error_location();
+        self.take_error_irritants();
+        let result = apply_invocation(self, procedure, args.to_vec())
+            .and_then(|trampoline| trampoline.run(self));
+        if result.is_err() {
+            self.unwind_to(depth);
+        }
+        result
+    }
+
+    /// Call the given procedure or primitive with the given arguments from
+    /// within some other evaluation, such as a finalizer run by a collection
+    /// that an allocation triggered. If the call fails, the call stack, and the
+    /// location and irritants recorded for any error already being reported,
+    /// are restored to what they were before the call, so that the caller can
+    /// ignore the error without disturbing the evaluation it interrupted.
+    pub fn call_nested(&mut self,
+                       procedure: &RootedValue,
+                       args: Vec<RootedValue>) -> SchemeResult {
+        let depth = self.stack_depth();
+        let location = self.take_error_location();
+        let irritants = self.take_error_irritants();
+        let result = apply_invocation(self, procedure, args)
+            .and_then(|trampoline| trampoline.run(self));
+        if result.is_err() {
+            self.unwind_to(depth);
+        }
+        self.error_location = location;
+        self.error_irritants = irritants;
         result
     }
+
+    /// Read every form in the given source string and evaluate them in the
+    /// global environment, returning the value of the last form. If the string
+    /// has no forms, the empty list is returned.
+    pub fn eval_str(&mut self, source: &str) -> Result<RootedValue, String> {
+        let reader = read_from_str(source, self, "<string>");
+        let mut result = Rooted::new(self, Value::EmptyList);
+        for (location, read_result) in reader {
+            let form = try!(read_result);
+            result.emplace(*try!(evaluate(self, &form, location)));
+        }
+        Ok(result)
+    }
 }
 
-/// ## `Heap` Methods for Source Locations
+/// ## `Heap` Methods for Images
 impl Heap {
-    /// Register the given pair as having originated from the given location.
-    pub fn enlocate(&mut self, loc: Location, cons: RootedConsPtr) {
-        self.locations.insert(*cons, loc);
+    /// Save the global environment, and everything reachable from it, as a heap
+    /// image written to the given writer. See the `image` module.
+    pub fn save_image<W: Writer>(&mut self, writer: &mut W) -> Result<(), SchemeError> {
+        image::save(self, writer)
+    }
+
+    /// Load the heap image read from the given reader, replacing this heap's
+    /// global environment. This heap should be freshly created, with the same
+    /// primitives as the heap the image was saved from.
+    pub fn load_image<R: Reader>(&mut self, reader: R) -> Result<(), SchemeError> {
+        image::load(self, reader)
+    }
+
+    /// Read and analyze the file at the given path into a compiled script,
+    /// which can be saved and later run without reading or analyzing the file
+    /// again. See `image::CompiledScript`.
+    pub fn compile_file(&mut self, path: &str) -> Result<CompiledScript, SchemeError> {
+        image::compile_file(self, path)
+    }
+
+    /// Run the given compiled script and return the value of its last form.
+    pub fn run_compiled(&mut self, script: &CompiledScript) -> SchemeResult {
+        image::run_compiled(self, script)
+    }
+}
+
+/// ## `Heap` Methods for the Command Line
+impl Heap {
+    /// Set the command line arguments returned by `(command-line)`. By
+    /// convention, the first element is the name of the script or program
+    /// being run.
+    pub fn set_command_line(&mut self, args: Vec<String>) {
+        self.command_line = args;
+    }
+
+    /// Get the command line arguments.
+    pub fn command_line(&self) -> &[String] {
+        self.command_line.as_slice()
+    }
+}
+
+/// ## `Heap` Methods for Arithmetic
+impl Heap {
+    /// Get how integer arithmetic on this heap behaves on overflow.
+    pub fn arithmetic_mode(&self) -> ArithmeticMode {
+        self.arithmetic_mode
+    }
+
+    /// Set how integer arithmetic on this heap behaves on overflow.
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic_mode = mode;
+    }
+}
+
+/// ## `Heap` Methods for Fuel
+impl Heap {
+    /// Evaluate the given form in the global environment with the given amount
+    /// of fuel. Every bytecode instruction costs one unit of fuel, and if it
+    /// runs out before the evaluation finishes, the evaluation is suspended so
+    /// that it can be continued later with `Heap::resume`. This way, a hostile
+    /// or buggy script can't hang the host.
+    ///
+    /// The form is run on the bytecode VM whatever the execution mode, because
+    /// only the VM can be suspended. The VM still delegates some calls to the
+    /// interpreter, such as calls through `apply`. Each trampoline step there
+    /// costs fuel too, but running out of fuel during one of them is an error.
+    pub fn evaluate_with_fuel(&mut self,
+                              form: &RootedValue,
+                              fuel: u64) -> Result<Evaluation, SchemeError> {
+        try!(ensure_prelude(self));
+        let previous = mem::replace(&mut self.fuel, Some(fuel));
+        let result = evaluate_with_fuel(self, form, Location::unknown());
+        self.fuel = previous;
+        result
+    }
+
+    /// Continue a suspended evaluation with the given amount of fuel.
+    pub fn resume(&mut self,
+                  suspended: Suspended,
+                  fuel: u64) -> Result<Evaluation, SchemeError> {
+        let previous = mem::replace(&mut self.fuel, Some(fuel));
+        let result = vm::resume(self, suspended);
+        self.fuel = previous;
+        result
+    }
+
+    /// Spend one unit of fuel. Return false if there is none left, and true
+    /// otherwise, including when fuel is unlimited.
+    #[inline]
+    pub fn consume_fuel(&mut self) -> bool {
+        match self.fuel {
+            None    => true,
+            Some(0) => false,
+            Some(n) => {
+                self.fuel = Some(n - 1);
+                true
+            },
+        }
+    }
+}
+
+/// ## `Heap` Methods for First-Class Environments
+impl Heap {
+    /// Get this heap's table of reified environments. See `environment::reify`.
+    pub fn reified_environments(&mut self) -> &mut ReifiedEnvironments {
+        &mut self.reified_environments
+    }
+}
+
+/// ## `Heap` Methods for Generators
+impl Heap {
+    /// Get this heap's table of generator states. See the `generator` module.
+    pub fn generators(&mut self) -> &mut Generators {
+        &mut self.generators
+    }
+
+    /// Note that a generator's body is about to run.
+    pub fn enter_generator(&mut self) {
+        self.running_generators += 1;
+    }
+
+    /// Note that a generator's body has yielded, returned, or failed.
+    pub fn leave_generator(&mut self) {
+        self.running_generators -= 1;
+    }
+
+    /// Hand the given value to the running generator, and take away the heap's
+    /// fuel so that the VM suspends the generator's body before its next
+    /// instruction. It is an error if no generator is running.
+    pub fn yield_value(&mut self, val: &RootedValue) -> Result<(), SchemeError> {
+        if self.running_generators == 0 {
+            return Err(E0214.error("Error: `yield` called outside of a generator".to_string()));
+        }
+        if self.yielded.is_some() {
+            return Err(E0214.error("Error: `yield` called from within a call made by a \
+                                    primitive".to_string()));
+        }
+        let fuel = mem::replace(&mut self.fuel, Some(0));
+        self.yielded = Some((val.clone(), fuel));
+        Ok(())
+    }
+
+    /// Take the value yielded since the last time this method was called, if
+    /// any, and give back the fuel that yielding took away.
+    pub fn take_yielded(&mut self) -> Option<RootedValue> {
+        self.yielded.take().map(|(val, fuel)| {
+            self.fuel = fuel;
+            val
+        })
+    }
+}
+
+/// ## `Heap` Methods for Interruption
+impl Heap {
+    /// Get a handle that can interrupt this heap's evaluation from another
+    /// thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.interrupted.clone())
+    }
+
+    /// Return true if evaluation has been interrupted since the last time this
+    /// method was called, false otherwise.
+    #[inline]
+    pub fn take_interrupt(&self) -> bool {
+        // Check with a cheap load first, since this is called at every step.
+        self.interrupted.load(Ordering::Relaxed)
+            && self.interrupted.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// ## `Heap` Methods for Exiting
+impl Heap {
+    /// Record that Scheme code asked to end the program, and return the error
+    /// that unwinds the current evaluation.
+    pub fn request_exit(&mut self, request: ExitRequest) -> SchemeError {
+        self.exit_request = Some(request);
+        E0313.error(format!("Exit: the program exited with status {}", request.status))
+    }
+
+    /// Take the request to end the program made since the last time this
+    /// method was called, if any.
+    pub fn take_exit_request(&mut self) -> Option<ExitRequest> {
+        self.exit_request.take()
+    }
+}
+
+/// ## `Heap` Methods for Deterministic Replay
+impl Heap {
+    /// Get the seed for this heap's pseudo-random number generator.
+    pub fn random_seed(&self) -> u64 {
+        self.random_seed
+    }
+
+    /// Set the seed for this heap's pseudo-random number generator, which
+    /// starts its sequence over.
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.random_seed = seed;
+        self.random = RandomSource::new(seed);
+    }
+
+    /// Get this heap's default random source, which `random-integer` uses when
+    /// it is not given one.
+    pub fn random_source(&mut self) -> &mut RandomSource {
+        &mut self.random
+    }
+
+    /// Stub the clock to always return the given time (in seconds since the
+    /// epoch), or pass `None` to go back to using the real clock.
+    pub fn stub_clock(&mut self, time: Option<i64>) {
+        self.clock_stub = time;
+    }
+
+    /// Get the current time in seconds since the epoch, respecting any stubbed
+    /// time.
+    pub fn current_time(&self) -> i64 {
+        self.clock_stub.unwrap_or_else(|| time::get_time().sec)
+    }
+
+    /// Get the current time in jiffies since an arbitrary epoch, respecting any
+    /// stubbed time. There are `JIFFIES_PER_SECOND` jiffies in a second.
+    pub fn current_jiffy(&self) -> i64 {
+        match self.clock_stub {
+            Some(secs) => secs * JIFFIES_PER_SECOND,
+            None       => (time::precise_time_ns() / 1000) as i64,
+        }
+    }
+}
+
+/// ## `Heap` Methods for Macros
+impl Heap {
+    /// Define a macro with the given name and transformer procedure.
+    pub fn define_macro(&mut self, name: String, transformer: RootedValue) {
+        self.macros.insert(name, transformer);
+        self.analysis_cache.invalidate();
+    }
+
+    /// Forget the macro with the given name, if there is one.
+    pub fn remove_macro(&mut self, name: &String) {
+        if self.macros.remove(name).is_some() {
+            self.analysis_cache.invalidate();
+        }
+    }
+
+    /// Get the transformer procedure for the macro with the given name, if
+    /// there is one.
+    pub fn get_macro(&self, name: &String) -> Option<RootedValue> {
+        self.macros.get(name).map(|t| t.clone())
+    }
+
+    /// Get every macro's name and transformer procedure.
+    pub fn macros(&self) -> Vec<(String, RootedValue)> {
+        self.macros.iter().map(|(name, t)| (name.clone(), t.clone())).collect()
+    }
+
+    /// Mark the prelude as loaded, and return true if it was not already.
+    pub fn mark_prelude_loaded(&mut self) -> bool {
+        let was_loaded = self.prelude_loaded;
+        self.prelude_loaded = true;
+        !was_loaded
+    }
+
+    /// Return true if the prelude has been loaded, false otherwise.
+    pub fn is_prelude_loaded(&self) -> bool {
+        self.prelude_loaded
+    }
+}
+
+/// ## `Heap` Methods for REPL Configuration
+impl Heap {
+    /// Get how the REPL should render its prompt.
+    pub fn repl_prompt(&self) -> &ReplPrompt {
+        &self.repl_prompt
+    }
+
+    /// Set how the REPL should render its prompt.
+    pub fn set_repl_prompt(&mut self, prompt: ReplPrompt) {
+        self.repl_prompt = prompt;
+    }
+
+    /// Get the banner printed when the REPL starts.
+    pub fn repl_banner(&self) -> &str {
+        self.repl_banner.as_slice()
     }
 
-    /// Get the registered source location of the given pair. If the pair was
-    /// not created by the reader, then None is returned.
-    pub fn locate(&self, cons: &RootedConsPtr) -> Location {
-        self.locations.get(&**cons)
-            .map(|loc| loc.clone())
-            .unwrap_or_else(Location::unknown)
+    /// Set the banner printed when the REPL starts.
+    pub fn set_repl_banner(&mut self, banner: String) {
+        self.repl_banner = banner;
     }
 }
 
@@ -776,6 +3619,10 @@ impl Heap {
         self.get_or_create_symbol("define".to_string())
     }
 
+    pub fn define_macro_symbol(&mut self) -> RootedValue {
+        self.get_or_create_symbol("define-macro".to_string())
+    }
+
     pub fn set_bang_symbol(&mut self) -> RootedValue {
         self.get_or_create_symbol("set!".to_string())
     }
@@ -790,8 +3637,35 @@ impl Heap {
 
     pub fn eof_symbol(&mut self) -> RootedValue {
         // Per R4RS, the EOF object must be something that is impossible to
-        // read. We fulfill that contract by having spaces in a symbol.
-        self.get_or_create_symbol("< END OF FILE >".to_string())
+        // read. We fulfill that contract with a symbol that is not in the
+        // symbol table, which no symbol that is read can be `eq?` to.
+        if let Some(ref eof) = self.eof_object {
+            return eof.clone();
+        }
+
+        let mut str = self.allocate_string();
+        str.clear();
+        str.push_str("< END OF FILE >");
+        let eof = Value::new_symbol(self, str);
+        self.eof_object = Some(eof.clone());
+        eof
+    }
+
+    /// Get the default object, which the parameters of optional and keyword
+    /// arguments that were not passed are bound to until they are given their
+    /// default values. Like the EOF object, it is an uninterned symbol that no
+    /// symbol that is read can be `eq?` to.
+    pub fn default_object(&mut self) -> RootedValue {
+        if let Some(ref default) = self.default_object {
+            return default.clone();
+        }
+
+        let mut str = self.allocate_string();
+        str.clear();
+        str.push_str("< DEFAULT OBJECT >");
+        let default = Value::new_symbol(self, str);
+        self.default_object = Some(default.clone());
+        default
     }
 }
 
@@ -834,6 +3708,10 @@ pub enum GcThing {
     String(StringPtr),
     Activation(ActivationPtr),
     Procedure(ProcedurePtr),
+    Vector(VectorPtr),
+    Bytevector(BytevectorPtr),
+    Flonum(FlonumPtr),
+    Native(NativePtr),
 }
 
 /// ## `GcThing` Constructors
@@ -857,6 +3735,26 @@ impl GcThing {
     pub fn from_activation_ptr(act: ActivationPtr) -> GcThing {
         GcThing::Activation(act)
     }
+
+    /// Create a `GcThing` from a `VectorPtr`.
+    pub fn from_vector_ptr(vector: VectorPtr) -> GcThing {
+        GcThing::Vector(vector)
+    }
+
+    /// Create a `GcThing` from a `BytevectorPtr`.
+    pub fn from_bytevector_ptr(bytes: BytevectorPtr) -> GcThing {
+        GcThing::Bytevector(bytes)
+    }
+
+    /// Create a `GcThing` from a `FlonumPtr`.
+    pub fn from_flonum_ptr(flonum: FlonumPtr) -> GcThing {
+        GcThing::Flonum(flonum)
+    }
+
+    /// Create a `GcThing` from a `NativePtr`.
+    pub fn from_native_ptr(native: NativePtr) -> GcThing {
+        GcThing::Native(native)
+    }
 }
 
 impl GcThing {
@@ -868,6 +3766,10 @@ impl GcThing {
             GcThing::String(ref p) => p.mark(),
             GcThing::Activation(ref p) => p.mark(),
             GcThing::Procedure(ref p) => p.mark(),
+            GcThing::Vector(ref p) => p.mark(),
+            GcThing::Bytevector(ref p) => p.mark(),
+            GcThing::Flonum(ref p) => p.mark(),
+            GcThing::Native(ref p) => p.mark(),
         }
     }
 
@@ -879,6 +3781,10 @@ impl GcThing {
             GcThing::String(ref p) => p.is_marked(),
             GcThing::Activation(ref p) => p.is_marked(),
             GcThing::Procedure(ref p) => p.is_marked(),
+            GcThing::Vector(ref p) => p.is_marked(),
+            GcThing::Bytevector(ref p) => p.is_marked(),
+            GcThing::Flonum(ref p) => p.is_marked(),
+            GcThing::Native(ref p) => p.is_marked(),
         }
     }
 }
@@ -889,10 +3795,64 @@ impl Trace for GcThing {
             GcThing::Cons(cons)      => cons.trace(),
             GcThing::Activation(act) => act.trace(),
             GcThing::Procedure(p)    => p.trace(),
-            // Strings don't hold any strong references to other `GcThing`s.
+            GcThing::Vector(v)       => v.trace(),
+            // Strings, bytevectors, flonums, and native objects don't hold any
+            // strong references to other `GcThing`s.
             GcThing::String(_)       => vec!().into_iter(),
+            GcThing::Bytevector(_)   => vec!().into_iter(),
+            GcThing::Flonum(_)       => vec!().into_iter(),
+            GcThing::Native(_)       => vec!().into_iter(),
+        }
+    }
+}
+
+#[test]
+fn test_heap_handle_scope() {
+    let heap = &mut Heap::new();
+    let before = heap.statistics().live_strings;
+
+    {
+        let scope = HandleScope::new(heap);
+        let str = Value::new_string(heap, "handled".to_string());
+        let handle = scope.handle(*str);
+        drop(str);
+
+        {
+            let inner = HandleScope::new(heap);
+            let _unused = inner.handle(*Value::new_string(heap, "inner".to_string()));
+        }
+
+        heap.collect_garbage();
+        assert_eq!(heap.statistics().live_strings, before + 1);
+        match *handle {
+            Value::String(str) => assert_eq!(*str, "handled"),
+            _                  => panic!("Should still have the string"),
         }
     }
+
+    heap.collect_garbage();
+    assert_eq!(heap.statistics().live_strings, before);
+}
+
+#[test]
+fn test_heap_dump_snapshot() {
+    use std::old_io::{MemWriter};
+
+    let heap = &mut Heap::new();
+    let str = Value::new_string(heap, "leaky \"string\"".to_string());
+    let mut cons = heap.allocate_cons();
+    cons.set_car(&str);
+
+    let mut writer = MemWriter::new();
+    heap.dump_snapshot(&mut writer).ok().expect("Should be able to dump a snapshot.");
+    let snapshot = String::from_utf8(writer.into_inner())
+        .ok()
+        .expect("The snapshot should be UTF-8.");
+
+    assert!(snapshot.starts_with("{\"roots\": [{\"id\": 0, \"kind\": \"global-activation\"}"));
+    assert!(snapshot.contains("\"kind\": \"rooted\""));
+    assert!(snapshot.contains("\"type\": \"activation\""));
+    assert!(snapshot.contains("\"label\": \"leaky \\\"string\\\"\""));
 }
 
 #[test]
@@ -905,3 +3865,655 @@ fn test_heap_allocate_tons() {
         .expect("Should be able to eval a file.");
     assert!(true, "Should have successfully run the program and allocated many cons cells");
 }
+
+#[test]
+fn test_heap_statistics() {
+    let heap = &mut Heap::new();
+    let before = heap.statistics();
+
+    {
+        let _cons = heap.allocate_cons();
+        let _string = heap.allocate_string();
+        let stats = heap.statistics();
+        assert_eq!(stats.allocations, before.allocations + 2);
+        assert_eq!(stats.live_cons_cells, before.live_cons_cells + 1);
+        assert_eq!(stats.live_strings, before.live_strings + 1);
+    }
+
+    heap.collect_garbage();
+    let after = heap.statistics();
+    assert_eq!(after.collections, before.collections + 1);
+    assert!(after.max_pause_ns >= after.last_pause_ns);
+    assert!(after.total_pause_ns >= after.last_pause_ns);
+}
+
+#[test]
+fn test_heap_with_config() {
+    let heap = &mut Heap::with_config(HeapConfig {
+        cons_capacity: 16,
+        min_collection_threshold: 1 << 20,
+        .. Default::default()
+    });
+
+    let before = heap.statistics().collections;
+    for _ in 0..100 {
+        heap.allocate_cons();
+    }
+    assert_eq!(heap.statistics().collections, before);
+    assert_eq!(heap.cons_cells.arenas.len(), 7);
+}
+
+#[test]
+fn test_heap_config_zero_capacity() {
+    let config = HeapConfig {
+        vectors_capacity: 0,
+        .. Default::default()
+    };
+    assert_eq!(config.validate(),
+               Err("HeapConfig: `vectors_capacity` must be greater than 0".to_string()));
+    assert!(Heap::try_with_config(config).is_err());
+    assert!(Heap::try_with_config(Default::default()).is_ok());
+}
+
+#[test]
+fn test_heap_incremental_collection() {
+    use eval::evaluate_file;
+
+    let heap = &mut Heap::with_config(HeapConfig {
+        incremental_mark_budget: Some(8),
+        .. Default::default()
+    });
+
+    let before = heap.statistics().collections;
+    evaluate_file(heap, "./tests/test_heap_allocate_tons.scm")
+        .ok()
+        .expect("Should be able to eval a file.");
+    assert!(heap.statistics().collections > before);
+}
+
+#[test]
+fn test_heap_incremental_allocate_black() {
+    let heap = &mut Heap::with_config(HeapConfig {
+        incremental_mark_budget: Some(1),
+        // Only collect when we explicitly ask to.
+        min_collection_threshold: 1 << 30,
+        .. Default::default()
+    });
+
+    let one = Rooted::new(heap, Value::new_integer(1));
+    let pair = Value::new_pair(heap, &one, &one);
+
+    assert!(!heap.collect_garbage_slice());
+    assert!(heap.is_marking());
+
+    // This pair is allocated mid-collection, and is only reachable from a root
+    // that was created after the roots were snapshotted.
+    let two = Rooted::new(heap, Value::new_integer(2));
+    let young = Value::new_pair(heap, &two, &pair);
+    while !heap.collect_garbage_slice() { }
+
+    // If either pair was swept, these allocations would reuse and clobber it.
+    let three = Rooted::new(heap, Value::new_integer(3));
+    let _clobbers : Vec<RootedValue> = (0..DEFAULT_CONS_CAPACITY)
+        .map(|_| Value::new_pair(heap, &three, &three))
+        .collect();
+    assert_eq!(format!("{}", *young), "(2 1 . 1)");
+}
+
+#[test]
+fn test_heap_foreign_lifetime() {
+    let heap = &mut Heap::new();
+    let val = heap.new_foreign("string", Box::new("native".to_string()));
+    let handle = val.to_foreign().expect("Should be a foreign value.");
+
+    assert!(!heap.is_foreign_released(handle));
+    assert_eq!(heap.foreign_ref::<String>(handle).ok().expect("Should be a String."),
+               "native");
+    assert!(heap.foreign_ref::<i64>(handle).is_err());
+
+    let object = heap.release_foreign(handle).ok().expect("Should release.");
+    assert_eq!(object.downcast_ref::<String>().map(|s| s.as_slice()), Some("native"));
+
+    assert!(heap.is_foreign_released(handle));
+    assert_eq!(heap.foreign_ref::<String>(handle).err().map(|e| e.to_string()),
+               Some("[E0306] Error: use of released foreign object #<foreign 0> (string)"
+                    .to_string()));
+    assert!(heap.release_foreign(handle).is_err());
+}
+
+#[test]
+fn test_heap_finalizers() {
+    use std::sync::{Arc, Mutex};
+
+    let heap = &mut Heap::new();
+    let finalized = Arc::new(Mutex::new(0));
+
+    let one = Rooted::new(heap, Value::new_integer(1));
+    let kept = Value::new_pair(heap, &one, &one);
+    {
+        let dropped = Value::new_pair(heap, &one, &one);
+        for val in [&kept, &dropped].iter() {
+            let f = finalized.clone();
+            heap.register_finalizer(*val, Finalizer::Native(Box::new(move |_: &mut Heap| {
+                *f.lock().unwrap() += 1;
+            }))).ok().expect("Should be able to register a finalizer on a pair.");
+        }
+    }
+
+    heap.collect_garbage();
+    assert_eq!(*finalized.lock().unwrap(), 1);
+    heap.collect_garbage();
+    assert_eq!(*finalized.lock().unwrap(), 1);
+
+    assert!(heap.register_finalizer(&one, Finalizer::Native(Box::new(|_: &mut Heap| { })))
+            .is_err());
+}
+
+#[test]
+fn test_heap_failing_scheme_finalizer() {
+    let heap = &mut Heap::new();
+    heap.eval_str("(register-finalizer! (list 1 2) (lambda () (car (cdr '()))))")
+        .ok()
+        .expect("Should be able to register a finalizer.");
+
+    heap.collect_garbage();
+    assert_eq!(heap.stack_depth(), 0, "Should unwind the failed finalizer's frames");
+    assert!(heap.take_error_location().is_none(),
+            "Should not leave the failed finalizer's error location behind");
+}
+
+#[test]
+fn test_heap_symbol_table_is_weak() {
+    let heap = &mut Heap::new();
+
+    let kept = heap.get_or_create_symbol("kept-symbol".to_string());
+    heap.get_or_create_symbol("dropped-symbol".to_string());
+    heap.collect_garbage();
+
+    assert!(heap.symbol_table.contains_key("kept-symbol"));
+    assert!(!heap.symbol_table.contains_key("dropped-symbol"));
+
+    // Interning the same name again still gets the same symbol while it is
+    // alive.
+    let again = heap.get_or_create_symbol("kept-symbol".to_string());
+    assert_eq!(*kept, *again);
+}
+
+#[test]
+fn test_heap_out_of_memory() {
+    use eval::{evaluate, evaluate_file};
+    use read::{read_from_str};
+
+    let heap = &mut Heap::with_config(HeapConfig {
+        max_arenas: Some(20),
+        .. Default::default()
+    });
+
+    let error = evaluate_file(heap, "./tests/test_heap_out_of_memory.scm")
+        .err()
+        .expect("Should run out of memory.");
+    assert!(error.contains("Error: out of memory"));
+
+    // The heap is still usable afterwards.
+    let mut reader = read_from_str("(+ 1 2)", heap, "test");
+    let (location, form) = reader.next().expect("Should have a form.");
+    let form = form.ok().expect("Should be able to read the form.");
+    let result = evaluate(heap, &form, location)
+        .ok()
+        .expect("Should be able to evaluate after running out of memory.");
+    assert_eq!(*result, Value::new_integer(3));
+}
+
+#[test]
+fn test_heap_native_objects() {
+    use std::sync::{Arc, Mutex};
+
+    struct Connection {
+        closed: Arc<Mutex<bool>>,
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            *self.closed.lock().unwrap() = true;
+        }
+    }
+
+    let heap = &mut Heap::new();
+    let closed = Arc::new(Mutex::new(false));
+    let finalized = Arc::new(Mutex::new(false));
+
+    {
+        let val = heap.new_native("connection", Box::new(Connection {
+            closed: closed.clone(),
+        }));
+        assert_eq!(format!("{}", *val), "#<native connection>");
+
+        let native = val.to_native(heap).expect("Should be a native value.");
+        assert!(native.downcast_ref::<Connection>().is_ok());
+        assert_eq!(native.downcast_ref::<String>().err().map(|e| e.to_string()),
+                   Some("[E0307] Error: native object #<native connection> has the wrong type"
+                        .to_string()));
+
+        let flag = finalized.clone();
+        heap.register_finalizer(&val, Finalizer::Native(Box::new(move |_: &mut Heap| {
+            *flag.lock().unwrap() = true;
+        }))).ok().expect("Should be able to register a finalizer on a native.");
+
+        heap.collect_garbage();
+        assert_eq!(heap.statistics().live_natives, 1);
+        assert!(!*closed.lock().unwrap());
+    }
+
+    heap.collect_garbage();
+    assert_eq!(heap.statistics().live_natives, 0);
+    assert!(*closed.lock().unwrap());
+    assert!(*finalized.lock().unwrap());
+}
+
+#[test]
+fn test_heap_register_primitive() {
+    use std::sync::{Arc, Mutex};
+    use eval::{evaluate};
+    use read::{read_from_str};
+
+    let heap = &mut Heap::new();
+    let calls = Arc::new(Mutex::new(0));
+    let counter = calls.clone();
+    heap.register_primitive("count-calls!", move |heap: &mut Heap, args: &[RootedValue]| {
+        let mut count = counter.lock().unwrap();
+        *count += 1;
+        Ok(Rooted::new(heap, Value::new_integer(*count + args.len() as i64)))
+    });
+
+    let reader = read_from_str("(count-calls!) (count-calls! 'a 'b)", heap, "test");
+    let mut result = None;
+    for (location, form) in reader {
+        let form = form.ok().expect("Should be able to read the form.");
+        result = Some(evaluate(heap, &form, location)
+                      .ok()
+                      .expect("Should be able to call the closure primitive."));
+    }
+    assert_eq!(*result.expect("Should have a result."), Value::new_integer(4));
+    assert_eq!(*calls.lock().unwrap(), 2);
+}
+
+#[test]
+fn test_heap_catch_panics() {
+    let heap = &mut Heap::with_config(HeapConfig {
+        catch_panics: true,
+        .. Default::default()
+    });
+    assert!(heap.is_catching_panics());
+    heap.register_primitive("buggy", |_: &mut Heap, _: &[RootedValue]| -> SchemeResult {
+        panic!("index out of bounds")
+    });
+
+    let error = heap.eval_str("(+ 1 (buggy))").err().expect("Should fail.");
+    assert!(error.contains("[E0212] Error: primitive `buggy` panicked: index out of bounds"),
+            "{}", error);
+    assert_eq!(heap.stack_depth(), 0);
+
+    // The heap is still usable after the panic.
+    let result = heap.eval_str("(+ 1 2)")
+        .ok()
+        .expect("Should be able to evaluate after a primitive panics.");
+    assert_eq!(*result, Value::new_integer(3));
+
+    heap.set_execution_mode(ExecutionMode::Bytecode);
+    let error = heap.eval_str("(buggy)").err().expect("Should fail.");
+    assert!(error.contains("[E0212] Error: primitive `buggy` panicked: index out of bounds"),
+            "{}", error);
+}
+
+#[test]
+fn test_heap_image() {
+    use std::old_io::{MemReader, MemWriter};
+
+    let heap = &mut Heap::new();
+    heap.eval_str("(define (make-counter n)                  \
+                     (lambda () (set! n (+ n 1)) n))         \
+                   (define counter (make-counter 0))         \
+                   (counter)                                 \
+                   (define shared (list 1 \"two\" 'three))   \
+                   (define data (cons shared shared))")
+        .ok()
+        .expect("Should be able to build the environment to save.");
+
+    let mut writer = MemWriter::new();
+    heap.save_image(&mut writer)
+        .ok()
+        .expect("Should be able to save the heap image.");
+
+    let restored = &mut Heap::new();
+    restored.load_image(MemReader::new(writer.into_inner()))
+        .ok()
+        .expect("Should be able to load the heap image.");
+
+    // Closures keep their state, and shared structure stays shared.
+    let result = restored.eval_str("(counter)")
+        .ok()
+        .expect("Should be able to call a restored closure.");
+    assert_eq!(*result, Value::new_integer(2));
+    let result = restored.eval_str("(list (eq? (car data) (cdr data)) (car (cdr shared)))")
+        .ok()
+        .expect("Should be able to use restored data.");
+    assert_eq!(format!("{}", *result), "(#t \"two\")");
+
+    // Host objects cannot be saved.
+    heap.register_primitive("host", |heap: &mut Heap, _: &[RootedValue]| {
+        Ok(heap.unspecified_symbol())
+    });
+    let mut writer = MemWriter::new();
+    assert!(heap.save_image(&mut writer).is_err());
+}
+
+#[test]
+fn test_heap_compile_file() {
+    use std::old_io::{MemReader, MemWriter};
+
+    let heap = &mut Heap::new();
+    let script = heap.compile_file("./tests/test_ref_defined_later.scm")
+        .ok()
+        .expect("Should be able to compile the script.");
+    let mut writer = MemWriter::new();
+    script.save(&mut writer)
+        .ok()
+        .expect("Should be able to save the compiled script.");
+
+    // Defining other globals first gives the script's globals different
+    // indices than they had when it was compiled.
+    let restored = &mut Heap::new();
+    restored.eval_str("(define unrelated 0) (define foo 'shadowed)")
+        .ok()
+        .expect("Should be able to define other globals.");
+    let script = CompiledScript::load(&mut MemReader::new(writer.into_inner()))
+        .ok()
+        .expect("Should be able to load the compiled script.");
+    let result = restored.run_compiled(&script)
+        .ok()
+        .expect("Should be able to run the compiled script.");
+    assert_eq!(*result, Value::new_integer(1));
+    let result = restored.eval_str("(foo)")
+        .ok()
+        .expect("Should be able to call a procedure the script defined.");
+    assert_eq!(*result, Value::new_integer(1));
+}
+
+#[test]
+fn test_heap_call() {
+    use eval::{evaluate};
+    use read::{read_from_str};
+
+    let heap = &mut Heap::new();
+    let mut reader = read_from_str("(lambda (a b) (cons b a))", heap, "test");
+    let (location, form) = reader.next().expect("Should have a form.");
+    let form = form.ok().expect("Should be able to read the form.");
+    let swap = evaluate(heap, &form, location)
+        .ok()
+        .expect("Should be able to evaluate the lambda.");
+
+    let one = Rooted::new(heap, Value::new_integer(1));
+    let two = Rooted::new(heap, Value::new_integer(2));
+    let pair = heap.call(&swap, &[one.clone(), two.clone()])
+        .ok()
+        .expect("Should be able to call the procedure.");
+    assert_eq!(format!("{}", *pair), "(2 . 1)");
+
+    let error = heap.call(&swap, &[one.clone()])
+        .err()
+        .expect("Should fail to call the procedure with too few arguments.");
+    assert_eq!(error.code.map(|entry| entry.code), Some("E0202"));
+    assert_eq!(heap.stack_depth(), 0);
+
+    let not_a_procedure = heap.call(&one, &[])
+        .err()
+        .expect("Should fail to call a non-procedure.");
+    assert_eq!(not_a_procedure.code.map(|entry| entry.code), Some("E0203"));
+}
+
+#[test]
+fn test_heap_eval_str() {
+    let heap = &mut Heap::new();
+
+    let result = heap.eval_str("(define (double x) (* x 2)) (double (+ 1 2))")
+        .ok()
+        .expect("Should be able to evaluate the string.");
+    assert_eq!(*result, Value::new_integer(6));
+
+    // Definitions persist in the global environment.
+    let result = heap.eval_str("(double 5)")
+        .ok()
+        .expect("Should be able to call a procedure defined earlier.");
+    assert_eq!(*result, Value::new_integer(10));
+
+    let result = heap.eval_str("")
+        .ok()
+        .expect("Should be able to evaluate an empty string.");
+    assert_eq!(*result, Value::EmptyList);
+
+    assert!(heap.eval_str("(car '())").is_err());
+    assert!(heap.eval_str("(+ 1").is_err());
+}
+
+#[test]
+fn test_heap_max_bytes() {
+    let heap = &mut Heap::new();
+    heap.eval_str("(define allocate-forever (lambda (xs) (allocate-forever (cons xs xs))))")
+        .ok()
+        .expect("Should be able to define allocate-forever.");
+
+    let limit = heap.bytes() + (1 << 20);
+    heap.set_max_bytes(Some(limit));
+    let error = heap.eval_str("(allocate-forever '())")
+        .err()
+        .expect("Should run out of memory.");
+    assert!(error.contains("Error: out of memory"));
+
+    // The garbage from the failed evaluation is reclaimed, and the heap is
+    // still usable afterwards.
+    heap.collect_garbage();
+    assert!(heap.bytes() <= limit);
+    let result = heap.eval_str("(+ 1 2)")
+        .ok()
+        .expect("Should be able to evaluate after running out of memory.");
+    assert_eq!(*result, Value::new_integer(3));
+}
+
+#[test]
+fn test_heap_interrupt() {
+    let heap = &mut Heap::new();
+    heap.eval_str("(define (forever) (forever))")
+        .ok()
+        .expect("Should be able to define forever.");
+
+    // Interrupt from within the evaluation, the way another thread would.
+    let handle = heap.interrupt_handle();
+    heap.register_primitive("interrupt!", move |heap: &mut Heap, _: &[RootedValue]| {
+        handle.interrupt();
+        Ok(heap.unspecified_symbol())
+    });
+    let error = heap.eval_str("(interrupt!) (forever)").err().expect("Should be interrupted.");
+    assert!(error.contains("[E0211] Error: interrupted"), "{}", error);
+    assert_eq!(heap.stack_depth(), 0);
+
+    // The interrupt is consumed, so the heap is still usable.
+    let result = heap.eval_str("(+ 1 2)")
+        .ok()
+        .expect("Should be able to evaluate after being interrupted.");
+    assert_eq!(*result, Value::new_integer(3));
+
+    // The bytecode VM can be interrupted too.
+    heap.set_execution_mode(ExecutionMode::Bytecode);
+    let error = heap.eval_str("(interrupt!) (forever)").err().expect("Should be interrupted.");
+    assert!(error.contains("[E0211] Error: interrupted"), "{}", error);
+}
+
+#[test]
+fn test_heap_primitive_tables() {
+    let heap = &mut Heap::new();
+    let other = &mut Heap::new();
+    let installed = other.primitive_table.len();
+
+    heap.register_primitive("nothing", |heap: &mut Heap, _: &[RootedValue]| {
+        Ok(Rooted::new(heap, Value::EmptyList))
+    });
+    assert_eq!(heap.primitive_table.len(), installed + 1);
+    assert_eq!(other.primitive_table.len(), installed);
+
+    let car = heap.eval_str("car").ok().expect("Should be able to get car.");
+    let other_car = other.eval_str("car").ok().expect("Should be able to get car.");
+    assert!(*car != *other_car, "Each heap should have its own primitives");
+    assert_eq!(heap.primitive_table.lookup("car").map(Value::Primitive), Some(*car));
+}
+
+#[test]
+fn test_heap_send() {
+    use std::thread::{Thread};
+
+    fn assert_send<T: Send>() { }
+    assert_send::<SendHeap>();
+
+    let mut heap = OwnedHeap::new();
+    heap.with(|heap| {
+        heap.eval_str("(define (square x) (* x x))")
+            .ok()
+            .expect("Should be able to define square.");
+    });
+
+    // Nothing obtained from the heap outlives the call to `with` above, so
+    // nothing outside the heap refers into it.
+    let heap = unsafe { heap.into_send() };
+    let result = Thread::scoped(move || {
+        let mut heap = heap.into_owned();
+        heap.with(|heap| {
+            let result = heap.eval_str("(square 7)")
+                .ok()
+                .expect("Should be able to evaluate on another thread.");
+            result.to_integer()
+        })
+    }).join();
+    assert_eq!(result.ok(), Some(Some(49)));
+}
+
+#[test]
+fn test_heap_lifecycle_hooks() {
+    use std::sync::{Arc, Mutex};
+
+    let counts = Arc::new(Mutex::new((0, 0, 0, 0)));
+
+    {
+        let c = counts.clone();
+        let created_at = Arc::new(Mutex::new(0));
+        let a = created_at.clone();
+        let on_create : LifecycleHook = Box::new(move |heap: &mut Heap| {
+            c.lock().unwrap().0 += 1;
+            *a.lock().unwrap() = &*heap as *const Heap as usize;
+        });
+
+        let heap = &mut Heap::with_lifecycle_hooks(vec!((LifecycleEvent::Create,
+                                                         on_create)));
+        assert_eq!(*counts.lock().unwrap(), (0, 0, 0, 0));
+
+        heap.ensure_created();
+        heap.ensure_created();
+        assert_eq!(*counts.lock().unwrap(), (1, 0, 0, 0));
+        assert_eq!(*created_at.lock().unwrap(), &*heap as *const Heap as usize);
+
+        let c = counts.clone();
+        heap.add_lifecycle_hook(LifecycleEvent::BeforeGc, Box::new(move |_: &mut Heap| {
+            c.lock().unwrap().1 += 1;
+        }));
+
+        let c = counts.clone();
+        heap.add_lifecycle_hook(LifecycleEvent::AfterGc, Box::new(move |_: &mut Heap| {
+            c.lock().unwrap().2 += 1;
+        }));
+
+        let c = counts.clone();
+        heap.add_lifecycle_hook(LifecycleEvent::Destroy, Box::new(move |_: &mut Heap| {
+            c.lock().unwrap().3 += 1;
+        }));
+
+        heap.collect_garbage();
+        assert_eq!(*counts.lock().unwrap(), (1, 1, 1, 0));
+    }
+
+    assert_eq!(*counts.lock().unwrap(), (1, 1, 1, 1));
+}
+
+#[test]
+fn test_heap_on_gc() {
+    use std::sync::{Arc, Mutex};
+
+    let heap = &mut Heap::new();
+    let events = Arc::new(Mutex::new(vec!()));
+
+    let e = events.clone();
+    heap.on_gc(Box::new(move |phase: GcPhase, stats: &HeapStatistics| {
+        e.lock().unwrap().push((phase, stats.collections));
+    }));
+
+    let before = heap.statistics().collections;
+    heap.collect_garbage();
+    assert_eq!(*events.lock().unwrap(), vec!((GcPhase::Start, before),
+                                      (GcPhase::End, before + 1)));
+}
+
+#[test]
+fn test_heap_record_source() {
+    let heap = &mut Heap::new();
+    let location = |line: u64| {
+        let mut location = Location::new("<stdin>".to_string());
+        location.line = line;
+        location
+    };
+
+    heap.record_source("<stdin>", "(define x 1)\n");
+    heap.record_source("<stdin>", "(car x)\n");
+    assert_eq!(heap.source_line(&location(2)), Some("(car x)".to_string()));
+    assert_eq!(heap.source_line(&location(3)), None);
+
+    for i in 0..(2 * MAX_RECORDED_SOURCE_LINES) {
+        heap.record_source("<stdin>", format!("{}\n", i).as_slice());
+    }
+    let last = 2 + 2 * MAX_RECORDED_SOURCE_LINES as u64;
+    assert_eq!(heap.source_line(&location(1)), None,
+               "Should forget the oldest lines of appended source");
+    assert_eq!(heap.source_line(&location(last)),
+               Some(format!("{}", 2 * MAX_RECORDED_SOURCE_LINES - 1)));
+    assert!(heap.sources.get("<stdin>").unwrap().lines <= 2 * MAX_RECORDED_SOURCE_LINES);
+
+    heap.set_source("frob.scm", "(frob)\n(knob)\n");
+    let mut knob = location(2);
+    knob.file = "frob.scm".to_string();
+    assert_eq!(heap.source_line(&knob), Some("(knob)".to_string()));
+}
+
+#[test]
+fn test_heap_pending_warnings_are_bounded() {
+    let heap = &mut Heap::new();
+    heap.set_warnings_enabled(true);
+
+    for i in 0..(MAX_PENDING_WARNINGS + 10) {
+        heap.warn(Location::new("frob.scm".to_string()), format!("warning {}", i));
+    }
+    let warnings = heap.take_warnings();
+    assert_eq!(warnings.len(), MAX_PENDING_WARNINGS);
+    assert_eq!(warnings[0].message.as_slice(), "warning 0");
+    assert!(heap.take_warnings().is_empty());
+}
+
+#[test]
+fn test_heap_names_with_prefix() {
+    let heap = &mut Heap::new();
+    heap.eval_str("(define frob-b 2) \
+                   (define frob-a 1) \
+                   (define (f) frob-undefined)")
+        .ok()
+        .expect("Should be able to define globals.");
+
+    let global = heap.global_activation();
+    assert_eq!(heap.environment.names_with_prefix("frob-", &*global),
+               vec!("frob-a".to_string(), "frob-b".to_string()));
+    assert!(heap.environment.names_with_prefix("", &*global).contains(&"car".to_string()));
+}
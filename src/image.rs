@@ -0,0 +1,1119 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Saving the global environment, and everything reachable from it, as a heap
+//! image, and loading it back into a fresh heap. Loading an image is much
+//! faster than evaluating the code that built it, such as a large prelude.
+//!
+//! An image is a single datum, so that it can be read back in with the normal
+//! reader:
+//!
+//!     (oxischeme-image 1
+//!      (prelude #t)
+//!      (globals (#u8(102 111 111) 0) ...)
+//!      (global-values (ref 0) ...)
+//!      (macros ...)
+//!      (definitions ...)
+//!      (objects (procedure 1 global 3 #u8(102 111 111) (#u8(...) 1 1)) ...)
+//!      (meanings (quotation (#u8(...) 1 14) 42) ...))
+//!
+//! Every pair, string, symbol, vector, bytevector, procedure, and activation is
+//! an entry in the `objects` section, and refers to the others by their index
+//! in it, so that sharing and cycles are preserved. The analyzed bodies of
+//! procedures are entries in the `meanings` section, each after its children.
+//! Strings and names are written as UTF-8 bytevectors, so that no escaping is
+//! needed. Primitives are saved by name, and found again by name when loading.
+//!
+//! Foreign objects, native objects, and closure primitives belong to the host,
+//! and cannot be saved.
+//!
+//! The same encoding is used for compiled scripts, which hold the analyzed
+//! forms of a single file rather than a whole heap. See `CompiledScript`.
+
+use std::collections::{HashMap};
+use std::old_io::{IoResult, MemReader, Reader, Writer};
+
+use environment::{ActivationPtr, RootedActivationPtr, SymbolId, SymbolNames};
+use errors::{E0213, SchemeError};
+use eval::{analyze, ensure_prelude, evaluate_meaning, Meaning, MeaningData};
+use heap::{Heap, Rooted};
+use primitives::{FastPath};
+use read::{read_from_file, read_from_reader, Location};
+use value::{flonum_to_string, Primitive, RootedValue, SchemeResult, Value};
+
+/// The version of the image format written by `save`. Images of any other
+/// version are rejected by `load`.
+pub static IMAGE_VERSION : i64 = 1;
+
+// SAVING ----------------------------------------------------------------------
+
+/// A GC thing that gets its own entry in an image.
+#[derive(Copy, Clone, Eq, Hash, PartialEq)]
+enum Object {
+    Value(Value),
+    Activation(ActivationPtr),
+}
+
+/// Numbers the objects and meanings reachable from the global environment,
+/// and encodes them as image entries.
+///
+/// Saving never allocates, so the objects reachable from the global activation
+/// need not be rooted individually.
+struct ImageWriter {
+    global: ActivationPtr,
+    /// When writing a compiled script, the names of the globals by their index
+    /// in the global activation, so that references to globals are written by
+    /// name rather than by index.
+    global_names: Option<HashMap<u32, SymbolId>>,
+    /// The heap's interned variable names, so that variables are written by
+    /// name rather than by `SymbolId`.
+    names: SymbolNames,
+    /// The primitives that may be saved, by name. See `PrimitiveTable::by_name`.
+    primitives: HashMap<&'static str, Primitive>,
+    indices: HashMap<Object, usize>,
+    objects: Vec<Object>,
+    meaning_indices: HashMap<usize, usize>,
+    meanings: Vec<String>,
+}
+
+impl ImageWriter {
+    fn new(global: ActivationPtr,
+           global_names: Option<HashMap<u32, SymbolId>>,
+           names: SymbolNames,
+           primitives: HashMap<&'static str, Primitive>) -> ImageWriter {
+        ImageWriter {
+            global: global,
+            global_names: global_names,
+            names: names,
+            primitives: primitives,
+            indices: HashMap::new(),
+            objects: vec!(),
+            meaning_indices: HashMap::new(),
+            meanings: vec!(),
+        }
+    }
+
+    /// Get the index of the given object, numbering it if this is the first
+    /// time it has been seen.
+    fn object(&mut self, object: Object) -> usize {
+        if let Some(index) = self.indices.get(&object) {
+            return *index;
+        }
+        let index = self.objects.len();
+        self.indices.insert(object, index);
+        self.objects.push(object);
+        index
+    }
+
+    fn value(&mut self, val: Value) -> Result<String, SchemeError> {
+        match val {
+            Value::EmptyList      => Ok("()".to_string()),
+            Value::Integer(i)     => Ok(format!("{}", i)),
+            Value::Flonum(f)      => Ok(flonum_to_string(*f)),
+            Value::Boolean(b)     => Ok(boolean(b)),
+            Value::Character(c)   => Ok(format!("(char {})", c as u32)),
+            Value::Primitive(p)   => {
+                if self.primitives.get(p.name()) != Some(&p) {
+                    return Err(E0213.error(format!("Error: cannot save {} in a heap image", val)));
+                }
+                Ok(format!("(primitive {})", bytes(p.name().as_bytes())))
+            },
+            Value::Pair(_)        |
+            Value::String(_)      |
+            Value::Symbol(_)      |
+            Value::Vector(_)      |
+            Value::Bytevector(_)  |
+            Value::Procedure(_)   => Ok(format!("(ref {})", self.object(Object::Value(val)))),
+            Value::Foreign(_)     |
+            Value::Native(_)      => {
+                Err(E0213.error(format!("Error: cannot save {} in a heap image", val)))
+            },
+        }
+    }
+
+    /// Encode a variable's value, which may be undefined.
+    fn variable(&mut self, val: Option<Value>) -> Result<String, SchemeError> {
+        match val {
+            Some(val) => self.value(val),
+            None      => Ok("undefined".to_string()),
+        }
+    }
+
+    fn activation(&mut self, act: Option<ActivationPtr>) -> String {
+        match act {
+            Some(act) if act == self.global => "global".to_string(),
+            Some(act)                       => {
+                format!("(ref {})", self.object(Object::Activation(act)))
+            },
+            None                            => "#f".to_string(),
+        }
+    }
+
+    /// Encode the entry for the given object.
+    fn entry(&mut self, object: Object) -> Result<String, SchemeError> {
+        match object {
+            Object::Activation(act) => {
+                let parent = self.activation(act.parent());
+                let mut vals = vec!();
+                for val in act.values().into_iter() {
+                    vals.push(try!(self.variable(val)));
+                }
+                Ok(format!("(activation {} {} ({}))",
+                           parent,
+                           boolean(act.is_captured()),
+                           vals.connect(" ")))
+            },
+            Object::Value(Value::Pair(cons)) => {
+                let car = try!(self.value(cons.unrooted_car()));
+                let cdr = try!(self.value(cons.unrooted_cdr()));
+                Ok(format!("(cons {} {})", car, cdr))
+            },
+            Object::Value(Value::String(str)) => {
+                Ok(format!("(string {})", bytes(str.as_bytes())))
+            },
+            Object::Value(Value::Symbol(sym)) => {
+                Ok(format!("(symbol {})", bytes(sym.as_bytes())))
+            },
+            Object::Value(Value::Vector(vector)) => {
+                let mut elements = vec!();
+                for i in 0..vector.len() {
+                    let element = vector.unrooted_get(i)
+                        .expect("Should be within the vector's length");
+                    elements.push(try!(self.value(element)));
+                }
+                Ok(format!("(vector ({}))", elements.connect(" ")))
+            },
+            Object::Value(Value::Bytevector(bv)) => {
+                Ok(format!("(bytevector {})", bytes(bv.as_slice())))
+            },
+            Object::Value(Value::Procedure(procedure)) if procedure.signature.is_some() => {
+                Err(E0213.error(format!("Error: cannot save {}, which has optional or keyword \
+                                         parameters, in a heap image",
+                                        Value::Procedure(procedure))))
+            },
+            Object::Value(Value::Procedure(procedure)) => {
+                let act = self.activation(procedure.act);
+                let level = procedure.act.map_or(0, depth) + 1;
+                let body = match procedure.body {
+                    Some(ref body) => format!("{}", try!(self.meaning(&**body, level))),
+                    None           => "#f".to_string(),
+                };
+                Ok(format!("(procedure {} {} {} {} {})",
+                           procedure.arity,
+                           act,
+                           body,
+                           name(&procedure.name),
+                           location(&procedure.location)))
+            },
+            Object::Value(val) => {
+                Err(E0213.error(format!("Error: cannot save {} in a heap image", val)))
+            },
+        }
+    }
+
+    /// Encode the index of the given variable in its activation. When writing a
+    /// compiled script, globals are written by name instead, because they have
+    /// different indices in the heap that runs the script.
+    fn coordinate(&self, level: u32, i: u32, j: u32) -> String {
+        if i == level {
+            self.global(j)
+        } else {
+            format!("{}", j)
+        }
+    }
+
+    fn global(&self, j: u32) -> String {
+        match self.global_names.as_ref().and_then(|names| names.get(&j)) {
+            Some(name) => self.symbol_id(*name),
+            None       => format!("{}", j),
+        }
+    }
+
+    fn symbol_id(&self, id: SymbolId) -> String {
+        bytes(self.names.name(id).as_bytes())
+    }
+
+    /// Get the index of the given meaning, nested `level` blocks deep from the
+    /// global activation, encoding it and its children if this is the first
+    /// time it has been seen.
+    fn meaning(&mut self, meaning: &Meaning, level: u32) -> Result<usize, SchemeError> {
+        if let Some(index) = self.meaning_indices.get(&meaning.identity()) {
+            return Ok(*index);
+        }
+
+        let loc = location(meaning.location());
+        let entry = match *meaning.data() {
+            MeaningData::Quotation(ref val) => {
+                format!("(quotation {} {})", loc, try!(self.value(**val)))
+            },
+            MeaningData::Reference(i, j, name) => {
+                format!("(reference {} {} {} {})",
+                        loc,
+                        i,
+                        self.coordinate(level, i, j),
+                        self.symbol_id(name))
+            },
+            MeaningData::GlobalReference(_, j, name) => {
+                format!("(global-reference {} {} {})", loc, self.global(j), self.symbol_id(name))
+            },
+            MeaningData::Definition(i, j, ref val) => {
+                format!("(definition {} {} {} {})",
+                        loc,
+                        i,
+                        self.coordinate(level, i, j),
+                        try!(self.meaning(val, level)))
+            },
+            MeaningData::SetVariable(i, j, ref val) => {
+                format!("(set-variable {} {} {} {})",
+                        loc,
+                        i,
+                        self.coordinate(level, i, j),
+                        try!(self.meaning(val, level)))
+            },
+            MeaningData::Conditional(ref condition, ref consequent, ref alternative) => {
+                format!("(conditional {} {} {} {})",
+                        loc,
+                        try!(self.meaning(condition, level)),
+                        try!(self.meaning(consequent, level)),
+                        try!(self.meaning(alternative, level)))
+            },
+            MeaningData::Sequence(ref first, ref second) => {
+                format!("(sequence {} {} {})",
+                        loc,
+                        try!(self.meaning(first, level)),
+                        try!(self.meaning(second, level)))
+            },
+            MeaningData::Lambda(_, _, _, _, Some(_))        |
+            MeaningData::FlatLambda(_, _, _, _, _, Some(_)) => {
+                return Err(E0213.error("Error: cannot save a lambda with optional or keyword \
+                                        parameters in a heap image".to_string()));
+            },
+            MeaningData::TheEnvironment(_) => {
+                return Err(E0213.error("Error: cannot save a use of `the-environment` in a \
+                                        heap image".to_string()));
+            },
+            MeaningData::Lambda(arity, ref body, ref lambda_name, ref lambda_loc, None) => {
+                format!("(lambda {} {} {} {} {})",
+                        loc,
+                        arity,
+                        try!(self.meaning(body, level + 1)),
+                        name(lambda_name),
+                        location(lambda_loc))
+            },
+            MeaningData::FlatLambda(arity,
+                                    ref body,
+                                    ref lambda_name,
+                                    ref lambda_loc,
+                                    ref captures,
+                                    None) => {
+                // The body of a flat closure sees its captured variables, if
+                // it has any, and then the global activation.
+                let body_level = if captures.is_empty() { 1 } else { 2 };
+                let captures : Vec<String> = captures.iter()
+                    .map(|&(i, j, name)| {
+                        format!("({} {} {})",
+                                i,
+                                self.coordinate(level, i, j),
+                                self.symbol_id(name))
+                    })
+                    .collect();
+                format!("(flat-lambda {} {} {} {} {} ({}))",
+                        loc,
+                        arity,
+                        try!(self.meaning(body, body_level)),
+                        name(lambda_name),
+                        location(lambda_loc),
+                        captures.connect(" "))
+            },
+            MeaningData::Invocation(ref procedure, ref params, ref call_site) => {
+                let params : Vec<&Meaning> = params.iter().collect();
+                try!(self.invocation(meaning, level, procedure, &params, call_site))
+            },
+            MeaningData::Invocation0(ref procedure, ref call_site) => {
+                try!(self.invocation(meaning, level, procedure, &[], call_site))
+            },
+            MeaningData::Invocation1(ref procedure, ref a, ref call_site) => {
+                try!(self.invocation(meaning, level, procedure, &[a], call_site))
+            },
+            MeaningData::Invocation2(ref procedure, ref a, ref b, ref call_site) => {
+                try!(self.invocation(meaning, level, procedure, &[a, b], call_site))
+            },
+            MeaningData::Invocation3(ref procedure, ref a, ref b, ref c, ref call_site) => {
+                try!(self.invocation(meaning, level, procedure, &[a, b, c], call_site))
+            },
+            MeaningData::Let(ref params, ref body, ref call_site) => {
+                let params : Vec<&Meaning> = params.iter().collect();
+                format!("(let {} {} {} {})",
+                        loc,
+                        try!(self.meanings(&params, level)),
+                        try!(self.meaning(body, level + 1)),
+                        location(call_site))
+            },
+            MeaningData::InlinedInvocation(ref procedure,
+                                           ref params,
+                                           ref inlined,
+                                           ref body,
+                                           ref call_site) => {
+                let params : Vec<&Meaning> = params.iter().collect();
+                format!("(inlined-invocation {} {} {} {} {} {})",
+                        loc,
+                        try!(self.meaning(procedure, level)),
+                        try!(self.meanings(&params, level)),
+                        try!(self.value(**inlined)),
+                        try!(self.meaning(body, level + 1)),
+                        location(call_site))
+            },
+            MeaningData::PrimitiveInvocation(ref procedure,
+                                             ref primitive,
+                                             _,
+                                             ref params,
+                                             ref call_site) => {
+                let params : Vec<&Meaning> = params.iter().collect();
+                format!("(primitive-invocation {} {} {} {} {})",
+                        loc,
+                        try!(self.meaning(procedure, level)),
+                        try!(self.value(**primitive)),
+                        try!(self.meanings(&params, level)),
+                        location(call_site))
+            },
+        };
+
+        let index = self.meanings.len();
+        self.meaning_indices.insert(meaning.identity(), index);
+        self.meanings.push(entry);
+        Ok(index)
+    }
+
+    fn meanings(&mut self, meanings: &[&Meaning], level: u32) -> Result<String, SchemeError> {
+        let mut indices = vec!();
+        for meaning in meanings.iter() {
+            indices.push(format!("{}", try!(self.meaning(*meaning, level))));
+        }
+        Ok(format!("({})", indices.connect(" ")))
+    }
+
+    fn invocation(&mut self,
+                  meaning: &Meaning,
+                  level: u32,
+                  procedure: &Meaning,
+                  params: &[&Meaning],
+                  call_site: &Location) -> Result<String, SchemeError> {
+        Ok(format!("(invocation {} {} {} {} {})",
+                   location(meaning.location()),
+                   boolean(meaning.is_tail_invocation()),
+                   try!(self.meaning(procedure, level)),
+                   try!(self.meanings(params, level)),
+                   location(call_site)))
+    }
+}
+
+fn boolean(b: bool) -> String {
+    if b { "#t".to_string() } else { "#f".to_string() }
+}
+
+fn bytes(bytes: &[u8]) -> String {
+    let bytes : Vec<String> = bytes.iter().map(|b| format!("{}", b)).collect();
+    format!("#u8({})", bytes.connect(" "))
+}
+
+fn name(name: &Option<String>) -> String {
+    match *name {
+        Some(ref name) => bytes(name.as_bytes()),
+        None           => "#f".to_string(),
+    }
+}
+
+fn location(loc: &Location) -> String {
+    format!("({} {} {})", bytes(loc.file.as_bytes()), loc.line, loc.column)
+}
+
+/// Get the number of activations between the given activation and the global
+/// activation.
+fn depth(act: ActivationPtr) -> u32 {
+    let mut depth = 0;
+    let mut parent = act.parent();
+    while let Some(act) = parent {
+        depth += 1;
+        parent = act.parent();
+    }
+    depth
+}
+
+impl ImageWriter {
+    /// Encode every object numbered so far. Encoding an object numbers the
+    /// objects it refers to, which are encoded in turn.
+    fn objects(&mut self) -> Result<Vec<String>, SchemeError> {
+        let mut objects = vec!();
+        while objects.len() < self.objects.len() {
+            let object = self.objects[objects.len()];
+            objects.push(try!(self.entry(object)));
+        }
+        Ok(objects)
+    }
+
+    /// Write the `objects` and `meanings` sections, one entry per line.
+    fn write_sections(&self, out: &mut String, objects: &[String]) {
+        out.push_str(" (objects");
+        for object in objects.iter() {
+            out.push_str("\n  ");
+            out.push_str(object.as_slice());
+        }
+        out.push_str(")\n (meanings");
+        for meaning in self.meanings.iter() {
+            out.push_str("\n  ");
+            out.push_str(meaning.as_slice());
+        }
+        out.push_str(")");
+    }
+}
+
+/// Save the given heap's global environment, and everything reachable from
+/// it, as an image written to the given writer.
+pub fn save<W: Writer>(heap: &mut Heap, writer: &mut W) -> Result<(), SchemeError> {
+    let global = heap.global_activation();
+    let mut image = ImageWriter::new(*global,
+                                     None,
+                                     heap.environment.names().clone(),
+                                     heap.primitive_table.by_name());
+
+    let mut globals = heap.environment.globals();
+    globals.sort_by(|a, b| a.1.cmp(&b.1));
+    let globals : Vec<String> = globals.into_iter()
+        .map(|(name, j)| format!("({} {})", image.symbol_id(name), j))
+        .collect();
+
+    let mut global_values = vec!();
+    for val in global.values().into_iter() {
+        global_values.push(try!(image.variable(val)));
+    }
+
+    let mut macros = heap.macros();
+    macros.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut macro_entries = vec!();
+    for (macro_name, transformer) in macros.into_iter() {
+        macro_entries.push(format!("({} {})",
+                                   bytes(macro_name.as_bytes()),
+                                   try!(image.value(*transformer))));
+    }
+
+    let mut definitions = heap.definitions();
+    definitions.sort_by(|a, b| a.0.cmp(&b.0));
+    let definitions : Vec<String> = definitions.into_iter()
+        .map(|(name, loc)| format!("({} {})", bytes(name.as_bytes()), location(&loc)))
+        .collect();
+
+    let objects = try!(image.objects());
+
+    let mut out = String::new();
+    out.push_str(format!("(oxischeme-image {}\n", IMAGE_VERSION).as_slice());
+    out.push_str(format!(" (prelude {})\n", boolean(heap.is_prelude_loaded())).as_slice());
+    out.push_str(format!(" (globals {})\n", globals.connect(" ")).as_slice());
+    out.push_str(format!(" (global-values {})\n", global_values.connect(" ")).as_slice());
+    out.push_str(format!(" (macros {})\n", macro_entries.connect(" ")).as_slice());
+    out.push_str(format!(" (definitions {})\n", definitions.connect(" ")).as_slice());
+    image.write_sections(&mut out, objects.as_slice());
+    out.push_str(")\n");
+
+    writer.write_str(out.as_slice())
+        .map_err(|e| E0213.error(format!("Error: could not write heap image: {}", e)))
+}
+
+// LOADING ---------------------------------------------------------------------
+
+/// An object allocated while loading an image. Holding these keeps every object
+/// rooted until loading is done.
+enum Loaded {
+    Value(RootedValue),
+    Activation(RootedActivationPtr),
+}
+
+fn malformed(what: &str) -> SchemeError {
+    E0213.error(format!("Error: malformed heap image: {}", what))
+}
+
+fn elements(val: Value) -> Result<Vec<Value>, SchemeError> {
+    val.iter()
+        .collect::<Result<Vec<Value>, ()>>()
+        .map_err(|_| malformed(format!("expected a list, found {}", val).as_slice()))
+}
+
+fn tag(val: Value) -> Result<String, SchemeError> {
+    match val {
+        Value::Symbol(sym) => Ok((*sym).clone()),
+        _                  => Err(malformed(format!("expected a tag, found {}", val).as_slice())),
+    }
+}
+
+fn integer(val: Value) -> Result<u64, SchemeError> {
+    match val {
+        Value::Integer(i) if i >= 0 => Ok(i as u64),
+        _                           => {
+            Err(malformed(format!("expected an index, found {}", val).as_slice()))
+        },
+    }
+}
+
+fn text(val: Value) -> Result<String, SchemeError> {
+    match val {
+        Value::Bytevector(bv) => {
+            String::from_utf8((*bv).clone()).map_err(|_| malformed("invalid UTF-8"))
+        },
+        _                     => Err(malformed(format!("expected text, found {}", val).as_slice())),
+    }
+}
+
+fn optional_text(val: Value) -> Result<Option<String>, SchemeError> {
+    match val {
+        Value::Boolean(false) => Ok(None),
+        _                     => text(val).map(Some),
+    }
+}
+
+fn flag(val: Value) -> Result<bool, SchemeError> {
+    match val {
+        Value::Boolean(b) => Ok(b),
+        _                 => {
+            Err(malformed(format!("expected a boolean, found {}", val).as_slice()))
+        },
+    }
+}
+
+fn read_location(val: Value) -> Result<Location, SchemeError> {
+    match try!(elements(val)).as_slice() {
+        [file, line, column] => Ok(Location {
+            file: try!(text(file)),
+            line: try!(integer(line)),
+            column: try!(integer(column)),
+        }),
+        _ => Err(malformed(format!("expected a location, found {}", val).as_slice())),
+    }
+}
+
+/// Read the datum at the start of the given reader, and check that it begins
+/// with the given header and the current version. Return the datum, which
+/// roots everything in it, and its sections.
+fn read_datum<R: Reader>(heap: &mut Heap,
+                         reader: R,
+                         header: &str) -> Result<(RootedValue, Vec<Value>), SchemeError> {
+    let datum = match read_from_reader(reader, heap, "<image>").next() {
+        Some((_, result)) => try!(result),
+        None              => return Err(malformed("empty image")),
+    };
+
+    let sections = try!(elements(*datum));
+    match sections.as_slice() {
+        [Value::Symbol(sym), Value::Integer(version), ..] if sym.as_slice() == header => {
+            if version != IMAGE_VERSION {
+                return Err(E0213.error(format!("Error: unsupported heap image version {}",
+                                               version)));
+            }
+        },
+        _ => return Err(malformed(format!("missing the `{}` header", header).as_slice())),
+    }
+    let sections = sections[2..].to_vec();
+    Ok((datum, sections))
+}
+
+/// Get the items of the section of the image with the given tag.
+fn section(sections: &[Value], name: &str) -> Result<Vec<Value>, SchemeError> {
+    for section in sections.iter() {
+        let items = try!(elements(*section));
+        if items.len() > 0 && try!(tag(items[0])) == name {
+            return Ok(items[1..].to_vec());
+        }
+    }
+    Err(malformed(format!("missing the `{}` section", name).as_slice()))
+}
+
+/// Allocates the objects and meanings of an image in a heap.
+struct ImageReader {
+    global: RootedActivationPtr,
+    /// The loading heap's primitives, by name. See `PrimitiveTable::by_name`.
+    primitives: HashMap<&'static str, Primitive>,
+    objects: Vec<Loaded>,
+    meanings: Vec<Meaning>,
+}
+
+impl ImageReader {
+    /// Allocate every object in the `objects` section, rebuild every meaning in
+    /// the `meanings` section, and then fill in the objects.
+    fn new(heap: &mut Heap, sections: &[Value]) -> Result<ImageReader, SchemeError> {
+        let mut loader = ImageReader {
+            global: heap.global_activation(),
+            primitives: heap.primitive_table.by_name(),
+            objects: vec!(),
+            meanings: vec!(),
+        };
+
+        // Allocate every object first, so that meanings and other objects can
+        // refer to them regardless of order.
+        let mut entries = vec!();
+        for object in try!(section(sections, "objects")).into_iter() {
+            let entry = try!(elements(object));
+            try!(loader.allocate(heap, entry.as_slice()));
+            entries.push(entry);
+        }
+
+        for meaning in try!(section(sections, "meanings")).into_iter() {
+            let entry = try!(elements(meaning));
+            try!(loader.rebuild(heap, entry.as_slice()));
+        }
+
+        for (index, entry) in entries.iter().enumerate() {
+            try!(loader.fill(heap, index, entry.as_slice()));
+        }
+
+        Ok(loader)
+    }
+
+    fn value(&self, datum: Value) -> Result<Value, SchemeError> {
+        match datum {
+            Value::EmptyList | Value::Integer(_) | Value::Flonum(_) | Value::Boolean(_) => {
+                return Ok(datum);
+            },
+            _ => { },
+        }
+
+        match try!(elements(datum)).as_slice() {
+            [kind, arg] => match try!(tag(kind)).as_slice() {
+                "ref" => match self.objects.get(try!(integer(arg)) as usize) {
+                    Some(&Loaded::Value(ref val)) => Ok(**val),
+                    _                             => Err(malformed("bad object reference")),
+                },
+                "char" => {
+                    ::std::char::from_u32(try!(integer(arg)) as u32)
+                        .map(Value::new_character)
+                        .ok_or_else(|| malformed("bad character"))
+                },
+                "primitive" => {
+                    let name = try!(text(arg));
+                    self.primitives.get(name.as_slice())
+                        .map(|p| Value::Primitive(*p))
+                        .ok_or_else(|| E0213.error(format!("Error: heap image refers to unknown \
+                                                            primitive `{}`", name)))
+                },
+                _ => Err(malformed(format!("bad value {}", datum).as_slice())),
+            },
+            _ => Err(malformed(format!("bad value {}", datum).as_slice())),
+        }
+    }
+
+    fn variable(&self, datum: Value) -> Result<Option<Value>, SchemeError> {
+        if let Value::Symbol(sym) = datum {
+            if sym.as_slice() == "undefined" {
+                return Ok(None);
+            }
+        }
+        self.value(datum).map(Some)
+    }
+
+    fn activation(&self, datum: Value) -> Result<Option<ActivationPtr>, SchemeError> {
+        match datum {
+            Value::Boolean(false) => return Ok(None),
+            Value::Symbol(sym) if sym.as_slice() == "global" => return Ok(Some(*self.global)),
+            _ => { },
+        }
+
+        match try!(elements(datum)).as_slice() {
+            [_, index] => match self.objects.get(try!(integer(index)) as usize) {
+                Some(&Loaded::Activation(ref act)) => Ok(Some(**act)),
+                _                                  => Err(malformed("bad activation reference")),
+            },
+            _ => Err(malformed(format!("bad activation {}", datum).as_slice())),
+        }
+    }
+
+    /// Get the index of a variable in its activation. Globals written by name,
+    /// as in compiled scripts, are looked up in the heap's global environment,
+    /// and defined if they are not there yet.
+    fn coordinate(&self, heap: &mut Heap, datum: Value) -> Result<u32, SchemeError> {
+        if let Value::Bytevector(_) = datum {
+            let id = heap.environment.intern(try!(text(datum)).as_slice());
+            return Ok(match heap.environment.lookup_global(id) {
+                Some(j) => j,
+                None    => heap.environment.define_global(id).1,
+            });
+        }
+        integer(datum).map(|j| j as u32)
+    }
+
+    fn meaning(&self, datum: Value) -> Result<Meaning, SchemeError> {
+        self.meanings.get(try!(integer(datum)) as usize)
+            .map(|m| m.clone())
+            .ok_or_else(|| malformed("bad meaning reference"))
+    }
+
+    fn meaning_list(&self, datum: Value) -> Result<Vec<Meaning>, SchemeError> {
+        let mut meanings = vec!();
+        for m in try!(elements(datum)).into_iter() {
+            meanings.push(try!(self.meaning(m)));
+        }
+        Ok(meanings)
+    }
+
+    /// Allocate the object for the given entry, without filling it in yet.
+    fn allocate(&mut self, heap: &mut Heap, entry: &[Value]) -> Result<(), SchemeError> {
+        let loaded = match entry {
+            [kind, rest..] => match try!(tag(kind)).as_slice() {
+                "cons" => {
+                    let cons = heap.allocate_cons();
+                    Loaded::Value(Rooted::new(heap, Value::Pair(*cons)))
+                },
+                "string" if rest.len() == 1 => {
+                    Loaded::Value(Value::new_string(heap, try!(text(rest[0]))))
+                },
+                "symbol" if rest.len() == 1 => {
+                    Loaded::Value(heap.get_or_create_symbol(try!(text(rest[0]))))
+                },
+                "vector" => Loaded::Value(Value::new_vector(heap, &[])),
+                "bytevector" => match rest {
+                    [Value::Bytevector(bv)] => {
+                        Loaded::Value(Value::new_bytevector(heap, (*bv).clone()))
+                    },
+                    _ => return Err(malformed("bad bytevector")),
+                },
+                "activation" => Loaded::Activation(heap.allocate_activation()),
+                "procedure" => {
+                    // A procedure must always have an activation, even before
+                    // it is filled in.
+                    let mut procedure = heap.allocate_procedure();
+                    procedure.act = Some(*self.global);
+                    Loaded::Value(Rooted::new(heap, Value::Procedure(*procedure)))
+                },
+                kind => return Err(malformed(format!("bad object `{}`", kind).as_slice())),
+            },
+            [] => return Err(malformed("empty object")),
+        };
+        self.objects.push(loaded);
+        Ok(())
+    }
+
+    /// Fill in the previously allocated object with the given index from its
+    /// entry.
+    fn fill(&self, heap: &mut Heap, index: usize, entry: &[Value]) -> Result<(), SchemeError> {
+        let object = match self.objects[index] {
+            Loaded::Value(ref val)        => **val,
+            Loaded::Activation(ref act) => {
+                let mut act = **act;
+                return match entry {
+                    [_, parent, captured, vals] => {
+                        let parent = try!(self.activation(parent));
+                        let mut values = vec!();
+                        for val in try!(elements(vals)).into_iter() {
+                            values.push(try!(self.variable(val)));
+                        }
+                        act.restore(parent, values, try!(flag(captured)));
+                        Ok(())
+                    },
+                    _ => Err(malformed("bad activation")),
+                };
+            },
+        };
+
+        match (object, entry) {
+            (Value::Pair(mut cons), [_, car, cdr]) => {
+                let car = Rooted::new(heap, try!(self.value(car)));
+                let cdr = Rooted::new(heap, try!(self.value(cdr)));
+                cons.set_car(&car);
+                cons.set_cdr(&cdr);
+            },
+            (Value::Vector(mut vector), [_, elems]) => {
+                let mut rooted = vec!();
+                for element in try!(elements(elems)).into_iter() {
+                    rooted.push(Rooted::new(heap, try!(self.value(element))));
+                }
+                vector.set_elements(rooted.as_slice());
+            },
+            (Value::Procedure(mut procedure), [_, arity, act, body, name, loc]) => {
+                procedure.arity = try!(integer(arity)) as u32;
+                procedure.act = Some(try!(try!(self.activation(act))
+                    .ok_or_else(|| malformed("procedure without an activation"))));
+                procedure.body = match body {
+                    Value::Boolean(false) => None,
+                    _                     => Some(Box::new(try!(self.meaning(body)))),
+                };
+                procedure.name = try!(optional_text(name));
+                procedure.location = try!(read_location(loc));
+                procedure.signature = None;
+            },
+            (Value::String(_), _) | (Value::Symbol(_), _) | (Value::Bytevector(_), _) => { },
+            _ => return Err(malformed(format!("bad object {}", object).as_slice())),
+        }
+        Ok(())
+    }
+
+    /// Rebuild the meaning for the given entry. Its children always come
+    /// before it.
+    fn rebuild(&mut self, heap: &mut Heap, entry: &[Value]) -> Result<(), SchemeError> {
+        let (kind, loc, rest) = match entry {
+            [kind, loc, rest..] => (try!(tag(kind)), try!(read_location(loc)), rest),
+            _                   => return Err(malformed("bad meaning")),
+        };
+
+        let mut tail = false;
+        let data = match (kind.as_slice(), rest) {
+            ("quotation", [val]) => {
+                MeaningData::Quotation(Rooted::new(heap, try!(self.value(val))))
+            },
+            ("reference", [i, j, name]) => {
+                MeaningData::Reference(try!(integer(i)) as u32,
+                                       try!(self.coordinate(heap, j)),
+                                       heap.environment.intern(try!(text(name)).as_slice()))
+            },
+            ("global-reference", [j, name]) => {
+                MeaningData::GlobalReference(self.global.clone(),
+                                             try!(self.coordinate(heap, j)),
+                                             heap.environment.intern(
+                                                 try!(text(name)).as_slice()))
+            },
+            ("definition", [i, j, val]) => {
+                MeaningData::Definition(try!(integer(i)) as u32,
+                                        try!(self.coordinate(heap, j)),
+                                        try!(self.meaning(val)))
+            },
+            ("set-variable", [i, j, val]) => {
+                MeaningData::SetVariable(try!(integer(i)) as u32,
+                                         try!(self.coordinate(heap, j)),
+                                         try!(self.meaning(val)))
+            },
+            ("conditional", [condition, consequent, alternative]) => {
+                MeaningData::Conditional(try!(self.meaning(condition)),
+                                         try!(self.meaning(consequent)),
+                                         try!(self.meaning(alternative)))
+            },
+            ("sequence", [first, second]) => {
+                MeaningData::Sequence(try!(self.meaning(first)), try!(self.meaning(second)))
+            },
+            ("lambda", [arity, body, name, lambda_loc]) => {
+                MeaningData::Lambda(try!(integer(arity)) as u32,
+                                    try!(self.meaning(body)),
+                                    try!(optional_text(name)),
+                                    try!(read_location(lambda_loc)),
+                                    None)
+            },
+            ("flat-lambda", [arity, body, name, lambda_loc, captures]) => {
+                let mut captured = vec!();
+                for capture in try!(elements(captures)).into_iter() {
+                    match try!(elements(capture)).as_slice() {
+                        [i, j, name] => captured.push((
+                            try!(integer(i)) as u32,
+                            try!(self.coordinate(heap, j)),
+                            heap.environment.intern(try!(text(name)).as_slice()))),
+                        _ => return Err(malformed("bad capture")),
+                    }
+                }
+                MeaningData::FlatLambda(try!(integer(arity)) as u32,
+                                        try!(self.meaning(body)),
+                                        try!(optional_text(name)),
+                                        try!(read_location(lambda_loc)),
+                                        captured,
+                                        None)
+            },
+            ("invocation", [is_tail, procedure, params, call_site]) => {
+                tail = try!(flag(is_tail));
+                let procedure = try!(self.meaning(procedure));
+                let call_site = try!(read_location(call_site));
+                let mut params = try!(self.meaning_list(params)).into_iter();
+                match (params.next(), params.next(), params.next(), params.len()) {
+                    (None, _, _, _) => MeaningData::Invocation0(procedure, call_site),
+                    (Some(a), None, _, _) => MeaningData::Invocation1(procedure, a, call_site),
+                    (Some(a), Some(b), None, _) => {
+                        MeaningData::Invocation2(procedure, a, b, call_site)
+                    },
+                    (Some(a), Some(b), Some(c), 0) => {
+                        MeaningData::Invocation3(procedure, a, b, c, call_site)
+                    },
+                    (Some(a), Some(b), Some(c), _) => {
+                        let mut all = vec!(a, b, c);
+                        all.extend(params);
+                        MeaningData::Invocation(procedure, all, call_site)
+                    },
+                }
+            },
+            ("let", [params, body, call_site]) => {
+                MeaningData::Let(try!(self.meaning_list(params)),
+                                 try!(self.meaning(body)),
+                                 try!(read_location(call_site)))
+            },
+            ("inlined-invocation", [procedure, params, inlined, body, call_site]) => {
+                MeaningData::InlinedInvocation(try!(self.meaning(procedure)),
+                                               try!(self.meaning_list(params)),
+                                               Rooted::new(heap, try!(self.value(inlined))),
+                                               try!(self.meaning(body)),
+                                               try!(read_location(call_site)))
+            },
+            ("primitive-invocation", [procedure, primitive, params, call_site]) => {
+                let primitive = try!(self.value(primitive));
+                let fast_path = match primitive {
+                    Value::Primitive(p) => FastPath::from_name(p.name()),
+                    _                   => None,
+                };
+                let fast_path = try!(fast_path.ok_or_else(|| malformed("bad fast path")));
+                MeaningData::PrimitiveInvocation(try!(self.meaning(procedure)),
+                                                 Rooted::new(heap, primitive),
+                                                 fast_path,
+                                                 try!(self.meaning_list(params)),
+                                                 try!(read_location(call_site)))
+            },
+            _ => return Err(malformed(format!("bad meaning `{}`", kind).as_slice())),
+        };
+
+        let meaning = Meaning::from_data(heap.meaning_unit(), data, loc, tail);
+        self.meanings.push(meaning);
+        Ok(())
+    }
+}
+
+/// Load the image read from the given reader into the given heap, replacing its
+/// global environment. The heap should be freshly created, with the same
+/// primitives as the heap the image was saved from.
+pub fn load<R: Reader>(heap: &mut Heap, reader: R) -> Result<(), SchemeError> {
+    let (_image, sections) = try!(read_datum(heap, reader, "oxischeme-image"));
+    let sections = sections.as_slice();
+    let loader = try!(ImageReader::new(heap, sections));
+
+    let mut globals = vec!();
+    for global in try!(section(sections, "globals")).into_iter() {
+        match try!(elements(global)).as_slice() {
+            [name, j] => {
+                globals.push((heap.environment.intern(try!(text(name)).as_slice()),
+                              try!(integer(j)) as u32));
+            },
+            _ => return Err(malformed("bad global")),
+        }
+    }
+    let mut values = vec!();
+    for val in try!(section(sections, "global-values")).into_iter() {
+        values.push(try!(loader.variable(val)));
+    }
+    heap.environment.restore_globals(globals);
+    let mut global = *loader.global;
+    let captured = global.is_captured();
+    global.restore(None, values, captured);
+
+    for entry in try!(section(sections, "macros")).into_iter() {
+        match try!(elements(entry)).as_slice() {
+            [name, transformer] => {
+                let transformer = Rooted::new(heap, try!(loader.value(transformer)));
+                heap.define_macro(try!(text(name)), transformer);
+            },
+            _ => return Err(malformed("bad macro")),
+        }
+    }
+
+    for entry in try!(section(sections, "definitions")).into_iter() {
+        match try!(elements(entry)).as_slice() {
+            [name, loc] => heap.record_definition(try!(text(name)), try!(read_location(loc))),
+            _           => return Err(malformed("bad definition")),
+        }
+    }
+
+    match try!(section(sections, "prelude")).as_slice() {
+        [loaded] => if try!(flag(loaded)) {
+            heap.mark_prelude_loaded();
+        },
+        _ => return Err(malformed("bad prelude flag")),
+    }
+
+    Ok(())
+}
+
+// COMPILED SCRIPTS ------------------------------------------------------------
+
+/// A script that has already been read and analyzed, so that running it skips
+/// straight to evaluation. See `Heap::compile_file` and `Heap::run_compiled`.
+///
+/// A compiled script uses the same encoding as an image, with the meaning of
+/// each top-level form listed in a `forms` section, and is conventionally saved
+/// with the `.scmc` extension:
+///
+///     (oxischeme-script 1
+///      (objects ...)
+///      (meanings ...)
+///      (forms 3 7 ...))
+///
+/// Globals are referred to by name rather than by index, so a compiled script
+/// can run in any heap with the same primitives and macros as the heap that
+/// compiled it.
+pub struct CompiledScript {
+    text: String,
+}
+
+impl CompiledScript {
+    /// Write this compiled script to the given writer.
+    pub fn save<W: Writer>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_str(self.text.as_slice())
+    }
+
+    /// Read a compiled script previously written by `save` from the given
+    /// reader. It is not checked until it is run.
+    pub fn load<R: Reader>(reader: &mut R) -> IoResult<CompiledScript> {
+        let text = try!(reader.read_to_string());
+        Ok(CompiledScript { text: text })
+    }
+}
+
+/// Read and analyze every form in the file at the given path. Macros are
+/// expanded at compile time, so any macros the script uses must be defined in
+/// this heap or by the script itself.
+pub fn compile_file(heap: &mut Heap, path: &str) -> Result<CompiledScript, SchemeError> {
+    try!(ensure_prelude(heap));
+
+    let reader = try!(read_from_file(path, heap).map_err(|e| {
+        format!("Error: compile_file could not read {}: {}", path, e)
+    }));
+    let mut forms = vec!();
+    for (location, read_result) in reader {
+        let form = try!(read_result);
+        forms.push(try!(analyze(heap, &form, location)));
+    }
+
+    let global = heap.global_activation();
+    let names = heap.environment.globals().into_iter()
+        .map(|(name, j)| (j, name))
+        .collect();
+    let mut script = ImageWriter::new(*global,
+                                      Some(names),
+                                      heap.environment.names().clone(),
+                                      heap.primitive_table.by_name());
+
+    let mut indices = vec!();
+    for form in forms.iter() {
+        indices.push(format!("{}", try!(script.meaning(form, 0))));
+    }
+    let objects = try!(script.objects());
+
+    let mut out = String::new();
+    out.push_str(format!("(oxischeme-script {}\n", IMAGE_VERSION).as_slice());
+    script.write_sections(&mut out, objects.as_slice());
+    out.push_str(format!("\n (forms {}))\n", indices.connect(" ")).as_slice());
+    Ok(CompiledScript { text: out })
+}
+
+/// Run the given compiled script in the global environment of the given heap,
+/// and return the value of its last form.
+pub fn run_compiled(heap: &mut Heap, script: &CompiledScript) -> SchemeResult {
+    try!(ensure_prelude(heap));
+
+    let reader = MemReader::new(script.text.clone().into_bytes());
+    let (_script, sections) = try!(read_datum(heap, reader, "oxischeme-script"));
+    let loader = try!(ImageReader::new(heap, sections.as_slice()));
+
+    let mut result = Rooted::new(heap, Value::EmptyList);
+    for form in try!(section(sections.as_slice(), "forms")).into_iter() {
+        let meaning = try!(loader.meaning(form));
+        result.emplace(*try!(evaluate_meaning(heap, &meaning).map_err(|e| e.to_scheme_error())));
+    }
+    Ok(result)
+}
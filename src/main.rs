@@ -23,69 +23,401 @@
 #![feature(unicode)]
 #![feature(unsafe_destructor)]
 
+extern crate time;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
 use std::old_io;
 use std::env;
 
+pub mod channel;
 pub mod environment;
+pub mod errors;
 pub mod eval;
+pub mod generator;
 pub mod heap;
+pub mod image;
+pub mod port;
 pub mod primitives;
+pub mod random;
 pub mod read;
+pub mod repl;
+#[cfg(feature = "serde")]
+pub mod serialization;
+pub mod tools;
+pub mod transcript;
 pub mod value;
+pub mod vm;
 
-/// Start a Read -> Evaluate -> Print loop.
-pub fn repl(heap: &mut heap::Heap) {
-    println!("Welcome to oxischeme!");
-    println!("C-c to exit.");
-    println!("");
+/// The interactive debugger used by the REPL. Whenever a breakpoint set with
+/// `set-breakpoint!` is hit, print where we stopped and read debugger commands
+/// from stdin until told to step or continue.
+fn repl_debugger(heap: &mut heap::Heap,
+                 act: &environment::RootedActivationPtr,
+                 meaning: &eval::Meaning) -> eval::DebugAction {
+    println!("Stopped at {}", meaning.location());
+    println!("{}", eval::disassemble(&heap.environment, meaning));
 
+    let mut stdin = old_io::stdio::stdin();
     loop {
-        let stdin = old_io::stdio::stdin();
-        let reader = read::Read::new(stdin, heap, "stdin".to_string());
-
-        print!("oxischeme> ");
-        for (location, read_result) in reader {
-            match read_result {
-                Err(msg) => {
-                    println!("{}", msg);
-                    break;
-                },
-                Ok(form) => {
-                    match eval::evaluate(heap, &form, location) {
-                        Ok(val) => println!("{}", *val),
-                        Err(e)  => println!("{}", e),
-                    };
+        print!("debug> ");
+        let line = match stdin.read_line() {
+            Ok(line) => line,
+            Err(_)   => return eval::DebugAction::Continue,
+        };
 
+        match line.trim() {
+            "s" | "step"     => return eval::DebugAction::Step,
+            "c" | "continue" => return eval::DebugAction::Continue,
+            "a" | "activation" => println!("{:?}", **act),
+            "bt" | "backtrace" => {
+                for (i, frame) in heap.backtrace_since(0).iter().enumerate() {
+                    println!("    {}: {}", i, frame);
                 }
+            },
+            _ => {
+                println!("Commands:");
+                println!("    s, step          Stop again before the next form.");
+                println!("    c, continue      Run until the next breakpoint.");
+                println!("    a, activation    Print the current activation.");
+                println!("    bt, backtrace    Print the call stack.");
+            },
+        }
+    }
+}
+
+/// The startup image: a heap image with the primitives defined and the prelude
+/// evaluated, built by `build.rs`. Loading it is much faster than evaluating
+/// the prelude from source.
+static STARTUP_IMAGE: &'static [u8] = include_bytes!(concat!(env!("OUT_DIR"),
+                                                             "/startup.image"));
+
+/// Load the startup image into the given fresh heap. If it cannot be loaded,
+/// report why, and the prelude is evaluated from source as usual instead.
+fn load_startup_image(heap: &mut heap::Heap) {
+    let reader = old_io::MemReader::new(STARTUP_IMAGE.to_vec());
+    if let Err(msg) = heap.load_image(reader) {
+        report_error(format!("Error: could not load the startup image: {}", msg).as_slice());
+    }
+}
+
+/// The file in the user's home directory that every line of REPL input is
+/// appended to.
+static HISTORY_FILE: &'static str = ".oxischeme_history";
+
+/// The Scheme file in the user's home directory that is evaluated before the
+/// REPL starts.
+static RC_FILE: &'static str = ".oxischemerc";
+
+/// The REPL's settings given on the command line.
+#[derive(Copy, Clone, Debug)]
+struct ReplOptions {
+    /// Whether to append input to the history file.
+    history: bool,
+    /// Whether to evaluate the rc file at startup.
+    rc: bool,
+}
+
+/// Start a Read -> Evaluate -> Print loop on stdin and stdout. If a transcript
+/// writer is given, every form read is recorded to it, and if a history file is
+/// given, every line of input is appended to it. See `repl::Repl`.
+///
+/// Ending a line with a tab lists the completions of the name at the end of
+/// the line, instead of reading it.
+pub fn run_repl(heap: &mut heap::Heap,
+                transcript: Option<transcript::TranscriptWriter>,
+                mut history: Option<old_io::File>) {
+    heap.set_debugger(Some(Box::new(repl_debugger)));
+    let mut session = repl::Repl::new(heap);
+    if let Some(writer) = transcript {
+        session.set_transcript(writer);
+    }
+
+    println!("{}", session.banner());
+    print!("{}", session.prompt());
+
+    let mut stdin = old_io::stdio::stdin();
+    loop {
+        let line = match stdin.read_line() {
+            Ok(line) => line,
+            Err(_)   => {
+                let response = session.finish();
+                print_response(session.heap(), &response);
+                return;
+            },
+        };
+
+        if let Some(text) = completion_request(line.as_slice()) {
+            println!("{}", session.complete(text).connect(" "));
+            print!("{}", session.prompt());
+            continue;
+        }
+
+        if let Some(ref mut file) = history {
+            if let Err(e) = file.write_str(line.as_slice()).and_then(|_| file.flush()) {
+                println!("Error: could not write history: {}", e);
             }
+        }
 
-            heap.collect_garbage();
-            print!("oxischeme> ");
+        let response = session.feed_line(line.as_slice());
+        if print_response(session.heap(), &response) {
+            return;
         }
+        print!("{}", response.prompt);
+    }
+}
+
+/// Print the output of a line of REPL input. If Scheme code asked to end the
+/// program, set the exit status it asked for and return true. Otherwise,
+/// return false.
+fn print_response(heap: &mut heap::Heap, response: &repl::ReplResponse) -> bool {
+    for output in response.outputs.iter() {
+        match *output {
+            repl::ReplOutput::Value(ref text)   |
+            repl::ReplOutput::Warning(ref text) |
+            repl::ReplOutput::Error(ref text)   => println!("{}", text),
+        }
+    }
+
+    if let Some(request) = response.exit {
+        exit_with(heap, request);
+        return true;
+    }
+    false
+}
+
+/// If the given line of REPL input ends with a tab, return the text before the
+/// tab. Standard input is read a line at a time, without a line editor, so a
+/// completion is asked for by typing Tab and then Enter.
+fn completion_request(line: &str) -> Option<&str> {
+    let line = line.trim_right_matches('\n');
+    if line.ends_with("\t") {
+        Some(line.trim_right_matches('\t'))
+    } else {
+        None
+    }
+}
+
+/// Get the path of the file with the given name in the user's home directory.
+fn home_file(name: &str) -> Option<Path> {
+    env::home_dir().map(|home| home.join(name))
+}
+
+/// Open the history file to append REPL input to, creating it if it does not
+/// exist yet.
+fn open_history() -> Option<old_io::File> {
+    let path = match home_file(HISTORY_FILE) {
+        Some(path) => path,
+        None       => return None,
+    };
+
+    match old_io::File::open_mode(&path, old_io::FileMode::Append, old_io::FileAccess::Write) {
+        Ok(file) => Some(file),
+        Err(e)   => {
+            report_error(format!("Error: could not open {}: {}", path.display(), e).as_slice());
+            None
+        },
+    }
+}
+
+/// Evaluate the rc file, if it exists, reporting any error in it. Return true
+/// if it asked to end the program with `exit`, false otherwise.
+fn load_rc(heap: &mut heap::Heap) -> bool {
+    let path = match home_file(RC_FILE) {
+        Some(ref path) if path.exists() => path.display().to_string(),
+        _                               => return false,
+    };
+
+    if let Err(msg) = eval::evaluate_file(heap, path.as_slice()) {
+        if handle_exit(heap) {
+            return true;
+        }
+        report_error(msg.as_slice());
+    }
+    false
+}
+
+/// Start the REPL with the given options, after evaluating the rc file unless
+/// it is disabled.
+fn start_repl(heap: &mut heap::Heap,
+              options: ReplOptions,
+              transcript: Option<transcript::TranscriptWriter>) {
+    if options.rc && load_rc(heap) {
+        return;
     }
+    let history = if options.history { open_history() } else { None };
+    run_repl(heap, transcript, history);
 }
 
-/// Given no arguments, start the REPL. Otherwise, treat each argument as a file
-/// path and read and evaluate each of them in turn.
+/// If Scheme code asked to end the program with `exit` or `emergency-exit`, set
+/// the exit status it asked for and return true. Otherwise, return false. A
+/// normal `exit` collects garbage first, so that pending finalizers run.
+fn handle_exit(heap: &mut heap::Heap) -> bool {
+    match heap.take_exit_request() {
+        Some(request) => {
+            exit_with(heap, request);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Set the exit status asked for by the given request, after collecting
+/// garbage unless it is an emergency exit.
+fn exit_with(heap: &mut heap::Heap, request: heap::ExitRequest) {
+    if !request.emergency {
+        heap.collect_garbage();
+    }
+    env::set_exit_status(request.status);
+}
+
+/// Print the given error message to stderr.
+fn report_error(msg: &str) {
+    let mut stderr = old_io::stdio::stderr();
+    (write!(&mut stderr, "{}\n", msg)).ok().expect("IO ERROR!");
+}
+
+/// Read and evaluate each form in the given string, printing the value of the
+/// last one.
+fn evaluate_expression(heap: &mut heap::Heap, expr: &str) -> Result<(), String> {
+    heap.record_source("<command line>", expr);
+    let reader = read::read_from_str(expr, heap, "<command line>");
+
+    let mut result = None;
+    for (location, read_result) in reader {
+        let form = try!(read_result);
+        result = Some(try!(eval::evaluate(heap, &form, location)));
+    }
+
+    if let Some(val) = result {
+        println!("{}", *val);
+    }
+    Ok(())
+}
+
+/// Print usage information.
+fn usage() {
+    println!("Usage:");
+    println!("    oxischeme [OPTION ...]           Start the REPL.");
+    println!("    oxischeme SCRIPT [ARG ...]       Evaluate SCRIPT with the given arguments.");
+    println!("    oxischeme -e EXPR [ARG ...]      Evaluate EXPR and print its value.");
+    println!("    oxischeme --record TRANSCRIPT    Start the REPL, recording input to TRANSCRIPT.");
+    println!("    oxischeme --replay TRANSCRIPT    Deterministically replay TRANSCRIPT.");
+    println!("    oxischeme --explain CODE         Explain the error with the given code.");
+    println!("");
+    println!("Options:");
+    println!("    --no-history                     Do not append REPL input to ~/{}.",
+             HISTORY_FILE);
+    println!("    --no-rc                          Do not evaluate ~/{} at startup.", RC_FILE);
+    println!("");
+    println!("Scripts may start with a `#!/usr/bin/env oxischeme` line, so that they can be");
+    println!("made executable and run directly.");
+}
+
+/// Given no arguments, start the REPL. Given `-e EXPR`, evaluate the expression
+/// and print its value. Otherwise, treat the first argument as the path to a
+/// script to evaluate, and the rest as arguments to that script, which are
+/// available via `(command-line)`. If the expression or script fails, the exit
+/// status is non-zero, so that executable scripts can be used from the shell.
+/// Scripts can also choose their exit status by calling `exit`.
+///
+/// The REPL evaluates `~/.oxischemerc` before it starts, and appends every line
+/// of input to `~/.oxischeme_history`, unless given `--no-rc` or
+/// `--no-history` respectively. A REPL recording a transcript does not
+/// evaluate the rc file, because replaying the transcript would not.
 pub fn main() {
     let heap = &mut heap::Heap::new();
+    load_startup_image(heap);
 
-    let mut args_were_passed = false;
+    let mut args : Vec<String> = env::args().collect();
+    let mut options = ReplOptions { history: true, rc: true };
+    while args.len() > 1 {
+        match args[1].as_slice() {
+            "--no-history" => options.history = false,
+            "--no-rc"      => options.rc = false,
+            _              => break,
+        }
+        args.remove(1);
+    }
 
-    for file_path in env::args().skip(1) {
-        args_were_passed = true;
+    if args.len() < 2 {
+        heap.set_command_line(args);
+        start_repl(heap, options, None);
+        return;
+    }
 
-        match eval::evaluate_file(heap, file_path.as_slice()) {
-            Ok(_) => { },
-            Err(msg) => {
-                let mut stderr = old_io::stdio::stderr();
-                (write!(&mut stderr, "{}", msg)).ok().expect("IO ERROR!");
+    match args[1].as_slice() {
+        "-h" | "--help" => usage(),
+
+        "-e" => {
+            if args.len() < 3 {
+                report_error("Error: -e requires an expression to evaluate");
+                usage();
                 return;
             }
-        }
-    }
 
-    if !args_were_passed {
-        repl(heap);
+            let mut command_line = vec!(args[0].clone());
+            command_line.push_all(&args[3..]);
+            heap.set_command_line(command_line);
+
+            if let Err(msg) = evaluate_expression(heap, args[2].as_slice()) {
+                if !handle_exit(heap) {
+                    report_error(msg.as_slice());
+                    env::set_exit_status(1);
+                }
+            }
+        },
+
+        "--explain" => {
+            if args.len() != 3 {
+                report_error("Error: --explain requires an error code, such as E0201");
+                usage();
+                return;
+            }
+
+            match errors::explain(args[2].as_slice()) {
+                Some(entry) => println!("{}: {}", entry.code, entry.explanation),
+                None        => report_error(format!("Error: unknown error code {}",
+                                                    args[2]).as_slice()),
+            }
+        },
+
+        "--record" | "--replay" => {
+            if args.len() != 3 {
+                report_error("Error: expected exactly one transcript file");
+                usage();
+                return;
+            }
+
+            heap.set_command_line(vec!(args[0].clone()));
+
+            if args[1].as_slice() == "--replay" {
+                if let Err(msg) = transcript::replay(heap, args[2].as_slice()) {
+                    report_error(msg.as_slice());
+                }
+                return;
+            }
+
+            match transcript::TranscriptWriter::create(args[2].as_slice(), heap) {
+                Ok(writer) => {
+                    start_repl(heap, ReplOptions { rc: false, .. options }, Some(writer))
+                },
+                Err(e) => report_error(format!("Error: could not create transcript {}: {}",
+                                               args[2],
+                                               e).as_slice()),
+            }
+        },
+
+        _ => {
+            heap.set_command_line(args[1..].to_vec());
+            if let Err(msg) = eval::evaluate_file(heap, args[1].as_slice()) {
+                if !handle_exit(heap) {
+                    report_error(msg.as_slice());
+                    env::set_exit_status(1);
+                }
+            }
+        },
     }
 }
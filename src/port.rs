@@ -0,0 +1,258 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ports: streams of characters that Scheme code reads data and lines of text
+//! from, or writes text to.
+//!
+//! The standard input and output of a process spawned with `run-process` are
+//! ports, and so are TCP connections, which can be both read and written. Input
+//! ports are read a line at a time, as the input arrives, so
+//! reading a datum only waits for the lines it spans rather than for the end
+//! of the stream:
+//!
+//!     (define cat (run-process "cat"))
+//!     (format (process-input cat) "(hello ~a)~%" 1)
+//!     (read (process-output cat))  ;; => (hello 1)
+//!     (close-port (process-input cat))
+//!
+//! A port's streams are closed by `close-port`, or when the port is collected:
+//! the collector drops native objects during its sweep, and dropping a port
+//! closes its descriptors.
+
+use std::old_io::{self, BufferedReader, IoResult};
+use std::old_io::net::tcp::{TcpStream};
+use std::old_io::pipe::{PipeStream};
+
+use errors::{SchemeError};
+use heap::{Heap};
+use read::{IncrementalRead, ReadStatus};
+use value::{RootedValue};
+
+/// The tag of the native objects wrapping ports.
+pub static PORT_TAG : &'static str = "port";
+
+/// The stream an input port reads from.
+enum Source {
+    /// The standard output of a process.
+    Pipe(BufferedReader<PipeStream>),
+    /// The receiving half of a TCP connection.
+    Tcp(BufferedReader<TcpStream>),
+}
+
+impl Source {
+    fn read_line(&mut self) -> IoResult<String> {
+        match *self {
+            Source::Pipe(ref mut pipe)  => pipe.read_line(),
+            Source::Tcp(ref mut stream) => stream.read_line(),
+        }
+    }
+}
+
+/// The stream an output port writes to.
+enum Sink {
+    /// The standard input of a process.
+    Pipe(PipeStream),
+    /// The sending half of a TCP connection.
+    Tcp(TcpStream),
+}
+
+impl Sink {
+    fn write_str(&mut self, string: &str) -> IoResult<()> {
+        match *self {
+            Sink::Pipe(ref mut pipe)  => pipe.write_str(string).and_then(|_| pipe.flush()),
+            Sink::Tcp(ref mut stream) => stream.write_str(string).and_then(|_| stream.flush()),
+        }
+    }
+}
+
+/// An input port, an output port, or both.
+pub struct Port {
+    /// The stream to read from, or `None` if this is not an input port or its
+    /// input has been closed.
+    input: Option<Source>,
+    /// The lines that have been read from `input` but not consumed by `read`
+    /// or `read_line` yet.
+    pending: IncrementalRead,
+    /// The stream to write to, or `None` if this is not an output port or its
+    /// output has been closed.
+    output: Option<Sink>,
+}
+
+impl Port {
+    /// Create an input port reading from the given pipe. The `name` is used for
+    /// the locations of the data read.
+    pub fn pipe_input(pipe: PipeStream, name: &str) -> Port {
+        Port {
+            input: Some(Source::Pipe(BufferedReader::new(pipe))),
+            pending: IncrementalRead::new(name),
+            output: None,
+        }
+    }
+
+    /// Create an output port writing to the given pipe.
+    pub fn pipe_output(pipe: PipeStream) -> Port {
+        Port {
+            input: None,
+            pending: IncrementalRead::new(""),
+            output: Some(Sink::Pipe(pipe)),
+        }
+    }
+
+    /// Create a port reading from and writing to the given TCP connection. The
+    /// `name` is used for the locations of the data read.
+    pub fn tcp(stream: TcpStream, name: &str) -> Port {
+        Port {
+            input: Some(Source::Tcp(BufferedReader::new(stream.clone()))),
+            pending: IncrementalRead::new(name),
+            output: Some(Sink::Tcp(stream)),
+        }
+    }
+
+    /// Wrap this port in a native object, so that Scheme code in the given
+    /// heap can use it.
+    pub fn to_value(self, heap: &mut Heap) -> RootedValue {
+        heap.new_native(PORT_TAG, Box::new(self))
+    }
+
+    /// Read the next datum, waiting for as many lines of input as it spans.
+    /// Returns `None` at the end of the input.
+    pub fn read(&mut self, heap: &mut Heap) -> Result<Option<RootedValue>, SchemeError> {
+        loop {
+            match self.pending.read(heap) {
+                ReadStatus::Datum(_, form) => return Ok(Some(form)),
+                ReadStatus::Error(error)   => return Err(error),
+                ReadStatus::NeedMoreInput  => { },
+            }
+
+            if !try!(self.fill()) && !self.pending.has_pending_input() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Read the rest of the current line, without its newline. Returns `None`
+    /// at the end of the input.
+    pub fn read_line(&mut self) -> Result<Option<String>, SchemeError> {
+        let line = match self.pending.take_line() {
+            Some(line) => Some(line),
+            None       => if try!(self.fill()) { self.pending.take_line() } else { None },
+        };
+        Ok(line.map(|mut line| {
+            if line.ends_with("\n") {
+                line.pop();
+            }
+            line
+        }))
+    }
+
+    /// Read the next line of input into `pending`. Returns false at the end of
+    /// the input.
+    fn fill(&mut self) -> Result<bool, SchemeError> {
+        let input = try!(self.input.as_mut().ok_or_else(|| {
+            SchemeError::new("Error: cannot read from a port that is not open for input"
+                             .to_string())
+        }));
+        match input.read_line() {
+            Ok(line) => {
+                self.pending.feed(line.as_slice());
+                Ok(true)
+            },
+            Err(ref e) if e.kind == old_io::EndOfFile => {
+                self.pending.finish();
+                Ok(false)
+            },
+            Err(e) => Err(SchemeError::new(format!("Error: reading from a port failed: {}", e))),
+        }
+    }
+
+    /// Write the given string, and flush it so that it is sent right away.
+    pub fn write_str(&mut self, string: &str) -> Result<(), SchemeError> {
+        let output = try!(self.output.as_mut().ok_or_else(|| {
+            SchemeError::new("Error: cannot write to a port that is not open for output"
+                             .to_string())
+        }));
+        output.write_str(string)
+            .map_err(|e| SchemeError::new(format!("Error: writing to a port failed: {}", e)))
+    }
+
+    /// Close both of this port's streams. Closing the standard input of a
+    /// process lets it see the end of its input, and closing a TCP connection
+    /// closes its socket once both halves are closed. Closing a closed port has
+    /// no effect.
+    pub fn close(&mut self) {
+        self.input.take();
+        self.output.take();
+    }
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::old_io::{Acceptor, Listener};
+    use std::old_io::net::tcp::{TcpListener, TcpStream};
+    use std::old_io::process::{Command};
+    use heap::{Heap};
+
+    #[test]
+    fn test_port_reads_as_input_arrives() {
+        let heap = &mut Heap::new();
+        let mut cat = Command::new("cat").spawn().ok().expect("Should be able to run cat.");
+        let mut input = Port::pipe_output(cat.stdin.take().unwrap());
+        let mut output = Port::pipe_input(cat.stdout.take().unwrap(), "cat");
+
+        // Each datum can be read before the process's input is closed.
+        input.write_str("(1 2\n3) \"a line\"\n").ok().expect("Should be able to write.");
+        let form = output.read(heap).ok().expect("Should be able to read.");
+        assert_eq!(format!("{}", *form.unwrap()), "(1 2 3)");
+        assert_eq!(output.read_line().ok().expect("Should be able to read a line."),
+                   Some(" \"a line\"".to_string()));
+
+        input.write_str("foo").ok().expect("Should be able to write.");
+        input.close();
+        let form = output.read(heap).ok().expect("Should be able to read.");
+        assert_eq!(format!("{}", *form.unwrap()), "foo");
+        assert!(output.read(heap).ok().expect("Should be at the end.").is_none());
+        assert_eq!(output.read_line().ok().expect("Should be at the end."), None);
+        assert!(input.write_str("bar").is_err());
+    }
+
+    #[test]
+    fn test_port_closed_when_collected() {
+        let heap = &mut Heap::new();
+        let mut acceptor = TcpListener::bind(("127.0.0.1", 0))
+            .and_then(|listener| listener.listen())
+            .ok()
+            .expect("Should be able to listen.");
+        let port = acceptor.socket_name().ok().expect("Should have a socket name.").port;
+        let stream = TcpStream::connect(("127.0.0.1", port))
+            .ok()
+            .expect("Should be able to connect.");
+        let mut server = acceptor.accept().ok().expect("Should be able to accept.");
+        server.set_read_timeout(Some(5000));
+
+        {
+            let mut client = Port::tcp(stream, "client");
+            client.write_str("ping\n").ok().expect("Should be able to write.");
+            let val = client.to_value(heap);
+            assert_eq!(format!("{}", *val), "#<native port>");
+        }
+
+        // The server only sees the end of the input once the unreachable port
+        // has been swept.
+        heap.collect_garbage();
+        assert_eq!(server.read_to_string().ok(), Some("ping\n".to_string()));
+    }
+}
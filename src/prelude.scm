@@ -0,0 +1,462 @@
+;; The oxischeme prelude.
+;;
+;; These definitions are evaluated in every heap before any other code, and
+;; make up the parts of the standard library that are written in Scheme rather
+;; than implemented as primitives in Rust.
+
+;; Return a new list containing the elements of `a` followed by the elements of
+;; `b`.
+(define append
+  (lambda (a b)
+    (if (null? a)
+        b
+        (cons (car a) (append (cdr a) b)))))
+
+;; Quasiquotation:
+;;
+;;     `(a ,b ,@c)
+;;
+;; The reader turns this into `(quasiquote (a (unquote b) (unquote-splicing
+;; c)))`, which expands into code that builds the template, with the value of
+;; `b` in place of `,b` and the elements of the list `c` spliced in place of
+;; `,@c`. Quasiquotes nest: unquotes inside an inner quasiquote belong to it,
+;; and are left in the result unless they are unquoted once for each level.
+;; Vectors in the template are quoted as they are, without unquoting inside
+;; them.
+(define %quasiquote
+  (lambda (template depth)
+    (define tagged?
+      (lambda (x tag)
+        (if (pair? x) (eq? (car x) tag) #f)))
+    (define quote-tagged
+      (lambda (tag depth)
+        (list 'list (list 'quote tag) (%quasiquote (car (cdr template)) depth))))
+    (if (tagged? template 'unquote)
+        (if (= depth 0)
+            (car (cdr template))
+            (quote-tagged 'unquote (- depth 1)))
+        (if (tagged? template 'unquote-splicing)
+            (if (= depth 0)
+                (error "unquote-splicing outside of a list:" template)
+                (quote-tagged 'unquote-splicing (- depth 1)))
+            (if (tagged? template 'quasiquote)
+                (quote-tagged 'quasiquote (+ depth 1))
+                (if (pair? template)
+                    (if (if (tagged? (car template) 'unquote-splicing) (= depth 0) #f)
+                        (list 'append
+                              (car (cdr (car template)))
+                              (%quasiquote (cdr template) depth))
+                        (list 'cons
+                              (%quasiquote (car template) depth)
+                              (%quasiquote (cdr template) depth)))
+                    (list 'quote template)))))))
+
+(define-macro quasiquote
+  (lambda (form)
+    (%quasiquote (car (cdr form)) 0)))
+
+;; Return the list `((proc 0) (proc 1) ... (proc (- n 1)))`.
+(define list-tabulate
+  (lambda (n proc)
+    (define loop
+      (lambda (i acc)
+        (if (< i 0)
+            acc
+            (loop (- i 1) (cons (proc i) acc)))))
+    (loop (- n 1) '())))
+
+;; Return the list of integers from `start` (inclusive) to `end` (exclusive).
+(define range
+  (lambda (start end)
+    (list-tabulate (if (< start end) (- end start) 0)
+                   (lambda (i) (+ start i)))))
+
+;; Loop over a range of integers or the elements of a list:
+;;
+;;     (for (i start end) body ...)
+;;     (for (x in list) body ...)
+;;
+;; The first form binds `i` to each integer from `start` (inclusive) to `end`
+;; (exclusive) in turn, and the second binds `x` to each element of `list` in
+;; turn. Either way, `body ...` is evaluated once per binding. Both forms expand
+;; into a tail-recursive loop, so they run in constant stack space.
+(define-macro for
+  (lambda (form)
+    (define spec (car (cdr form)))
+    (define body (cdr (cdr form)))
+    (define var (car spec))
+    (define iterate
+      (lambda (condition element next)
+        (list 'define '%for-loop
+              (list 'lambda (list '%for-state)
+                    (list 'if condition
+                          (list 'begin
+                                (list (cons 'lambda (cons (list var) body))
+                                      element)
+                                (list '%for-loop next))
+                          ''unspecified)))))
+    (if (eq? (car (cdr spec)) 'in)
+        (list (list 'lambda '()
+                    (iterate '(pair? %for-state)
+                             '(car %for-state)
+                             '(cdr %for-state))
+                    (list '%for-loop (car (cdr (cdr spec))))))
+        (list (list 'lambda (list '%for-end)
+                    (iterate '(< %for-state %for-end)
+                             '%for-state
+                             '(+ %for-state 1))
+                    (list '%for-loop (car (cdr spec))))
+              (car (cdr (cdr spec)))))))
+
+;; Pattern matching:
+;;
+;;     (match expr
+;;       (pattern body ...)
+;;       (pattern (guard test) body ...)
+;;       ...)
+;;
+;; Evaluate `expr`, and then the body of the first clause whose pattern matches
+;; its value (and whose guard `test`, if any, is true), with the pattern's
+;; variables bound. It is an error if no clause matches. Patterns are:
+;;
+;;   * `_`, which matches anything.
+;;   * A symbol, which matches anything and binds it to that variable.
+;;   * A number, boolean, or character literal, or a quoted datum like `'foo`,
+;;     which matches values that are `eq?` to it.
+;;   * `(cons p1 p2)`, which matches a pair whose car matches `p1` and whose cdr
+;;     matches `p2`.
+;;   * `(list p ...)`, which matches a proper list of exactly as many elements,
+;;     each matching the corresponding `p`.
+;;   * `#(p ...)`, which matches a vector of exactly as many elements, each
+;;     matching the corresponding `p`.
+;;   * `(? pred p ...)`, which matches values for which `(pred value)` is true
+;;     and which also match every `p`.
+;;
+;; Each clause expands into its quoted pattern, the predicates used in the
+;; pattern, and procedures for the guard and body that take the pattern's
+;; variables as parameters. The pattern is matched at runtime by `%match`.
+
+;; Look up the value of a `?` pattern's predicate in the alist of predicates
+;; used by a clause. The keys are the predicate expressions from the quoted
+;; pattern itself, so they can be compared with `eq?`.
+(define %match-lookup
+  (lambda (key alist)
+    (if (eq? (car (car alist)) key)
+        (cdr (car alist))
+        (%match-lookup key (cdr alist)))))
+
+;; Match `val` against `pat`. Return the list of bound values consed onto
+;; `bindings` in reverse order, or #f if it does not match.
+(define %match-pattern
+  (lambda (pat val preds bindings)
+    (if (eq? bindings #f)
+        #f
+        (if (symbol? pat)
+            (if (eq? pat '_) bindings (cons val bindings))
+            (if (pair? pat)
+                (if (eq? (car pat) 'quote)
+                    (if (eq? (car (cdr pat)) val) bindings #f)
+                    (if (eq? (car pat) '?)
+                        (if ((%match-lookup (car (cdr pat)) preds) val)
+                            (%match-each (cdr (cdr pat)) val preds bindings)
+                            #f)
+                        (if (eq? (car pat) 'cons)
+                            (if (pair? val)
+                                (%match-pattern (car (cdr (cdr pat)))
+                                                (cdr val)
+                                                preds
+                                                (%match-pattern (car (cdr pat))
+                                                                (car val)
+                                                                preds
+                                                                bindings))
+                                #f)
+                            (%match-list (cdr pat) val preds bindings))))
+                (if (vector? pat)
+                    (if (vector? val)
+                        (%match-list (vector->list pat) (vector->list val) preds bindings)
+                        #f)
+                    (if (eq? pat val) bindings #f)))))))
+
+;; Match `val` against every pattern in `pats`.
+(define %match-each
+  (lambda (pats val preds bindings)
+    (if (null? pats)
+        bindings
+        (%match-each (cdr pats)
+                     val
+                     preds
+                     (%match-pattern (car pats) val preds bindings)))))
+
+;; Match each element of the list `vals` against the corresponding pattern in
+;; `pats`.
+(define %match-list
+  (lambda (pats vals preds bindings)
+    (if (null? pats)
+        (if (null? vals) bindings #f)
+        (if (pair? vals)
+            (%match-list (cdr pats)
+                         (cdr vals)
+                         preds
+                         (%match-pattern (car pats) (car vals) preds bindings))
+            #f))))
+
+;; Try each clause in turn against `val`.
+(define %match
+  (lambda (val clauses)
+    (if (null? clauses)
+        (error "no `match` clause matched" val)
+        (%match-clause val (car clauses) (cdr clauses)))))
+
+(define %match-clause
+  (lambda (val clause rest)
+    (define bindings (%match-pattern (car clause) val (car (cdr clause)) '()))
+    (define guard (car (cdr (cdr clause))))
+    (if (eq? bindings #f)
+        (%match val rest)
+        (if (if guard (apply guard bindings) #t)
+            (apply (car (cdr (cdr (cdr clause)))) bindings)
+            (%match val rest)))))
+
+;; Get the variables bound by `pat`, consed onto `acc` in reverse order. This
+;; must visit patterns in the same order as `%match-pattern`.
+(define %match-vars
+  (lambda (pat acc)
+    (if (symbol? pat)
+        (if (eq? pat '_) acc (cons pat acc))
+        (if (pair? pat)
+            (if (eq? (car pat) 'quote)
+                acc
+                (if (eq? (car pat) '?)
+                    (%match-vars-list (cdr (cdr pat)) acc)
+                    (%match-vars-list (cdr pat) acc)))
+            (if (vector? pat)
+                (%match-vars-list (vector->list pat) acc)
+                acc)))))
+
+(define %match-vars-list
+  (lambda (pats acc)
+    (if (null? pats)
+        acc
+        (%match-vars-list (cdr pats) (%match-vars (car pats) acc)))))
+
+;; Get expressions building the alist of `?` predicates used by `pat`.
+(define %match-preds
+  (lambda (pat acc)
+    (if (pair? pat)
+        (if (eq? (car pat) 'quote)
+            acc
+            (if (eq? (car pat) '?)
+                (%match-preds-list (cdr (cdr pat))
+                                   (cons (list 'cons
+                                               (list 'quote (car (cdr pat)))
+                                               (car (cdr pat)))
+                                         acc))
+                (%match-preds-list (cdr pat) acc)))
+        (if (vector? pat)
+            (%match-preds-list (vector->list pat) acc)
+            acc))))
+
+(define %match-preds-list
+  (lambda (pats acc)
+    (if (null? pats)
+        acc
+        (%match-preds-list (cdr pats) (%match-preds (car pats) acc)))))
+
+(define %match-expand-clause
+  (lambda (clause)
+    (define pat (car clause))
+    (define vars (%match-vars pat '()))
+    (define guarded (if (pair? (cdr clause))
+                        (if (pair? (car (cdr clause)))
+                            (eq? (car (car (cdr clause))) 'guard)
+                            #f)
+                        #f))
+    (define body (if guarded (cdr (cdr clause)) (cdr clause)))
+    (list 'list
+          (list 'quote pat)
+          (cons 'list (%match-preds pat '()))
+          (if guarded
+              (list 'lambda vars (car (cdr (car (cdr clause)))))
+              #f)
+          (cons 'lambda (cons vars body)))))
+
+(define %match-expand-clauses
+  (lambda (clauses)
+    (if (null? clauses)
+        '()
+        (cons (%match-expand-clause (car clauses))
+              (%match-expand-clauses (cdr clauses))))))
+
+(define-macro match
+  (lambda (form)
+    (list '%match
+          (car (cdr form))
+          (cons 'list (%match-expand-clauses (cdr (cdr form)))))))
+
+;; Promises:
+;;
+;;     (delay expr)
+;;     (force promise)
+;;
+;; `delay` returns a promise to evaluate `expr` later, and `force` evaluates it
+;; the first time it is called and returns the memoized value afterwards. A
+;; promise is represented as `(%promise forced? . thunk-or-value)`; once forced,
+;; the thunk is replaced by its value so that the thunk's environment can be
+;; collected.
+(define %make-promise
+  (lambda (thunk)
+    (cons '%promise (cons #f thunk))))
+
+(define-macro delay
+  (lambda (form)
+    (list '%make-promise (list 'lambda '() (car (cdr form))))))
+
+(define promise?
+  (lambda (obj)
+    (if (pair? obj) (eq? (car obj) '%promise) #f)))
+
+(define force
+  (lambda (promise)
+    (define state (cdr promise))
+    (if (car state)
+        (cdr state)
+        ((lambda (val)
+           ;; Forcing the promise may have forced it re-entrantly, in which case
+           ;; the first value wins.
+           (if (car state)
+               (cdr state)
+               (begin
+                 (set-car! state #t)
+                 (set-cdr! state val)
+                 val)))
+         ((cdr state))))))
+
+;; Streams, after SRFI 41:
+;;
+;;     (stream-cons a b)
+;;
+;; Return a stream pair whose car is `a` and whose cdr is the stream `b`,
+;; neither of which is evaluated until it is needed. The empty stream is `()`,
+;; also available as `stream-null`.
+(define stream-null '())
+
+(define %make-stream-pair
+  (lambda (car-promise cdr-promise)
+    (cons '%stream-pair (cons car-promise cdr-promise))))
+
+(define-macro stream-cons
+  (lambda (form)
+    (list '%make-stream-pair
+          (list 'delay (car (cdr form)))
+          (list 'delay (car (cdr (cdr form)))))))
+
+(define stream-null?
+  (lambda (obj)
+    (null? obj)))
+
+(define stream-pair?
+  (lambda (obj)
+    (if (pair? obj) (eq? (car obj) '%stream-pair) #f)))
+
+(define stream?
+  (lambda (obj)
+    (if (null? obj) #t (stream-pair? obj))))
+
+(define stream-car
+  (lambda (s)
+    (force (car (cdr s)))))
+
+(define stream-cdr
+  (lambda (s)
+    (force (cdr (cdr s)))))
+
+;; Return the infinite stream of integers counting up from `n`.
+(define stream-from
+  (lambda (n)
+    (stream-cons n (stream-from (+ n 1)))))
+
+;; Return the `n`th element of the stream `s`.
+(define stream-ref
+  (lambda (s n)
+    (if (= n 0)
+        (stream-car s)
+        (stream-ref (stream-cdr s) (- n 1)))))
+
+(define stream-map
+  (lambda (proc s)
+    (if (stream-null? s)
+        stream-null
+        (stream-cons (proc (stream-car s))
+                     (stream-map proc (stream-cdr s))))))
+
+(define stream-filter
+  (lambda (pred s)
+    (if (stream-null? s)
+        stream-null
+        (if (pred (stream-car s))
+            (stream-cons (stream-car s) (stream-filter pred (stream-cdr s)))
+            (stream-filter pred (stream-cdr s))))))
+
+;; Return a list of the elements of the finite stream `s`.
+(define stream->list
+  (lambda (s)
+    (if (stream-null? s)
+        '()
+        (cons (stream-car s) (stream->list (stream-cdr s))))))
+
+;; Return a list of at most the first `n` elements of the stream `s`, which may
+;; be infinite. The rest of the stream is not forced after the `n`th element,
+;; since finding the next element of a filtered stream may never finish.
+(define stream->list/limit
+  (lambda (s n)
+    (if (if (= n 0) #t (stream-null? s))
+        '()
+        (cons (stream-car s)
+              (if (= n 1)
+                  '()
+                  (stream->list/limit (stream-cdr s) (- n 1)))))))
+
+;; Benchmarking:
+;;
+;;     (time expr)
+;;
+;; Evaluate `expr`, print how long it took and how much of that was spent
+;; collecting garbage, and return its value. The report is printed to standard
+;; output, so this needs the `Io` capability.
+(define-macro time
+  (lambda (form)
+    (list '%time (list 'lambda '() (car (cdr form))))))
+
+;; Continuation marks, after Racket:
+;;
+;;     (with-continuation-mark key val body)
+;;
+;; Evaluate `body` with a mark attached to its continuation, which maps `key`
+;; to `val`. `(current-continuation-marks)` returns the marks in effect as a
+;; list of `(key . val)` pairs, youngest first, so that exception handlers,
+;; parameterizations, and debuggers can inspect the dynamic context. Unlike in
+;; Racket, `body` is not in tail position, and a mark never replaces another.
+(define-macro with-continuation-mark
+  (lambda (form)
+    (list '%with-continuation-mark
+          (car (cdr form))
+          (car (cdr (cdr form)))
+          (list 'lambda '() (car (cdr (cdr (cdr form))))))))
+
+;; Return the values of the marks with the given key in `marks`, as returned by
+;; `current-continuation-marks`, youngest first.
+(define continuation-mark-set->list
+  (lambda (marks key)
+    (if (null? marks)
+        '()
+        (if (eq? (car (car marks)) key)
+            (cons (cdr (car marks)) (continuation-mark-set->list (cdr marks) key))
+            (continuation-mark-set->list (cdr marks) key)))))
+
+;; Return the value of the youngest mark with the given key in `marks`, or in
+;; effect if `marks` is `#f`, or `default` if there is no such mark.
+(define continuation-mark-set-first
+  (lambda (marks key default)
+    (define found
+      (continuation-mark-set->list (if marks marks (current-continuation-marks)) key))
+    (if (null? found) default (car found))))
@@ -14,363 +14,2592 @@
 
 //! Implementation of primitive procedures.
 
-use environment::{ActivationPtr, Environment};
-use eval::{apply_invocation, Trampoline, TrampolineResult};
-use heap::{Heap, Rooted};
-use read::{Read};
-use value::{RootedValue, Value};
+use std::any::{Any};
+use std::env;
+use std::fmt;
+use std::num::{Int};
+use std::old_io::{self, Acceptor, Listener};
+use std::old_io::net::tcp::{TcpAcceptor, TcpListener, TcpStream};
+use std::old_io::process::{Command, Process, ProcessExit};
+use std::num::wrapping::{WrappingOps};
+
+use channel::{Channel, Message, CHANNEL_TAG};
+use environment::{self, ActivationPtr, Environment, EnvironmentHandle};
+use errors::{E0204, E0301, E0302, E0303, E0304, E0305, E0310, E0311, E0312, E0314, E0401,
+             E0402, SchemeError};
+use eval::{apply_invocation, disassemble, evaluate_in_environment, Trampoline, TrampolineResult};
+use generator;
+use heap::{DefinitionOrigin, ExitRequest, Finalizer, Heap, ReplPrompt, Rooted,
+           JIFFIES_PER_SECOND};
+use port::{Port};
+use random::{RandomSource, RANDOM_SOURCE_TAG};
+use read::{read_from_reader};
+use time;
+use value::{self, PrimitiveTable, RootedValue, SchemeResult, Value};
 
 /// The function signature for primitives.
 pub type PrimitiveFunction = fn(&mut Heap, Vec<RootedValue>) -> TrampolineResult;
 
-fn cons(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref car, ref cdr] = args.as_slice() {
-        Ok(Trampoline::Value(Value::new_pair(heap, car, cdr)))
-    } else {
-        Err("Error: bad arguments to `cons`".to_string())
-    }
+/// The type of value a primitive expects for one of its arguments.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ArgumentType {
+    /// Any value at all.
+    Any,
+    /// A pair.
+    Pair,
+    /// A symbol.
+    Symbol,
+    /// A fixnum.
+    Fixnum,
+    /// A flonum.
+    Flonum,
+    /// A fixnum or a flonum.
+    Number,
+    /// A vector.
+    Vector,
+    /// A string.
+    String,
+    /// A procedure or a primitive.
+    Procedure,
 }
 
-fn car(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref arg] = args.as_slice() {
-        arg.car(heap)
-            .ok_or(format!("Error: cannot take car of non-cons: {}", **arg))
-            .map(|v| Trampoline::Value(v))
-    } else {
-        Err("Error: bad arguments to `car`".to_string())
+impl ArgumentType {
+    /// Get a description of this type, as used in error messages.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            ArgumentType::Any       => "a value",
+            ArgumentType::Pair      => "a pair",
+            ArgumentType::Symbol    => "a symbol",
+            ArgumentType::Fixnum    => "a fixnum",
+            ArgumentType::Flonum    => "a flonum",
+            ArgumentType::Number    => "a number",
+            ArgumentType::Vector    => "a vector",
+            ArgumentType::String    => "a string",
+            ArgumentType::Procedure => "a procedure",
+        }
     }
-}
 
-fn set_car_bang(heap: &mut Heap, mut args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref mut cons, ref val] = args.as_mut_slice() {
-        if let &mut Value::Pair(ref mut cons) = &mut **cons {
-            cons.set_car(val);
-            return Ok(Trampoline::Value(heap.unspecified_symbol()));
+    /// Return true if the given value is of this type, false otherwise.
+    pub fn matches(&self, val: &Value) -> bool {
+        match (*self, *val) {
+            (ArgumentType::Any, _)                         => true,
+            (ArgumentType::Pair, Value::Pair(_))           => true,
+            (ArgumentType::Symbol, Value::Symbol(_))       => true,
+            (ArgumentType::Fixnum, Value::Integer(_))      => true,
+            (ArgumentType::Flonum, Value::Flonum(_))       => true,
+            (ArgumentType::Number, v)                      => v.is_number(),
+            (ArgumentType::Vector, Value::Vector(_))       => true,
+            (ArgumentType::String, Value::String(_))       => true,
+            (ArgumentType::Procedure, Value::Procedure(_)) => true,
+            (ArgumentType::Procedure, Value::Primitive(_)) => true,
+            _                                              => false,
         }
-        return Err(format!("Can't set-car! on non-cons: {}", **cons));
-    } else {
-        Err("Error: bad arguments to `set-car!`".to_string())
     }
 }
 
-fn cdr(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref arg] = args.as_slice() {
-        arg.cdr(heap)
-            .ok_or(format!("Error: cannot take cdr of non-cons: {}", **arg))
-            .map(|v| Trampoline::Value(v))
-    } else {
-        Err("Error: bad arguments to `cdr`".to_string())
+/// The number and types of the arguments a builtin primitive accepts. A
+/// primitive registered with a signature has its arguments checked against it
+/// before it is called, so that every primitive reports bad arguments the same
+/// way, and so that the primitive itself can index into its arguments without
+/// checking them again.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PrimitiveSignature {
+    /// The fewest arguments the primitive accepts.
+    pub min_arity: usize,
+    /// The most arguments the primitive accepts, or `None` if it accepts any
+    /// number of arguments past `min_arity`.
+    pub max_arity: Option<usize>,
+    /// The types of the leading arguments. Arguments past the end of these are
+    /// not checked.
+    pub types: &'static [ArgumentType],
+}
+
+impl PrimitiveSignature {
+    /// Get the signature of a primitive taking exactly one argument of each of
+    /// the given types.
+    pub fn fixed(types: &'static [ArgumentType]) -> PrimitiveSignature {
+        PrimitiveSignature {
+            min_arity: types.len(),
+            max_arity: Some(types.len()),
+            types: types,
+        }
+    }
+
+    /// Get the signature of a primitive taking at least the given number of
+    /// arguments, the first of which have the given types.
+    pub fn variadic(min_arity: usize, types: &'static [ArgumentType]) -> PrimitiveSignature {
+        PrimitiveSignature {
+            min_arity: min_arity,
+            max_arity: None,
+            types: types,
+        }
     }
+
+    /// Get the signature of a primitive taking at least the given number of
+    /// arguments, and at most one of each of the given types.
+    pub fn optional(min_arity: usize, types: &'static [ArgumentType]) -> PrimitiveSignature {
+        PrimitiveSignature {
+            min_arity: min_arity,
+            max_arity: Some(types.len()),
+            types: types,
+        }
+    }
+
+    /// Get the number of arguments the primitive takes, if it always takes the
+    /// same number of arguments.
+    pub fn arity(&self) -> Option<usize> {
+        match self.max_arity {
+            Some(max) if max == self.min_arity => Some(max),
+            _                                  => None,
+        }
+    }
+
+    /// Check the given arguments to the primitive with the given name against
+    /// this signature.
+    pub fn check(&self, name: &str, args: &[RootedValue]) -> Result<(), SchemeError> {
+        let count = args.len();
+        if count < self.min_arity || self.max_arity.map_or(false, |max| count > max) {
+            let expected = match self.max_arity {
+                Some(max) if max == self.min_arity => format!("{}", max),
+                Some(max)                          => format!("{} to {}", self.min_arity, max),
+                None                               => format!("at least {}", self.min_arity),
+            };
+            let last = self.max_arity.unwrap_or(self.min_arity);
+            let noun = if last == 1 { "argument" } else { "arguments" };
+            return Err(E0301.error(format!("Error: `{}` expects {} {}, found {}",
+                                           name, expected, noun, count)));
+        }
+
+        for (i, (arg, ty)) in args.iter().zip(self.types.iter()).enumerate() {
+            if !ty.matches(&**arg) {
+                return Err(argument_type_error(name, *ty, i + 1, &**arg));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Describe the error of passing the given value as the argument at the given
+/// position, counting from one, to the primitive with the given name, which
+/// expects an argument of the given type there.
+fn argument_type_error(name: &str,
+                       expected: ArgumentType,
+                       position: usize,
+                       found: &Value) -> SchemeError {
+    let code = match expected {
+        ArgumentType::Pair   => &E0305,
+        ArgumentType::Fixnum |
+        ArgumentType::Flonum |
+        ArgumentType::Number => &E0304,
+        _                    => &E0312,
+    };
+    code.error(format!("Error: `{}` expects {} as argument {}, found {}",
+                       name, expected.description(), position, found))
+}
+
+/// How the integer arithmetic primitives behave when a result does not fit in
+/// a fixnum. Each heap has its own mode, which is `ArithmeticMode::Error` by
+/// default.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ArithmeticMode {
+    /// Wrap around using two's complement.
+    Wrap,
+    /// Clamp the result to the largest or smallest fixnum.
+    Saturate,
+    /// Signal an error.
+    Error,
+    /// Promote the result to an arbitrary precision integer. Bignums are not
+    /// implemented yet, so for now this signals an error as well.
+    Promote,
 }
 
-fn set_cdr_bang(heap: &mut Heap, mut args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref mut cons, ref val] = args.as_mut_slice() {
-        if let &mut Value::Pair(ref mut cons) = &mut **cons {
-            cons.set_cdr(val);
-            return Ok(Trampoline::Value(heap.unspecified_symbol()));
+impl ArithmeticMode {
+    /// Get the name of this mode, as used by `(arithmetic-mode)`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ArithmeticMode::Wrap     => "wrap",
+            ArithmeticMode::Saturate => "saturate",
+            ArithmeticMode::Error    => "error",
+            ArithmeticMode::Promote  => "promote",
         }
-        return Err(format!("Can't set-cdr! on non-cons: {}", **cons));
-    } else {
-        Err("Error: bad arguments to `set-cdr!`".to_string())
     }
+
+    /// Get the mode with the given name, if there is one.
+    pub fn from_name(name: &str) -> Option<ArithmeticMode> {
+        match name {
+            "wrap"     => Some(ArithmeticMode::Wrap),
+            "saturate" => Some(ArithmeticMode::Saturate),
+            "error"    => Some(ArithmeticMode::Error),
+            "promote"  => Some(ArithmeticMode::Promote),
+            _          => None,
+        }
+    }
+}
+
+/// Get the result of an integer operation, handling overflow according to the
+/// heap's `ArithmeticMode`. `checked` is the result if it did not overflow an
+/// `i64`, `wrapped` is the two's complement result, and `is_positive` is the
+/// sign of the true result. Results that fit in an `i64` but not in a fixnum
+/// are overflows too.
+fn integer_result(heap: &Heap,
+                  operator: &str,
+                  checked: Option<i64>,
+                  wrapped: i64,
+                  is_positive: bool) -> Result<i64, SchemeError> {
+    let is_positive = match checked {
+        Some(n) if value::is_fixnum(n) => return Ok(n),
+        Some(n) => n > 0,
+        None    => is_positive,
+    };
+
+    match heap.arithmetic_mode() {
+        ArithmeticMode::Wrap     => Ok(value::wrap_fixnum(wrapped)),
+        ArithmeticMode::Saturate => {
+            Ok(if is_positive { value::MAX_FIXNUM } else { value::MIN_FIXNUM })
+        },
+        ArithmeticMode::Error    => {
+            Err(E0302.error(format!("Error: integer overflow in `{}`", operator)))
+        },
+        ArithmeticMode::Promote  => {
+            Err(E0302.error(format!("Error: integer overflow in `{}`, and bignums are not \
+                                     supported yet",
+                                    operator)))
+        },
+    }
+}
+
+fn cons_value(heap: &mut Heap, car: &RootedValue, cdr: &RootedValue) -> SchemeResult {
+    Ok(Value::new_pair(heap, car, cdr))
+}
+
+fn cons(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    cons_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn car_value(heap: &mut Heap, arg: &RootedValue) -> SchemeResult {
+    arg.car(heap).ok_or(argument_type_error("car", ArgumentType::Pair, 1, &**arg))
+}
+
+fn car(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    car_value(heap, &args[0]).map(Trampoline::Value)
+}
+
+fn set_car_bang(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let mut cons = args[0].to_pair(heap).expect("`set-car!` is declared to take a pair");
+    cons.set_car(&args[1]);
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+fn cdr_value(heap: &mut Heap, arg: &RootedValue) -> SchemeResult {
+    arg.cdr(heap).ok_or(argument_type_error("cdr", ArgumentType::Pair, 1, &**arg))
+}
+
+fn cdr(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    cdr_value(heap, &args[0]).map(Trampoline::Value)
+}
+
+fn set_cdr_bang(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let mut cons = args[0].to_pair(heap).expect("`set-cdr!` is declared to take a pair");
+    cons.set_cdr(&args[1]);
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
 }
 
 fn list(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    use value;
     Ok(Trampoline::Value(value::list(heap, args.as_slice())))
 }
 
 fn length(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref arg] = args.as_slice() {
-        let len = try!(arg.len().ok().ok_or(
-            format!("Error: can only take length of proper lists, got {}", **arg)));
-        Ok(Trampoline::Value(Rooted::new(heap, Value::new_integer(len as i64))))
-    } else {
-        Err("Error: bad arguments to `length`".to_string())
+    let mut len = 0;
+    for item in value::iter_list(heap, &args[0]) {
+        try!(item);
+        len += 1;
+    }
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_integer(len))))
+}
+
+/// Call the given comparison procedure with `a` and `b`, and return true if it
+/// considers `a` less than `b`.
+fn is_less(heap: &mut Heap,
+           less: &RootedValue,
+           a: &RootedValue,
+           b: &RootedValue) -> Result<bool, SchemeError> {
+    let result = try!(apply_invocation(heap, less, vec!(a.clone(), b.clone()))
+        .and_then(|trampoline| trampoline.run(heap)));
+    Ok(*result != Value::Boolean(false))
+}
+
+/// Sort the given values with a merge sort, which is stable and calls the
+/// comparison procedure O(n log n) times even in the worst case. An error from
+/// the comparison procedure aborts the sort.
+fn merge_sort(heap: &mut Heap,
+              less: &RootedValue,
+              mut vals: Vec<RootedValue>) -> Result<Vec<RootedValue>, SchemeError> {
+    if vals.len() <= 1 {
+        return Ok(vals);
+    }
+
+    let mid = vals.len() / 2;
+    let right = vals[mid..].to_vec();
+    vals.truncate(mid);
+    let left = try!(merge_sort(heap, less, vals));
+    let right = try!(merge_sort(heap, less, right));
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        // Only take from the right half when it is strictly less, so that
+        // equal elements keep their original order.
+        if try!(is_less(heap, less, &right[j], &left[i])) {
+            merged.push(right[j].clone());
+            j += 1;
+        } else {
+            merged.push(left[i].clone());
+            i += 1;
+        }
+    }
+    merged.extend(left[i..].iter().map(|v| v.clone()));
+    merged.extend(right[j..].iter().map(|v| v.clone()));
+    Ok(merged)
+}
+
+fn list_sort(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let vals : Vec<RootedValue> = try!(value::iter_list(heap, &args[1]).collect());
+    let sorted = try!(merge_sort(heap, &args[0], vals));
+    Ok(Trampoline::Value(value::list(heap, sorted.as_slice())))
+}
+
+fn vector_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(match **arg {
+        Value::Vector(_) => true,
+        _                => false
+    }))))
+}
+
+fn vector_to_list(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let vector = args[0].to_vector(heap)
+        .expect("`vector->list` is declared to take a vector");
+    let vals : Vec<RootedValue> = (0..vector.len())
+        .filter_map(|i| vector.get(heap, i))
+        .collect();
+    Ok(Trampoline::Value(value::list(heap, vals.as_slice())))
+}
+
+fn vector_sort_bang(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let mut vector = args[1].to_vector(heap)
+        .expect("`vector-sort!` is declared to take a vector");
+    let vals : Vec<RootedValue> = (0..vector.len())
+        .filter_map(|i| vector.get(heap, i))
+        .collect();
+    let sorted = try!(merge_sort(heap, &args[0], vals));
+    vector.set_elements(sorted.as_slice());
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+fn iota(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let mut numbers = [0, 0, 1];
+    for (i, arg) in args.iter().enumerate() {
+        numbers[i] = arg.to_integer().expect("`iota` is declared to take fixnums");
     }
+
+    let [count, start, step] = numbers;
+    if count < 0 {
+        return Err(E0312.error(format!("Error: `iota` expects a non-negative count, found {}",
+                                       count)));
+    }
+
+    // The elements are evenly spaced, so if the last one is a fixnum then so
+    // are all the others.
+    if count > 0 {
+        let last = (count - 1).checked_mul(step).and_then(|n| n.checked_add(start));
+        if !last.map_or(false, value::is_fixnum) {
+            return Err(E0302.error("Error: integer overflow in `iota`".to_string()));
+        }
+    }
+
+    let vals : Vec<RootedValue> = (0..count)
+        .map(|i| Rooted::new(heap, Value::new_integer(start + i * step)))
+        .collect();
+    Ok(Trampoline::Value(value::list(heap, vals.as_slice())))
 }
 
 fn apply(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+
     // Note: we don't support concatenating many argument lists yet:
     //
     //     (apply f '(1 2) '(3 4)) == (apply f '(1 2 3 4))
     //
     // We should suport that eventually.
-    if let [ref proc_val, ref args] = args.as_slice() {
-        let v : Vec<RootedValue> = try!(args.iter()
-            .map(|result_val| {
-                result_val
-                    .map(|r| Rooted::new(heap, r))
-                    .map_err(|_| "Must pass a proper list to `apply`".to_string())
-            })
-            .collect());
-        apply_invocation(heap, proc_val, v)
-    } else {
-        Err("Error: bad arguments to `apply`".to_string())
+    let v : Vec<RootedValue> = try!(value::iter_list(heap, &args[1]).collect());
+    apply_invocation(heap, &args[0], v)
+}
+
+fn percent_with_continuation_mark(heap: &mut Heap,
+                                  args: Vec<RootedValue>) -> TrampolineResult {
+    // The mark has to be removed once the body returns, so the body is run
+    // here rather than returned as a tail call.
+    heap.push_continuation_mark(args[0].clone(), args[1].clone());
+    let result = call_thunk(heap, &args[2]);
+    heap.pop_continuation_mark();
+    Ok(Trampoline::Value(try!(result)))
+}
+
+/// Call the given thunk, and run the trampoline it returns to completion.
+fn call_thunk(heap: &mut Heap, thunk: &RootedValue) -> SchemeResult {
+    apply_invocation(heap, thunk, vec!()).and_then(|trampoline| trampoline.run(heap))
+}
+
+fn dynamic_wind(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let (before, thunk, after) = (&args[0], &args[1], &args[2]);
+    try!(call_thunk(heap, before));
+    // As with `%with-continuation-mark`, the body is run here rather than
+    // returned as a tail call, so that `after` is called once it returns or
+    // fails.
+    let height = heap.push_wind(after.clone());
+    let result = call_thunk(heap, thunk);
+    // If the body called `exit`, this extent was already left, and `after`
+    // already called.
+    for after in heap.pop_winds(height).into_iter() {
+        try!(call_thunk(heap, &after));
     }
+    Ok(Trampoline::Value(try!(result)))
 }
 
-fn error(_: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    let mut string = String::from_str("ERROR!");
-    for val in args.iter() {
-        string.push_str(format!("\n\t{}", **val).as_slice());
+fn current_continuation_marks(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    let mut result = Rooted::new(heap, Value::EmptyList);
+    for (key, val) in heap.continuation_marks().into_iter().rev() {
+        let mark = Value::new_pair(heap, &key, &val);
+        result = Value::new_pair(heap, &mark, &result);
     }
-    Err(string)
+    Ok(Trampoline::Value(result))
 }
 
-fn print(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    for val in args.iter() {
-        println!("{}", **val);
+#[cfg(feature = "debugging")]
+fn trace(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    heap.trace(&args[0]);
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+#[cfg(feature = "debugging")]
+fn untrace(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    heap.untrace(&args[0]);
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+/// Get the file name and line number from the arguments to `set-breakpoint!` or
+/// `clear-breakpoint!`.
+#[cfg(feature = "debugging")]
+fn breakpoint_args(args: &Vec<RootedValue>, name: &str) -> Result<(String, u64), SchemeError> {
+    if let (Value::String(str), Value::Integer(n)) = (*args[0], *args[1]) {
+        if n > 0 {
+            return Ok(((*str).clone(), n as u64));
+        }
     }
+    Err(E0312.error(format!("Error: `{}` expects a positive line number, found {}",
+                            name, *args[1])))
+}
+
+#[cfg(feature = "debugging")]
+fn set_breakpoint_bang(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let (file, line) = try!(breakpoint_args(&args, "set-breakpoint!"));
+    heap.set_breakpoint(file.as_slice(), line);
     Ok(Trampoline::Value(heap.unspecified_symbol()))
 }
 
-fn read(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    // Only supports reading from stdin right now.
+#[cfg(feature = "debugging")]
+fn clear_breakpoint_bang(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let (file, line) = try!(breakpoint_args(&args, "clear-breakpoint!"));
+    heap.clear_breakpoint(file.as_slice(), line);
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
 
-    use std::old_io;
+#[cfg(feature = "debugging")]
+fn profile_report(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    if !heap.is_profiling() {
+        return Err(E0310.error("Error: `profile-report` called while not profiling".to_string()));
+    }
 
-    if args.len() != 0 {
-        return Err("`read` called with too many parameters".to_string());
+    println!("{:>10}  {:>14}  {}", "calls", "time (ns)", "procedure");
+    for entry in heap.profile_report().iter() {
+        match entry.location {
+            Some(ref loc) => println!("{:>10}  {:>14}  {} at {}",
+                                      entry.calls, entry.nanoseconds, entry.name, loc),
+            None          => println!("{:>10}  {:>14}  {}",
+                                      entry.calls, entry.nanoseconds, entry.name),
+        }
     }
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
 
-    let stdin = old_io::stdio::stdin();
-    let reader = Read::new(stdin, heap, "stdin".to_string());
-    for (_, read_result) in reader {
-        let form = try!(read_result);
-        return Ok(Trampoline::Value(form));
+fn foreign_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let is_foreign = args[0].to_foreign().is_some();
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(is_foreign))))
+}
+
+fn native_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let is_native = match *args[0] {
+        Value::Native(_) => true,
+        _                => false,
+    };
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(is_native))))
+}
+
+fn foreign_released_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let handle = try!(args[0].to_foreign().ok_or(
+        E0312.error(format!("Error: `foreign-released?` expects a foreign object, found {}",
+                            *args[0]))));
+    let released = heap.is_foreign_released(handle);
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(released))))
+}
+
+fn foreign_release_bang(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let handle = try!(args[0].to_foreign().ok_or(
+        E0312.error(format!("Error: `foreign-release!` expects a foreign object, found {}",
+                            *args[0]))));
+    // Dropping the native object here runs its destructor, closing any native
+    // resource it owns.
+    try!(heap.release_foreign(handle));
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+fn register_finalizer_bang(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    try!(heap.register_finalizer(&args[0], Finalizer::Scheme(args[1].clone())));
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+fn error(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let (message, irritants) = (&args[0], &args[1..]);
+
+    // The message is displayed rather than written, so that it reads like any
+    // other error message.
+    let mut error = match **message {
+        Value::String(str) => E0401.error(format!("ERROR! {}", *str)),
+        _                  => E0401.error(format!("ERROR! {}", **message)),
+    };
+    for val in irritants.iter() {
+        error.message.push_str(format!("\n\t{}", **val).as_slice());
     }
+    heap.note_error_irritants(irritants.to_vec());
+    Err(error)
+}
 
-    Ok(Trampoline::Value(heap.eof_symbol()))
+fn assert(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    match *args[0] {
+        Value::Boolean(false) => Err(E0402.error("Error: assertion failed".to_string())),
+        _                     => Ok(Trampoline::Value(heap.unspecified_symbol())),
+    }
 }
 
-fn not(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref arg] = args.as_slice() {
-        Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(match **arg {
-            Value::Boolean(b) if b == false => true,
-            _                               => false,
-        }))))
-    } else {
-        Err("Error: bad arguments to `not`".to_string())
+/// Expand the `~` directives in the given SRFI-28 format string with the given
+/// arguments: `~a` displays the next argument, `~s` writes it, `~d` writes the
+/// next argument which must be an integer, `~%` is a newline, and `~~` is a
+/// tilde.
+fn format_string(template: &str, args: &[RootedValue]) -> Result<String, SchemeError> {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            out.push(c);
+            continue;
+        }
+
+        let directive = try!(chars.next().ok_or(
+            "Error: `format` string ends in the middle of a directive".to_string()));
+        match directive {
+            '%' => out.push('\n'),
+            '~' => out.push('~'),
+            'a' | 's' | 'd' => {
+                let arg = try!(args.next().ok_or(
+                    format!("Error: `format` is missing an argument for `~{}`", directive)));
+                match (directive, **arg) {
+                    ('a', Value::String(str))    => out.push_str(str.as_slice()),
+                    ('a', Value::Character(c))   => out.push(c),
+                    ('d', Value::Integer(_)) | ('a', _) | ('s', _) => {
+                        out.push_str(format!("{}", **arg).as_slice());
+                    },
+                    _ => {
+                        return Err(E0312.error(format!("Error: `format` expects an integer \
+                                                        for `~d`, found {}", **arg)));
+                    },
+                }
+            },
+            _ => {
+                return Err(SchemeError::new(format!("Error: `format` does not support `~{}`",
+                                                    directive)));
+            },
+        }
+    }
+
+    if args.next().is_some() {
+        return Err(SchemeError::new("Error: `format` was given more arguments than \
+                                     directives".to_string()));
     }
+    Ok(out)
 }
 
-fn null_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref arg] = args.as_slice() {
-        Ok(Trampoline::Value(
-            Rooted::new(heap, Value::new_boolean(**arg == Value::EmptyList))))
-    } else {
-        Err("Error: bad arguments to `null?`".to_string())
+fn format(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    // Either `(format template arg ...)` as in SRFI-28, or with a destination
+    // first as in SRFI-48: `#f` for a string, `#t` for standard output, or a
+    // port.
+    let (destination, template, rest) = match *args[0] {
+        Value::Boolean(_) | Value::Native(_) if args.len() > 1 => {
+            (Some(&args[0]), &args[1], &args[2..])
+        },
+        _ => (None, &args[0], &args[1..]),
+    };
+
+    let string = match **template {
+        Value::String(str) => try!(format_string(str.as_slice(), rest)),
+        _ => {
+            return Err(E0312.error(format!("Error: `format` expects a format string, found {}",
+                                           **template)));
+        },
+    };
+
+    match destination.map(|d| **d) {
+        Some(Value::Boolean(true)) => {
+            if !heap.has_capability(Capability::Io) {
+                return Err(SchemeError::new("Error: `format` cannot write to standard output \
+                                             without the `Io` capability".to_string()));
+            }
+            print!("{}", string);
+        },
+        Some(Value::Native(_)) => {
+            try!(try!(with_port(heap, "format", destination.unwrap(),
+                                |p: &mut Port, _: &mut Heap| p.write_str(string.as_slice()))));
+        },
+        _ => return Ok(Trampoline::Value(Value::new_string(heap, string))),
     }
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
 }
 
-fn pair_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref arg] = args.as_slice() {
-        Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(match **arg {
-            Value::Pair(_) => true,
-            _              => false,
-        }))))
-    } else {
-        Err("Error: bad arguments to `pair?`".to_string())
+/// Get a copy of the given string argument to the primitive with the given
+/// name, whose signature declares that the argument is a string.
+fn string_argument(name: &str, val: &RootedValue) -> String {
+    match **val {
+        Value::String(str) => (*str).clone(),
+        _                  => unreachable!("`{}` is declared to take a string", name),
     }
 }
 
-fn atom_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref arg] = args.as_slice() {
-        Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(match **arg {
-            Value::Pair(_) => false,
-            _              => true,
-        }))))
-    } else {
-        Err("Error: bad arguments to `atom?`".to_string())
+/// Call the given function with the native object of type `T` that the given
+/// value wraps, for the primitive with the given name. `what` describes a `T`
+/// in error messages.
+fn with_native<T, F, R>(heap: &mut Heap,
+                        name: &str,
+                        what: &str,
+                        val: &RootedValue,
+                        f: F) -> Result<R, SchemeError>
+    where T: Any, F: FnOnce(&mut T) -> R
+{
+    let mut native = try!(val.to_native(heap).ok_or(
+        E0312.error(format!("Error: `{}` expects {}, found {}", name, what, **val))));
+    let object = try!(native.downcast_mut::<T>());
+    Ok(f(object))
+}
+
+/// Like `with_native`, for ports, but also pass the heap to the given function,
+/// because reading data from a port allocates.
+fn with_port<F, R>(heap: &mut Heap,
+                   name: &str,
+                   val: &RootedValue,
+                   f: F) -> Result<R, SchemeError>
+    where F: FnOnce(&mut Port, &mut Heap) -> R
+{
+    let mut native = try!(val.to_native(heap).ok_or(
+        E0312.error(format!("Error: `{}` expects a port, found {}", name, **val))));
+    let port = try!(native.downcast_mut::<Port>());
+    Ok(f(port, heap))
+}
+
+fn random_integer(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let n = match *args[0] {
+        Value::Integer(n) if n > 0 => n as u64,
+        _                          => {
+            return Err(E0312.error(format!("Error: `random-integer` expects a positive integer, \
+                                            found {}", *args[0])));
+        },
+    };
+
+    let i = match args.get(1) {
+        Some(source) => {
+            try!(with_native(heap, "random-integer", "a random source", source,
+                             |s: &mut RandomSource| s.below(n)))
+        },
+        None         => heap.random_source().below(n),
+    };
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_integer(i as i64))))
+}
+
+fn make_random_source(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    Ok(Trampoline::Value(heap.new_native(RANDOM_SOURCE_TAG, Box::new(RandomSource::new(0)))))
+}
+
+fn random_source_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let is_source = match args[0].to_native(heap) {
+        Some(native) => native.downcast_ref::<RandomSource>().is_ok(),
+        None         => false,
+    };
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(is_source))))
+}
+
+fn random_source_randomize_bang(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    // Seed from the heap's default source rather than the clock, so that
+    // replaying a transcript gives the same numbers.
+    let seed = heap.random_source().next_u64();
+    try!(with_native(heap, "random-source-randomize!", "a random source", &args[0],
+                     |s: &mut RandomSource| {
+        *s = RandomSource::new(seed);
+    }));
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+fn random_source_pseudo_randomize_bang(heap: &mut Heap,
+                                       args: Vec<RootedValue>) -> TrampolineResult {
+    let seed = match (*args[1], *args[2]) {
+        (Value::Integer(i), Value::Integer(j)) => {
+            (i as u64).wrapping_mul(0x100000001b3) ^ (j as u64)
+        },
+        _ => unreachable!("`random-source-pseudo-randomize!` is declared to take fixnums"),
+    };
+    try!(with_native(heap, "random-source-pseudo-randomize!", "a random source", &args[0],
+                     |s: &mut RandomSource| {
+        *s = RandomSource::new(seed);
+    }));
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+fn make_channel(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    Ok(Trampoline::Value(heap.new_native(CHANNEL_TAG, Box::new(Channel::new()))))
+}
+
+fn channel_send(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let message = try!(Message::from_value(&*args[1]));
+    try!(with_native(heap, "channel-send", "a channel", &args[0],
+                     |c: &mut Channel| c.send(message)));
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+fn channel_receive(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    // Clone the channel, so that the heap is not borrowed while blocking.
+    let channel = try!(with_native(heap, "channel-receive", "a channel", &args[0],
+                                   |c: &mut Channel| c.clone()));
+    Ok(Trampoline::Value(channel.receive().to_value(heap)))
+}
+
+fn channel_try_receive(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let message = try!(with_native(heap, "channel-try-receive", "a channel", &args[0],
+                                   |c: &mut Channel| c.try_receive()));
+    match message {
+        Some(message) => Ok(Trampoline::Value(message.to_value(heap))),
+        None          => Ok(Trampoline::Value(args[1].clone())),
     }
 }
 
-fn eq_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref a, ref b] = args.as_slice() {
-        Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(*a == *b))))
-    } else {
-        Err("Error: bad arguments to `eq?`".to_string())
+fn make_generator(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    Ok(Trampoline::Value(try!(generator::make_generator(heap, &args[0]))))
+}
+
+fn generator_next(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    match try!(generator::next(heap, &args[0])) {
+        Some(val) => Ok(Trampoline::Value(val)),
+        None      => Ok(Trampoline::Value(match args.get(1) {
+            Some(default) => default.clone(),
+            None          => heap.eof_symbol(),
+        })),
     }
 }
 
-fn symbol_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref arg] = args.as_slice() {
-        Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(match **arg {
-            Value::Symbol(_) => true,
-            _                => false
-        }))))
-    } else {
-        Err("Error: bad arguments to `symbol?`".to_string())
+fn percent_default_object_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let default = heap.default_object();
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(*args[0] == *default))))
+}
+
+fn eval(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    match args.get(1) {
+        Some(env) => evaluate_in_environment(heap, &args[0], env),
+        None      => {
+            let env = try!(environment::global_environment(heap));
+            evaluate_in_environment(heap, &args[0], &env)
+        },
     }
 }
 
-fn number_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref arg] = args.as_slice() {
-        Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(match **arg {
-            Value::Integer(_) => true,
-            _                 => false
-        }))))
-    } else {
-        Err("Error: bad arguments to `number?`".to_string())
+fn interaction_environment(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    Ok(Trampoline::Value(try!(environment::global_environment(heap))))
+}
+
+fn environment_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let is_environment = match args[0].to_native(heap) {
+        Some(native) => native.downcast_ref::<EnvironmentHandle>().is_ok(),
+        None         => false,
+    };
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(is_environment))))
+}
+
+fn environment_bindings(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let bindings = try!(environment::bindings(heap, &args[0]));
+    let mut pairs = Vec::with_capacity(bindings.len());
+    for (name, val) in bindings.into_iter() {
+        let name = heap.environment.name(name).to_string();
+        let name = heap.get_or_create_symbol(name);
+        pairs.push(Value::new_pair(heap, &name, &val));
     }
+    Ok(Trampoline::Value(value::list(heap, pairs.as_slice())))
 }
 
-fn string_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref arg] = args.as_slice() {
-        Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(match **arg {
-            Value::String(_) => true,
-            _                => false
-        }))))
-    } else {
-        Err("Error: bad arguments to `string?`".to_string())
+fn environment_ref(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let name = args[1].to_symbol(heap).expect("`environment-ref` is declared to take a symbol");
+    match try!(environment::lookup(heap, &args[0], name.as_slice())) {
+        Some(val) => Ok(Trampoline::Value(val)),
+        None      => {
+            Err(E0204.error(format!("Reference to variable that hasn't been defined: {}",
+                                    name.as_slice())))
+        },
     }
 }
 
-fn number_equal(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref a, ref b] = args.as_slice() {
-        let first = try!(a.to_integer().ok_or(
-            "Error: cannot use `=` with non-numbers".to_string()));
-        let second = try!(b.to_integer().ok_or(
-            "Error: cannot use `=` with non-numbers".to_string()));
-        Ok(Trampoline::Value(
-            Rooted::new(heap, Value::new_boolean(first == second))))
-    } else {
-        Err("Error: bad arguments to `=`".to_string())
+fn print(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    for val in args.iter() {
+        println!("{}", **val);
+    }
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+fn read(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    if let Some(port) = args.get(0) {
+        let form = try!(try!(with_port(heap, "read", port, |p: &mut Port, heap: &mut Heap| {
+            p.read(heap)
+        })));
+        return Ok(Trampoline::Value(match form {
+            Some(form) => form,
+            None       => heap.eof_symbol(),
+        }));
+    }
+
+    let stdin = old_io::stdio::stdin();
+    let reader = read_from_reader(stdin, heap, "stdin");
+    for (_, read_result) in reader {
+        let form = try!(read_result);
+        return Ok(Trampoline::Value(form));
     }
+
+    Ok(Trampoline::Value(heap.eof_symbol()))
 }
 
-fn gt(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref a, ref b] = args.as_slice() {
-        let first = try!(a.to_integer().ok_or(
-            "Error: cannot use `>` with non-numbers".to_string()));
-        let second = try!(b.to_integer().ok_or(
-            "Error: cannot use `>` with non-numbers".to_string()));
-        Ok(Trampoline::Value(
-            Rooted::new(heap, Value::new_boolean(first > second))))
-    } else {
-        Err("Error: bad arguments to `>`".to_string())
+fn read_line(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let line = try!(try!(with_port(heap, "read-line", &args[0],
+                                   |p: &mut Port, _: &mut Heap| p.read_line())));
+    match line {
+        Some(line) => Ok(Trampoline::Value(Value::new_string(heap, line))),
+        None       => Ok(Trampoline::Value(heap.eof_symbol())),
     }
 }
 
-fn lt(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref a, ref b] = args.as_slice() {
-        let first = try!(a.to_integer().ok_or(
-            "Error: cannot use `<` with non-numbers".to_string()));
-        let second = try!(b.to_integer().ok_or(
-            "Error: cannot use `<` with non-numbers".to_string()));
-        Ok(Trampoline::Value(
-            Rooted::new(heap, Value::new_boolean(first < second))))
-    } else {
-        Err("Error: bad arguments to `<`".to_string())
+fn close_port(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    try!(with_port(heap, "close-port", &args[0], |p: &mut Port, _: &mut Heap| p.close()));
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+fn command_line(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    let strings : Vec<String> = heap.command_line().to_vec();
+    let vals : Vec<RootedValue> = strings.into_iter()
+        .map(|s| Value::new_string(heap, s))
+        .collect();
+    Ok(Trampoline::Value(value::list(heap, vals.as_slice())))
+}
+
+fn current_second(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    // There are no real numbers, so this is truncated to a whole second.
+    let now = try!(Value::new_checked_integer(heap.current_time()));
+    Ok(Trampoline::Value(Rooted::new(heap, now)))
+}
+
+fn current_jiffy(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    let now = try!(Value::new_checked_integer(heap.current_jiffy()));
+    Ok(Trampoline::Value(Rooted::new(heap, now)))
+}
+
+fn jiffies_per_second(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_integer(JIFFIES_PER_SECOND))))
+}
+
+/// Call the given thunk, print how long it took and how much of that was spent
+/// collecting garbage, and return its value. This implements the prelude's
+/// `time` macro.
+fn percent_time(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let before = heap.statistics();
+    let start = time::precise_time_ns();
+    let result = try!(call_thunk(heap, &args[0]));
+    let elapsed = time::precise_time_ns() - start;
+    let after = heap.statistics();
+
+    println!("time: {:.3} ms elapsed, {:.3} ms in {} garbage collections",
+             elapsed as f64 / 1e6,
+             (after.total_pause_ns - before.total_pause_ns) as f64 / 1e6,
+             after.collections - before.collections);
+    Ok(Trampoline::Value(result))
+}
+
+fn get_environment_variable(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let name = string_argument("get-environment-variable", &args[0]);
+    match env::var(name.as_slice()) {
+        Ok(val) => Ok(Trampoline::Value(Value::new_string(heap, val))),
+        Err(_)  => Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(false)))),
     }
 }
 
-fn add(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref a, ref b] = args.as_slice() {
-        let first = try!(a.to_integer().ok_or(
-            "Error: cannot use `+` with non-numbers".to_string()));
-        let second = try!(b.to_integer().ok_or(
-            "Error: cannot use `+` with non-numbers".to_string()));
-        Ok(Trampoline::Value(
-            Rooted::new(heap, Value::new_integer(first + second))))
-    } else {
-        Err("Error: bad arguments to `+`".to_string())
+fn get_environment_variables(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    let mut vars : Vec<(String, String)> = env::vars().collect();
+    vars.sort();
+    let pairs : Vec<RootedValue> = vars.into_iter()
+        .map(|(name, val)| {
+            let name = Value::new_string(heap, name);
+            let val = Value::new_string(heap, val);
+            Value::new_pair(heap, &name, &val)
+        })
+        .collect();
+    Ok(Trampoline::Value(value::list(heap, pairs.as_slice())))
+}
+
+/// Get the exit status requested by the arguments to `exit` or
+/// `emergency-exit`: 0 when there is no argument or it is `#t`, 1 when it is
+/// `#f`, or the given integer.
+fn exit_status(name: &str, args: &[RootedValue]) -> Result<i32, SchemeError> {
+    match args.first().map(|arg| **arg) {
+        None                        => Ok(0),
+        Some(Value::Boolean(true))  => Ok(0),
+        Some(Value::Boolean(false)) => Ok(1),
+        Some(Value::Integer(i)) if i >= 0 && i <= 255 => Ok(i as i32),
+        Some(arg) => Err(E0312.error(format!("Error: `{}` expects a boolean or an exit status, \
+                                              found {}", name, arg))),
     }
 }
 
-fn subtract(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref a, ref b] = args.as_slice() {
-        let first = try!(a.to_integer().ok_or(
-            "Error: cannot use `-` with non-numbers".to_string()));
-        let second = try!(b.to_integer().ok_or(
-            "Error: cannot use `-` with non-numbers".to_string()));
-        Ok(Trampoline::Value(
-            Rooted::new(heap, Value::new_integer(first - second))))
-    } else {
-        Err("Error: bad arguments to `-`".to_string())
+fn exit(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let status = try!(exit_status("exit", args.as_slice()));
+    // Unlike `emergency-exit`, `exit` leaves every `dynamic-wind` extent in
+    // effect first, calling their `after` thunks.
+    for after in heap.pop_winds(0).into_iter() {
+        try!(call_thunk(heap, &after));
     }
+    Err(heap.request_exit(ExitRequest { status: status, emergency: false }))
 }
 
-fn divide(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref a, ref b] = args.as_slice() {
-        let first = try!(a.to_integer().ok_or(
-            "Error: cannot use `/` with non-numbers".to_string()));
-        let second = try!(b.to_integer().ok_or(
-            "Error: cannot use `/` with non-numbers".to_string()));
-        if second == 0 {
-            return Err("Error: divide by zero".to_string());
-        }
-        Ok(Trampoline::Value(
-            Rooted::new(heap, Value::new_integer(first / second))))
-    } else {
-        Err("Error: bad arguments to `/`".to_string())
+fn emergency_exit(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let status = try!(exit_status("emergency-exit", args.as_slice()));
+    Err(heap.request_exit(ExitRequest { status: status, emergency: true }))
+}
+
+/// The tag of the native objects wrapping processes spawned by `run-process`.
+static PROCESS_TAG : &'static str = "process";
+
+fn run_process(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let mut strings = vec!();
+    for arg in args.iter() {
+        match **arg {
+            Value::String(str) => strings.push((*str).clone()),
+            _                  => {
+                return Err(E0312.error(format!("Error: `run-process` expects strings, found {}",
+                                               **arg)));
+            },
+        }
     }
+
+    let program = strings.remove(0);
+    let process = try!(Command::new(program.as_slice()).args(strings.as_slice()).spawn()
+        .map_err(|e| format!("Error: `run-process` could not run {}: {}", program, e)));
+    Ok(Trampoline::Value(heap.new_native(PROCESS_TAG, Box::new(process))))
 }
 
-fn multiply(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-    if let [ref a, ref b] = args.as_slice() {
-        let first = try!(a.to_integer().ok_or(
-            "Error: cannot use `*` with non-numbers".to_string()));
-        let second = try!(b.to_integer().ok_or(
-            "Error: cannot use `*` with non-numbers".to_string()));
-        Ok(Trampoline::Value(
-            Rooted::new(heap, Value::new_integer(first * second))))
-    } else {
-        Err("Error: bad arguments to `*`".to_string())
+fn process_input(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    // The port owns the pipe, so it can only be taken once.
+    let stdin = try!(try!(with_native(heap, "process-input", "a process", &args[0],
+                                      |p: &mut Process| p.stdin.take()))
+        .ok_or("Error: `process-input` was already called on this process".to_string()));
+    Ok(Trampoline::Value(Port::pipe_output(stdin).to_value(heap)))
+}
+
+fn process_output(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let stdout = try!(try!(with_native(heap, "process-output", "a process", &args[0],
+                                       |p: &mut Process| p.stdout.take()))
+        .ok_or("Error: `process-output` was already called on this process".to_string()));
+    Ok(Trampoline::Value(Port::pipe_input(stdout, "process-output").to_value(heap)))
+}
+
+fn process_wait(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let exit = try!(try!(with_native(heap, "process-wait", "a process", &args[0],
+                                     |p: &mut Process| {
+        // Close the process's input first, or a process reading it until the
+        // end would never exit. Once it has been taken with `process-input`,
+        // closing it is up to the port.
+        p.stdin.take();
+        p.wait().map_err(|e| format!("Error: `process-wait` failed: {}", e))
+    })));
+
+    // A process killed by a signal has the negated signal number as its
+    // status, as in Python's `subprocess`.
+    let status = match exit {
+        ProcessExit::ExitStatus(status) => status as i64,
+        ProcessExit::ExitSignal(signal) => -(signal as i64),
+    };
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_integer(status))))
+}
+
+/// The tag of the native objects wrapping listening TCP sockets. The socket is
+/// closed when the native object is collected.
+static TCP_LISTENER_TAG : &'static str = "tcp-listener";
+
+/// Get the host and port arguments to `tcp-connect` or `tcp-listen`.
+fn host_and_port(name: &str, args: &[RootedValue]) -> Result<(String, u16), SchemeError> {
+    match (*args[0], *args[1]) {
+        (Value::String(host), Value::Integer(port)) if port >= 0 && port <= 65535 => {
+            Ok(((*host).clone(), port as u16))
+        },
+        _ => Err(E0312.error(format!("Error: `{}` expects a port number, found {}",
+                                     name, *args[1]))),
     }
 }
 
-fn define_primitive(env: &mut Environment,
-                    act: &mut ActivationPtr,
-                    name: &'static str,
-                    function: PrimitiveFunction) {
-    let (i, j) = env.define(name.to_string());
-    assert!(i == 0, "All primitives should be defined on the global activation");
-    act.define(j, Value::new_primitive(name, function));
+fn tcp_connect(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let (host, port) = try!(host_and_port("tcp-connect", args.as_slice()));
+    let stream = try!(TcpStream::connect((host.as_slice(), port)).map_err(|e| {
+        format!("Error: `tcp-connect` could not connect to {}:{}: {}", host, port, e)
+    }));
+    Ok(Trampoline::Value(Port::tcp(stream, "tcp-connect").to_value(heap)))
+}
+
+fn tcp_listen(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let (host, port) = try!(host_and_port("tcp-listen", args.as_slice()));
+    let acceptor = try!(TcpListener::bind((host.as_slice(), port))
+        .and_then(|listener| listener.listen())
+        .map_err(|e| format!("Error: `tcp-listen` could not listen on {}:{}: {}",
+                             host, port, e)));
+    Ok(Trampoline::Value(heap.new_native(TCP_LISTENER_TAG, Box::new(acceptor))))
+}
+
+fn tcp_listener_port(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let name = try!(try!(with_native(heap, "tcp-listener-port", "a TCP listener", &args[0],
+                                     |a: &mut TcpAcceptor| a.socket_name()))
+        .map_err(|e| format!("Error: `tcp-listener-port` failed: {}", e)));
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_integer(name.port as i64))))
+}
+
+fn tcp_accept(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let stream = try!(try!(with_native(heap, "tcp-accept", "a TCP listener", &args[0],
+                                       |a: &mut TcpAcceptor| a.accept()))
+        .map_err(|e| format!("Error: `tcp-accept` failed: {}", e)));
+    Ok(Trampoline::Value(Port::tcp(stream, "tcp-accept").to_value(heap)))
 }
 
-pub fn define_primitives(env: &mut Environment, act: &mut ActivationPtr) {
-    define_primitive(env, act, "cons", cons);
-    define_primitive(env, act, "car", car);
-    define_primitive(env, act, "set-car!", set_car_bang);
-    define_primitive(env, act, "cdr", cdr);
-    define_primitive(env, act, "set-cdr!", set_cdr_bang);
+fn gc_stats(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    let stats = heap.statistics();
+    let fields = [
+        ("allocations", stats.allocations as i64),
+        ("live-cons-cells", stats.live_cons_cells as i64),
+        ("live-strings", stats.live_strings as i64),
+        ("live-activations", stats.live_activations as i64),
+        ("live-procedures", stats.live_procedures as i64),
+        ("live-vectors", stats.live_vectors as i64),
+        ("live-bytevectors", stats.live_bytevectors as i64),
+        ("live-flonums", stats.live_flonums as i64),
+        ("live-natives", stats.live_natives as i64),
+        ("collections", stats.collections as i64),
+        ("total-pause-ns", stats.total_pause_ns as i64),
+        ("max-pause-ns", stats.max_pause_ns as i64),
+        ("last-pause-ns", stats.last_pause_ns as i64),
+    ];
+
+    let entries : Vec<RootedValue> = fields.iter()
+        .map(|&(name, n)| {
+            let entry = [heap.get_or_create_symbol(name.to_string()),
+                         Rooted::new(heap, Value::new_integer(n))];
+            value::list(heap, &entry)
+        })
+        .collect();
+    Ok(Trampoline::Value(value::list(heap, entries.as_slice())))
+}
 
-    define_primitive(env, act, "list", list);
-    define_primitive(env, act, "length", length);
+fn set_repl_prompt_bang(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let prompt = match *args[0] {
+        Value::String(str)                        => ReplPrompt::Format((*str).clone()),
+        Value::Procedure(_) | Value::Primitive(_) => ReplPrompt::Procedure(args[0].clone()),
+        _                                         => {
+            return Err(E0312.error(format!("Error: `set-repl-prompt!` expects a string or a \
+                                            procedure, found {}",
+                                           *args[0])));
+        },
+    };
+    heap.set_repl_prompt(prompt);
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
 
-    define_primitive(env, act, "apply", apply);
+fn set_repl_banner_bang(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let banner = string_argument("set-repl-banner!", &args[0]);
+    heap.set_repl_banner(banner);
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
 
-    define_primitive(env, act, "error", error);
-    define_primitive(env, act, "print", print);
-    define_primitive(env, act, "read", read);
+fn not_value(heap: &mut Heap, arg: &RootedValue) -> SchemeResult {
+    Ok(Rooted::new(heap, Value::new_boolean(match **arg {
+        Value::Boolean(b) if b == false => true,
+        _                               => false,
+    })))
+}
 
-    define_primitive(env, act, "not", not);
-    define_primitive(env, act, "null?", null_question);
-    define_primitive(env, act, "pair?", pair_question);
-    define_primitive(env, act, "atom?", atom_question);
-    define_primitive(env, act, "eq?", eq_question);
-    define_primitive(env, act, "symbol?", symbol_question);
-    define_primitive(env, act, "number?", number_question);
-    define_primitive(env, act, "string?", string_question);
+fn not(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    not_value(heap, &args[0]).map(Trampoline::Value)
+}
+
+fn null_question_value(heap: &mut Heap, arg: &RootedValue) -> SchemeResult {
+    Ok(Rooted::new(heap, Value::new_boolean(**arg == Value::EmptyList)))
+}
+
+fn null_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    null_question_value(heap, &args[0]).map(Trampoline::Value)
+}
+
+fn pair_question_value(heap: &mut Heap, arg: &RootedValue) -> SchemeResult {
+    Ok(Rooted::new(heap, Value::new_boolean(match **arg {
+        Value::Pair(_) => true,
+        _              => false,
+    })))
+}
+
+fn pair_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    pair_question_value(heap, &args[0]).map(Trampoline::Value)
+}
+
+fn atom_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(match **arg {
+        Value::Pair(_) => false,
+        _              => true,
+    }))))
+}
+
+fn eq_question_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    Ok(Rooted::new(heap, Value::new_boolean(*a == *b)))
+}
+
+fn eq_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    eq_question_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn symbol_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(match **arg {
+        Value::Symbol(_) => true,
+        _                => false
+    }))))
+}
+
+fn number_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(arg.is_number()))))
+}
+
+fn string_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(match **arg {
+        Value::String(_) => true,
+        _                => false
+    }))))
+}
+
+/// Describe the error of passing the given operands to the binary primitive
+/// with the given name, which expects both of them to be of the given type.
+fn operand_type_error(operator: &str,
+                      expected: ArgumentType,
+                      a: &RootedValue,
+                      b: &RootedValue) -> SchemeError {
+    if expected.matches(&**a) {
+        argument_type_error(operator, expected, 2, &**b)
+    } else {
+        argument_type_error(operator, expected, 1, &**a)
+    }
+}
+
+/// Get the integer values of the operands of the binary integer primitive with
+/// the given name.
+fn integer_operands(operator: &str,
+                    a: &RootedValue,
+                    b: &RootedValue) -> Result<(i64, i64), SchemeError> {
+    match (a.to_integer(), b.to_integer()) {
+        (Some(first), Some(second)) => Ok((first, second)),
+        _ => Err(operand_type_error(operator, ArgumentType::Number, a, b)),
+    }
+}
+
+/// Get the operands of a binary generic arithmetic primitive as floats, if
+/// either of them is a flonum. Mixing a fixnum with a flonum gives an inexact
+/// result, as it does in the rest of the numeric tower.
+fn inexact_operands(a: &RootedValue, b: &RootedValue) -> Option<(f64, f64)> {
+    match (**a, **b) {
+        (Value::Flonum(x), Value::Flonum(y))  => Some((*x, *y)),
+        (Value::Flonum(x), Value::Integer(j)) => Some((*x, j as f64)),
+        (Value::Integer(i), Value::Flonum(y)) => Some((i as f64, *y)),
+        _                                     => None,
+    }
+}
+
+fn number_equal_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    if let Some((first, second)) = inexact_operands(a, b) {
+        return Ok(Rooted::new(heap, Value::new_boolean(first == second)));
+    }
+    let (first, second) = try!(integer_operands("=", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first == second)))
+}
+
+fn number_equal(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    number_equal_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn gt_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    if let Some((first, second)) = inexact_operands(a, b) {
+        return Ok(Rooted::new(heap, Value::new_boolean(first > second)));
+    }
+    let (first, second) = try!(integer_operands(">", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first > second)))
+}
+
+fn gt(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    gt_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn lt_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    if let Some((first, second)) = inexact_operands(a, b) {
+        return Ok(Rooted::new(heap, Value::new_boolean(first < second)));
+    }
+    let (first, second) = try!(integer_operands("<", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first < second)))
+}
+
+fn lt(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    lt_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn add_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    if let Some((first, second)) = inexact_operands(a, b) {
+        return Ok(Value::new_flonum(heap, first + second));
+    }
+    let (first, second) = try!(integer_operands("+", a, b));
+    let sum = try!(integer_result(heap,
+                                  "+",
+                                  first.checked_add(second),
+                                  first.wrapping_add(second),
+                                  second > 0));
+    Ok(Rooted::new(heap, Value::new_integer(sum)))
+}
+
+fn add(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    add_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn subtract_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    if let Some((first, second)) = inexact_operands(a, b) {
+        return Ok(Value::new_flonum(heap, first - second));
+    }
+    let (first, second) = try!(integer_operands("-", a, b));
+    let difference = try!(integer_result(heap,
+                                         "-",
+                                         first.checked_sub(second),
+                                         first.wrapping_sub(second),
+                                         second < 0));
+    Ok(Rooted::new(heap, Value::new_integer(difference)))
+}
+
+fn subtract(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    subtract_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn divide_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    // Inexact division by zero is an infinity or NaN, not an error.
+    if let Some((first, second)) = inexact_operands(a, b) {
+        return Ok(Value::new_flonum(heap, first / second));
+    }
+    let (first, second) = try!(integer_operands("/", a, b));
+    if second == 0 {
+        return Err(E0303.error("Error: divide by zero".to_string()));
+    }
+    // The only overflowing division is `MIN_FIXNUM / -1`, which wraps back
+    // around to `MIN_FIXNUM`.
+    let quotient = try!(integer_result(heap,
+                                       "/",
+                                       first.checked_div(second),
+                                       first,
+                                       true));
+    Ok(Rooted::new(heap, Value::new_integer(quotient)))
+}
+
+fn divide(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    divide_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn multiply_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    if let Some((first, second)) = inexact_operands(a, b) {
+        return Ok(Value::new_flonum(heap, first * second));
+    }
+    let (first, second) = try!(integer_operands("*", a, b));
+    let product = try!(integer_result(heap,
+                                      "*",
+                                      first.checked_mul(second),
+                                      first.wrapping_mul(second),
+                                      (first < 0) == (second < 0)));
+    Ok(Rooted::new(heap, Value::new_integer(product)))
+}
+
+fn multiply(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    multiply_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+/// Get the operands of a fixnum-specific operator. Unlike the generic operators,
+/// these only accept fixnums, so a single tag test on each operand is all the
+/// dispatch they need.
+fn fixnum_operands(operator: &str,
+                   a: &RootedValue,
+                   b: &RootedValue) -> Result<(i64, i64), SchemeError> {
+    match (**a, **b) {
+        (Value::Integer(first), Value::Integer(second)) => Ok((first, second)),
+        _ => Err(operand_type_error(operator, ArgumentType::Fixnum, a, b)),
+    }
+}
+
+/// Get the result of a fixnum-specific operator. Overflow is always an error,
+/// whatever the heap's `ArithmeticMode`, because these operators never promote
+/// or wrap their results.
+fn fixnum_result(heap: &mut Heap, operator: &str, result: Option<i64>) -> SchemeResult {
+    match result {
+        Some(n) if value::is_fixnum(n) => Ok(Rooted::new(heap, Value::new_integer(n))),
+        _ => Err(E0302.error(format!("Error: integer overflow in `{}`", operator))),
+    }
+}
+
+fn fx_add_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(fixnum_operands("fx+", a, b));
+    fixnum_result(heap, "fx+", first.checked_add(second))
+}
+
+fn fx_add(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fx_add_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fx_subtract_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(fixnum_operands("fx-", a, b));
+    fixnum_result(heap, "fx-", first.checked_sub(second))
+}
+
+fn fx_subtract(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fx_subtract_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fx_multiply_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(fixnum_operands("fx*", a, b));
+    fixnum_result(heap, "fx*", first.checked_mul(second))
+}
+
+fn fx_multiply(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fx_multiply_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fx_equal_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(fixnum_operands("fx=?", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first == second)))
+}
+
+fn fx_equal(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fx_equal_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fx_lt_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(fixnum_operands("fx<?", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first < second)))
+}
+
+fn fx_lt(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fx_lt_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fx_gt_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(fixnum_operands("fx>?", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first > second)))
+}
+
+fn fx_gt(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fx_gt_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fx_le_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(fixnum_operands("fx<=?", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first <= second)))
+}
+
+fn fx_le(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fx_le_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fx_ge_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(fixnum_operands("fx>=?", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first >= second)))
+}
+
+fn fx_ge(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fx_ge_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+/// Get the operands of a flonum-specific operator. Like the fixnum-specific
+/// operators, these only accept flonums, and never convert their operands.
+fn flonum_operands(operator: &str,
+                   a: &RootedValue,
+                   b: &RootedValue) -> Result<(f64, f64), SchemeError> {
+    match (**a, **b) {
+        (Value::Flonum(first), Value::Flonum(second)) => Ok((*first, *second)),
+        _ => Err(operand_type_error(operator, ArgumentType::Flonum, a, b)),
+    }
+}
+
+fn fl_add_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(flonum_operands("fl+", a, b));
+    Ok(Value::new_flonum(heap, first + second))
+}
+
+fn fl_add(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fl_add_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fl_subtract_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(flonum_operands("fl-", a, b));
+    Ok(Value::new_flonum(heap, first - second))
+}
+
+fn fl_subtract(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fl_subtract_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fl_multiply_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(flonum_operands("fl*", a, b));
+    Ok(Value::new_flonum(heap, first * second))
+}
+
+fn fl_multiply(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fl_multiply_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fl_divide_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(flonum_operands("fl/", a, b));
+    Ok(Value::new_flonum(heap, first / second))
+}
+
+fn fl_divide(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fl_divide_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fl_equal_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(flonum_operands("fl=?", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first == second)))
+}
+
+fn fl_equal(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fl_equal_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fl_lt_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(flonum_operands("fl<?", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first < second)))
+}
+
+fn fl_lt(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fl_lt_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fl_gt_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(flonum_operands("fl>?", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first > second)))
+}
+
+fn fl_gt(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fl_gt_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fl_le_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(flonum_operands("fl<=?", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first <= second)))
+}
+
+fn fl_le(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fl_le_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+fn fl_ge_value(heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+    let (first, second) = try!(flonum_operands("fl>=?", a, b));
+    Ok(Rooted::new(heap, Value::new_boolean(first >= second)))
+}
+
+fn fl_ge(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    fl_ge_value(heap, &args[0], &args[1]).map(Trampoline::Value)
+}
+
+/// Apply the given function to the argument of a unary flonum-specific
+/// primitive, which is declared to take a flonum.
+fn flonum_function(heap: &mut Heap,
+                   args: Vec<RootedValue>,
+                   function: fn(f64) -> f64) -> TrampolineResult {
+    let x = args[0].to_flonum().expect("Unary flonum primitives are declared to take a flonum");
+    Ok(Trampoline::Value(Value::new_flonum(heap, function(x))))
+}
+
+fn flabs(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::abs)
+}
+
+fn flsqrt(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::sqrt)
+}
+
+fn flexp(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::exp)
+}
+
+fn fllog(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::ln)
+}
+
+fn flsin(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::sin)
+}
+
+fn flcos(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::cos)
+}
+
+fn fltan(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::tan)
+}
+
+fn flatan(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::atan)
+}
+
+fn flfloor(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::floor)
+}
+
+fn flceiling(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::ceil)
+}
+
+fn flround(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::round)
+}
+
+fn fltruncate(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    flonum_function(heap, args, f64::trunc)
+}
+
+/// Apply the given function to the argument of a unary generic math primitive,
+/// which is declared to take any number. The result is always inexact.
+fn inexact_function(heap: &mut Heap,
+                    args: Vec<RootedValue>,
+                    function: fn(f64) -> f64) -> TrampolineResult {
+    let x = match *args[0] {
+        Value::Integer(i) => i as f64,
+        Value::Flonum(x)  => *x,
+        _ => unreachable!("Unary math primitives are declared to take a number"),
+    };
+    Ok(Trampoline::Value(Value::new_flonum(heap, function(x))))
+}
+
+fn sin(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    inexact_function(heap, args, f64::sin)
+}
+
+fn cos(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    inexact_function(heap, args, f64::cos)
+}
+
+fn tan(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    inexact_function(heap, args, f64::tan)
+}
+
+fn atan(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    inexact_function(heap, args, f64::atan)
+}
+
+fn log(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    inexact_function(heap, args, f64::ln)
+}
+
+fn exp(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    inexact_function(heap, args, f64::exp)
+}
+
+/// The square root of an exact perfect square is exact, e.g. `(sqrt 9)` is `3`,
+/// and every other square root is inexact.
+fn sqrt(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    if let Value::Integer(i) = *args[0] {
+        let root = (i as f64).sqrt() as i64;
+        if i >= 0 && root * root == i {
+            return Ok(Trampoline::Value(Rooted::new(heap, Value::new_integer(root))));
+        }
+    }
+    inexact_function(heap, args, f64::sqrt)
+}
+
+fn flonum_question(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(args[0].to_flonum().is_some()))))
+}
+
+fn inexact(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    match **arg {
+        Value::Integer(i) => Ok(Trampoline::Value(Value::new_flonum(heap, i as f64))),
+        Value::Flonum(_)  => Ok(Trampoline::Value(arg.clone())),
+        _ => unreachable!("`inexact` is declared to take a number"),
+    }
+}
+
+/// Convert a flonum to an exact integer. There are no exact rationals, so only
+/// flonums with an integral value can be converted.
+fn exact(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    match **arg {
+        Value::Integer(_) => Ok(Trampoline::Value(arg.clone())),
+        Value::Flonum(x)  => {
+            // Casting a flonum that is out of range of an `i64` is undefined,
+            // so check that it fits in a fixnum first.
+            let (min, max) = (value::MIN_FIXNUM as f64, -(value::MIN_FIXNUM as f64));
+            if *x < min || *x >= max {
+                return Err(E0314.error(format!("Error: {} does not fit in a fixnum", **arg)));
+            }
+            let i = *x as i64;
+            if *x != *x || i as f64 != *x {
+                return Err(SchemeError::new(format!("Error: cannot convert {} to an exact \
+                                                     integer", **arg)));
+            }
+            Ok(Trampoline::Value(Rooted::new(heap, Value::new_integer(i))))
+        },
+        _ => unreachable!("`exact` is declared to take a number"),
+    }
+}
+
+fn procedure_source(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    let procedure = match **arg {
+        Value::Procedure(p) => p,
+        _                   => {
+            return Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(false))));
+        },
+    };
+
+    let name = match procedure.name {
+        Some(ref name) => heap.get_or_create_symbol(name.clone()),
+        None           => Rooted::new(heap, Value::new_boolean(false)),
+    };
+    let items = [
+        name,
+        Value::new_string(heap, procedure.location.file.clone()),
+        Rooted::new(heap, Value::new_integer(procedure.location.line as i64)),
+        Rooted::new(heap, Value::new_integer(procedure.location.column as i64)),
+    ];
+    Ok(Trampoline::Value(value::list(heap, &items)))
+}
+
+fn procedure_arity(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    // A fixed arity is an integer, and any other is a pair of the minimum
+    // and maximum number of arguments, where a procedure with keyword
+    // parameters has no maximum. Primitives without a signature are #f.
+    let (min, max) = match **arg {
+        Value::Procedure(p) => match p.signature {
+            None                                   => (p.arity, Some(p.arity)),
+            Some(ref s) if s.keywords.is_empty()   => (s.required, Some(s.arity())),
+            Some(ref s)                            => (s.required, None),
+        },
+        Value::Primitive(p) => match p.signature() {
+            Some(s) => (s.min_arity as u32, s.max_arity.map(|max| max as u32)),
+            None    => {
+                return Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(false))));
+            },
+        },
+        _                   => unreachable!("`procedure-arity` is declared to take a procedure"),
+    };
+
+    let min_val = Rooted::new(heap, Value::new_integer(min as i64));
+    match max {
+        Some(max) if max == min => Ok(Trampoline::Value(min_val)),
+        Some(max)               => {
+            let max_val = Rooted::new(heap, Value::new_integer(max as i64));
+            Ok(Trampoline::Value(Value::new_pair(heap, &min_val, &max_val)))
+        },
+        None                    => {
+            let max_val = Rooted::new(heap, Value::new_boolean(false));
+            Ok(Trampoline::Value(Value::new_pair(heap, &min_val, &max_val)))
+        },
+    }
+}
+
+fn procedure_name(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    let name = match **arg {
+        Value::Procedure(p) => p.name.clone(),
+        Value::Primitive(p) => Some(p.name().to_string()),
+        _                   => unreachable!("`procedure-name` is declared to take a procedure"),
+    };
+    Ok(Trampoline::Value(match name {
+        Some(name) => heap.get_or_create_symbol(name),
+        None       => Rooted::new(heap, Value::new_boolean(false)),
+    }))
+}
+
+fn procedure_location(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    let location = match **arg {
+        Value::Procedure(p) => p.location.clone(),
+        _                   => {
+            return Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(false))));
+        },
+    };
+    let items = [
+        Value::new_string(heap, location.file),
+        Rooted::new(heap, Value::new_integer(location.line as i64)),
+        Rooted::new(heap, Value::new_integer(location.column as i64)),
+    ];
+    Ok(Trampoline::Value(value::list(heap, &items)))
+}
+
+fn procedure_documentation(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    let doc = match **arg {
+        Value::Procedure(p) => heap.documentation(&p.location),
+        Value::Primitive(p) => {
+            heap.primitive_info(p.name())
+                .and_then(|info| info.documentation)
+                .map(|doc| doc.to_string())
+        },
+        _                   => None,
+    };
+    Ok(Trampoline::Value(match doc {
+        Some(doc) => Value::new_string(heap, doc),
+        None      => Rooted::new(heap, Value::new_boolean(false)),
+    }))
+}
+
+/// List every installed primitive as a pair of its name and module, both as
+/// symbols.
+fn primitive_procedures(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    let registry : Vec<(&'static str, &'static str)> = heap.primitive_registry().iter()
+        .map(|info| (info.name, info.module))
+        .collect();
+    let mut entries = vec!();
+    for &(name, module) in registry.iter() {
+        let name = heap.get_or_create_symbol(name.to_string());
+        let module = heap.get_or_create_symbol(module.to_string());
+        entries.push(Value::new_pair(heap, &name, &module));
+    }
+    Ok(Trampoline::Value(value::list(heap, entries.as_slice())))
+}
+
+fn percent_disassemble(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let arg = &args[0];
+    let procedure = try!(arg.to_procedure(heap).ok_or(
+        E0312.error(format!("Error: `%disassemble` expects a procedure, found {}", **arg))));
+    let mut text = format!("procedure {} {} @ {}\n",
+                           procedure.name.as_ref()
+                               .map(|n| n.as_slice())
+                               .unwrap_or("<anonymous>"),
+                           procedure.arity,
+                           procedure.location);
+    let body = procedure.body.as_ref()
+        .expect("Should never see an uninitialized procedure!");
+    text.push_str(disassemble(&heap.environment, &**body).as_slice());
+    Ok(Trampoline::Value(Value::new_string(heap, text)))
+}
+
+fn where_(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let name = args[0].to_symbol(heap).expect("`where` is declared to take a symbol");
+    match heap.where_defined(&**name) {
+        Some(DefinitionOrigin::Source(loc)) => {
+            let items = [
+                Value::new_string(heap, loc.file.clone()),
+                Rooted::new(heap, Value::new_integer(loc.line as i64)),
+                Rooted::new(heap, Value::new_integer(loc.column as i64)),
+            ];
+            Ok(Trampoline::Value(value::list(heap, &items)))
+        },
+        Some(DefinitionOrigin::Primitive(_)) => {
+            Ok(Trampoline::Value(heap.get_or_create_symbol("primitive".to_string())))
+        },
+        None => Ok(Trampoline::Value(Rooted::new(heap, Value::new_boolean(false)))),
+    }
+}
+
+fn arithmetic_mode(heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
+    let sym = match args.first() {
+        Some(arg) => arg.to_symbol(heap).expect("`arithmetic-mode` is declared to take a symbol"),
+        None      => {
+            let name = heap.arithmetic_mode().name().to_string();
+            return Ok(Trampoline::Value(heap.get_or_create_symbol(name)));
+        },
+    };
+
+    let mode = try!(ArithmeticMode::from_name(sym.as_slice()).ok_or(
+        E0311.error(format!("Error: unknown arithmetic mode: {}", sym.as_slice()))));
+    heap.set_arithmetic_mode(mode);
+    Ok(Trampoline::Value(heap.unspecified_symbol()))
+}
+
+/// The primitives which `analyze` can call directly at their call sites,
+/// without collecting their arguments into a `Vec` or going through
+/// `apply_invocation`.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum FastPath {
+    Car,
+    Cdr,
+    Not,
+    NullQuestion,
+    PairQuestion,
+    Cons,
+    EqQuestion,
+    NumberEqual,
+    Gt,
+    Lt,
+    Add,
+    Subtract,
+    Divide,
+    Multiply,
+    FxAdd,
+    FxSubtract,
+    FxMultiply,
+    FxEqual,
+    FxLt,
+    FxGt,
+    FxLe,
+    FxGe,
+    FlAdd,
+    FlSubtract,
+    FlMultiply,
+    FlDivide,
+    FlEqual,
+    FlLt,
+    FlGt,
+    FlLe,
+    FlGe,
+}
+
+impl FastPath {
+    /// Get the fast path for the primitive with the given name, if it has one.
+    pub fn from_name(name: &str) -> Option<FastPath> {
+        match name {
+            "car"   => Some(FastPath::Car),
+            "cdr"   => Some(FastPath::Cdr),
+            "not"   => Some(FastPath::Not),
+            "null?" => Some(FastPath::NullQuestion),
+            "pair?" => Some(FastPath::PairQuestion),
+            "cons"  => Some(FastPath::Cons),
+            "eq?"   => Some(FastPath::EqQuestion),
+            "="     => Some(FastPath::NumberEqual),
+            ">"     => Some(FastPath::Gt),
+            "<"     => Some(FastPath::Lt),
+            "+"     => Some(FastPath::Add),
+            "-"     => Some(FastPath::Subtract),
+            "/"     => Some(FastPath::Divide),
+            "*"     => Some(FastPath::Multiply),
+            "fx+"   => Some(FastPath::FxAdd),
+            "fx-"   => Some(FastPath::FxSubtract),
+            "fx*"   => Some(FastPath::FxMultiply),
+            "fx=?"  => Some(FastPath::FxEqual),
+            "fx<?"  => Some(FastPath::FxLt),
+            "fx>?"  => Some(FastPath::FxGt),
+            "fx<=?" => Some(FastPath::FxLe),
+            "fx>=?" => Some(FastPath::FxGe),
+            "fl+"   => Some(FastPath::FlAdd),
+            "fl-"   => Some(FastPath::FlSubtract),
+            "fl*"   => Some(FastPath::FlMultiply),
+            "fl/"   => Some(FastPath::FlDivide),
+            "fl=?"  => Some(FastPath::FlEqual),
+            "fl<?"  => Some(FastPath::FlLt),
+            "fl>?"  => Some(FastPath::FlGt),
+            "fl<=?" => Some(FastPath::FlLe),
+            "fl>=?" => Some(FastPath::FlGe),
+            _       => None,
+        }
+    }
+
+    /// Get the number of arguments this primitive takes.
+    pub fn arity(&self) -> usize {
+        match *self {
+            FastPath::Car | FastPath::Cdr | FastPath::Not |
+            FastPath::NullQuestion | FastPath::PairQuestion => 1,
+            _                                                => 2,
+        }
+    }
+
+    /// Call this primitive, which must take one argument.
+    pub fn call1(&self, heap: &mut Heap, arg: &RootedValue) -> SchemeResult {
+        match *self {
+            FastPath::Car          => car_value(heap, arg),
+            FastPath::Cdr          => cdr_value(heap, arg),
+            FastPath::Not          => not_value(heap, arg),
+            FastPath::NullQuestion => null_question_value(heap, arg),
+            FastPath::PairQuestion => pair_question_value(heap, arg),
+            _ => panic!("`FastPath::call1` called on a binary primitive"),
+        }
+    }
+
+    /// Call this primitive, which must take two arguments.
+    pub fn call2(&self, heap: &mut Heap, a: &RootedValue, b: &RootedValue) -> SchemeResult {
+        match *self {
+            FastPath::Cons        => cons_value(heap, a, b),
+            FastPath::EqQuestion  => eq_question_value(heap, a, b),
+            FastPath::NumberEqual => number_equal_value(heap, a, b),
+            FastPath::Gt          => gt_value(heap, a, b),
+            FastPath::Lt          => lt_value(heap, a, b),
+            FastPath::Add         => add_value(heap, a, b),
+            FastPath::Subtract    => subtract_value(heap, a, b),
+            FastPath::Divide      => divide_value(heap, a, b),
+            FastPath::Multiply    => multiply_value(heap, a, b),
+            FastPath::FxAdd       => fx_add_value(heap, a, b),
+            FastPath::FxSubtract  => fx_subtract_value(heap, a, b),
+            FastPath::FxMultiply  => fx_multiply_value(heap, a, b),
+            FastPath::FxEqual     => fx_equal_value(heap, a, b),
+            FastPath::FxLt        => fx_lt_value(heap, a, b),
+            FastPath::FxGt        => fx_gt_value(heap, a, b),
+            FastPath::FxLe        => fx_le_value(heap, a, b),
+            FastPath::FxGe        => fx_ge_value(heap, a, b),
+            FastPath::FlAdd       => fl_add_value(heap, a, b),
+            FastPath::FlSubtract  => fl_subtract_value(heap, a, b),
+            FastPath::FlMultiply  => fl_multiply_value(heap, a, b),
+            FastPath::FlDivide    => fl_divide_value(heap, a, b),
+            FastPath::FlEqual     => fl_equal_value(heap, a, b),
+            FastPath::FlLt        => fl_lt_value(heap, a, b),
+            FastPath::FlGt        => fl_gt_value(heap, a, b),
+            FastPath::FlLe        => fl_le_value(heap, a, b),
+            FastPath::FlGe        => fl_ge_value(heap, a, b),
+            _ => panic!("`FastPath::call2` called on a unary primitive"),
+        }
+    }
+}
+
+/// The primitives whose results depend only on their arguments, and which
+/// have no side effects, so that calls to them with constant arguments can be
+/// folded during syntactic analysis. `cons` is not pure because each call
+/// returns a new pair. Flonum results are boxed too, but Scheme leaves whether
+/// equal numbers are `eq?` unspecified.
+static PURE_PRIMITIVES: [&'static str; 57] = [
+    "car", "cdr", "length",
+    "not", "null?", "pair?", "atom?", "eq?", "symbol?", "number?", "string?",
+    "=", ">", "<", "+", "-", "/", "*",
+    "fx+", "fx-", "fx*", "fx=?", "fx<?", "fx>?", "fx<=?", "fx>=?",
+    "fl+", "fl-", "fl*", "fl/", "fl=?", "fl<?", "fl>?", "fl<=?", "fl>=?",
+    "flabs", "flsqrt", "flexp", "fllog", "flsin", "flcos", "fltan", "flatan",
+    "flfloor", "flceiling", "flround", "fltruncate",
+    "sin", "cos", "tan", "atan", "log", "exp", "sqrt",
+    "flonum?", "inexact", "exact",
+];
+
+/// Return true if the primitive with the given name is pure, false otherwise.
+pub fn is_pure_primitive(name: &str) -> bool {
+    PURE_PRIMITIVES.iter().any(|p| *p == name)
+}
+
+/// The documentation strings of the builtin primitives, as returned by
+/// `procedure-documentation` and shown by the REPL's `,doc` command.
+static PRIMITIVE_DOCUMENTATION: [(&'static str, &'static str); 39] = [
+    ("cons", "Return a new pair of the given car and cdr."),
+    ("car", "Return the car of the given pair."),
+    ("cdr", "Return the cdr of the given pair."),
+    ("set-car!", "Set the car of the given pair to the given value."),
+    ("set-cdr!", "Set the cdr of the given pair to the given value."),
+    ("list", "Return a new list of the given arguments."),
+    ("length", "Return the number of items in the given list."),
+    ("iota", "Return a list of count numbers, from start and increasing by step."),
+    ("list-sort",
+     "Return a new list of the items of the given list, stably sorted by the given less-than \
+      procedure."),
+    ("vector?", "Return #t if the given value is a vector, and #f otherwise."),
+    ("vector->list", "Return a new list of the items of the given vector."),
+    ("vector-sort!", "Stably sort the given vector in place by the given less-than procedure."),
+    ("apply",
+     "Call the given procedure with the given arguments, the last of which is a list of more \
+      arguments."),
+    ("not", "Return #t if the given value is #f, and #f otherwise."),
+    ("null?", "Return #t if the given value is the empty list."),
+    ("pair?", "Return #t if the given value is a pair."),
+    ("atom?", "Return #t if the given value is not a pair."),
+    ("eq?", "Return #t if the given values are the same object."),
+    ("symbol?", "Return #t if the given value is a symbol."),
+    ("number?", "Return #t if the given value is a fixnum or a flonum."),
+    ("string?", "Return #t if the given value is a string."),
+    ("=", "Return #t if the given numbers are equal."),
+    (">", "Return #t if the first number is greater than the second."),
+    ("<", "Return #t if the first number is less than the second."),
+    ("+", "Return the sum of the given numbers."),
+    ("-", "Return the difference of the given numbers."),
+    ("*", "Return the product of the given numbers."),
+    ("/", "Return the quotient of the given numbers. Fixnum division truncates."),
+    ("flonum?", "Return #t if the given value is a flonum."),
+    ("inexact", "Return the given number as a flonum."),
+    ("exact", "Return the given number as a fixnum, if it has an integral value."),
+    ("sqrt",
+     "Return the square root of the given number, which is exact for exact perfect squares."),
+    ("procedure-arity", "Return the number of arguments the given procedure takes."),
+    ("procedure-documentation", "Return the documentation string of the given procedure, or #f."),
+    ("primitive-procedures",
+     "Return an association list of the name of every installed primitive and its module."),
+    ("eval", "Evaluate the given form in the given environment, or the interaction environment."),
+    ("dynamic-wind",
+     "Call before, then thunk, then after, even if thunk fails or exits, and return the value \
+      of thunk."),
+    ("error", "Signal an error with the given message and irritants."),
+    ("format", "Format the given arguments according to the given format string."),
+];
+
+/// Get the documentation string of the builtin primitive with the given name,
+/// if it has one.
+fn primitive_documentation(name: &str) -> Option<&'static str> {
+    PRIMITIVE_DOCUMENTATION.iter()
+        .find(|&&(primitive, _)| primitive == name)
+        .map(|&(_, doc)| doc)
+}
+
+/// A group of related primitives that can be granted to, or withheld from, the
+/// scripts evaluated in a heap. See `PrimitiveSet`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Capability {
+    /// Pairs, lists, predicates, arithmetic, random numbers, channels,
+    /// generators, continuation marks, `dynamic-wind`, environments, `eval`,
+    /// `apply`, `error`, `assert`, `format`, and `read-line` and `close-port`.
+    /// These only compute with their arguments and their own state, and are
+    /// always safe to grant: ports can only be made with `Process` and
+    /// `Network`. `format`
+    /// only writes to standard output with `Io`.
+    Pure,
+    /// Reading from stdin and printing to stdout: `read`, `print`, and the
+    /// `%time` primitive behind the `time` macro. `read` also reads from ports.
+    Io,
+    /// Inspecting and configuring the host and the runtime, such as
+    /// `command-line`, `gc-stats`, the clock, environment variables, `exit`,
+    /// `register-finalizer!`, and the REPL settings.
+    Host,
+    /// Introspecting procedures and debugging them, such as `procedure-source`,
+    /// `where`, `trace`, and breakpoints.
+    Debugging,
+    /// Inspecting and releasing the foreign and native objects handed to
+    /// Scheme by the embedder.
+    Foreign,
+    /// Spawning external commands and talking to them through ports, such as
+    /// `run-process`, `process-input`, and `process-wait`. A script granted this can do anything
+    /// the host process can.
+    Process,
+    /// Connecting to and listening on TCP sockets, such as `tcp-connect` and
+    /// `tcp-listen`. Connections are ports.
+    Network,
+}
+
+impl Capability {
+    /// Get the name of the module of primitives with this capability, as
+    /// listed by `(primitive-procedures)`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Capability::Pure      => "pure",
+            Capability::Io        => "io",
+            Capability::Host      => "host",
+            Capability::Debugging => "debugging",
+            Capability::Foreign   => "foreign",
+            Capability::Process   => "process",
+            Capability::Network   => "network",
+        }
+    }
+}
+
+/// The module of the primitives defined by the embedder, with
+/// `PrimitiveSet::define` or `Heap::register_primitive`, rather than built in.
+pub static EMBEDDER_MODULE: &'static str = "embedder";
+
+/// Everything a heap knows about one of the primitives installed in its global
+/// environment. See `Heap::primitive_registry`.
+#[derive(Copy, Clone, Debug)]
+pub struct PrimitiveInfo {
+    /// The name the primitive is bound to.
+    pub name: &'static str,
+    /// The name of the capability the primitive was installed with, or
+    /// `EMBEDDER_MODULE`.
+    pub module: &'static str,
+    /// The arity and argument types the primitive declared, if it did.
+    pub signature: Option<PrimitiveSignature>,
+    /// The primitive's documentation string, if it has one.
+    pub documentation: Option<&'static str>,
+}
+
+/// Every capability. A `PrimitiveSet::all` set grants each of these.
+static ALL_CAPABILITIES: [Capability; 7] = [
+    Capability::Pure,
+    Capability::Io,
+    Capability::Host,
+    Capability::Debugging,
+    Capability::Foreign,
+    Capability::Process,
+    Capability::Network,
+];
+
+/// A builder describing which primitives a new heap's global environment gets,
+/// passed to `Heap::with_config` as `HeapConfig::primitives`.
+///
+/// Embedders running untrusted scripts can start from `PrimitiveSet::pure` and
+/// grant just the capabilities they need:
+///
+///     let config = HeapConfig {
+///         primitives: PrimitiveSet::pure()
+///             .grant(Capability::Io)
+///             .remove("read")
+///             .define("log", my_log),
+///         .. Default::default()
+///     };
+pub struct PrimitiveSet {
+    capabilities: Vec<Capability>,
+    removed: Vec<&'static str>,
+    defined: Vec<(&'static str, PrimitiveFunction)>,
+}
+
+impl PrimitiveSet {
+    /// Get the set of every primitive. This is the default.
+    pub fn all() -> PrimitiveSet {
+        PrimitiveSet {
+            capabilities: ALL_CAPABILITIES.to_vec(),
+            removed: vec!(),
+            defined: vec!(),
+        }
+    }
+
+    /// Get the set of only the `Capability::Pure` primitives.
+    pub fn pure() -> PrimitiveSet {
+        PrimitiveSet {
+            capabilities: vec!(Capability::Pure),
+            removed: vec!(),
+            defined: vec!(),
+        }
+    }
+
+    /// Add the primitives with the given capability to this set.
+    pub fn grant(mut self, capability: Capability) -> PrimitiveSet {
+        if !self.has_capability(capability) {
+            self.capabilities.push(capability);
+        }
+        self
+    }
+
+    /// Remove the primitives with the given capability from this set.
+    pub fn revoke(mut self, capability: Capability) -> PrimitiveSet {
+        self.capabilities.retain(|c| *c != capability);
+        self
+    }
+
+    /// Remove the primitive with the given name from this set, whatever its
+    /// capability.
+    pub fn remove(mut self, name: &'static str) -> PrimitiveSet {
+        self.removed.push(name);
+        self
+    }
+
+    /// Add a primitive with the given name and function to this set, replacing
+    /// any builtin primitive with the same name.
+    pub fn define(mut self, name: &'static str, function: PrimitiveFunction) -> PrimitiveSet {
+        self.defined.push((name, function));
+        self
+    }
+
+    /// Return true if this set grants the given capability, false otherwise.
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities.iter().any(|c| *c == capability)
+    }
+
+    /// Return true if this set includes the builtin primitive with the given
+    /// name and capability, false otherwise.
+    fn includes(&self, capability: Capability, name: &str) -> bool {
+        self.has_capability(capability) && !self.removed.iter().any(|n| *n == name)
+    }
+
+    /// Define the primitives in this set on the global activation, adding
+    /// them to the given table, and return what was installed, in order.
+    pub fn install(&self,
+                   table: &mut PrimitiveTable,
+                   env: &mut Environment,
+                   act: &mut ActivationPtr) -> Vec<PrimitiveInfo> {
+        let mut installed = vec!();
+        install_builtins(&mut Installer {
+            table: &mut *table,
+            env: &mut *env,
+            act: &mut *act,
+            set: self,
+            installed: &mut installed,
+        });
+
+        for &(name, function) in self.defined.iter() {
+            define_value(env, act, name, Value::new_primitive(table, name, function));
+            installed.push(PrimitiveInfo {
+                name: name,
+                module: EMBEDDER_MODULE,
+                signature: None,
+                documentation: None,
+            });
+        }
+        installed
+    }
+}
+
+impl Clone for PrimitiveSet {
+    fn clone(&self) -> PrimitiveSet {
+        PrimitiveSet {
+            capabilities: self.capabilities.clone(),
+            removed: self.removed.clone(),
+            defined: self.defined.iter().map(|&(name, function)| (name, function)).collect(),
+        }
+    }
+}
+
+impl fmt::Debug for PrimitiveSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let defined : Vec<&'static str> = self.defined.iter().map(|&(name, _)| name).collect();
+        write!(f, "PrimitiveSet {{ capabilities: {:?}, removed: {:?}, defined: {:?} }}",
+               self.capabilities, self.removed, defined)
+    }
+}
+
+impl Default for PrimitiveSet {
+    fn default() -> PrimitiveSet {
+        PrimitiveSet::all()
+    }
+}
+
+/// Defines the builtin primitives that a `PrimitiveSet` includes.
+struct Installer<'a> {
+    table: &'a mut PrimitiveTable,
+    env: &'a mut Environment,
+    act: &'a mut ActivationPtr,
+    set: &'a PrimitiveSet,
+    installed: &'a mut Vec<PrimitiveInfo>,
+}
+
+impl<'a> Installer<'a> {
+    /// Define the builtin primitive with the given name, if the set includes
+    /// it. Its arguments are checked against the given signature before the
+    /// function is called.
+    fn define_checked(&mut self,
+                      capability: Capability,
+                      name: &'static str,
+                      signature: PrimitiveSignature,
+                      function: PrimitiveFunction) {
+        if self.set.includes(capability, name) {
+            let primitive = Value::new_builtin_primitive(self.table, name, function,
+                                                         Some(signature));
+            define_value(self.env, self.act, name, primitive);
+            self.record(capability, name, Some(signature));
+        }
+    }
+
+    /// Define a stub for the primitive with the given name, which is not
+    /// available because the given feature is missing, if the set includes it.
+    /// This way, using the primitive is an error naming the missing feature,
+    /// rather than an unbound variable error that looks like a typo.
+    #[cfg(not(feature = "debugging"))]
+    fn define_unavailable(&mut self,
+                          capability: Capability,
+                          name: &'static str,
+                          feature: &'static str) {
+        if self.set.includes(capability, name) {
+            let primitive = Value::new_unavailable_primitive(self.table, name, feature);
+            define_value(self.env, self.act, name, primitive);
+            self.record(capability, name, None);
+        }
+    }
+
+    /// Record that the builtin primitive with the given name was installed.
+    fn record(&mut self,
+              capability: Capability,
+              name: &'static str,
+              signature: Option<PrimitiveSignature>) {
+        self.installed.push(PrimitiveInfo {
+            name: name,
+            module: capability.name(),
+            signature: signature,
+            documentation: primitive_documentation(name),
+        });
+    }
+}
+
+fn define_value(env: &mut Environment,
+                act: &mut ActivationPtr,
+                name: &'static str,
+                primitive: Value) {
+    let id = env.intern(name);
+    let (i, j) = env.define(id);
+    assert!(i == 0, "All primitives should be defined on the global activation");
+    act.define(j, primitive);
+}
+
+/// The primitives provided by the `debugging` feature.
+#[cfg(not(feature = "debugging"))]
+static DEBUGGING_PRIMITIVES: [&'static str; 5] = [
+    "trace",
+    "untrace",
+    "set-breakpoint!",
+    "clear-breakpoint!",
+    "profile-report",
+];
+
+#[cfg(feature = "debugging")]
+fn define_debugging_primitives(installer: &mut Installer) {
+    let fixed = PrimitiveSignature::fixed;
+
+    installer.define_checked(Capability::Debugging, "trace", fixed(&PROCEDURE), trace);
+    installer.define_checked(Capability::Debugging, "untrace", fixed(&ANY), untrace);
+    installer.define_checked(Capability::Debugging, "set-breakpoint!", fixed(&STRING_FIXNUM),
+                             set_breakpoint_bang);
+    installer.define_checked(Capability::Debugging, "clear-breakpoint!", fixed(&STRING_FIXNUM),
+                             clear_breakpoint_bang);
+    installer.define_checked(Capability::Debugging, "profile-report", fixed(&NO_ARGUMENTS),
+                             profile_report);
+}
 
-    define_primitive(env, act, "=", number_equal);
-    define_primitive(env, act, ">", gt);
-    define_primitive(env, act, "<", lt);
+#[cfg(not(feature = "debugging"))]
+fn define_debugging_primitives(installer: &mut Installer) {
+    for name in DEBUGGING_PRIMITIVES.iter() {
+        installer.define_unavailable(Capability::Debugging, *name, "debugging");
+    }
+}
 
-    define_primitive(env, act, "+", add);
-    define_primitive(env, act, "-", subtract);
-    define_primitive(env, act, "/", divide);
-    define_primitive(env, act, "*", multiply);
+// The argument types in the builtin primitives' signatures. Many primitives
+// take the same types, so they share these.
+static NO_ARGUMENTS: [ArgumentType; 0] = [];
+static ANY: [ArgumentType; 1] = [ArgumentType::Any];
+static ANY_ANY: [ArgumentType; 2] = [ArgumentType::Any, ArgumentType::Any];
+static PAIR: [ArgumentType; 1] = [ArgumentType::Pair];
+static PAIR_ANY: [ArgumentType; 2] = [ArgumentType::Pair, ArgumentType::Any];
+static SYMBOL: [ArgumentType; 1] = [ArgumentType::Symbol];
+static PROCEDURE: [ArgumentType; 1] = [ArgumentType::Procedure];
+static PROCEDURE_ANY: [ArgumentType; 2] = [ArgumentType::Procedure, ArgumentType::Any];
+static VECTOR: [ArgumentType; 1] = [ArgumentType::Vector];
+static PROCEDURE_VECTOR: [ArgumentType; 2] = [ArgumentType::Procedure, ArgumentType::Vector];
+static NUMBER: [ArgumentType; 1] = [ArgumentType::Number];
+static NUMBER_NUMBER: [ArgumentType; 2] = [ArgumentType::Number, ArgumentType::Number];
+static FIXNUM_FIXNUM: [ArgumentType; 2] = [ArgumentType::Fixnum, ArgumentType::Fixnum];
+static FLONUM: [ArgumentType; 1] = [ArgumentType::Flonum];
+static FLONUM_FLONUM: [ArgumentType; 2] = [ArgumentType::Flonum, ArgumentType::Flonum];
+static FIXNUM_FIXNUM_FIXNUM: [ArgumentType; 3] =
+    [ArgumentType::Fixnum, ArgumentType::Fixnum, ArgumentType::Fixnum];
+static FIXNUM_ANY: [ArgumentType; 2] = [ArgumentType::Fixnum, ArgumentType::Any];
+static ANY_FIXNUM_FIXNUM: [ArgumentType; 3] =
+    [ArgumentType::Any, ArgumentType::Fixnum, ArgumentType::Fixnum];
+static ANY_SYMBOL: [ArgumentType; 2] = [ArgumentType::Any, ArgumentType::Symbol];
+static ANY_PROCEDURE: [ArgumentType; 2] = [ArgumentType::Any, ArgumentType::Procedure];
+static ANY_ANY_PROCEDURE: [ArgumentType; 3] =
+    [ArgumentType::Any, ArgumentType::Any, ArgumentType::Procedure];
+static PROCEDURE_PROCEDURE_PROCEDURE: [ArgumentType; 3] =
+    [ArgumentType::Procedure, ArgumentType::Procedure, ArgumentType::Procedure];
+static STRING: [ArgumentType; 1] = [ArgumentType::String];
+static STRING_FIXNUM: [ArgumentType; 2] = [ArgumentType::String, ArgumentType::Fixnum];
+
+fn install_builtins(installer: &mut Installer) {
+    let fixed = PrimitiveSignature::fixed;
+    let variadic = PrimitiveSignature::variadic;
+    let optional = PrimitiveSignature::optional;
+
+    installer.define_checked(Capability::Pure, "cons", fixed(&ANY_ANY), cons);
+    installer.define_checked(Capability::Pure, "car", fixed(&PAIR), car);
+    installer.define_checked(Capability::Pure, "set-car!", fixed(&PAIR_ANY), set_car_bang);
+    installer.define_checked(Capability::Pure, "cdr", fixed(&PAIR), cdr);
+    installer.define_checked(Capability::Pure, "set-cdr!", fixed(&PAIR_ANY), set_cdr_bang);
+
+    installer.define_checked(Capability::Pure, "list", variadic(0, &NO_ARGUMENTS), list);
+    installer.define_checked(Capability::Pure, "length", fixed(&ANY), length);
+    installer.define_checked(Capability::Pure, "iota", optional(1, &FIXNUM_FIXNUM_FIXNUM), iota);
+    installer.define_checked(Capability::Pure, "list-sort", fixed(&PROCEDURE_ANY), list_sort);
+    installer.define_checked(Capability::Pure, "vector?", fixed(&ANY), vector_question);
+    installer.define_checked(Capability::Pure, "vector->list", fixed(&VECTOR), vector_to_list);
+    installer.define_checked(Capability::Pure, "vector-sort!", fixed(&PROCEDURE_VECTOR),
+                             vector_sort_bang);
+
+    installer.define_checked(Capability::Pure, "apply", fixed(&PROCEDURE_ANY), apply);
+    installer.define_checked(Capability::Debugging, "procedure-source", fixed(&PROCEDURE),
+                             procedure_source);
+    installer.define_checked(Capability::Debugging, "procedure-arity", fixed(&PROCEDURE),
+                             procedure_arity);
+    installer.define_checked(Capability::Debugging, "procedure-name", fixed(&PROCEDURE),
+                             procedure_name);
+    installer.define_checked(Capability::Debugging, "procedure-location", fixed(&PROCEDURE),
+                             procedure_location);
+    installer.define_checked(Capability::Debugging, "procedure-documentation", fixed(&PROCEDURE),
+                             procedure_documentation);
+    installer.define_checked(Capability::Debugging, "primitive-procedures", fixed(&NO_ARGUMENTS),
+                             primitive_procedures);
+    installer.define_checked(Capability::Debugging, "%disassemble", fixed(&PROCEDURE),
+                             percent_disassemble);
+    installer.define_checked(Capability::Debugging, "where", fixed(&SYMBOL), where_);
+
+    define_debugging_primitives(installer);
+
+    installer.define_checked(Capability::Foreign, "foreign?", fixed(&ANY), foreign_question);
+    installer.define_checked(Capability::Foreign, "foreign-released?", fixed(&ANY),
+                             foreign_released_question);
+    installer.define_checked(Capability::Foreign, "foreign-release!", fixed(&ANY),
+                             foreign_release_bang);
+    installer.define_checked(Capability::Foreign, "native?", fixed(&ANY), native_question);
+
+    installer.define_checked(Capability::Pure, "error", variadic(1, &ANY), error);
+    installer.define_checked(Capability::Pure, "assert", fixed(&ANY), assert);
+    installer.define_checked(Capability::Pure, "format", variadic(1, &ANY), format);
+
+    installer.define_checked(Capability::Pure, "make-channel", fixed(&NO_ARGUMENTS), make_channel);
+    installer.define_checked(Capability::Pure, "channel-send", fixed(&ANY_ANY), channel_send);
+    installer.define_checked(Capability::Pure, "channel-receive", fixed(&ANY), channel_receive);
+    installer.define_checked(Capability::Pure, "channel-try-receive", fixed(&ANY_ANY),
+                             channel_try_receive);
+
+    installer.define_checked(Capability::Pure, "read-line", fixed(&ANY), read_line);
+    installer.define_checked(Capability::Pure, "close-port", fixed(&ANY), close_port);
+
+    installer.define_checked(Capability::Pure, "%with-continuation-mark", fixed(&ANY_ANY_PROCEDURE),
+                             percent_with_continuation_mark);
+    installer.define_checked(Capability::Pure, "current-continuation-marks", fixed(&NO_ARGUMENTS),
+                             current_continuation_marks);
+    installer.define_checked(Capability::Pure, "dynamic-wind",
+                             fixed(&PROCEDURE_PROCEDURE_PROCEDURE), dynamic_wind);
+
+    installer.define_checked(Capability::Pure, "%default-object?", fixed(&ANY),
+                             percent_default_object_question);
+
+    installer.define_checked(Capability::Pure, "eval", optional(1, &ANY_ANY), eval);
+    installer.define_checked(Capability::Pure, "interaction-environment", fixed(&NO_ARGUMENTS),
+                             interaction_environment);
+    installer.define_checked(Capability::Pure, "environment?", fixed(&ANY), environment_question);
+    installer.define_checked(Capability::Pure, "environment-bindings", fixed(&ANY),
+                             environment_bindings);
+    installer.define_checked(Capability::Pure, "environment-ref", fixed(&ANY_SYMBOL),
+                             environment_ref);
+
+    installer.define_checked(Capability::Pure, "make-generator", fixed(&ANY), make_generator);
+    installer.define_checked(Capability::Pure, "generator-next", optional(1, &ANY_ANY),
+                             generator_next);
+
+    installer.define_checked(Capability::Pure, "random-integer", optional(1, &FIXNUM_ANY),
+                             random_integer);
+    installer.define_checked(Capability::Pure, "make-random-source", fixed(&NO_ARGUMENTS),
+                             make_random_source);
+    installer.define_checked(Capability::Pure, "random-source?", fixed(&ANY),
+                             random_source_question);
+    installer.define_checked(Capability::Pure, "random-source-randomize!", fixed(&ANY),
+                             random_source_randomize_bang);
+    installer.define_checked(Capability::Pure, "random-source-pseudo-randomize!",
+                             fixed(&ANY_FIXNUM_FIXNUM), random_source_pseudo_randomize_bang);
+    installer.define_checked(Capability::Io, "print", variadic(0, &NO_ARGUMENTS), print);
+    installer.define_checked(Capability::Io, "read", optional(0, &ANY), read);
+    installer.define_checked(Capability::Io, "%time", fixed(&PROCEDURE), percent_time);
+    installer.define_checked(Capability::Host, "command-line", fixed(&NO_ARGUMENTS), command_line);
+    installer.define_checked(Capability::Host, "gc-stats", fixed(&NO_ARGUMENTS), gc_stats);
+    installer.define_checked(Capability::Host, "current-second", fixed(&NO_ARGUMENTS),
+                             current_second);
+    installer.define_checked(Capability::Host, "current-jiffy", fixed(&NO_ARGUMENTS),
+                             current_jiffy);
+    installer.define_checked(Capability::Host, "jiffies-per-second", fixed(&NO_ARGUMENTS),
+                             jiffies_per_second);
+    installer.define_checked(Capability::Host, "get-environment-variable", fixed(&STRING),
+                             get_environment_variable);
+    installer.define_checked(Capability::Host, "get-environment-variables", fixed(&NO_ARGUMENTS),
+                             get_environment_variables);
+    installer.define_checked(Capability::Host, "exit", optional(0, &ANY), exit);
+
+    installer.define_checked(Capability::Process, "run-process", variadic(1, &STRING), run_process);
+    installer.define_checked(Capability::Process, "process-input", fixed(&ANY), process_input);
+    installer.define_checked(Capability::Process, "process-output", fixed(&ANY),
+                             process_output);
+    installer.define_checked(Capability::Process, "process-wait", fixed(&ANY), process_wait);
+
+    installer.define_checked(Capability::Network, "tcp-connect", fixed(&STRING_FIXNUM),
+                             tcp_connect);
+    installer.define_checked(Capability::Network, "tcp-listen", fixed(&STRING_FIXNUM), tcp_listen);
+    installer.define_checked(Capability::Network, "tcp-listener-port", fixed(&ANY),
+                             tcp_listener_port);
+    installer.define_checked(Capability::Network, "tcp-accept", fixed(&ANY), tcp_accept);
+    installer.define_checked(Capability::Host, "emergency-exit", optional(0, &ANY), emergency_exit);
+    installer.define_checked(Capability::Host, "register-finalizer!", fixed(&ANY_PROCEDURE),
+                             register_finalizer_bang);
+    installer.define_checked(Capability::Host, "set-repl-prompt!", fixed(&ANY),
+                             set_repl_prompt_bang);
+    installer.define_checked(Capability::Host, "set-repl-banner!", fixed(&STRING),
+                             set_repl_banner_bang);
+
+    installer.define_checked(Capability::Pure, "not", fixed(&ANY), not);
+    installer.define_checked(Capability::Pure, "null?", fixed(&ANY), null_question);
+    installer.define_checked(Capability::Pure, "pair?", fixed(&ANY), pair_question);
+    installer.define_checked(Capability::Pure, "atom?", fixed(&ANY), atom_question);
+    installer.define_checked(Capability::Pure, "eq?", fixed(&ANY_ANY), eq_question);
+    installer.define_checked(Capability::Pure, "symbol?", fixed(&ANY), symbol_question);
+    installer.define_checked(Capability::Pure, "number?", fixed(&ANY), number_question);
+    installer.define_checked(Capability::Pure, "string?", fixed(&ANY), string_question);
+
+    installer.define_checked(Capability::Pure, "=", fixed(&NUMBER_NUMBER), number_equal);
+    installer.define_checked(Capability::Pure, ">", fixed(&NUMBER_NUMBER), gt);
+    installer.define_checked(Capability::Pure, "<", fixed(&NUMBER_NUMBER), lt);
+
+    installer.define_checked(Capability::Pure, "+", fixed(&NUMBER_NUMBER), add);
+    installer.define_checked(Capability::Pure, "-", fixed(&NUMBER_NUMBER), subtract);
+    installer.define_checked(Capability::Pure, "/", fixed(&NUMBER_NUMBER), divide);
+    installer.define_checked(Capability::Pure, "*", fixed(&NUMBER_NUMBER), multiply);
+
+    installer.define_checked(Capability::Pure, "fx+", fixed(&FIXNUM_FIXNUM), fx_add);
+    installer.define_checked(Capability::Pure, "fx-", fixed(&FIXNUM_FIXNUM), fx_subtract);
+    installer.define_checked(Capability::Pure, "fx*", fixed(&FIXNUM_FIXNUM), fx_multiply);
+    installer.define_checked(Capability::Pure, "fx=?", fixed(&FIXNUM_FIXNUM), fx_equal);
+    installer.define_checked(Capability::Pure, "fx<?", fixed(&FIXNUM_FIXNUM), fx_lt);
+    installer.define_checked(Capability::Pure, "fx>?", fixed(&FIXNUM_FIXNUM), fx_gt);
+    installer.define_checked(Capability::Pure, "fx<=?", fixed(&FIXNUM_FIXNUM), fx_le);
+    installer.define_checked(Capability::Pure, "fx>=?", fixed(&FIXNUM_FIXNUM), fx_ge);
+
+    installer.define_checked(Capability::Pure, "fl+", fixed(&FLONUM_FLONUM), fl_add);
+    installer.define_checked(Capability::Pure, "fl-", fixed(&FLONUM_FLONUM), fl_subtract);
+    installer.define_checked(Capability::Pure, "fl*", fixed(&FLONUM_FLONUM), fl_multiply);
+    installer.define_checked(Capability::Pure, "fl/", fixed(&FLONUM_FLONUM), fl_divide);
+    installer.define_checked(Capability::Pure, "fl=?", fixed(&FLONUM_FLONUM), fl_equal);
+    installer.define_checked(Capability::Pure, "fl<?", fixed(&FLONUM_FLONUM), fl_lt);
+    installer.define_checked(Capability::Pure, "fl>?", fixed(&FLONUM_FLONUM), fl_gt);
+    installer.define_checked(Capability::Pure, "fl<=?", fixed(&FLONUM_FLONUM), fl_le);
+    installer.define_checked(Capability::Pure, "fl>=?", fixed(&FLONUM_FLONUM), fl_ge);
+    installer.define_checked(Capability::Pure, "flabs", fixed(&FLONUM), flabs);
+    installer.define_checked(Capability::Pure, "flsqrt", fixed(&FLONUM), flsqrt);
+    installer.define_checked(Capability::Pure, "flexp", fixed(&FLONUM), flexp);
+    installer.define_checked(Capability::Pure, "fllog", fixed(&FLONUM), fllog);
+    installer.define_checked(Capability::Pure, "flsin", fixed(&FLONUM), flsin);
+    installer.define_checked(Capability::Pure, "flcos", fixed(&FLONUM), flcos);
+    installer.define_checked(Capability::Pure, "fltan", fixed(&FLONUM), fltan);
+    installer.define_checked(Capability::Pure, "flatan", fixed(&FLONUM), flatan);
+    installer.define_checked(Capability::Pure, "flfloor", fixed(&FLONUM), flfloor);
+    installer.define_checked(Capability::Pure, "flceiling", fixed(&FLONUM), flceiling);
+    installer.define_checked(Capability::Pure, "flround", fixed(&FLONUM), flround);
+    installer.define_checked(Capability::Pure, "fltruncate", fixed(&FLONUM), fltruncate);
+    installer.define_checked(Capability::Pure, "flonum?", fixed(&ANY), flonum_question);
+    installer.define_checked(Capability::Pure, "inexact", fixed(&NUMBER), inexact);
+    installer.define_checked(Capability::Pure, "exact", fixed(&NUMBER), exact);
+
+    installer.define_checked(Capability::Pure, "sin", fixed(&NUMBER), sin);
+    installer.define_checked(Capability::Pure, "cos", fixed(&NUMBER), cos);
+    installer.define_checked(Capability::Pure, "tan", fixed(&NUMBER), tan);
+    installer.define_checked(Capability::Pure, "atan", fixed(&NUMBER), atan);
+    installer.define_checked(Capability::Pure, "log", fixed(&NUMBER), log);
+    installer.define_checked(Capability::Pure, "exp", fixed(&NUMBER), exp);
+    installer.define_checked(Capability::Pure, "sqrt", fixed(&NUMBER), sqrt);
+    installer.define_checked(Capability::Host, "arithmetic-mode", optional(0, &SYMBOL),
+                             arithmetic_mode);
 }
 
 // TESTS -----------------------------------------------------------------------
 
 #[cfg(test)]
 mod tests {
-    use eval::{evaluate_file};
-    use heap::{Heap};
+    use eval::{apply_invocation, evaluate, evaluate_file};
+    use heap::{Heap, Rooted};
+    use read::{Location};
     use value::{Value};
 
     #[test]
@@ -463,16 +2692,540 @@ mod tests {
         assert_eq!(*result, Value::new_integer(3));
     }
 
+    #[test]
+    fn test_primitives_procedure_introspection() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_procedure_introspection.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result),
+                   "((2 (1 . 3) (0 . #f) 1 #f) \
+                    (add #f car) \
+                    ((\"./tests/test_primitives_procedure_introspection.scm\" 2 1) #f))");
+
+        let error = heap.eval_str("(procedure-name 5)").err().expect("Should fail.");
+        assert!(error.contains("Error: `procedure-name` expects a procedure as argument 1, \
+                                found 5"),
+                "{}", error);
+    }
+
+    #[test]
+    fn test_primitives_procedure_documentation() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_procedure_documentation.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result),
+                   "(\"Add two numbers.\" #f \"hello\" \"Return x.\" \
+                    \"Return the car of the given pair.\")");
+    }
+
+    #[test]
+    fn test_primitives_procedure_source() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_procedure_source.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        let pair = result.to_pair(heap)
+            .expect("Result should be a pair");
+        let name = heap.get_or_create_symbol("add-one".to_string());
+        assert_eq!(pair.car(heap), name);
+        assert_eq!(*pair.caddr(heap).ok().expect("pair.caddr"),
+                   Value::new_integer(2));
+        assert_eq!(*pair.cadddr(heap).ok().expect("pair.cadddr"),
+                   Value::new_integer(1));
+    }
+
+    #[test]
+    fn test_primitives_disassemble() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_disassemble.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        match *result {
+            Value::String(str) => {
+                let file = "./tests/test_primitives_disassemble.scm";
+                let expected_start = format!("procedure add-one 1 @ {0}:1:1\n\
+                                              primitive-invocation @ {0}:1:29\n",
+                                             file);
+                assert!(str.starts_with(expected_start.as_slice()));
+                assert!(str.contains("  reference x (0, 0) @ "));
+                assert!(str.contains("  quotation 1 @ "));
+            },
+            _ => panic!("Result should be a string"),
+        }
+    }
+
+    #[test]
+    fn test_primitives_arity_error() {
+        let heap = &mut Heap::new();
+        let error = evaluate_file(heap, "./tests/test_primitives_arity_error.scm")
+            .err()
+            .expect("Should get an error evaluating this file.");
+        assert!(error.contains("expected 1 args for `add-one` defined at \
+                                ./tests/test_primitives_arity_error.scm:1:1, found 2"));
+    }
+
+    #[test]
+    fn test_primitives_where() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_where.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        let pair = result.to_pair(heap)
+            .expect("Result should be a pair");
+
+        let defined = pair.car(heap).to_pair(heap)
+            .expect("Should have a location for a defined variable");
+        assert_eq!(*defined.cadr(heap).ok().expect("defined.cadr"),
+                   Value::new_integer(2));
+
+        let primitive = heap.get_or_create_symbol("primitive".to_string());
+        assert_eq!(pair.cadr(heap).ok().expect("pair.cadr"), primitive);
+        assert_eq!(*pair.caddr(heap).ok().expect("pair.caddr"),
+                   Value::new_boolean(false));
+    }
+
+    #[test]
+    #[cfg(feature = "debugging")]
+    fn test_primitives_trace() {
+        use std::old_io::{ChanWriter, Writer};
+        use std::old_io::stdio;
+        use std::sync::mpsc::channel;
+
+        let heap = &mut Heap::new();
+
+        // Capture what the traced calls print to standard output.
+        let (sender, receiver) = channel();
+        let previous = stdio::set_stdout(Box::new(ChanWriter::new(sender)));
+        let result = evaluate_file(heap, "./tests/test_primitives_trace.scm");
+        // Restoring the previous standard output drops our writer, which closes
+        // the channel so that we can read everything that was sent over it.
+        stdio::set_stdout(previous.unwrap_or_else(|| {
+            Box::new(stdio::stdout()) as Box<Writer + Send>
+        }));
+        let mut output = vec!();
+        for bytes in receiver.iter() {
+            output.push_all(bytes.as_slice());
+        }
+        let output = String::from_utf8(output)
+            .ok()
+            .expect("The trace should be valid UTF-8.");
+
+        let result = result.ok().expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(6));
+        assert_eq!(heap.trace_depth(), 0);
+        assert_eq!(output.as_slice(), "> (fact 3)\n\
+                                       | > (fact 2)\n\
+                                       | | > (fact 1)\n\
+                                       | | < 1\n\
+                                       | < 2\n\
+                                       < 6\n");
+
+        let fact = heap.get_or_create_symbol("fact".to_string());
+        let fact_val = evaluate(heap, &fact, Location::unknown())
+            .ok()
+            .expect("Should be able to get `fact`.");
+        assert!(!heap.is_traced(&*fact_val));
+    }
+
+    #[test]
+    #[cfg(feature = "debugging")]
+    fn test_primitives_profile_report() {
+        let heap = &mut Heap::new();
+        heap.enable_profiling();
+        let result = evaluate_file(heap, "./tests/test_primitives_profile_report.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(24));
+
+        let report = heap.profile_report();
+        let fact = report.iter()
+            .find(|entry| entry.name == "fact")
+            .expect("Should have profiled `fact`.");
+        assert_eq!(fact.calls, 4);
+        let location = fact.location.clone().expect("`fact` has a location");
+        assert_eq!(location.line, 1);
+        assert!(report.iter().any(|entry| entry.name == "*" && entry.calls == 3));
+    }
+
+    #[test]
+    fn test_primitives_unavailable() {
+        let heap = &mut Heap::new();
+        let stub = Value::new_unavailable_primitive(&mut heap.primitive_table,
+                                                    "frobnicate",
+                                                    "frobnication");
+        let stub = Rooted::new(heap, stub);
+        let error = apply_invocation(heap, &stub, vec!())
+            .err()
+            .expect("Calling an unavailable primitive should be an error.");
+        assert_eq!(error.to_string(),
+                   "[E0207] Error: feature-not-available: `frobnicate` requires the \
+                    `frobnication` feature, which is not available");
+    }
+
+    #[test]
+    fn test_primitives_gc_stats() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_gc_stats.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(12));
+    }
+
+    #[test]
+    fn test_primitives_foreign_release() {
+        let heap = &mut Heap::new();
+        let handle = heap.new_foreign("counter", Box::new(0i64));
+
+        let call = |heap: &mut Heap, name: &str, arg: &RootedValue| {
+            let sym = heap.get_or_create_symbol(name.to_string());
+            let primitive = evaluate(heap, &sym, Location::unknown())
+                .ok()
+                .expect("Should be able to get the primitive.");
+            apply_invocation(heap, &primitive, vec!(arg.clone()))
+                .and_then(|trampoline| trampoline.run(heap))
+        };
+
+        let released = call(heap, "foreign-released?", &handle).ok().expect("ok");
+        assert_eq!(*released, Value::new_boolean(false));
+
+        assert!(call(heap, "foreign-release!", &handle).is_ok());
+
+        let released = call(heap, "foreign-released?", &handle).ok().expect("ok");
+        assert_eq!(*released, Value::new_boolean(true));
+
+        let error = call(heap, "foreign-release!", &handle).err().expect("err");
+        assert_eq!(error.to_string(), "[E0308] Error: foreign object #<foreign 0> (counter) \
+                                       was already released");
+    }
+
+    #[test]
+    fn test_primitives_register_finalizer() {
+        let heap = &mut Heap::new();
+        evaluate_file(heap, "./tests/test_primitives_register_finalizer.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        heap.collect_garbage();
+
+        let finalized = heap.get_or_create_symbol("finalized".to_string());
+        let result = evaluate(heap, &finalized, Location::unknown())
+            .ok()
+            .expect("Should be able to get `finalized`.");
+        assert_eq!(*result, Value::new_integer(1));
+    }
+
     #[test]
     fn test_primitives_error() {
         let heap = &mut Heap::new();
         let error = evaluate_file(heap, "./tests/test_primitives_error.scm")
             .err()
             .expect("Should get an error evaluating this file.");
-        assert_eq!(error, "./tests/test_primitives_error.scm:1:1:\n\
-                           ERROR!\n\
-                           \t\"got an error:\"\n\
-                           \t(1 2)");
+        assert_eq!(error, "./tests/test_primitives_error.scm:1:1:\n    \
+                           1 | (error \"got an error:\" '(1 2))\n    \
+                           \x20 | ^\n\
+                           [E0401] ERROR! got an error:\n\
+                           \t(1 2)\n\
+                           Backtrace:\n    \
+                           0: error at ./tests/test_primitives_error.scm:1:1");
+    }
+
+    #[test]
+    fn test_primitives_error_irritants() {
+        use eval::{evaluate_with_backtrace};
+        use read::{read_from_str};
+
+        let heap = &mut Heap::new();
+        let mut reader = read_from_str("(error \"bad thing:\" 42 'foo)", heap, "test");
+        let (location, form) = reader.next().expect("Should have a form.");
+        let form = form.ok().expect("Should be able to read the form.");
+        let error = evaluate_with_backtrace(heap, &form, location)
+            .err()
+            .expect("Should get an error evaluating `error`.");
+        assert_eq!(error.code.map(|entry| entry.code), Some("E0401"));
+        assert_eq!(error.message, "ERROR! bad thing:\n\t42\n\tfoo");
+        let irritants : Vec<String> = error.irritants.iter()
+            .map(|val| format!("{}", **val))
+            .collect();
+        assert_eq!(irritants, vec!("42".to_string(), "foo".to_string()));
+
+        // A message that looks like it has a code is still the message of an
+        // `error`, and nothing else.
+        let mut reader = read_from_str("(error \"[E0303] Error: divide by zero\")", heap, "test");
+        let (location, form) = reader.next().expect("Should have a form.");
+        let form = form.ok().expect("Should be able to read the form.");
+        let error = evaluate_with_backtrace(heap, &form, location)
+            .err()
+            .expect("Should get an error evaluating `error`.");
+        assert_eq!(error.code.map(|entry| entry.code), Some("E0401"));
+        assert_eq!(error.message, "ERROR! [E0303] Error: divide by zero");
+    }
+
+    #[test]
+    fn test_primitives_assert() {
+        let heap = &mut Heap::new();
+        let error = evaluate_file(heap, "./tests/test_primitives_assert.scm")
+            .err()
+            .expect("Should get an error evaluating this file.");
+        assert_eq!(error, "./tests/test_primitives_assert.scm:2:1:\n    \
+                           2 | (assert (= 1 2))\n    \
+                           \x20 | ^\n\
+                           [E0402] Error: assertion failed\n\
+                           Backtrace:\n    \
+                           0: assert at ./tests/test_primitives_assert.scm:2:1");
+    }
+
+    #[test]
+    fn test_primitives_sort() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_sort.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result),
+                   "((1 2 3 4 5) #((1 . a) (1 . c) (2 . b) (3 . d)))");
+    }
+
+    #[test]
+    fn test_primitives_vectors() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_vectors.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "(#t #f (1 (2 3) #(4)) ())");
+    }
+
+    #[test]
+    fn test_primitives_format() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_format.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "\"x = 42, name = bob, \"bob\" ~\n\"");
+
+        let result = heap.eval_str("(format #f \"~s ~a\" #\\a #\\a)")
+            .ok()
+            .expect("Should be able to format characters.");
+        assert_eq!(format!("{}", *result), "\"#\\a a\"");
+
+        let error = heap.eval_str("(format \"~d\" 'foo)")
+            .err()
+            .expect("Should get an error formatting a symbol with `~d`.");
+        assert!(error.contains("[E0312] Error: `format` expects an integer for `~d`, found foo"));
+    }
+
+    #[test]
+    fn test_primitives_random() {
+        let run = || {
+            let heap = &mut Heap::new();
+            let result = evaluate_file(heap, "./tests/test_primitives_random.scm")
+                .ok()
+                .expect("Should be able to eval a file.");
+            format!("{}", *result)
+        };
+
+        // Pseudo-randomized sources give the same numbers every time.
+        let first = run();
+        assert_eq!(first, run());
+        assert!(first.starts_with("(#t "));
+
+        let heap = &mut Heap::new();
+        heap.set_random_seed(7);
+        let a = heap.eval_str("(random-integer 1000000)").ok().expect("random");
+        heap.set_random_seed(7);
+        let b = heap.eval_str("(random-integer 1000000)").ok().expect("random");
+        assert_eq!(*a, *b);
+    }
+
+    #[test]
+    fn test_primitives_clock() {
+        let heap = &mut Heap::new();
+        heap.stub_clock(Some(100));
+        let result = heap.eval_str("(list (current-second) (current-jiffy) (jiffies-per-second))")
+            .ok()
+            .expect("Should be able to read the clock.");
+        assert_eq!(format!("{}", *result), "(100 100000000 1000000)");
+
+        let result = heap.eval_str("(time (+ 1 2))")
+            .ok()
+            .expect("Should be able to time an expression.");
+        assert_eq!(*result, Value::new_integer(3));
+    }
+
+    #[test]
+    fn test_primitives_exit() {
+        use heap::{ExitRequest};
+
+        let heap = &mut Heap::new();
+        let error = heap.eval_str("(define (f) (exit 3)) (+ 1 (f))")
+            .err()
+            .expect("Should get an error exiting.");
+        assert!(error.contains("[E0313] Exit: the program exited with status 3"));
+        assert_eq!(heap.take_exit_request(), Some(ExitRequest { status: 3, emergency: false }));
+        assert_eq!(heap.take_exit_request(), None);
+
+        assert!(heap.eval_str("(emergency-exit #f)").is_err());
+        assert_eq!(heap.take_exit_request(), Some(ExitRequest { status: 1, emergency: true }));
+
+        // `exit` calls the `after` thunks of the `dynamic-wind` extents it
+        // leaves, youngest first, but `emergency-exit` does not.
+        heap.eval_str("(define log '())                                        \
+                       (define (note x) (lambda () (set! log (cons x log))))")
+            .ok()
+            .expect("Should be able to define note.");
+        assert!(heap.eval_str("(dynamic-wind (note 'before)                      \
+                                             (lambda ()                           \
+                                               (dynamic-wind (note 'inner)        \
+                                                             (lambda () (exit))   \
+                                                             (note 'inner-after)))\
+                                             (note 'after))").is_err());
+        assert_eq!(heap.take_exit_request(), Some(ExitRequest { status: 0, emergency: false }));
+        let log = heap.eval_str("log").ok().expect("Should be able to get the log.");
+        assert_eq!(format!("{}", *log), "(after inner-after inner before)");
+
+        assert!(heap.eval_str("(set! log '()) \
+                               (dynamic-wind (note 'before) emergency-exit (note 'after))")
+                    .is_err());
+        let log = heap.eval_str("log").ok().expect("Should be able to get the log.");
+        assert_eq!(format!("{}", *log), "(before)");
+        heap.take_exit_request();
+
+        // The heap is still usable after exiting.
+        let result = heap.eval_str("(get-environment-variable \"OXISCHEME_SURELY_UNSET\")")
+            .ok()
+            .expect("Should be able to get an environment variable.");
+        assert_eq!(*result, Value::new_boolean(false));
+    }
+
+    #[test]
+    fn test_primitives_run_process() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_run_process.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "(\"a line\" (hello \"world\") #t 0)");
+
+        let result = heap.eval_str("(process-wait (run-process \"false\"))")
+            .ok()
+            .expect("Should be able to run `false`.");
+        assert_eq!(*result, Value::new_integer(1));
+
+        // The port owns the pipe, so it can only be taken once.
+        assert!(heap.eval_str("((lambda (p) (process-input p) (process-input p)) \
+                                (run-process \"true\"))").is_err());
+        assert!(heap.eval_str("(read-line (process-input (run-process \"true\")))").is_err());
+    }
+
+    #[test]
+    fn test_primitives_tcp() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_tcp.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "(\"ping\" (+ 1 2) \"ping pong\" #t)");
+    }
+
+    #[test]
+    fn test_primitives_continuation_marks() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_continuation_marks.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "(((2 1) outer none) ())");
+
+        // Marks are removed when their body fails, too.
+        assert!(heap.eval_str("(with-continuation-mark 'k 1 (car '()))").is_err());
+        assert_eq!(heap.continuation_marks().len(), 0);
+    }
+
+    #[test]
+    fn test_primitives_dynamic_wind() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_dynamic_wind.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result),
+                   "(value (before inner-before during inner-after after))");
+
+        // `after` is called when the body fails, too.
+        assert!(heap.eval_str("(set! log '()) \
+                               (dynamic-wind (note 'before) (lambda () (car '())) (note 'after))")
+                    .is_err());
+        let log = heap.eval_str("log").ok().expect("Should be able to get the log.");
+        assert_eq!(format!("{}", *log), "(after before)");
+    }
+
+    #[test]
+    fn test_primitives_environments() {
+        use vm::{ExecutionMode};
+
+        for &(mode, flat_closures) in [(ExecutionMode::Interpreter, false),
+                                       (ExecutionMode::Interpreter, true),
+                                       (ExecutionMode::Bytecode, false)].iter() {
+            let heap = &mut Heap::new();
+            heap.set_execution_mode(mode);
+            heap.set_flat_closures(flat_closures);
+            let result = evaluate_file(heap, "./tests/test_primitives_environments.scm")
+                .ok()
+                .expect("Should be able to eval a file.");
+            assert_eq!(format!("{}", *result), "(6 12 5 11 1 3 #t #f #t (count . 6))");
+
+            let error = heap.eval_str("(environment-ref env 'nope)").err()
+                .expect("Should fail.");
+            assert!(error.contains("Reference to variable that hasn't been defined: nope"));
+            let error = heap.eval_str("(the-environment 1)").err().expect("Should fail.");
+            assert!(error.contains("Static error: improperly formed the-environment"));
+        }
+    }
+
+    #[test]
+    fn test_primitives_generator() {
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_generator.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "((0 1 2) (3 4) a b done done)");
+
+        let error = heap.eval_str("(define saved #f) \
+                                   (define g (make-generator (lambda (yield) \
+                                     (set! saved yield) (yield 1)))) \
+                                   (generator-next g) \
+                                   (saved 2)")
+            .err()
+            .expect("Should get an error yielding outside of a generator.");
+        assert!(error.contains("Error: `yield` called outside of a generator"));
+
+        let error = heap.eval_str("(define g (make-generator (lambda (yield) \
+                                     (yield (generator-next g))))) \
+                                   (generator-next g)")
+            .err()
+            .expect("Should get an error asking a running generator for a value.");
+        assert!(error.contains("Error: generator is already running"));
+    }
+
+    #[test]
+    fn test_primitives_command_line() {
+        let heap = &mut Heap::new();
+        heap.set_command_line(vec!("script.scm".to_string(),
+                                   "arg1".to_string()));
+        let result = evaluate_file(heap, "./tests/test_primitives_command_line.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(*result, Value::new_integer(2));
+    }
+
+    #[test]
+    fn test_primitives_set_repl_prompt() {
+        use heap::{ReplPrompt};
+
+        let heap = &mut Heap::new();
+        evaluate_file(heap, "./tests/test_primitives_set_repl_prompt.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        match *heap.repl_prompt() {
+            ReplPrompt::Format(ref format) => assert_eq!(*format, "[{count}] {module}> "),
+            _                              => panic!("Should have a format prompt"),
+        }
+        assert_eq!(heap.repl_banner(), "Hello!");
     }
 
     #[test]
@@ -508,6 +3261,142 @@ mod tests {
         assert_eq!(*result, Value::new_integer(42));
     }
 
+    #[test]
+    fn test_primitives_arithmetic_mode() {
+        use value::{MAX_FIXNUM, MIN_FIXNUM};
+
+        let heap = &mut Heap::new();
+        let result = evaluate_file(heap, "./tests/test_primitives_arithmetic_mode.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        let pair = result.to_pair(heap)
+            .expect("Result should be a pair");
+        let saturate = heap.get_or_create_symbol("saturate".to_string());
+        assert_eq!(pair.car(heap), saturate);
+        assert_eq!(*pair.cadr(heap).ok().expect("pair.cadr"),
+                   Value::new_integer(MIN_FIXNUM));
+        assert_eq!(*pair.caddr(heap).ok().expect("pair.caddr"),
+                   Value::new_integer(MAX_FIXNUM));
+    }
+
+    #[test]
+    fn test_primitives_integer_overflow() {
+        use vm::{ExecutionMode};
+
+        // Overflow is checked on the fast paths used at call sites, as well as
+        // when the primitives are applied.
+        for &mode in [ExecutionMode::Interpreter, ExecutionMode::Bytecode].iter() {
+            let heap = &mut Heap::new();
+            heap.set_execution_mode(mode);
+            for &(source, operator) in [("(+ 1152921504606846975 1)", "+"),
+                                        ("(- -1152921504606846976 1)", "-"),
+                                        ("(* 1152921504606846975 2)", "*"),
+                                        ("(/ -1152921504606846976 -1)", "/"),
+                                        ("(apply + '(1152921504606846975 1))", "+")].iter() {
+                let error = heap.eval_str(source).err().expect("Should overflow.");
+                assert!(error.contains(format!("[E0302] Error: integer overflow in `{}`",
+                                               operator).as_slice()),
+                        "{}: {}", source, error);
+            }
+
+            heap.eval_str("(arithmetic-mode 'promote)").ok().expect("Should set the mode.");
+            let error = heap.eval_str("(* 1152921504606846975 2)").err()
+                .expect("Should overflow until bignums are supported.");
+            assert!(error.contains("bignums are not supported yet"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_primitives_fixnum_operators() {
+        use vm::{ExecutionMode};
+
+        for &mode in [ExecutionMode::Interpreter, ExecutionMode::Bytecode].iter() {
+            let heap = &mut Heap::new();
+            heap.set_execution_mode(mode);
+            let result = evaluate_file(heap, "./tests/test_primitives_fixnum_operators.scm")
+                .ok()
+                .expect("Should be able to eval a file.");
+            assert_eq!(format!("{}", *result), "(7 -3 42 #t #f #t #t #f 55)");
+
+            let error = heap.eval_str("(fx+ 1 \"2\")").err().expect("Should reject strings.");
+            assert!(error.contains("Error: `fx+` expects a fixnum as argument 2"), "{}", error);
+
+            // Fixnum operators never promote, whatever the arithmetic mode.
+            heap.eval_str("(arithmetic-mode 'wrap)").ok().expect("Should set the mode.");
+            let error = heap.eval_str("(fx* 1152921504606846975 2)").err()
+                .expect("Should overflow.");
+            assert!(error.contains("[E0302] Error: integer overflow in `fx*`"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_primitives_flonums() {
+        use vm::{ExecutionMode};
+
+        for &mode in [ExecutionMode::Interpreter, ExecutionMode::Bytecode].iter() {
+            let heap = &mut Heap::new();
+            heap.set_execution_mode(mode);
+            let result = evaluate_file(heap, "./tests/test_primitives_flonums.scm")
+                .ok()
+                .expect("Should be able to eval a file.");
+            assert_eq!(format!("{}", *result),
+                       "(3.5 0.5 0.5 #t #t 0.75 3.0 #t 4.0 -2.0 1.0 0.0 0.0 3 1.5 3 3.0 #t #f \
+                        +inf.0)");
+
+            let error = heap.eval_str("(fl+ 1 2.0)").err().expect("Should reject fixnums.");
+            assert!(error.contains("Error: `fl+` expects a flonum as argument 1, found 1"),
+                    "{}", error);
+            let error = heap.eval_str("(exact 1.5)").err().expect("Should not be exact.");
+            assert!(error.contains("cannot convert 1.5 to an exact integer"), "{}", error);
+            let error = heap.eval_str("(exact 1e19)").err().expect("Should not be a fixnum.");
+            assert!(error.contains("[E0314] Error: "), "{}", error);
+            assert!(error.contains("does not fit in a fixnum"), "{}", error);
+
+            heap.eval_str("(define x (fl* 0.5 3.0))").ok().expect("Should define x.");
+            heap.collect_garbage();
+            let x = heap.eval_str("x").ok().expect("Should get x.");
+            assert_eq!(x.to_flonum(), Some(1.5));
+        }
+    }
+
+    #[test]
+    fn test_primitives_signatures() {
+        use vm::{ExecutionMode};
+
+        for &mode in [ExecutionMode::Interpreter, ExecutionMode::Bytecode].iter() {
+            let heap = &mut Heap::new();
+            heap.set_execution_mode(mode);
+
+            let error = heap.eval_str("(car 5)").err().expect("Should reject 5.");
+            assert!(error.contains("[E0305] Error: `car` expects a pair as argument 1, found 5"),
+                    "{}", error);
+            let error = heap.eval_str("(apply car '(1 2))").err().expect("Should reject 2 args.");
+            assert!(error.contains("[E0301] Error: `car` expects 1 argument, found 2"),
+                    "{}", error);
+            let error = heap.eval_str("(apply + '(1 a))").err().expect("Should reject a.");
+            assert!(error.contains("[E0304] Error: `+` expects a number as argument 2, found a"),
+                    "{}", error);
+            let error = heap.eval_str("(list-sort 1 '())").err().expect("Should reject 1.");
+            assert!(error.contains("[E0312] Error: `list-sort` expects a procedure as \
+                                    argument 1, found 1"),
+                    "{}", error);
+            let error = heap.eval_str("(iota)").err().expect("Should reject no arguments.");
+            assert!(error.contains("[E0301] Error: `iota` expects 1 to 3 arguments, found 0"),
+                    "{}", error);
+            let error = heap.eval_str("(get-environment-variable 1)").err()
+                .expect("Should reject 1.");
+            assert!(error.contains("[E0312] Error: `get-environment-variable` expects a string \
+                                    as argument 1, found 1"),
+                    "{}", error);
+
+            let result = heap.eval_str("(list (procedure-arity fx+) (procedure-arity iota) \
+                                              (procedure-arity list))")
+                .ok()
+                .expect("Should get the arities.");
+            assert_eq!(format!("{}", *result), "(2 (1 . 3) (0 . #f))");
+        }
+    }
+
     #[test]
     fn test_primitives_pair() {
         let heap = &mut Heap::new();
@@ -615,4 +3504,76 @@ mod tests {
         assert_eq!(*pair.car(heap), Value::new_boolean(true));
         assert_eq!(*pair.cdr(heap), Value::new_boolean(false));
     }
+
+    #[test]
+    fn test_primitives_sandboxed() {
+        use eval::{Trampoline, TrampolineResult};
+        use heap::{HeapConfig};
+        use super::{Capability, PrimitiveSet};
+        use value::{RootedValue};
+
+        fn first(heap: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+            Ok(Trampoline::Value(Rooted::new(heap, Value::new_integer(1))))
+        }
+
+        let heap = &mut Heap::with_config(HeapConfig {
+            primitives: PrimitiveSet::pure()
+                .grant(Capability::Io)
+                .remove("read")
+                .define("car", first),
+            .. Default::default()
+        });
+
+        let result = heap.eval_str("(+ (car '(5 6)) 2)")
+            .ok()
+            .expect("Should be able to use pure primitives.");
+        assert_eq!(*result, Value::new_integer(3));
+
+        assert!(heap.eval_str("print").is_ok());
+        assert!(heap.eval_str("read").is_err());
+        assert!(heap.eval_str("gc-stats").is_err());
+        assert!(heap.eval_str("command-line").is_err());
+        assert!(heap.eval_str("foreign-release!").is_err());
+
+        assert_eq!(heap.primitive_info("car").map(|info| info.module), Some("embedder"));
+        assert_eq!(heap.primitive_info("print").map(|info| info.module), Some("io"));
+        assert!(heap.primitive_info("read").is_none());
+        assert!(heap.primitive_info("gc-stats").is_none());
+    }
+
+    #[test]
+    fn test_primitives_sandboxed_prelude() {
+        use heap::{HeapConfig};
+        use super::{PrimitiveSet};
+
+        // The prelude still loads without the primitives it calls, and only the
+        // procedures that call them fail.
+        let heap = &mut Heap::with_config(HeapConfig {
+            primitives: PrimitiveSet::pure().remove("null?"),
+            .. Default::default()
+        });
+
+        let result = heap.eval_str("(stream-pair? stream-null)")
+            .ok()
+            .expect("Should be able to load the prelude without `null?`.");
+        assert_eq!(*result, Value::new_boolean(false));
+        assert!(heap.eval_str("(stream-null? stream-null)").is_err());
+    }
+
+    #[test]
+    fn test_primitives_primitive_procedures() {
+        let heap = &mut Heap::new();
+        let result = heap.eval_str("(list (car (primitive-procedures)) \
+                                          (procedure-documentation car))")
+            .ok()
+            .expect("Should list the primitives.");
+        assert_eq!(format!("{}", *result),
+                   "((cons . pure) \"Return the car of the given pair.\")");
+        assert_eq!(heap.primitive_info("print").map(|info| info.module), Some("io"));
+
+        let count = heap.eval_str("(length (primitive-procedures))")
+            .ok()
+            .expect("Should count the primitives.");
+        assert_eq!(*count, Value::new_integer(heap.primitive_registry().len() as i64));
+    }
 }
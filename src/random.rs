@@ -0,0 +1,112 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pseudo-random number generation, in the style of SRFI-27.
+//!
+//! Every heap has a default random source, seeded from `Heap::random_seed`, so
+//! that replaying a transcript replays `random-integer` too. Scheme code can
+//! also make random sources of its own, which are native objects:
+//!
+//!     (define source (make-random-source))
+//!     (random-source-pseudo-randomize! source 4 2)
+//!     (random-integer 6 source)
+//!
+//! Every new random source starts in the same state, so a simulation that
+//! makes its own source gets the same numbers on every run until it is
+//! randomized. There are no real numbers yet, so there is no `random-real`.
+
+use std::num::wrapping::{WrappingOps};
+use std::u64;
+
+/// The tag of the native objects wrapping random sources.
+pub static RANDOM_SOURCE_TAG : &'static str = "random-source";
+
+/// Mixed into every seed, so that small seeds such as 0 and 1 do not start the
+/// generator in a nearly all zero state.
+static SEED_MIX : u64 = 0x9e3779b97f4a7c15;
+
+/// A xorshift64* pseudo-random number generator. It is fast and has a period of
+/// 2^64 - 1, but it is not suitable for cryptography.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RandomSource {
+    state: u64,
+}
+
+impl RandomSource {
+    /// Create a new random source from the given seed. Sources with the same
+    /// seed produce the same numbers.
+    pub fn new(seed: u64) -> RandomSource {
+        // Xorshift never leaves the all zero state.
+        let state = seed ^ SEED_MIX;
+        RandomSource {
+            state: if state == 0 { SEED_MIX } else { state },
+        }
+    }
+
+    /// Get the next pseudo-random 64 bit number.
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// Get a pseudo-random number in `[0, n)`. Numbers past the largest
+    /// multiple of `n` are drawn again, so that small results are not more
+    /// likely than large ones.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `n` is zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        assert!(n > 0, "Cannot get a random number below zero");
+        let limit = u64::MAX - u64::MAX % n;
+        loop {
+            let x = self.next_u64();
+            if x < limit {
+                return x % n;
+            }
+        }
+    }
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_same_seed_same_numbers() {
+        let mut a = RandomSource::new(42);
+        let mut b = RandomSource::new(42);
+        for _ in range(0, 100) {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+    }
+
+    #[test]
+    fn test_random_below() {
+        let mut source = RandomSource::new(0);
+        let mut seen = [false; 6];
+        for _ in range(0, 1000) {
+            let n = source.below(6);
+            assert!(n < 6);
+            seen[n as usize] = true;
+        }
+        assert!(seen.iter().all(|s| *s), "Every number below 6 should come up.");
+    }
+}
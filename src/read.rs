@@ -15,12 +15,17 @@
 //! Parsing values.
 
 use std::cell::{RefCell};
+use std::collections::{HashMap, HashSet};
+use std::f64;
 use std::fmt;
 use std::iter::{Peekable};
+use std::num::{Int};
 use std::old_io::{BufferedReader, File, IoError, IoErrorKind, IoResult, MemReader};
 
+use errors::{ErrorCode, E0001, E0002, E0003, E0004, E0005, E0006, E0007, E0008, E0009, E0010,
+             E0011, E0012, E0013, E0014, SchemeError};
 use heap::{Heap, Rooted};
-use value::{list, RootedValue, SchemeResult, Value};
+use value::{self, RootedValue, SchemeResult, Value};
 
 /// `CharReader` reads characters one at a time from the given input `Reader`.
 struct CharReader<R> {
@@ -75,8 +80,66 @@ fn is_eof_or_delimiter(oc: &Option<char>) -> bool {
     }
 }
 
+/// Replace every reference to the given datum label placeholder within the
+/// given datum with the datum itself, creating cycles.
+fn replace_placeholder(placeholder: &RootedValue, datum: &RootedValue) {
+    let mut seen = HashSet::new();
+    let mut stack = vec!(**datum);
+
+    while let Some(val) = stack.pop() {
+        if !seen.insert(val) {
+            continue;
+        }
+
+        match val {
+            Value::Pair(mut cons) => {
+                if cons.unrooted_car() == **placeholder {
+                    cons.set_car(datum);
+                } else {
+                    stack.push(cons.unrooted_car());
+                }
+
+                if cons.unrooted_cdr() == **placeholder {
+                    cons.set_cdr(datum);
+                } else {
+                    stack.push(cons.unrooted_cdr());
+                }
+            },
+            Value::Vector(mut vector) => {
+                for i in range(0, vector.len()) {
+                    match vector.unrooted_get(i) {
+                        Some(element) if element == **placeholder => {
+                            vector.set(i, datum).unwrap();
+                        },
+                        Some(element) => stack.push(element),
+                        None          => {},
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Return true if the character may follow a '#' to begin a radix or
+/// exactness prefix of a number, false otherwise.
+fn is_number_prefix(c: &char) -> bool {
+    match *c {
+        'x' | 'X' | 'b' | 'B' | 'o' | 'O' | 'd' | 'D' | 'e' | 'E' | 'i' | 'I' => true,
+        _                                                                     => false,
+    }
+}
+
 fn is_symbol_initial(c: &char) -> bool {
     c.is_alphabetic() || is_symbol_special_initial(c) || is_symbol_peculiar(c)
+        || is_symbol_unicode(c)
+}
+
+/// Return true if the character is any other non-ASCII character that may be
+/// part of an identifier, such as a mathematical symbol or an emoji, false
+/// otherwise. Numeric characters may only be subsequent.
+fn is_symbol_unicode(c: &char) -> bool {
+    (*c as u32) >= 0x80 && !c.is_whitespace() && !c.is_control() && !c.is_numeric()
 }
 
 fn is_symbol_peculiar(c: &char) -> bool {
@@ -90,7 +153,47 @@ fn is_symbol_special_initial(c: &char) -> bool {
 }
 
 fn is_symbol_subsequent(c: &char) -> bool {
-    is_symbol_initial(c) || c.is_digit(10) || *c == '.' || *c == '+' || *c == '-'
+    is_symbol_initial(c) || c.is_numeric() || *c == '.' || *c == '+' || *c == '-' || *c == '@'
+}
+
+/// Get the flonum spelled by the given identifier, if it is one of the
+/// infinities or NaN, e.g. `+inf.0`.
+fn special_flonum(name: &str) -> Option<f64> {
+    match name {
+        "+inf.0"            => Some(f64::INFINITY),
+        "-inf.0"            => Some(f64::NEG_INFINITY),
+        "+nan.0" | "-nan.0" => Some(f64::NAN),
+        _                   => None,
+    }
+}
+
+/// Return true if the given symbol name can be written as a bare identifier,
+/// false if it must be written between pipes, e.g. `|hello world|`.
+pub fn is_bare_identifier(name: &str) -> bool {
+    // Keywords are read back in by `Read::read_keyword`.
+    if name.starts_with("#:") {
+        return name.len() > 2 && name[2..].chars().all(|c| is_symbol_subsequent(&c));
+    }
+    if special_flonum(name).is_some() {
+        return false;
+    }
+
+    let mut chars = name.chars();
+    let first_ok = match chars.next() {
+        None    => false,
+        // A leading '.' must be followed by something, and a leading '+', '-',
+        // or '.' must not be followed by a digit (or by '.' and a digit after a
+        // sign), or the name would read back in as the dot of a pair or as a
+        // number, e.g. `+5`, `-.5`, or `.5`.
+        Some(c) if c == '.' || c == '+' || c == '-' => {
+            let rest = &name[1..];
+            let rest = if c != '.' && rest.starts_with(".") { &rest[1..] } else { rest };
+            let is_number = rest.chars().next().map_or(false, |c| c.is_digit(10));
+            (c != '.' || name.len() > 1) && !is_number
+        },
+        Some(c) => is_symbol_initial(&c),
+    };
+    first_ok && chars.all(|c| is_symbol_subsequent(&c))
 }
 
 /// A source location.
@@ -122,6 +225,17 @@ impl Location {
         loc.column = 0;
         loc
     }
+
+    /// Move this location past the given character.
+    fn advance(&mut self, c: char) {
+        match c {
+            '\n' => {
+                self.line += 1;
+                self.column = 1;
+            },
+            _    => self.column += 1,
+        }
+    }
 }
 
 impl fmt::Display for Location {
@@ -145,10 +259,25 @@ pub type SchemeResultAndLocation = (Location, SchemeResult);
 /// `Read` iteratively parses values from the input `Reader`.
 pub struct Read<R: Reader> {
     chars: RefCell<Peekable<CharReader<R>>>,
+    /// A character taken from `chars` in order to peek at the one after it,
+    /// which is the next character to be read.
+    pushed_back: Option<char>,
     current_location: Location,
     result: Result<(), String>,
     heap_ptr: *mut Heap,
-    had_error: bool
+    had_error: bool,
+    /// The datums of the datum labels (`#n=`) defined so far in the current
+    /// outermost datum. While a labeled datum is still being read, its label
+    /// maps to a placeholder that is patched once the datum is complete.
+    labels: HashMap<u64, RootedValue>,
+    /// Whether symbols are folded to lower case, set by the `#!fold-case` and
+    /// `#!no-fold-case` directives.
+    fold_case: bool,
+    /// The number of characters taken from the input so far.
+    consumed: usize,
+    /// Whether the failure being reported, if any, is only because the input
+    /// ended in the middle of a datum. See `IncrementalRead`.
+    incomplete: bool,
 }
 
 impl<'a, R: Reader> Read<R> {
@@ -156,10 +285,15 @@ impl<'a, R: Reader> Read<R> {
     pub fn new(reader: R, heap: *mut Heap, file_name: String) -> Read<R> {
         Read {
             chars: RefCell::new(CharReader::new(reader).peekable()),
+            pushed_back: None,
             current_location: Location::new(file_name),
             result: Ok(()),
             heap_ptr: heap,
             had_error: false,
+            labels: HashMap::new(),
+            fold_case: false,
+            consumed: 0,
+            incomplete: false,
         }
     }
 
@@ -173,6 +307,22 @@ impl<'a, R: Reader> Read<R> {
 
     /// Peek at the next character in our input stream.
     fn peek_char(&self) -> Option<char> {
+        if self.pushed_back.is_some() {
+            return self.pushed_back;
+        }
+
+        match self.chars.borrow_mut().peek() {
+            None    => None,
+            Some(c) => Some(*c)
+        }
+    }
+
+    /// Peek at the character after the next one in our input stream.
+    fn peek_second_char(&mut self) -> Option<char> {
+        if self.pushed_back.is_none() {
+            self.pushed_back = self.chars.borrow_mut().next();
+        }
+
         match self.chars.borrow_mut().peek() {
             None    => None,
             Some(c) => Some(*c)
@@ -181,16 +331,14 @@ impl<'a, R: Reader> Read<R> {
 
     /// Take the next character from the input stream.
     fn next_char(&mut self) -> Option<char> {
-        let opt_c = self.chars.borrow_mut().next();
+        let opt_c = match self.pushed_back.take() {
+            None => self.chars.borrow_mut().next(),
+            c    => c,
+        };
 
-        if let Some(ref c) = opt_c.as_ref() {
-            match **c {
-                '\n' => {
-                    self.current_location.line += 1;
-                    self.current_location.column = 1;
-                },
-                _ => self.current_location.column += 1,
-            };
+        if let Some(c) = opt_c {
+            self.current_location.advance(c);
+            self.consumed += 1;
         }
 
         opt_c
@@ -208,19 +356,99 @@ impl<'a, R: Reader> Read<R> {
         }
     }
 
-    /// Trim initial whitespace and skip comments.
-    fn trim(&mut self) {
+    /// Skip a block comment, e.g. `#| ... |#`. Block comments nest. If this
+    /// ever returns `Some`, then it will always be `Some((Location, Err))`.
+    fn skip_block_comment(&mut self) -> Option<SchemeResultAndLocation> {
+        self.next_char();
+        self.next_char();
+
+        let mut depth = 1;
+        while depth > 0 {
+            match [self.next_char(), self.peek_char()] {
+                [None, _]                => return self.unterminated_block_comment(),
+                [Some('|'), Some('#')]   => {
+                    self.next_char();
+                    depth -= 1;
+                },
+                [Some('#'), Some('|')]   => {
+                    self.next_char();
+                    depth += 1;
+                },
+                _                        => { },
+            }
+        }
+
+        None
+    }
+
+    /// Skip a datum comment, e.g. `#;(ignored datum)`. If this ever returns
+    /// `Some`, then it will always be `Some((Location, Err))`.
+    fn skip_datum_comment(&mut self) -> Option<SchemeResultAndLocation> {
+        self.next_char();
+        self.next_char();
+
+        match self.read_datum() {
+            Some((_, Ok(_))) => None,
+            None             => self.unexpected_eof(),
+            err              => err,
+        }
+    }
+
+    /// Read a directive, e.g. `#!fold-case`, or a shebang line, which are
+    /// skipped like comments. If this ever returns `Some`, then it will always
+    /// be `Some((Location, Err))`.
+    fn read_directive(&mut self) -> Option<SchemeResultAndLocation> {
+        let at_start = self.consumed == 0;
+        self.next_char();
+        self.next_char();
+
+        // A `#!/usr/bin/env oxischeme` line at the very start of the input
+        // makes a script directly executable.
+        if at_start && (self.peek_char() == Some('/') || self.peek_char() == Some(' ')) {
+            self.skip_line();
+            return None;
+        }
+
+        let mut name = String::new();
         loop {
-            let skip_line = match self.peek_char() {
-                Some(c) if c.is_whitespace() => false,
-                Some(c) if is_comment(&c)    => true,
-                _                            => return,
-            };
+            match self.peek_char() {
+                Some(c) if is_symbol_subsequent(&c) => {
+                    self.next_char();
+                    name.push(c);
+                },
+                _                                   => break,
+            }
+        }
 
-            if skip_line {
-                self.skip_line();
-            } else {
-                self.next_char();
+        match name.as_slice() {
+            "fold-case"    => self.fold_case = true,
+            "no-fold-case" => self.fold_case = false,
+            _              => return self.unknown_directive(name),
+        }
+        None
+    }
+
+    /// Trim initial whitespace and skip comments. If this ever returns `Some`,
+    /// then it will always be `Some((Location, Err))`.
+    fn trim(&mut self) -> Option<SchemeResultAndLocation> {
+        loop {
+            match self.peek_char() {
+                Some(c) if c.is_whitespace() => {
+                    self.next_char();
+                },
+                Some(c) if is_comment(&c)    => self.skip_line(),
+                Some('#')                    => {
+                    let skipped = match self.peek_second_char() {
+                        Some('|') => self.skip_block_comment(),
+                        Some(';') => self.skip_datum_comment(),
+                        Some('!') => self.read_directive(),
+                        _         => return None,
+                    };
+                    if skipped.is_some() {
+                        return skipped;
+                    }
+                },
+                _                            => return None,
             }
         }
     }
@@ -231,16 +459,27 @@ impl<'a, R: Reader> Read<R> {
         &self.result
     }
 
-    /// Report a failure reading values.
-    fn report_failure(&mut self, msg: String) -> Option<SchemeResultAndLocation> {
+    /// Report a failure reading values, of the given kind.
+    fn report_failure(&mut self,
+                      code: &'static ErrorCode,
+                      msg: String) -> Option<SchemeResultAndLocation> {
         self.had_error = true;
         Some((self.current_location.clone(),
-             Err(format!("{}: {}", self.current_location, msg))))
+             Err(code.error(format!("{}: {}", self.current_location, msg)))))
+    }
+
+    /// Report a failure caused by the input ending in the middle of a datum,
+    /// which, unlike other failures, more input could fix.
+    fn report_incomplete(&mut self,
+                         code: &'static ErrorCode,
+                         msg: String) -> Option<SchemeResultAndLocation> {
+        self.incomplete = true;
+        self.report_failure(code, msg)
     }
 
     /// Report an unexpected character.
     fn unexpected_character(&mut self, c: &char) -> Option<SchemeResultAndLocation> {
-        self.report_failure(format!("Unexpected character: {}", c))
+        self.report_failure(&E0001, format!("Unexpected character: {}", c))
     }
 
     /// Expect that the next character is `c` and report a failure if it is
@@ -249,10 +488,10 @@ impl<'a, R: Reader> Read<R> {
     fn expect_character(&mut self, c: char) -> Option<SchemeResultAndLocation> {
         match self.next_char() {
             None => {
-                self.report_failure(format!("Expected '{}', but found EOF.", c))
+                self.report_incomplete(&E0002, format!("Expected '{}', but found EOF.", c))
             },
             Some(d) if d != c => {
-                self.report_failure(format!("Expected '{}', found: '{}'", c, d))
+                self.report_failure(&E0002, format!("Expected '{}', found: '{}'", c, d))
             },
             _ => None
         }
@@ -260,17 +499,68 @@ impl<'a, R: Reader> Read<R> {
 
     /// Report an unexpected EOF.
     fn unexpected_eof(&mut self) -> Option<SchemeResultAndLocation> {
-        self.report_failure("Unexpected EOF".to_string())
+        self.report_incomplete(&E0003, "Unexpected EOF".to_string())
     }
 
     /// Report a bad character literal, e.g. `#\bad`.
     fn bad_character_literal(&mut self) -> Option<SchemeResultAndLocation> {
-        self.report_failure("Bad character value".to_string())
+        self.report_failure(&E0004, "Bad character value".to_string())
+    }
+
+    /// Report an integer literal that does not fit in a fixnum.
+    fn integer_out_of_range(&mut self) -> Option<SchemeResultAndLocation> {
+        self.report_failure(&E0006, "Integer literal out of range".to_string())
+    }
+
+    /// Report an exact numeric literal that is not an integer, e.g. `#e1.5`,
+    /// which we have no representation for.
+    fn inexact_exact_number(&mut self) -> Option<SchemeResultAndLocation> {
+        self.report_failure(&E0008, "Exact number literal is not an integer".to_string())
+    }
+
+    /// Report a malformed decimal literal, e.g. `1.2.3`.
+    fn bad_decimal_literal(&mut self) -> Option<SchemeResultAndLocation> {
+        self.report_failure(&E0008, "Bad decimal literal".to_string())
+    }
+
+    /// Report a bytevector element that is not a byte.
+    fn bad_byte(&mut self, element: &RootedValue) -> Option<SchemeResultAndLocation> {
+        self.report_failure(&E0009,
+                            format!("Bytevector elements must be integers from 0 to 255, \
+                                     found: {}",
+                                    **element))
+    }
+
+    /// Report a reference to a datum label that has not been defined.
+    fn undefined_datum_label(&mut self,
+                             label: u64,
+                             why: &str) -> Option<SchemeResultAndLocation> {
+        self.report_failure(&E0010, format!("Undefined datum label: #{}# {}", label, why))
+    }
+
+    /// Report a datum label that is defined more than once.
+    fn duplicate_datum_label(&mut self, label: u64) -> Option<SchemeResultAndLocation> {
+        self.report_failure(&E0011, format!("Duplicate datum label: #{}=", label))
+    }
+
+    /// Report a `#!` directive that we don't know.
+    fn unknown_directive(&mut self, name: String) -> Option<SchemeResultAndLocation> {
+        self.report_failure(&E0012, format!("Unknown directive: #!{}", name))
+    }
+
+    /// Report a symbol between pipes that is never closed.
+    fn unterminated_symbol(&mut self) -> Option<SchemeResultAndLocation> {
+        self.report_incomplete(&E0013, "Unterminated |symbol|".to_string())
+    }
+
+    /// Report a block comment that is never closed.
+    fn unterminated_block_comment(&mut self) -> Option<SchemeResultAndLocation> {
+        self.report_incomplete(&E0007, "Unterminated block comment".to_string())
     }
 
     /// Report an unterminated string literal.
     fn unterminated_string(&mut self) -> Option<SchemeResultAndLocation> {
-        self.report_failure("Unterminated string literal".to_string())
+        self.report_incomplete(&E0005, "Unterminated string literal".to_string())
     }
 
     /// Register the given value as having originated form the given location,
@@ -350,15 +640,16 @@ impl<'a, R: Reader> Read<R> {
         }
     }
 
-    /// Given that we have already peeked a '#' character, read in either a
-    /// boolean or a character.
-    fn read_bool_or_char(&mut self,
-                         loc: Location) -> Option<SchemeResultAndLocation> {
+    /// Given that we have already peeked a '#' character, read in a boolean, a
+    /// character, a number with a radix or exactness prefix, a vector, a
+    /// keyword, or a bytevector.
+    fn read_hash_syntax(&mut self,
+                        loc: Location) -> Option<SchemeResultAndLocation> {
         if let Some(e) = self.expect_character('#') {
             return Some(e);
         }
 
-        // Deterimine if this is a boolean or a character.
+        // Deterimine what kind of value this is.
         match [self.next_char(), self.peek_char()] {
             [Some('t'), d] if is_eof_or_delimiter(&d)  => {
                 self.root(loc, Value::new_boolean(true))
@@ -369,6 +660,25 @@ impl<'a, R: Reader> Read<R> {
             [Some('\\'), _]                            => {
                 self.read_character(loc)
             },
+            [Some(c), _] if is_number_prefix(&c)       => {
+                self.read_prefixed_number(c, loc)
+            },
+            [Some(c), _] if c.is_digit(10)             => {
+                self.read_datum_label(c, loc)
+            },
+            [Some('('), _]                             => {
+                self.read_vector(loc)
+            },
+            [Some(':'), _]                             => {
+                self.read_keyword(loc)
+            },
+            [Some('u'), Some('8')]                     => {
+                self.next_char();
+                if let Some(e) = self.expect_character('(') {
+                    return Some(e);
+                }
+                self.read_bytevector(loc)
+            },
             [Some(c), _]                               => {
                 self.unexpected_character(&c)
             },
@@ -376,15 +686,117 @@ impl<'a, R: Reader> Read<R> {
         }
     }
 
-    /// Read an integer.
-    fn read_integer(&mut self,
-                    is_negative: bool,
-                    loc: Location) -> Option<SchemeResultAndLocation> {
+    /// Read a datum label definition, e.g. `#0=(a . #0#)`, or a reference to
+    /// one, e.g. `#0#`, given the first digit of the label.
+    fn read_datum_label(&mut self,
+                        first: char,
+                        loc: Location) -> Option<SchemeResultAndLocation> {
+        let mut label = first.to_digit(10).unwrap() as u64;
+        loop {
+            match self.next_char() {
+                Some(c) if c.is_digit(10) => {
+                    let d = c.to_digit(10).unwrap() as u64;
+                    label = match label.checked_mul(10).and_then(|n| n.checked_add(d)) {
+                        Some(n) => n,
+                        None    => return self.integer_out_of_range(),
+                    };
+                },
+                Some('=')                 => return self.read_labeled_datum(label, loc),
+                Some('#')                 => {
+                    let datum = self.labels.get(&label).map(|val| **val);
+                    return match datum {
+                        Some(val) => self.root(loc, val),
+                        None      => self.undefined_datum_label(label, "is not defined"),
+                    };
+                },
+                Some(c)                   => return self.unexpected_character(&c),
+                None                      => return self.unexpected_eof(),
+            }
+        }
+    }
+
+    /// Read the datum following a `#n=` datum label, and then patch any
+    /// references to the label within the datum.
+    fn read_labeled_datum(&mut self,
+                          label: u64,
+                          loc: Location) -> Option<SchemeResultAndLocation> {
+        if self.labels.contains_key(&label) {
+            return self.duplicate_datum_label(label);
+        }
+
+        // A fresh pair can't be `eq?` to anything in the datum but references
+        // to this label.
+        let empty = Rooted::new(self.heap(), Value::EmptyList);
+        let placeholder = Value::new_pair(self.heap(), &empty, &empty);
+        self.labels.insert(label, placeholder.clone());
+
+        let datum = match self.read_datum() {
+            Some((_, Ok(datum))) => datum,
+            None                 => return self.unexpected_eof(),
+            err                  => return err,
+        };
+        if *datum == *placeholder {
+            return self.undefined_datum_label(label, "refers to itself");
+        }
+
+        replace_placeholder(&placeholder, &datum);
+        self.labels.insert(label, datum.clone());
+        self.enlocate(loc, datum)
+    }
+
+    /// Read a number after its prefixes, given the character following the
+    /// first '#'. A number may have at most one radix prefix (`#x`, `#b`,
+    /// `#o`, `#d`) and at most one exactness prefix (`#e`, `#i`), in either
+    /// order, followed by an optional sign.
+    fn read_prefixed_number(&mut self,
+                            first: char,
+                            loc: Location) -> Option<SchemeResultAndLocation> {
+        let mut radix = None;
+        let mut is_exact = None;
+        let mut prefix = first;
+
+        loop {
+            match prefix {
+                'x' | 'X' if radix.is_none()    => radix = Some(16),
+                'b' | 'B' if radix.is_none()    => radix = Some(2),
+                'o' | 'O' if radix.is_none()    => radix = Some(8),
+                'd' | 'D' if radix.is_none()    => radix = Some(10),
+                'e' | 'E' if is_exact.is_none() => is_exact = Some(true),
+                'i' | 'I' if is_exact.is_none() => is_exact = Some(false),
+                c                               => return self.unexpected_character(&c),
+            }
+
+            if self.peek_char() != Some('#') {
+                break;
+            }
+            self.next_char();
+            prefix = match self.next_char() {
+                None    => return self.unexpected_eof(),
+                Some(c) => c,
+            };
+        }
+
+        let is_negative = match self.peek_char() {
+            Some('-') => { self.next_char(); true },
+            Some('+') => { self.next_char(); false },
+            _         => false,
+        };
+
+        self.read_number(is_negative, radix.unwrap_or(10), is_exact, loc)
+    }
+
+    /// Read a number in the given radix. Integers are exact and decimals, e.g.
+    /// `1.5` or `1e3`, are inexact, unless `is_exact` says otherwise.
+    fn read_number(&mut self,
+                   is_negative: bool,
+                   radix: usize,
+                   is_exact: Option<bool>,
+                   loc: Location) -> Option<SchemeResultAndLocation> {
         let sign : i64 = if is_negative { -1 } else { 1 };
 
         let mut abs_value : i64 = match self.next_char() {
             None    => return self.unexpected_eof(),
-            Some(c) => match c.to_digit(10) {
+            Some(c) => match c.to_digit(radix) {
                 None    => return self.unexpected_character(&c),
                 Some(d) => d as i64
             }
@@ -394,20 +806,76 @@ impl<'a, R: Reader> Read<R> {
             match self.peek_char() {
                 None                        => break,
                 Some(c) if is_delimiter(&c) => break,
-                Some(c)                     => match c.to_digit(10) {
+                Some('.') | Some('e') | Some('E') if radix == 10 => {
+                    let digits = format!("{}{}", if is_negative { "-" } else { "" }, abs_value);
+                    return self.read_decimal(digits, is_exact, loc);
+                },
+                Some(c)                     => match c.to_digit(radix) {
                     None    => return self.unexpected_character(&c),
-                    Some(d) => abs_value = (abs_value * 10) + (d as i64),
+                    Some(d) => {
+                        // The magnitude of `MIN_FIXNUM` is one more than
+                        // `MAX_FIXNUM`, so check the signed value.
+                        abs_value = match abs_value.checked_mul(radix as i64)
+                                                   .and_then(|n| n.checked_add(d as i64)) {
+                            Some(n) if value::is_fixnum(n * sign) => n,
+                            _ => return self.integer_out_of_range(),
+                        };
+                    },
                 }
             }
             self.next_char();
         }
 
+        if is_exact == Some(false) {
+            return self.read_flonum(abs_value as f64 * sign as f64, loc);
+        }
         self.root(loc, Value::new_integer(abs_value * sign))
     }
 
+    /// Read the rest of a decimal literal, given the sign and digits before its
+    /// '.' or exponent.
+    fn read_decimal(&mut self,
+                    mut text: String,
+                    is_exact: Option<bool>,
+                    loc: Location) -> Option<SchemeResultAndLocation> {
+        loop {
+            match self.peek_char() {
+                None                        => break,
+                Some(c) if is_delimiter(&c) => break,
+                Some(c)                     => {
+                    self.next_char();
+                    text.push(c);
+                },
+            }
+        }
+
+        let x = match text.parse::<f64>() {
+            Ok(x)  => x,
+            Err(_) => return self.bad_decimal_literal(),
+        };
+        if is_exact != Some(true) {
+            return self.read_flonum(x, loc);
+        }
+
+        // We have no exact rationals, so only integral decimals can be exact.
+        let i = x as i64;
+        if i as f64 != x || !value::is_fixnum(i) {
+            return self.inexact_exact_number();
+        }
+        self.root(loc, Value::new_integer(i))
+    }
+
+    /// Finish reading a flonum literal with the given value.
+    fn read_flonum(&mut self, x: f64, loc: Location) -> Option<SchemeResultAndLocation> {
+        let val = Value::new_flonum(self.heap(), x);
+        self.enlocate(loc, val)
+    }
+
     /// Read a pair, with the leading '(' already taken from the input.
     fn read_pair(&mut self, loc: Location) -> Option<SchemeResultAndLocation> {
-        self.trim();
+        if let Some(e) = self.trim() {
+            return Some(e);
+        }
         match self.peek_char() {
             None      => return self.unexpected_eof(),
 
@@ -417,26 +885,36 @@ impl<'a, R: Reader> Read<R> {
             },
 
             _         => {
-                let car = match self.next() {
+                let car = match self.read_datum() {
                     Some((_, Ok(v))) => v,
                     err => return err,
                 };
 
-                self.trim();
+                if let Some(e) = self.trim() {
+                    return Some(e);
+                }
                 let next_loc = self.current_location.clone();
 
+                // A '.' is only the dot of an improper list when it stands on
+                // its own, otherwise it begins a symbol such as `...`.
+                let is_dot = self.peek_char() == Some('.')
+                    && is_eof_or_delimiter(&self.peek_second_char());
+
                 match self.peek_char() {
                     None => return self.unexpected_eof(),
 
                     // Improper list.
-                    Some('.') => {
+                    Some('.') if is_dot => {
                         self.next_char();
-                        let cdr = match self.next() {
+                        let cdr = match self.read_datum() {
                             Some((_, Ok(v))) => v,
+                            None => return self.unexpected_eof(),
                             err => return err,
                         };
 
-                        self.trim();
+                        if let Some(e) = self.trim() {
+                            return Some(e);
+                        }
                         if let Some(e) = self.expect_character(')') {
                             return Some(e);
                         }
@@ -462,6 +940,59 @@ impl<'a, R: Reader> Read<R> {
         };
     }
 
+    /// Read the elements of a vector or bytevector up to and including the
+    /// closing ')', with the leading "#(" or "#u8(" already taken from the
+    /// input. Return the elements, or what to return from the iterator if
+    /// there was an error.
+    fn read_elements(&mut self) -> Result<Vec<RootedValue>, Option<SchemeResultAndLocation>> {
+        let mut elements = vec!();
+        loop {
+            if let Some(e) = self.trim() {
+                return Err(Some(e));
+            }
+            match self.peek_char() {
+                None      => return Err(self.unexpected_eof()),
+                Some(')') => {
+                    self.next_char();
+                    return Ok(elements);
+                },
+                _         => match self.read_datum() {
+                    Some((_, Ok(v))) => elements.push(v),
+                    err              => return Err(err),
+                },
+            }
+        }
+    }
+
+    /// Read a vector, with the leading "#(" already taken from the input.
+    fn read_vector(&mut self, loc: Location) -> Option<SchemeResultAndLocation> {
+        match self.read_elements() {
+            Ok(elements) => {
+                self.enlocate(loc, Value::new_vector(self.heap(), elements.as_slice()))
+            },
+            Err(err)     => err,
+        }
+    }
+
+    /// Read a bytevector, with the leading "#u8(" already taken from the
+    /// input.
+    fn read_bytevector(&mut self, loc: Location) -> Option<SchemeResultAndLocation> {
+        let elements = match self.read_elements() {
+            Ok(elements) => elements,
+            Err(err)     => return err,
+        };
+
+        let mut bytes = Vec::with_capacity(elements.len());
+        for element in elements.iter() {
+            match element.to_integer() {
+                Some(n) if n >= 0 && n <= 255 => bytes.push(n as u8),
+                _                             => return self.bad_byte(element),
+            }
+        }
+
+        self.enlocate(loc, Value::new_bytevector(self.heap(), bytes))
+    }
+
     /// Read a string in from the input.
     fn read_string(&mut self, loc: Location) -> Option<SchemeResultAndLocation> {
         if let Some(e) = self.expect_character('"') {
@@ -521,69 +1052,340 @@ impl<'a, R: Reader> Read<R> {
             };
         }
 
+        if self.fold_case {
+            str = str.chars().map(|c| c.to_lowercase()).collect();
+        }
+        if let Some(x) = special_flonum(str.as_slice()) {
+            return self.read_flonum(x, loc);
+        }
         return self.enlocate(loc, self.heap().get_or_create_symbol(str));
     }
 
-    /// Read a quoted form from input, e.g. `'(1 2 3)`.
-    fn read_quoted(&mut self, loc: Location) -> Option<SchemeResultAndLocation> {
-        if let Some(e) = self.expect_character('\'') {
+    /// Read a keyword, e.g. `#:key`, after the starting '#' and ':' characters
+    /// have been read. A keyword is a symbol whose name starts with `#:`, which
+    /// no bare symbol can, so keywords can't be confused with variables.
+    fn read_keyword(&mut self, loc: Location) -> Option<SchemeResultAndLocation> {
+        let mut str = "#:".to_string();
+        loop {
+            match self.peek_char() {
+                Some(c) if is_symbol_subsequent(&c) => {
+                    self.next_char();
+                    str.push(c)
+                },
+                Some(c) if str.len() == 2           => return self.unexpected_character(&c),
+                None if str.len() == 2              => return self.unexpected_eof(),
+                _                                   => break,
+            };
+        }
+
+        if self.fold_case {
+            str = str.chars().map(|c| c.to_lowercase()).collect();
+        }
+        return self.enlocate(loc, self.heap().get_or_create_symbol(str));
+    }
+
+    /// Read a symbol written between pipes, e.g. `|hello world|`. The name may
+    /// contain any character, with `\|` and `\\` escaping pipes and
+    /// backslashes, `\n` and `\t` for newlines and tabs, and `\x41;` for the
+    /// character with the given hexadecimal code point. Its case is never
+    /// folded.
+    fn read_pipe_symbol(&mut self, loc: Location) -> Option<SchemeResultAndLocation> {
+        if let Some(e) = self.expect_character('|') {
             return Some(e);
         }
 
-        return match self.next() {
-            Some((_, Ok(val))) => self.enlocate(loc,
-                                                list(self.heap(), &mut [
-                                                    self.heap().get_or_create_symbol("quote".to_string()),
-                                                    val
-                                                ])),
-            err => err
-        };
+        let mut str = String::new();
+
+        loop {
+            match self.next_char() {
+                None       => return self.unterminated_symbol(),
+                Some('|')  => return self.enlocate(loc, self.heap().get_or_create_symbol(str)),
+                Some('\\') => {
+                    match self.next_char() {
+                        Some('n')  => str.push('\n'),
+                        Some('t')  => str.push('\t'),
+                        Some('\\') => str.push('\\'),
+                        Some('|')  => str.push('|'),
+                        Some('x')  => match self.read_hex_escape() {
+                            Ok(c)  => str.push(c),
+                            Err(e) => return e,
+                        },
+                        Some(c)    => return self.unexpected_character(&c),
+                        None       => return self.unterminated_symbol(),
+                    }
+                },
+                Some(c)    => str.push(c),
+            }
+        }
     }
-}
 
-impl<R: Reader> Iterator for Read<R> {
-    type Item = SchemeResultAndLocation;
+    /// Read the hexadecimal code point and terminating ';' of a `\x41;`
+    /// escape, after the `\x` has already been read.
+    fn read_hex_escape(&mut self) -> Result<char, Option<SchemeResultAndLocation>> {
+        let mut code : u32 = 0;
+        let mut digits = 0;
+        loop {
+            match self.next_char() {
+                Some(';') if digits > 0 => break,
+                Some(c)                 => match c.to_digit(16) {
+                    Some(d) if digits < 6 => {
+                        code = code * 16 + d as u32;
+                        digits += 1;
+                    },
+                    _                     => return Err(self.unexpected_character(&c)),
+                },
+                None                    => return Err(self.unexpected_eof()),
+            }
+        }
 
-    fn next(&mut self) -> Option<SchemeResultAndLocation> {
+        match ::std::char::from_u32(code) {
+            Some(c) => Ok(c),
+            None    => Err(self.report_failure(&E0014,
+                                               format!("Invalid code point: \\x{:x};", code))),
+        }
+    }
+
+    /// Read a quotation form from input, e.g. `'(1 2 3)` or `,@rest`, and
+    /// expand it into a list, e.g. `(quote (1 2 3))` or
+    /// `(unquote-splicing rest)`. The expanded list is located at the quote
+    /// character, and its tail at the quoted datum.
+    fn read_quoted(&mut self, loc: Location) -> Option<SchemeResultAndLocation> {
+        let name = match self.next_char() {
+            Some('\'') => "quote",
+            Some('`')  => "quasiquote",
+            Some(',')  => {
+                if self.peek_char() == Some('@') {
+                    self.next_char();
+                    "unquote-splicing"
+                } else {
+                    "unquote"
+                }
+            },
+            Some(c)    => return self.unexpected_character(&c),
+            None       => return self.unexpected_eof(),
+        };
+
+        let (datum_loc, val) = match self.read_datum() {
+            Some((datum_loc, Ok(val))) => (datum_loc, val),
+            None                       => return self.unexpected_eof(),
+            err                        => return err,
+        };
+
+        let symbol = self.heap().get_or_create_symbol(name.to_string());
+        let empty = Rooted::new(self.heap(), Value::EmptyList);
+        let tail = Value::new_pair(self.heap(), &val, &empty);
+        let tail_pair = tail.to_pair(self.heap()).unwrap();
+        self.heap().enlocate(datum_loc, tail_pair);
+
+        self.enlocate(loc, Value::new_pair(self.heap(), &symbol, &tail))
+    }
+
+    /// Read the next datum from the input, or return `None` at EOF.
+    fn read_datum(&mut self) -> Option<SchemeResultAndLocation> {
         if self.had_error {
             return None;
         }
 
-        self.trim();
+        if let Some(e) = self.trim() {
+            return Some(e);
+        }
         let location = self.current_location.clone();
 
         match self.peek_char() {
             None                             => None,
-            Some('\'')                       => self.read_quoted(location),
+            Some('\'') | Some('`') | Some(',') => self.read_quoted(location),
             Some('-')                        => {
                 self.next_char();
                 match self.peek_char() {
                     Some(c) if c.is_digit(10) => {
-                        self.read_integer(true, location)
+                        self.read_number(true, 10, None, location)
                     },
                     _                         => self.read_symbol(Some('-'),
                                                                   location),
                 }
             },
-            Some(c) if c.is_digit(10)        => self.read_integer(false,
-                                                                  location),
-            Some('#')                        => self.read_bool_or_char(location),
+            Some(c) if c.is_digit(10)        => self.read_number(false, 10, None,
+                                                                 location),
+            Some('#')                        => self.read_hash_syntax(location),
             Some('"')                        => self.read_string(location),
+            Some('|')                        => self.read_pipe_symbol(location),
             Some('(')                        => {
                 self.next_char();
                 self.read_pair(location)
             },
+            Some('.')                        => {
+                self.next_char();
+                match self.peek_char() {
+                    Some(c) if is_symbol_subsequent(&c) => {
+                        self.read_symbol(Some('.'), location)
+                    },
+                    _                                   => self.unexpected_character(&'.'),
+                }
+            },
             Some(c) if is_symbol_initial(&c) => self.read_symbol(None, location),
             Some(c)                          => self.unexpected_character(&c),
         }
     }
 }
 
+impl<R: Reader> Iterator for Read<R> {
+    type Item = SchemeResultAndLocation;
+
+    fn next(&mut self) -> Option<SchemeResultAndLocation> {
+        // Datum labels are scoped to the outermost datum they appear in.
+        self.labels.clear();
+        self.read_datum()
+    }
+}
+
+/// The result of trying to read a datum with `IncrementalRead::read`.
+pub enum ReadStatus {
+    /// A complete datum, and its location.
+    Datum(Location, RootedValue),
+    /// The input fed so far does not hold another complete datum. Feed more
+    /// input, or call `IncrementalRead::finish` if there is no more.
+    NeedMoreInput,
+    /// The input has a syntax error that more input could not fix. The rest of
+    /// the buffered input is discarded.
+    Error(SchemeError),
+}
+
+/// A reader that is fed its input in chunks, such as lines typed at the REPL
+/// or data arriving over a socket, rather than pulling its input from a
+/// `Reader` that blocks until more is available. A datum is only read once all
+/// of it has been fed, so that input which ends in the middle of a datum can be
+/// told apart from a syntax error.
+pub struct IncrementalRead {
+    /// The input that has been fed but not read yet.
+    buffer: String,
+    /// The location of the start of `buffer`.
+    location: Location,
+    finished: bool,
+}
+
+impl IncrementalRead {
+    /// Create a new `IncrementalRead`, with no input fed yet. The `file_name`
+    /// is only used for the locations of the values read.
+    pub fn new(file_name: &str) -> IncrementalRead {
+        IncrementalRead {
+            buffer: String::new(),
+            location: Location::new(file_name.to_string()),
+            finished: false,
+        }
+    }
+
+    /// Add a chunk of input after the input fed so far.
+    pub fn feed(&mut self, chunk: &str) {
+        self.buffer.push_str(chunk);
+    }
+
+    /// Note that there is no more input to feed, so that a datum at the very
+    /// end of the input, such as `foo` without a trailing newline, is complete
+    /// rather than possibly the start of a longer datum.
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    /// Return true if the start of a datum has been fed but not read yet, false
+    /// if only whitespace is left. The REPL uses this to decide between its
+    /// usual prompt and a continuation prompt.
+    pub fn has_pending_input(&self) -> bool {
+        self.buffer.chars().any(|c| !c.is_whitespace())
+    }
+
+    /// Try to read the next datum from the input fed so far.
+    pub fn read(&mut self, heap: &mut Heap) -> ReadStatus {
+        let mut reader = read_from_str(self.buffer.as_slice(), heap, "");
+        reader.current_location = self.location.clone();
+
+        match reader.next() {
+            Some((location, Ok(form))) => {
+                // A datum that runs right up to the end of the input might
+                // continue in the next chunk, unless it ends with a closing
+                // delimiter.
+                let closed = match self.buffer.chars().nth(reader.consumed - 1) {
+                    Some(')') | Some('"') | Some('|') => true,
+                    _                                 => false,
+                };
+                if reader.peek_char().is_none() && !closed && !self.finished {
+                    return ReadStatus::NeedMoreInput;
+                }
+
+                self.consume(reader.consumed, reader.current_location.clone());
+                ReadStatus::Datum(location, form)
+            },
+
+            Some((_, Err(error))) => {
+                if reader.incomplete && !self.finished {
+                    return ReadStatus::NeedMoreInput;
+                }
+
+                self.discard();
+                ReadStatus::Error(error)
+            },
+
+            // Only whitespace and comments are left, but a line comment
+            // continues until the next newline.
+            None => {
+                if self.finished || self.buffer.ends_with("\n") {
+                    self.discard();
+                }
+                ReadStatus::NeedMoreInput
+            },
+        }
+    }
+
+    /// Take the input fed so far up to and including the next newline, or all
+    /// of it if there is no newline. Ports use this to mix reading data with
+    /// reading lines of text. Returns `None` if there is no buffered input.
+    pub fn take_line(&mut self) -> Option<String> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+
+        let count = match self.buffer.chars().position(|c| c == '\n') {
+            Some(n) => n + 1,
+            None    => self.buffer.chars().count(),
+        };
+        let line : String = self.buffer.chars().take(count).collect();
+        let mut location = self.location.clone();
+        for c in line.chars() {
+            location.advance(c);
+        }
+        self.consume(count, location);
+        Some(line)
+    }
+
+    /// Drop the first `count` characters of the buffered input, which end at
+    /// the given location.
+    fn consume(&mut self, count: usize, location: Location) {
+        self.buffer = self.buffer.chars().skip(count).collect();
+        self.location = location;
+    }
+
+    /// Drop all of the buffered input.
+    fn discard(&mut self) {
+        for c in self.buffer.chars() {
+            self.location.advance(c);
+        }
+        self.buffer.clear();
+    }
+}
+
+/// Create a `Read` instance from any input `Reader`, such as a socket or an
+/// in-memory buffer. The `file_name` is only used for the locations of the
+/// values read.
+pub fn read_from_reader<R: Reader>(reader: R,
+                                   heap: *mut Heap,
+                                   file_name: &str) -> Read<R> {
+    Read::new(reader, heap, file_name.to_string())
+}
+
 /// Create a `Read` instance from a byte vector.
 pub fn read_from_bytes(bytes: Vec<u8>,
                        heap: *mut Heap,
                        file_name: &str) -> Read<MemReader> {
-    Read::new(MemReader::new(bytes), heap, file_name.to_string())
+    read_from_reader(MemReader::new(bytes), heap, file_name)
 }
 
 /// Create a `Read` instance from a `String`.
@@ -600,12 +1402,15 @@ pub fn read_from_str(str: &str,
     read_from_string(str.to_string(), heap, file_name)
 }
 
-/// Create a `Read` instance from the file at `path_name`.
-pub fn read_from_file(path_name: &str, heap: *mut Heap) -> IoResult<Read<File>> {
-    let file_name = path_name.clone().to_string();
-    let path = Path::new(path_name);
-    let file = try!(File::open(&path));
-    Ok(Read::new(file, heap, file_name))
+/// Create a `Read` instance from the file at `path_name`. The file's text is
+/// recorded in the heap, so that errors in it can show the line they occurred
+/// on. See `Heap::set_source`.
+pub fn read_from_file(path_name: &str, heap: *mut Heap) -> IoResult<Read<MemReader>> {
+    let text = try!(File::open(&Path::new(path_name)).read_to_string());
+    unsafe {
+        (*heap).set_source(path_name, text.as_slice());
+    }
+    Ok(read_from_string(text, heap, path_name))
 }
 
 // TESTS -----------------------------------------------------------------------
@@ -614,7 +1419,7 @@ pub fn read_from_file(path_name: &str, heap: *mut Heap) -> IoResult<Read<File>>
 mod tests {
     use super::*;
     use heap::{Heap, Rooted};
-    use value::{Value};
+    use value::{RootedValue, SchemeResult, Value};
 
     #[test]
     fn test_read_integers() {
@@ -656,6 +1461,33 @@ mod tests {
                                  Value::new_character('\n')));
     }
 
+    #[test]
+    fn test_read_quasiquoted() {
+        //           1234567890123456
+        let input = "`(a ,b ,@c) ,'d";
+        let heap = &mut Heap::new();
+        let results : Vec<RootedValue> = read_from_str(input, heap, "test_read_quasiquoted")
+            .map(|(_, r)| r.ok().expect("Should not get a read error"))
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(format!("{}", *results[0]),
+                   "(quasiquote (a (unquote b) (unquote-splicing c)))");
+        assert_eq!(format!("{}", *results[1]), "(unquote (quote d))");
+
+        let location_of = |heap: &mut Heap, val: &RootedValue| {
+            let pair = val.to_pair(heap).expect("Should be a pair");
+            let loc = heap.locate(&pair);
+            (loc.line, loc.column)
+        };
+
+        assert_eq!(location_of(heap, &results[0]), (1, 1));
+        let tail = results[0].cdr(heap).unwrap();
+        assert_eq!(location_of(heap, &tail), (1, 2));
+        let unquoted = tail.car(heap).unwrap().cdr(heap).unwrap().car(heap).unwrap();
+        assert_eq!(location_of(heap, &unquoted), (1, 5));
+        assert_eq!(location_of(heap, &results[1]), (1, 13));
+    }
+
     #[test]
     fn test_read_comments() {
         let input = "1 ;; this is a comment\n2";
@@ -874,6 +1706,364 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_read_comments() {
+        let input = "#| a #| nested |# comment |# 1 \
+                     (2 #;(ignored) 3 #| inside |#) \
+                     #;4 #; #| between |# 5 \
+                     (6 . #;7 8) ; line comment\n\
+                     #t #;9";
+
+        let heap = &mut Heap::new();
+        let results : Vec<String> = read_from_str(input, heap, "test_read_comments")
+            .map(|(_, r)| format!("{}", *r.ok().expect("Should not get a read error")))
+            .collect();
+        assert_eq!(results, vec!("1".to_string(),
+                                 "(2 3)".to_string(),
+                                 "(6 . 8)".to_string(),
+                                 "#t".to_string()));
+    }
+
+    #[test]
+    fn test_read_unterminated_block_comment() {
+        let heap = &mut Heap::new();
+        let results : Vec<SchemeResult> = read_from_str("1 #| #| |# 2", heap, "unterminated")
+            .map(|(_, r)| r)
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[1].as_ref().err()
+                .expect("Should get a read error")
+                .message
+                .contains("Unterminated block comment"));
+    }
+
+    #[test]
+    fn test_read_radix_prefixes() {
+        let input = "#x1F #XfF #b1010 #o17 #d10 #e#x-10 #x#e+7 #b-1";
+        let heap = &mut Heap::new();
+        let results : Vec<Value> = read_from_str(input, heap, "test_read_radix_prefixes")
+            .map(|(_, r)| *r.ok().expect("Should not get a read error"))
+            .collect();
+        assert_eq!(results, vec!(Value::new_integer(31),
+                                 Value::new_integer(255),
+                                 Value::new_integer(10),
+                                 Value::new_integer(15),
+                                 Value::new_integer(10),
+                                 Value::new_integer(-16),
+                                 Value::new_integer(7),
+                                 Value::new_integer(-1)));
+    }
+
+    #[test]
+    fn test_read_decimals() {
+        let input = "1.5 -0.25 1e3 2.5E-1 #i10 #e2.0 +inf.0 -inf.0 +nan.0";
+        let heap = &mut Heap::new();
+        let results : Vec<String> = read_from_str(input, heap, "test_read_decimals")
+            .map(|(_, r)| format!("{}", *r.ok().expect("Should not get a read error")))
+            .collect();
+        assert_eq!(results, vec!("1.5", "-0.25", "1000.0", "0.25", "10.0", "2", "+inf.0",
+                                 "-inf.0", "+nan.0"));
+    }
+
+    #[test]
+    fn test_read_bad_radix_prefixes() {
+        for input in ["#b102", "#x#o7", "#e#e1", "#e1.5", "1.2.3", "#x"].iter() {
+            let heap = &mut Heap::new();
+            let results : Vec<SchemeResult> = read_from_str(*input, heap, "bad_prefix")
+                .map(|(_, r)| r)
+                .collect();
+            assert_eq!(results.len(), 1);
+            assert!(results[0].is_err(), "{} should fail to read", input);
+        }
+    }
+
+    #[test]
+    fn test_read_vectors() {
+        let input = "#() #(1 #(2) (3 . 4) #;5 \"six\") #u8(0 #xff) #U8(1)";
+        let heap = &mut Heap::new();
+        let results : Vec<SchemeResult> = read_from_str(input, heap, "test_read_vectors")
+            .map(|(_, r)| r)
+            .collect();
+        assert_eq!(results.len(), 4);
+        assert_eq!(format!("{}", **results[0].as_ref().ok().unwrap()), "#()");
+        assert_eq!(format!("{}", **results[1].as_ref().ok().unwrap()),
+                   "#(1 #(2) (3 . 4) \"six\")");
+        assert_eq!(format!("{}", **results[2].as_ref().ok().unwrap()), "#u8(0 255)");
+        assert!(results[3].is_err());
+    }
+
+    #[test]
+    fn test_read_bad_bytevectors() {
+        for input in ["#u8(256)", "#u8(-1)", "#u8(a)", "#u8(1 2"].iter() {
+            let heap = &mut Heap::new();
+            let results : Vec<SchemeResult> = read_from_str(*input, heap, "bad_bytevector")
+                .map(|(_, r)| r)
+                .collect();
+            assert_eq!(results.len(), 1);
+            assert!(results[0].is_err(), "{} should fail to read", input);
+        }
+    }
+
+    #[test]
+    fn test_read_dotted_pairs() {
+        let input = "(a . b) (1 2 . 3) (a . (b . (c))) (... .x) ((a . b) . (c . d)) (a .\nb)";
+        let expected = ["(a . b)", "(1 2 . 3)", "(a b c)", "(... .x)", "((a . b) c . d)",
+                        "(a . b)"];
+
+        let heap = &mut Heap::new();
+        let results : Vec<String> = read_from_str(input, heap, "test_read_dotted_pairs")
+            .map(|(_, r)| format!("{}", *r.ok().expect("Should not get a read error")))
+            .collect();
+        assert_eq!(results, expected.iter().map(|s| s.to_string()).collect::<Vec<String>>());
+
+        // Printing and reading back in should give the same structure.
+        for printed in results.iter() {
+            let reread : Vec<String> = read_from_str(printed.as_slice(), heap, "reread")
+                .map(|(_, r)| format!("{}", *r.ok().expect("Should not get a read error")))
+                .collect();
+            assert_eq!(reread, vec!(printed.clone()));
+        }
+    }
+
+    #[test]
+    fn test_read_bad_dotted_pairs() {
+        for input in ["(a . b c)", "(. a)", "(a . )", "(a .", "."].iter() {
+            let heap = &mut Heap::new();
+            let results : Vec<SchemeResult> = read_from_str(*input, heap, "bad_dotted_pair")
+                .map(|(_, r)| r)
+                .collect();
+            assert_eq!(results.len(), 1);
+            assert!(results[0].is_err(), "{} should fail to read", input);
+        }
+    }
+
+    #[test]
+    fn test_read_datum_labels() {
+        let input = "#0=(1 . #0#) #0=#(a #0#) (#1=(x) #1#) #0=(#1=(a) #1# . #0#) \
+                     #5=(b #6='c . #5#)";
+        let expected = ["#0=(1 . #0#)", "#0=#(a #0#)", "((x) (x))", "#0=((a) (a) . #0#)",
+                        "#0=(b (quote c) . #0#)"];
+
+        let heap = &mut Heap::new();
+        let results : Vec<RootedValue> = read_from_str(input, heap, "test_read_datum_labels")
+            .map(|(_, r)| r.ok().expect("Should not get a read error"))
+            .collect();
+        let printed : Vec<String> = results.iter().map(|v| format!("{}", **v)).collect();
+        assert_eq!(printed, expected.iter().map(|s| s.to_string()).collect::<Vec<String>>());
+
+        // Every reference to a label is the same object.
+        let shared = results[2].car(heap).unwrap();
+        assert_eq!(*shared, *results[2].cdr(heap).unwrap().car(heap).unwrap());
+
+        // Printed cycles can be read back in.
+        for printed in printed.iter() {
+            let reread : Vec<String> = read_from_str(printed.as_slice(), heap, "reread")
+                .map(|(_, r)| format!("{}", *r.ok().expect("Should not get a read error")))
+                .collect();
+            assert_eq!(reread, vec!(printed.clone()));
+        }
+    }
+
+    #[test]
+    fn test_read_bad_datum_labels() {
+        for input in ["#0#", "#0=#0#", "(#0=a #0=b)", "#0=", "#0", "#0=a #0#"].iter() {
+            let heap = &mut Heap::new();
+            let results : Vec<SchemeResult> = read_from_str(*input, heap, "bad_datum_label")
+                .map(|(_, r)| r)
+                .collect();
+            assert!(results.last().unwrap().is_err(), "{} should fail to read", input);
+        }
+    }
+
+    #[test]
+    fn test_read_pipe_symbols() {
+        let input = "|hello world| |a\\|b\\x3b;| || λ→x ab@c -> #!fold-case FOO |FOO| \
+                     #!no-fold-case FOO";
+        let expected = ["|hello world|", "|a\\|b;|", "||", "λ→x", "ab@c", "->", "foo", "FOO",
+                        "FOO"];
+
+        let heap = &mut Heap::new();
+        let results : Vec<String> = read_from_str(input, heap, "test_read_pipe_symbols")
+            .map(|(_, r)| format!("{}", *r.ok().expect("Should not get a read error")))
+            .collect();
+        assert_eq!(results, expected.iter().map(|s| s.to_string()).collect::<Vec<String>>());
+
+        // Symbols are the same whether or not they are written with pipes.
+        let results : Vec<Value> = read_from_str("abc |abc| -1 |-1|", heap, "same_symbol")
+            .map(|(_, r)| *r.ok().expect("Should not get a read error"))
+            .collect();
+        assert_eq!(results[0], results[1]);
+        assert_eq!(format!("{}", results[3]), "|-1|");
+        assert!(results[2] != results[3]);
+
+        // Names that would read back in as numbers keep their pipes, and other
+        // names starting with a sign or a dot don't need them.
+        let input = "|+5| |.5| |-.5| |+.5| + - ... |+a| |.a| |-|";
+        let expected = ["|+5|", "|.5|", "|-.5|", "|+.5|", "+", "-", "...", "+a", ".a", "-"];
+        let results : Vec<String> = read_from_str(input, heap, "numeric_symbols")
+            .map(|(_, r)| format!("{}", *r.ok().expect("Should not get a read error")))
+            .collect();
+        assert_eq!(results, expected.iter().map(|s| s.to_string()).collect::<Vec<String>>());
+        for printed in results.iter() {
+            let reread : Vec<String> = read_from_str(printed.as_slice(), heap, "reread")
+                .map(|(_, r)| format!("{}", *r.ok().expect("Should not get a read error")))
+                .collect();
+            assert_eq!(reread, vec!(printed.clone()));
+        }
+    }
+
+    #[test]
+    fn test_read_bad_pipe_symbols() {
+        for input in ["|abc", "|\\q|", "|\\x;|", "|\\xd800;|", "#!unknown"].iter() {
+            let heap = &mut Heap::new();
+            let results : Vec<SchemeResult> = read_from_str(*input, heap, "bad_symbol")
+                .map(|(_, r)| r)
+                .collect();
+            assert_eq!(results.len(), 1);
+            assert!(results[0].is_err(), "{} should fail to read", input);
+        }
+    }
+
+    #[test]
+    fn test_read_keywords() {
+        let heap = &mut Heap::new();
+        let results : Vec<String> = read_from_str("#:key #:x->y (#:a . 1) |#:b|", heap,
+                                                  "test_read_keywords")
+            .map(|(_, r)| format!("{}", *r.ok().expect("Should not get a read error")))
+            .collect();
+        assert_eq!(results, vec!("#:key".to_string(),
+                                 "#:x->y".to_string(),
+                                 "(#:a . 1)".to_string(),
+                                 "#:b".to_string()));
+
+        for input in ["#:", "#: a", "#:)"].iter() {
+            let results : Vec<SchemeResult> = read_from_str(*input, heap, "bad_keyword")
+                .map(|(_, r)| r)
+                .collect();
+            assert!(results[0].is_err(), "{} should fail to read", input);
+        }
+    }
+
+    #[test]
+    fn test_read_incrementally() {
+        let heap = &mut Heap::new();
+        let mut input = IncrementalRead::new("chunks");
+
+        let read_datum = |heap: &mut Heap, input: &mut IncrementalRead| {
+            match input.read(heap) {
+                ReadStatus::Datum(loc, form) => Some(format!("{} {}", loc, *form)),
+                ReadStatus::NeedMoreInput    => None,
+                ReadStatus::Error(msg)       => panic!("Unexpected read error: {}", msg),
+            }
+        };
+
+        input.feed("(define x");
+        assert_eq!(read_datum(heap, &mut input), None);
+        assert!(input.has_pending_input());
+
+        input.feed(" \"a \n");
+        assert_eq!(read_datum(heap, &mut input), None);
+        input.feed("b\") #| comment\n");
+        assert_eq!(read_datum(heap, &mut input),
+                   Some("chunks:1:1 (define x \"a \nb\")".to_string()));
+        assert_eq!(read_datum(heap, &mut input), None);
+        input.feed("|# 'foo");
+        assert_eq!(read_datum(heap, &mut input), None);
+        input.feed("bar\n");
+        assert_eq!(read_datum(heap, &mut input), Some("chunks:3:4 (quote foobar)".to_string()));
+        assert_eq!(read_datum(heap, &mut input), None);
+        assert!(!input.has_pending_input());
+
+        input.feed("baz");
+        assert_eq!(read_datum(heap, &mut input), None);
+        input.finish();
+        assert_eq!(read_datum(heap, &mut input), Some("chunks:4:1 baz".to_string()));
+        assert_eq!(read_datum(heap, &mut input), None);
+    }
+
+    #[test]
+    fn test_read_incrementally_error() {
+        let heap = &mut Heap::new();
+        let mut input = IncrementalRead::new("chunks");
+        input.feed("(1 2 #y 3) (4\n");
+        match input.read(heap) {
+            ReadStatus::Error(error) => assert!(error.message.contains("chunks:1:8")),
+            _                        => panic!("Should get a read error"),
+        }
+
+        // The rest of the input that had been fed is discarded.
+        assert!(!input.has_pending_input());
+        input.feed("5\n");
+        match input.read(heap) {
+            ReadStatus::Datum(loc, form) => {
+                assert_eq!(format!("{} {}", loc, *form), "chunks:2:1 5");
+            },
+            _                            => panic!("Should read a datum"),
+        }
+    }
+
+    #[test]
+    fn test_read_incrementally_take_line() {
+        let heap = &mut Heap::new();
+        let mut input = IncrementalRead::new("chunks");
+        input.feed("1 rest of line\nnext 2\n");
+        match input.read(heap) {
+            ReadStatus::Datum(_, form) => assert_eq!(*form, Value::new_integer(1)),
+            _                          => panic!("Should read a datum"),
+        }
+
+        assert_eq!(input.take_line(), Some(" rest of line\n".to_string()));
+        assert_eq!(input.take_line(), Some("next 2\n".to_string()));
+        assert_eq!(input.take_line(), None);
+
+        input.feed("3\n");
+        match input.read(heap) {
+            ReadStatus::Datum(loc, form) => {
+                assert_eq!(format!("{} {}", loc, *form), "chunks:3:1 3");
+            },
+            _                            => panic!("Should read a datum"),
+        }
+    }
+
+    #[test]
+    fn test_read_shebang() {
+        let inputs = ["#!/usr/bin/env oxischeme\n(+ 1 2)",
+                      "#! /usr/local/bin/oxischeme -q\n(+ 1 2)"];
+        for input in inputs.iter() {
+            let heap = &mut Heap::new();
+            let results : Vec<String> = read_from_str(*input, heap, "test_read_shebang")
+                .map(|(_, r)| format!("{}", *r.ok().expect("Should not get a read error")))
+                .collect();
+            assert_eq!(results, vec!("(+ 1 2)".to_string()));
+        }
+
+        // A shebang is only allowed on the first line.
+        let heap = &mut Heap::new();
+        let results : Vec<SchemeResult> = read_from_str("1\n#!/usr/bin/env oxischeme\n2",
+                                                        heap,
+                                                        "late_shebang")
+            .map(|(_, r)| r)
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+    }
+
+    #[test]
+    fn test_read_from_reader() {
+        use std::old_io::{BufReader};
+
+        let heap = &mut Heap::new();
+        let bytes = b"(1 2) three";
+        let results : Vec<(Location, Value)> = read_from_reader(BufReader::new(bytes),
+                                                                heap,
+                                                                "buffer")
+            .map(|(loc, r)| (loc, *r.ok().expect("Should not get a read error")))
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(format!("{}", results[0].1), "(1 2)");
+        assert_eq!(format!("{}", results[1].0), "buffer:1:7");
+    }
+
     #[test]
     fn test_read_from_file() {
         let heap = &mut Heap::new();
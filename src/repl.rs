@@ -0,0 +1,366 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An interactive Read -> Evaluate -> Print session, driven a line at a time.
+//!
+//! A `Repl` does not own a terminal: the host feeds it each line of input with
+//! `Repl::feed_line`, and shows the output and the next prompt from the
+//! `ReplResponse` it gets back. The `oxischeme` binary drives one from stdin,
+//! and a GUI or web front-end can drive one from a text box:
+//!
+//!     let mut repl = Repl::new(heap);
+//!     let response = repl.feed_line("(+ 1 2)");
+//!     // response.outputs == [ReplOutput::Value("3")]
+//!
+//! Ending the session is up to the host too. When Scheme code calls `exit`,
+//! the response holds its `ExitRequest`.
+
+use eval;
+use heap::{ExitRequest, Heap, ReplPrompt, Rooted};
+use primitives::{PrimitiveInfo};
+use read::{IncrementalRead, ReadStatus};
+use transcript::{TranscriptWriter};
+use value::{Value};
+
+/// The name of the current module, as shown in the REPL prompt. There is no
+/// module system yet, so this is always the same.
+static CURRENT_MODULE: &'static str = "user";
+
+/// The name of the source of REPL input, as shown in source locations.
+static SOURCE_NAME: &'static str = "stdin";
+
+/// The prompt shown while the REPL is waiting for the rest of a datum that
+/// spans several lines.
+static CONTINUATION_PROMPT: &'static str = "... ";
+
+/// One piece of output from a line of REPL input.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ReplOutput {
+    /// The printed value of a form.
+    Value(String),
+    /// A warning about a form, which comes before the form's value.
+    Warning(String),
+    /// An error reading or evaluating a form, or recording it to the
+    /// transcript.
+    Error(String),
+}
+
+/// What the REPL did with a line of input.
+#[derive(Clone, Debug)]
+pub struct ReplResponse {
+    /// The output of every form the line completed, in order.
+    pub outputs: Vec<ReplOutput>,
+    /// The prompt to show before the next line.
+    pub prompt: String,
+    /// If Scheme code asked to end the session, how it asked to. The rest of
+    /// the line's forms are not evaluated.
+    pub exit: Option<ExitRequest>,
+}
+
+/// An interactive session evaluating forms in a heap.
+///
+/// The prompt and the banner can be configured with `Heap::set_repl_prompt`
+/// and `Heap::set_repl_banner`, or from Scheme with `set-repl-prompt!` and
+/// `set-repl-banner!`. While a datum that spans several lines is being typed,
+/// the continuation prompt is shown instead.
+pub struct Repl<'a> {
+    heap: &'a mut Heap,
+    input: IncrementalRead,
+    /// The number of values printed so far.
+    count: usize,
+    transcript: Option<TranscriptWriter>,
+}
+
+impl<'a> Repl<'a> {
+    /// Create a new session evaluating forms in the given heap.
+    pub fn new(heap: &'a mut Heap) -> Repl<'a> {
+        Repl {
+            heap: heap,
+            input: IncrementalRead::new(SOURCE_NAME),
+            count: 0,
+            transcript: None,
+        }
+    }
+
+    /// Record every form read from now on to the given transcript.
+    pub fn set_transcript(&mut self, transcript: TranscriptWriter) {
+        self.transcript = Some(transcript);
+    }
+
+    /// Get the heap this session evaluates forms in.
+    pub fn heap(&mut self) -> &mut Heap {
+        &mut *self.heap
+    }
+
+    /// Get the banner to show when the session starts.
+    pub fn banner(&self) -> String {
+        self.heap.repl_banner().to_string()
+    }
+
+    /// Get the prompt to show before the next line of input.
+    pub fn prompt(&mut self) -> String {
+        if self.input.has_pending_input() {
+            CONTINUATION_PROMPT.to_string()
+        } else {
+            render_prompt(self.heap, self.count)
+        }
+    }
+
+    /// Read and evaluate every form completed by the given line of input. A
+    /// form that is not complete yet waits for the following lines.
+    ///
+    /// A line starting with a comma, when no datum is waiting to be completed,
+    /// is a command to the REPL rather than Scheme code:
+    ///
+    /// * `,doc name` shows the documentation of the procedure bound to the
+    ///   global `name`.
+    pub fn feed_line(&mut self, line: &str) -> ReplResponse {
+        if !self.input.has_pending_input() && line.trim_left().starts_with(",") {
+            let output = self.run_command(line.trim());
+            return ReplResponse {
+                outputs: vec!(output),
+                prompt: self.prompt(),
+                exit: None,
+            };
+        }
+
+        self.input.feed(line);
+        self.heap.record_source(SOURCE_NAME, line);
+        if !line.ends_with("\n") {
+            self.input.feed("\n");
+            self.heap.record_source(SOURCE_NAME, "\n");
+        }
+        self.respond()
+    }
+
+    /// Note that there is no more input, and read and evaluate the form at the
+    /// very end of it, if there is one.
+    pub fn finish(&mut self) -> ReplResponse {
+        self.input.finish();
+        self.respond()
+    }
+
+    /// Get the names bound in the global environment that complete the name at
+    /// the end of the given text.
+    pub fn complete(&mut self, text: &str) -> Vec<String> {
+        let is_delimiter = |c: char| match c {
+            '(' | ')' | '\'' | '`' | ',' | '"' | ';' => true,
+            _                                        => c.is_whitespace(),
+        };
+        let start = text.rfind(is_delimiter).map_or(0, |i| i + 1);
+        let prefix = &text[start..];
+        if prefix.is_empty() {
+            return vec!();
+        }
+
+        let global = self.heap.global_activation();
+        self.heap.environment.names_with_prefix(prefix, &*global)
+    }
+
+    /// Run a REPL command, such as `,doc car`.
+    fn run_command(&mut self, command: &str) -> ReplOutput {
+        let words : Vec<&str> = command.split(' ').filter(|w| !w.is_empty()).collect();
+        match words.as_slice() {
+            [",doc", name] => self.documentation(name),
+            _              => ReplOutput::Error(format!("Error: unknown REPL command: {}",
+                                                        command)),
+        }
+    }
+
+    /// Describe the documentation of the procedure bound to the given global.
+    fn documentation(&mut self, name: &str) -> ReplOutput {
+        let global = self.heap.global_activation();
+        let val = match self.heap.environment.lookup_global_name(name) {
+            Some(j) => global.fetch(self.heap, 0, j).ok(),
+            None    => None,
+        };
+        let val = match val {
+            Some(val) => val,
+            None      => return ReplOutput::Error(format!("Error: `{}` is not defined", name)),
+        };
+
+        match *val {
+            Value::Procedure(p) => match self.heap.documentation(&p.location) {
+                Some(doc) => ReplOutput::Value(doc),
+                None      => ReplOutput::Value(format!("`{}` is not documented", name)),
+            },
+            Value::Primitive(p) => match self.heap.primitive_info(p.name()) {
+                Some(&PrimitiveInfo { documentation: Some(doc), module, .. }) => {
+                    ReplOutput::Value(format!("`{}` is the {} primitive `{}`: {}",
+                                              name, module, p.name(), doc))
+                },
+                _ => ReplOutput::Value(format!("`{}` is the primitive `{}`", name, p.name())),
+            },
+            _ => ReplOutput::Error(format!("Error: `{}` is not a procedure", name)),
+        }
+    }
+
+    /// Read and evaluate the complete forms fed so far.
+    fn respond(&mut self) -> ReplResponse {
+        let mut outputs = vec!();
+        let mut exit = None;
+
+        loop {
+            match self.input.read(self.heap) {
+                ReadStatus::Datum(location, form) => {
+                    if let Some(ref mut writer) = self.transcript {
+                        let now = self.heap.current_time();
+                        if let Err(e) = writer.record(now, &form) {
+                            outputs.push(ReplOutput::Error(
+                                format!("Error: could not record transcript: {}", e)));
+                        }
+                    }
+
+                    let result = eval::evaluate(self.heap, &form, location);
+                    for warning in self.heap.take_warnings().iter() {
+                        outputs.push(ReplOutput::Warning(format!("{}", warning)));
+                    }
+                    match result {
+                        Ok(val) => {
+                            self.count += 1;
+                            outputs.push(ReplOutput::Value(format!("{}", *val)));
+                        },
+                        Err(e) => {
+                            exit = self.heap.take_exit_request();
+                            if exit.is_some() {
+                                break;
+                            }
+                            outputs.push(ReplOutput::Error(e));
+                        },
+                    };
+
+                    self.heap.collect_garbage();
+                },
+                ReadStatus::Error(error) => {
+                    outputs.push(ReplOutput::Error(error.to_string()));
+                },
+                ReadStatus::NeedMoreInput => break,
+            }
+        }
+
+        ReplResponse {
+            outputs: outputs,
+            prompt: self.prompt(),
+            exit: exit,
+        }
+    }
+}
+
+/// Render the REPL prompt as configured on the heap, given the number of results
+/// printed so far.
+fn render_prompt(heap: &mut Heap, count: usize) -> String {
+    let procedure = match *heap.repl_prompt() {
+        ReplPrompt::Format(ref format) => {
+            return format
+                .replace("{count}", count.to_string().as_slice())
+                .replace("{module}", CURRENT_MODULE);
+        },
+        ReplPrompt::Procedure(ref procedure) => procedure.clone(),
+    };
+
+    let args = vec!(
+        Rooted::new(heap, Value::new_integer(count as i64)),
+        Value::new_string(heap, CURRENT_MODULE.to_string())
+    );
+    match heap.call_nested(&procedure, args) {
+        Ok(val) => match *val {
+            Value::String(str) => (*str).clone(),
+            v                  => format!("{}", v),
+        },
+        Err(e) => format!("Error rendering prompt: {}\noxischeme> ", e),
+    }
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heap::{Heap, ReplPrompt};
+
+    #[test]
+    fn test_repl_feed_line() {
+        let heap = &mut Heap::new();
+        heap.set_repl_prompt(ReplPrompt::Format("{count}> ".to_string()));
+        let mut repl = Repl::new(heap);
+        assert_eq!(repl.prompt(), "0> ");
+
+        let response = repl.feed_line("(define x 1) (+ x 1) (car '())");
+        assert_eq!(response.outputs[1], ReplOutput::Value("2".to_string()));
+        match response.outputs[2] {
+            ReplOutput::Error(ref e) => {
+                assert!(e.contains("car"), "{}", e);
+                assert!(e.contains("1 | (define x 1) (+ x 1) (car '())\n"),
+                        "Should show the offending line: {}", e);
+            },
+            ref output               => panic!("Expected an error, found {:?}", output),
+        }
+        assert_eq!(response.prompt, "2> ");
+        assert!(response.exit.is_none());
+
+        let response = repl.feed_line("(list x");
+        assert!(response.outputs.is_empty());
+        assert_eq!(response.prompt, "... ");
+        let response = repl.feed_line("2)");
+        assert_eq!(response.outputs, vec!(ReplOutput::Value("(1 2)".to_string())));
+        assert_eq!(response.prompt, "3> ");
+
+        let response = repl.feed_line("(exit 3) (define y 2)");
+        assert_eq!(response.exit.map(|request| request.status), Some(3));
+        assert!(repl.heap().eval_str("y").is_err(),
+                "Should not evaluate the forms after `exit`");
+    }
+
+    #[test]
+    fn test_repl_failing_prompt_procedure() {
+        let heap = &mut Heap::new();
+        heap.eval_str("(set-repl-prompt! (lambda (count module) (car (cdr '()))))")
+            .ok()
+            .expect("Should be able to set the prompt.");
+        let mut repl = Repl::new(heap);
+        assert!(repl.prompt().starts_with("Error rendering prompt: "));
+        assert_eq!(repl.heap().stack_depth(), 0);
+        assert!(repl.heap().take_error_location().is_none());
+    }
+
+    #[test]
+    fn test_repl_doc_command() {
+        let heap = &mut Heap::new();
+        let mut repl = Repl::new(heap);
+        repl.feed_line("(define frob (lambda (x) \"Frob x.\" x))");
+        let response = repl.feed_line(",doc frob");
+        assert_eq!(response.outputs, vec!(ReplOutput::Value("Frob x.".to_string())));
+
+        let response = repl.feed_line(",doc car");
+        assert_eq!(response.outputs,
+                   vec!(ReplOutput::Value("`car` is the pure primitive `car`: Return the car \
+                                           of the given pair.".to_string())));
+
+        let response = repl.feed_line(",doc no-such-thing");
+        match response.outputs[0] {
+            ReplOutput::Error(ref e) => assert!(e.contains("not defined"), "{}", e),
+            ref output               => panic!("Expected an error, found {:?}", output),
+        }
+    }
+
+    #[test]
+    fn test_repl_complete() {
+        let heap = &mut Heap::new();
+        let mut repl = Repl::new(heap);
+        repl.feed_line("(define frob-a 1) (define frob-b 2)");
+        assert_eq!(repl.complete("(list frob-"),
+                   vec!("frob-a".to_string(), "frob-b".to_string()));
+        assert!(repl.complete("(list ").is_empty());
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Converting Scheme data to and from serde's data model, so that embedders can
+//! pass configuration and results across the boundary in any serde format. This
+//! module is only available with the `serde` feature.
+//!
+//! Scheme data maps to serde's data model as follows:
+//!
+//! * Integers, flonums, booleans, and characters map to `i64`, `f64`, `bool`,
+//! and `char`.
+//!
+//! * Strings and symbols both map to strings. Deserialized strings are always
+//! Scheme strings, except for the keys of maps, which become symbols.
+//!
+//! * Bytevectors map to bytes.
+//!
+//! * Proper lists and vectors both map to sequences. Deserialized sequences are
+//! always lists.
+//!
+//! * There are no hash tables yet, so maps are association lists. A non-empty
+//! list whose elements are all pairs with a symbol in their car serializes as a
+//! map, and a deserialized map is an association list with symbol keys:
+//!
+//!     {"name": "oxischeme", "tags": [1, 2]}  <=>  ((name . "oxischeme") (tags 1 2))
+//!
+//! * Unit and `None` deserialize as the empty list, which serializes as an
+//! empty sequence.
+//!
+//! Procedures, primitives, foreign and native objects, improper lists, and
+//! cyclic data cannot be serialized.
+//!
+//! Values live in a heap, so they cannot implement `Deserialize` by
+//! themselves. Use `deserialize` or a `ValueSeed` instead.
+
+use std::cell::{RefCell};
+use std::collections::{HashSet};
+use std::fmt;
+
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Unexpected,
+                Visitor};
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq, Serializer};
+
+use heap::{Heap, Rooted};
+use value::{list, RootedValue, Value};
+
+// SERIALIZATION ---------------------------------------------------------------
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let path = RefCell::new(HashSet::new());
+        Datum { val: *self, path: &path }.serialize(serializer)
+    }
+}
+
+impl Serialize for Rooted<Value> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+/// A value being serialized, along with the pairs and vectors that enclose it,
+/// so that cycles are reported rather than serialized forever.
+///
+/// Serializing never allocates, so the values within a rooted value need not be
+/// rooted themselves.
+struct Datum<'a> {
+    val: Value,
+    path: &'a RefCell<HashSet<Value>>,
+}
+
+impl<'a> Datum<'a> {
+    fn with(&self, val: Value) -> Datum<'a> {
+        Datum { val: val, path: self.path }
+    }
+
+    /// Serialize the given elements of this datum, which is a list or vector.
+    fn serialize_elements<S: Serializer>(&self,
+                                         serializer: S,
+                                         elements: Vec<Value>) -> Result<S::Ok, S::Error> {
+        if !self.path.borrow_mut().insert(self.val) {
+            return Err(ser::Error::custom("Error: cannot serialize cyclic data"));
+        }
+
+        let result = if self.val.is_pair() && is_association_list(&elements) {
+            self.serialize_association_list(serializer, elements)
+        } else {
+            let mut seq = try!(serializer.serialize_seq(Some(elements.len())));
+            for element in elements.into_iter() {
+                try!(seq.serialize_element(&self.with(element)));
+            }
+            seq.end()
+        };
+
+        self.path.borrow_mut().remove(&self.val);
+        result
+    }
+
+    fn serialize_association_list<S: Serializer>(&self,
+                                                 serializer: S,
+                                                 elements: Vec<Value>)
+                                                 -> Result<S::Ok, S::Error> {
+        let mut map = try!(serializer.serialize_map(Some(elements.len())));
+        for element in elements.into_iter() {
+            if let Value::Pair(cons) = element {
+                try!(map.serialize_entry(&self.with(cons.unrooted_car()),
+                                         &self.with(cons.unrooted_cdr())));
+            }
+        }
+        map.end()
+    }
+}
+
+impl<'a> Serialize for Datum<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.val {
+            Value::Integer(i)    => serializer.serialize_i64(i),
+            Value::Flonum(f)     => serializer.serialize_f64(*f),
+            Value::Boolean(b)    => serializer.serialize_bool(b),
+            Value::Character(c)  => serializer.serialize_char(c),
+            Value::String(s)     => serializer.serialize_str(&s[..]),
+            Value::Symbol(s)     => serializer.serialize_str(&s[..]),
+            Value::Bytevector(b) => serializer.serialize_bytes(&b[..]),
+            Value::EmptyList     => try!(serializer.serialize_seq(Some(0))).end(),
+            Value::Pair(_)       => {
+                let elements = try!(list_elements(self.val).map_err(ser::Error::custom));
+                self.serialize_elements(serializer, elements)
+            },
+            Value::Vector(v)     => {
+                let elements = (0..v.len())
+                    .filter_map(|i| v.unrooted_get(i))
+                    .collect();
+                self.serialize_elements(serializer, elements)
+            },
+            _                    => Err(ser::Error::custom(
+                format!("Error: cannot serialize {}", self.val))),
+        }
+    }
+}
+
+/// Get the elements of the given list, or an error if it is improper or its
+/// spine is cyclic.
+fn list_elements(val: Value) -> Result<Vec<Value>, String> {
+    let mut elements = vec!();
+    let mut spine = HashSet::new();
+    let mut rest = val;
+    loop {
+        match rest {
+            Value::EmptyList => return Ok(elements),
+            Value::Pair(cons) => {
+                if !spine.insert(rest) {
+                    return Err("Error: cannot serialize cyclic data".to_string());
+                }
+                elements.push(cons.unrooted_car());
+                rest = cons.unrooted_cdr();
+            },
+            _ => return Err(format!("Error: cannot serialize improper list {}", val)),
+        }
+    }
+}
+
+/// Return true if the given list elements are an association list: there is at
+/// least one, and every one is a pair with a symbol in its car.
+fn is_association_list(elements: &[Value]) -> bool {
+    !elements.is_empty() && elements.iter().all(|element| {
+        match *element {
+            Value::Pair(cons) => match cons.unrooted_car() {
+                Value::Symbol(_) => true,
+                _                => false,
+            },
+            _                 => false,
+        }
+    })
+}
+
+// DESERIALIZATION -------------------------------------------------------------
+
+/// Deserialize a value from the given deserializer, allocating it in the given
+/// heap.
+pub fn deserialize<'de, D>(heap: &mut Heap, deserializer: D) -> Result<RootedValue, D::Error>
+    where D: Deserializer<'de>
+{
+    ValueSeed::new(heap).deserialize(deserializer)
+}
+
+/// A `DeserializeSeed` that allocates the values it deserializes in a heap. Use
+/// this to deserialize values nested within the host's own data structures.
+pub struct ValueSeed<'a> {
+    heap: &'a mut Heap,
+}
+
+impl<'a> ValueSeed<'a> {
+    /// Create a seed that allocates in the given heap.
+    pub fn new(heap: &'a mut Heap) -> ValueSeed<'a> {
+        ValueSeed { heap: heap }
+    }
+}
+
+impl<'a, 'de> DeserializeSeed<'de> for ValueSeed<'a> {
+    type Value = RootedValue;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<RootedValue, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'a, 'de> Visitor<'de> for ValueSeed<'a> {
+    type Value = RootedValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a Scheme datum")
+    }
+
+    fn visit_bool<E: de::Error>(self, b: bool) -> Result<RootedValue, E> {
+        Ok(Rooted::new(self.heap, Value::new_boolean(b)))
+    }
+
+    fn visit_i64<E: de::Error>(self, i: i64) -> Result<RootedValue, E> {
+        let i = try!(Value::new_checked_integer(i).map_err(E::custom));
+        Ok(Rooted::new(self.heap, i))
+    }
+
+    fn visit_u64<E: de::Error>(self, u: u64) -> Result<RootedValue, E> {
+        if u > i64::max_value() as u64 {
+            return Err(E::invalid_value(Unexpected::Unsigned(u), &self));
+        }
+        self.visit_i64(u as i64)
+    }
+
+    fn visit_f64<E: de::Error>(self, f: f64) -> Result<RootedValue, E> {
+        Ok(Value::new_flonum(self.heap, f))
+    }
+
+    fn visit_char<E: de::Error>(self, c: char) -> Result<RootedValue, E> {
+        Ok(Rooted::new(self.heap, Value::new_character(c)))
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<RootedValue, E> {
+        Ok(Value::new_string(self.heap, s.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, s: String) -> Result<RootedValue, E> {
+        Ok(Value::new_string(self.heap, s))
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<RootedValue, E> {
+        Ok(Value::new_bytevector(self.heap, bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<RootedValue, E> {
+        Ok(Value::new_bytevector(self.heap, bytes))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<RootedValue, E> {
+        Ok(Rooted::new(self.heap, Value::EmptyList))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<RootedValue, E> {
+        self.visit_unit()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<RootedValue, D::Error> {
+        self.deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<RootedValue, A::Error> {
+        let heap = self.heap;
+        let mut elements = vec!();
+        while let Some(element) = try!(seq.next_element_seed(ValueSeed::new(&mut *heap))) {
+            elements.push(element);
+        }
+        Ok(list(heap, &elements))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<RootedValue, A::Error> {
+        let heap = self.heap;
+        let mut entries = vec!();
+        while let Some(key) = try!(map.next_key_seed(ValueSeed::new(&mut *heap))) {
+            let key = match *key {
+                Value::String(s) => heap.get_or_create_symbol((*s).clone()),
+                _                => key,
+            };
+            let val = try!(map.next_value_seed(ValueSeed::new(&mut *heap)));
+            entries.push(Value::new_pair(heap, &key, &val));
+        }
+        Ok(list(heap, &entries))
+    }
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::*;
+    use heap::{Heap};
+    use value::{RootedValue};
+
+    fn round_trip(heap: &mut Heap, json: &str) -> (RootedValue, String) {
+        let mut deserializer = serde_json::Deserializer::from_str(json);
+        let val = deserialize(heap, &mut deserializer)
+            .ok()
+            .expect("Should be able to deserialize the JSON.");
+        let json = serde_json::to_string(&val)
+            .ok()
+            .expect("Should be able to serialize the value.");
+        (val, json)
+    }
+
+    #[test]
+    fn test_serialization_round_trip() {
+        let heap = &mut Heap::new();
+
+        let (val, json) = round_trip(heap, r#"[1, true, "two", [3]]"#);
+        assert_eq!(format!("{}", *val), "(1 #t \"two\" (3))");
+        assert_eq!(json, r#"[1,true,"two",[3]]"#);
+
+        let (val, json) = round_trip(heap, "[1.5, -2.0]");
+        assert_eq!(format!("{}", *val), "(1.5 -2.0)");
+        assert_eq!(json, "[1.5,-2.0]");
+
+        let (val, json) = round_trip(heap, r#"{"name": "oxischeme", "tags": [1, 2]}"#);
+        assert_eq!(format!("{}", *val), "((name . \"oxischeme\") (tags 1 2))");
+        assert_eq!(json, r#"{"name":"oxischeme","tags":[1,2]}"#);
+    }
+
+    #[test]
+    fn test_serialization_errors() {
+        let heap = &mut Heap::new();
+
+        let val = heap.eval_str("(cons 1 2)")
+            .ok()
+            .expect("Should be able to make a pair.");
+        assert!(serde_json::to_string(&val).is_err());
+
+        let val = heap.eval_str("(define l (list 1 2)) (set-cdr! (cdr l) l) l")
+            .ok()
+            .expect("Should be able to make a cyclic list.");
+        assert!(serde_json::to_string(&val).is_err());
+
+        let val = heap.eval_str("car")
+            .ok()
+            .expect("Should be able to get a primitive.");
+        assert!(serde_json::to_string(&val).is_err());
+
+        // Integers that don't fit in a fixnum can't be deserialized.
+        let mut deserializer = serde_json::Deserializer::from_str("1152921504606846976");
+        assert!(deserialize(heap, &mut deserializer).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Syntactic analysis for editor tooling.
+//!
+//! `analyze_for_tools` analyzes a form exactly as `eval::analyze` does, and
+//! also records where each variable the form mentions is bound, and every
+//! place it is referenced or assigned. This is enough for an editor plugin to
+//! go to a variable's definition, or to rename it.
+//!
+//! Source locations are those the analyzer already tracks: a reference is
+//! located at the innermost form containing it, and a binding at the `lambda`
+//! or `define` form that binds it. Two references in the same form share a
+//! location, so look bindings up by both location and name.
+
+use std::collections::{HashMap};
+
+use errors::{SchemeError};
+use eval;
+use heap::{DefinitionOrigin, Heap};
+use read::{Location};
+use value::{RootedValue};
+
+/// A place where a variable is referenced or assigned.
+#[derive(Clone, Debug)]
+pub struct VariableReference {
+    /// The coordinates the reference was resolved to: the variable is the j'th
+    /// binding of the i'th activation up from the one the reference is
+    /// evaluated in.
+    pub coordinates: (u32, u32),
+    /// The location of the innermost form containing the reference.
+    pub location: Location,
+    /// True if the variable is assigned with `set!` or redefined with `define`
+    /// here, false if its value is only read.
+    pub is_assignment: bool,
+}
+
+/// A variable bound by a `lambda`'s parameters, a `define`, or the global
+/// activation, with every reference to it.
+#[derive(Clone, Debug)]
+pub struct VariableBinding {
+    /// The variable's name.
+    pub name: String,
+    /// The number of lexical blocks the variable's block is nested in, which
+    /// is `0` for globals.
+    pub depth: u32,
+    /// The variable's index in the activations of its block.
+    pub index: u32,
+    /// The `lambda` form binding a parameter, or the first `define` form
+    /// binding a variable. A global that was not defined by Scheme source,
+    /// such as a primitive, has no location.
+    pub location: Option<Location>,
+    /// Every reference to the variable, in the order they were analyzed.
+    pub references: Vec<VariableReference>,
+}
+
+impl VariableBinding {
+    /// Return true if this binding's site or one of its references is at the
+    /// given location, false otherwise.
+    fn is_at(&self, location: &Location) -> bool {
+        let same = |other: &Location| {
+            other.file == location.file
+                && other.line == location.line
+                && other.column == location.column
+        };
+        self.location.as_ref().map_or(false, |l| same(l))
+            || self.references.iter().any(|r| same(&r.location))
+    }
+}
+
+/// The bindings and references recorded while analyzing a form.
+#[derive(Clone, Debug)]
+pub struct ToolAnalysis {
+    /// Every variable the form binds or refers to, in the order they were
+    /// first seen.
+    pub bindings: Vec<VariableBinding>,
+}
+
+impl ToolAnalysis {
+    /// Find the binding of the variable with the given name that is bound or
+    /// referenced at the given location, as for going to its definition or
+    /// renaming it.
+    pub fn binding_at(&self, location: &Location, name: &str) -> Option<&VariableBinding> {
+        self.bindings.iter().find(|b| b.name == name && b.is_at(location))
+    }
+}
+
+/// Records bindings and references as forms are analyzed. While one is set on
+/// the heap with `Heap::replace_tool_recorder`, the analyzer reports to it.
+///
+/// Every lexical block is given an id of its own, because the coordinates of a
+/// variable only say how many blocks up from the reference it is bound, and
+/// sibling blocks at the same depth bind different variables.
+pub struct ToolRecorder {
+    /// The ids of the blocks enclosing the form being analyzed, from the
+    /// global top level, whose id is `0`, to the youngest.
+    blocks: Vec<usize>,
+    next_block: usize,
+    /// The index into `bindings` of each variable, by its block id and index.
+    indices: HashMap<(usize, u32), usize>,
+    bindings: Vec<VariableBinding>,
+}
+
+impl ToolRecorder {
+    /// Create a new `ToolRecorder` at the global top level.
+    pub fn new() -> ToolRecorder {
+        ToolRecorder {
+            blocks: vec!(0),
+            next_block: 1,
+            indices: HashMap::new(),
+            bindings: vec!(),
+        }
+    }
+
+    /// Note that analysis entered a new lexical block.
+    pub fn enter_block(&mut self) {
+        self.blocks.push(self.next_block);
+        self.next_block += 1;
+    }
+
+    /// Note that analysis left the youngest lexical block.
+    pub fn exit_block(&mut self) {
+        assert!(self.blocks.len() > 1, "Should never exit the global top level");
+        self.blocks.pop();
+    }
+
+    /// Record that the variable at the given coordinates is bound at the given
+    /// location. If it was already bound, this rebinds it, and is recorded as
+    /// an assignment.
+    pub fn bind(&mut self, name: &str, i: u32, j: u32, location: Location) {
+        let binding = self.binding(name, i, j);
+        if binding.location.is_none() {
+            binding.location = Some(location);
+        } else {
+            binding.references.push(VariableReference {
+                coordinates: (i, j),
+                location: location,
+                is_assignment: true,
+            });
+        }
+    }
+
+    /// Record a reference to the variable at the given coordinates.
+    pub fn refer(&mut self,
+                 name: &str,
+                 i: u32,
+                 j: u32,
+                 location: Location,
+                 is_assignment: bool) {
+        self.binding(name, i, j).references.push(VariableReference {
+            coordinates: (i, j),
+            location: location,
+            is_assignment: is_assignment,
+        });
+    }
+
+    /// Get the recorded results.
+    pub fn finish(self) -> ToolAnalysis {
+        ToolAnalysis {
+            bindings: self.bindings,
+        }
+    }
+
+    /// Get the binding of the variable at the given coordinates, creating it if
+    /// this is the first time it is seen.
+    fn binding(&mut self, name: &str, i: u32, j: u32) -> &mut VariableBinding {
+        let depth = self.blocks.len() as u32 - 1 - i;
+        let key = (self.blocks[depth as usize], j);
+        let index = match self.indices.get(&key) {
+            Some(index) => *index,
+            None        => {
+                self.bindings.push(VariableBinding {
+                    name: name.to_string(),
+                    depth: depth,
+                    index: j,
+                    location: None,
+                    references: vec!(),
+                });
+                self.bindings.len() - 1
+            },
+        };
+        self.indices.insert(key, index);
+        &mut self.bindings[index]
+    }
+}
+
+/// Analyze the given form as `eval::analyze` would, including defining the
+/// globals it defines, and return its bindings and references rather than its
+/// meaning. Globals the form does not define are located where they were
+/// defined by any source the heap has already evaluated.
+pub fn analyze_for_tools(heap: &mut Heap,
+                         form: &RootedValue,
+                         location: Location) -> Result<ToolAnalysis, SchemeError> {
+    let outer = heap.replace_tool_recorder(Some(ToolRecorder::new()));
+    let result = eval::analyze(heap, form, location);
+    let recorder = heap.replace_tool_recorder(outer)
+        .expect("The recorder set above should still be set");
+    try!(result);
+
+    let mut analysis = recorder.finish();
+    for binding in analysis.bindings.iter_mut() {
+        if binding.depth == 0 && binding.location.is_none() {
+            if let Some(DefinitionOrigin::Source(loc)) = heap.where_defined(&binding.name) {
+                binding.location = Some(loc);
+            }
+        }
+    }
+    Ok(analysis)
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heap::{Heap};
+    use read::{read_from_str};
+
+    #[test]
+    fn test_tools_analyze_for_tools() {
+        let heap = &mut Heap::new();
+        let source = "(define frob\n\
+                      \x20 (lambda (x)\n\
+                      \x20   (define y x)\n\
+                      \x20   (set! y (+ y x))\n\
+                      \x20   (lambda (x) (+ x y))))";
+        let (location, form) = read_from_str(source, heap, "frob.scm").next()
+            .expect("Should read a form");
+        let form = form.ok().expect("Should read the form without errors");
+        let analysis = analyze_for_tools(heap, &form, location)
+            .ok().expect("Should analyze the form");
+
+        let frob = analysis.bindings.iter().find(|b| b.name == "frob")
+            .expect("Should record the global `frob`");
+        assert_eq!(frob.depth, 0);
+        assert_eq!(format!("{}", frob.location.as_ref().unwrap()), "frob.scm:1:1");
+
+        let params : Vec<&VariableBinding> = analysis.bindings.iter()
+            .filter(|b| b.name == "x")
+            .collect();
+        assert_eq!(params.len(), 2);
+        assert_eq!(format!("{}", params[0].location.as_ref().unwrap()), "frob.scm:2:3");
+        assert_eq!(params[0].references.len(), 2);
+        assert_eq!(format!("{}", params[1].location.as_ref().unwrap()), "frob.scm:5:5");
+        assert_eq!(params[1].references.len(), 1);
+        assert_eq!(params[1].references[0].coordinates, (0, 0));
+
+        let y = analysis.bindings.iter().find(|b| b.name == "y")
+            .expect("Should record the local `y`");
+        assert_eq!((y.depth, y.index), (1, 1));
+        assert_eq!(format!("{}", y.location.as_ref().unwrap()), "frob.scm:3:5");
+        let assignments : Vec<String> = y.references.iter()
+            .filter(|r| r.is_assignment)
+            .map(|r| format!("{}", r.location))
+            .collect();
+        assert_eq!(assignments, vec!("frob.scm:4:5".to_string()));
+        assert_eq!(y.references.last().unwrap().coordinates, (1, 1));
+
+        let plus = analysis.bindings.iter().find(|b| b.name == "+")
+            .expect("Should record the global `+`");
+        assert_eq!(plus.depth, 0);
+        assert!(plus.location.is_none(), "Primitives are not defined by source");
+
+        let site = y.references[0].location.clone();
+        let found = analysis.binding_at(&site, "y").expect("Should find `y` by location");
+        assert_eq!(found.index, 1);
+    }
+}
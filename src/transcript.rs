@@ -0,0 +1,188 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording and replaying REPL transcripts.
+//!
+//! A transcript is itself a file of Scheme data, so that it can be read back in
+//! with the normal reader. The first form records the seed of the heap's
+//! pseudo-random number generator:
+//!
+//!     (transcript-seed 1423094311)
+//!
+//! Every following form is an entry recording the time (in seconds since the
+//! epoch) at which a form was entered at the REPL, and the form itself:
+//!
+//!     (transcript-entry 1423094315 (define x (+ 1 2)))
+//!
+//! When replaying a transcript, the seed is restored and the clock is stubbed
+//! to return each entry's recorded time while that entry is evaluated. This
+//! makes replaying deterministic, which is handy for attaching to bug reports
+//! and for generating teaching materials.
+
+use std::old_io::{File, IoResult};
+
+use eval;
+use heap::{Heap};
+use read::{read_from_file};
+use value::{RootedValue, Written};
+
+/// Records forms entered at the REPL to a transcript file.
+pub struct TranscriptWriter {
+    file: File,
+}
+
+impl TranscriptWriter {
+    /// Create a new transcript file at the given path, recording the heap's
+    /// current random seed as its header.
+    pub fn create(path_name: &str, heap: &Heap) -> IoResult<TranscriptWriter> {
+        let path = Path::new(path_name);
+        let mut file = try!(File::create(&path));
+        try!(write!(&mut file, "(transcript-seed {})\n", heap.random_seed()));
+        Ok(TranscriptWriter {
+            file: file,
+        })
+    }
+
+    /// Record that the given form was entered at the given time. The form is
+    /// written with escapes, so that replaying reads back the same datum.
+    pub fn record(&mut self, time: i64, form: &RootedValue) -> IoResult<()> {
+        try!(write!(&mut self.file, "(transcript-entry {} {})\n", time, Written(**form)));
+        self.file.flush()
+    }
+}
+
+/// Get the integer following the given tag in a `(tag integer ...)` form.
+fn tagged_integer(heap: &mut Heap,
+                  form: &RootedValue,
+                  tag: &str) -> Result<i64, String> {
+    if let Some(pair) = form.to_pair(heap) {
+        if let Some(name) = pair.car(heap).to_symbol(heap) {
+            if name.as_slice() == tag {
+                let n = try!(pair.cadr(heap));
+                if let Some(i) = n.to_integer() {
+                    return Ok(i);
+                }
+            }
+        }
+    }
+
+    Err(format!("Error: expected a `{}` form in transcript, found {}",
+                tag,
+                **form))
+}
+
+/// Replay the transcript at the given path, printing each form and its value
+/// as if it had been entered at the REPL.
+pub fn replay(heap: &mut Heap, path_name: &str) -> Result<(), String> {
+    let mut reader = match read_from_file(path_name, heap) {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(format!("Error: could not read transcript {}: {}",
+                               path_name,
+                               e));
+        },
+    };
+
+    let seed = match reader.next() {
+        Some((_, read_result)) => {
+            let header = try!(read_result);
+            try!(tagged_integer(heap, &header, "transcript-seed"))
+        },
+        None => return Err(format!("Error: empty transcript: {}", path_name)),
+    };
+    heap.set_random_seed(seed as u64);
+
+    for (location, read_result) in reader {
+        let entry = try!(read_result);
+        let time = try!(tagged_integer(heap, &entry, "transcript-entry"));
+        let pair = entry.to_pair(heap)
+            .expect("tagged_integer already checked that entry is a pair");
+        let form = try!(pair.caddr(heap));
+
+        println!("oxischeme> {}", *form);
+
+        heap.stub_clock(Some(time));
+        match eval::evaluate(heap, &form, location) {
+            Ok(val) => println!("{}", *val),
+            Err(e)  => println!("{}", e),
+        };
+        heap.stub_clock(None);
+
+        heap.collect_garbage();
+    }
+
+    Ok(())
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heap::{Heap};
+
+    #[test]
+    fn test_transcript_replay() {
+        let heap = &mut Heap::new();
+        replay(heap, "./tests/test_transcript_replay.scm")
+            .ok()
+            .expect("Should be able to replay a transcript.");
+        assert_eq!(heap.random_seed(), 42);
+    }
+
+    #[test]
+    fn test_transcript_record_then_replay() {
+        use std::env;
+        use read::{read_from_str};
+        use value::{Value};
+
+        let path = env::temp_dir().join("oxischeme_test_transcript.scm");
+        let path_name = path.display().to_string();
+        let source = "(define s \"say \\\"hello there\\\"\\\\now\")\n\
+                      (define c #\\space)\n\
+                      (define sym '|odd symbol|)";
+
+        {
+            let heap = &mut Heap::new();
+            let mut writer = TranscriptWriter::create(path_name.as_slice(), heap)
+                .ok()
+                .expect("Should be able to create a transcript.");
+            let reader = read_from_str(source, heap, "<test>");
+            for (_, read_result) in reader {
+                let form = read_result.ok().expect("Should be able to read the source.");
+                writer.record(0, &form)
+                    .ok()
+                    .expect("Should be able to record a form.");
+            }
+        }
+
+        let heap = &mut Heap::new();
+        replay(heap, path_name.as_slice())
+            .ok()
+            .expect("Should be able to replay the recorded transcript.");
+
+        let s = heap.eval_str("s").ok().expect("Should have defined `s`.");
+        match *s {
+            Value::String(str) => assert_eq!(str.as_slice(), "say \"hello there\"\\now"),
+            _                  => panic!("`s` should be a string, found {}", *s),
+        }
+
+        let c = heap.eval_str("c").ok().expect("Should have defined `c`.");
+        assert_eq!(*c, Value::new_character(' '));
+
+        let sym = heap.eval_str("sym").ok().expect("Should have defined `sym`.");
+        let expected = heap.get_or_create_symbol("odd symbol".to_string());
+        assert_eq!(sym, expected);
+    }
+}
@@ -14,16 +14,21 @@
 
 //! Scheme value implementation.
 
-use std::collections::{HashSet};
+use std::char;
+use std::collections::{HashMap, HashSet};
 use std::default::{Default};
 use std::fmt;
 use std::hash;
+use std::rc::{Rc};
 
 use environment::{ActivationPtr, RootedActivationPtr};
-use eval::{Meaning, TrampolineResult};
-use heap::{ArenaPtr, GcThing, Heap, IterGcThing, Rooted, RootedStringPtr,
-           StringPtr, ToGcThing, Trace};
-use primitives::{PrimitiveFunction};
+use errors::{E0207, E0208, E0314, SchemeError};
+use eval::{Meaning, Signature, TrampolineResult};
+use heap::{ArenaPtr, BytevectorPtr, FlonumPtr, ForeignHandle, GcThing, HandleScope, Heap,
+           IterGcThing, NativePtr, Rooted, RootedBytevectorPtr, RootedNativePtr,
+           RootedStringPtr, StringPtr, ToGcThing, Trace};
+use primitives::{PrimitiveFunction, PrimitiveSignature};
+use read::{is_bare_identifier, Location};
 
 /// A cons cell is a pair of `car` and `cdr` values. A list is one or more cons
 /// cells, daisy chained together via the `cdr`. A list is "proper" if the last
@@ -31,8 +36,8 @@ use primitives::{PrimitiveFunction};
 /// "improper".
 #[derive(Copy, Eq, Hash, PartialEq)]
 pub struct Cons {
-    car: Value,
-    cdr: Value,
+    car: Word,
+    cdr: Word,
 }
 
 impl Default for Cons {
@@ -40,8 +45,8 @@ impl Default for Cons {
     /// `Heap::allocate_cons` and get back a `ConsPtr`.
     fn default() -> Cons {
         Cons {
-            car: Value::EmptyList,
-            cdr: Value::EmptyList,
+            car: Word::new(Value::EmptyList),
+            cdr: Word::new(Value::EmptyList),
         }
     }
 }
@@ -49,22 +54,35 @@ impl Default for Cons {
 impl Cons {
     /// Get the car of this cons cell.
     pub fn car(&self, heap: &mut Heap) -> RootedValue {
-        Rooted::new(heap, self.car)
+        Rooted::new(heap, self.car.unpack())
     }
 
     /// Get the cdr of this cons cell.
     pub fn cdr(&self, heap: &mut Heap) -> RootedValue {
-        Rooted::new(heap, self.cdr)
+        Rooted::new(heap, self.cdr.unpack())
+    }
+
+    /// Get the car of this cons cell without rooting it. Only use this when
+    /// nothing can trigger a GC while the result is in use, such as when
+    /// comparing values.
+    pub fn unrooted_car(&self) -> Value {
+        self.car.unpack()
+    }
+
+    /// Get the cdr of this cons cell without rooting it. See
+    /// `Cons::unrooted_car`.
+    pub fn unrooted_cdr(&self) -> Value {
+        self.cdr.unpack()
     }
 
     /// Set the car of this cons cell.
     pub fn set_car(&mut self, car: &RootedValue) {
-        self.car = **car;
+        self.car = Word::new(**car);
     }
 
     /// Set the cdr of this cons cell.
     pub fn set_cdr(&mut self, cdr: &RootedValue) {
-        self.cdr = **cdr;
+        self.cdr = Word::new(**cdr);
     }
 }
 
@@ -72,11 +90,11 @@ impl Trace for Cons {
     fn trace(&self) -> IterGcThing {
         let mut results = vec!();
 
-        if let Some(car) = self.car.to_gc_thing() {
+        if let Some(car) = self.car.unpack().to_gc_thing() {
             results.push(car);
         }
 
-        if let Some(cdr) = self.cdr.to_gc_thing() {
+        if let Some(cdr) = self.cdr.unpack().to_gc_thing() {
             results.push(cdr);
         }
 
@@ -96,12 +114,90 @@ impl ToGcThing for ConsPtr {
 /// A rooted pointer to a cons cell on the heap.
 pub type RootedConsPtr = Rooted<ConsPtr>;
 
+/// A vector is a fixed length sequence of values, indexed from zero. Like cons
+/// cells, vectors store their elements packed into `Word`s.
+pub struct Vector {
+    elements: Vec<Word>,
+}
+
+impl Default for Vector {
+    /// Do not use this method, instead allocate vectors on the heap with
+    /// `Heap::allocate_vector` and get back a `VectorPtr`.
+    fn default() -> Vector {
+        Vector {
+            elements: vec!(),
+        }
+    }
+}
+
+impl Vector {
+    /// Get the number of elements in this vector.
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    /// Get the element at the given index, or `None` if the index is out of
+    /// bounds.
+    pub fn get(&self, heap: &mut Heap, index: usize) -> Option<RootedValue> {
+        self.unrooted_get(index).map(|val| Rooted::new(heap, val))
+    }
+
+    /// Get the element at the given index without rooting it. See
+    /// `Cons::unrooted_car`.
+    pub fn unrooted_get(&self, index: usize) -> Option<Value> {
+        self.elements.get(index).map(|w| w.unpack())
+    }
+
+    /// Set the element at the given index. Return `Err` if the index is out of
+    /// bounds.
+    pub fn set(&mut self, index: usize, val: &RootedValue) -> Result<(), ()> {
+        if index >= self.elements.len() {
+            return Err(());
+        }
+        self.elements[index] = Word::new(**val);
+        Ok(())
+    }
+
+    /// Replace every element of this vector with the given values.
+    pub fn set_elements(&mut self, vals: &[RootedValue]) {
+        self.elements.clear();
+        self.elements.extend(vals.iter().map(|v| Word::new(**v)));
+    }
+}
+
+impl Trace for Vector {
+    fn trace(&self) -> IterGcThing {
+        let results : Vec<GcThing> = self.elements.iter()
+            .filter_map(|w| w.unpack().to_gc_thing())
+            .collect();
+        results.into_iter()
+    }
+}
+
+/// A pointer to a vector on the heap.
+pub type VectorPtr = ArenaPtr<Vector>;
+
+impl ToGcThing for VectorPtr {
+    fn to_gc_thing(&self) -> Option<GcThing> {
+        Some(GcThing::from_vector_ptr(*self))
+    }
+}
+
+/// A rooted pointer to a vector on the heap.
+pub type RootedVectorPtr = Rooted<VectorPtr>;
+
 /// User defined procedures are represented by their body and a pointer to the
-/// activation that they were defined within.
+/// activation that they were defined within. Procedures also remember the name
+/// they were defined with (if any) and the location of their definition, for
+/// better error messages. Procedures with optional or keyword parameters also
+/// have a signature, and their arity counts all of their parameters.
 pub struct Procedure {
     pub arity: u32,
     pub body: Option<Box<Meaning>>,
     pub act: Option<ActivationPtr>,
+    pub name: Option<String>,
+    pub location: Location,
+    pub signature: Option<Rc<Signature>>,
 }
 
 impl Default for Procedure {
@@ -110,6 +206,9 @@ impl Default for Procedure {
             body: None,
             act: None,
             arity: 0,
+            name: None,
+            location: Location::unknown(),
+            signature: None,
         }
     }
 }
@@ -129,6 +228,7 @@ impl Trace for Procedure {
 impl hash::Hash for Procedure {
     fn hash<H: hash::Hasher>(&self, state: &mut H) {
         self.arity.hash(state);
+        self.signature.hash(state);
         self.act.hash(state);
         self.body.as_ref()
             .expect("Should never hash an uninitialized Procedure")
@@ -149,42 +249,160 @@ impl ToGcThing for ProcedurePtr {
 pub type RootedProcedurePtr = Rooted<ProcedurePtr>;
 
 /// A primitive procedure, such as Scheme's `+` or `cons`.
+///
+/// A primitive is a compact handle into a heap's `PrimitiveTable`, so that it
+/// fits in a `Word`. Like an `ArenaPtr`, a primitive is only meaningful to the
+/// heap it came from, and must not outlive it.
+#[derive(Copy, Clone, Eq, Hash, PartialEq)]
+pub struct Primitive(*const PrimitiveEntry);
+
+/// An entry in a table of primitives.
 #[derive(Copy)]
-pub struct Primitive {
+struct PrimitiveEntry {
     /// The function implementing the primitive.
     function: PrimitiveFunction,
     /// The name of the primitive.
     name: &'static str,
+    /// If this primitive is a stub standing in for a primitive that is not
+    /// available, the name of the missing feature.
+    missing_feature: Option<&'static str>,
+    /// If this primitive is implemented by a closure registered with
+    /// `Heap::register_primitive`, the closure's index in the heap's table of
+    /// closure primitives.
+    closure: Option<usize>,
+    /// Whether this is one of the primitives built into oxischeme, rather than
+    /// one defined by an embedder. The analyzer only trusts what it knows about
+    /// primitives by name, such as their arity, for builtins.
+    builtin: bool,
+    /// The arguments this primitive accepts, if it declared them when it was
+    /// registered. See `PrimitiveSignature`.
+    signature: Option<PrimitiveSignature>,
 }
 
-impl PartialEq for Primitive {
-    fn eq(&self, rhs: &Self) -> bool {
-        self.function as usize == rhs.function as usize
-    }
+/// The side table of a heap's primitives. Each entry is boxed, so that the
+/// `Primitive` handles pointing at it stay valid as the table grows, and they
+/// are all freed with the heap.
+pub struct PrimitiveTable {
+    entries: Vec<Box<PrimitiveEntry>>,
 }
 
-impl Eq for Primitive { }
+impl PrimitiveTable {
+    /// Create a new, empty `PrimitiveTable`.
+    pub fn new() -> PrimitiveTable {
+        PrimitiveTable {
+            entries: vec!(),
+        }
+    }
 
-impl hash::Hash for Primitive {
-    fn hash<H: hash::Hasher>(&self, state: &mut H) {
-        let u = self.function as usize;
-        u.hash(state);
+    /// Get the handle for the given primitive function, adding it to the table
+    /// if this is the first time it has been seen.
+    fn register(&mut self, entry: PrimitiveEntry) -> Primitive {
+        let existing = self.entries.iter().find(|e| {
+            e.function as usize == entry.function as usize
+                && e.name == entry.name
+                && e.missing_feature == entry.missing_feature
+                && e.closure == entry.closure
+                && e.builtin == entry.builtin
+                && e.signature == entry.signature
+        }).map(|e| Primitive(&**e as *const PrimitiveEntry));
+        if let Some(primitive) = existing {
+            return primitive;
+        }
+        let entry = Box::new(entry);
+        let primitive = Primitive(&*entry as *const PrimitiveEntry);
+        self.entries.push(entry);
+        primitive
+    }
+
+    /// Get the number of primitives in this table.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Find the primitive with the given name, preferring builtins. Closure
+    /// primitives cannot be saved in a heap image, so they are never found.
+    pub fn lookup(&self, name: &str) -> Option<Primitive> {
+        self.entries.iter()
+            .find(|e| e.name == name && e.closure.is_none() && e.builtin)
+            .or_else(|| self.entries.iter().find(|e| e.name == name && e.closure.is_none()))
+            .map(|e| Primitive(&**e as *const PrimitiveEntry))
+    }
+
+    /// Get the primitive that `lookup` finds for each name in this table.
+    pub fn by_name(&self) -> HashMap<&'static str, Primitive> {
+        let mut primitives = HashMap::new();
+        for entry in self.entries.iter() {
+            if !primitives.contains_key(entry.name) {
+                if let Some(primitive) = self.lookup(entry.name) {
+                    primitives.insert(entry.name, primitive);
+                }
+            }
+        }
+        primitives
     }
 }
 
 impl Primitive {
+    /// Get this primitive's entry in its heap's table.
+    fn entry(&self) -> PrimitiveEntry {
+        unsafe { *self.0 }
+    }
+
+    /// Get the name of this primitive.
+    pub fn name(&self) -> &'static str {
+        self.entry().name
+    }
+
+    /// Return true if this is one of the primitives built into oxischeme, false
+    /// if it was defined by an embedder.
+    pub fn is_builtin(&self) -> bool {
+        self.entry().builtin
+    }
+
+    /// Get the arguments this primitive declared that it accepts, if it did.
+    pub fn signature(&self) -> Option<PrimitiveSignature> {
+        self.entry().signature
+    }
+
+    /// If this primitive is a stub for a primitive that is not available, get
+    /// the name of the missing feature.
+    pub fn missing_feature(&self) -> Option<&'static str> {
+        self.entry().missing_feature
+    }
+
+    /// Call this primitive with the given arguments, after checking them
+    /// against its signature, if it has one.
     #[inline]
     pub fn call(&self, heap: &mut Heap, args: Vec<RootedValue>) -> TrampolineResult {
-        (self.function)(heap, args)
+        let entry = self.entry();
+        if let Some(feature) = entry.missing_feature {
+            return Err(E0207.error(format!("Error: feature-not-available: `{}` requires the `{}` \
+                                            feature, which is not available",
+                                           entry.name,
+                                           feature)));
+        }
+        if let Some(signature) = entry.signature {
+            try!(signature.check(entry.name, args.as_slice()));
+        }
+        if let Some(index) = entry.closure {
+            return heap.call_closure_primitive(index, args);
+        }
+        (entry.function)(heap, args)
     }
 }
 
 impl fmt::Debug for Primitive {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.name)
+        write!(f, "{}", self.name())
     }
 }
 
+/// The function behind every stub and closure primitive. Both are checked for
+/// in `Primitive::call`, so this is never actually called.
+fn unavailable_primitive(_: &mut Heap, _: Vec<RootedValue>) -> TrampolineResult {
+    unreachable!()
+}
+
 /// `Value` represents a scheme value of any type.
 ///
 /// Note that `Eq` and `PartialEq` are object identity, not structural
@@ -204,9 +422,16 @@ pub enum Value {
     /// `String`.
     Symbol(StringPtr),
 
-    /// Scheme integers are represented as 64 bit integers.
+    /// Scheme integers are fixnums: 64 bit integers that are always between
+    /// `MIN_FIXNUM` and `MAX_FIXNUM`, so that they fit in a `Word`. That is
+    /// 61 bits, not the full 64: the reader rejects literals outside that
+    /// range, and integers from outside Scheme must be checked with
+    /// `Value::new_checked_integer`.
     Integer(i64),
 
+    /// Scheme inexact reals are flonums: a pointer to a GC-managed `f64`.
+    Flonum(FlonumPtr),
+
     /// Scheme booleans are represented with `bool`.
     Boolean(bool),
 
@@ -220,15 +445,45 @@ pub enum Value {
     /// A primitive Scheme procedure is just a pointer to a `Primitive` type
     /// function pointer.
     Primitive(Primitive),
+
+    /// A handle to a foreign object owned by the heap. See
+    /// `Heap::new_foreign`.
+    Foreign(ForeignHandle),
+
+    /// The scheme vector type is a pointer to a GC-managed `Vector`.
+    Vector(VectorPtr),
+
+    /// The scheme bytevector type is a pointer to a GC-managed `Vec<u8>`.
+    Bytevector(BytevectorPtr),
+
+    /// An opaque, GC-managed native object. See `Heap::new_native`.
+    Native(NativePtr),
 }
 
 /// # `Value` Constructors
 impl Value {
-    /// Create a new integer value.
+    /// Create a new integer value. The integer must be a fixnum, between
+    /// `MIN_FIXNUM` and `MAX_FIXNUM`. Integers that come from outside Scheme,
+    /// and so might not be fixnums, should use `Value::new_checked_integer`
+    /// instead.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the integer is not a fixnum.
     pub fn new_integer(i: i64) -> Value {
+        assert!(is_fixnum(i), "Integer values must be fixnums, found {}", i);
         Value::Integer(i)
     }
 
+    /// Create a new integer value, or return an overflow error if the integer
+    /// is not a fixnum.
+    pub fn new_checked_integer(i: i64) -> Result<Value, SchemeError> {
+        if !is_fixnum(i) {
+            return Err(E0314.error(format!("Error: {} does not fit in a fixnum", i)));
+        }
+        Ok(Value::Integer(i))
+    }
+
     /// Create a new boolean value.
     pub fn new_boolean(b: bool) -> Value {
         Value::Boolean(b)
@@ -249,24 +504,88 @@ impl Value {
         Rooted::new(heap, Value::Pair(*cons))
     }
 
-    /// Create a new procedure with the given parameter list and body.
+    /// Create a new procedure with the given parameter list and body, defined
+    /// with the given name at the given location.
     pub fn new_procedure(heap: &mut Heap,
                          arity: u32,
                          act: &RootedActivationPtr,
-                         body: Meaning) -> RootedValue {
+                         body: Meaning,
+                         name: Option<String>,
+                         location: Location,
+                         signature: Option<Rc<Signature>>) -> RootedValue {
         let mut procedure = heap.allocate_procedure();
         procedure.arity = arity;
         procedure.act = Some(**act);
         procedure.body = Some(Box::new(body));
+        let mut closure_act = **act;
+        closure_act.capture();
+        procedure.name = name;
+        procedure.location = location;
+        procedure.signature = signature;
         Rooted::new(heap, Value::Procedure(*procedure))
     }
 
-    pub fn new_primitive(name: &'static str,
+    /// Create a primitive with the given name, implemented by the given
+    /// function, in the given table.
+    pub fn new_primitive(table: &mut PrimitiveTable,
+                         name: &'static str,
                          function: PrimitiveFunction) -> Value {
-        Value::Primitive(Primitive {
+        Value::Primitive(table.register(PrimitiveEntry {
             name: name,
-            function: function
-        })
+            function: function,
+            missing_feature: None,
+            closure: None,
+            builtin: false,
+            signature: None,
+        }))
+    }
+
+    /// Create one of the primitives built into oxischeme. See
+    /// `Primitive::is_builtin`. If a signature is given, arguments are checked
+    /// against it before the function is called.
+    pub fn new_builtin_primitive(table: &mut PrimitiveTable,
+                                 name: &'static str,
+                                 function: PrimitiveFunction,
+                                 signature: Option<PrimitiveSignature>) -> Value {
+        Value::Primitive(table.register(PrimitiveEntry {
+            name: name,
+            function: function,
+            missing_feature: None,
+            closure: None,
+            builtin: true,
+            signature: signature,
+        }))
+    }
+
+    /// Create a stub primitive standing in for the primitive with the given
+    /// name, which is not available because the given feature is missing.
+    /// Calling the stub is an error naming the missing feature.
+    pub fn new_unavailable_primitive(table: &mut PrimitiveTable,
+                                     name: &'static str,
+                                     feature: &'static str) -> Value {
+        Value::Primitive(table.register(PrimitiveEntry {
+            name: name,
+            function: unavailable_primitive,
+            missing_feature: Some(feature),
+            closure: None,
+            builtin: false,
+            signature: None,
+        }))
+    }
+
+    /// Create a primitive implemented by the closure with the given index in
+    /// the heap's table of closure primitives. See `Heap::register_primitive`.
+    pub fn new_closure_primitive(table: &mut PrimitiveTable,
+                                 name: &'static str,
+                                 index: usize) -> Value {
+        Value::Primitive(table.register(PrimitiveEntry {
+            name: name,
+            function: unavailable_primitive,
+            missing_feature: None,
+            closure: Some(index),
+            builtin: false,
+            signature: None,
+        }))
     }
 
     /// Create a new string value with the given string.
@@ -281,6 +600,27 @@ impl Value {
     pub fn new_symbol(heap: &mut Heap, str: RootedStringPtr) -> RootedValue {
         Rooted::new(heap, Value::Symbol(*str))
     }
+
+    /// Create a new vector value with the given elements.
+    pub fn new_vector(heap: &mut Heap, elements: &[RootedValue]) -> RootedValue {
+        let mut vector = heap.allocate_vector();
+        vector.set_elements(elements);
+        Rooted::new(heap, Value::Vector(*vector))
+    }
+
+    /// Create a new bytevector value with the given bytes.
+    pub fn new_bytevector(heap: &mut Heap, bytes: Vec<u8>) -> RootedValue {
+        let mut value = heap.allocate_bytevector();
+        **value = bytes;
+        Rooted::new(heap, Value::Bytevector(*value))
+    }
+
+    /// Create a new flonum value with the given float.
+    pub fn new_flonum(heap: &mut Heap, f: f64) -> RootedValue {
+        let mut value = heap.allocate_flonum();
+        *value = f;
+        Rooted::new(heap, Value::Flonum(*value))
+    }
 }
 
 /// # `Value` Methods
@@ -333,6 +673,41 @@ impl Value {
         }
     }
 
+    /// Coerce this vector value to a `VectorPtr` to the `Vector` this value is
+    /// referring to.
+    pub fn to_vector(&self, heap: &mut Heap) -> Option<RootedVectorPtr> {
+        match *self {
+            Value::Vector(v) => Some(Rooted::new(heap, v)),
+            _                => None,
+        }
+    }
+
+    /// Coerce this bytevector value to a `BytevectorPtr` to the bytes this
+    /// value is referring to.
+    pub fn to_bytevector(&self, heap: &mut Heap) -> Option<RootedBytevectorPtr> {
+        match *self {
+            Value::Bytevector(b) => Some(Rooted::new(heap, b)),
+            _                    => None,
+        }
+    }
+
+    /// Coerce this native value to a `NativePtr` to the native object this
+    /// value is referring to.
+    pub fn to_native(&self, heap: &mut Heap) -> Option<RootedNativePtr> {
+        match *self {
+            Value::Native(n) => Some(Rooted::new(heap, n)),
+            _                => None,
+        }
+    }
+
+    /// Coerce this foreign value to its `ForeignHandle`.
+    pub fn to_foreign(&self) -> Option<ForeignHandle> {
+        match *self {
+            Value::Foreign(h) => Some(h),
+            _                 => None,
+        }
+    }
+
     /// Coerce this procedure value to a `ProcedurePtr` to the `Procedure` this
     /// value is referring to.
     pub fn to_procedure(&self, heap: &mut Heap) -> Option<RootedProcedurePtr> {
@@ -350,12 +725,28 @@ impl Value {
         }
     }
 
+    /// Coerce this flonum value to its underlying `f64`.
+    pub fn to_flonum(&self) -> Option<f64> {
+        match *self {
+            Value::Flonum(f) => Some(*f),
+            _                => None,
+        }
+    }
+
+    /// Return true if this value is a number of any kind, false otherwise.
+    pub fn is_number(&self) -> bool {
+        match *self {
+            Value::Integer(_) | Value::Flonum(_) => true,
+            _                                    => false,
+        }
+    }
+
     /// Assuming that this value is a proper list, get the length of the list.
     pub fn len(&self) -> Result<u64, ()> {
         match *self {
             Value::EmptyList => Ok(0),
             Value::Pair(p)   => {
-                let cdr_len = try!(p.cdr.len());
+                let cdr_len = try!(p.cdr.unpack().len());
                 Ok(cdr_len + 1)
             },
             _                => Err(()),
@@ -377,26 +768,133 @@ impl ToGcThing for Value {
             Value::Symbol(sym)  => Some(GcThing::from_string_ptr(sym)),
             Value::Pair(cons)   => Some(GcThing::from_cons_ptr(cons)),
             Value::Procedure(p) => Some(GcThing::from_procedure_ptr(p)),
+            Value::Vector(v)    => Some(GcThing::from_vector_ptr(v)),
+            Value::Bytevector(b) => Some(GcThing::from_bytevector_ptr(b)),
+            Value::Flonum(f)    => Some(GcThing::from_flonum_ptr(f)),
+            Value::Native(n)    => Some(GcThing::from_native_ptr(n)),
             _                   => None,
         }
     }
 }
 
-fn print(f: &mut fmt::Formatter, val: &Value, seen: &mut HashSet<ConsPtr>) -> fmt::Result {
+/// The datum labels used when printing a value.
+struct Labels {
+    /// The pairs and vectors that need a label, because they are part of a
+    /// cycle. See `find_cycles`.
+    cyclic: HashSet<Value>,
+    /// The labels of the values in `cyclic` that have been printed so far.
+    printed: HashMap<Value, usize>,
+    /// Whether strings are printed with escapes, as `write` does.
+    escape_strings: bool,
+}
+
+/// Find every pair and vector that is the target of a back edge in a depth
+/// first traversal of the given value. Every cycle contains at least one of
+/// them, so labeling them is enough for printing to terminate, without
+/// labeling structure that is merely shared.
+fn find_cycles(val: Value,
+               visited: &mut HashSet<Value>,
+               path: &mut HashSet<Value>,
+               cyclic: &mut HashSet<Value>) {
+    match val {
+        Value::Pair(_) | Value::Vector(_) => { },
+        _                                 => return,
+    }
+
+    if path.contains(&val) {
+        cyclic.insert(val);
+        return;
+    }
+    if !visited.insert(val) {
+        return;
+    }
+
+    path.insert(val);
+    match val {
+        Value::Pair(cons)  => {
+            find_cycles(cons.car.unpack(), visited, path, cyclic);
+            find_cycles(cons.cdr.unpack(), visited, path, cyclic);
+        },
+        Value::Vector(vector) => {
+            for i in range(0, vector.len()) {
+                find_cycles(vector.unrooted_get(i).unwrap(), visited, path, cyclic);
+            }
+        },
+        _                  => unreachable!(),
+    }
+    path.remove(&val);
+}
+
+/// If the given value needs a datum label, print it: `#n=` the first time the
+/// value is printed, and `#n#` in place of the value after that. Return true if
+/// the value itself should not be printed, false otherwise.
+fn print_label(f: &mut fmt::Formatter,
+               val: Value,
+               labels: &mut Labels) -> Result<bool, fmt::Error> {
+    if !labels.cyclic.contains(&val) {
+        return Ok(false);
+    }
+
+    if let Some(n) = labels.printed.get(&val) {
+        try!(write!(f, "#{}#", n));
+        return Ok(true);
+    }
+
+    let n = labels.printed.len();
+    labels.printed.insert(val, n);
+    try!(write!(f, "#{}=", n));
+    Ok(false)
+}
+
+fn print(f: &mut fmt::Formatter, val: &Value, labels: &mut Labels) -> fmt::Result {
+    if try!(print_label(f, *val, labels)) {
+        return Ok(());
+    }
+
     match *val {
         Value::EmptyList        => write!(f, "()"),
         Value::Pair(ref cons)   => {
             try!(write!(f, "("));
-            try!(print_pair(f, cons, seen));
+            try!(print_pair(f, cons, labels));
             write!(f, ")")
         },
+        Value::String(ref str) if labels.escape_strings => {
+            try!(write!(f, "\""));
+            for c in str.chars() {
+                try!(match c {
+                    '"'  => write!(f, "\\\""),
+                    '\\' => write!(f, "\\\\"),
+                    '\n' => write!(f, "\\n"),
+                    '\t' => write!(f, "\\t"),
+                    c    => write!(f, "{}", c),
+                });
+            }
+            write!(f, "\"")
+        },
         Value::String(ref str)  => {
             try!(write!(f, "\""));
             try!(write!(f, "{}", **str));
             write!(f, "\"")
         },
-        Value::Symbol(ref s)    => write!(f, "{}", **s),
+        Value::Symbol(ref s)    => {
+            if is_bare_identifier(s.as_slice()) {
+                return write!(f, "{}", **s);
+            }
+
+            try!(write!(f, "|"));
+            for c in s.chars() {
+                try!(match c {
+                    '|'  => write!(f, "\\|"),
+                    '\\' => write!(f, "\\\\"),
+                    '\n' => write!(f, "\\n"),
+                    '\t' => write!(f, "\\t"),
+                    c    => write!(f, "{}", c),
+                });
+            }
+            write!(f, "|")
+        },
         Value::Integer(ref i)   => write!(f, "{}", i),
+        Value::Flonum(ref x)    => write!(f, "{}", flonum_to_string(**x)),
         Value::Boolean(ref b)   => {
             write!(f, "{}", if *b {
                 "#t"
@@ -412,49 +910,279 @@ fn print(f: &mut fmt::Formatter, val: &Value, seen: &mut HashSet<ConsPtr>) -> fm
         },
         Value::Procedure(ref p) => write!(f, "#<procedure {:?}>", p),
         Value::Primitive(ref p) => write!(f, "#<procedure {:?}>", p),
+        Value::Foreign(ref h)   => write!(f, "#<foreign {}>", h.index()),
+        Value::Native(ref n)    => write!(f, "#<native {}>", n.tag()),
+        Value::Vector(ref v)    => {
+            try!(write!(f, "#("));
+            for i in range(0, v.len()) {
+                if i > 0 {
+                    try!(write!(f, " "));
+                }
+                try!(print(f, &v.unrooted_get(i).unwrap(), labels));
+            }
+            write!(f, ")")
+        },
+        Value::Bytevector(ref b) => {
+            try!(write!(f, "#u8("));
+            for (i, byte) in b.iter().enumerate() {
+                try!(write!(f, "{}{}", if i == 0 { "" } else { " " }, byte));
+            }
+            write!(f, ")")
+        },
     }
 }
 
-/// Print the given cons pair, without the containing "(" and ")".
-fn print_pair(f: &mut fmt::Formatter, cons: &ConsPtr, seen: &mut HashSet<ConsPtr>) -> fmt::Result {
-    if seen.contains(cons) {
-        return write!(f, "<cyclic value>");
+/// Get the text representation of the given flonum. It always has a decimal
+/// point or an exponent, so that it is read back in as a flonum rather than a
+/// fixnum, and infinities and NaN are written as in R7RS.
+pub fn flonum_to_string(x: f64) -> String {
+    if x.is_nan() {
+        return "+nan.0".to_string();
+    }
+    if x.is_infinite() {
+        return if x > 0.0 { "+inf.0" } else { "-inf.0" }.to_string();
     }
-    seen.insert(*cons);
-
-    try!(print(f, &cons.car, seen));
 
-    if let Value::Pair(rest) = cons.cdr {
-        if seen.contains(&rest) {
-            return write!(f, " . <cyclic value>");
-        }
+    let s = format!("{}", x);
+    if s.contains(".") || s.contains("e") {
+        s
+    } else {
+        s + ".0"
     }
+}
+
+/// Print the given cons pair, without the containing "(" and ")".
+fn print_pair(f: &mut fmt::Formatter, cons: &ConsPtr, labels: &mut Labels) -> fmt::Result {
+    try!(print(f, &cons.car.unpack(), labels));
 
-    match cons.cdr {
+    match cons.cdr.unpack() {
         Value::EmptyList => Ok(()),
-        Value::Pair(ref cdr) => {
+        // A labeled cdr has to be printed in dotted notation, so that there is
+        // somewhere to put its label.
+        Value::Pair(ref cdr) if !labels.cyclic.contains(&Value::Pair(*cdr)) => {
             try!(write!(f, " "));
-            print_pair(f, cdr, seen)
+            print_pair(f, cdr, labels)
         },
         ref val => {
             try!(write!(f, " . "));
-            print(f, val, seen)
+            print(f, val, labels)
         },
     }
 }
 
+/// Print the given value, labeling any cycles in it.
+fn print_labeled(f: &mut fmt::Formatter, val: &Value, escape_strings: bool) -> fmt::Result {
+    let mut cyclic = HashSet::new();
+    find_cycles(*val, &mut HashSet::new(), &mut HashSet::new(), &mut cyclic);
+    print(f, val, &mut Labels {
+        cyclic: cyclic,
+        printed: HashMap::new(),
+        escape_strings: escape_strings,
+    })
+}
+
 impl fmt::Display for Value {
     /// Print the given value's text representation to the given writer. This is
-    /// the opposite of `Read`.
+    /// the opposite of `Read`. Cyclic values are printed with datum labels,
+    /// e.g. `#0=(1 . #0#)`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        print_labeled(f, self, false)
+    }
+}
+
+/// Prints a value the way `write` does. Unlike `Value`'s `Display`, which
+/// prints the contents of strings as they are, quotes, backslashes, newlines,
+/// and tabs within strings are escaped, so that the text reads back in as the
+/// same datum.
+#[derive(Copy, Clone)]
+pub struct Written(pub Value);
+
+impl fmt::Display for Written {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        print(f, self, &mut HashSet::new())
+        print_labeled(f, &self.0, true)
+    }
+}
+
+/// The smallest integer that fits in a `Word`.
+pub const MIN_FIXNUM: i64 = -(1 << 60);
+
+/// The largest integer that fits in a `Word`.
+pub const MAX_FIXNUM: i64 = (1 << 60) - 1;
+
+/// Return true if the given integer is a fixnum, false otherwise.
+#[inline]
+pub fn is_fixnum(i: i64) -> bool {
+    i >= MIN_FIXNUM && i <= MAX_FIXNUM
+}
+
+/// Wrap the given integer around into the fixnum range, using two's
+/// complement.
+#[inline]
+pub fn wrap_fixnum(i: i64) -> i64 {
+    (i << TAG_BITS) >> TAG_BITS
+}
+
+const TAG_BITS: usize = 3;
+const TAG_MASK: u64 = (1 << TAG_BITS) - 1;
+
+const TAG_FIXNUM: u64 = 0;
+const TAG_PAIR: u64 = 1;
+const TAG_STRING: u64 = 2;
+const TAG_SYMBOL: u64 = 3;
+const TAG_PROCEDURE: u64 = 4;
+const TAG_VECTOR: u64 = 5;
+const TAG_BYTEVECTOR: u64 = 6;
+const TAG_IMMEDIATE: u64 = 7;
+
+/// Immediates other than fixnums are told apart by the five bits above the
+/// tag, and keep their payload above those.
+const IMMEDIATE_BITS: usize = 8;
+const IMMEDIATE_MASK: u64 = (1 << IMMEDIATE_BITS) - 1;
+
+const EMPTY_LIST: u64 = (0 << TAG_BITS) | TAG_IMMEDIATE;
+const BOOLEAN: u64 = (1 << TAG_BITS) | TAG_IMMEDIATE;
+const CHARACTER: u64 = (2 << TAG_BITS) | TAG_IMMEDIATE;
+const UNDEFINED: u64 = (3 << TAG_BITS) | TAG_IMMEDIATE;
+const PRIMITIVE: u64 = (4 << TAG_BITS) | TAG_IMMEDIATE;
+const FOREIGN: u64 = (5 << TAG_BITS) | TAG_IMMEDIATE;
+const NATIVE: u64 = (6 << TAG_BITS) | TAG_IMMEDIATE;
+const FLONUM: u64 = (7 << TAG_BITS) | TAG_IMMEDIATE;
+
+/// A `Value` packed into a single tagged machine word. This is how cons cells
+/// and activations store their values, so that they are as small and cheap to
+/// copy as possible. Use `Value` everywhere else, it is much more convenient
+/// to match on.
+///
+/// The low three bits of a word are its tag. Fixnums have a tag of zero, and
+/// keep their value in the upper 61 bits. `ArenaPtr`s are always aligned to at
+/// least eight bytes, so pointers to pairs, strings, symbols, procedures,
+/// vectors, and bytevectors are stored as-is with their tag in the low bits.
+/// Every other value, including the handles of primitives and foreign objects,
+/// is an immediate: immediates share the last tag, are told apart by the next
+/// five bits, and keep their payload above those.
+///
+/// We ran out of pointer tags before native objects and flonums came along, so
+/// pointers to them are immediates too. The pointer is shifted up past the
+/// immediate's bits, which loses nothing because heap addresses fit in well
+/// under 59 bits on every 64-bit platform.
+///
+/// A word may also be undefined, which activations use for variables that are
+/// referenced before they are defined.
+#[derive(Copy, Clone, Eq, Hash, PartialEq)]
+pub struct Word(u64);
+
+impl Word {
+    /// Pack the given value into a word.
+    #[inline]
+    pub fn new(val: Value) -> Word {
+        Word(match val {
+            Value::Integer(i)   => {
+                // Packing shifts out the top bits, so a value that isn't a
+                // fixnum would silently become a different integer.
+                assert!(is_fixnum(i), "Integer values must be fixnums, found {}", i);
+                ((i << TAG_BITS) as u64) | TAG_FIXNUM
+            },
+            Value::Pair(p)      => Word::pointer(p.to_raw(), TAG_PAIR),
+            Value::String(s)    => Word::pointer(s.to_raw(), TAG_STRING),
+            Value::Symbol(s)    => Word::pointer(s.to_raw(), TAG_SYMBOL),
+            Value::Procedure(p) => Word::pointer(p.to_raw(), TAG_PROCEDURE),
+            Value::Vector(v)    => Word::pointer(v.to_raw(), TAG_VECTOR),
+            Value::Bytevector(b) => Word::pointer(b.to_raw(), TAG_BYTEVECTOR),
+            Value::Primitive(p) => Word::immediate_pointer(p.0 as usize, PRIMITIVE),
+            Value::Foreign(h)   => ((h.index() as u64) << IMMEDIATE_BITS) | FOREIGN,
+            Value::Native(n)    => Word::immediate_pointer(n.to_raw(), NATIVE),
+            Value::Flonum(x)    => Word::immediate_pointer(x.to_raw(), FLONUM),
+            Value::EmptyList    => EMPTY_LIST,
+            Value::Boolean(b)   => ((b as u64) << IMMEDIATE_BITS) | BOOLEAN,
+            Value::Character(c) => ((c as u32 as u64) << IMMEDIATE_BITS) | CHARACTER,
+        })
+    }
+
+    /// Get the word for a variable that has not been defined yet.
+    #[inline]
+    pub fn undefined() -> Word {
+        Word(UNDEFINED)
+    }
+
+    #[inline]
+    fn pointer(raw: usize, tag: u64) -> u64 {
+        let raw = raw as u64;
+        debug_assert!(raw & TAG_MASK == 0, "ArenaPtrs should be aligned to eight bytes");
+        raw | tag
+    }
+
+    #[inline]
+    fn immediate_pointer(raw: usize, kind: u64) -> u64 {
+        let raw = raw as u64;
+        debug_assert!(raw & TAG_MASK == 0, "ArenaPtrs should be aligned to eight bytes");
+        debug_assert!(raw >> (64 - (IMMEDIATE_BITS - TAG_BITS)) == 0,
+                      "Heap addresses should fit in an immediate's payload");
+        (raw << (IMMEDIATE_BITS - TAG_BITS)) | kind
+    }
+
+    /// Unpack this word into a `Value`, or `None` if it is undefined.
+    #[inline]
+    pub fn get(&self) -> Option<Value> {
+        let payload = (self.0 & !TAG_MASK) as usize;
+        Some(match self.0 & TAG_MASK {
+            TAG_FIXNUM    => Value::Integer((self.0 as i64) >> TAG_BITS),
+            TAG_PAIR      => Value::Pair(unsafe { ArenaPtr::from_raw(payload) }),
+            TAG_STRING    => Value::String(unsafe { ArenaPtr::from_raw(payload) }),
+            TAG_SYMBOL    => Value::Symbol(unsafe { ArenaPtr::from_raw(payload) }),
+            TAG_PROCEDURE => Value::Procedure(unsafe { ArenaPtr::from_raw(payload) }),
+            TAG_VECTOR    => Value::Vector(unsafe { ArenaPtr::from_raw(payload) }),
+            TAG_BYTEVECTOR => Value::Bytevector(unsafe { ArenaPtr::from_raw(payload) }),
+            _ => {
+                let payload = self.0 >> IMMEDIATE_BITS;
+                match self.0 & IMMEDIATE_MASK {
+                    EMPTY_LIST => Value::EmptyList,
+                    BOOLEAN    => Value::Boolean(payload != 0),
+                    CHARACTER  => Value::Character(
+                        char::from_u32(payload as u32)
+                            .expect("Character words should hold valid chars")),
+                    PRIMITIVE  => {
+                        let raw = (self.0 & !IMMEDIATE_MASK) >> (IMMEDIATE_BITS - TAG_BITS);
+                        Value::Primitive(Primitive(raw as *const PrimitiveEntry))
+                    },
+                    FOREIGN    => Value::Foreign(ForeignHandle::from_index(payload as usize)),
+                    NATIVE     => {
+                        let raw = (self.0 & !IMMEDIATE_MASK) >> (IMMEDIATE_BITS - TAG_BITS);
+                        Value::Native(unsafe { ArenaPtr::from_raw(raw as usize) })
+                    },
+                    FLONUM     => {
+                        let raw = (self.0 & !IMMEDIATE_MASK) >> (IMMEDIATE_BITS - TAG_BITS);
+                        Value::Flonum(unsafe { ArenaPtr::from_raw(raw as usize) })
+                    },
+                    _          => return None,
+                }
+            },
+        })
+    }
+
+    /// Unpack this word into a `Value`.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if this word is undefined.
+    #[inline]
+    pub fn unpack(&self) -> Value {
+        self.get().expect("Should never unpack an undefined Word")
+    }
+}
+
+impl fmt::Debug for Word {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get() {
+            Some(val) => write!(f, "Word({:?})", val),
+            None      => write!(f, "Word(undefined)"),
+        }
     }
 }
 
 pub type RootedValue = Rooted<Value>;
 
-/// Either a Scheme `RootedValue`, or a `String` containing an error message.
-pub type SchemeResult = Result<RootedValue, String>;
+/// Either a Scheme `RootedValue`, or a `SchemeError` describing what went wrong.
+pub type SchemeResult = Result<RootedValue, SchemeError>;
 
 /// An iterator which yields `Ok` for each value in a cons-list and finishes
 /// with `None` when the end of the list is reached (the scheme empty list
@@ -475,35 +1203,106 @@ impl Iterator for ConsIterator {
         match self.val {
             Value::EmptyList => None,
             Value::Pair(cons) => {
-                let Cons { car, cdr } = *cons;
-                self.val = cdr;
-                Some(Ok(car))
+                self.val = cons.cdr.unpack();
+                Some(Ok(cons.car.unpack()))
             },
             _ => Some(Err(())),
         }
     }
 }
 
-/// A helper utility to create a cons list from the given values.
-pub fn list(heap: &mut Heap, values: &[RootedValue]) -> RootedValue {
-    list_helper(heap, &mut values.iter())
+/// Iterate over the given Scheme list, yielding a rooted value for each element.
+///
+/// The resulting `ValueStream` yields `Err` and then stops if the list turns out
+/// to be improper, or cyclic. Prefer this to walking lists with `car` and `cdr`
+/// by hand, so that bad lists are always reported the same way.
+pub fn iter_list(heap: &mut Heap, list: &RootedValue) -> ValueStream {
+    ValueStream {
+        list: list.clone(),
+        current: Rooted::new(heap, **list),
+        slow: **list,
+        steps: 0,
+        done: false,
+    }
 }
 
-fn list_helper<'a, T: Iterator<Item=&'a RootedValue>>(heap: &mut Heap,
-                                                      values: &mut T) -> RootedValue {
-    match values.next() {
-        None      => Rooted::new(heap, Value::EmptyList),
-        Some(car) => {
-            let rest = list_helper(heap, values);
-            Value::new_pair(heap, car, &rest)
-        },
+/// An iterator over the elements of a Scheme list, created with `iter_list`.
+/// Unlike `ConsIterator`, the elements are rooted, and cycles are detected
+/// rather than iterated over forever.
+pub struct ValueStream {
+    /// The whole list, kept for error messages.
+    list: RootedValue,
+    /// The rest of the list that has yet to be iterated over.
+    current: RootedValue,
+    /// A pointer that advances at half the speed of `current`, for detecting
+    /// cycles. It is always reachable from `list`, so it needn't be rooted.
+    slow: Value,
+    steps: usize,
+    done: bool,
+}
+
+impl Iterator for ValueStream {
+    type Item = Result<RootedValue, SchemeError>;
+
+    fn next(&mut self) -> Option<Result<RootedValue, SchemeError>> {
+        if self.done {
+            return None;
+        }
+
+        let (car, cdr) = match *self.current {
+            Value::EmptyList => {
+                self.done = true;
+                return None;
+            },
+            Value::Pair(cons) => (cons.car.unpack(), cons.cdr.unpack()),
+            _ => {
+                self.done = true;
+                return Some(Err(E0208.error(format!("Error: expected a proper list, found {}",
+                                                    *self.list))));
+            },
+        };
+
+        self.current.emplace(cdr);
+        self.steps += 1;
+        if self.steps % 2 == 0 {
+            if let Value::Pair(cons) = self.slow {
+                self.slow = cons.cdr.unpack();
+            }
+        }
+
+        if let (Value::Pair(a), Value::Pair(b)) = (*self.current, self.slow) {
+            if a == b {
+                self.done = true;
+                return Some(Err(E0208.error("Error: expected a proper list, found a cyclic \
+                                             list".to_string())));
+            }
+        }
+
+        let mut item = self.current.clone();
+        item.emplace(car);
+        Some(Ok(item))
     }
 }
 
+/// A helper utility to create a cons list from the given values.
+pub fn list(heap: &mut Heap, values: &[RootedValue]) -> RootedValue {
+    // Build the list back to front, keeping each partial list alive with a
+    // handle rather than a `Rooted` per cons cell.
+    let scope = HandleScope::new(heap);
+    let mut result = scope.handle(Value::EmptyList);
+    for val in values.iter().rev() {
+        let mut cons = heap.allocate_cons();
+        cons.set_car(val);
+        cons.cdr = Word::new(*result);
+        result = scope.handle(Value::Pair(*cons));
+    }
+    scope.escape(result)
+}
+
 /// ## The 28 car/cdr compositions.
 impl Cons {
     pub fn cddr(&self, heap: &mut Heap) -> SchemeResult {
-        self.cdr.cdr(heap).ok_or("bad cddr".to_string())
+        self.cdr.unpack().cdr(heap).ok_or("bad cddr".to_string())
     }
 
     pub fn cdddr(&self, heap: &mut Heap) -> SchemeResult {
@@ -514,7 +1313,7 @@ impl Cons {
     // TODO FITZGEN: cddddr
 
     pub fn cadr(&self, heap: &mut Heap) -> SchemeResult {
-        self.cdr.car(heap).ok_or("bad cadr".to_string())
+        self.cdr.unpack().car(heap).ok_or("bad cadr".to_string())
     }
 
     pub fn caddr(&self, heap: &mut Heap) -> SchemeResult {
@@ -532,8 +1331,105 @@ impl Cons {
 
 #[cfg(test)]
 mod tests {
-    use eval::{evaluate_file};
-    use heap::{Heap};
+    use super::*;
+    use std::mem;
+    use errors::{SchemeError};
+    use eval::{evaluate, evaluate_file};
+    use heap::{Heap, Rooted};
+    use read::{Location};
+
+    #[test]
+    fn test_iter_list() {
+        let heap = &mut Heap::new();
+        let vals : Vec<RootedValue> = (1..4)
+            .map(|i| Rooted::new(heap, Value::new_integer(i)))
+            .collect();
+        let l = list(heap, vals.as_slice());
+        let items : Vec<RootedValue> = iter_list(heap, &l)
+            .collect::<Result<Vec<RootedValue>, SchemeError>>()
+            .ok()
+            .expect("Should be able to iterate over a proper list.");
+        assert_eq!(items, vals);
+    }
+
+    #[test]
+    fn test_iter_list_improper() {
+        let heap = &mut Heap::new();
+        let one = Rooted::new(heap, Value::new_integer(1));
+        let two = Rooted::new(heap, Value::new_integer(2));
+        let pair = Value::new_pair(heap, &one, &two);
+        let results : Vec<Result<RootedValue, SchemeError>> = iter_list(heap, &pair).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().err().map(|e| e.to_string()),
+                   Some("[E0208] Error: expected a proper list, found (1 . 2)".to_string()));
+    }
+
+    #[test]
+    fn test_iter_list_cycle() {
+        let heap = &mut Heap::new();
+        let one = Rooted::new(heap, Value::new_integer(1));
+        let empty = Rooted::new(heap, Value::EmptyList);
+        let pair = Value::new_pair(heap, &one, &empty);
+        let mut cons = pair.to_pair(heap).expect("Should be a pair.");
+        cons.set_cdr(&pair);
+        let results : Vec<Result<RootedValue, SchemeError>> = iter_list(heap, &pair).collect();
+        assert!(results.last().expect("Should yield something.").is_err());
+    }
+
+    #[test]
+    fn test_word_round_trip() {
+        use std::mem;
+
+        let heap = &mut Heap::new();
+        let one = Rooted::new(heap, Value::new_integer(1));
+        let pair = Value::new_pair(heap, &one, &one);
+        let string = Value::new_string(heap, "hello".to_string());
+        let car = heap.get_or_create_symbol("car".to_string());
+        let car_primitive = evaluate(heap, &car, Location::unknown())
+            .ok()
+            .expect("Should be able to evaluate `car`");
+        let vector = Value::new_vector(heap, &[one.clone(), pair.clone()]);
+        let bytevector = Value::new_bytevector(heap, vec!(1, 2, 3));
+        let vals = vec!(Value::new_integer(0),
+                        Value::new_integer(-42),
+                        Value::new_integer(MIN_FIXNUM),
+                        Value::new_integer(MAX_FIXNUM),
+                        Value::new_boolean(true),
+                        Value::new_boolean(false),
+                        Value::new_character('λ'),
+                        Value::EmptyList,
+                        *pair,
+                        *string,
+                        *car,
+                        *car_primitive,
+                        *vector,
+                        *bytevector);
+        for val in vals.iter() {
+            assert_eq!(Word::new(*val).unpack(), *val);
+        }
+
+        assert_eq!(Word::undefined().get(), None);
+        assert_eq!(mem::size_of::<Word>(), 8);
+        assert_eq!(mem::size_of::<Cons>(), 16);
+    }
+
+    #[test]
+    fn test_new_checked_integer() {
+        assert_eq!(Value::new_checked_integer(MAX_FIXNUM), Ok(Value::new_integer(MAX_FIXNUM)));
+        assert_eq!(Value::new_checked_integer(MIN_FIXNUM), Ok(Value::new_integer(MIN_FIXNUM)));
+        let error = Value::new_checked_integer(MAX_FIXNUM + 1)
+            .err()
+            .expect("Should not fit in a fixnum.");
+        assert_eq!(error.code.map(|entry| entry.code), Some("E0314"));
+        assert!(Value::new_checked_integer(MIN_FIXNUM - 1).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_new_integer_not_fixnum() {
+        Value::new_integer(MAX_FIXNUM + 1);
+    }
 
     #[test]
     fn test_print_cycle() {
@@ -543,4 +1439,24 @@ mod tests {
             .expect("Should be able to eval a file.");
         assert!(true, "Shouldn't get stuck in an infinite loop printing a cyclic value");
     }
+
+    #[test]
+    fn test_print_datum_labels() {
+        let heap = &mut Heap::new();
+        let one = Rooted::new(heap, Value::new_integer(1));
+        let empty = Rooted::new(heap, Value::EmptyList);
+
+        let cycle = Value::new_pair(heap, &one, &empty);
+        cycle.to_pair(heap).unwrap().set_cdr(&cycle);
+        assert_eq!(format!("{}", *cycle), "#0=(1 . #0#)");
+
+        let vector = Value::new_vector(heap, &[one.clone(), cycle.clone()]);
+        vector.to_vector(heap).unwrap().set(0, &vector).unwrap();
+        assert_eq!(format!("{}", *vector), "#0=#(#0# #1=(1 . #1#))");
+
+        // Shared structure that isn't part of a cycle doesn't need labels.
+        let tail = Value::new_pair(heap, &one, &empty);
+        let shared = Value::new_pair(heap, &tail, &tail);
+        assert_eq!(format!("{}", *shared), "((1) 1)");
+    }
 }
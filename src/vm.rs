@@ -0,0 +1,945 @@
+// Copyright 2015 Nick Fitzgerald
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A second execution tier: a compiler from `Meaning` trees to a flat
+//! bytecode, and a virtual machine to run it.
+//!
+//! The meaning interpreter in `eval` calls a function pointer for every node of
+//! the meaning tree, and trampolines every call through a `Thunk`. The VM
+//! instead runs a dispatch loop over a flat vector of instructions, keeping
+//! intermediate values on an explicit operand stack and Scheme procedure calls
+//! on an explicit stack of frames, so that neither uses the Rust stack.
+//!
+//! For example, the meaning of `(if (< n 2) n (fib (- n 1)))`, in tail
+//! position, compiles to:
+//!
+//!     0: global < 12
+//!     1: local n (0, 0)
+//!     2: constant 2
+//!     3: call 2
+//!     4: jump-if-false 7
+//!     5: local n (0, 0)
+//!     6: return
+//!     7: global fib 13
+//!     ...
+//!     12: tail-call 1
+//!
+//! Syntactic analysis is the same for both tiers, and the meaning interpreter
+//! remains the reference implementation. Anything the VM does not handle
+//! itself, such as calls to primitives other than the fast paths, traced or
+//! profiled calls, and arity errors, is delegated to `apply_invocation`.
+//!
+//! Procedure bodies are compiled the first time they are called in bytecode
+//! mode, and the bytecode is cached on the heap, in a `CodeCache`. See
+//! `Heap::compiled_code`.
+
+use std::collections::{HashMap};
+use std::fmt;
+use std::mem;
+use std::rc::{Rc};
+
+use environment::{self, Activation, RootedActivationPtr, Scope, SymbolId};
+use errors::{E0204, E0205, E0209, E0210, E0211, SchemeError};
+use eval::{apply_invocation, make_closure, procedure_value_name, Frame, Meaning, MeaningData};
+use heap::{Heap, Rooted};
+use primitives::{FastPath};
+use read::{Location};
+use value::{RootedValue, SchemeResult, Value};
+
+/// How a heap evaluates the meanings produced by syntactic analysis.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExecutionMode {
+    /// Interpret the meaning trees directly.
+    Interpreter,
+    /// Compile meanings to bytecode and run it on the VM.
+    Bytecode,
+}
+
+/// A single bytecode instruction.
+#[derive(Copy, Clone, Debug)]
+enum Op {
+    /// Push the n'th constant.
+    Constant(u32),
+    /// Push the value of the j'th binding of the i'th activation.
+    Local(u32, u32, SymbolId),
+    /// Push the value of the j'th binding of the global activation.
+    Global(u32, SymbolId),
+    /// Pop a value, bind it to the j'th binding of the current activation, and
+    /// push the unspecified value.
+    Define(u32),
+    /// Pop a value, set the j'th binding of the i'th activation to it, and push
+    /// the unspecified value.
+    Set(u32, u32),
+    /// Discard the value on top of the stack.
+    Pop,
+    /// Continue at the given instruction.
+    Jump(u32),
+    /// Pop a value, and continue at the given instruction if it is false.
+    JumpIfFalse(u32),
+    /// Push a new procedure for the n'th lambda, closing over the current
+    /// activation.
+    Closure(u32),
+    /// Push the current activation, reified as a first-class environment whose
+    /// variables are named by the n'th scope.
+    TheEnvironment(u32),
+    /// Pop the given number of arguments and the procedure beneath them, call
+    /// the procedure, and push its result. The second operand is the index of
+    /// the call site.
+    Call(u32, u32),
+    /// Like `Call`, but in tail position: the result of the call is returned
+    /// from the current frame, and a callee run by the VM replaces it.
+    TailCall(u32, u32),
+    /// Pop a value and return it from the current frame.
+    Return,
+}
+
+/// Static information about a call site, needed to make the call.
+struct CallSite {
+    /// The name the procedure is invoked by, if it is a variable reference.
+    name: Option<SymbolId>,
+    /// The location of the invocation.
+    location: Location,
+    /// If the procedure was a primitive with a fast path when analyzed, the
+    /// fast path and the primitive it belongs to.
+    fast_path: Option<(FastPath, RootedValue)>,
+}
+
+/// The bytecode compiled from a meaning.
+pub struct Code {
+    /// The meaning this code was compiled from. Holding it keeps its
+    /// `Meaning::identity` from being reused while this code is cached.
+    source: Meaning,
+    ops: Vec<Op>,
+    /// The location of the meaning each instruction was compiled from.
+    locations: Vec<Location>,
+    constants: Vec<RootedValue>,
+    lambdas: Vec<Meaning>,
+    scopes: Vec<Rc<Scope>>,
+    sites: Vec<CallSite>,
+}
+
+impl Code {
+    /// Compile the given meaning, in tail position, so that the code returns
+    /// the meaning's value.
+    pub fn compile(meaning: &Meaning) -> Code {
+        let mut code = Code {
+            source: meaning.clone(),
+            ops: vec!(),
+            locations: vec!(),
+            constants: vec!(),
+            lambdas: vec!(),
+            scopes: vec!(),
+            sites: vec!(),
+        };
+        code.compile_meaning(meaning, true);
+        code
+    }
+
+    /// Get the meaning this code was compiled from.
+    pub fn source(&self) -> &Meaning {
+        &self.source
+    }
+
+    fn emit(&mut self, op: Op, location: &Location) -> usize {
+        self.ops.push(op);
+        self.locations.push(location.clone());
+        self.ops.len() - 1
+    }
+
+    /// Point the jump at the given index to the next instruction emitted.
+    fn patch(&mut self, at: usize) {
+        let target = self.ops.len() as u32;
+        self.ops[at] = match self.ops[at] {
+            Op::Jump(_)        => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            op                 => panic!("Cannot patch non-jump instruction {:?}", op),
+        };
+    }
+
+    fn compile_meaning(&mut self, meaning: &Meaning, tail: bool) {
+        let location = meaning.location();
+
+        match *meaning.data() {
+            MeaningData::Quotation(ref val) => {
+                self.constants.push(val.clone());
+                let n = (self.constants.len() - 1) as u32;
+                self.emit(Op::Constant(n), location);
+            },
+            MeaningData::Reference(i, j, name) => {
+                self.emit(Op::Local(i, j, name), location);
+            },
+            MeaningData::GlobalReference(_, j, name) => {
+                self.emit(Op::Global(j, name), location);
+            },
+            MeaningData::Definition(_, j, ref val) => {
+                self.compile_meaning(val, false);
+                self.emit(Op::Define(j), location);
+            },
+            MeaningData::SetVariable(i, j, ref val) => {
+                self.compile_meaning(val, false);
+                self.emit(Op::Set(i, j), location);
+            },
+            MeaningData::Lambda(..) | MeaningData::FlatLambda(..) => {
+                self.lambdas.push(meaning.clone());
+                let n = (self.lambdas.len() - 1) as u32;
+                self.emit(Op::Closure(n), location);
+            },
+            MeaningData::TheEnvironment(ref scope) => {
+                self.scopes.push(scope.clone());
+                let n = (self.scopes.len() - 1) as u32;
+                self.emit(Op::TheEnvironment(n), location);
+            },
+
+            // The remaining meanings take care of their own tail position.
+
+            MeaningData::Conditional(ref condition, ref consequent, ref alternative) => {
+                self.compile_meaning(condition, false);
+                let to_alternative = self.emit(Op::JumpIfFalse(0), location);
+                self.compile_meaning(consequent, tail);
+                if tail {
+                    self.patch(to_alternative);
+                    self.compile_meaning(alternative, true);
+                } else {
+                    let to_end = self.emit(Op::Jump(0), location);
+                    self.patch(to_alternative);
+                    self.compile_meaning(alternative, false);
+                    self.patch(to_end);
+                }
+                return;
+            },
+            MeaningData::Sequence(ref first, ref second) => {
+                self.compile_meaning(first, false);
+                self.emit(Op::Pop, location);
+                self.compile_meaning(second, tail);
+                return;
+            },
+            MeaningData::Invocation(..)  |
+            MeaningData::Invocation0(..) |
+            MeaningData::Invocation1(..) |
+            MeaningData::Invocation2(..) |
+            MeaningData::Invocation3(..) => {
+                let children = meaning.data().children();
+                self.compile_call(children[0], &children[1..], None, location, tail);
+                return;
+            },
+            MeaningData::Let(ref params, ref body, _) => {
+                // Apply a closure of the lambda, as the invocation did before it
+                // was reduced to a `Let`.
+                let lambda = Meaning::from_data(&meaning.unit(),
+                                                MeaningData::Lambda(params.len() as u32,
+                                                                    body.clone(),
+                                                                    None,
+                                                                    location.clone(),
+                                                                    None),
+                                                location.clone(),
+                                                false);
+                let params : Vec<&Meaning> = params.iter().collect();
+                self.compile_call(&lambda, params.as_slice(), None, location, tail);
+                return;
+            },
+            MeaningData::InlinedInvocation(ref procedure, ref params, _, _, _) => {
+                // Calling the procedure normally is equivalent to evaluating
+                // the inlined body, and saves compiling the body twice.
+                let params : Vec<&Meaning> = params.iter().collect();
+                self.compile_call(procedure, params.as_slice(), None, location, tail);
+                return;
+            },
+            MeaningData::PrimitiveInvocation(ref procedure,
+                                             ref primitive,
+                                             fast_path,
+                                             ref params,
+                                             _) => {
+                let params : Vec<&Meaning> = params.iter().collect();
+                self.compile_call(procedure,
+                                  params.as_slice(),
+                                  Some((fast_path, primitive.clone())),
+                                  location,
+                                  tail);
+                return;
+            },
+        }
+
+        if tail {
+            self.emit(Op::Return, location);
+        }
+    }
+
+    fn compile_call(&mut self,
+                    procedure: &Meaning,
+                    params: &[&Meaning],
+                    fast_path: Option<(FastPath, RootedValue)>,
+                    location: &Location,
+                    tail: bool) {
+        self.compile_meaning(procedure, false);
+        for param in params.iter() {
+            self.compile_meaning(*param, false);
+        }
+
+        let name = match *procedure.data() {
+            MeaningData::Reference(_, _, name)       |
+            MeaningData::GlobalReference(_, _, name) => Some(name),
+            _                                        => None,
+        };
+        self.sites.push(CallSite {
+            name: name,
+            location: location.clone(),
+            fast_path: fast_path,
+        });
+
+        let site = (self.sites.len() - 1) as u32;
+        let argc = params.len() as u32;
+        self.emit(if tail { Op::TailCall(argc, site) } else { Op::Call(argc, site) },
+                  location);
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, op) in self.ops.iter().enumerate() {
+            try!(write!(f, "{}: ", i));
+            try!(match *op {
+                Op::Constant(n)       => write!(f, "constant {}", *self.constants[n as usize]),
+                Op::Local(i, j, name) => write!(f, "local {} ({}, {})", name, i, j),
+                Op::Global(j, name)   => write!(f, "global {} {}", name, j),
+                Op::Define(j)         => write!(f, "define {}", j),
+                Op::Set(i, j)         => write!(f, "set ({}, {})", i, j),
+                Op::Pop               => write!(f, "pop"),
+                Op::Jump(t)           => write!(f, "jump {}", t),
+                Op::JumpIfFalse(t)    => write!(f, "jump-if-false {}", t),
+                Op::Closure(n)        => write!(f, "closure {}", n),
+                Op::TheEnvironment(_) => write!(f, "the-environment"),
+                Op::Call(argc, _)     => write!(f, "call {}", argc),
+                Op::TailCall(argc, _) => write!(f, "tail-call {}", argc),
+                Op::Return            => write!(f, "return"),
+            });
+            try!(write!(f, "\n"));
+        }
+        Ok(())
+    }
+}
+
+/// The bytecode compiled for procedure bodies, so that each body is compiled
+/// only once rather than every time it is called. See `Heap::compiled_code`.
+///
+/// Entries are keyed by the identity of the meaning they were compiled from.
+/// Each `Code` holds a handle to that meaning, so its identity cannot be reused
+/// by another meaning while the entry is cached. Once the cache is full, the
+/// least recently used entry is evicted to make room, which lets the meaning
+/// it was compiled from, and the values it quotes, be freed.
+pub struct CodeCache {
+    capacity: usize,
+    /// The code for each cached meaning, and when it was last used.
+    entries: HashMap<usize, (Rc<Code>, u64)>,
+    clock: u64,
+}
+
+impl CodeCache {
+    /// Create a new, empty cache that holds the code for at most `capacity`
+    /// procedure bodies. A capacity of `0` disables caching, so that bodies are
+    /// compiled every time they are called.
+    pub fn new(capacity: usize) -> CodeCache {
+        CodeCache {
+            capacity: capacity,
+            entries: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Get the maximum number of procedure bodies whose code is cached.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Get the number of procedure bodies whose code is currently cached.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get the code for the given procedure body, compiling it if it is not
+    /// cached.
+    pub fn get(&mut self, body: &Meaning) -> Rc<Code> {
+        self.clock += 1;
+        let key = body.identity();
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.1 = self.clock;
+            return entry.0.clone();
+        }
+
+        let code = Rc::new(Code::compile(body));
+        if self.capacity == 0 {
+            return code;
+        }
+        if self.entries.len() >= self.capacity {
+            let oldest = self.entries.iter()
+                .min_by(|&(_, &(_, used))| used)
+                .map(|(&key, _)| key);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (code.clone(), self.clock));
+        code
+    }
+}
+
+/// A Scheme procedure call being run by the VM.
+struct VmFrame {
+    code: Rc<Code>,
+    /// The index of the next instruction.
+    pc: usize,
+    act: RootedActivationPtr,
+    /// The height of the operand stack when this frame was entered.
+    base: usize,
+    /// The depth of the heap's call stack when this frame was entered.
+    depth: usize,
+}
+
+/// The result of executing one instruction: `Some` value if the outermost frame
+/// returned, otherwise `None`.
+type StepResult = Result<Option<RootedValue>, SchemeError>;
+
+/// A computation that ran out of fuel before it finished, and can be resumed
+/// with more fuel by `Heap::resume`. While suspended, its values stay rooted.
+pub struct Suspended {
+    frame: VmFrame,
+    frames: Vec<VmFrame>,
+    stack: Vec<RootedValue>,
+    /// The frames this computation had pushed on the heap's call stack, oldest
+    /// first.
+    call_stack: Vec<Frame>,
+    /// The depth of the heap's call stack when this computation started.
+    depth: usize,
+}
+
+/// The outcome of evaluating with a limited amount of fuel.
+pub enum Evaluation {
+    /// The evaluation finished with the given value.
+    Done(RootedValue),
+    /// The evaluation ran out of fuel.
+    Suspended(Suspended),
+}
+
+/// Evaluate the given meaning in the given activation on the VM.
+pub fn evaluate(heap: &mut Heap,
+                meaning: &Meaning,
+                act: &RootedActivationPtr) -> SchemeResult {
+    let depth = heap.stack_depth();
+    let frame = new_frame(meaning, act, depth);
+    let result = run(heap, frame, vec!(), vec!());
+    heap.unwind_to(depth);
+    match try!(result) {
+        Evaluation::Done(val) => Ok(val),
+        // Only the outermost evaluation can be suspended; nested evaluations
+        // running out of fuel are an error.
+        Evaluation::Suspended(_) => Err(E0210.error("Error: out of fuel".to_string())),
+    }
+}
+
+/// Evaluate the given meaning in the given activation on the VM, until it
+/// finishes or the heap runs out of fuel. See `Heap::evaluate_with_fuel`.
+pub fn evaluate_with_fuel(heap: &mut Heap,
+                          meaning: &Meaning,
+                          act: &RootedActivationPtr) -> Result<Evaluation, SchemeError> {
+    let depth = heap.stack_depth();
+    let frame = new_frame(meaning, act, depth);
+    let result = run(heap, frame, vec!(), vec!());
+    settle(heap, depth, result)
+}
+
+/// Call the given procedure with the given arguments on the VM, until the call
+/// returns or the heap runs out of fuel. Only procedures defined in Scheme can
+/// be suspended, so it is an error to call anything else. See the `generator`
+/// module.
+pub fn call_with_fuel(heap: &mut Heap,
+                      procedure: &RootedValue,
+                      args: Vec<RootedValue>) -> Result<Evaluation, SchemeError> {
+    let proc_ptr = match **procedure {
+        Value::Procedure(proc_ptr) if proc_ptr.arity as usize == args.len()
+                                      && proc_ptr.signature.is_none() => proc_ptr,
+        _ => {
+            return Err(SchemeError::new(format!("Error: cannot suspend a call to {} with {} \
+                                                 arguments",
+                                                **procedure, args.len())));
+        },
+    };
+
+    let proc_act = proc_ptr.act.as_ref()
+        .expect("Should never see an uninitialized procedure!");
+    let rooted_proc_act = Rooted::new(heap, *proc_act);
+    let body = proc_ptr.body.as_ref()
+        .expect("Should never see an uninitialized procedure!");
+
+    let depth = heap.stack_depth();
+    heap.push_frame(Frame {
+        name: procedure_value_name(procedure),
+        location: Location::unknown(),
+    });
+
+    let code = heap.compiled_code(&**body);
+    let act = Activation::extend(heap, &rooted_proc_act, args);
+    if heap.take_out_of_memory() {
+        heap.unwind_to(depth);
+        return Err(E0205.error("Error: out of memory".to_string()));
+    }
+
+    let frame = VmFrame {
+        code: code,
+        pc: 0,
+        act: act,
+        base: 0,
+        depth: depth,
+    };
+    let result = run(heap, frame, vec!(), vec!());
+    settle(heap, depth, result)
+}
+
+/// Continue a suspended computation until it finishes or the heap runs out of
+/// fuel again. See `Heap::resume`.
+pub fn resume(heap: &mut Heap, suspended: Suspended) -> Result<Evaluation, SchemeError> {
+    let Suspended { mut frame, mut frames, stack, call_stack, depth: old_depth } = suspended;
+
+    // The call stack may be at a different depth than when we were suspended,
+    // so move our frames' depths along with it.
+    let depth = heap.stack_depth();
+    frame.depth = frame.depth - old_depth + depth;
+    for f in frames.iter_mut() {
+        f.depth = f.depth - old_depth + depth;
+    }
+    for f in call_stack.into_iter() {
+        heap.push_frame(f);
+    }
+
+    let result = run(heap, frame, frames, stack);
+    settle(heap, depth, result)
+}
+
+/// Clean up the heap's call stack after running until finished or out of
+/// fuel.
+fn settle(heap: &mut Heap,
+          depth: usize,
+          result: Result<Evaluation, SchemeError>) -> Result<Evaluation, SchemeError> {
+    match result {
+        Ok(Evaluation::Suspended(mut suspended)) => {
+            suspended.call_stack = heap.split_off_frames(depth);
+            suspended.depth = depth;
+            Ok(Evaluation::Suspended(suspended))
+        },
+        otherwise => {
+            heap.unwind_to(depth);
+            otherwise
+        },
+    }
+}
+
+fn new_frame(meaning: &Meaning, act: &RootedActivationPtr, depth: usize) -> VmFrame {
+    VmFrame {
+        code: Rc::new(Code::compile(meaning)),
+        pc: 0,
+        act: act.clone(),
+        base: 0,
+        depth: depth,
+    }
+}
+
+/// Run the VM until the outermost frame returns. Every instruction costs one
+/// unit of the heap's fuel, and if it runs out, the computation is suspended
+/// between instructions.
+fn run(heap: &mut Heap,
+       mut frame: VmFrame,
+       mut frames: Vec<VmFrame>,
+       mut stack: Vec<RootedValue>) -> Result<Evaluation, SchemeError> {
+    let global_act = heap.global_activation();
+
+    loop {
+        if !heap.consume_fuel() {
+            return Ok(Evaluation::Suspended(Suspended {
+                frame: frame,
+                frames: frames,
+                stack: stack,
+                call_stack: vec!(),
+                depth: 0,
+            }));
+        }
+        if heap.take_interrupt() {
+            return Err(E0211.error("Error: interrupted".to_string()));
+        }
+
+        let op = frame.code.ops[frame.pc];
+        frame.pc += 1;
+
+        match execute(heap, &global_act, &mut stack, &mut frames, &mut frame, op) {
+            Ok(None)      => { },
+            Ok(Some(val)) => return Ok(Evaluation::Done(val)),
+            Err(e)        => {
+                // Failed instructions never leave their frame, so the
+                // instruction that failed is the one we just executed.
+                heap.note_error_location(&frame.code.locations[frame.pc - 1]);
+                return Err(e);
+            },
+        }
+    }
+}
+
+fn execute(heap: &mut Heap,
+           global_act: &RootedActivationPtr,
+           stack: &mut Vec<RootedValue>,
+           frames: &mut Vec<VmFrame>,
+           frame: &mut VmFrame,
+           op: Op) -> StepResult {
+    match op {
+        Op::Constant(n) => {
+            stack.push(frame.code.constants[n as usize].clone());
+        },
+        Op::Local(i, j, name) => {
+            let val = try!(frame.act.fetch(heap, i, j).ok().ok_or(
+                E0204.error(format!("Reference to variable that hasn't been defined: {}",
+                                    heap.environment.name(name)))));
+            stack.push(val);
+        },
+        Op::Global(j, name) => {
+            let val = try!(global_act.fetch(heap, 0, j).ok().ok_or(
+                E0204.error(format!("Reference to variable that hasn't been defined: {}",
+                                    heap.environment.name(name)))));
+            stack.push(val);
+        },
+        Op::Define(j) => {
+            let val = stack.pop().unwrap();
+            frame.act.define(j, *val);
+            stack.push(heap.unspecified_symbol());
+        },
+        Op::Set(i, j) => {
+            let val = stack.pop().unwrap();
+            try!(frame.act.update(i, j, &val).ok().ok_or(
+                E0209.error("Cannot set variable before it has been defined".to_string())));
+            stack.push(heap.unspecified_symbol());
+        },
+        Op::Pop => {
+            stack.pop();
+        },
+        Op::Jump(target) => {
+            frame.pc = target as usize;
+        },
+        Op::JumpIfFalse(target) => {
+            let val = stack.pop().unwrap();
+            if *val == Value::new_boolean(false) {
+                frame.pc = target as usize;
+            }
+        },
+        Op::Closure(n) => {
+            let procedure = try!(make_closure(heap,
+                                              frame.code.lambdas[n as usize].data(),
+                                              &frame.act));
+            if heap.take_out_of_memory() {
+                return Err(E0205.error("Error: out of memory".to_string()));
+            }
+            stack.push(procedure);
+        },
+        Op::TheEnvironment(n) => {
+            let env = try!(environment::reify(heap, &frame.act, &*frame.code.scopes[n as usize]));
+            stack.push(env);
+        },
+        Op::Call(argc, site) => {
+            return call(heap, stack, frames, frame, argc as usize, site as usize, false);
+        },
+        Op::TailCall(argc, site) => {
+            return call(heap, stack, frames, frame, argc as usize, site as usize, true);
+        },
+        Op::Return => {
+            let val = stack.pop().unwrap();
+            return finish(heap, stack, frames, frame, val, true);
+        },
+    }
+
+    Ok(None)
+}
+
+/// Pop the arguments and procedure for the given call site off of the stack and
+/// call the procedure.
+fn call(heap: &mut Heap,
+        stack: &mut Vec<RootedValue>,
+        frames: &mut Vec<VmFrame>,
+        frame: &mut VmFrame,
+        argc: usize,
+        site: usize,
+        tail: bool) -> StepResult {
+    let at = stack.len() - argc;
+    let args = stack[at..].to_vec();
+    stack.truncate(at);
+    let proc_val = stack.pop().unwrap();
+
+    let code = frame.code.clone();
+    let site = &code.sites[site];
+    let observed = heap.is_profiling() || heap.is_traced(&*proc_val);
+
+    if let Some((fast_path, ref primitive)) = site.fast_path {
+        if *proc_val == **primitive && !observed {
+            let result = match args.as_slice() {
+                [ref a]        => fast_path.call1(heap, a),
+                [ref a, ref b] => fast_path.call2(heap, a, b),
+                _              => panic!("Fast path call with the wrong number of arguments"),
+            };
+            return match result {
+                Ok(val) => finish(heap, stack, frames, frame, val, tail),
+                Err(e)  => {
+                    let call = call_frame(heap, site, &proc_val);
+                    heap.push_frame(call);
+                    Err(e)
+                },
+            };
+        }
+    }
+
+    let depth = heap.stack_depth();
+    let call = call_frame(heap, site, &proc_val);
+    heap.push_frame(call);
+
+    if let Value::Procedure(proc_ptr) = *proc_val {
+        if proc_ptr.arity as usize == args.len() && proc_ptr.signature.is_none() && !observed {
+            let proc_act = proc_ptr.act.as_ref()
+                .expect("Should never see an uninitialized procedure!");
+            let rooted_proc_act = Rooted::new(heap, *proc_act);
+            let body = proc_ptr.body.as_ref()
+                .expect("Should never see an uninitialized procedure!");
+
+            let callee_code = heap.compiled_code(&**body);
+            let act = Activation::extend(heap, &rooted_proc_act, args);
+            if heap.take_out_of_memory() {
+                return Err(E0205.error("Error: out of memory".to_string()));
+            }
+
+            if tail {
+                heap.elide_tail_frames(frame.depth);
+                stack.truncate(frame.base);
+                frame.code = callee_code;
+                frame.pc = 0;
+                frame.act = act;
+            } else {
+                let callee = VmFrame {
+                    code: callee_code,
+                    pc: 0,
+                    act: act,
+                    base: stack.len(),
+                    depth: depth,
+                };
+                frames.push(mem::replace(frame, callee));
+            }
+            return Ok(None);
+        }
+    }
+
+    // Everything else, including arity errors, is handled by the interpreter.
+    let val = try!(apply_invocation(heap, &proc_val, args)
+                   .and_then(|trampoline| trampoline.run(heap)));
+    heap.unwind_to(depth);
+    finish(heap, stack, frames, frame, val, tail)
+}
+
+/// Get the call stack frame for a call from the given call site.
+fn call_frame(heap: &Heap, site: &CallSite, proc_val: &RootedValue) -> Frame {
+    Frame {
+        name: match site.name {
+            Some(name) => heap.environment.name(name).to_string(),
+            None       => procedure_value_name(proc_val),
+        },
+        location: site.location.clone(),
+    }
+}
+
+/// Push the given value onto the stack or, if it is in tail position, return it
+/// from the current frame.
+fn finish(heap: &mut Heap,
+          stack: &mut Vec<RootedValue>,
+          frames: &mut Vec<VmFrame>,
+          frame: &mut VmFrame,
+          val: RootedValue,
+          tail: bool) -> StepResult {
+    if !tail {
+        stack.push(val);
+        return Ok(None);
+    }
+
+    stack.truncate(frame.base);
+    heap.unwind_to(frame.depth);
+    match frames.pop() {
+        Some(caller) => {
+            *frame = caller;
+            stack.push(val);
+            Ok(None)
+        },
+        None => Ok(Some(val)),
+    }
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eval::{evaluate_file, evaluate_with_backtrace};
+    use heap::{Heap, HeapConfig};
+    use read::{read_from_file};
+    use std::default::{Default};
+    use value::{Value};
+
+    fn evaluate_file_in(mode: ExecutionMode, file: &str) -> String {
+        let heap = &mut Heap::with_config(HeapConfig {
+            execution_mode: mode,
+            .. Default::default()
+        });
+        match evaluate_file(heap, file) {
+            Ok(val) => format!("{}", *val),
+            Err(e)  => e,
+        }
+    }
+
+    #[test]
+    fn test_vm_matches_interpreter() {
+        let files = [
+            "./tests/test_eval_closures.scm",
+            "./tests/test_eval_local_definitions.scm",
+            "./tests/test_eval_define_macro.scm",
+            "./tests/test_eval_invocation_arities.scm",
+            "./tests/test_eval_primitive_fast_path.scm",
+            "./tests/test_primitives_apply.scm",
+            "./tests/test_primitives_arity_error.scm",
+            "./tests/test_prelude_for.scm",
+            "./tests/test_prelude_match.scm",
+            "./tests/test_set_defined_later.scm",
+        ];
+
+        for file in files.iter() {
+            assert_eq!(evaluate_file_in(ExecutionMode::Bytecode, file),
+                       evaluate_file_in(ExecutionMode::Interpreter, file));
+        }
+    }
+
+    #[test]
+    fn test_vm_code_cache_eviction() {
+        let heap = &mut Heap::with_config(HeapConfig {
+            execution_mode: ExecutionMode::Bytecode,
+            compiled_code_capacity: 2,
+            .. Default::default()
+        });
+        for i in 0..10i64 {
+            let result = heap.eval_str(format!("((lambda (x) (+ x {})) 1)", i).as_slice())
+                .ok()
+                .expect("Should be able to call a lambda.");
+            assert_eq!(*result, Value::new_integer(i + 1));
+            assert!(heap.compiled_code_len() <= 2);
+        }
+
+        // Bodies that were evicted are compiled again when they are called.
+        heap.eval_str("(define (f x) (* x 2)) (define (g x) (* x 3)) (define (h x) (* x 4))")
+            .ok()
+            .expect("Should be able to define procedures.");
+        let result = heap.eval_str("(+ (f 1) (g 1) (h 1) (f 1))")
+            .ok()
+            .expect("Should be able to call the procedures.");
+        assert_eq!(*result, Value::new_integer(11));
+        assert!(heap.compiled_code_len() <= 2);
+    }
+
+    #[test]
+    fn test_vm_tail_calls() {
+        let heap = &mut Heap::with_config(HeapConfig {
+            execution_mode: ExecutionMode::Bytecode,
+            .. Default::default()
+        });
+        let result = evaluate_file(heap, "./tests/test_vm_tail_calls.scm")
+            .ok()
+            .expect("Should be able to eval a file.");
+        assert_eq!(format!("{}", *result), "(done #f)");
+        assert_eq!(heap.stack_depth(), 0);
+    }
+
+    #[test]
+    fn test_vm_backtrace() {
+        let heap = &mut Heap::new();
+        heap.set_execution_mode(ExecutionMode::Bytecode);
+        let reader = read_from_file("./tests/test_eval_backtrace.scm", heap)
+            .ok()
+            .expect("Should be able to read a file.");
+
+        let mut error = None;
+        for (location, read_result) in reader {
+            let form = read_result.ok().expect("Should be able to read a form.");
+            if let Err(e) = evaluate_with_backtrace(heap, &form, location) {
+                error = Some(e);
+            }
+        }
+
+        let error = error.expect("Should get an error evaluating this file.");
+        let names : Vec<String> = error.backtrace.iter()
+            .map(|frame| frame.name.clone())
+            .collect();
+        assert_eq!(names, vec!("car".to_string(),
+                               "inner".to_string(),
+                               "outer".to_string()));
+        assert_eq!(heap.stack_depth(), 0);
+    }
+
+    #[test]
+    fn test_vm_fuel() {
+        use read::{read_from_str};
+        use value::{RootedValue};
+
+        fn read_form(heap: &mut Heap, source: &str) -> RootedValue {
+            let mut reader = read_from_str(source, heap, "fuel.scm");
+            let (_, form) = reader.next().expect("Should have a form.");
+            form.ok().expect("Should be able to read the form.")
+        }
+
+        let heap = &mut Heap::new();
+        heap.eval_str("(define (count-down n) (if (= n 0) 'done (count-down (- n 1))))
+                       (define (forever) (forever))")
+            .ok()
+            .expect("Should be able to define procedures.");
+
+        let form = read_form(heap, "(count-down 1000)");
+        let mut result = heap.evaluate_with_fuel(&form, 100)
+            .ok()
+            .expect("Should be able to start evaluating.");
+        let mut suspensions = 0;
+        loop {
+            result = match result {
+                Evaluation::Done(val) => {
+                    assert_eq!(format!("{}", *val), "done");
+                    break;
+                },
+                Evaluation::Suspended(suspended) => {
+                    suspensions += 1;
+                    heap.resume(suspended, 100)
+                        .ok()
+                        .expect("Should be able to resume evaluating.")
+                },
+            };
+        }
+        assert!(suspensions > 10);
+        assert_eq!(heap.stack_depth(), 0);
+
+        let form = read_form(heap, "(forever)");
+        match heap.evaluate_with_fuel(&form, 10000).ok().expect("Should not fail.") {
+            Evaluation::Suspended(_) => { },
+            Evaluation::Done(_)      => panic!("Should run out of fuel."),
+        }
+        assert_eq!(heap.stack_depth(), 0);
+
+        // The interpreter can't be suspended, so running out of fuel in a call
+        // delegated to it is an error.
+        let form = read_form(heap, "(apply forever '())");
+        assert_eq!(heap.evaluate_with_fuel(&form, 10000).err().map(|e| e.to_string()),
+                   Some("[E0210] Error: out of fuel".to_string()));
+        assert_eq!(heap.stack_depth(), 0);
+    }
+}
@@ -0,0 +1,10 @@
+(define count-down
+  (lambda (n acc)
+    (if (= n 0)
+        acc
+        (count-down (- n 1) (cons n acc)))))
+(define sum
+  (lambda (a b c d)
+    (+ a (+ b (+ c d)))))
+(list (length (count-down 1000 '()))
+      (sum (sum 1 2 3 4) (sum 5 6 7 8) (car (count-down 3 '())) 10))
@@ -0,0 +1,3 @@
+(define inner (lambda (x) (car x)))
+(define outer (lambda (x) (+ 1 (inner x))))
+(outer 5)
@@ -0,0 +1,3 @@
+(define x 1)
+(define y (+ x 1))
+y
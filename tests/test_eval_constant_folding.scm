@@ -0,0 +1,3 @@
+(define f (lambda () (if (< 1 2) (+ 1 (* 2 3)) (car 5))))
+(define g (lambda () (/ 1 0)))
+(list (f) (%disassemble f) (%disassemble g))
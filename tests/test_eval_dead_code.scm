@@ -0,0 +1,10 @@
+(define y 0)
+(define f
+  (lambda (x)
+    1
+    "stray"
+    car
+    (lambda () 2)
+    (set! y x)
+    (+ x 1)))
+(list (f 5) y (begin 1 2 3))
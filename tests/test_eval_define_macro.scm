@@ -0,0 +1,4 @@
+(define-macro swap-args
+  (lambda (form)
+    (list (car (cdr form)) (car (cdr (cdr (cdr form)))) (car (cdr (cdr form))))))
+(swap-args - 2 5)
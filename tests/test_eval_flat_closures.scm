@@ -0,0 +1,15 @@
+(define (make-adder n) (lambda (x) (+ x n)))
+(define (make-counter) (define n 0) (lambda () (set! n (+ n 1)) n))
+(define (parity n)
+  (define (even? k) (if (= k 0) #t (odd? (- k 1))))
+  (define (odd? k) (if (= k 0) #f (even? (- k 1))))
+  (even? n))
+(define (make-adder3 a) (lambda (b) (lambda (c) (+ a b c))))
+(define counter (make-counter))
+(counter)
+(list ((make-adder 2) 1)
+      (counter)
+      (parity 4)
+      (((make-adder3 1) 2) 3)
+      (%disassemble make-adder)
+      (%disassemble make-counter))
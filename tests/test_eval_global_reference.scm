@@ -0,0 +1,3 @@
+(define f (lambda () later))
+(define later 5)
+(list (f) (%disassemble f))
@@ -0,0 +1,7 @@
+(define square (lambda (x) (* x x)))
+(define add1-square (lambda (y) (+ (square y) 1)))
+(define before (add1-square 3))
+(define disassembly (%disassemble add1-square))
+(set! square (lambda (x) x))
+(define after (add1-square 3))
+(list before after disassembly)
@@ -0,0 +1,6 @@
+(define zero (lambda () 0))
+(define one (lambda (a) a))
+(define two (lambda (a b) (cons a b)))
+(define three (lambda (a b c) (list a b c)))
+(define four (lambda (a b c d) (list a b c d)))
+(list (zero) (one 1) (two 1 2) (three 1 2 3) (four 1 2 3 4))
@@ -0,0 +1,20 @@
+(define make-counter
+  (lambda (a b)
+    ((lambda (x y)
+       (define total (+ x y))
+       (lambda ()
+         (set! total (+ total 1))
+         total))
+     a b)))
+(define counter (make-counter 1 2))
+(counter)
+(define sum-to
+  (lambda (n acc)
+    ((lambda (m)
+       (if (= m 0)
+           acc
+           (sum-to (- m 1) (+ acc m))))
+     n)))
+(list (counter)
+      ((lambda (x) (* x x)) 7)
+      (sum-to 10000 0))
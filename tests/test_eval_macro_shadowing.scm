@@ -0,0 +1,15 @@
+;; A lambda parameter shadows a prelude macro with the same name.
+(define local
+  ((lambda (for) (for 1 2))
+   (lambda (a b) (+ a b))))
+
+;; So does an internal definition.
+(define internal
+  ((lambda ()
+     (define match (lambda (x) (* x 10)))
+     (match 4))))
+
+;; And a global definition replaces the macro.
+(define time (lambda (x) (* x 2)))
+
+(list local internal (time 21))
@@ -0,0 +1,8 @@
+(define opt (lambda (a #:optional b (c (+ a 10))) (list a b c)))
+(define key (lambda (a #:key (b 2) (c (* b 10))) (list a b c)))
+(define both (lambda (a #:optional (b 'b) #:key c) (list a b c)))
+(define (named x #:key (y 'y)) (list x y))
+(list (opt 1) (opt 1 2) (opt 1 2 3)
+      (key 1) (key 1 #:c 3) (key 1 #:b 5) (key 1 #:c 3 #:b 4)
+      (both 1) (both 1 #:c 3) (both 1 2 #:c 3)
+      (named 1 #:y 2))
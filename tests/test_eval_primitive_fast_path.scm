@@ -0,0 +1,4 @@
+(define first (lambda (xs) (car xs)))
+(define before (first '(1 2)))
+(set! car cdr)
+(list before (first '(1 2)) (%disassemble first))
@@ -0,0 +1,17 @@
+(define count-down
+  (lambda (n)
+    (if (= n 0)
+        'done
+        (count-down (- n 1)))))
+
+(define make-counters
+  (lambda (n acc)
+    (if (= n 0)
+        acc
+        (make-counters (- n 1) (cons (lambda () n) acc)))))
+
+(define counters (make-counters 3 '()))
+(list (count-down 100)
+      ((car counters))
+      ((car (cdr counters)))
+      ((car (cdr (cdr counters)))))
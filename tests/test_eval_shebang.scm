@@ -0,0 +1,3 @@
+#!/usr/bin/env oxischeme
+(define (double x) (* 2 x))
+(double 21)
@@ -0,0 +1,3 @@
+(define v #(1 "two" (3 4) #(5)))
+(define q '#(six #u8(7 8)))
+(list v q #u8() (eq? v v))
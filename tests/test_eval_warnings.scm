@@ -0,0 +1,7 @@
+(define first (lambda (x y) x))
+(define shadow
+  (lambda (x) ((lambda (x) x) x)))
+(define ignore (lambda (_ignored) 'ok))
+(define later (lambda () (undefined-thing) (defined-later)))
+(define defined-later (lambda () 'ok))
+(first 1 2)
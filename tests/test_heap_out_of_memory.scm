@@ -0,0 +1,4 @@
+(define allocate-forever
+  (lambda (xs)
+    (allocate-forever (cons xs xs))))
+(allocate-forever '())
@@ -0,0 +1,6 @@
+(define total 0)
+(for (i 0 5)
+  (set! total (+ total i)))
+(for (x in (iota 3 10))
+  (set! total (+ total x)))
+total
@@ -0,0 +1 @@
+(list-tabulate 3 (lambda (i) (* i i)))
@@ -0,0 +1,23 @@
+(define describe
+  (lambda (x)
+    (match x
+      (0 'zero)
+      ('nil 'nil)
+      ((list 'point a b) (guard (= a b)) (list 'diagonal a))
+      ((list 'point a b) (+ a b))
+      ((cons (? number? n) _) (list 'starts-with n))
+      (#(x y) (list 'pair-vector x y))
+      (#(_ (? symbol? s) #(z)) (list 'nested-vector s z))
+      ((? symbol? s) s)
+      (_ 'other))))
+(list (describe 0)
+      (describe 'nil)
+      (describe '(point 3 3))
+      (describe '(point 1 2))
+      (describe '(4 5 6))
+      (describe 'foo)
+      (describe "bar")
+      (describe #(1 2))
+      (describe #(1 b #(3)))
+      (describe #(1 2 3))
+      (describe #()))
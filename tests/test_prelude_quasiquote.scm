@@ -0,0 +1,8 @@
+(define b 2)
+(define c '(3 4))
+(list `(a ,b ,@c e)
+      `(1 . ,b)
+      `(x `(y ,(z ,b)))
+      `,b
+      `(,@c)
+      `())
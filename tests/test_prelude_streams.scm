@@ -0,0 +1,18 @@
+(define evaluations 0)
+(define squares
+  (stream-map (lambda (x)
+                (set! evaluations (+ evaluations 1))
+                (* x x))
+              (stream-from 0)))
+(define evens
+  (stream-filter (lambda (x) (= (* 2 (/ x 2)) x))
+                 squares))
+
+(define first (stream->list/limit evens 3))
+(define again (stream->list/limit evens 3))
+
+(list first
+      again
+      evaluations
+      (stream->list (stream-cons 1 (stream-cons 2 stream-null)))
+      (stream->list/limit (stream-filter (lambda (x) (< x 3)) (stream-from 0)) 3))
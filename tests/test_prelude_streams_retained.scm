@@ -0,0 +1,2 @@
+(define nats (stream-from 0))
+(stream-ref nats 3000)
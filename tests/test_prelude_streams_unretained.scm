@@ -0,0 +1 @@
+(stream-ref (stream-from 0) 3000)
@@ -0,0 +1,4 @@
+(arithmetic-mode 'wrap)
+(define wrapped (+ 1152921504606846975 1))
+(arithmetic-mode 'saturate)
+(list (arithmetic-mode) wrapped (* 1152921504606846975 2))
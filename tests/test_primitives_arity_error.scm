@@ -0,0 +1,2 @@
+(define add-one (lambda (x) (+ x 1)))
+(add-one 1 2)
@@ -0,0 +1 @@
+(length (command-line))
@@ -0,0 +1,16 @@
+(define (inner)
+  (list (continuation-mark-set->list (current-continuation-marks) 'depth)
+        (continuation-mark-set-first #f 'name 'none)
+        (continuation-mark-set-first #f 'missing 'none)))
+
+(define (outer)
+  (with-continuation-mark 'depth 2
+    (with-continuation-mark 'name 'outer
+      (inner))))
+
+(define result
+  (with-continuation-mark 'depth 1
+    (outer)))
+
+;; The marks are gone once their bodies return.
+(list result (current-continuation-marks))
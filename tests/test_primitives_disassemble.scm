@@ -0,0 +1,2 @@
+(define add-one (lambda (x) (+ x 1)))
+(%disassemble add-one)
@@ -0,0 +1,13 @@
+(define log '())
+(define (note x) (lambda () (set! log (cons x log))))
+
+(define result
+  (dynamic-wind (note 'before)
+                (lambda ()
+                  (dynamic-wind (note 'inner-before)
+                                (note 'during)
+                                (note 'inner-after))
+                  'value)
+                (note 'after)))
+
+(list result (reverse log))
@@ -0,0 +1,17 @@
+(define (make-counter start)
+  (define count start)
+  (the-environment))
+(define env (make-counter 5))
+(eval '(set! count (+ count 1)) env)
+(eval '(define extra (* count 2)) env)
+(eval '(define from-eval 3))
+(list (environment-ref env 'count)
+      (environment-ref env 'extra)
+      (environment-ref env 'start)
+      (eval '(+ count start) env)
+      (eval '(car '(1 2)) (interaction-environment))
+      from-eval
+      (environment? env)
+      (environment? 5)
+      (eq? (environment-ref env 'car) car)
+      (assq 'count (environment-bindings env)))
@@ -0,0 +1,16 @@
+(define (sum-to n)
+  (define (loop i acc)
+    (if (fx>? i n)
+        acc
+        (loop (fx+ i 1) (fx+ acc i))))
+  (loop 1 0))
+
+(list (fx+ 3 4)
+      (fx- 3 6)
+      (fx* 6 7)
+      (fx=? 2 2)
+      (fx<? 3 2)
+      (fx>? 3 2)
+      (fx<=? 2 2)
+      (fx>=? 1 2)
+      (sum-to 10))
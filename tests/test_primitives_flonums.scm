@@ -0,0 +1,20 @@
+(list (+ 1.5 2)
+      (* 2 0.25)
+      (/ 1 2.0)
+      (< 1 1.5)
+      (= 2 2.0)
+      (fl+ 0.5 0.25)
+      (fl* 1.5 2.0)
+      (fl<? 1.0 2.0)
+      (flsqrt 16.0)
+      (flfloor -1.5)
+      (exp 0)
+      (sin 0)
+      (log 1)
+      (sqrt 9)
+      (sqrt 2.25)
+      (exact 3.0)
+      (inexact 3)
+      (number? 1.5)
+      (flonum? 1)
+      (/ 1.0 0))
@@ -0,0 +1,2 @@
+(define name "bob")
+(format "x = ~d, name = ~a, ~s ~~~%" 42 name name)
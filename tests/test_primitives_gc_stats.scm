@@ -0,0 +1,4 @@
+(define stats (gc-stats))
+(if (eq? (car (car stats)) 'allocations)
+    (length stats)
+    #f)
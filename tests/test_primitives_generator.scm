@@ -0,0 +1,34 @@
+;; A generator of the natural numbers, which never finishes.
+(define naturals
+  (make-generator
+   (lambda (yield)
+     (define (loop i)
+       (yield i)
+       (loop (+ i 1)))
+     (loop 0))))
+
+(define (take generator n)
+  (if (= n 0)
+      '()
+      (take-after (generator-next generator) generator n)))
+
+(define (take-after val generator n)
+  (cons val (take generator (- n 1))))
+
+;; `yield` may be called by procedures the body calls, too.
+(define (yield-each yield lst)
+  (if (null? lst)
+      'done
+      (begin
+        (yield (car lst))
+        (yield-each yield (cdr lst)))))
+
+(define letters
+  (make-generator (lambda (yield) (yield-each yield '(a b)))))
+
+(list (take naturals 3)
+      (take naturals 2)
+      (generator-next letters)
+      (generator-next letters)
+      (generator-next letters 'done)
+      (generator-next letters 'done))
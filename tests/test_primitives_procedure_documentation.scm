@@ -0,0 +1,10 @@
+(define add
+  (lambda (a b)
+    "Add two numbers."
+    (+ a b)))
+(define greeting (lambda () "hello"))
+(list (procedure-documentation add)
+      (procedure-documentation greeting)
+      (greeting)
+      (procedure-documentation (lambda (x) "Return x." x))
+      (procedure-documentation car))
@@ -0,0 +1,14 @@
+;; The definition below is on line 2, column 1.
+(define add (lambda (a b) (+ a b)))
+(define opt (lambda (a #:optional b c) a))
+(define key (lambda (#:key a) a))
+(list (list (procedure-arity add)
+            (procedure-arity opt)
+            (procedure-arity key)
+            (procedure-arity car)
+            (procedure-arity list))
+      (list (procedure-name add)
+            (procedure-name (lambda (x) x))
+            (procedure-name car))
+      (list (procedure-location add)
+            (procedure-location car)))
@@ -0,0 +1,3 @@
+;; The definition below is on line 2, column 1.
+(define add-one (lambda (x) (+ x 1)))
+(procedure-source add-one)
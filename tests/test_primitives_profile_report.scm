@@ -0,0 +1,8 @@
+(define fact
+  (lambda (n)
+    (if (< n 2)
+        1
+        (* n (fact (- n 1))))))
+(define result (fact 4))
+(profile-report)
+result
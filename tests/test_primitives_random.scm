@@ -0,0 +1,7 @@
+(define source (make-random-source))
+(random-source-pseudo-randomize! source 4 2)
+(define (rolls n)
+  (if (= n 0)
+      '()
+      (cons (random-integer 6 source) (rolls (- n 1)))))
+(cons (random-source? source) (rolls 10))
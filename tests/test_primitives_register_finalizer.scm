@@ -0,0 +1,5 @@
+(define finalized 0)
+(define kept (cons 1 2))
+(register-finalizer! kept (lambda () (set! finalized (+ finalized 10))))
+(register-finalizer! (cons 3 4) (lambda () (set! finalized (+ finalized 1))))
+finalized
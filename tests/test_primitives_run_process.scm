@@ -0,0 +1,11 @@
+(define cat (run-process "cat"))
+(define to-cat (process-input cat))
+(define from-cat (process-output cat))
+
+;; Each line can be read back as soon as it has been written, before the input
+;; is closed.
+(format to-cat "a line~%(hello ~s)~%" "world")
+(define line (read-line from-cat))
+(define echoed (read from-cat))
+(close-port to-cat)
+(list line echoed (eq? (read from-cat) (read-line from-cat)) (process-wait cat))
@@ -0,0 +1,2 @@
+(set-repl-prompt! "[{count}] {module}> ")
+(set-repl-banner! "Hello!")
@@ -0,0 +1,5 @@
+;; Sorting pairs by their car must keep pairs with equal cars in their original
+;; order.
+(define pairs '#((3 . d) (1 . a) (2 . b) (1 . c)))
+(vector-sort! (lambda (a b) (< (car a) (car b))) pairs)
+(list (list-sort < '(3 1 4 5 2)) pairs)
@@ -0,0 +1,13 @@
+;; Connect to ourselves. The connection is queued until it is accepted, so this
+;; works without threads.
+(define listener (tcp-listen "127.0.0.1" 0))
+(define client (tcp-connect "127.0.0.1" (tcp-listener-port listener)))
+(define server (tcp-accept listener))
+
+(format client "ping~%(+ 1 2)~%")
+(define request (read-line server))
+(define datum (read server))
+(format server "~a pong~%" request)
+(define response (read-line client))
+(close-port client)
+(list request datum response (eq? (read server) (read-line server)))
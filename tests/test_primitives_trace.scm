@@ -0,0 +1,9 @@
+(define fact
+  (lambda (n)
+    (if (< n 2)
+        1
+        (* n (fact (- n 1))))))
+(trace fact)
+(define result (fact 3))
+(untrace fact)
+result
@@ -0,0 +1,4 @@
+(list (vector? #(1 2))
+      (vector? '(1 2))
+      (vector->list #(1 (2 3) #(4)))
+      (vector->list #()))
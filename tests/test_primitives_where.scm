@@ -0,0 +1,3 @@
+;; `foo` is defined on line 2.
+(define foo 5)
+(list (where 'foo) (where 'car) (where 'not-defined-anywhere))
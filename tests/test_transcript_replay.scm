@@ -0,0 +1,3 @@
+(transcript-seed 42)
+(transcript-entry 1423094311 (define x 1))
+(transcript-entry 1423094315 (+ x 2))
@@ -0,0 +1,4 @@
+(define count-down (lambda (n) (if (eq? n 0) 'done (count-down (- n 1)))))
+(define my-even? (lambda (n) (if (eq? n 0) #t (my-odd? (- n 1)))))
+(define my-odd? (lambda (n) (if (eq? n 0) #f (my-even? (- n 1)))))
+(list (count-down 100000) (my-even? 10001))